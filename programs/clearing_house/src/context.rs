@@ -2,18 +2,28 @@ use anchor_lang::{prelude::*, AnchorDeserialize, AnchorSerialize};
 use anchor_spl::token::{Mint, Token, TokenAccount};
 
 use crate::controller::position::PositionDirection;
+use crate::state::collateral::{CollateralAssets, UserCollateralAssets};
+use crate::state::history::bankruptcy::BankruptcyHistory;
 use crate::state::history::curve::{CurveHistory, ExtendedCurveHistory};
 use crate::state::history::deposit::DepositHistory;
-use crate::state::history::funding_rate::FundingRateHistory;
-use crate::state::history::liquidation::LiquidationHistory;
+use crate::state::history::funding_rate::{ExtendedFundingRateHistory, FundingRateHistory};
+use crate::state::history::insurance_fund_stake::InsuranceFundStakeHistory;
+use crate::state::history::liquidation::{ExtendedLiquidationHistory, LiquidationHistory};
 use crate::state::history::order_history::OrderHistory;
-use crate::state::history::{funding_payment::FundingPaymentHistory, trade::TradeHistory};
+use crate::state::history::{
+    funding_payment::FundingPaymentHistory,
+    trade::{ExtendedTradeHistory, TradeHistory},
+};
+use crate::state::insurance_fund::InsuranceFundStake;
 use crate::state::market::Markets;
+use crate::state::order_book::OrderBook;
 use crate::state::order_state::OrderState;
 use crate::state::settlement::SettlementState;
 use crate::state::state::State;
 use crate::state::user::{User, UserPositions};
-use crate::state::user_orders::{OrderTriggerCondition, OrderType, UserOrders};
+use crate::state::user_orders::{
+    OrderTriggerCondition, OrderType, TrailingDistanceType, UserOrders,
+};
 
 #[derive(Accounts)]
 #[instruction(
@@ -62,6 +72,20 @@ pub struct Initialize<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct MigrateState<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    /// CHECK: owner and admin checked in `migrate_state`, the state can't be deserialized before it's resized
+    #[account(
+        mut,
+        seeds = [b"clearing_house".as_ref()],
+        bump
+    )]
+    pub state: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct InitializeHistory<'info> {
     pub admin: Signer<'info>,
@@ -73,17 +97,29 @@ pub struct InitializeHistory<'info> {
     #[account(zero)]
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
     #[account(zero)]
-    pub trade_history: AccountLoader<'info, TradeHistory>,
+    pub trade_history: AccountLoader<'info, ExtendedTradeHistory>,
     #[account(zero)]
-    pub liquidation_history: AccountLoader<'info, LiquidationHistory>,
+    pub liquidation_history: AccountLoader<'info, ExtendedLiquidationHistory>,
     #[account(zero)]
     pub deposit_history: AccountLoader<'info, DepositHistory>,
     #[account(zero)]
-    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
+    pub funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
     #[account(zero)]
     pub curve_history: AccountLoader<'info, ExtendedCurveHistory>,
 }
 
+#[derive(Accounts)]
+pub struct InitializeBankruptcyHistory<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(zero)]
+    pub bankruptcy_history: AccountLoader<'info, BankruptcyHistory>,
+}
+
 #[derive(Accounts)]
 #[instruction(
     order_house_nonce: u8,
@@ -110,6 +146,31 @@ pub struct InitializeOrderState<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(market_index: u64)]
+pub struct InitializeOrderBook<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        init,
+        seeds = [b"order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        space = std::mem::size_of::<OrderBook>() + 8,
+        bump,
+        payer = admin
+    )]
+    pub order_book: AccountLoader<'info, OrderBook>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(user_nonce: u8)]
 pub struct InitializeUser<'info> {
@@ -159,6 +220,30 @@ pub struct InitializeUserWithExplicitPayer<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(user_nonce: u8, sub_account_id: u8)]
+pub struct InitializeUserSubAccount<'info> {
+    #[account(
+        init,
+        seeds = [b"user", authority.key.as_ref(), [sub_account_id].as_ref()],
+        space = 224 + 8,
+        bump,
+        payer = authority
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        init,
+        payer = authority,
+        space = 1072 + 8,
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(user_orders_nonce: u8)]
 pub struct InitializeUserOrders<'info> {
@@ -204,6 +289,23 @@ pub struct InitializeUserOrdersWithExplicitPayer<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct ResizeUserPositions<'info> {
+    #[account(
+        has_one = authority,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
 pub struct InitializeUserOptionalAccounts {
     pub whitelist_token: bool,
@@ -226,13 +328,15 @@ pub struct InitializeMarket<'info> {
 }
 
 #[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
 pub struct DepositCollateral<'info> {
     #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         mut,
         has_one = authority,
-        constraint = &user.positions.eq(&user_positions.key())
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
     )]
     pub user: Box<Account<'info, User>>,
     pub authority: Signer<'info>,
@@ -266,13 +370,15 @@ pub struct DepositCollateral<'info> {
 }
 
 #[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
 pub struct WithdrawCollateral<'info> {
     #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         mut,
         has_one = authority,
-        constraint = &user.positions.eq(&user_positions.key())
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
     )]
     pub user: Box<Account<'info, User>>,
     pub authority: Signer<'info>,
@@ -321,104 +427,706 @@ pub struct WithdrawCollateral<'info> {
 }
 
 #[derive(Accounts)]
-pub struct WithdrawFees<'info> {
+#[instruction(from_sub_account_id: u8, to_sub_account_id: u8)]
+pub struct TransferCollateral<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
     #[account(
-        has_one = admin
+        mut,
+        has_one = authority,
+        constraint = &from_user.positions.eq(&from_user_positions.key()),
+        constraint = from_user.sub_account_id == from_sub_account_id
     )]
-    pub state: Box<Account<'info, State>>,
-    pub admin: Signer<'info>,
+    pub from_user: Box<Account<'info, User>>,
+    #[account(mut)]
+    pub from_user_positions: AccountLoader<'info, UserPositions>,
     #[account(
         mut,
-        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+        has_one = authority,
+        constraint = &to_user.positions.eq(&to_user_positions.key()),
+        constraint = to_user.sub_account_id == to_sub_account_id
     )]
-    pub collateral_vault: Box<Account<'info, TokenAccount>>,
-    /// CHECK: withdraw fails if this isn't vault owner
+    pub to_user: Box<Account<'info, User>>,
+    #[account(mut)]
+    pub to_user_positions: AccountLoader<'info, UserPositions>,
+    pub authority: Signer<'info>,
     #[account(
-        constraint = &state.collateral_vault_authority.eq(&collateral_vault_authority.key())
+        constraint = &state.markets.eq(&markets.key())
     )]
-    pub collateral_vault_authority: AccountInfo<'info>,
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
+pub struct TransferIsolatedCollateral<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
     #[account(
         mut,
+        has_one = authority,
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(mut)]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    pub authority: Signer<'info>,
+    #[account(
         constraint = &state.markets.eq(&markets.key())
     )]
     pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeCollateralAssets<'info> {
     #[account(mut)]
-    pub recipient: Box<Account<'info, TokenAccount>>,
-    pub token_program: Program<'info, Token>,
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        init,
+        seeds = [b"collateral_assets".as_ref()],
+        space = std::mem::size_of::<CollateralAssets>() + 8,
+        bump,
+        payer = admin
+    )]
+    pub collateral_assets: AccountLoader<'info, CollateralAssets>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct WithdrawFromInsuranceVault<'info> {
+pub struct AddCollateralAsset<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
     #[account(
         has_one = admin
     )]
     pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &state.collateral_assets.eq(&collateral_assets.key())
+    )]
+    pub collateral_assets: AccountLoader<'info, CollateralAssets>,
+    pub collateral_asset_mint: Box<Account<'info, Mint>>,
+    #[account(
+        init,
+        seeds = [b"collateral_asset_vault".as_ref(), collateral_asset_mint.key().as_ref()],
+        bump,
+        payer = admin,
+        token::mint = collateral_asset_mint,
+        token::authority = collateral_asset_vault_authority
+    )]
+    pub collateral_asset_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: checked in `add_collateral_asset`
+    pub collateral_asset_vault_authority: AccountInfo<'info>,
+    /// CHECK: checked in `add_collateral_asset`
+    pub oracle: AccountInfo<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct AdminUpdateCollateralAssets<'info> {
     pub admin: Signer<'info>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
     #[account(
         mut,
-        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+        constraint = &state.collateral_assets.eq(&collateral_assets.key())
     )]
-    pub insurance_vault: Box<Account<'info, TokenAccount>>,
-    /// CHECK: withdraw fails if this isn't vault owner
+    pub collateral_assets: AccountLoader<'info, CollateralAssets>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeUserCollateralAssets<'info> {
     #[account(
-        constraint = &state.insurance_vault_authority.eq(&insurance_vault_authority.key())
+        mut,
+        has_one = authority,
     )]
-    pub insurance_vault_authority: AccountInfo<'info>,
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        init,
+        seeds = [b"user_collateral_assets", user.key().as_ref()],
+        space = std::mem::size_of::<UserCollateralAssets>() + 8,
+        bump,
+        payer = authority
+    )]
+    pub user_collateral_assets: AccountLoader<'info, UserCollateralAssets>,
     #[account(mut)]
-    pub recipient: Box<Account<'info, TokenAccount>>,
-    pub token_program: Program<'info, Token>,
+    pub authority: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct WithdrawFromInsuranceVaultToMarket<'info> {
+pub struct DepositCollateralAsset<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        has_one = authority
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
     #[account(
         mut,
-        has_one = admin
+        constraint = &state.collateral_assets.eq(&collateral_assets.key())
+    )]
+    pub collateral_assets: AccountLoader<'info, CollateralAssets>,
+    #[account(
+        mut,
+        has_one = user
     )]
+    pub user_collateral_assets: AccountLoader<'info, UserCollateralAssets>,
+    /// checked against the asset's vault in `deposit_collateral_asset`
+    #[account(mut)]
+    pub collateral_asset_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_collateral_asset_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawCollateralAsset<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         mut,
+        has_one = authority,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.collateral_assets.eq(&collateral_assets.key())
+    )]
+    pub collateral_assets: AccountLoader<'info, CollateralAssets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_collateral_assets: AccountLoader<'info, UserCollateralAssets>,
+    /// checked against the asset's vault in `withdraw_collateral_asset`
+    #[account(mut)]
+    pub collateral_asset_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: checked against the asset's vault authority in `withdraw_collateral_asset`
+    pub collateral_asset_vault_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_collateral_asset_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+    #[account(
         constraint = &state.markets.eq(&markets.key())
     )]
     pub markets: AccountLoader<'info, Markets>,
-    pub admin: Signer<'info>,
     #[account(
         mut,
-        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+}
+
+#[derive(Accounts)]
+pub struct ManageBorrow<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        has_one = authority,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.collateral_assets.eq(&collateral_assets.key())
+    )]
+    pub collateral_assets: AccountLoader<'info, CollateralAssets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_collateral_assets: AccountLoader<'info, UserCollateralAssets>,
+    /// checked against the borrowed asset's vault in `borrow` and `repay`
+    #[account(mut)]
+    pub vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: checked against the borrowed asset's vault authority in `borrow` and `repay`
+    pub vault_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_token_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+    #[account(
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+}
+
+#[derive(Accounts)]
+pub struct SettleLendingInterest<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    #[account(mut)]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeInsuranceFund<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(zero)]
+    pub insurance_fund_stake_history: AccountLoader<'info, InsuranceFundStakeHistory>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeInsuranceFundStake<'info> {
+    #[account(
+        init,
+        seeds = [b"insurance_fund_stake", authority.key().as_ref()],
+        space = std::mem::size_of::<InsuranceFundStake>() + 8,
+        bump,
+        payer = authority
+    )]
+    pub insurance_fund_stake: Box<Account<'info, InsuranceFundStake>>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddInsuranceFundStake<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub insurance_fund_stake: Box<Account<'info, InsuranceFundStake>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_collateral_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+    #[account(
+        mut,
+        constraint = &state.insurance_fund.stake_history.eq(&insurance_fund_stake_history.key())
+    )]
+    pub insurance_fund_stake_history: AccountLoader<'info, InsuranceFundStakeHistory>,
+}
+
+#[derive(Accounts)]
+pub struct RequestRemoveInsuranceFundStake<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub insurance_fund_stake: Box<Account<'info, InsuranceFundStake>>,
+    pub authority: Signer<'info>,
+    #[account(
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = &state.insurance_fund.stake_history.eq(&insurance_fund_stake_history.key())
+    )]
+    pub insurance_fund_stake_history: AccountLoader<'info, InsuranceFundStakeHistory>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveInsuranceFundStake<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub insurance_fund_stake: Box<Account<'info, InsuranceFundStake>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: withdraw fails if this isn't vault owner
+    #[account(
+        constraint = &state.insurance_vault_authority.eq(&insurance_vault_authority.key())
+    )]
+    pub insurance_vault_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_collateral_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+    #[account(
+        mut,
+        constraint = &state.insurance_fund.stake_history.eq(&insurance_fund_stake_history.key())
+    )]
+    pub insurance_fund_stake_history: AccountLoader<'info, InsuranceFundStakeHistory>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateCollateralAsset<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &state.collateral_assets.eq(&collateral_assets.key())
+    )]
+    pub collateral_assets: AccountLoader<'info, CollateralAssets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_collateral_assets: AccountLoader<'info, UserCollateralAssets>,
+    #[account(
+        mut,
+        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+    )]
+    pub collateral_vault: Box<Account<'info, TokenAccount>>,
+    /// checked against the asset's vault in `liquidate_collateral_asset`
+    #[account(mut)]
+    pub collateral_asset_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: checked against the asset's vault authority in `liquidate_collateral_asset`
+    pub collateral_asset_vault_authority: AccountInfo<'info>,
+    /// CHECK: checked against the asset's oracle in `liquidate_collateral_asset`
+    pub oracle: AccountInfo<'info>,
+    #[account(mut)]
+    pub liquidator_collateral_account: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub liquidator_collateral_asset_account: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+    #[account(
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+    )]
+    pub collateral_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: withdraw fails if this isn't vault owner
+    #[account(
+        constraint = &state.collateral_vault_authority.eq(&collateral_vault_authority.key())
+    )]
+    pub collateral_vault_authority: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(mut)]
+    pub recipient: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFromInsuranceVault<'info> {
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: withdraw fails if this isn't vault owner
+    #[account(
+        constraint = &state.insurance_vault_authority.eq(&insurance_vault_authority.key())
+    )]
+    pub insurance_vault_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub recipient: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SettleFeesToInsuranceFund<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+    )]
+    pub collateral_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: withdraw fails if this isn't vault owner
+    #[account(
+        constraint = &state.collateral_vault_authority.eq(&collateral_vault_authority.key())
+    )]
+    pub collateral_vault_authority: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFromInsuranceVaultToMarket<'info> {
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.insurance_vault.eq(&insurance_vault.key())
+    )]
+    pub insurance_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: withdraw fails if this isn't vault owner
+    #[account(
+        constraint = &state.insurance_vault_authority.eq(&insurance_vault_authority.key())
+    )]
+    pub insurance_vault_authority: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+    )]
+    pub collateral_vault: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ManagePositionOptionalAccounts {
+    pub discount_token: bool,
+    pub referrer: bool,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
+pub struct OpenPosition<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        has_one = authority,
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
+    )]
+    pub user: Box<Account<'info, User>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.extended_trade_history.eq(&trade_history.key())
+    )]
+    pub trade_history: AccountLoader<'info, ExtendedTradeHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_funding_rate_history.eq(&funding_rate_history.key())
+    )]
+    pub funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_curve_history.eq(&extended_curve_history.key())
+    )]
+    pub extended_curve_history: AccountLoader<'info, ExtendedCurveHistory>,
+    /// CHECK: validated in `open_position` ix constraint
+    pub oracle: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FillOrder<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        constraint = &state.order_state.eq(&order_state.key())
+    )]
+    pub order_state: Box<Account<'info, OrderState>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        has_one = authority
+    )]
+    pub filler: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_orders: AccountLoader<'info, UserOrders>,
+    #[account(
+        mut,
+        constraint = &state.extended_trade_history.eq(&trade_history.key())
+    )]
+    pub trade_history: AccountLoader<'info, ExtendedTradeHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_funding_rate_history.eq(&funding_rate_history.key())
     )]
-    pub insurance_vault: Box<Account<'info, TokenAccount>>,
-    /// CHECK: withdraw fails if this isn't vault owner
+    pub funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
     #[account(
-        constraint = &state.insurance_vault_authority.eq(&insurance_vault_authority.key())
+        mut,
+        constraint = &order_state.order_history.eq(&order_history.key())
     )]
-    pub insurance_vault_authority: AccountInfo<'info>,
+    pub order_history: AccountLoader<'info, OrderHistory>,
     #[account(
         mut,
-        constraint = &state.collateral_vault.eq(&collateral_vault.key())
+        constraint = &state.extended_curve_history.eq(&extended_curve_history.key())
     )]
-    pub collateral_vault: Box<Account<'info, TokenAccount>>,
-    pub token_program: Program<'info, Token>,
-}
-
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
-pub struct ManagePositionOptionalAccounts {
-    pub discount_token: bool,
-    pub referrer: bool,
+    pub extended_curve_history: AccountLoader<'info, ExtendedCurveHistory>,
+    /// CHECK: validated in `controller::orders::fill_order`
+    pub oracle: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct OpenPosition<'info> {
-    #[account(mut)]
+pub struct UpdateTrailingStop<'info> {
     pub state: Box<Account<'info, State>>,
     #[account(
-        mut,
-        has_one = authority,
-        constraint = &user.positions.eq(&user_positions.key())
+        constraint = &state.order_state.eq(&order_state.key())
     )]
+    pub order_state: Box<Account<'info, OrderState>>,
     pub user: Box<Account<'info, User>>,
-    pub authority: Signer<'info>,
     #[account(
-        mut,
         constraint = &state.markets.eq(&markets.key())
     )]
     pub markets: AccountLoader<'info, Markets>,
@@ -426,28 +1134,19 @@ pub struct OpenPosition<'info> {
         mut,
         has_one = user
     )]
-    pub user_positions: AccountLoader<'info, UserPositions>,
-    #[account(
-        mut,
-        constraint = &state.trade_history.eq(&trade_history.key())
-    )]
-    pub trade_history: AccountLoader<'info, TradeHistory>,
-    #[account(
-        mut,
-        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
-    )]
-    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    pub user_orders: AccountLoader<'info, UserOrders>,
     #[account(
         mut,
-        constraint = &state.funding_rate_history.eq(&funding_rate_history.key())
+        constraint = &order_state.order_history.eq(&order_history.key())
     )]
-    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
-    /// CHECK: validated in `open_position` ix constraint
+    pub order_history: AccountLoader<'info, OrderHistory>,
+    /// CHECK: validated in `controller::orders::update_trailing_stop`
     pub oracle: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct FillOrder<'info> {
+pub struct FillOrderWithJitMaker<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         constraint = &state.order_state.eq(&order_state.key())
@@ -464,6 +1163,15 @@ pub struct FillOrder<'info> {
         constraint = &user.positions.eq(&user_positions.key())
     )]
     pub user: Box<Account<'info, User>>,
+    pub maker_authority: Signer<'info>,
+    #[account(
+        mut,
+        constraint = &maker.authority.eq(&maker_authority.key()),
+        constraint = &maker.positions.eq(&maker_positions.key()),
+        constraint = !maker.key().eq(&user.key()),
+        constraint = !maker.key().eq(&filler.key())
+    )]
+    pub maker: Box<Account<'info, User>>,
     #[account(
         mut,
         constraint = &state.markets.eq(&markets.key())
@@ -474,6 +1182,8 @@ pub struct FillOrder<'info> {
         has_one = user
     )]
     pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(mut)]
+    pub maker_positions: AccountLoader<'info, UserPositions>,
     #[account(
         mut,
         has_one = user
@@ -481,9 +1191,9 @@ pub struct FillOrder<'info> {
     pub user_orders: AccountLoader<'info, UserOrders>,
     #[account(
         mut,
-        constraint = &state.trade_history.eq(&trade_history.key())
+        constraint = &state.extended_trade_history.eq(&trade_history.key())
     )]
-    pub trade_history: AccountLoader<'info, TradeHistory>,
+    pub trade_history: AccountLoader<'info, ExtendedTradeHistory>,
     #[account(
         mut,
         constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
@@ -491,9 +1201,14 @@ pub struct FillOrder<'info> {
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
     #[account(
         mut,
-        constraint = &state.funding_rate_history.eq(&funding_rate_history.key())
+        constraint = &state.deposit_history.eq(&deposit_history.key())
     )]
-    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_funding_rate_history.eq(&funding_rate_history.key())
+    )]
+    pub funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
     #[account(
         mut,
         constraint = &order_state.order_history.eq(&order_history.key())
@@ -509,7 +1224,9 @@ pub struct FillOrder<'info> {
 }
 
 #[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
 pub struct PlaceOrder<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         constraint = &state.order_state.eq(&order_state.key())
@@ -517,7 +1234,8 @@ pub struct PlaceOrder<'info> {
     pub order_state: Box<Account<'info, OrderState>>,
     #[account(
         has_one = authority,
-        constraint = &user.positions.eq(&user_positions.key())
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
     )]
     pub user: Box<Account<'info, User>>,
     pub authority: Signer<'info>,
@@ -540,6 +1258,11 @@ pub struct PlaceOrder<'info> {
         constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
     )]
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
     #[account(
         mut,
         constraint = &order_state.order_history.eq(&order_history.key())
@@ -564,6 +1287,13 @@ pub struct OrderParams {
     pub optional_accounts: OrderParamsOptionalAccounts,
     pub position_limit: u128,
     pub oracle_price_offset: i128,
+    pub isolated_collateral: u128,
+    pub trailing_distance: u128,
+    pub trailing_distance_type: TrailingDistanceType,
+    pub slice_base_asset_amount: u128,
+    pub slice_interval: i64,
+    pub linked_order_id: u128,
+    pub max_ts: i64,
     pub padding0: bool,
     pub padding1: bool,
 }
@@ -575,14 +1305,36 @@ impl Default for OrderType {
     }
 }
 
+/// The opposing order a maker supplies when filling part of a taker order just in time. Its
+/// direction is always the opposite of the taker's.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct JitMakerParams {
+    pub base_asset_amount: u128,
+    pub price: u128,
+}
+
+/// Fields left as None keep the order's current value
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct ModifyOrderParams {
+    pub base_asset_amount: Option<u128>,
+    pub price: Option<u128>,
+    pub oracle_price_offset: Option<i128>,
+    pub trigger_price: Option<u128>,
+    /// Set if the order rests on the order book, which is then passed as the first remaining account
+    pub order_book: bool,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
 pub struct OrderParamsOptionalAccounts {
     pub discount_token: bool,
     pub referrer: bool,
+    pub order_book: bool,
 }
 
 #[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
 pub struct PlaceAndFillOrder<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         constraint = &state.order_state.eq(&order_state.key())
@@ -591,7 +1343,8 @@ pub struct PlaceAndFillOrder<'info> {
     #[account(
         mut,
         has_one = authority,
-        constraint = &user.positions.eq(&user_positions.key())
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
     )]
     pub user: Box<Account<'info, User>>,
     pub authority: Signer<'info>,
@@ -617,14 +1370,19 @@ pub struct PlaceAndFillOrder<'info> {
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
     #[account(
         mut,
-        constraint = &state.trade_history.eq(&trade_history.key())
+        constraint = &state.deposit_history.eq(&deposit_history.key())
     )]
-    pub trade_history: AccountLoader<'info, TradeHistory>,
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
     #[account(
         mut,
-        constraint = &state.funding_rate_history.eq(&funding_rate_history.key())
+        constraint = &state.extended_trade_history.eq(&trade_history.key())
     )]
-    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
+    pub trade_history: AccountLoader<'info, ExtendedTradeHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_funding_rate_history.eq(&funding_rate_history.key())
+    )]
+    pub funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
     #[account(
         mut,
         constraint = &order_state.order_history.eq(&order_history.key())
@@ -640,7 +1398,9 @@ pub struct PlaceAndFillOrder<'info> {
 }
 
 #[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
 pub struct CancelOrder<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         constraint = &state.order_state.eq(&order_state.key())
@@ -648,7 +1408,8 @@ pub struct CancelOrder<'info> {
     pub order_state: Box<Account<'info, OrderState>>,
     #[account(
         has_one = authority,
-        constraint = &user.positions.eq(&user_positions.key())
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
     )]
     pub user: Box<Account<'info, User>>,
     pub authority: Signer<'info>,
@@ -671,6 +1432,11 @@ pub struct CancelOrder<'info> {
         constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
     )]
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
     #[account(
         mut,
         constraint = &order_state.order_history.eq(&order_history.key())
@@ -680,6 +1446,7 @@ pub struct CancelOrder<'info> {
 
 #[derive(Accounts)]
 pub struct ExpireOrder<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         constraint = &state.order_state.eq(&order_state.key())
@@ -711,16 +1478,23 @@ pub struct ExpireOrder<'info> {
         constraint = &order_state.order_history.eq(&order_history.key())
     )]
     pub order_history: AccountLoader<'info, OrderHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
 }
 
 #[derive(Accounts)]
+#[instruction(sub_account_id: u8)]
 pub struct ClosePosition<'info> {
     #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         mut,
         has_one = authority,
-        constraint = &user.positions.eq(&user_positions.key())
+        constraint = &user.positions.eq(&user_positions.key()),
+        constraint = user.sub_account_id == sub_account_id
     )]
     pub user: Box<Account<'info, User>>,
     pub authority: Signer<'info>,
@@ -736,9 +1510,9 @@ pub struct ClosePosition<'info> {
     pub user_positions: AccountLoader<'info, UserPositions>,
     #[account(
         mut,
-        constraint = &state.trade_history.eq(&trade_history.key())
+        constraint = &state.extended_trade_history.eq(&trade_history.key())
     )]
-    pub trade_history: AccountLoader<'info, TradeHistory>,
+    pub trade_history: AccountLoader<'info, ExtendedTradeHistory>,
     #[account(
         mut,
         constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
@@ -746,15 +1520,21 @@ pub struct ClosePosition<'info> {
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
     #[account(
         mut,
-        constraint = &state.funding_rate_history.eq(&funding_rate_history.key())
+        constraint = &state.deposit_history.eq(&deposit_history.key())
     )]
-    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_funding_rate_history.eq(&funding_rate_history.key())
+    )]
+    pub funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
     /// CHECK: validated in `close_position`ix constraint
     pub oracle: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
 pub struct Liquidate<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     pub authority: Signer<'info>,
     #[account(
@@ -800,23 +1580,109 @@ pub struct Liquidate<'info> {
     pub user_positions: AccountLoader<'info, UserPositions>,
     #[account(
         mut,
-        constraint = &state.trade_history.eq(&trade_history.key())
+        constraint = &state.extended_trade_history.eq(&trade_history.key())
     )]
-    pub trade_history: AccountLoader<'info, TradeHistory>,
+    pub trade_history: AccountLoader<'info, ExtendedTradeHistory>,
     #[account(
         mut,
-        constraint = &state.liquidation_history.eq(&liquidation_history.key())
+        constraint = &state.extended_liquidation_history.eq(&liquidation_history.key())
     )]
-    pub liquidation_history: AccountLoader<'info, LiquidationHistory>,
+    pub liquidation_history: AccountLoader<'info, ExtendedLiquidationHistory>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+    #[account(
+        mut,
+        constraint = &state.bankruptcy_history.eq(&bankruptcy_history.key())
+    )]
+    pub bankruptcy_history: AccountLoader<'info, BankruptcyHistory>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateByPositionTransfer<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        has_one = authority,
+        constraint = &liquidator.positions.eq(&liquidator_positions.key())
+    )]
+    pub liquidator: Box<Account<'info, User>>,
+    #[account(mut)]
+    pub liquidator_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        mut,
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
+    #[account(
+        mut,
+        constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
+    )]
+    pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_liquidation_history.eq(&liquidation_history.key())
+    )]
+    pub liquidation_history: AccountLoader<'info, ExtendedLiquidationHistory>,
+}
+
+#[derive(Accounts)]
+pub struct FlagUserLiquidatable<'info> {
+    #[account(mut)]
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = &user.positions.eq(&user_positions.key())
+    )]
+    pub user: Box<Account<'info, User>>,
+    #[account(
+        mut,
+        has_one = user
+    )]
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    #[account(
+        constraint = &state.markets.eq(&markets.key())
+    )]
+    pub markets: AccountLoader<'info, Markets>,
     #[account(
         mut,
         constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
     )]
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
 }
 
 #[derive(Accounts)]
 pub struct SettleFunding<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(
         mut,
@@ -837,6 +1703,11 @@ pub struct SettleFunding<'info> {
         constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
     )]
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
 }
 
 #[derive(Accounts)]
@@ -851,9 +1722,14 @@ pub struct UpdateFundingRate<'info> {
     pub oracle: AccountInfo<'info>,
     #[account(
         mut,
-        constraint = &state.funding_rate_history.eq(&funding_rate_history.key())
+        constraint = &state.extended_funding_rate_history.eq(&funding_rate_history.key())
     )]
-    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
+    pub funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
+    #[account(
+        mut,
+        constraint = &state.extended_curve_history.eq(&extended_curve_history.key())
+    )]
+    pub extended_curve_history: AccountLoader<'info, ExtendedCurveHistory>,
 }
 
 #[derive(Accounts)]
@@ -890,6 +1766,8 @@ pub struct MoveAMMPrice<'info> {
         constraint = &state.markets.eq(&markets.key())
     )]
     pub markets: AccountLoader<'info, Markets>,
+    /// CHECK: checked in `move_amm_price` ix constraint
+    pub oracle: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
@@ -967,6 +1845,54 @@ pub struct UpdateCurveHistory<'info> {
     pub curve_history: AccountLoader<'info, CurveHistory>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateTradeHistory<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(zero)]
+    pub extended_trade_history: AccountLoader<'info, ExtendedTradeHistory>,
+    #[account(
+        constraint = &state.trade_history.eq(&trade_history.key())
+    )]
+    pub trade_history: AccountLoader<'info, TradeHistory>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateLiquidationHistory<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(zero)]
+    pub extended_liquidation_history: AccountLoader<'info, ExtendedLiquidationHistory>,
+    #[account(
+        constraint = &state.liquidation_history.eq(&liquidation_history.key())
+    )]
+    pub liquidation_history: AccountLoader<'info, LiquidationHistory>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFundingRateHistory<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(zero)]
+    pub extended_funding_rate_history: AccountLoader<'info, ExtendedFundingRateHistory>,
+    #[account(
+        constraint = &state.funding_rate_history.eq(&funding_rate_history.key())
+    )]
+    pub funding_rate_history: AccountLoader<'info, FundingRateHistory>,
+}
+
 #[derive(Accounts)]
 pub struct AdminUpdateUserForgoSettlement<'info> {
     pub admin: Signer<'info>,
@@ -1042,6 +1968,7 @@ pub struct UpdateSettlementState<'info> {
 
 #[derive(Accounts)]
 pub struct SettlePosition<'info> {
+    #[account(mut)]
     pub state: Box<Account<'info, State>>,
     #[account(mut)]
     pub settlement_state: Box<Account<'info, SettlementState>>,
@@ -1066,6 +1993,11 @@ pub struct SettlePosition<'info> {
         constraint = &state.funding_payment_history.eq(&funding_payment_history.key())
     )]
     pub funding_payment_history: AccountLoader<'info, FundingPaymentHistory>,
+    #[account(
+        mut,
+        constraint = &state.deposit_history.eq(&deposit_history.key())
+    )]
+    pub deposit_history: AccountLoader<'info, DepositHistory>,
 }
 
 #[derive(Accounts)]
@@ -1096,7 +2028,10 @@ pub struct ClaimCollateral<'info> {
 
 #[derive(Accounts)]
 pub struct TransferFromInsuranceVaultToCollateralVault<'info> {
-    #[account(has_one = admin)]
+    #[account(
+        mut,
+        has_one = admin
+    )]
     pub state: Box<Account<'info, State>>,
     pub admin: Signer<'info>,
     #[account(
@@ -4,10 +4,12 @@ use crate::controller::position::PositionDirection;
 use crate::error::{ClearingHouseResult, ErrorCode};
 use crate::math::amm::{calculate_quote_asset_amount_swapped, calculate_spread_reserves};
 use crate::math::casting::{cast, cast_to_i128};
-use crate::math::constants::PRICE_TO_PEG_PRECISION_RATIO;
-use crate::math::{amm, bn, quote_asset::*};
+use crate::math::curve::Curve;
+use crate::math::{amm, quote_asset::*};
 use crate::math_error;
-use crate::state::market::AMM;
+use crate::state::market::{Market, OraclePriceData, AMM};
+use crate::state::state::ValidityGuardRails;
+use anchor_lang::prelude::AccountInfo;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum SwapDirection {
@@ -58,7 +60,7 @@ fn calculate_quote_swap_output_without_spread(
         quote_asset_reserve_amount,
         amm.quote_asset_reserve,
         direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     let base_asset_amount = cast_to_i128(initial_base_asset_reserve)?
@@ -99,7 +101,7 @@ fn calculate_quote_swap_output_with_spread(
         quote_asset_reserve_amount,
         quote_asset_reserve_with_spread,
         direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     let base_asset_amount_with_spread = cast_to_i128(base_asset_reserve_with_spread)?
@@ -111,7 +113,7 @@ fn calculate_quote_swap_output_with_spread(
         quote_asset_reserve_amount,
         amm.quote_asset_reserve,
         direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     // find the quote asset reserves if the position were closed
@@ -119,7 +121,7 @@ fn calculate_quote_swap_output_with_spread(
         base_asset_amount_with_spread.unsigned_abs(),
         new_base_asset_reserve,
         direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     // calculate the quote asset surplus by taking the difference between what quote_asset_amount is
@@ -218,7 +220,7 @@ fn calculate_base_swap_output_without_spread(
         base_asset_swap_amount,
         amm.base_asset_reserve,
         direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     let quote_asset_amount = calculate_quote_asset_amount_swapped(
@@ -255,7 +257,7 @@ fn calculate_base_swap_output_with_spread(
         base_asset_swap_amount,
         base_asset_reserve_with_spread,
         direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     let quote_asset_amount = calculate_quote_asset_amount_swapped(
@@ -269,7 +271,7 @@ fn calculate_base_swap_output_with_spread(
         base_asset_swap_amount,
         amm.base_asset_reserve,
         direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     // calculate the quote asset surplus by taking the difference between what quote_asset_amount is
@@ -294,6 +296,55 @@ fn calculate_base_swap_output_with_spread(
     ))
 }
 
+pub fn update_spreads(
+    amm: &mut AMM,
+    net_base_asset_amount: i128,
+    oracle_price_data: Option<&OraclePriceData>,
+    mark_price: u128,
+) -> ClearingHouseResult {
+    let (long_spread, short_spread) =
+        amm::calculate_spreads(amm, net_base_asset_amount, oracle_price_data, mark_price)?;
+    amm.long_spread = long_spread;
+    amm.short_spread = short_spread;
+
+    Ok(())
+}
+
+/// Recomputes the market's spreads at its current mark price. Called after the reserves change
+/// outside of a trade (repegs, k updates, liquidations) so the stored spreads don't go stale.
+pub fn update_market_spreads(
+    market: &mut Market,
+    oracle_price_data: Option<&OraclePriceData>,
+) -> ClearingHouseResult {
+    let mark_price = market.amm.mark_price()?;
+    update_spreads(
+        &mut market.amm,
+        market.base_asset_amount,
+        oracle_price_data,
+        mark_price,
+    )
+}
+
+/// Same as update_market_spreads, reading the oracle price and only using it if it's valid
+pub fn update_market_spreads_with_oracle(
+    market: &mut Market,
+    price_oracle: &AccountInfo,
+    clock_slot: u64,
+    oracle_validity_guard_rails: &ValidityGuardRails,
+) -> ClearingHouseResult {
+    let oracle_price_data = market.amm.get_oracle_price(price_oracle, clock_slot)?;
+    let oracle_is_valid =
+        amm::is_oracle_valid(&market.amm, &oracle_price_data, oracle_validity_guard_rails)?;
+    update_market_spreads(
+        market,
+        if oracle_is_valid {
+            Some(&oracle_price_data)
+        } else {
+            None
+        },
+    )
+}
+
 pub fn move_price(
     amm: &mut AMM,
     base_asset_reserve: u128,
@@ -301,36 +352,25 @@ pub fn move_price(
 ) -> ClearingHouseResult {
     amm.base_asset_reserve = base_asset_reserve;
     amm.quote_asset_reserve = quote_asset_reserve;
-
-    let k = bn::U256::from(base_asset_reserve)
-        .checked_mul(bn::U256::from(quote_asset_reserve))
-        .ok_or_else(math_error!())?;
-
-    amm.sqrt_k = k.integer_sqrt().try_to_u128()?;
+    amm.sqrt_k = amm
+        .curve()
+        .calculate_sqrt_k(base_asset_reserve, quote_asset_reserve)?;
 
     Ok(())
 }
 
 #[allow(dead_code)]
 pub fn move_to_price(amm: &mut AMM, target_price: u128) -> ClearingHouseResult {
-    let sqrt_k = bn::U256::from(amm.sqrt_k);
-    let k = sqrt_k.checked_mul(sqrt_k).ok_or_else(math_error!())?;
-
-    let new_base_asset_amount_squared = k
-        .checked_mul(bn::U256::from(amm.peg_multiplier))
-        .ok_or_else(math_error!())?
-        .checked_mul(bn::U256::from(PRICE_TO_PEG_PRECISION_RATIO))
-        .ok_or_else(math_error!())?
-        .checked_div(bn::U256::from(target_price))
-        .ok_or_else(math_error!())?;
-
-    let new_base_asset_amount = new_base_asset_amount_squared.integer_sqrt();
-    let new_quote_asset_amount = k
-        .checked_div(new_base_asset_amount)
-        .ok_or_else(math_error!())?;
+    let curve = amm.curve();
+    let new_base_asset_amount = curve.calculate_base_asset_reserve_for_price(
+        target_price,
+        amm.peg_multiplier,
+        amm.sqrt_k,
+    )?;
+    let new_quote_asset_amount = curve.calculate_reserve(new_base_asset_amount, amm.sqrt_k)?;
 
-    amm.base_asset_reserve = new_base_asset_amount.try_to_u128()?;
-    amm.quote_asset_reserve = new_quote_asset_amount.try_to_u128()?;
+    amm.base_asset_reserve = new_base_asset_amount;
+    amm.quote_asset_reserve = new_quote_asset_amount;
 
     Ok(())
 }
@@ -0,0 +1,169 @@
+use crate::error::ClearingHouseResult;
+use crate::math::bankruptcy::calculate_socialized_loss_funding_rate;
+use crate::math::casting::{cast, cast_to_u128};
+use crate::math_error;
+use crate::state::market::Market;
+use solana_program::msg;
+use std::cmp::min;
+
+#[derive(Default)]
+pub struct BankruptcyResolution {
+    pub loss_covered_by_fee_pool: u128,
+    pub loss_covered_by_insurance_fund: u64,
+    pub loss_socialized: u128,
+    pub cumulative_funding_rate_long_delta: i128,
+    pub cumulative_funding_rate_short_delta: i128,
+    pub uncovered_loss: u128,
+}
+
+/// A bankrupt position's loss is paid from the market's fee pool, then from the insurance fund, and
+/// what's left is socialized by charging it to the positions on the other side of the market
+/// through their cumulative funding rate. The caller moves the insurance fund's part to the
+/// collateral vault.
+pub fn resolve_bankruptcy(
+    market: &mut Market,
+    bankrupt_loss: u128,
+    bankrupt_base_asset_amount: i128,
+    insurance_vault_amount: u64,
+) -> ClearingHouseResult<BankruptcyResolution> {
+    let loss_covered_by_fee_pool = min(bankrupt_loss, market.amm.total_fee_minus_distributions);
+    market.amm.total_fee_minus_distributions = market
+        .amm
+        .total_fee_minus_distributions
+        .checked_sub(loss_covered_by_fee_pool)
+        .ok_or_else(math_error!())?;
+    let remaining_loss = bankrupt_loss
+        .checked_sub(loss_covered_by_fee_pool)
+        .ok_or_else(math_error!())?;
+
+    let loss_covered_by_insurance_fund =
+        cast(min(remaining_loss, cast_to_u128(insurance_vault_amount)?))?;
+    let remaining_loss = remaining_loss
+        .checked_sub(cast(loss_covered_by_insurance_fund)?)
+        .ok_or_else(math_error!())?;
+
+    let mut resolution = BankruptcyResolution {
+        loss_covered_by_fee_pool,
+        loss_covered_by_insurance_fund,
+        ..BankruptcyResolution::default()
+    };
+
+    if remaining_loss == 0 {
+        return Ok(resolution);
+    }
+
+    // The other side of the market profited from the bankrupt position's loss
+    if bankrupt_base_asset_amount > 0 && market.base_asset_amount_short != 0 {
+        // shorts pay when their funding rate falls
+        let funding_rate_delta =
+            calculate_socialized_loss_funding_rate(remaining_loss, market.base_asset_amount_short)?;
+        market.amm.cumulative_funding_rate_short = market
+            .amm
+            .cumulative_funding_rate_short
+            .checked_sub(funding_rate_delta)
+            .ok_or_else(math_error!())?;
+        resolution.cumulative_funding_rate_short_delta = -funding_rate_delta;
+        resolution.loss_socialized = remaining_loss;
+    } else if bankrupt_base_asset_amount < 0 && market.base_asset_amount_long != 0 {
+        // longs pay when their funding rate rises
+        let funding_rate_delta =
+            calculate_socialized_loss_funding_rate(remaining_loss, market.base_asset_amount_long)?;
+        market.amm.cumulative_funding_rate_long = market
+            .amm
+            .cumulative_funding_rate_long
+            .checked_add(funding_rate_delta)
+            .ok_or_else(math_error!())?;
+        resolution.cumulative_funding_rate_long_delta = funding_rate_delta;
+        resolution.loss_socialized = remaining_loss;
+    } else {
+        resolution.uncovered_loss = remaining_loss;
+    }
+
+    Ok(resolution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{AMM_RESERVE_PRECISION, QUOTE_PRECISION};
+    use crate::state::market::AMM;
+
+    fn test_market(total_fee_minus_distributions: u128, long: i128, short: i128) -> Market {
+        Market {
+            base_asset_amount_long: long,
+            base_asset_amount_short: short,
+            amm: AMM {
+                total_fee_minus_distributions,
+                ..AMM::default()
+            },
+            ..Market::default()
+        }
+    }
+
+    #[test]
+    fn fee_pool_covers_loss_first() {
+        let mut market = test_market(100, 0, 0);
+        let resolution = resolve_bankruptcy(&mut market, 60, 1, 1000).unwrap();
+
+        assert_eq!(resolution.loss_covered_by_fee_pool, 60);
+        assert_eq!(resolution.loss_covered_by_insurance_fund, 0);
+        assert_eq!(resolution.loss_socialized, 0);
+        assert_eq!(resolution.uncovered_loss, 0);
+        let total_fee_minus_distributions = market.amm.total_fee_minus_distributions;
+        assert_eq!(total_fee_minus_distributions, 40);
+    }
+
+    #[test]
+    fn insurance_fund_covers_what_fee_pool_cant() {
+        let mut market = test_market(100, 0, 0);
+        let resolution = resolve_bankruptcy(&mut market, 150, 1, 1000).unwrap();
+
+        assert_eq!(resolution.loss_covered_by_fee_pool, 100);
+        assert_eq!(resolution.loss_covered_by_insurance_fund, 50);
+        assert_eq!(resolution.loss_socialized, 0);
+        let total_fee_minus_distributions = market.amm.total_fee_minus_distributions;
+        assert_eq!(total_fee_minus_distributions, 0);
+    }
+
+    #[test]
+    fn remaining_loss_socialized_to_other_side() {
+        let base_asset_amount = AMM_RESERVE_PRECISION as i128;
+
+        // a bankrupt long's loss is charged to the shorts
+        let mut market = test_market(0, base_asset_amount, -base_asset_amount);
+        let resolution =
+            resolve_bankruptcy(&mut market, QUOTE_PRECISION, base_asset_amount, 0).unwrap();
+        assert_eq!(resolution.loss_socialized, QUOTE_PRECISION);
+        assert_eq!(
+            resolution.cumulative_funding_rate_short_delta,
+            -100_000_000_000_000
+        );
+        assert_eq!(resolution.cumulative_funding_rate_long_delta, 0);
+        let cumulative_funding_rate_short = market.amm.cumulative_funding_rate_short;
+        assert_eq!(cumulative_funding_rate_short, -100_000_000_000_000);
+
+        // a bankrupt short's loss is charged to the longs
+        let mut market = test_market(0, base_asset_amount, -base_asset_amount);
+        let resolution =
+            resolve_bankruptcy(&mut market, QUOTE_PRECISION, -base_asset_amount, 0).unwrap();
+        assert_eq!(resolution.loss_socialized, QUOTE_PRECISION);
+        assert_eq!(
+            resolution.cumulative_funding_rate_long_delta,
+            100_000_000_000_000
+        );
+        let cumulative_funding_rate_long = market.amm.cumulative_funding_rate_long;
+        assert_eq!(cumulative_funding_rate_long, 100_000_000_000_000);
+    }
+
+    #[test]
+    fn loss_uncovered_without_other_side() {
+        let base_asset_amount = AMM_RESERVE_PRECISION as i128;
+        let mut market = test_market(10, base_asset_amount, 0);
+        let resolution = resolve_bankruptcy(&mut market, 100, base_asset_amount, 20).unwrap();
+
+        assert_eq!(resolution.loss_covered_by_fee_pool, 10);
+        assert_eq!(resolution.loss_covered_by_insurance_fund, 20);
+        assert_eq!(resolution.loss_socialized, 0);
+        assert_eq!(resolution.uncovered_loss, 70);
+    }
+}
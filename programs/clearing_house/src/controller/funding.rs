@@ -1,5 +1,5 @@
 use std::cell::{Ref, RefMut};
-use std::cmp::{max, min};
+use std::cmp::max;
 
 use anchor_lang::prelude::*;
 
@@ -8,18 +8,18 @@ use crate::math::amm;
 use crate::math::amm::normalise_oracle_price;
 use crate::math::casting::{cast, cast_to_i128, cast_to_i64};
 use crate::math::collateral::calculate_updated_collateral;
-use crate::math::constants::{
-    AMM_TO_QUOTE_PRECISION_RATIO_I128, FUNDING_PAYMENT_PRECISION, ONE_HOUR,
+use crate::math::constants::{AMM_TO_QUOTE_PRECISION_RATIO_I128, ONE_HOUR};
+use crate::math::funding::{
+    calculate_funding_payment, calculate_funding_rate, calculate_funding_rate_long_short,
 };
-use crate::math::funding::{calculate_funding_payment, calculate_funding_rate_long_short};
 use crate::math::oracle;
 use crate::math_error;
 use crate::state::history::funding_payment::{FundingPaymentHistory, FundingPaymentRecord};
-use crate::state::history::funding_rate::{FundingRateHistory, FundingRateRecord};
+use crate::state::history::funding_rate::{ExtendedFundingRateHistory, ExtendedFundingRateRecord};
 use crate::state::market::AMM;
 use crate::state::market::{Market, Markets};
 use crate::state::state::OracleGuardRails;
-use crate::state::user::{User, UserPositions};
+use crate::state::user::{MarketPosition, User};
 use solana_program::clock::UnixTimestamp;
 use solana_program::msg;
 
@@ -27,15 +27,15 @@ use solana_program::msg;
 /// and the user's market position tracks how much funding the user been cumulatively paid for that market.
 /// If the two values are not equal, the user owes/is owed funding.
 pub fn settle_funding_payment(
-    user: &mut User,
-    user_positions: &mut RefMut<UserPositions>,
+    user: &mut Account<User>,
+    user_positions: &mut [MarketPosition],
     markets: &Ref<Markets>,
     funding_payment_history: &mut RefMut<FundingPaymentHistory>,
     now: UnixTimestamp,
 ) -> ClearingHouseResult {
-    let user_key = user_positions.user;
+    let user_key = user.key();
     let mut funding_payment: i128 = 0;
-    for market_position in user_positions.positions.iter_mut() {
+    for market_position in user_positions.iter_mut() {
         if market_position.base_asset_amount == 0 {
             continue;
         }
@@ -68,9 +68,20 @@ pub fn settle_funding_payment(
                 base_asset_amount: market_position.base_asset_amount,          //10e13
             });
 
-            funding_payment = funding_payment
-                .checked_add(market_funding_rate_payment)
-                .ok_or_else(math_error!())?;
+            if market_position.is_isolated() {
+                let isolated_funding_payment_collateral = market_funding_rate_payment
+                    .checked_div(AMM_TO_QUOTE_PRECISION_RATIO_I128)
+                    .ok_or_else(math_error!())?;
+
+                market_position.isolated_collateral = calculate_updated_collateral(
+                    market_position.isolated_collateral,
+                    isolated_funding_payment_collateral,
+                )?;
+            } else {
+                funding_payment = funding_payment
+                    .checked_add(market_funding_rate_payment)
+                    .ok_or_else(math_error!())?;
+            }
 
             market_position.last_cumulative_funding_rate = amm_cumulative_funding_rate;
             market_position.last_funding_rate_ts = amm.last_funding_rate_ts;
@@ -92,7 +103,7 @@ pub fn update_funding_rate(
     price_oracle: &AccountInfo,
     now: UnixTimestamp,
     clock_slot: u64,
-    funding_rate_history: &mut RefMut<FundingRateHistory>,
+    funding_rate_history: &mut RefMut<ExtendedFundingRateHistory>,
     guard_rails: &OracleGuardRails,
     funding_paused: bool,
     precomputed_mark_price: Option<u128>,
@@ -112,6 +123,10 @@ pub fn update_funding_rate(
     let normalised_oracle_price =
         normalise_oracle_price(&market.amm, &oracle_price_data, precomputed_mark_price)?;
 
+    if !block_funding_rate_update {
+        sample_premium(market, now, normalised_oracle_price, precomputed_mark_price)?;
+    }
+
     // round next update time to be available on the hour
     let mut next_update_wait = market.amm.funding_period;
     if market.amm.funding_period > 1 {
@@ -167,21 +182,24 @@ pub fn update_funding_rate(
             .ok_or_else(math_error!())?;
         // funding period = 1 hour, window = 1 day
         // low periodicity => quickly updating/settled funding rates => lower funding rate payment per interval
-        let price_spread = cast_to_i128(mark_price_twap)?
-            .checked_sub(oracle_price_twap)
-            .ok_or_else(math_error!())?;
-
-        // clamp price divergence to 3% for funding rate calculation
-        let max_price_spread = oracle_price_twap
-            .checked_div(33)
-            .ok_or_else(math_error!())?; // 3%
-        let clamped_price_spread = max(-max_price_spread, min(price_spread, max_price_spread));
+        let premium_index = if market.premium_sample_duration > 0 {
+            market
+                .premium_sample_sum
+                .checked_div(cast(market.premium_sample_duration)?)
+                .ok_or_else(math_error!())?
+        } else {
+            cast_to_i128(mark_price_twap)?
+                .checked_sub(oracle_price_twap)
+                .ok_or_else(math_error!())?
+        };
 
-        let funding_rate = clamped_price_spread
-            .checked_mul(cast(FUNDING_PAYMENT_PRECISION)?)
-            .ok_or_else(math_error!())?
-            .checked_div(cast(period_adjustment)?)
-            .ok_or_else(math_error!())?;
+        let (raw_funding_rate, funding_rate) = calculate_funding_rate(
+            premium_index,
+            oracle_price_twap,
+            period_adjustment,
+            market.max_funding_rate,
+            market.funding_interest_rate,
+        )?;
 
         let (funding_rate_long, funding_rate_short) =
             calculate_funding_rate_long_short(market, funding_rate)?;
@@ -200,19 +218,68 @@ pub fn update_funding_rate(
 
         market.amm.last_funding_rate = funding_rate;
         market.amm.last_funding_rate_ts = now;
+        market.premium_sample_sum = 0;
+        market.premium_sample_duration = 0;
 
         let record_id = funding_rate_history.next_record_id();
-        funding_rate_history.append(FundingRateRecord {
+        funding_rate_history.append(ExtendedFundingRateRecord {
             ts: now,
             record_id,
             market_index,
             funding_rate,
+            raw_funding_rate,
+            premium_index,
             cumulative_funding_rate_long: market.amm.cumulative_funding_rate_long,
             cumulative_funding_rate_short: market.amm.cumulative_funding_rate_short,
             mark_price_twap,
             oracle_price_twap,
+            padding: [0; 5],
         });
     }
 
     Ok(())
 }
+
+/// Adds the time since the last sample, weighted by the mark/oracle spread at the last sample, to
+/// the premium sample sum and takes a new sample. The sum divided by the time it covers is the
+/// premium index for the next funding rate update
+fn sample_premium(
+    market: &mut Market,
+    now: UnixTimestamp,
+    oracle_price: i128,
+    precomputed_mark_price: Option<u128>,
+) -> ClearingHouseResult {
+    // the first sample only sets the spread the next one is weighted by
+    if market.last_premium_sample_ts != 0 {
+        let time_since_last_sample = now
+            .checked_sub(market.last_premium_sample_ts)
+            .ok_or_else(math_error!())?;
+
+        market.premium_sample_sum = market
+            .premium_sample_sum
+            .checked_add(
+                cast_to_i128(market.last_premium)?
+                    .checked_mul(cast_to_i128(time_since_last_sample)?)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?;
+        market.premium_sample_duration = market
+            .premium_sample_duration
+            .checked_add(cast(time_since_last_sample)?)
+            .ok_or_else(math_error!())?;
+    }
+
+    let mark_price = match precomputed_mark_price {
+        Some(mark_price) => mark_price,
+        None => market.amm.mark_price()?,
+    };
+
+    market.last_premium = cast_to_i64(
+        cast_to_i128(mark_price)?
+            .checked_sub(oracle_price)
+            .ok_or_else(math_error!())?,
+    )?;
+    market.last_premium_sample_ts = now;
+
+    Ok(())
+}
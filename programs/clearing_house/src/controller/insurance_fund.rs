@@ -0,0 +1,444 @@
+use crate::error::{ClearingHouseResult, ErrorCode};
+use crate::math::casting::{cast, cast_to_u128};
+use crate::math::constants::INSURANCE_FUND_DEAD_SHARES;
+use crate::math::insurance_fund::{
+    calculate_amount_for_shares, calculate_shares_for_amount,
+    calculate_shares_for_amount_rounded_up,
+};
+use crate::math_error;
+use crate::state::insurance_fund::{InsuranceFund, InsuranceFundStake};
+use crate::state::market::Market;
+use solana_program::clock::UnixTimestamp;
+use solana_program::msg;
+use std::cmp::min;
+
+/// Returns the shares minted for the stake. The vault's balance from before anyone staked belongs
+/// to the protocol, so it's given to the protocol as shares before the first stake is priced.
+/// The protocol also keeps the first INSURANCE_FUND_DEAD_SHARES shares minted, so a staker can never
+/// own the whole supply and inflate the share value by sending tokens straight to the vault.
+pub fn add_stake(
+    insurance_fund: &mut InsuranceFund,
+    insurance_fund_stake: &mut InsuranceFundStake,
+    amount: u64,
+    insurance_vault_amount: u64,
+) -> ClearingHouseResult<u128> {
+    if insurance_fund.total_shares == 0 && insurance_vault_amount > 0 {
+        insurance_fund.protocol_shares = cast_to_u128(insurance_vault_amount)?;
+        insurance_fund.total_shares = insurance_fund.protocol_shares;
+    }
+
+    if insurance_fund.total_shares > 0 && insurance_vault_amount == 0 {
+        return Err(ErrorCode::InsuranceFundDepleted);
+    }
+
+    let mut shares =
+        calculate_shares_for_amount(amount, insurance_fund.total_shares, insurance_vault_amount)?;
+
+    if insurance_fund.protocol_shares < INSURANCE_FUND_DEAD_SHARES {
+        let dead_shares = INSURANCE_FUND_DEAD_SHARES - insurance_fund.protocol_shares;
+        shares = shares.saturating_sub(dead_shares);
+        insurance_fund.protocol_shares = INSURANCE_FUND_DEAD_SHARES;
+        insurance_fund.total_shares = insurance_fund
+            .total_shares
+            .checked_add(dead_shares)
+            .ok_or_else(math_error!())?;
+    }
+
+    if shares == 0 {
+        return Err(ErrorCode::InvalidInsuranceFundStake);
+    }
+
+    insurance_fund_stake.shares = insurance_fund_stake
+        .shares
+        .checked_add(shares)
+        .ok_or_else(math_error!())?;
+    insurance_fund.total_shares = insurance_fund
+        .total_shares
+        .checked_add(shares)
+        .ok_or_else(math_error!())?;
+
+    Ok(shares)
+}
+
+/// Returns the value of the shares requested. The shares stay staked and keep covering losses until
+/// they're removed.
+pub fn request_remove_stake(
+    insurance_fund: &InsuranceFund,
+    insurance_fund_stake: &mut InsuranceFundStake,
+    shares: u128,
+    insurance_vault_amount: u64,
+    now: UnixTimestamp,
+) -> ClearingHouseResult<u64> {
+    if insurance_fund_stake.last_withdraw_request_shares != 0 {
+        return Err(ErrorCode::InsuranceFundUnstakeRequestPending);
+    }
+
+    if shares == 0 || shares > insurance_fund_stake.shares {
+        return Err(ErrorCode::InvalidInsuranceFundStake);
+    }
+
+    let value =
+        calculate_amount_for_shares(shares, insurance_fund.total_shares, insurance_vault_amount)?;
+
+    insurance_fund_stake.last_withdraw_request_shares = shares;
+    insurance_fund_stake.last_withdraw_request_value = value;
+    insurance_fund_stake.last_withdraw_request_ts = now;
+
+    Ok(value)
+}
+
+pub fn cancel_request_remove_stake(
+    insurance_fund_stake: &mut InsuranceFundStake,
+) -> ClearingHouseResult<u128> {
+    let shares = insurance_fund_stake.last_withdraw_request_shares;
+    if shares == 0 {
+        return Err(ErrorCode::NoInsuranceFundUnstakeRequest);
+    }
+
+    insurance_fund_stake.last_withdraw_request_shares = 0;
+    insurance_fund_stake.last_withdraw_request_value = 0;
+    insurance_fund_stake.last_withdraw_request_ts = 0;
+
+    Ok(shares)
+}
+
+/// Returns the amount withdrawn and the shares burned. Stakers take the lower of the shares' value
+/// when they were requested and now, so they bear losses during the unstaking period without
+/// earning its gains.
+pub fn remove_stake(
+    insurance_fund: &mut InsuranceFund,
+    insurance_fund_stake: &mut InsuranceFundStake,
+    insurance_vault_amount: u64,
+    now: UnixTimestamp,
+) -> ClearingHouseResult<(u64, u128)> {
+    let shares = insurance_fund_stake.last_withdraw_request_shares;
+    if shares == 0 {
+        return Err(ErrorCode::NoInsuranceFundUnstakeRequest);
+    }
+
+    let unstakeable_ts = insurance_fund_stake
+        .last_withdraw_request_ts
+        .checked_add(insurance_fund.unstaking_period)
+        .ok_or_else(math_error!())?;
+    if now < unstakeable_ts {
+        return Err(ErrorCode::InsuranceFundUnstakingPeriodNotOver);
+    }
+
+    let amount = min(
+        insurance_fund_stake.last_withdraw_request_value,
+        calculate_amount_for_shares(shares, insurance_fund.total_shares, insurance_vault_amount)?,
+    );
+
+    insurance_fund_stake.shares = insurance_fund_stake
+        .shares
+        .checked_sub(shares)
+        .ok_or_else(math_error!())?;
+    insurance_fund.total_shares = insurance_fund
+        .total_shares
+        .checked_sub(shares)
+        .ok_or_else(math_error!())?;
+    cancel_request_remove_stake(insurance_fund_stake)?;
+
+    Ok((amount, shares))
+}
+
+/// Once users have staked, the admin can only withdraw what the protocol's shares are worth, less
+/// the dead shares while anyone else holds shares
+pub fn remove_protocol_stake(
+    insurance_fund: &mut InsuranceFund,
+    amount: u64,
+    insurance_vault_amount: u64,
+) -> ClearingHouseResult {
+    if insurance_fund.total_shares == 0 {
+        return Ok(());
+    }
+
+    let shares = calculate_shares_for_amount_rounded_up(
+        amount,
+        insurance_fund.total_shares,
+        insurance_vault_amount,
+    )?;
+    if shares > calculate_withdrawable_protocol_shares(insurance_fund) {
+        return Err(ErrorCode::AdminWithdrawTooLarge);
+    }
+
+    insurance_fund.protocol_shares = insurance_fund
+        .protocol_shares
+        .checked_sub(shares)
+        .ok_or_else(math_error!())?;
+    insurance_fund.total_shares = insurance_fund
+        .total_shares
+        .checked_sub(shares)
+        .ok_or_else(math_error!())?;
+
+    Ok(())
+}
+
+/// Returns the amount withdrawn. Takes everything the protocol's withdrawable shares are worth,
+/// which is the whole vault until someone stakes.
+pub fn remove_all_protocol_stake(
+    insurance_fund: &mut InsuranceFund,
+    insurance_vault_amount: u64,
+) -> ClearingHouseResult<u64> {
+    if insurance_fund.total_shares == 0 {
+        return Ok(insurance_vault_amount);
+    }
+
+    let amount = calculate_amount_for_shares(
+        calculate_withdrawable_protocol_shares(insurance_fund),
+        insurance_fund.total_shares,
+        insurance_vault_amount,
+    )?;
+
+    remove_protocol_stake(insurance_fund, amount, insurance_vault_amount)?;
+
+    Ok(amount)
+}
+
+fn calculate_withdrawable_protocol_shares(insurance_fund: &InsuranceFund) -> u128 {
+    if insurance_fund.protocol_shares == insurance_fund.total_shares {
+        insurance_fund.protocol_shares
+    } else {
+        insurance_fund
+            .protocol_shares
+            .saturating_sub(INSURANCE_FUND_DEAD_SHARES)
+    }
+}
+
+/// Takes the stakers' share of the fees the market has collected since it was last settled out of
+/// the market's fee pool. Returns the amount to move to the insurance vault.
+pub fn settle_fees_to_insurance_fund(
+    market: &mut Market,
+    insurance_fund: &InsuranceFund,
+) -> ClearingHouseResult<u64> {
+    let new_fees = market
+        .amm
+        .total_fee
+        .checked_sub(market.amm.total_fee_settled_to_insurance_fund)
+        .ok_or_else(math_error!())?;
+
+    let amount = min(
+        new_fees
+            .checked_mul(insurance_fund.fee_share_numerator)
+            .ok_or_else(math_error!())?
+            .checked_div(insurance_fund.fee_share_denominator)
+            .ok_or_else(math_error!())?,
+        market.amm.total_fee_minus_distributions,
+    );
+
+    market.amm.total_fee_minus_distributions = market
+        .amm
+        .total_fee_minus_distributions
+        .checked_sub(amount)
+        .ok_or_else(math_error!())?;
+    market.amm.total_fee_settled_to_insurance_fund = market.amm.total_fee;
+
+    cast(amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::QUOTE_PRECISION;
+
+    #[test]
+    fn first_stake_gives_dead_shares_to_protocol() {
+        let mut insurance_fund = InsuranceFund::default();
+        let mut insurance_fund_stake = InsuranceFundStake::default();
+
+        let shares = add_stake(
+            &mut insurance_fund,
+            &mut insurance_fund_stake,
+            10 * QUOTE_PRECISION as u64,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(shares, 9 * QUOTE_PRECISION);
+        let stake_shares = insurance_fund_stake.shares;
+        assert_eq!(stake_shares, 9 * QUOTE_PRECISION);
+        assert_eq!(insurance_fund.protocol_shares, INSURANCE_FUND_DEAD_SHARES);
+        assert_eq!(insurance_fund.total_shares, 10 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn vault_balance_before_first_stake_belongs_to_protocol() {
+        let mut insurance_fund = InsuranceFund::default();
+        let mut insurance_fund_stake = InsuranceFundStake::default();
+
+        let shares = add_stake(
+            &mut insurance_fund,
+            &mut insurance_fund_stake,
+            10 * QUOTE_PRECISION as u64,
+            5 * QUOTE_PRECISION as u64,
+        )
+        .unwrap();
+
+        assert_eq!(shares, 10 * QUOTE_PRECISION);
+        assert_eq!(insurance_fund.protocol_shares, 5 * QUOTE_PRECISION);
+        assert_eq!(insurance_fund.total_shares, 15 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn cant_stake_into_depleted_fund() {
+        let mut insurance_fund = InsuranceFund {
+            total_shares: 10 * QUOTE_PRECISION,
+            protocol_shares: INSURANCE_FUND_DEAD_SHARES,
+            ..InsuranceFund::default()
+        };
+        let mut insurance_fund_stake = InsuranceFundStake::default();
+
+        assert!(add_stake(&mut insurance_fund, &mut insurance_fund_stake, 100, 0).is_err());
+    }
+
+    #[test]
+    fn unstake_takes_lower_of_requested_and_current_value() {
+        let mut insurance_fund = InsuranceFund {
+            total_shares: 10 * QUOTE_PRECISION,
+            protocol_shares: INSURANCE_FUND_DEAD_SHARES,
+            unstaking_period: 100,
+            ..InsuranceFund::default()
+        };
+        let mut insurance_fund_stake = InsuranceFundStake {
+            shares: 9 * QUOTE_PRECISION,
+            ..InsuranceFundStake::default()
+        };
+
+        let value = request_remove_stake(
+            &insurance_fund,
+            &mut insurance_fund_stake,
+            9 * QUOTE_PRECISION,
+            10 * QUOTE_PRECISION as u64,
+            0,
+        )
+        .unwrap();
+        assert_eq!(value, 9 * QUOTE_PRECISION as u64);
+
+        // can't request again or unstake before the period is over
+        assert!(request_remove_stake(
+            &insurance_fund,
+            &mut insurance_fund_stake,
+            QUOTE_PRECISION,
+            10 * QUOTE_PRECISION as u64,
+            0,
+        )
+        .is_err());
+        assert!(remove_stake(
+            &mut insurance_fund,
+            &mut insurance_fund_stake,
+            10 * QUOTE_PRECISION as u64,
+            99,
+        )
+        .is_err());
+
+        // the vault lost half its value during the unstaking period
+        let (amount, shares) = remove_stake(
+            &mut insurance_fund,
+            &mut insurance_fund_stake,
+            5 * QUOTE_PRECISION as u64,
+            100,
+        )
+        .unwrap();
+        assert_eq!(amount, 4_500_000);
+        assert_eq!(shares, 9 * QUOTE_PRECISION);
+        let stake_shares = insurance_fund_stake.shares;
+        let last_withdraw_request_shares = insurance_fund_stake.last_withdraw_request_shares;
+        assert_eq!(stake_shares, 0);
+        assert_eq!(last_withdraw_request_shares, 0);
+        assert_eq!(insurance_fund.total_shares, INSURANCE_FUND_DEAD_SHARES);
+
+        // gains during the unstaking period stay in the fund
+        let mut insurance_fund_stake = InsuranceFundStake {
+            shares: QUOTE_PRECISION,
+            ..InsuranceFundStake::default()
+        };
+        insurance_fund.total_shares = 2 * QUOTE_PRECISION;
+        request_remove_stake(
+            &insurance_fund,
+            &mut insurance_fund_stake,
+            QUOTE_PRECISION,
+            2 * QUOTE_PRECISION as u64,
+            0,
+        )
+        .unwrap();
+        let (amount, _) = remove_stake(
+            &mut insurance_fund,
+            &mut insurance_fund_stake,
+            4 * QUOTE_PRECISION as u64,
+            100,
+        )
+        .unwrap();
+        assert_eq!(amount, QUOTE_PRECISION as u64);
+    }
+
+    #[test]
+    fn admin_cant_withdraw_dead_shares_while_others_staked() {
+        let mut insurance_fund = InsuranceFund {
+            total_shares: 10 * QUOTE_PRECISION,
+            protocol_shares: 2 * QUOTE_PRECISION,
+            ..InsuranceFund::default()
+        };
+
+        assert!(remove_protocol_stake(
+            &mut insurance_fund,
+            2 * QUOTE_PRECISION as u64,
+            10 * QUOTE_PRECISION as u64,
+        )
+        .is_err());
+
+        remove_protocol_stake(
+            &mut insurance_fund,
+            QUOTE_PRECISION as u64,
+            10 * QUOTE_PRECISION as u64,
+        )
+        .unwrap();
+        assert_eq!(insurance_fund.protocol_shares, INSURANCE_FUND_DEAD_SHARES);
+        assert_eq!(insurance_fund.total_shares, 9 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn removing_all_protocol_stake_keeps_stakers_share_value() {
+        let mut insurance_fund = InsuranceFund {
+            total_shares: 10 * QUOTE_PRECISION,
+            protocol_shares: 5 * QUOTE_PRECISION,
+            ..InsuranceFund::default()
+        };
+        let staker_shares = 5 * QUOTE_PRECISION;
+        let insurance_vault_amount = 20 * QUOTE_PRECISION as u64;
+
+        let value_before = calculate_amount_for_shares(
+            staker_shares,
+            insurance_fund.total_shares,
+            insurance_vault_amount,
+        )
+        .unwrap();
+
+        let amount =
+            remove_all_protocol_stake(&mut insurance_fund, insurance_vault_amount).unwrap();
+        assert_eq!(amount, 8 * QUOTE_PRECISION as u64);
+        assert_eq!(insurance_fund.protocol_shares, INSURANCE_FUND_DEAD_SHARES);
+
+        let value_after = calculate_amount_for_shares(
+            staker_shares,
+            insurance_fund.total_shares,
+            insurance_vault_amount - amount,
+        )
+        .unwrap();
+        assert_eq!(value_after, value_before);
+
+        // only the dead shares are left, so there's nothing more to take
+        let amount =
+            remove_all_protocol_stake(&mut insurance_fund, insurance_vault_amount - amount)
+                .unwrap();
+        assert_eq!(amount, 0);
+    }
+
+    #[test]
+    fn remove_all_protocol_stake_takes_whole_vault_before_anyone_staked() {
+        let mut insurance_fund = InsuranceFund::default();
+
+        let amount = remove_all_protocol_stake(&mut insurance_fund, 100).unwrap();
+        assert_eq!(amount, 100);
+        assert_eq!(insurance_fund.total_shares, 0);
+    }
+}
@@ -0,0 +1,268 @@
+use std::cell::RefMut;
+
+use anchor_lang::prelude::*;
+use anchor_spl::token::TokenAccount;
+
+use crate::controller::orders::MakerAccounts;
+use crate::error::{ClearingHouseResult, ErrorCode};
+use crate::math::casting::cast;
+use crate::math::lending::{calculate_balance_with_interest, calculate_cumulative_interest};
+use crate::math_error;
+use crate::state::collateral::{CollateralAssets, UserCollateralAssets};
+use crate::state::history::deposit::{DepositDirection, DepositHistory, DepositRecord};
+use crate::state::lending::LendingPool;
+use crate::state::state::State;
+use crate::state::user::User;
+use solana_program::clock::UnixTimestamp;
+use solana_program::msg;
+
+/// Accrues the pool's interest up to now
+pub fn update_lending_pool(
+    lending_pool: &mut LendingPool,
+    now: UnixTimestamp,
+) -> ClearingHouseResult {
+    if now <= lending_pool.last_interest_ts {
+        return Ok(());
+    }
+
+    let (cumulative_deposit_interest, cumulative_borrow_interest) =
+        calculate_cumulative_interest(lending_pool, now)?;
+
+    lending_pool.total_borrows = calculate_balance_with_interest(
+        lending_pool.total_borrows,
+        lending_pool.cumulative_borrow_interest,
+        cumulative_borrow_interest,
+    )?;
+    lending_pool.cumulative_deposit_interest = cumulative_deposit_interest;
+    lending_pool.cumulative_borrow_interest = cumulative_borrow_interest;
+    lending_pool.last_interest_ts = now;
+
+    Ok(())
+}
+
+/// Accrues the interest of the pool the asset is borrowed from, or of the quote pool if there's no
+/// asset index, and returns the nonce of the pool's vault authority
+pub fn update_borrowed_lending_pool(
+    state: &mut State,
+    collateral_assets: &mut CollateralAssets,
+    asset_index: Option<u64>,
+    vault: &Account<TokenAccount>,
+    vault_authority: &AccountInfo,
+    now: UnixTimestamp,
+) -> ClearingHouseResult<u8> {
+    match asset_index {
+        None => {
+            if !state.collateral_vault.eq(&vault.key())
+                || !state.collateral_vault_authority.eq(vault_authority.key)
+            {
+                return Err(ErrorCode::InvalidCollateralAsset);
+            }
+
+            update_lending_pool(&mut state.quote_lending_pool, now)?;
+            Ok(state.collateral_vault_nonce)
+        }
+        Some(asset_index) => {
+            let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+            if !collateral_asset.initialized
+                || !collateral_asset.vault.eq(&vault.key())
+                || !collateral_asset.vault_authority.eq(vault_authority.key)
+            {
+                return Err(ErrorCode::InvalidCollateralAsset);
+            }
+
+            update_lending_pool(&mut collateral_asset.lending_pool, now)?;
+            Ok(collateral_asset.vault_nonce)
+        }
+    }
+}
+
+/// The user's collateral is their quote deposit, so the quote deposit interest is paid into it.
+/// Like funding payments, the interest has to be settled before anything changes the user's
+/// collateral, and update_quote_deposits has to be called with the collateral the instruction
+/// leaves them with. Returns the user's collateral after the interest is paid.
+pub fn settle_quote_deposit_interest(
+    user: &mut Account<User>,
+    quote_lending_pool: &mut LendingPool,
+    deposit_history: &mut RefMut<DepositHistory>,
+    now: UnixTimestamp,
+) -> ClearingHouseResult<u128> {
+    let cumulative_deposit_interest = quote_lending_pool.cumulative_deposit_interest;
+    if user.last_cumulative_deposit_interest == cumulative_deposit_interest {
+        return Ok(user.collateral);
+    }
+
+    // Users created before lending was added join the pool's deposits when they're first settled
+    if user.last_cumulative_deposit_interest == 0 {
+        quote_lending_pool.total_deposits = quote_lending_pool
+            .total_deposits
+            .checked_add(user.collateral)
+            .ok_or_else(math_error!())?;
+        user.last_cumulative_deposit_interest = cumulative_deposit_interest;
+        return Ok(user.collateral);
+    }
+
+    let collateral_before = user.collateral;
+    let collateral = calculate_balance_with_interest(
+        collateral_before,
+        user.last_cumulative_deposit_interest,
+        cumulative_deposit_interest,
+    )?;
+    let interest = collateral
+        .checked_sub(collateral_before)
+        .ok_or_else(math_error!())?;
+
+    if interest > 0 {
+        let record_id = deposit_history.next_record_id();
+        deposit_history.append(DepositRecord {
+            ts: now,
+            record_id,
+            user_authority: user.authority,
+            user: user.key(),
+            direction: DepositDirection::InterestAccrual,
+            collateral_before,
+            cumulative_deposits_before: user.cumulative_deposits,
+            amount: cast(interest)?,
+        });
+
+        user.collateral = collateral;
+        quote_lending_pool.total_deposits = quote_lending_pool
+            .total_deposits
+            .checked_add(interest)
+            .ok_or_else(math_error!())?;
+    }
+
+    user.last_cumulative_deposit_interest = cumulative_deposit_interest;
+
+    Ok(user.collateral)
+}
+
+/// Moves the change an instruction made to a user's collateral into the quote pool's deposits, so
+/// the deposits the utilization is based on are the collateral that earns interest
+pub fn update_quote_deposits(
+    quote_lending_pool: &mut LendingPool,
+    collateral_before: u128,
+    collateral_after: u128,
+) -> ClearingHouseResult {
+    quote_lending_pool.total_deposits = quote_lending_pool
+        .total_deposits
+        .checked_add(collateral_after)
+        .ok_or_else(math_error!())?
+        .checked_sub(collateral_before)
+        .ok_or_else(math_error!())?;
+
+    Ok(())
+}
+
+/// Settles the quote deposit interest of a filler or liquidator. They can be the user account itself,
+/// whose interest is already settled and which is written back in their place, so None is returned
+/// for them.
+pub fn settle_quote_deposit_interest_unless_user(
+    counterparty: &mut Account<User>,
+    user_key: &Pubkey,
+    quote_lending_pool: &mut LendingPool,
+    deposit_history: &mut RefMut<DepositHistory>,
+    now: UnixTimestamp,
+) -> ClearingHouseResult<Option<u128>> {
+    if counterparty.key().eq(user_key) {
+        return Ok(None);
+    }
+
+    settle_quote_deposit_interest(counterparty, quote_lending_pool, deposit_history, now).map(Some)
+}
+
+/// Settles each maker's quote deposit interest and returns their collateral afterwards
+pub fn settle_makers_quote_deposit_interest(
+    makers: &mut [MakerAccounts],
+    quote_lending_pool: &mut LendingPool,
+    deposit_history: &mut RefMut<DepositHistory>,
+    now: UnixTimestamp,
+) -> ClearingHouseResult<Vec<u128>> {
+    makers
+        .iter_mut()
+        .map(|maker| {
+            settle_quote_deposit_interest(&mut maker.user, quote_lending_pool, deposit_history, now)
+        })
+        .collect()
+}
+
+/// Moves the changes to the makers' collateral into the quote pool's deposits. Makers are passed
+/// as remaining accounts, so their settled interest is written back here even if they weren't
+/// matched.
+pub fn update_makers_quote_deposits(
+    makers: &[MakerAccounts],
+    makers_collateral_settled: &[u128],
+    quote_lending_pool: &mut LendingPool,
+) -> ClearingHouseResult {
+    for (maker, collateral_settled) in makers.iter().zip(makers_collateral_settled) {
+        update_quote_deposits(
+            quote_lending_pool,
+            *collateral_settled,
+            maker.user.collateral,
+        )?;
+        maker
+            .user
+            .exit(&crate::ID)
+            .or(Err(ErrorCode::UnableToWriteToRemainingAccount))?;
+    }
+
+    Ok(())
+}
+
+/// Settles the interest on the user's collateral asset deposits and borrows and on their quote
+/// borrows. Must be called before any of their balances change.
+pub fn settle_collateral_assets_interest(
+    user_collateral_assets: &mut UserCollateralAssets,
+    collateral_assets: &mut CollateralAssets,
+    quote_lending_pool: &LendingPool,
+) -> ClearingHouseResult {
+    for (index, collateral_asset) in collateral_assets.assets.iter_mut().enumerate() {
+        if !collateral_asset.initialized {
+            continue;
+        }
+
+        let cumulative_deposit_interest = collateral_asset.lending_pool.cumulative_deposit_interest;
+        let balance_before = user_collateral_assets.balances[index];
+        let balance = calculate_balance_with_interest(
+            balance_before,
+            user_collateral_assets.last_cumulative_deposit_interest[index],
+            cumulative_deposit_interest,
+        )?;
+        collateral_asset.lending_pool.total_deposits = collateral_asset
+            .lending_pool
+            .total_deposits
+            .checked_add(
+                balance
+                    .checked_sub(balance_before)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?;
+        user_collateral_assets.balances[index] = balance;
+        user_collateral_assets.last_cumulative_deposit_interest[index] =
+            cumulative_deposit_interest;
+
+        let cumulative_borrow_interest = collateral_asset.lending_pool.cumulative_borrow_interest;
+        user_collateral_assets.borrows[index] = calculate_balance_with_interest(
+            user_collateral_assets.borrows[index],
+            user_collateral_assets.last_cumulative_borrow_interest[index],
+            cumulative_borrow_interest,
+        )?;
+        user_collateral_assets.last_cumulative_borrow_interest[index] = cumulative_borrow_interest;
+    }
+
+    let cumulative_quote_borrow_interest = quote_lending_pool.cumulative_borrow_interest;
+    user_collateral_assets.quote_borrows = calculate_balance_with_interest(
+        user_collateral_assets.quote_borrows,
+        user_collateral_assets.last_cumulative_quote_borrow_interest,
+        cumulative_quote_borrow_interest,
+    )?;
+    user_collateral_assets.last_cumulative_quote_borrow_interest = cumulative_quote_borrow_interest;
+
+    Ok(())
+}
+
+pub fn update_has_borrows(user: &mut User, user_collateral_assets: &UserCollateralAssets) {
+    let borrows = user_collateral_assets.borrows;
+    let has_borrows =
+        user_collateral_assets.quote_borrows > 0 || borrows.iter().any(|borrow| *borrow > 0);
+    user.has_borrows = has_borrows as u8;
+}
@@ -1,5 +1,8 @@
 pub mod amm;
+pub mod bankruptcy;
 pub mod funding;
+pub mod insurance_fund;
+pub mod lending;
 pub mod orders;
 pub mod position;
 pub mod repeg;
@@ -1,3 +1,4 @@
+use crate::controller::amm::SwapDirection;
 use crate::controller::position::{add_new_position, get_position_index};
 use crate::error::ClearingHouseResult;
 use crate::error::ErrorCode;
@@ -12,12 +13,14 @@ use std::cmp::min;
 use crate::context::*;
 use crate::math::{amm, fees, margin::*, orders::*};
 use crate::state::{
+    history::curve::ExtendedCurveHistory,
     history::order_history::{OrderHistory, OrderRecord},
-    history::trade::{TradeHistory, TradeRecord},
+    history::trade::{ExtendedTradeHistory, ExtendedTradeRecord},
     market::Markets,
+    order_book::{OrderBook, OrderBookEntry, ORDER_BOOK_SIDE_SIZE},
     order_state::*,
     state::*,
-    user::{User, UserPositions},
+    user::{MarketPosition, User, UserPositions},
     user_orders::*,
 };
 
@@ -32,13 +35,27 @@ use crate::order_validation::{
     validate_order_can_be_canceled,
 };
 use crate::state::history::funding_payment::FundingPaymentHistory;
-use crate::state::history::funding_rate::FundingRateHistory;
+use crate::state::history::funding_rate::ExtendedFundingRateHistory;
 use crate::state::history::order_history::OrderAction;
 use crate::state::market::Market;
 use spl_token::state::Account as TokenAccount;
-use std::cell::RefMut;
+use std::cell::{Ref, RefMut};
 use std::collections::BTreeMap;
 
+/// Accounts for a user whose resting order on the order book is matched during a fill
+pub struct MakerAccounts<'info> {
+    pub user: Box<Account<'info, User>>,
+    pub user_positions: AccountLoader<'info, UserPositions>,
+    pub user_orders: AccountLoader<'info, UserOrders>,
+}
+
+/// A maker that fills part of a taker order just in time, without resting an order on the book
+pub struct JitMaker<'a, 'info> {
+    pub user: &'a mut Box<Account<'info, User>>,
+    pub user_positions: &'a AccountLoader<'info, UserPositions>,
+    pub params: JitMakerParams,
+}
+
 pub fn place_order(
     state: &State,
     order_state: &OrderState,
@@ -53,12 +70,87 @@ pub fn place_order(
     clock: &Clock,
     params: OrderParams,
     oracle: Option<&AccountInfo>,
+    order_book: Option<&AccountLoader<OrderBook>>,
 ) -> ClearingHouseResult {
     let now = clock.unix_timestamp;
 
-    let user_positions = &mut user_positions
+    let user_positions = &mut UserPositions::load_positions_mut(user_positions)?;
+    let funding_payment_history = &mut funding_payment_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let markets = &markets
+        .load()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    controller::funding::settle_funding_payment(
+        user,
+        user_positions,
+        markets,
+        funding_payment_history,
+        now,
+    )?;
+
+    let user_orders = &mut user_orders
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let discount_tier = calculate_order_fee_tier(&state.fee_structure, discount_token)?;
+    let order_history = &mut order_history
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    let isolated_collateral = params.isolated_collateral;
+    place_order_with_loaded_accounts(
+        state,
+        order_state,
+        user,
+        user_positions,
+        markets,
+        user_orders,
+        order_history,
+        discount_tier,
+        referrer,
+        clock,
+        params,
+        oracle,
+        order_book,
+    )?;
+
+    // Moving collateral into an isolated position can't leave the cross margin positions undercollateralized
+    if isolated_collateral > 0
+        && !meets_initial_margin_requirement(
+            user,
+            user_positions,
+            spot_balances_value_lower_bound(user)?,
+            markets,
+        )?
+    {
+        return Err(ErrorCode::InsufficientCollateral);
+    }
+
+    Ok(())
+}
+
+/// Cancels and then places a batch of orders, settling funding and checking margin once for the
+/// whole batch. Oracles and order books are looked up by the market of each order.
+pub fn cancel_and_place_orders(
+    state: &State,
+    order_state: &OrderState,
+    user: &mut Box<Account<User>>,
+    user_positions: &AccountLoader<UserPositions>,
+    markets: &AccountLoader<Markets>,
+    user_orders: &AccountLoader<UserOrders>,
+    funding_payment_history: &AccountLoader<FundingPaymentHistory>,
+    order_history: &AccountLoader<OrderHistory>,
+    discount_token: Option<TokenAccount>,
+    referrer: &Option<Account<User>>,
+    clock: &Clock,
+    cancel_order_ids: Vec<u128>,
+    params: Vec<OrderParams>,
+    oracles: &BTreeMap<Pubkey, &AccountInfo>,
+    order_books: &BTreeMap<u64, AccountLoader<OrderBook>>,
+) -> ClearingHouseResult {
+    let now = clock.unix_timestamp;
+
+    let user_positions = &mut UserPositions::load_positions_mut(user_positions)?;
     let funding_payment_history = &mut funding_payment_history
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
@@ -76,15 +168,107 @@ pub fn place_order(
     let user_orders = &mut user_orders
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let order_history = &mut order_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    // Cancels go first so the replaced orders free up their slots
+    for order_id in cancel_order_ids.iter() {
+        let order_index = user_orders
+            .orders
+            .iter()
+            .position(|order| order.order_id == *order_id)
+            .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+        let order = &mut user_orders.orders[order_index];
+        let oracle = oracles
+            .get(&markets.get_market(order.market_index).amm.oracle)
+            .copied();
+
+        cancel_order_with_loaded_accounts(
+            state,
+            order,
+            user,
+            user_positions,
+            markets,
+            order_history,
+            clock,
+            oracle,
+            false,
+        )?;
+    }
+
+    let discount_tier = calculate_order_fee_tier(&state.fee_structure, discount_token)?;
+    let mut isolates_collateral = false;
+    for params in params.into_iter() {
+        let oracle = oracles
+            .get(&markets.get_market(params.market_index).amm.oracle)
+            .copied();
+        let order_book = if params.optional_accounts.order_book {
+            Some(
+                order_books
+                    .get(&params.market_index)
+                    .ok_or_else(print_error!(ErrorCode::InvalidOrderBook))?,
+            )
+        } else {
+            None
+        };
+
+        isolates_collateral = isolates_collateral || params.isolated_collateral > 0;
+
+        place_order_with_loaded_accounts(
+            state,
+            order_state,
+            user,
+            user_positions,
+            markets,
+            user_orders,
+            order_history,
+            discount_tier,
+            referrer,
+            clock,
+            params,
+            oracle,
+            order_book,
+        )?;
+    }
+
+    // Moving collateral into an isolated position can't leave the cross margin positions undercollateralized
+    if isolates_collateral
+        && !meets_initial_margin_requirement(
+            user,
+            user_positions,
+            spot_balances_value_lower_bound(user)?,
+            markets,
+        )?
+    {
+        return Err(ErrorCode::InsufficientCollateral);
+    }
+
+    Ok(())
+}
+
+fn place_order_with_loaded_accounts(
+    state: &State,
+    order_state: &OrderState,
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    markets: &Markets,
+    user_orders: &mut UserOrders,
+    order_history: &mut OrderHistory,
+    discount_tier: OrderDiscountTier,
+    referrer: &Option<Account<User>>,
+    clock: &Clock,
+    params: OrderParams,
+    oracle: Option<&AccountInfo>,
+    order_book: Option<&AccountLoader<OrderBook>>,
+) -> ClearingHouseResult {
+    let now = clock.unix_timestamp;
+
     let new_order_idx = user_orders
         .orders
         .iter()
         .position(|order| order.status.eq(&OrderStatus::Init))
         .ok_or(ErrorCode::MaxNumberOfOrders)?;
-    let discount_tier = calculate_order_fee_tier(&state.fee_structure, discount_token)?;
-    let order_history_account = &mut order_history
-        .load_mut()
-        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
 
     if params.user_order_id > 0 {
         let user_order_id_already_used = user_orders
@@ -104,12 +288,18 @@ pub fn place_order(
     // Increment open orders for existing position
     let position_index = get_position_index(user_positions, market_index)
         .or_else(|_| add_new_position(user_positions, market_index))?;
-    let market_position = &mut user_positions.positions[position_index];
+    let market_position = &mut user_positions[position_index];
+
+    // Move collateral into the position's isolated collateral if the user opts into isolated margin
+    if params.isolated_collateral > 0 {
+        controller::position::isolate_position(user, market_position, params.isolated_collateral)?;
+    }
+
     market_position.open_orders += 1;
 
     let base_asset_amount = get_base_asset_amount_for_order(&params, market, market_position);
 
-    let order_id = order_history_account.next_order_id();
+    let order_id = order_history.next_order_id();
     let new_order = Order {
         status: OrderStatus::Open,
         order_type: params.order_type,
@@ -135,6 +325,13 @@ pub fn place_order(
         },
         post_only: params.post_only,
         oracle_price_offset: params.oracle_price_offset,
+        trailing_distance: params.trailing_distance,
+        trailing_distance_type: params.trailing_distance_type,
+        slice_base_asset_amount: params.slice_base_asset_amount,
+        slice_interval: params.slice_interval,
+        last_slice_ts: 0,
+        linked_order_id: params.linked_order_id,
+        max_ts: params.max_ts,
         immediate_or_cancel: params.immediate_or_cancel,
         padding: [0; 3],
     };
@@ -149,11 +346,61 @@ pub fn place_order(
 
     validate_order(&new_order, market, order_state, valid_oracle_price)?;
 
+    if new_order.is_expired(now) {
+        msg!("Order max_ts {} has already passed", new_order.max_ts);
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    // Link the new order to an existing order so that once either fills, the other is canceled
+    if new_order.is_linked() {
+        let linked_order = user_orders
+            .orders
+            .iter_mut()
+            .find(|order| {
+                order.order_id == new_order.linked_order_id && order.status == OrderStatus::Open
+            })
+            .ok_or_else(print_error!(ErrorCode::InvalidLinkedOrder))?;
+
+        if linked_order.market_index != new_order.market_index || linked_order.is_linked() {
+            msg!("Linked order must be in the same market and can't already be linked");
+            return Err(ErrorCode::InvalidLinkedOrder);
+        }
+
+        linked_order.linked_order_id = new_order.order_id;
+    }
+
     user_orders.orders[new_order_idx] = new_order;
 
+    // Rest the order on the market's order book so it can be matched against taker orders
+    if let Some(order_book) = order_book {
+        if new_order.order_type != OrderType::Limit
+            || new_order.has_oracle_price_offset()
+            || new_order.immediate_or_cancel
+        {
+            msg!("Only limit orders with a fixed price can rest on the order book");
+            return Err(print_error!(ErrorCode::InvalidOrder)());
+        }
+
+        let order_book = &mut order_book
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let rests_on_book = order_book.insert(
+            new_order.direction,
+            OrderBookEntry {
+                user: user.key(),
+                order_id: new_order.order_id,
+                price: new_order.price,
+                ts: new_order.ts,
+            },
+        );
+        if !rests_on_book {
+            msg!("Order book side is full, order is only fillable against the amm");
+        }
+    }
+
     // Add to the order history account
-    let record_id = order_history_account.next_record_id();
-    order_history_account.append(OrderRecord {
+    let record_id = order_history.next_record_id();
+    order_history.append(OrderRecord {
         ts: now,
         record_id,
         order: new_order,
@@ -167,7 +414,8 @@ pub fn place_order(
         filler_reward: 0,
         fee: 0,
         quote_asset_amount_surplus: 0,
-        padding: [0; 8],
+        counterparty: Pubkey::default(),
+        padding: [0; 4],
     });
 
     Ok(())
@@ -326,9 +574,7 @@ pub fn cancel_order(
 ) -> ClearingHouseResult {
     let now = clock.unix_timestamp;
 
-    let user_positions = &mut user_positions
-        .load_mut()
-        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let user_positions = &mut UserPositions::load_positions_mut(user_positions)?;
     let funding_payment_history = &mut funding_payment_history
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
@@ -343,6 +589,36 @@ pub fn cancel_order(
         now,
     )?;
 
+    let order_history = &mut order_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    cancel_order_with_loaded_accounts(
+        state,
+        order,
+        user,
+        user_positions,
+        markets,
+        order_history,
+        clock,
+        oracle,
+        best_effort,
+    )
+}
+
+fn cancel_order_with_loaded_accounts(
+    state: &State,
+    order: &mut Order,
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    markets: &Ref<Markets>,
+    order_history: &mut OrderHistory,
+    clock: &Clock,
+    oracle: Option<&AccountInfo>,
+    best_effort: bool,
+) -> ClearingHouseResult {
+    let now = clock.unix_timestamp;
+
     if order.status != OrderStatus::Open {
         return Err(ErrorCode::OrderNotOpen);
     }
@@ -373,11 +649,8 @@ pub fn cancel_order(
     }
 
     // Add to the order history account
-    let order_history_account = &mut order_history
-        .load_mut()
-        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    let record_id = order_history_account.next_record_id();
-    order_history_account.append(OrderRecord {
+    let record_id = order_history.next_record_id();
+    order_history.append(OrderRecord {
         ts: now,
         record_id,
         order: *order,
@@ -391,307 +664,1550 @@ pub fn cancel_order(
         filler_reward: 0,
         fee: 0,
         quote_asset_amount_surplus: 0,
-        padding: [0; 8],
+        counterparty: Pubkey::default(),
+        padding: [0; 4],
     });
 
     // Decrement open orders for existing position
     let position_index = get_position_index(user_positions, order.market_index)?;
-    let market_position = &mut user_positions.positions[position_index];
+    let market_position = &mut user_positions[position_index];
     market_position.open_orders -= 1;
+    controller::position::release_isolated_collateral(user, market_position)?;
     *order = Order::default();
 
     Ok(())
 }
 
-pub fn expire_orders(
+/// Changes an open order in place, keeping its order_id. The modified order goes through the same
+/// validation as a newly placed order.
+pub fn modify_order(
+    order_id: u128,
+    state: &State,
+    order_state: &OrderState,
     user: &mut Box<Account<User>>,
     user_positions: &AccountLoader<UserPositions>,
+    markets: &AccountLoader<Markets>,
     user_orders: &AccountLoader<UserOrders>,
-    filler: &mut Box<Account<User>>,
+    funding_payment_history: &AccountLoader<FundingPaymentHistory>,
     order_history: &AccountLoader<OrderHistory>,
     clock: &Clock,
+    oracle: Option<&AccountInfo>,
+    order_book: Option<&AccountLoader<OrderBook>>,
+    params: ModifyOrderParams,
 ) -> ClearingHouseResult {
     let now = clock.unix_timestamp;
-    let ten_quote = 10 * QUOTE_PRECISION;
-
-    if user.collateral >= ten_quote {
-        msg!("User has more than ten quote asset, cant expire orders");
-        return Err(ErrorCode::CantExpireOrders);
-    }
 
-    let max_filler_reward = QUOTE_PRECISION / 100; // .01 quote asset
-    let filler_reward = min(user.collateral, max_filler_reward);
+    let user_positions = &mut UserPositions::load_positions_mut(user_positions)?;
+    let funding_payment_history = &mut funding_payment_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let markets = &markets
+        .load()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    controller::funding::settle_funding_payment(
+        user,
+        user_positions,
+        markets,
+        funding_payment_history,
+        now,
+    )?;
 
     let user_orders = &mut user_orders
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-
-    let expired_orders = user_orders
+    let order_index = user_orders
         .orders
         .iter()
-        .filter(|&order| order.status == OrderStatus::Open)
-        .count();
-    if expired_orders == 0 {
-        msg!("No orders to be expired");
-        return Err(ErrorCode::CantExpireOrders);
+        .position(|order| order.order_id == order_id)
+        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+    let order = &mut user_orders.orders[order_index];
+
+    if order.status != OrderStatus::Open {
+        return Err(ErrorCode::OrderNotOpen);
     }
 
-    user.collateral = calculate_updated_collateral(user.collateral, -(filler_reward as i128))?;
-    filler.collateral = calculate_updated_collateral(filler.collateral, filler_reward as i128)?;
+    if order.is_expired(now) {
+        msg!("Order expired at {}", order.max_ts);
+        return Err(ErrorCode::OrderExpired);
+    }
 
-    let filler_reward_per_order: i128 = cast_to_i128(filler_reward)? / (expired_orders as i128);
+    if order.order_type == OrderType::Market {
+        msg!("Market orders can not be modified");
+        return Err(ErrorCode::InvalidOrder);
+    }
 
-    let user_positions = &mut user_positions
-        .load_mut()
-        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    let order_history_account = &mut order_history
-        .load_mut()
-        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    for order in user_orders.orders.iter_mut() {
-        if order.status == OrderStatus::Init {
-            continue;
+    let market = markets.get_market(order.market_index);
+    if let Some(oracle) = oracle {
+        if !market.amm.oracle.eq(oracle.key) {
+            return Err(ErrorCode::InvalidOracle);
         }
-
-        order.fee = order
-            .fee
-            .checked_add(filler_reward_per_order)
-            .ok_or_else(math_error!())?;
-
-        // Add to the order history account
-        let record_id = order_history_account.next_record_id();
-        order_history_account.append(OrderRecord {
-            ts: now,
-            record_id,
-            order: *order,
-            user: user.key(),
-            authority: user.authority,
-            action: OrderAction::Expire,
-            filler: filler.key(),
-            trade_record_id: 0,
-            base_asset_amount_filled: 0,
-            quote_asset_amount_filled: 0,
-            filler_reward: filler_reward_per_order.unsigned_abs(),
-            fee: filler_reward_per_order,
-            quote_asset_amount_surplus: 0,
-            padding: [0; 8],
-        });
-
-        let position_index = get_position_index(user_positions, order.market_index)?;
-        let market_position = &mut user_positions.positions[position_index];
-        market_position.open_orders -= 1;
-        *order = Order::default();
     }
 
-    Ok(())
-}
-
-pub fn fill_order(
-    order_id: u128,
-    state: &State,
-    order_state: &OrderState,
+    // Modifying an order pulls its current version, so it's subject to the same checks as a cancel
+    let valid_oracle_price = get_valid_oracle_price(
+        oracle,
+        market,
+        order,
+        &state.oracle_guard_rails.validity,
+        clock.slot,
+    )?;
+    validate_order_can_be_canceled(order, user, user_positions, markets, valid_oracle_price)?;
+
+    let mut modified_order = *order;
+    if let Some(base_asset_amount) = params.base_asset_amount {
+        if base_asset_amount <= order.base_asset_amount_filled {
+            msg!(
+                "Order base asset amount must be greater than the {} already filled",
+                order.base_asset_amount_filled
+            );
+            return Err(ErrorCode::InvalidOrder);
+        }
+        modified_order.base_asset_amount = base_asset_amount;
+    }
+    if let Some(price) = params.price {
+        modified_order.price = price;
+    }
+    if let Some(oracle_price_offset) = params.oracle_price_offset {
+        modified_order.oracle_price_offset = oracle_price_offset;
+    }
+    if let Some(trigger_price) = params.trigger_price {
+        modified_order.trigger_price = trigger_price;
+    }
+
+    let valid_oracle_price = get_valid_oracle_price(
+        oracle,
+        market,
+        &modified_order,
+        &state.oracle_guard_rails.validity,
+        clock.slot,
+    )?;
+    validate_order(&modified_order, market, order_state, valid_oracle_price)?;
+
+    // A repriced order loses its place in the queue
+    if let Some(order_book) = order_book {
+        if modified_order.order_type != OrderType::Limit
+            || modified_order.has_oracle_price_offset()
+            || modified_order.immediate_or_cancel
+        {
+            msg!("Only limit orders with a fixed price can rest on the order book");
+            return Err(print_error!(ErrorCode::InvalidOrder)());
+        }
+
+        let order_book = &mut order_book
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        if let Some(entry_index) = order_book.find(modified_order.direction, order_id) {
+            order_book.remove(modified_order.direction, entry_index);
+        }
+        let rests_on_book = order_book.insert(
+            modified_order.direction,
+            OrderBookEntry {
+                user: user.key(),
+                order_id,
+                price: modified_order.price,
+                ts: now,
+            },
+        );
+        if !rests_on_book {
+            msg!("Order book side is full, order is only fillable against the amm");
+        }
+    }
+
+    *order = modified_order;
+
+    // Add to the order history account
+    let order_history_account = &mut order_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let record_id = order_history_account.next_record_id();
+    order_history_account.append(OrderRecord {
+        ts: now,
+        record_id,
+        order: *order,
+        user: user.key(),
+        authority: user.authority,
+        action: OrderAction::Modify,
+        filler: Pubkey::default(),
+        trade_record_id: 0,
+        base_asset_amount_filled: 0,
+        quote_asset_amount_filled: 0,
+        filler_reward: 0,
+        fee: 0,
+        quote_asset_amount_surplus: 0,
+        counterparty: Pubkey::default(),
+        padding: [0; 4],
+    });
+
+    Ok(())
+}
+
+pub fn expire_orders(
     user: &mut Box<Account<User>>,
     user_positions: &AccountLoader<UserPositions>,
-    markets: &AccountLoader<Markets>,
-    oracle: &AccountInfo,
     user_orders: &AccountLoader<UserOrders>,
     filler: &mut Box<Account<User>>,
-    funding_payment_history: &AccountLoader<FundingPaymentHistory>,
-    trade_history: &AccountLoader<TradeHistory>,
     order_history: &AccountLoader<OrderHistory>,
-    funding_rate_history: &AccountLoader<FundingRateHistory>,
-    referrer: Option<Account<User>>,
     clock: &Clock,
-) -> ClearingHouseResult<u128> {
+) -> ClearingHouseResult {
     let now = clock.unix_timestamp;
-    let clock_slot = clock.slot;
+    let ten_quote = 10 * QUOTE_PRECISION;
+
+    if user.collateral >= ten_quote {
+        msg!("User has more than ten quote asset, cant expire orders");
+        return Err(ErrorCode::CantExpireOrders);
+    }
 
-    let user_positions = &mut user_positions
+    let max_filler_reward = QUOTE_PRECISION / 100; // .01 quote asset
+    let filler_reward = min(user.collateral, max_filler_reward);
+
+    let user_orders = &mut user_orders
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    let funding_payment_history = &mut funding_payment_history
+
+    let expired_orders = user_orders
+        .orders
+        .iter()
+        .filter(|&order| order.status == OrderStatus::Open)
+        .count();
+    if expired_orders == 0 {
+        msg!("No orders to be expired");
+        return Err(ErrorCode::CantExpireOrders);
+    }
+
+    user.collateral = calculate_updated_collateral(user.collateral, -(filler_reward as i128))?;
+    filler.collateral = calculate_updated_collateral(filler.collateral, filler_reward as i128)?;
+
+    let filler_reward_per_order: i128 = cast_to_i128(filler_reward)? / (expired_orders as i128);
+
+    let user_positions = &mut UserPositions::load_positions_mut(user_positions)?;
+    let order_history_account = &mut order_history
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    {
-        let markets = &markets
-            .load()
-            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-        controller::funding::settle_funding_payment(
-            user,
-            user_positions,
-            markets,
-            funding_payment_history,
-            now,
-        )?;
+    for order in user_orders.orders.iter_mut() {
+        if order.status == OrderStatus::Init {
+            continue;
+        }
+
+        order.fee = order
+            .fee
+            .checked_add(filler_reward_per_order)
+            .ok_or_else(math_error!())?;
+
+        // Add to the order history account
+        let record_id = order_history_account.next_record_id();
+        order_history_account.append(OrderRecord {
+            ts: now,
+            record_id,
+            order: *order,
+            user: user.key(),
+            authority: user.authority,
+            action: OrderAction::Expire,
+            filler: filler.key(),
+            trade_record_id: 0,
+            base_asset_amount_filled: 0,
+            quote_asset_amount_filled: 0,
+            filler_reward: filler_reward_per_order.unsigned_abs(),
+            fee: filler_reward_per_order,
+            quote_asset_amount_surplus: 0,
+            counterparty: Pubkey::default(),
+            padding: [0; 4],
+        });
+
+        let position_index = get_position_index(user_positions, order.market_index)?;
+        let market_position = &mut user_positions[position_index];
+        market_position.open_orders -= 1;
+        controller::position::release_isolated_collateral(user, market_position)?;
+        *order = Order::default();
     }
 
+    Ok(())
+}
+
+/// Cancels the user's orders whose max_ts has passed. The caller is paid the minimum filler reward
+/// for each expired order, taken from the order owner's collateral.
+pub fn cancel_expired_orders(
+    order_state: &OrderState,
+    user: &mut Box<Account<User>>,
+    user_positions: &AccountLoader<UserPositions>,
+    user_orders: &AccountLoader<UserOrders>,
+    filler: &mut Box<Account<User>>,
+    order_history: &AccountLoader<OrderHistory>,
+    clock: &Clock,
+) -> ClearingHouseResult {
+    let now = clock.unix_timestamp;
+
     let user_orders = &mut user_orders
         .load_mut()
         .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    let order_index = user_orders
+
+    let expired_orders = user_orders
         .orders
         .iter()
-        .position(|order| order.order_id == order_id)
-        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
-    let order = &mut user_orders.orders[order_index];
+        .filter(|&order| order.status == OrderStatus::Open && order.is_expired(now))
+        .count();
+    if expired_orders == 0 {
+        msg!("No orders past their max_ts");
+        return Err(ErrorCode::CantExpireOrders);
+    }
 
-    if order.status != OrderStatus::Open {
-        return Err(ErrorCode::OrderNotOpen);
+    let user_positions = &mut UserPositions::load_positions_mut(user_positions)?;
+    let order_history_account = &mut order_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    for order in user_orders.orders.iter_mut() {
+        if order.status != OrderStatus::Open || !order.is_expired(now) {
+            continue;
+        }
+
+        let filler_reward = min(
+            user.collateral,
+            order_state
+                .order_filler_reward_structure
+                .time_based_reward_lower_bound,
+        );
+        user.collateral = calculate_updated_collateral(user.collateral, -(filler_reward as i128))?;
+        filler.collateral = calculate_updated_collateral(filler.collateral, filler_reward as i128)?;
+
+        let fee = cast_to_i128(filler_reward)?;
+        order.fee = order.fee.checked_add(fee).ok_or_else(math_error!())?;
+
+        // Add to the order history account
+        let record_id = order_history_account.next_record_id();
+        order_history_account.append(OrderRecord {
+            ts: now,
+            record_id,
+            order: *order,
+            user: user.key(),
+            authority: user.authority,
+            action: OrderAction::ExpireMaxTs,
+            filler: filler.key(),
+            trade_record_id: 0,
+            base_asset_amount_filled: 0,
+            quote_asset_amount_filled: 0,
+            filler_reward,
+            fee,
+            quote_asset_amount_surplus: 0,
+            counterparty: Pubkey::default(),
+            padding: [0; 4],
+        });
+
+        let position_index = get_position_index(user_positions, order.market_index)?;
+        let market_position = &mut user_positions[position_index];
+        market_position.open_orders -= 1;
+        controller::position::release_isolated_collateral(user, market_position)?;
+        *order = Order::default();
     }
 
-    let market_index = order.market_index;
-    {
-        let markets = &markets
-            .load()
-            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-        let market = markets.get_market(market_index);
+    Ok(())
+}
 
-        if !market.initialized {
-            return Err(ErrorCode::MarketIndexNotInitialized);
+/// Once one order of a one-cancels-other pair starts filling, the other one is canceled
+fn cancel_linked_order(
+    linked_order_id: u128,
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    user_orders: &mut RefMut<UserOrders>,
+    order_history: &mut RefMut<OrderHistory>,
+    now: i64,
+) -> ClearingHouseResult {
+    let linked_order = user_orders
+        .orders
+        .iter_mut()
+        .find(|order| order.order_id == linked_order_id && order.status == OrderStatus::Open);
+
+    if let Some(linked_order) = linked_order {
+        remove_linked_order(linked_order, user, user_positions, order_history, now)?;
+    }
+
+    Ok(())
+}
+
+/// Linked orders are attached to the position in their market, so they're canceled once the
+/// position is closed. The user's orders only have to be passed in if the position has open orders.
+pub fn cancel_linked_orders_for_closed_position(
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    user_orders_accounts: Option<(&AccountLoader<UserOrders>, &AccountLoader<OrderHistory>)>,
+    market_index: u64,
+    now: i64,
+) -> ClearingHouseResult {
+    let position_index = match get_position_index(user_positions, market_index) {
+        Ok(position_index) => position_index,
+        Err(_) => return Ok(()),
+    };
+
+    let market_position = &user_positions[position_index];
+    if market_position.base_asset_amount != 0 || market_position.open_orders == 0 {
+        return Ok(());
+    }
+
+    let (user_orders, order_history) = match user_orders_accounts {
+        Some(user_orders_accounts) => user_orders_accounts,
+        None => {
+            msg!("User orders required to cancel linked orders for closed position");
+            return Err(ErrorCode::UserOrdersNotFound);
         }
+    };
 
-        if !market.amm.oracle.eq(oracle.key) {
-            return Err(ErrorCode::InvalidOracle);
+    let user_orders = &mut user_orders
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let order_history = &mut order_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    for order in user_orders.orders.iter_mut() {
+        if order.status != OrderStatus::Open
+            || order.market_index != market_index
+            || !order.is_linked()
+        {
+            continue;
         }
+
+        remove_linked_order(order, user, user_positions, order_history, now)?;
     }
 
-    let mark_price_before: u128;
-    let oracle_mark_spread_pct_before: i128;
-    let is_oracle_valid: bool;
-    let oracle_price: i128;
+    Ok(())
+}
+
+fn remove_linked_order(
+    order: &mut Order,
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    order_history: &mut RefMut<OrderHistory>,
+    now: i64,
+) -> ClearingHouseResult {
+    // Add to the order history account
+    let record_id = order_history.next_record_id();
+    order_history.append(OrderRecord {
+        ts: now,
+        record_id,
+        order: *order,
+        user: user.key(),
+        authority: user.authority,
+        action: OrderAction::Cancel,
+        filler: Pubkey::default(),
+        trade_record_id: 0,
+        base_asset_amount_filled: 0,
+        quote_asset_amount_filled: 0,
+        filler_reward: 0,
+        fee: 0,
+        quote_asset_amount_surplus: 0,
+        counterparty: Pubkey::default(),
+        padding: [0; 4],
+    });
+
+    let position_index = get_position_index(user_positions, order.market_index)?;
+    let market_position = &mut user_positions[position_index];
+    market_position.open_orders -= 1;
+    controller::position::release_isolated_collateral(user, market_position)?;
+    *order = Order::default();
+
+    Ok(())
+}
+
+pub fn update_trailing_stop(
+    order_id: u128,
+    state: &State,
+    user: &Account<User>,
+    markets: &AccountLoader<Markets>,
+    oracle: &AccountInfo,
+    user_orders: &AccountLoader<UserOrders>,
+    order_history: &AccountLoader<OrderHistory>,
+    clock: &Clock,
+) -> ClearingHouseResult {
+    let now = clock.unix_timestamp;
+
+    let user_orders = &mut user_orders
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let order_index = user_orders
+        .orders
+        .iter()
+        .position(|order| order.order_id == order_id)
+        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+    let order = &mut user_orders.orders[order_index];
+
+    if order.status != OrderStatus::Open {
+        return Err(ErrorCode::OrderNotOpen);
+    }
+
+    if order.order_type != OrderType::TrailingStop {
+        msg!("Order is not a trailing stop");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    let trigger_price = {
+        let markets = &markets
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market(order.market_index);
+
+        if !market.amm.oracle.eq(oracle.key) {
+            return Err(ErrorCode::InvalidOracle);
+        }
+
+        let valid_oracle_price = get_valid_oracle_price(
+            Some(oracle),
+            market,
+            order,
+            &state.oracle_guard_rails.validity,
+            clock.slot,
+        )?;
+
+        calculate_trailing_stop_trigger_price(order, market.amm.mark_price()?, valid_oracle_price)?
+    };
+
+    order.trigger_price = match trigger_price {
+        Some(trigger_price) => trigger_price,
+        None => {
+            msg!("Price hasn't moved in the order's favor");
+            return Err(ErrorCode::CouldNotUpdateTrailingStop);
+        }
+    };
+
+    let order_history_account = &mut order_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let record_id = order_history_account.next_record_id();
+    order_history_account.append(OrderRecord {
+        ts: now,
+        record_id,
+        order: *order,
+        user: user.key(),
+        authority: user.authority,
+        action: OrderAction::TriggerUpdate,
+        filler: Pubkey::default(),
+        trade_record_id: 0,
+        base_asset_amount_filled: 0,
+        quote_asset_amount_filled: 0,
+        filler_reward: 0,
+        fee: 0,
+        quote_asset_amount_surplus: 0,
+        counterparty: Pubkey::default(),
+        padding: [0; 4],
+    });
+
+    Ok(())
+}
+
+pub fn fill_order(
+    order_id: u128,
+    state: &State,
+    order_state: &OrderState,
+    user: &mut Box<Account<User>>,
+    user_positions: &AccountLoader<UserPositions>,
+    spot_balances_value: i128,
+    markets: &AccountLoader<Markets>,
+    oracle: &AccountInfo,
+    user_orders: &AccountLoader<UserOrders>,
+    filler: &mut Box<Account<User>>,
+    funding_payment_history: &AccountLoader<FundingPaymentHistory>,
+    trade_history: &AccountLoader<ExtendedTradeHistory>,
+    order_history: &AccountLoader<OrderHistory>,
+    funding_rate_history: &AccountLoader<ExtendedFundingRateHistory>,
+    extended_curve_history: &AccountLoader<ExtendedCurveHistory>,
+    mut referrer: Option<Account<User>>,
+    order_book: Option<&AccountLoader<OrderBook>>,
+    makers: &mut [MakerAccounts],
+    jit_maker: Option<JitMaker>,
+    clock: &Clock,
+) -> ClearingHouseResult<u128> {
+    let now = clock.unix_timestamp;
+    let clock_slot = clock.slot;
+
+    let user_positions = &mut UserPositions::load_positions_mut(user_positions)?;
+    let funding_payment_history = &mut funding_payment_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    {
+        let markets = &markets
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            markets,
+            funding_payment_history,
+            now,
+        )?;
+    }
+
+    let user_orders = &mut user_orders
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let order_index = user_orders
+        .orders
+        .iter()
+        .position(|order| order.order_id == order_id)
+        .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+    let order = &mut user_orders.orders[order_index];
+
+    if order.status != OrderStatus::Open {
+        return Err(ErrorCode::OrderNotOpen);
+    }
+
+    if order.is_expired(now) {
+        msg!("Order expired at {}", order.max_ts);
+        return Err(ErrorCode::OrderExpired);
+    }
+
+    let market_index = order.market_index;
+    {
+        let markets = &markets
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market(market_index);
+
+        if !market.initialized {
+            return Err(ErrorCode::MarketIndexNotInitialized);
+        }
+
+        if !market.amm.oracle.eq(oracle.key) {
+            return Err(ErrorCode::InvalidOracle);
+        }
+    }
+
+    let mark_price_before: u128;
+    let oracle_mark_spread_pct_before: i128;
+    let is_oracle_valid: bool;
+    let oracle_price: i128;
+    {
+        let markets = &mut markets
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market_mut(market_index);
+        let oracle_price_data = &market.amm.get_oracle_price(oracle, clock_slot)?;
+        is_oracle_valid = amm::is_oracle_valid(
+            &market.amm,
+            oracle_price_data,
+            &state.oracle_guard_rails.validity,
+        )?;
+        if is_oracle_valid {
+            let mark_price = market.amm.mark_price()?;
+            let trade_record = trade_history
+                .load()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?
+                .next_record_id();
+            let extended_curve_history = &mut extended_curve_history
+                .load_mut()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+            controller::repeg::formulaic_repeg(
+                market,
+                market_index,
+                oracle_price_data,
+                mark_price,
+                state,
+                extended_curve_history,
+                trade_record,
+                now,
+            )?;
+        }
+        mark_price_before = market.amm.mark_price()?;
+        oracle_mark_spread_pct_before = amm::calculate_oracle_mark_spread_pct(
+            &market.amm,
+            oracle_price_data,
+            Some(mark_price_before),
+        )?;
+        oracle_price = oracle_price_data.price;
+        let normalised_price =
+            normalise_oracle_price(&market.amm, oracle_price_data, Some(mark_price_before))?;
+        if is_oracle_valid {
+            amm::update_oracle_price_twap(&mut market.amm, now, normalised_price)?;
+        }
+        controller::amm::update_spreads(
+            &mut market.amm,
+            market.base_asset_amount,
+            if is_oracle_valid {
+                Some(oracle_price_data)
+            } else {
+                None
+            },
+            mark_price_before,
+        )?;
+    }
+
+    let valid_oracle_price = if is_oracle_valid {
+        Some(oracle_price)
+    } else {
+        None
+    };
+
+    let (base_asset_amount_matched_on_book, book_potentially_risk_increasing) = match order_book {
+        Some(order_book) => match_order_with_order_book(
+            state,
+            order_state,
+            order,
+            user,
+            user_positions,
+            spot_balances_value,
+            markets,
+            order_book,
+            makers,
+            filler,
+            &mut referrer,
+            funding_payment_history,
+            trade_history,
+            order_history,
+            mark_price_before,
+            oracle_price,
+            valid_oracle_price,
+            now,
+        )?,
+        None => (0, false),
+    };
+
+    let (base_asset_amount_jit, jit_potentially_risk_increasing) = match jit_maker {
+        Some(jit_maker) => fill_order_with_jit_maker(
+            state,
+            order_state,
+            order,
+            user,
+            user_positions,
+            spot_balances_value,
+            markets,
+            jit_maker,
+            filler,
+            &mut referrer,
+            funding_payment_history,
+            trade_history,
+            order_history,
+            mark_price_before,
+            oracle_price,
+            valid_oracle_price,
+            now,
+        )?,
+        None => (0, false),
+    };
+
+    let base_asset_amount_matched = base_asset_amount_matched_on_book
+        .checked_add(base_asset_amount_jit)
+        .ok_or_else(math_error!())?;
+    let matched_potentially_risk_increasing =
+        book_potentially_risk_increasing || jit_potentially_risk_increasing;
+
+    // The rest of the order is filled against the amm
+    let (
+        base_asset_amount,
+        quote_asset_amount,
+        potentially_risk_increasing,
+        quote_asset_amount_surplus,
+    ) = if base_asset_amount_matched > 0
+        && order.base_asset_amount == order.base_asset_amount_filled
+    {
+        (0, 0, false, 0)
+    } else {
+        execute_order(
+            user,
+            user_positions,
+            spot_balances_value,
+            order,
+            &mut markets
+                .load_mut()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
+            market_index,
+            mark_price_before,
+            now,
+            valid_oracle_price,
+        )?
+    };
+
+    if base_asset_amount == 0 && base_asset_amount_matched == 0 {
+        return Ok(0);
+    }
+
+    // Read before the order can be reset
+    let linked_order_id = order.linked_order_id;
+
+    let mark_price_after: u128;
+    let oracle_price_after: i128;
+    let oracle_mark_spread_pct_after: i128;
+    let spread: u128;
+    {
+        let markets = &mut markets
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market_mut(market_index);
+        mark_price_after = market.amm.mark_price()?;
+        // post only orders are filled at their limit price without the spread
+        spread = if order.post_only {
+            0
+        } else {
+            amm::get_spread(&market.amm, order.direction)
+        };
+        let oracle_price_data = &market.amm.get_oracle_price(oracle, clock_slot)?;
+        oracle_mark_spread_pct_after = amm::calculate_oracle_mark_spread_pct(
+            &market.amm,
+            oracle_price_data,
+            Some(mark_price_after),
+        )?;
+        oracle_price_after = oracle_price_data.price;
+    }
+
+    let is_oracle_mark_too_divergent_before = amm::is_oracle_mark_too_divergent(
+        oracle_mark_spread_pct_before,
+        &state.oracle_guard_rails.price_divergence,
+    )?;
+
+    let is_oracle_mark_too_divergent_after = amm::is_oracle_mark_too_divergent(
+        oracle_mark_spread_pct_after,
+        &state.oracle_guard_rails.price_divergence,
+    )?;
+
+    // if oracle-mark divergence pushed outside limit, block order
+    if is_oracle_mark_too_divergent_after && !is_oracle_mark_too_divergent_before && is_oracle_valid
+    {
+        return Err(ErrorCode::OracleMarkSpreadLimit);
+    }
+
+    // if oracle-mark divergence outside limit and risk-increasing, block order
+    if is_oracle_mark_too_divergent_after
+        && oracle_mark_spread_pct_after.unsigned_abs()
+            >= oracle_mark_spread_pct_before.unsigned_abs()
+        && is_oracle_valid
+        && potentially_risk_increasing
+    {
+        return Err(ErrorCode::OracleMarkSpreadLimit);
+    }
+
+    // Order fails if it's risk increasing and it brings the user collateral below the margin requirement
+    let meets_maintenance_requirement = meets_margin_requirement_for_market(
+        user,
+        user_positions,
+        spot_balances_value,
+        &markets
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
+        market_index,
+        if order.post_only {
+            // for post only orders allow user to fill up to partial margin requirement
+            MarginType::Partial
+        } else {
+            MarginType::Init
+        },
+    )?;
+    if !meets_maintenance_requirement
+        && (potentially_risk_increasing || matched_potentially_risk_increasing)
+    {
+        return Err(ErrorCode::InsufficientCollateral);
+    }
+
+    let discount_tier = order.discount_tier;
+    let (user_fee, fee_to_market, token_discount, filler_reward, referrer_reward, referee_discount) =
+        fees::calculate_fee_for_order(
+            quote_asset_amount,
+            &state.fee_structure,
+            &order_state.order_filler_reward_structure,
+            &discount_tier,
+            order.get_filler_reward_ts()?,
+            now,
+            &referrer,
+            filler.key() == user.key(),
+            quote_asset_amount_surplus,
+            order.post_only,
+        )?;
+
+    // Increment the clearing house's total fee variables
+    {
+        let markets = &mut markets
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market_mut(market_index);
+        market.amm.total_fee = market
+            .amm
+            .total_fee
+            .checked_add(fee_to_market)
+            .ok_or_else(math_error!())?;
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .checked_add(fee_to_market)
+            .ok_or_else(math_error!())?;
+    }
+
+    // Update the collateral backing the position based on fee/rebate
+    {
+        let position_index = get_position_index(user_positions, market_index)?;
+        controller::position::update_collateral_for_fee(
+            user,
+            &mut user_positions[position_index],
+            user_fee,
+        )?;
+    }
+
+    // Increment the user's total fee variables
+    if user_fee > 0 {
+        user.total_fee_paid = user
+            .total_fee_paid
+            .checked_add(cast(user_fee.unsigned_abs())?)
+            .ok_or_else(math_error!())?;
+    } else {
+        user.total_fee_rebate = user
+            .total_fee_rebate
+            .checked_add(cast(user_fee.unsigned_abs())?)
+            .ok_or_else(math_error!())?;
+    }
+
+    user.total_token_discount = user
+        .total_token_discount
+        .checked_add(token_discount)
+        .ok_or_else(math_error!())?;
+    user.total_referee_discount = user
+        .total_referee_discount
+        .checked_add(referee_discount)
+        .ok_or_else(math_error!())?;
+
+    filler.collateral = filler
+        .collateral
+        .checked_add(cast(filler_reward)?)
+        .ok_or_else(math_error!())?;
+
+    // Update the referrer's collateral with their reward
+    if let Some(mut referrer) = referrer {
+        referrer.total_referral_reward = referrer
+            .total_referral_reward
+            .checked_add(referrer_reward)
+            .ok_or_else(math_error!())?;
+        referrer
+            .exit(&crate::ID)
+            .or(Err(ErrorCode::UnableToWriteToRemainingAccount))?;
+    }
+
+    {
+        let markets = &mut markets
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market(market_index);
+        update_order_after_trade(
+            order,
+            market.amm.minimum_base_asset_trade_size,
+            base_asset_amount,
+            quote_asset_amount,
+            user_fee,
+        )?;
+    }
+
+    if order.order_type == OrderType::Twap {
+        order.last_slice_ts = now;
+    }
+
+    if base_asset_amount > 0 {
+        let trade_history_account = &mut trade_history
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let trade_record_id = trade_history_account.next_record_id();
+        trade_history_account.append(ExtendedTradeRecord {
+            ts: now,
+            record_id: trade_record_id,
+            user_authority: user.authority,
+            user: *user.to_account_info().key,
+            direction: order.direction,
+            base_asset_amount,
+            quote_asset_amount,
+            mark_price_before,
+            mark_price_after,
+            fee: user_fee,
+            token_discount,
+            quote_asset_amount_surplus,
+            referee_discount,
+            liquidation: false,
+            market_index,
+            oracle_price: oracle_price_after,
+            maker: Pubkey::default(),
+            maker_fee: 0,
+            spread,
+            padding: [0; 4],
+        });
+
+        let order_history_account = &mut order_history
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let record_id = order_history_account.next_record_id();
+        order_history_account.append(OrderRecord {
+            ts: now,
+            record_id,
+            order: *order,
+            user: user.key(),
+            authority: user.authority,
+            action: OrderAction::Fill,
+            filler: filler.key(),
+            trade_record_id,
+            base_asset_amount_filled: base_asset_amount,
+            quote_asset_amount_filled: quote_asset_amount,
+            filler_reward,
+            fee: user_fee,
+            quote_asset_amount_surplus,
+            counterparty: Pubkey::default(),
+            padding: [0; 4],
+        });
+    }
+
+    // Cant reset order until after its been logged in order history
+    if order.base_asset_amount == order.base_asset_amount_filled
+        || order.order_type == OrderType::Market
+    {
+        *order = Order::default();
+        let position_index = get_position_index(user_positions, market_index)?;
+        let market_position = &mut user_positions[position_index];
+        market_position.open_orders -= 1;
+        controller::position::release_isolated_collateral(user, market_position)?;
+    }
+
+    if linked_order_id != 0 {
+        cancel_linked_order(
+            linked_order_id,
+            user,
+            user_positions,
+            user_orders,
+            &mut order_history
+                .load_mut()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
+            now,
+        )?;
+    }
+
+    // Try to update the funding rate at the end of every trade
+    {
+        let markets = &mut markets
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market_mut(market_index);
+        let funding_rate_history = &mut funding_rate_history
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        controller::funding::update_funding_rate(
+            market_index,
+            market,
+            oracle,
+            now,
+            clock_slot,
+            funding_rate_history,
+            &state.oracle_guard_rails,
+            state.funding_paused,
+            Some(mark_price_before),
+        )?;
+    }
+
+    base_asset_amount
+        .checked_add(base_asset_amount_matched)
+        .ok_or_else(math_error!())
+}
+
+/// Matches the taker order against resting maker orders on the order book in price-time priority.
+/// Matching stops at the first entry that doesn't cross the taker's price, is worse for the taker
+/// than the amm's mark price or whose maker accounts weren't passed in, so a filler can't skip
+/// makers with better prices or earlier orders.
+/// Trades happen at the maker's limit price and don't move the amm.
+pub fn match_order_with_order_book(
+    state: &State,
+    order_state: &OrderState,
+    order: &mut Order,
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    spot_balances_value: i128,
+    markets: &AccountLoader<Markets>,
+    order_book: &AccountLoader<OrderBook>,
+    makers: &mut [MakerAccounts],
+    filler: &mut Box<Account<User>>,
+    referrer: &mut Option<Account<User>>,
+    funding_payment_history: &mut RefMut<FundingPaymentHistory>,
+    trade_history: &AccountLoader<ExtendedTradeHistory>,
+    order_history: &AccountLoader<OrderHistory>,
+    mark_price: u128,
+    oracle_price: i128,
+    valid_oracle_price: Option<i128>,
+    now: i64,
+) -> ClearingHouseResult<(u128, bool)> {
+    // Post only orders can only provide liquidity and trigger orders are only filled by the amm
+    if order.post_only
+        || order.base_asset_amount == 0
+        || !matches!(order.order_type, OrderType::Market | OrderType::Limit)
+    {
+        return Ok((0, false));
+    }
+
+    // Each maker's user account is written back separately, so it can't alias another user account
+    // in the instruction
+    let referrer_key = referrer.as_ref().map(|referrer| referrer.key());
+    for (i, maker) in makers.iter().enumerate() {
+        let maker_key = maker.user.key();
+        if maker_key == user.key()
+            || maker_key == filler.key()
+            || Some(maker_key) == referrer_key
+            || makers[..i]
+                .iter()
+                .any(|other_maker| other_maker.user.key() == maker_key)
+        {
+            return Err(ErrorCode::InvalidMakerAccounts);
+        }
+    }
+
+    let market_index = order.market_index;
+    let minimum_base_asset_trade_size = {
+        let markets = &markets
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        markets
+            .get_market(market_index)
+            .amm
+            .minimum_base_asset_trade_size
+    };
+
+    let taker_limit_price = match order.order_type {
+        OrderType::Market => order.price,
+        _ => order.get_limit_price(valid_oracle_price)?,
+    };
+
+    let maker_direction = match order.direction {
+        PositionDirection::Long => PositionDirection::Short,
+        PositionDirection::Short => PositionDirection::Long,
+    };
+
+    let order_book = &mut order_book
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let trade_history = &mut trade_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let order_history = &mut order_history
+        .load_mut()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    let mut base_asset_amount_matched: u128 = 0;
+    let mut potentially_risk_increasing = false;
+    let mut entry_index: usize = 0;
+    while entry_index < ORDER_BOOK_SIDE_SIZE {
+        let entry = order_book.side(maker_direction)[entry_index];
+        if entry.is_empty() {
+            break;
+        }
+
+        let entry_price = entry.price;
+        if !maker_price_fills_taker(order.direction, entry_price, taker_limit_price, mark_price) {
+            break;
+        }
+
+        if entry.user == user.key() {
+            entry_index += 1;
+            continue;
+        }
+
+        let maker = match makers
+            .iter_mut()
+            .find(|maker| maker.user.key() == entry.user)
+        {
+            Some(maker) => maker,
+            None => break,
+        };
+
+        // The maker's borrows can't be counted without their spot balances
+        if maker.user.has_borrows == 1 {
+            entry_index += 1;
+            continue;
+        }
+
+        let maker_user_orders = &mut maker
+            .user_orders
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let maker_order = match maker_user_orders.orders.iter_mut().find(|maker_order| {
+            maker_order.order_id == entry.order_id
+                && maker_order.status == OrderStatus::Open
+                && maker_order.price == entry_price
+                && !maker_order.is_expired(now)
+        }) {
+            Some(maker_order) => maker_order,
+            None => {
+                // the order was canceled, filled outside of the book, repriced or expired
+                order_book.remove(maker_direction, entry_index);
+                continue;
+            }
+        };
+
+        let maker_user_positions = &mut UserPositions::load_positions_mut(&maker.user_positions)?;
+        {
+            let markets = &markets
+                .load()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+            controller::funding::settle_funding_payment(
+                &mut maker.user,
+                maker_user_positions,
+                markets,
+                funding_payment_history,
+                now,
+            )?;
+        }
+
+        let taker_base_asset_amount_unfilled = order
+            .base_asset_amount
+            .checked_sub(order.base_asset_amount_filled)
+            .ok_or_else(math_error!())?;
+        let maker_base_asset_amount_unfilled = maker_order
+            .base_asset_amount
+            .checked_sub(maker_order.base_asset_amount_filled)
+            .ok_or_else(math_error!())?;
+
+        let (taker_base_asset_amount, maker_base_asset_amount) = {
+            let markets = &markets
+                .load()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+            let taker_base_asset_amount = calculate_base_asset_amount_taker_can_execute_at_price(
+                user,
+                user_positions,
+                spot_balances_value,
+                order,
+                markets,
+                market_index,
+                entry_price,
+            )?;
+
+            let maker_base_asset_amount = min(
+                maker_base_asset_amount_unfilled,
+                calculate_base_asset_amount_user_can_execute_at_price(
+                    &maker.user,
+                    maker_user_positions,
+                    spot_balances_value_lower_bound(&maker.user)?,
+                    maker_order,
+                    markets,
+                    market_index,
+                    entry_price,
+                )?,
+            );
+
+            (taker_base_asset_amount, maker_base_asset_amount)
+        };
+
+        if taker_base_asset_amount < minimum_base_asset_trade_size {
+            break;
+        }
+
+        let base_asset_amount = min(taker_base_asset_amount, maker_base_asset_amount);
+
+        // Don't leave either order with less than the minimum trade size left to fill
+        let taker_base_asset_amount_left = taker_base_asset_amount_unfilled
+            .checked_sub(base_asset_amount)
+            .ok_or_else(math_error!())?;
+        let maker_base_asset_amount_left = maker_base_asset_amount_unfilled
+            .checked_sub(base_asset_amount)
+            .ok_or_else(math_error!())?;
+        if base_asset_amount < minimum_base_asset_trade_size
+            || (taker_base_asset_amount_left > 0
+                && taker_base_asset_amount_left < minimum_base_asset_trade_size)
+            || (maker_base_asset_amount_left > 0
+                && maker_base_asset_amount_left < minimum_base_asset_trade_size)
+        {
+            entry_index += 1;
+            continue;
+        }
+
+        let taker_potentially_risk_increasing = fill_order_with_maker(
+            state,
+            order_state,
+            order,
+            user,
+            user_positions,
+            &mut maker.user,
+            maker_user_positions,
+            Some(&mut *maker_order),
+            markets,
+            filler,
+            referrer,
+            trade_history,
+            order_history,
+            base_asset_amount,
+            entry_price,
+            mark_price,
+            oracle_price,
+            now,
+        )?;
+
+        base_asset_amount_matched = base_asset_amount_matched
+            .checked_add(base_asset_amount)
+            .ok_or_else(math_error!())?;
+        potentially_risk_increasing |= taker_potentially_risk_increasing;
+
+        let maker_linked_order_id = maker_order.linked_order_id;
+
+        // Cant reset order until after its been logged in order history
+        if maker_order.base_asset_amount == maker_order.base_asset_amount_filled {
+            *maker_order = Order::default();
+            let position_index = get_position_index(maker_user_positions, market_index)?;
+            let market_position = &mut maker_user_positions[position_index];
+            market_position.open_orders -= 1;
+            controller::position::release_isolated_collateral(&mut maker.user, market_position)?;
+            order_book.remove(maker_direction, entry_index);
+        } else {
+            entry_index += 1;
+        }
+
+        if maker_linked_order_id != 0 {
+            cancel_linked_order(
+                maker_linked_order_id,
+                &mut maker.user,
+                maker_user_positions,
+                maker_user_orders,
+                order_history,
+                now,
+            )?;
+        }
+
+        if order.base_asset_amount == order.base_asset_amount_filled {
+            break;
+        }
+    }
+
+    for maker in makers.iter() {
+        maker
+            .user
+            .exit(&crate::ID)
+            .or(Err(ErrorCode::UnableToWriteToRemainingAccount))?;
+    }
+
+    Ok((base_asset_amount_matched, potentially_risk_increasing))
+}
+
+/// Fills part of the taker's order against a maker that signed an opposing order for this fill.
+/// The maker's price has to cross the taker's limit price and be at least as good for the taker
+/// as the amm's mark price. The amm fills whatever the maker doesn't.
+pub fn fill_order_with_jit_maker(
+    state: &State,
+    order_state: &OrderState,
+    order: &mut Order,
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    spot_balances_value: i128,
+    markets: &AccountLoader<Markets>,
+    jit_maker: JitMaker,
+    filler: &mut Box<Account<User>>,
+    referrer: &mut Option<Account<User>>,
+    funding_payment_history: &mut RefMut<FundingPaymentHistory>,
+    trade_history: &AccountLoader<ExtendedTradeHistory>,
+    order_history: &AccountLoader<OrderHistory>,
+    mark_price: u128,
+    oracle_price: i128,
+    valid_oracle_price: Option<i128>,
+    now: i64,
+) -> ClearingHouseResult<(u128, bool)> {
+    if order.post_only
+        || order.base_asset_amount == 0
+        || !matches!(order.order_type, OrderType::Market | OrderType::Limit)
+    {
+        return Ok((0, false));
+    }
+
+    // The maker's user account is written back at the end of the instruction, so it can't alias
+    // the referrer
+    if referrer
+        .as_ref()
+        .map_or(false, |referrer| referrer.key() == jit_maker.user.key())
+    {
+        return Err(ErrorCode::InvalidMakerAccounts);
+    }
+
+    let maker_price = jit_maker.params.price;
+    let taker_limit_price = match order.order_type {
+        OrderType::Market => order.price,
+        _ => order.get_limit_price(valid_oracle_price)?,
+    };
+    if !maker_price_fills_taker(order.direction, maker_price, taker_limit_price, mark_price) {
+        msg!(
+            "jit maker price {} mark price {} taker limit price {}",
+            maker_price,
+            mark_price,
+            taker_limit_price
+        );
+        return Err(ErrorCode::InvalidJitMakerPrice);
+    }
+
+    let market_index = order.market_index;
+    let maker_user_positions = &mut UserPositions::load_positions_mut(jit_maker.user_positions)?;
     {
-        let markets = &mut markets
-            .load_mut()
+        let markets = &markets
+            .load()
             .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-        let market = markets.get_market_mut(market_index);
-        mark_price_before = market.amm.mark_price()?;
-        let oracle_price_data = &market.amm.get_oracle_price(oracle, clock_slot)?;
-        oracle_mark_spread_pct_before = amm::calculate_oracle_mark_spread_pct(
-            &market.amm,
-            oracle_price_data,
-            Some(mark_price_before),
-        )?;
-        oracle_price = oracle_price_data.price;
-        let normalised_price =
-            normalise_oracle_price(&market.amm, oracle_price_data, Some(mark_price_before))?;
-        is_oracle_valid = amm::is_oracle_valid(
-            &market.amm,
-            oracle_price_data,
-            &state.oracle_guard_rails.validity,
+        controller::funding::settle_funding_payment(
+            jit_maker.user,
+            maker_user_positions,
+            markets,
+            funding_payment_history,
+            now,
         )?;
-        if is_oracle_valid {
-            amm::update_oracle_price_twap(&mut market.amm, now, normalised_price)?;
-        }
     }
+    get_position_index(maker_user_positions, market_index)
+        .or_else(|_| add_new_position(maker_user_positions, market_index))?;
 
-    let valid_oracle_price = if is_oracle_valid {
-        Some(oracle_price)
-    } else {
-        None
+    let (base_asset_amount, _) = {
+        let markets = &markets
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let taker_base_asset_amount = calculate_base_asset_amount_taker_can_execute_at_price(
+            user,
+            user_positions,
+            spot_balances_value,
+            order,
+            markets,
+            market_index,
+            maker_price,
+        )?;
+        let base_asset_amount_unfilled = order
+            .base_asset_amount
+            .checked_sub(order.base_asset_amount_filled)
+            .ok_or_else(math_error!())?;
+
+        calculate_jit_maker_base_asset_amount_split(
+            base_asset_amount_unfilled,
+            min(jit_maker.params.base_asset_amount, taker_base_asset_amount),
+            markets
+                .get_market(market_index)
+                .amm
+                .minimum_base_asset_trade_size,
+        )?
     };
 
-    let (
-        base_asset_amount,
-        quote_asset_amount,
-        potentially_risk_increasing,
-        quote_asset_amount_surplus,
-    ) = execute_order(
+    if base_asset_amount == 0 {
+        msg!("Jit maker cant fill order");
+        return Ok((0, false));
+    }
+
+    let potentially_risk_increasing = fill_order_with_maker(
+        state,
+        order_state,
+        order,
         user,
         user_positions,
-        order,
-        &mut markets
+        jit_maker.user,
+        maker_user_positions,
+        None,
+        markets,
+        filler,
+        referrer,
+        &mut trade_history
             .load_mut()
             .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
-        market_index,
-        mark_price_before,
+        &mut order_history
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
+        base_asset_amount,
+        maker_price,
+        mark_price,
+        oracle_price,
         now,
-        valid_oracle_price,
     )?;
 
-    if base_asset_amount == 0 {
-        return Ok(0);
-    }
+    Ok((base_asset_amount, potentially_risk_increasing))
+}
 
-    let mark_price_after: u128;
-    let oracle_price_after: i128;
-    let oracle_mark_spread_pct_after: i128;
-    {
-        let markets = &mut markets
-            .load_mut()
-            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-        let market = markets.get_market_mut(market_index);
-        mark_price_after = market.amm.mark_price()?;
-        let oracle_price_data = &market.amm.get_oracle_price(oracle, clock_slot)?;
-        oracle_mark_spread_pct_after = amm::calculate_oracle_mark_spread_pct(
-            &market.amm,
-            oracle_price_data,
-            Some(mark_price_after),
-        )?;
-        oracle_price_after = oracle_price_data.price;
-    }
+/// Fills part of the taker's order against a user willing to take the other side at a fixed
+/// price, either from a resting order on the order book or from an order supplied just in time.
+/// Returns whether the fill potentially increased the taker's risk.
+fn fill_order_with_maker(
+    state: &State,
+    order_state: &OrderState,
+    order: &mut Order,
+    user: &mut Box<Account<User>>,
+    user_positions: &mut [MarketPosition],
+    maker: &mut Box<Account<User>>,
+    maker_user_positions: &mut [MarketPosition],
+    mut maker_order: Option<&mut Order>,
+    markets: &AccountLoader<Markets>,
+    filler: &mut Box<Account<User>>,
+    referrer: &mut Option<Account<User>>,
+    trade_history: &mut RefMut<ExtendedTradeHistory>,
+    order_history: &mut RefMut<OrderHistory>,
+    base_asset_amount: u128,
+    maker_price: u128,
+    mark_price: u128,
+    oracle_price: i128,
+    now: i64,
+) -> ClearingHouseResult<bool> {
+    let market_index = order.market_index;
 
-    let is_oracle_mark_too_divergent_before = amm::is_oracle_mark_too_divergent(
-        oracle_mark_spread_pct_before,
-        &state.oracle_guard_rails.price_divergence,
-    )?;
+    let maker_direction = match order.direction {
+        PositionDirection::Long => PositionDirection::Short,
+        PositionDirection::Short => PositionDirection::Long,
+    };
 
-    let is_oracle_mark_too_divergent_after = amm::is_oracle_mark_too_divergent(
-        oracle_mark_spread_pct_after,
-        &state.oracle_guard_rails.price_divergence,
+    // when the taker goes long, make the base asset slightly more expensive
+    let swap_direction = match order.direction {
+        PositionDirection::Long => SwapDirection::Remove,
+        PositionDirection::Short => SwapDirection::Add,
+    };
+
+    let quote_asset_amount = calculate_quote_asset_amount_for_maker_order(
+        base_asset_amount,
+        maker_price,
+        swap_direction,
     )?;
 
-    // if oracle-mark divergence pushed outside limit, block order
-    if is_oracle_mark_too_divergent_after && !is_oracle_mark_too_divergent_before && is_oracle_valid
-    {
-        return Err(ErrorCode::OracleMarkSpreadLimit);
-    }
+    let taker_position_index = get_position_index(user_positions, market_index)?;
+    let maker_position_index = get_position_index(maker_user_positions, market_index)?;
+    let (
+        taker_potentially_risk_increasing,
+        maker_potentially_risk_increasing,
+        minimum_base_asset_trade_size,
+    ) = {
+        let markets = &mut markets
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let market = markets.get_market_mut(market_index);
 
-    // if oracle-mark divergence outside limit and risk-increasing, block order
-    if is_oracle_mark_too_divergent_after
-        && oracle_mark_spread_pct_after.unsigned_abs()
-            >= oracle_mark_spread_pct_before.unsigned_abs()
-        && is_oracle_valid
-        && potentially_risk_increasing
-    {
-        return Err(ErrorCode::OracleMarkSpreadLimit);
-    }
+        let (taker_potentially_risk_increasing, taker_reduce_only) =
+            controller::position::update_position_with_matched_trade(
+                order.direction,
+                base_asset_amount,
+                quote_asset_amount,
+                market,
+                user,
+                &mut user_positions[taker_position_index],
+            )?;
+
+        let (maker_potentially_risk_increasing, maker_reduce_only) =
+            controller::position::update_position_with_matched_trade(
+                maker_direction,
+                base_asset_amount,
+                quote_asset_amount,
+                market,
+                maker,
+                &mut maker_user_positions[maker_position_index],
+            )?;
+
+        let maker_order_reduce_only = maker_order
+            .as_deref()
+            .map_or(false, |maker_order| maker_order.reduce_only);
+        if (!taker_reduce_only && order.reduce_only)
+            || (!maker_reduce_only && maker_order_reduce_only)
+        {
+            return Err(ErrorCode::ReduceOnlyOrderIncreasedRisk);
+        }
 
-    // Order fails if it's risk increasing and it brings the user collateral below the margin requirement
-    let meets_maintenance_requirement = if order.post_only {
-        // for post only orders allow user to fill up to partial margin requirement
-        meets_partial_margin_requirement(
-            user,
-            user_positions,
-            &markets
-                .load()
-                .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
-        )?
-    } else {
-        meets_initial_margin_requirement(
-            user,
-            user_positions,
-            &markets
-                .load()
-                .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
-        )?
+        (
+            taker_potentially_risk_increasing,
+            maker_potentially_risk_increasing,
+            market.amm.minimum_base_asset_trade_size,
+        )
     };
-    if !meets_maintenance_requirement && potentially_risk_increasing {
-        return Err(ErrorCode::InsufficientCollateral);
-    }
 
-    let discount_tier = order.discount_tier;
-    let (user_fee, fee_to_market, token_discount, filler_reward, referrer_reward, referee_discount) =
-        fees::calculate_fee_for_order(
-            quote_asset_amount,
-            &state.fee_structure,
-            &order_state.order_filler_reward_structure,
-            &discount_tier,
-            order.ts,
-            now,
-            &referrer,
-            filler.key() == user.key(),
-            quote_asset_amount_surplus,
-            order.post_only,
-        )?;
+    let (
+        taker_fee,
+        maker_rebate,
+        fee_to_market,
+        token_discount,
+        filler_reward,
+        referrer_reward,
+        referee_discount,
+    ) = fees::calculate_fee_for_matched_order(
+        quote_asset_amount,
+        &state.fee_structure,
+        state.maker_rebate_numerator,
+        state.maker_rebate_denominator,
+        &order_state.order_filler_reward_structure,
+        &order.discount_tier,
+        order.ts,
+        now,
+        referrer,
+        filler.key() == user.key(),
+    )?;
+    let maker_fee = -cast_to_i128(maker_rebate)?;
 
     // Increment the clearing house's total fee variables
     {
@@ -711,28 +2227,15 @@ pub fn fill_order(
             .ok_or_else(math_error!())?;
     }
 
-    // Update user's collateral based on fee/rebate
-    user.collateral = if user_fee >= 0 {
-        user.collateral.saturating_sub(user_fee.unsigned_abs())
-    } else {
-        user.collateral
-            .checked_add(user_fee.unsigned_abs())
-            .ok_or_else(math_error!())?
-    };
-
-    // Increment the user's total fee variables
-    if user_fee > 0 {
-        user.total_fee_paid = user
-            .total_fee_paid
-            .checked_add(cast(user_fee.unsigned_abs())?)
-            .ok_or_else(math_error!())?;
-    } else {
-        user.total_fee_rebate = user
-            .total_fee_rebate
-            .checked_add(cast(user_fee.unsigned_abs())?)
-            .ok_or_else(math_error!())?;
-    }
-
+    controller::position::update_collateral_for_fee(
+        user,
+        &mut user_positions[taker_position_index],
+        taker_fee,
+    )?;
+    user.total_fee_paid = user
+        .total_fee_paid
+        .checked_add(cast(taker_fee.unsigned_abs())?)
+        .ok_or_else(math_error!())?;
     user.total_token_discount = user
         .total_token_discount
         .checked_add(token_discount)
@@ -742,64 +2245,72 @@ pub fn fill_order(
         .checked_add(referee_discount)
         .ok_or_else(math_error!())?;
 
+    controller::position::update_collateral_for_fee(
+        maker,
+        &mut maker_user_positions[maker_position_index],
+        maker_fee,
+    )?;
+    maker.total_fee_rebate = maker
+        .total_fee_rebate
+        .checked_add(cast(maker_rebate)?)
+        .ok_or_else(math_error!())?;
+
     filler.collateral = filler
         .collateral
         .checked_add(cast(filler_reward)?)
         .ok_or_else(math_error!())?;
 
-    // Update the referrer's collateral with their reward
-    if let Some(mut referrer) = referrer {
+    if let Some(referrer) = referrer.as_mut() {
         referrer.total_referral_reward = referrer
             .total_referral_reward
             .checked_add(referrer_reward)
             .ok_or_else(math_error!())?;
-        referrer
-            .exit(&crate::ID)
-            .or(Err(ErrorCode::UnableToWriteToRemainingAccount))?;
     }
 
-    {
-        let markets = &mut markets
-            .load()
-            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-        let market = markets.get_market(market_index);
+    update_order_after_trade(
+        order,
+        minimum_base_asset_trade_size,
+        base_asset_amount,
+        quote_asset_amount,
+        taker_fee,
+    )?;
+    if let Some(maker_order) = maker_order.as_deref_mut() {
         update_order_after_trade(
-            order,
-            market.amm.minimum_base_asset_trade_size,
+            maker_order,
+            minimum_base_asset_trade_size,
             base_asset_amount,
             quote_asset_amount,
-            user_fee,
+            maker_fee,
         )?;
     }
 
-    let trade_history_account = &mut trade_history
-        .load_mut()
-        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    let trade_record_id = trade_history_account.next_record_id();
-    trade_history_account.append(TradeRecord {
+    let maker_key = maker.key();
+    let trade_record_id = trade_history.next_record_id();
+    trade_history.append(ExtendedTradeRecord {
         ts: now,
         record_id: trade_record_id,
         user_authority: user.authority,
-        user: *user.to_account_info().key,
+        user: user.key(),
         direction: order.direction,
         base_asset_amount,
         quote_asset_amount,
-        mark_price_before,
-        mark_price_after,
-        fee: user_fee,
+        mark_price_before: mark_price,
+        mark_price_after: mark_price,
+        fee: taker_fee,
         token_discount,
-        quote_asset_amount_surplus,
+        quote_asset_amount_surplus: 0,
         referee_discount,
         liquidation: false,
         market_index,
-        oracle_price: oracle_price_after,
+        oracle_price,
+        maker: maker_key,
+        maker_fee,
+        spread: 0,
+        padding: [0; 4],
     });
 
-    let order_history_account = &mut order_history
-        .load_mut()
-        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-    let record_id = order_history_account.next_record_id();
-    order_history_account.append(OrderRecord {
+    let record_id = order_history.next_record_id();
+    order_history.append(OrderRecord {
         ts: now,
         record_id,
         order: *order,
@@ -811,49 +2322,63 @@ pub fn fill_order(
         base_asset_amount_filled: base_asset_amount,
         quote_asset_amount_filled: quote_asset_amount,
         filler_reward,
-        fee: user_fee,
-        quote_asset_amount_surplus,
-        padding: [0; 8],
+        fee: taker_fee,
+        quote_asset_amount_surplus: 0,
+        counterparty: maker_key,
+        padding: [0; 4],
     });
 
-    // Cant reset order until after its been logged in order history
-    if order.base_asset_amount == order.base_asset_amount_filled
-        || order.order_type == OrderType::Market
-    {
-        *order = Order::default();
-        let position_index = get_position_index(user_positions, market_index)?;
-        let market_position = &mut user_positions.positions[position_index];
-        market_position.open_orders -= 1;
+    if let Some(maker_order) = maker_order.as_deref() {
+        let record_id = order_history.next_record_id();
+        order_history.append(OrderRecord {
+            ts: now,
+            record_id,
+            order: *maker_order,
+            user: maker_key,
+            authority: maker.authority,
+            action: OrderAction::Fill,
+            filler: filler.key(),
+            trade_record_id,
+            base_asset_amount_filled: base_asset_amount,
+            quote_asset_amount_filled: quote_asset_amount,
+            filler_reward: 0,
+            fee: maker_fee,
+            quote_asset_amount_surplus: 0,
+            counterparty: user.key(),
+            padding: [0; 4],
+        });
     }
 
-    // Try to update the funding rate at the end of every trade
-    {
-        let markets = &mut markets
-            .load_mut()
-            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-        let market = markets.get_market_mut(market_index);
-        let funding_rate_history = &mut funding_rate_history
-            .load_mut()
-            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
-        controller::funding::update_funding_rate(
+    // The maker is checked here since they aren't covered by the taker's margin check
+    let maker_order_post_only = maker_order
+        .as_deref()
+        .map_or(false, |maker_order| maker_order.post_only);
+    if maker_potentially_risk_increasing
+        && !meets_margin_requirement_for_market(
+            maker,
+            maker_user_positions,
+            spot_balances_value_lower_bound(maker)?,
+            &markets
+                .load()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?,
             market_index,
-            market,
-            oracle,
-            now,
-            clock_slot,
-            funding_rate_history,
-            &state.oracle_guard_rails,
-            state.funding_paused,
-            Some(mark_price_before),
-        )?;
+            if maker_order_post_only {
+                MarginType::Partial
+            } else {
+                MarginType::Init
+            },
+        )?
+    {
+        return Err(ErrorCode::InsufficientCollateral);
     }
 
-    Ok(base_asset_amount)
+    Ok(taker_potentially_risk_increasing)
 }
 
 pub fn execute_order(
     user: &mut User,
-    user_positions: &mut RefMut<UserPositions>,
+    user_positions: &mut [MarketPosition],
+    spot_balances_value: i128,
     order: &mut Order,
     markets: &mut RefMut<Markets>,
     market_index: u64,
@@ -874,6 +2399,7 @@ pub fn execute_order(
         _ => execute_non_market_order(
             user,
             user_positions,
+            spot_balances_value,
             order,
             markets,
             market_index,
@@ -886,7 +2412,7 @@ pub fn execute_order(
 
 pub fn execute_market_order(
     user: &mut User,
-    user_positions: &mut RefMut<UserPositions>,
+    user_positions: &mut [MarketPosition],
     order: &mut Order,
     markets: &mut RefMut<Markets>,
     market_index: u64,
@@ -894,17 +2420,21 @@ pub fn execute_market_order(
     now: i64,
 ) -> ClearingHouseResult<(u128, u128, bool, u128)> {
     let position_index = get_position_index(user_positions, market_index)?;
-    let market_position = &mut user_positions.positions[position_index];
+    let market_position = &mut user_positions[position_index];
     let market = markets.get_market_mut(market_index);
 
+    let base_asset_amount_unfilled = order
+        .base_asset_amount
+        .checked_sub(order.base_asset_amount_filled)
+        .ok_or_else(math_error!())?;
     let base_asset_amount = if order.reduce_only {
         calculate_base_asset_amount_for_reduce_only_order(
-            order.base_asset_amount,
+            base_asset_amount_unfilled,
             order.direction,
             market_position.base_asset_amount,
         )
     } else {
-        order.base_asset_amount
+        base_asset_amount_unfilled
     };
 
     let (
@@ -966,7 +2496,8 @@ pub fn execute_market_order(
 
 pub fn execute_non_market_order(
     user: &mut User,
-    user_positions: &mut RefMut<UserPositions>,
+    user_positions: &mut [MarketPosition],
+    spot_balances_value: i128,
     order: &mut Order,
     markets: &mut RefMut<Markets>,
     market_index: u64,
@@ -974,10 +2505,16 @@ pub fn execute_non_market_order(
     now: i64,
     valid_oracle_price: Option<i128>,
 ) -> ClearingHouseResult<(u128, u128, bool, u128)> {
+    if !order.is_slice_available(now)? {
+        msg!("Twap order slice interval hasn't passed");
+        return Ok((0, 0, false, 0));
+    }
+
     // Determine the base asset amount the user can fill
     let base_asset_amount_user_can_execute = calculate_base_asset_amount_user_can_execute(
         user,
         user_positions,
+        spot_balances_value,
         order,
         markets,
         market_index,
@@ -1036,7 +2573,7 @@ pub fn execute_non_market_order(
     }
 
     let position_index = get_position_index(user_positions, market_index)?;
-    let market_position = &mut user_positions.positions[position_index];
+    let market_position = &mut user_positions[position_index];
 
     let maker_limit_price = if order.post_only {
         Some(order.get_limit_price(valid_oracle_price)?)
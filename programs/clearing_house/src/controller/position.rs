@@ -11,9 +11,9 @@ use crate::math::orders::calculate_quote_asset_amount_for_maker_order;
 use crate::math::pnl::calculate_pnl;
 use crate::math::position::calculate_base_asset_value_and_pnl;
 use crate::math_error;
-use crate::{Market, MarketPosition, User, UserPositions};
+use crate::{Market, MarketPosition, User};
 use solana_program::msg;
-use std::cell::RefMut;
+use std::cmp::min;
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq)]
 pub enum PositionDirection {
@@ -29,11 +29,10 @@ impl Default for PositionDirection {
 }
 
 pub fn add_new_position(
-    user_positions: &mut RefMut<UserPositions>,
+    user_positions: &mut [MarketPosition],
     market_index: u64,
 ) -> ClearingHouseResult<usize> {
     let new_position_index = user_positions
-        .positions
         .iter()
         .position(|market_position| market_position.is_available())
         .ok_or(ErrorCode::MaxNumberOfPositions)?;
@@ -46,8 +45,10 @@ pub fn add_new_position(
         last_cumulative_repeg_rebate: 0,
         last_funding_rate_ts: 0,
         open_orders: 0,
-        padding0: 0,
-        padding1: 0,
+        isolated_collateral: 0,
+        is_isolated: 0,
+        padding1: [0; 7],
+        liquidation_start_slot: 0,
         padding2: 0,
         padding3: 0,
         padding4: 0,
@@ -55,17 +56,16 @@ pub fn add_new_position(
         padding6: 0,
     };
 
-    user_positions.positions[new_position_index] = new_market_position;
+    user_positions[new_position_index] = new_market_position;
 
     Ok(new_position_index)
 }
 
 pub fn get_position_index(
-    user_positions: &mut RefMut<UserPositions>,
+    user_positions: &mut [MarketPosition],
     market_index: u64,
 ) -> ClearingHouseResult<usize> {
     let position_index = user_positions
-        .positions
         .iter_mut()
         .position(|market_position| market_position.is_for(market_index));
 
@@ -306,7 +306,7 @@ pub fn reduce(
             .ok_or_else(math_error!())?
     };
 
-    user.collateral = calculate_updated_collateral(user.collateral, pnl)?;
+    update_collateral_for_pnl(user, market_position, pnl)?;
 
     Ok((base_asset_swapped, quote_asset_amount_surplus))
 }
@@ -404,7 +404,7 @@ pub fn reduce_with_base_asset_amount(
             .ok_or_else(math_error!())?
     };
 
-    user.collateral = calculate_updated_collateral(user.collateral, pnl)?;
+    update_collateral_for_pnl(user, market_position, pnl)?;
 
     Ok((quote_asset_amount, quote_asset_amount_surplus))
 }
@@ -454,7 +454,7 @@ pub fn close(
         swap_direction,
     )?;
 
-    user.collateral = calculate_updated_collateral(user.collateral, pnl)?;
+    update_collateral_for_pnl(user, market_position, pnl)?;
     market_position.last_cumulative_funding_rate = 0;
     market_position.last_funding_rate_ts = 0;
 
@@ -725,6 +725,251 @@ pub fn update_position_with_quote_asset_amount(
     ))
 }
 
+/// Updates a position for a trade matched against another user's order. No amm swap takes place, so
+/// the net market position is unchanged once both sides of the trade are applied.
+pub fn update_position_with_matched_trade(
+    direction: PositionDirection,
+    base_asset_amount: u128,
+    quote_asset_amount: u128,
+    market: &mut Market,
+    user: &mut User,
+    market_position: &mut MarketPosition,
+) -> ClearingHouseResult<(bool, bool)> {
+    let base_asset_amount_before = market_position.base_asset_amount;
+    let base_asset_amount_delta = match direction {
+        PositionDirection::Long => cast_to_i128(base_asset_amount)?,
+        PositionDirection::Short => -cast_to_i128(base_asset_amount)?,
+    };
+
+    let increase_position = base_asset_amount_before == 0
+        || base_asset_amount_before > 0 && direction == PositionDirection::Long
+        || base_asset_amount_before < 0 && direction == PositionDirection::Short;
+
+    let potentially_risk_increasing;
+    let reduce_only;
+    if increase_position {
+        market_position.quote_asset_amount = market_position
+            .quote_asset_amount
+            .checked_add(quote_asset_amount)
+            .ok_or_else(math_error!())?;
+
+        potentially_risk_increasing = true;
+        reduce_only = false;
+    } else {
+        let base_asset_amount_closed =
+            min(base_asset_amount, base_asset_amount_before.unsigned_abs());
+
+        let quote_asset_amount_closed = quote_asset_amount
+            .checked_mul(base_asset_amount_closed)
+            .ok_or_else(math_error!())?
+            .checked_div(base_asset_amount)
+            .ok_or_else(math_error!())?;
+
+        let initial_quote_asset_amount_closed = market_position
+            .quote_asset_amount
+            .checked_mul(base_asset_amount_closed)
+            .ok_or_else(math_error!())?
+            .checked_div(base_asset_amount_before.unsigned_abs())
+            .ok_or_else(math_error!())?;
+
+        let pnl = if base_asset_amount_before > 0 {
+            cast_to_i128(quote_asset_amount_closed)?
+                .checked_sub(cast(initial_quote_asset_amount_closed)?)
+                .ok_or_else(math_error!())?
+        } else {
+            cast_to_i128(initial_quote_asset_amount_closed)?
+                .checked_sub(cast(quote_asset_amount_closed)?)
+                .ok_or_else(math_error!())?
+        };
+
+        update_collateral_for_pnl(user, market_position, pnl)?;
+
+        // any amount beyond the existing position opens a position in the opposite direction
+        let quote_asset_amount_opened = quote_asset_amount
+            .checked_sub(quote_asset_amount_closed)
+            .ok_or_else(math_error!())?;
+
+        market_position.quote_asset_amount = market_position
+            .quote_asset_amount
+            .checked_sub(initial_quote_asset_amount_closed)
+            .ok_or_else(math_error!())?
+            .checked_add(quote_asset_amount_opened)
+            .ok_or_else(math_error!())?;
+
+        let base_asset_amount_opened = base_asset_amount
+            .checked_sub(base_asset_amount_closed)
+            .ok_or_else(math_error!())?;
+
+        // If the value of the new position is less than value of the old position, consider it risk decreasing
+        potentially_risk_increasing = base_asset_amount_opened >= base_asset_amount_closed;
+        reduce_only = base_asset_amount_opened == 0;
+    }
+
+    market_position.base_asset_amount = base_asset_amount_before
+        .checked_add(base_asset_amount_delta)
+        .ok_or_else(math_error!())?;
+    let base_asset_amount_after = market_position.base_asset_amount;
+
+    // Update funding rate if this is a new position or the position flipped direction
+    if base_asset_amount_after == 0 {
+        market_position.last_cumulative_funding_rate = 0;
+        market_position.last_funding_rate_ts = 0;
+    } else if base_asset_amount_before == 0
+        || base_asset_amount_before.signum() != base_asset_amount_after.signum()
+    {
+        market_position.last_cumulative_funding_rate = if base_asset_amount_after > 0 {
+            market.amm.cumulative_funding_rate_long
+        } else {
+            market.amm.cumulative_funding_rate_short
+        };
+        market_position.last_funding_rate_ts = market.amm.last_funding_rate_ts;
+    }
+
+    if base_asset_amount_before == 0 && base_asset_amount_after != 0 {
+        market.open_interest = market
+            .open_interest
+            .checked_add(1)
+            .ok_or_else(math_error!())?;
+    } else if base_asset_amount_before != 0 && base_asset_amount_after == 0 {
+        market.open_interest = market
+            .open_interest
+            .checked_sub(1)
+            .ok_or_else(math_error!())?;
+    }
+
+    if base_asset_amount_before > 0 {
+        market.base_asset_amount_long = market
+            .base_asset_amount_long
+            .checked_sub(base_asset_amount_before)
+            .ok_or_else(math_error!())?;
+    } else {
+        market.base_asset_amount_short = market
+            .base_asset_amount_short
+            .checked_sub(base_asset_amount_before)
+            .ok_or_else(math_error!())?;
+    }
+
+    if base_asset_amount_after > 0 {
+        market.base_asset_amount_long = market
+            .base_asset_amount_long
+            .checked_add(base_asset_amount_after)
+            .ok_or_else(math_error!())?;
+    } else {
+        market.base_asset_amount_short = market
+            .base_asset_amount_short
+            .checked_add(base_asset_amount_after)
+            .ok_or_else(math_error!())?;
+    }
+
+    market.base_asset_amount = market
+        .base_asset_amount
+        .checked_add(base_asset_amount_delta)
+        .ok_or_else(math_error!())?;
+
+    Ok((potentially_risk_increasing, reduce_only))
+}
+
+pub fn update_collateral_for_pnl(
+    user: &mut User,
+    market_position: &mut MarketPosition,
+    pnl: i128,
+) -> ClearingHouseResult {
+    if market_position.is_isolated() {
+        market_position.isolated_collateral =
+            calculate_updated_collateral(market_position.isolated_collateral, pnl)?;
+    } else {
+        user.collateral = calculate_updated_collateral(user.collateral, pnl)?;
+    }
+
+    Ok(())
+}
+
+pub fn update_collateral_for_fee(
+    user: &mut User,
+    market_position: &mut MarketPosition,
+    fee: i128,
+) -> ClearingHouseResult {
+    // a negative fee is a rebate
+    update_collateral_for_pnl(user, market_position, -fee)
+}
+
+pub fn isolate_position(
+    user: &mut User,
+    market_position: &mut MarketPosition,
+    isolated_collateral: u128,
+) -> ClearingHouseResult {
+    if !market_position.is_isolated() && !market_position.is_available() {
+        return Err(ErrorCode::PositionMarginModeMismatch);
+    }
+
+    if isolated_collateral > user.collateral {
+        return Err(ErrorCode::InsufficientCollateral);
+    }
+
+    user.collateral = user
+        .collateral
+        .checked_sub(isolated_collateral)
+        .ok_or_else(math_error!())?;
+
+    market_position.isolated_collateral = market_position
+        .isolated_collateral
+        .checked_add(isolated_collateral)
+        .ok_or_else(math_error!())?;
+    market_position.is_isolated = 1;
+
+    Ok(())
+}
+
+pub fn remove_isolated_collateral(
+    user: &mut User,
+    market_position: &mut MarketPosition,
+    isolated_collateral: u128,
+) -> ClearingHouseResult {
+    if !market_position.is_isolated() {
+        return Err(ErrorCode::PositionNotIsolated);
+    }
+
+    if isolated_collateral > market_position.isolated_collateral {
+        return Err(ErrorCode::InsufficientCollateral);
+    }
+
+    market_position.isolated_collateral = market_position
+        .isolated_collateral
+        .checked_sub(isolated_collateral)
+        .ok_or_else(math_error!())?;
+
+    user.collateral = user
+        .collateral
+        .checked_add(isolated_collateral)
+        .ok_or_else(math_error!())?;
+
+    Ok(())
+}
+
+/// Once an isolated position has no base asset amount and no open orders, the remaining isolated
+/// collateral is returned to the user's cross margin collateral
+pub fn release_isolated_collateral(
+    user: &mut User,
+    market_position: &mut MarketPosition,
+) -> ClearingHouseResult {
+    if !market_position.is_isolated()
+        || market_position.is_open_position()
+        || market_position.has_open_order()
+    {
+        return Ok(());
+    }
+
+    user.collateral = user
+        .collateral
+        .checked_add(market_position.isolated_collateral)
+        .ok_or_else(math_error!())?;
+    market_position.isolated_collateral = 0;
+    market_position.is_isolated = 0;
+    market_position.liquidation_start_slot = 0;
+
+    Ok(())
+}
+
 fn calculate_quote_asset_amount_surplus(
     swap_direction: SwapDirection,
     quote_asset_swapped: u128,
@@ -6,11 +6,14 @@ use crate::math::constants::{
     SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR,
 };
 use crate::math_error;
-use crate::state::market::Market;
+use crate::state::history::curve::{ExtendedCurveHistory, ExtendedCurveRecord};
+use crate::state::market::{Market, OraclePriceData};
 
-use crate::state::state::OracleGuardRails;
+use crate::math::bn::U256;
+use crate::math::constants::{PRICE_SPREAD_PRECISION_U128, UPDATE_K_ALLOWED_PRICE_CHANGE};
+use crate::state::state::{OracleGuardRails, State};
 
-use crate::math::casting::cast_to_u128;
+use crate::math::casting::{cast_to_i128, cast_to_u128};
 use anchor_lang::prelude::AccountInfo;
 use solana_program::msg;
 
@@ -39,11 +42,7 @@ pub fn repeg(
     if oracle_is_valid {
         let terminal_price_after = amm::calculate_terminal_price(market)?;
 
-        let mark_price_after = amm::calculate_price(
-            market.amm.quote_asset_reserve,
-            market.amm.base_asset_reserve,
-            market.amm.peg_multiplier,
-        )?;
+        let mark_price_after = market.amm.mark_price()?;
 
         let oracle_conf_band_top = cast_to_u128(oracle_price)?
             .checked_add(oracle_conf)
@@ -120,3 +119,185 @@ pub fn repeg(
 
     Ok(adjustment_cost)
 }
+
+/// Repegs the amm toward the oracle price when the mark/oracle gap exceeds the state's formulaic
+/// repeg threshold. The cost is budgeted against the fees above the clearing house's share and
+/// each adjustment is written to the extended curve history. Expects a valid oracle price.
+/// Returns the adjustment cost if the peg was moved
+pub fn formulaic_repeg(
+    market: &mut Market,
+    market_index: u64,
+    oracle_price_data: &OraclePriceData,
+    mark_price: u128,
+    state: &State,
+    curve_history: &mut ExtendedCurveHistory,
+    trade_record: u128,
+    now: i64,
+) -> ClearingHouseResult<Option<i128>> {
+    if state.formulaic_repeg_threshold_denominator == 0 {
+        return Ok(None);
+    }
+
+    let oracle_mark_spread_pct =
+        amm::calculate_oracle_mark_spread_pct(&market.amm, oracle_price_data, Some(mark_price))?;
+
+    let threshold = state
+        .formulaic_repeg_threshold_numerator
+        .checked_mul(PRICE_SPREAD_PRECISION_U128)
+        .ok_or_else(math_error!())?
+        .checked_div(state.formulaic_repeg_threshold_denominator)
+        .ok_or_else(math_error!())?;
+
+    if oracle_mark_spread_pct.unsigned_abs() <= threshold {
+        return Ok(None);
+    }
+
+    let optimal_peg = repeg::calculate_optimal_peg(&market.amm, oracle_price_data.price)?;
+    let budget = repeg::calculate_fee_pool_budget(&market.amm)?;
+    let (new_peg, expected_cost) = repeg::calculate_budgeted_peg(market, optimal_peg, budget)?;
+
+    // rounding can push the cost of a partial repeg just past the budget
+    if new_peg == 0
+        || new_peg == market.amm.peg_multiplier
+        || (expected_cost > 0 && expected_cost.unsigned_abs() > budget)
+    {
+        return Ok(None);
+    }
+
+    let market_before = *market;
+    let adjustment_cost = repeg::adjust_peg_cost(market, new_peg)?;
+    update_fee_pool_for_adjustment_cost(market, adjustment_cost)?;
+
+    record_curve_adjustment(
+        curve_history,
+        market_index,
+        &market_before,
+        market,
+        adjustment_cost,
+        oracle_price_data.price,
+        trade_record,
+        now,
+    );
+
+    Ok(Some(adjustment_cost))
+}
+
+/// Deepens liquidity (raises k) while the fee pool is above the clearing house's share of fees and
+/// thins it (lowers k) once the pool is depleted, moving sqrt_k by at most the state's formulaic k
+/// max change per update. Each update is written to the extended curve history.
+/// Returns the adjustment cost if k was changed
+pub fn formulaic_update_k(
+    market: &mut Market,
+    market_index: u64,
+    oracle_price: i128,
+    state: &State,
+    curve_history: &mut ExtendedCurveHistory,
+    now: i64,
+) -> ClearingHouseResult<Option<i128>> {
+    if state.formulaic_k_max_change_numerator == 0 || state.formulaic_k_max_change_denominator == 0
+    {
+        return Ok(None);
+    }
+
+    let budget = repeg::calculate_fee_pool_budget(&market.amm)?;
+    let increase = budget > 0;
+
+    let (new_sqrt_k, _) = match repeg::calculate_budgeted_k(
+        market,
+        increase,
+        budget,
+        state.formulaic_k_max_change_numerator,
+        state.formulaic_k_max_change_denominator,
+    )? {
+        Some(budgeted_k) => budgeted_k,
+        None => return Ok(None),
+    };
+
+    let market_before = *market;
+    let adjustment_cost = amm::adjust_k_cost(market, U256::from(new_sqrt_k))?;
+
+    let price_before = market_before.amm.mark_price()?;
+    let price_after = market.amm.mark_price()?;
+    if cast_to_i128(price_before)?
+        .checked_sub(cast_to_i128(price_after)?)
+        .ok_or_else(math_error!())?
+        .unsigned_abs()
+        > UPDATE_K_ALLOWED_PRICE_CHANGE
+    {
+        msg!("formulaic k update moved mark price too far, skipping");
+        *market = market_before;
+        return Ok(None);
+    }
+
+    update_fee_pool_for_adjustment_cost(market, adjustment_cost)?;
+
+    record_curve_adjustment(
+        curve_history,
+        market_index,
+        &market_before,
+        market,
+        adjustment_cost,
+        oracle_price,
+        0,
+        now,
+    );
+
+    Ok(Some(adjustment_cost))
+}
+
+fn update_fee_pool_for_adjustment_cost(
+    market: &mut Market,
+    adjustment_cost: i128,
+) -> ClearingHouseResult {
+    if adjustment_cost > 0 {
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .checked_sub(adjustment_cost.unsigned_abs())
+            .ok_or_else(math_error!())?;
+    } else {
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .checked_add(adjustment_cost.unsigned_abs())
+            .ok_or_else(math_error!())?;
+    }
+
+    Ok(())
+}
+
+fn record_curve_adjustment(
+    curve_history: &mut ExtendedCurveHistory,
+    market_index: u64,
+    market_before: &Market,
+    market_after: &Market,
+    adjustment_cost: i128,
+    oracle_price: i128,
+    trade_record: u128,
+    now: i64,
+) {
+    let record_id = curve_history.next_record_id();
+    curve_history.append(ExtendedCurveRecord {
+        ts: now,
+        record_id,
+        market_index,
+        peg_multiplier_before: market_before.amm.peg_multiplier,
+        base_asset_reserve_before: market_before.amm.base_asset_reserve,
+        quote_asset_reserve_before: market_before.amm.quote_asset_reserve,
+        sqrt_k_before: market_before.amm.sqrt_k,
+        peg_multiplier_after: market_after.amm.peg_multiplier,
+        base_asset_reserve_after: market_after.amm.base_asset_reserve,
+        quote_asset_reserve_after: market_after.amm.quote_asset_reserve,
+        sqrt_k_after: market_after.amm.sqrt_k,
+        base_asset_amount_long: market_after.base_asset_amount_long.unsigned_abs(),
+        base_asset_amount_short: market_after.base_asset_amount_short.unsigned_abs(),
+        base_asset_amount: market_after.base_asset_amount,
+        open_interest: market_after.open_interest,
+        total_fee: market_after.amm.total_fee,
+        total_fee_minus_distributions: market_after.amm.total_fee_minus_distributions,
+        adjustment_cost,
+        oracle_price,
+        trade_record,
+        padding: [0; 5],
+    });
+}
@@ -134,6 +134,84 @@ pub enum ErrorCode {
     SettlementNotEnabled,
     #[msg("MustCallSettlePositionFirst")]
     MustCallSettlePositionFirst,
+    #[msg("Invalid Sub Account Id")]
+    InvalidSubAccountId,
+    #[msg("Cant transfer collateral to same sub account")]
+    CantTransferToSameSubAccount,
+    #[msg("Invalid number of positions")]
+    InvalidNumberOfPositions,
+    #[msg("Position margin mode mismatch")]
+    PositionMarginModeMismatch,
+    #[msg("Position is not isolated")]
+    PositionNotIsolated,
+    #[msg("Invalid order book")]
+    InvalidOrderBook,
+    #[msg("Order book full")]
+    OrderBookFull,
+    #[msg("Invalid maker accounts")]
+    InvalidMakerAccounts,
+    #[msg("Jit maker price must cross the taker's price and improve on the amm's")]
+    InvalidJitMakerPrice,
+    #[msg("Could not update trailing stop")]
+    CouldNotUpdateTrailingStop,
+    #[msg("Invalid linked order")]
+    InvalidLinkedOrder,
+    #[msg("User orders not found")]
+    UserOrdersNotFound,
+    #[msg("Order has expired")]
+    OrderExpired,
+    #[msg("Collateral assets already initialized")]
+    CollateralAssetsAlreadyInitialized,
+    #[msg("Invalid collateral asset")]
+    InvalidCollateralAsset,
+    #[msg("User collateral assets not found")]
+    UserCollateralAssetsNotFound,
+    #[msg("User has no quote collateral deficit to cover")]
+    NoQuoteCollateralDeficit,
+    #[msg("Invalid borrow")]
+    InvalidBorrow,
+    #[msg("Invalid interest rate curve")]
+    InvalidInterestRateCurve,
+    #[msg("Insurance fund already initialized")]
+    InsuranceFundAlreadyInitialized,
+    #[msg("Invalid insurance fund stake")]
+    InvalidInsuranceFundStake,
+    #[msg("Insurance fund has no balance to back its shares")]
+    InsuranceFundDepleted,
+    #[msg("Insurance fund unstake request already pending")]
+    InsuranceFundUnstakeRequestPending,
+    #[msg("No insurance fund unstake request")]
+    NoInsuranceFundUnstakeRequest,
+    #[msg("Insurance fund unstaking period not over")]
+    InsuranceFundUnstakingPeriodNotOver,
+    #[msg("Invalid insurance fund fee share")]
+    InvalidInsuranceFundFeeShare,
+    #[msg("User can't liquidate themself")]
+    UserCantLiquidateThemself,
+    #[msg("Position transfer would leave the user bankrupt")]
+    PositionTransferBankruptsUser,
+    #[msg("Invalid liquidation auction")]
+    InvalidLiquidationAuction,
+    #[msg("Invalid liquidation fee")]
+    InvalidLiquidationFee,
+    #[msg("Invalid spread")]
+    InvalidSpread,
+    #[msg("Invalid formulaic k max change")]
+    InvalidFormulaicKMaxChange,
+    #[msg("Invalid amplification coefficient")]
+    InvalidAmplificationCoefficient,
+    #[msg("Curve did not converge")]
+    CurveDidNotConverge,
+    #[msg("Invalid maker rebate")]
+    InvalidMakerRebate,
+    #[msg("State already migrated")]
+    StateAlreadyMigrated,
+    #[msg("Invalid funding rate parameters")]
+    InvalidFundingRateParameters,
+    #[msg("Market already migrated")]
+    MarketAlreadyMigrated,
+    #[msg("Invalid imf factor")]
+    InvalidImfFactor,
 }
 
 #[macro_export]
@@ -1,21 +1,35 @@
 #![allow(clippy::too_many_arguments)]
 #![allow(unaligned_references)]
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
 use borsh::{BorshDeserialize, BorshSerialize};
 
 use context::*;
+use controller::orders::JitMaker;
 use controller::position::{add_new_position, get_position_index, PositionDirection};
-use error::ErrorCode;
+use error::{ClearingHouseResult, ErrorCode};
 use math::{amm, bn, constants::*, fees, margin::*, orders::*, withdrawal::*};
 
+use crate::math::amm::{calculate_mark_twap_spread_pct, is_oracle_mark_too_divergent};
+use crate::math::bankruptcy::{
+    allocate_bankrupt_loss, calculate_bankrupt_loss, calculate_liquidated_position_pnl,
+};
+use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
+use crate::math::curve::{get_curve, Curve};
+use crate::math::slippage::{calculate_slippage, calculate_slippage_pct};
 use crate::state::{
-    history::trade::TradeRecord,
-    market::{Market, Markets, OracleSource, AMM},
+    history::bankruptcy::BankruptcyRecord,
+    history::funding_rate::ExtendedFundingRateRecord,
+    history::liquidation::ExtendedLiquidationRecord,
+    history::trade::ExtendedTradeRecord,
+    market::{CurveType, Market, Markets, OracleSource, AMM},
     order_state::*,
     state::*,
     user::{MarketPosition, User, UserPositions},
     user_orders::*,
 };
+use std::cell::Ref;
+use std::cmp::min;
 
 pub mod context;
 pub mod controller;
@@ -26,6 +40,7 @@ pub mod optional_accounts;
 pub mod order_validation;
 pub mod settlement_ratios;
 pub mod state;
+mod state_migration;
 mod user_initialization;
 
 #[cfg(feature = "mainnet-beta")]
@@ -38,26 +53,33 @@ pub mod clearing_house {
     use crate::math;
     use crate::optional_accounts::{
         get_discount_token, get_oracle_for_cancel_order_by_order_id,
-        get_oracle_for_cancel_order_by_user_order_id, get_oracle_for_place_order, get_referrer,
-        get_referrer_for_fill_order,
+        get_oracle_for_cancel_order_by_user_order_id, get_oracle_for_place_order,
+        get_oracles_and_order_books, get_order_book, get_order_book_and_makers_for_fill_order,
+        get_order_book_and_oracle_for_modify_order, get_referrer, get_referrer_for_fill_order,
     };
     use crate::state::history::curve::ExtendedCurveRecord;
     use crate::state::history::deposit::{DepositDirection, DepositRecord};
-    use crate::state::history::liquidation::LiquidationRecord;
+    use crate::state::history::insurance_fund_stake::{InsuranceFundStakeRecord, StakeAction};
 
     use super::*;
-    use crate::margin_validation::validate_margin;
-    use crate::math::amm::{
-        calculate_mark_twap_spread_pct, is_oracle_mark_too_divergent, normalise_oracle_price,
+    use crate::margin_validation::{
+        validate_collateral_asset_weights, validate_imf_factor, validate_interest_rate_curve,
+        validate_liquidation_fees, validate_margin,
+    };
+    use crate::math::amm::normalise_oracle_price;
+    use crate::math::casting::cast_to_u128;
+    use crate::math::collateral::{
+        calculate_collateral_asset_amount, calculate_collateral_asset_value,
+        calculate_spot_balances_value, get_valid_collateral_asset_price,
     };
-    use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
     use crate::math::position::calculated_settled_position_value;
-    use crate::math::slippage::{calculate_slippage, calculate_slippage_pct};
+    use crate::optional_accounts::OracleAccountInfos;
+    use crate::state::collateral::CollateralAsset;
+    use crate::state::insurance_fund::InsuranceFund;
+    use crate::state::lending::LendingPool;
     use crate::state::market::OraclePriceData;
     use crate::state::order_state::{OrderFillerRewardStructure, OrderState};
     use crate::state::settlement::SettlementState;
-    use std::cmp::min;
-    use std::ops::Div;
 
     pub fn initialize(
         ctx: Context<Initialize>,
@@ -168,25 +190,74 @@ pub mod clearing_house {
                 use_for_liquidations: true,
             },
             order_state: Pubkey::default(),
-            extended_curve_history: Pubkey::default(),
+            maker_rebate_numerator: DEFAULT_MAKER_REBATE_NUMERATOR,
+            maker_rebate_denominator: DEFAULT_MAKER_REBATE_DENOMINATOR,
             padding0: 0,
             padding1: 0,
-            padding2: 0,
-            padding3: 0,
+            extended_trade_history: Pubkey::default(),
+            collateral_assets: Pubkey::default(),
+            quote_lending_pool: LendingPool::new(Clock::get()?.unix_timestamp),
+            insurance_fund: InsuranceFund {
+                stake_history: Pubkey::default(),
+                total_shares: 0,
+                protocol_shares: 0,
+                unstaking_period: DEFAULT_INSURANCE_FUND_UNSTAKING_PERIOD,
+                fee_share_numerator: DEFAULT_INSURANCE_FUND_FEE_SHARE_NUMERATOR,
+                fee_share_denominator: DEFAULT_INSURANCE_FUND_FEE_SHARE_DENOMINATOR,
+            },
+            bankruptcy_history: Pubkey::default(),
+            position_transfer_discount_numerator: DEFAULT_POSITION_TRANSFER_DISCOUNT_NUMERATOR,
+            position_transfer_discount_denominator: DEFAULT_POSITION_TRANSFER_DISCOUNT_DENOMINATOR,
+            extended_liquidation_history: Pubkey::default(),
+            liquidation_auction: LiquidationAuction {
+                start_liquidator_share_numerator:
+                    DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_NUMERATOR,
+                start_liquidator_share_denominator:
+                    DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_DENOMINATOR,
+                duration: DEFAULT_LIQUIDATION_AUCTION_DURATION,
+            },
+            formulaic_repeg_threshold_numerator: DEFAULT_FORMULAIC_REPEG_THRESHOLD_NUMERATOR,
+            formulaic_repeg_threshold_denominator: DEFAULT_FORMULAIC_REPEG_THRESHOLD_DENOMINATOR,
+            formulaic_k_max_change_numerator: DEFAULT_FORMULAIC_K_MAX_CHANGE_NUMERATOR,
+            formulaic_k_max_change_denominator: DEFAULT_FORMULAIC_K_MAX_CHANGE_DENOMINATOR,
+            extended_funding_rate_history: Pubkey::default(),
+            extended_curve_history: Pubkey::default(),
         };
 
         Ok(())
     }
 
+    #[allow(clippy::result_large_err)]
+    pub fn migrate_state(ctx: Context<MigrateState>) -> Result<()> {
+        state_migration::migrate(
+            &ctx.accounts.state,
+            &ctx.accounts.admin,
+            &ctx.accounts.system_program,
+            ctx.program_id,
+        )
+    }
+
+    /// Gives a market initialized before markets had their own liquidation fees the exchange wide
+    /// ones
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn migrate_market(ctx: Context<AdminUpdateMarket>, market_index: u64) -> Result<()> {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        state_migration::migrate_market(market, &ctx.accounts.state)?;
+        Ok(())
+    }
+
     pub fn initialize_history(ctx: Context<InitializeHistory>) -> Result<()> {
         let state = &mut ctx.accounts.state;
 
         // If all of the history account keys are set to the default, assume they haven't been initialized tet
         if !state.deposit_history.eq(&Pubkey::default())
-            && !state.trade_history.eq(&Pubkey::default())
-            && !state.liquidation_history.eq(&Pubkey::default())
+            && !state.extended_trade_history.eq(&Pubkey::default())
+            && !state.extended_liquidation_history.eq(&Pubkey::default())
             && !state.funding_payment_history.eq(&Pubkey::default())
-            && !state.funding_rate_history.eq(&Pubkey::default())
+            && !state.extended_funding_rate_history.eq(&Pubkey::default())
             && !state.curve_history.eq(&Pubkey::default())
         {
             return Err(ErrorCode::HistoryAlreadyInitialized.into());
@@ -207,15 +278,29 @@ pub mod clearing_house {
         let extended_curve_history = ctx.accounts.curve_history.to_account_info().key;
 
         state.deposit_history = *deposit_history;
-        state.trade_history = *trade_history;
-        state.funding_rate_history = *funding_rate_history;
+        state.extended_trade_history = *trade_history;
+        state.extended_funding_rate_history = *funding_rate_history;
         state.funding_payment_history = *funding_payment_history;
-        state.liquidation_history = *liquidation_history;
+        state.extended_liquidation_history = *liquidation_history;
         state.extended_curve_history = *extended_curve_history;
 
         Ok(())
     }
 
+    #[allow(clippy::result_large_err)]
+    pub fn initialize_bankruptcy_history(ctx: Context<InitializeBankruptcyHistory>) -> Result<()> {
+        let state = &mut ctx.accounts.state;
+
+        if !state.bankruptcy_history.eq(&Pubkey::default()) {
+            return Err(ErrorCode::HistoryAlreadyInitialized.into());
+        }
+
+        state.bankruptcy_history = ctx.accounts.bankruptcy_history.key();
+        ctx.accounts.bankruptcy_history.load_init()?;
+
+        Ok(())
+    }
+
     pub fn initialize_order_state(
         ctx: Context<InitializeOrderState>,
         _order_house_nonce: u8,
@@ -254,6 +339,8 @@ pub mod clearing_house {
         margin_ratio_initial: u32,
         margin_ratio_partial: u32,
         margin_ratio_maintenance: u32,
+        curve_type: CurveType,
+        amplification_coefficient: u64,
     ) -> Result<()> {
         let markets = &mut ctx.accounts.markets.load_mut()?;
         let market = &markets.markets[Markets::index_from_u64(market_index)];
@@ -269,10 +356,22 @@ pub mod clearing_house {
             return Err(ErrorCode::InvalidInitialPeg.into());
         }
 
-        let init_mark_price = amm::calculate_price(
+        let amplification_coefficient_valid = match curve_type {
+            CurveType::ConstantProduct => amplification_coefficient == 0,
+            CurveType::StableSwap => {
+                amplification_coefficient > 0
+                    && amplification_coefficient <= MAX_AMPLIFICATION_COEFFICIENT
+            }
+        };
+        if !amplification_coefficient_valid {
+            return Err(ErrorCode::InvalidAmplificationCoefficient.into());
+        }
+
+        let init_mark_price = get_curve(curve_type, amplification_coefficient).calculate_price(
             amm_quote_asset_reserve,
             amm_base_asset_reserve,
             amm_peg_multiplier,
+            amm_base_asset_reserve,
         )?;
 
         // Verify there's no overflow
@@ -306,6 +405,19 @@ pub mod clearing_house {
             margin_ratio_maintenance,
         )?;
 
+        // The market's liquidation fees start out as the exchange wide ones
+        let (
+            partial_liquidation_penalty,
+            full_liquidation_penalty,
+            partial_liquidation_liquidator_share,
+            full_liquidation_liquidator_share,
+        ) = math::liquidation::calculate_market_liquidation_fees(&ctx.accounts.state)?;
+        validate_liquidation_fees(partial_liquidation_penalty, full_liquidation_penalty)?;
+        validate_liquidation_fees(
+            partial_liquidation_liquidator_share,
+            full_liquidation_liquidator_share,
+        )?;
+
         let market = Market {
             initialized: true,
             base_asset_amount_long: 0,
@@ -315,11 +427,17 @@ pub mod clearing_house {
             margin_ratio_initial, // unit is 20% (+2 decimal places)
             margin_ratio_partial,
             margin_ratio_maintenance,
-            padding0: 0,
-            padding1: 0,
-            padding2: 0,
-            padding3: 0,
-            padding4: 0,
+            partial_liquidation_penalty,
+            full_liquidation_penalty,
+            partial_liquidation_liquidator_share,
+            full_liquidation_liquidator_share,
+            imf_factor: 0,
+            premium_sample_sum: 0,
+            premium_sample_duration: 0,
+            last_premium: 0,
+            last_premium_sample_ts: 0,
+            max_funding_rate: 0,
+            funding_interest_rate: 0,
             amm: AMM {
                 oracle: *ctx.accounts.oracle.key,
                 oracle_source,
@@ -345,10 +463,13 @@ pub mod clearing_house {
                 last_oracle_price: oracle_price,
                 minimum_base_asset_trade_size: 10000000,
                 base_spread: 0,
-                padding0: 0,
-                padding1: 0,
-                padding2: 0,
-                padding3: 0,
+                long_spread: 0,
+                short_spread: 0,
+                max_spread: 0,
+                total_fee_settled_to_insurance_fund: 0,
+                curve_type,
+                amplification_coefficient,
+                padding3: [0; 7],
             },
         };
 
@@ -357,7 +478,109 @@ pub mod clearing_house {
         Ok(())
     }
 
-    pub fn deposit_collateral(ctx: Context<DepositCollateral>, amount: u64) -> Result<()> {
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn initialize_order_book(
+        ctx: Context<InitializeOrderBook>,
+        market_index: u64,
+    ) -> Result<()> {
+        let order_book = &mut ctx.accounts.order_book.load_init()?;
+        order_book.market_index = market_index;
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn initialize_collateral_assets(ctx: Context<InitializeCollateralAssets>) -> Result<()> {
+        let state = &mut ctx.accounts.state;
+
+        if !state.collateral_assets.eq(&Pubkey::default()) {
+            return Err(ErrorCode::CollateralAssetsAlreadyInitialized.into());
+        }
+
+        state.collateral_assets = ctx.accounts.collateral_assets.key();
+        ctx.accounts.collateral_assets.load_init()?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn add_collateral_asset(
+        ctx: Context<AddCollateralAsset>,
+        asset_index: u64,
+        oracle_source: OracleSource,
+        asset_weight: u128,
+        liquidation_discount: u128,
+    ) -> Result<()> {
+        validate_collateral_asset_weights(asset_weight, liquidation_discount)?;
+        let clock = Clock::get()?;
+
+        // the quote asset is deposited through deposit_collateral
+        let collateral_asset_mint = &ctx.accounts.collateral_asset_mint;
+        if collateral_asset_mint
+            .key()
+            .eq(&ctx.accounts.state.collateral_mint)
+        {
+            return Err(ErrorCode::InvalidCollateralAsset.into());
+        }
+
+        let collateral_asset_vault_key = ctx.accounts.collateral_asset_vault.key();
+        let (collateral_asset_vault_authority, collateral_asset_vault_nonce) =
+            Pubkey::find_program_address(&[collateral_asset_vault_key.as_ref()], ctx.program_id);
+
+        // clearing house must be authority of collateral asset vault
+        if ctx.accounts.collateral_asset_vault.owner != collateral_asset_vault_authority {
+            return Err(ErrorCode::InvalidCollateralAccountAuthority.into());
+        }
+
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        if collateral_assets.get_asset(asset_index).initialized {
+            return Err(ErrorCode::InvalidCollateralAsset.into());
+        }
+
+        let collateral_asset = CollateralAsset {
+            initialized: true,
+            mint: collateral_asset_mint.key(),
+            mint_decimals: collateral_asset_mint.decimals,
+            vault: collateral_asset_vault_key,
+            vault_authority: collateral_asset_vault_authority,
+            vault_nonce: collateral_asset_vault_nonce,
+            oracle: ctx.accounts.oracle.key(),
+            oracle_source,
+            asset_weight,
+            liquidation_discount,
+            lending_pool: LendingPool::new(clock.unix_timestamp),
+            padding0: 0,
+            padding1: 0,
+        };
+
+        // Verify oracle is readable
+        collateral_asset.get_oracle_price(&ctx.accounts.oracle, clock.slot)?;
+
+        *collateral_assets.get_asset_mut(asset_index) = collateral_asset;
+
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn initialize_insurance_fund(ctx: Context<InitializeInsuranceFund>) -> Result<()> {
+        let state = &mut ctx.accounts.state;
+
+        if !state.insurance_fund.stake_history.eq(&Pubkey::default()) {
+            return Err(ErrorCode::InsuranceFundAlreadyInitialized.into());
+        }
+
+        state.insurance_fund.stake_history = ctx.accounts.insurance_fund_stake_history.key();
+        ctx.accounts.insurance_fund_stake_history.load_init()?;
+
+        Ok(())
+    }
+
+    pub fn deposit_collateral(
+        ctx: Context<DepositCollateral>,
+        _sub_account_id: u8,
+        amount: u64,
+    ) -> Result<()> {
         let user = &mut ctx.accounts.user;
         let clock = Clock::get()?;
         let now = clock.unix_timestamp;
@@ -366,7 +589,15 @@ pub mod clearing_house {
             return Err(ErrorCode::InsufficientDeposit.into());
         }
 
-        let collateral_before = user.collateral;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_lending_pool(quote_lending_pool, now)?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_before = controller::lending::settle_quote_deposit_interest(
+            user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
         let cumulative_deposits_before = user.cumulative_deposits;
 
         user.collateral = user
@@ -379,7 +610,7 @@ pub mod clearing_house {
             .ok_or_else(math_error!())?;
 
         let markets = &ctx.accounts.markets.load()?;
-        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
         let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
         controller::funding::settle_funding_payment(
             user,
@@ -389,6 +620,11 @@ pub mod clearing_house {
             now,
         )?;
 
+        // Deposits don't load the user's spot balances, so the cross margin auction is only
+        // checked for users without borrows
+        let spot_balances_value = spot_balances_value_lower_bound(user).ok();
+        reset_liquidation_start_slots(user, user_positions, spot_balances_value, markets)?;
+
         controller::token::receive(
             &ctx.accounts.token_program,
             &ctx.accounts.user_collateral_account,
@@ -397,7 +633,6 @@ pub mod clearing_house {
             amount,
         )?;
 
-        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
         let record_id = deposit_history.next_record_id();
         deposit_history.append(DepositRecord {
             ts: now,
@@ -410,6 +645,12 @@ pub mod clearing_house {
             amount,
         });
 
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_before,
+            user.collateral,
+        )?;
+
         if ctx.accounts.state.max_deposit > 0
             && user.cumulative_deposits > cast(ctx.accounts.state.max_deposit)?
         {
@@ -422,16 +663,28 @@ pub mod clearing_house {
     #[access_control(
         exchange_not_paused(&ctx.accounts.state)
     )]
-    pub fn withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64) -> Result<()> {
+    pub fn withdraw_collateral(
+        ctx: Context<WithdrawCollateral>,
+        _sub_account_id: u8,
+        amount: u64,
+    ) -> Result<()> {
         let user = &mut ctx.accounts.user;
         let clock = Clock::get()?;
         let now = clock.unix_timestamp;
 
-        let collateral_before = user.collateral;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_lending_pool(quote_lending_pool, now)?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_before = controller::lending::settle_quote_deposit_interest(
+            user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
         let cumulative_deposits_before = user.cumulative_deposits;
 
         let markets = &ctx.accounts.markets.load()?;
-        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
         let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
         controller::funding::settle_funding_payment(
             user,
@@ -469,7 +722,14 @@ pub mod clearing_house {
             .checked_sub(cast(insurance_account_withdrawal)?)
             .ok_or_else(math_error!())?;
 
-        if !meets_initial_margin_requirement(user, user_positions, markets)? {
+        let spot_balances_value = optional_accounts::get_spot_balances_value(
+            ctx.remaining_accounts,
+            &ctx.accounts.state,
+            user,
+            &user.key(),
+            clock.slot,
+        )?;
+        if !meets_initial_margin_requirement(user, user_positions, spot_balances_value, markets)? {
             return Err(ErrorCode::InsufficientCollateral.into());
         }
 
@@ -493,7 +753,6 @@ pub mod clearing_house {
             )?;
         }
 
-        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
         let record_id = deposit_history.next_record_id();
         deposit_history.append(DepositRecord {
             ts: now,
@@ -506,280 +765,214 @@ pub mod clearing_house {
             amount: amount_withdraw,
         });
 
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_before,
+            user.collateral,
+        )?;
+
         Ok(())
     }
 
-    #[allow(unused_must_use)]
     #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index) &&
-        exchange_not_paused(&ctx.accounts.state) &&
-        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+        exchange_not_paused(&ctx.accounts.state)
     )]
-    pub fn open_position<'info>(
-        ctx: Context<OpenPosition>,
-        direction: PositionDirection,
-        quote_asset_amount: u128,
-        market_index: u64,
-        limit_price: u128,
-        optional_accounts: ManagePositionOptionalAccounts,
+    pub fn transfer_collateral(
+        ctx: Context<TransferCollateral>,
+        from_sub_account_id: u8,
+        to_sub_account_id: u8,
+        amount: u64,
     ) -> Result<()> {
-        let user = &mut ctx.accounts.user;
-        let clock = Clock::get()?;
-        let now = clock.unix_timestamp;
-        let clock_slot = clock.slot;
+        if from_sub_account_id == to_sub_account_id {
+            return Err(ErrorCode::CantTransferToSameSubAccount.into());
+        }
 
-        if quote_asset_amount == 0 {
-            return Err(ErrorCode::TradeSizeTooSmall.into());
+        if amount == 0 {
+            return Err(ErrorCode::InsufficientDeposit.into());
         }
 
-        // Settle user's funding payments so that collateral is up to date
-        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        let markets = &ctx.accounts.markets.load()?;
         let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+
+        let from_user = &mut ctx.accounts.from_user;
+        let from_user_positions =
+            &mut UserPositions::load_positions_mut(&ctx.accounts.from_user_positions)?;
+        let from_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            from_user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
         controller::funding::settle_funding_payment(
-            user,
-            user_positions,
-            &ctx.accounts.markets.load()?,
+            from_user,
+            from_user_positions,
+            markets,
             funding_payment_history,
             now,
         )?;
 
-        // Get existing position or add a new position for market
-        let position_index = get_position_index(user_positions, market_index)
-            .or_else(|_| add_new_position(user_positions, market_index))?;
-        let market_position = &mut user_positions.positions[position_index];
+        let to_user = &mut ctx.accounts.to_user;
+        let to_user_positions =
+            &mut UserPositions::load_positions_mut(&ctx.accounts.to_user_positions)?;
+        let to_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            to_user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        controller::funding::settle_funding_payment(
+            to_user,
+            to_user_positions,
+            markets,
+            funding_payment_history,
+            now,
+        )?;
 
-        // Collect data about position/market before trade is executed so that it can be stored in trade history
-        let mark_price_before: u128;
-        let oracle_mark_spread_pct_before: i128;
-        let is_oracle_valid: bool;
-        {
-            let market = &mut ctx.accounts.markets.load_mut()?.markets
-                [Markets::index_from_u64(market_index)];
-            mark_price_before = market.amm.mark_price()?;
-            let oracle_price_data = &market
-                .amm
-                .get_oracle_price(&ctx.accounts.oracle, clock_slot)?;
-            oracle_mark_spread_pct_before = amm::calculate_oracle_mark_spread_pct(
-                &market.amm,
-                oracle_price_data,
-                Some(mark_price_before),
-            )?;
-            is_oracle_valid = amm::is_oracle_valid(
-                &market.amm,
-                oracle_price_data,
-                &ctx.accounts.state.oracle_guard_rails.validity,
-            )?;
-            if is_oracle_valid {
-                let normalised_oracle_price = normalise_oracle_price(
-                    &market.amm,
-                    oracle_price_data,
-                    Some(mark_price_before),
-                )?;
-                amm::update_oracle_price_twap(&mut market.amm, now, normalised_oracle_price)?;
-            }
+        if cast_to_u128(amount)? > from_user.collateral {
+            return Err(ErrorCode::InsufficientCollateral.into());
         }
 
-        // A trade is risk increasing if it increases the users leverage
-        // If a trade is risk increasing and brings the user's margin ratio below initial requirement
-        // the trade fails
-        // If a trade is risk increasing and it pushes the mark price too far away from the oracle price
-        // the trade fails
-        let potentially_risk_increasing;
-        let base_asset_amount;
-        let mut quote_asset_amount = quote_asset_amount;
-        let quote_asset_amount_surplus;
-        {
-            let markets = &mut ctx.accounts.markets.load_mut()?;
-            let market = markets.get_market_mut(market_index);
-            let (
-                _potentially_risk_increasing,
-                _,
-                _base_asset_amount,
-                _quote_asset_amount,
-                _quote_asset_amount_surplus,
-            ) = controller::position::update_position_with_quote_asset_amount(
-                quote_asset_amount,
-                direction,
-                market,
-                user,
-                market_position,
-                mark_price_before,
-                now,
-            )?;
+        let from_collateral_before = from_user.collateral;
+        let to_collateral_before = to_user.collateral;
 
-            potentially_risk_increasing = _potentially_risk_increasing;
-            base_asset_amount = _base_asset_amount;
-            quote_asset_amount = _quote_asset_amount;
-            quote_asset_amount_surplus = _quote_asset_amount_surplus;
-        }
+        // internal transfers move collateral between sub accounts without changing either
+        // account's cumulative deposits, which only track funds moved in and out of the vault
+        from_user.collateral = from_user
+            .collateral
+            .checked_sub(cast(amount)?)
+            .ok_or_else(math_error!())?;
 
-        // Collect data about position/market after trade is executed so that it can be stored in trade history
-        let mark_price_after: u128;
-        let oracle_price_after: i128;
-        let oracle_mark_spread_pct_after: i128;
-        {
-            let market = &mut ctx.accounts.markets.load_mut()?.markets
-                [Markets::index_from_u64(market_index)];
-            mark_price_after = market.amm.mark_price()?;
-            let oracle_price_data = &market
-                .amm
-                .get_oracle_price(&ctx.accounts.oracle, clock_slot)?;
-            oracle_mark_spread_pct_after = amm::calculate_oracle_mark_spread_pct(
-                &market.amm,
-                oracle_price_data,
-                Some(mark_price_after),
-            )?;
-            oracle_price_after = oracle_price_data.price;
-        }
+        to_user.collateral = to_user
+            .collateral
+            .checked_add(cast(amount)?)
+            .ok_or_else(math_error!())?;
 
-        // Trade fails if it's risk increasing and it brings the user below the initial margin ratio level
-        let meets_initial_margin_requirement =
-            meets_initial_margin_requirement(user, user_positions, &ctx.accounts.markets.load()?)?;
-        if !meets_initial_margin_requirement && potentially_risk_increasing {
+        let spot_balances_value = optional_accounts::get_spot_balances_value(
+            ctx.remaining_accounts,
+            &ctx.accounts.state,
+            from_user,
+            &from_user.key(),
+            clock.slot,
+        )?;
+        if !meets_initial_margin_requirement(
+            from_user,
+            from_user_positions,
+            spot_balances_value,
+            markets,
+        )? {
             return Err(ErrorCode::InsufficientCollateral.into());
         }
 
-        // Calculate the fee to charge the user
-        let (discount_token, referrer) = optional_accounts::get_discount_token_and_referrer(
-            optional_accounts,
-            ctx.remaining_accounts,
-            &ctx.accounts.state.discount_mint,
-            &user.key(),
-            &ctx.accounts.authority.key(),
-        )?;
-        let (user_fee, fee_to_market, token_discount, referrer_reward, referee_discount) =
-            fees::calculate_fee_for_trade(
-                quote_asset_amount,
-                &ctx.accounts.state.fee_structure,
-                discount_token,
-                &referrer,
-                quote_asset_amount_surplus,
-            )?;
+        let to_spot_balances_value = spot_balances_value_lower_bound(to_user).ok();
+        reset_liquidation_start_slots(to_user, to_user_positions, to_spot_balances_value, markets)?;
 
-        // Increment the clearing house's total fee variables
-        {
-            let market = &mut ctx.accounts.markets.load_mut()?.markets
-                [Markets::index_from_u64(market_index)];
-            market.amm.total_fee = market
-                .amm
-                .total_fee
-                .checked_add(fee_to_market)
-                .ok_or_else(math_error!())?;
-            market.amm.total_fee_minus_distributions = market
-                .amm
-                .total_fee_minus_distributions
-                .checked_add(fee_to_market)
-                .ok_or_else(math_error!())?;
-        }
+        let record_id = deposit_history.next_record_id();
+        deposit_history.append(DepositRecord {
+            ts: now,
+            record_id,
+            user_authority: from_user.authority,
+            user: from_user.key(),
+            direction: DepositDirection::TransferOut,
+            collateral_before: from_collateral_before,
+            cumulative_deposits_before: from_user.cumulative_deposits,
+            amount,
+        });
+
+        let record_id = deposit_history.next_record_id();
+        deposit_history.append(DepositRecord {
+            ts: now,
+            record_id,
+            user_authority: to_user.authority,
+            user: to_user.key(),
+            direction: DepositDirection::TransferIn,
+            collateral_before: to_collateral_before,
+            cumulative_deposits_before: to_user.cumulative_deposits,
+            amount,
+        });
 
-        // Subtract the fee from user's collateral
-        user.collateral = user.collateral.saturating_sub(user_fee);
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            from_collateral_settled,
+            from_user.collateral,
+        )?;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            to_collateral_settled,
+            to_user.collateral,
+        )?;
 
-        // Increment the user's total fee variables
-        user.total_fee_paid = user
-            .total_fee_paid
-            .checked_add(cast(user_fee)?)
-            .ok_or_else(math_error!())?;
-        user.total_token_discount = user
-            .total_token_discount
-            .checked_add(token_discount)
-            .ok_or_else(math_error!())?;
-        user.total_referee_discount = user
-            .total_referee_discount
-            .checked_add(referee_discount)
-            .ok_or_else(math_error!())?;
+        Ok(())
+    }
 
-        // Update the referrer's collateral with their reward
-        if referrer.is_some() {
-            let mut referrer = referrer.unwrap();
-            referrer.total_referral_reward = referrer
-                .total_referral_reward
-                .checked_add(referrer_reward)
-                .ok_or_else(math_error!())?;
-            referrer.exit(ctx.program_id)?;
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn add_isolated_collateral(
+        ctx: Context<TransferIsolatedCollateral>,
+        _sub_account_id: u8,
+        market_index: u64,
+        amount: u64,
+    ) -> Result<()> {
+        if amount == 0 {
+            return Err(ErrorCode::InsufficientDeposit.into());
         }
 
-        // Trade fails if the trade is risk increasing and it pushes to mark price too far
-        // away from the oracle price
-        let is_oracle_mark_too_divergent_before = amm::is_oracle_mark_too_divergent(
-            oracle_mark_spread_pct_before,
-            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        let user = &mut ctx.accounts.user;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        let markets = &ctx.accounts.markets.load()?;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
         )?;
-        let is_oracle_mark_too_divergent_after = amm::is_oracle_mark_too_divergent(
-            oracle_mark_spread_pct_after,
-            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            markets,
+            funding_payment_history,
+            now,
         )?;
 
-        // if oracle-mark divergence pushed outside limit, block trade
-        if is_oracle_mark_too_divergent_after
-            && !is_oracle_mark_too_divergent_before
-            && is_oracle_valid
-        {
-            return Err(ErrorCode::OracleMarkSpreadLimit.into());
-        }
-
-        // if oracle-mark divergence outside limit and risk-increasing, block trade
-        if is_oracle_mark_too_divergent_after
-            && oracle_mark_spread_pct_after.unsigned_abs()
-                >= oracle_mark_spread_pct_before.unsigned_abs()
-            && is_oracle_valid
-            && potentially_risk_increasing
-        {
-            return Err(ErrorCode::OracleMarkSpreadLimit.into());
+        let position_index = get_position_index(user_positions, market_index)?;
+        let market_position = &mut user_positions[position_index];
+        if !market_position.is_isolated() {
+            return Err(ErrorCode::PositionNotIsolated.into());
         }
 
-        // Add to the trade history account
-        let trade_history_account = &mut ctx.accounts.trade_history.load_mut()?;
-        let record_id = trade_history_account.next_record_id();
-        trade_history_account.append(TradeRecord {
-            ts: now,
-            record_id,
-            user_authority: *ctx.accounts.authority.to_account_info().key,
-            user: *user.to_account_info().key,
-            direction,
-            base_asset_amount,
-            quote_asset_amount,
-            mark_price_before,
-            mark_price_after,
-            fee: cast(user_fee)?,
-            token_discount,
-            quote_asset_amount_surplus,
-            referee_discount,
-            liquidation: false,
-            market_index,
-            oracle_price: oracle_price_after,
-        });
+        controller::position::isolate_position(user, market_position, cast(amount)?)?;
 
-        // If the user adds a limit price to their trade, check that their entry price is better than the limit price
-        if limit_price != 0
-            && !limit_price_satisfied(
-                limit_price,
-                quote_asset_amount,
-                base_asset_amount,
-                direction,
-            )?
-        {
-            return Err(ErrorCode::SlippageOutsideLimit.into());
+        let spot_balances_value = optional_accounts::get_spot_balances_value(
+            ctx.remaining_accounts,
+            &ctx.accounts.state,
+            user,
+            &user.key(),
+            clock.slot,
+        )?;
+        if !meets_initial_margin_requirement(user, user_positions, spot_balances_value, markets)? {
+            return Err(ErrorCode::InsufficientCollateral.into());
         }
 
-        // Try to update the funding rate at the end of every trade
-        {
-            let market = &mut ctx.accounts.markets.load_mut()?.markets
-                [Markets::index_from_u64(market_index)];
-            let price_oracle = &ctx.accounts.oracle;
-            let funding_rate_history = &mut ctx.accounts.funding_rate_history.load_mut()?;
-            controller::funding::update_funding_rate(
-                market_index,
-                market,
-                price_oracle,
-                now,
-                clock_slot,
-                funding_rate_history,
-                &ctx.accounts.state.oracle_guard_rails,
-                ctx.accounts.state.funding_paused,
-                Some(mark_price_before),
-            )?;
-        }
+        reset_liquidation_start_slots(user, user_positions, Some(spot_balances_value), markets)?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
+        )?;
 
         Ok(())
     }
@@ -787,1088 +980,805 @@ pub mod clearing_house {
     #[allow(unused_must_use)]
     #[access_control(
         market_initialized(&ctx.accounts.markets, market_index) &&
-        exchange_not_paused(&ctx.accounts.state) &&
-        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+        exchange_not_paused(&ctx.accounts.state)
     )]
-    pub fn close_position(
-        ctx: Context<ClosePosition>,
+    pub fn remove_isolated_collateral(
+        ctx: Context<TransferIsolatedCollateral>,
+        _sub_account_id: u8,
         market_index: u64,
-        optional_accounts: ManagePositionOptionalAccounts,
+        amount: u64,
     ) -> Result<()> {
+        if amount == 0 {
+            return Err(ErrorCode::InsufficientDeposit.into());
+        }
+
         let user = &mut ctx.accounts.user;
         let clock = Clock::get()?;
         let now = clock.unix_timestamp;
-        let clock_slot = clock.slot;
 
-        // Settle user's funding payments so that collateral is up to date
-        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let markets = &ctx.accounts.markets.load()?;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
         let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
         controller::funding::settle_funding_payment(
             user,
             user_positions,
-            &ctx.accounts.markets.load()?,
+            markets,
             funding_payment_history,
             now,
         )?;
 
         let position_index = get_position_index(user_positions, market_index)?;
-        let market_position = &mut user_positions.positions[position_index];
-
-        let market =
-            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-
-        // Collect data about market before trade is executed so that it can be stored in trade history
-        let mark_price_before = market.amm.mark_price()?;
-        let oracle_price_data = &market
-            .amm
-            .get_oracle_price(&ctx.accounts.oracle, clock_slot)?;
-        let oracle_mark_spread_pct_before = amm::calculate_oracle_mark_spread_pct(
-            &market.amm,
-            oracle_price_data,
-            Some(mark_price_before),
+        controller::position::remove_isolated_collateral(
+            user,
+            &mut user_positions[position_index],
+            cast(amount)?,
         )?;
-        let direction_to_close =
-            math::position::direction_to_close_position(market_position.base_asset_amount);
-        let (quote_asset_amount, base_asset_amount, quote_asset_amount_surplus) =
-            controller::position::close(
-                user,
-                market,
-                market_position,
-                now,
-                None,
-                Some(mark_price_before),
-                true,
-            )?;
-        let base_asset_amount = base_asset_amount.unsigned_abs();
 
-        // Calculate the fee to charge the user
-        let (discount_token, referrer) = optional_accounts::get_discount_token_and_referrer(
-            optional_accounts,
+        let spot_balances_value = optional_accounts::get_spot_balances_value(
             ctx.remaining_accounts,
-            &ctx.accounts.state.discount_mint,
+            &ctx.accounts.state,
+            user,
             &user.key(),
-            &ctx.accounts.authority.key(),
+            clock.slot,
         )?;
-        let (user_fee, fee_to_market, token_discount, referrer_reward, referee_discount) =
-            fees::calculate_fee_for_trade(
-                quote_asset_amount,
-                &ctx.accounts.state.fee_structure,
-                discount_token,
-                &referrer,
-                quote_asset_amount_surplus,
-            )?;
+        if !meets_margin_requirement_for_market(
+            user,
+            user_positions,
+            spot_balances_value,
+            markets,
+            market_index,
+            MarginType::Init,
+        )? {
+            return Err(ErrorCode::InsufficientCollateral.into());
+        }
 
-        // Increment the clearing house's total fee variables
-        market.amm.total_fee = market
-            .amm
-            .total_fee
-            .checked_add(fee_to_market)
-            .ok_or_else(math_error!())?;
-        market.amm.total_fee_minus_distributions = market
-            .amm
-            .total_fee_minus_distributions
-            .checked_add(fee_to_market)
-            .ok_or_else(math_error!())?;
+        controller::position::release_isolated_collateral(
+            user,
+            &mut user_positions[position_index],
+        )?;
 
-        // Subtract the fee from user's collateral
-        user.collateral = user.collateral.saturating_sub(user_fee);
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
+        )?;
 
-        // Increment the user's total fee variables
-        user.total_fee_paid = user
-            .total_fee_paid
-            .checked_add(cast(user_fee)?)
-            .ok_or_else(math_error!())?;
-        user.total_token_discount = user
-            .total_token_discount
-            .checked_add(token_discount)
-            .ok_or_else(math_error!())?;
-        user.total_referee_discount = user
-            .total_referee_discount
-            .checked_add(referee_discount)
-            .ok_or_else(math_error!())?;
+        Ok(())
+    }
 
-        // Update the referrer's collateral with their reward
-        if referrer.is_some() {
-            let mut referrer = referrer.unwrap();
-            referrer.total_referral_reward = referrer
-                .total_referral_reward
-                .checked_add(referrer_reward)
-                .ok_or_else(math_error!())?;
-            referrer.exit(ctx.program_id)?;
+    #[allow(clippy::result_large_err)]
+    pub fn initialize_user_collateral_assets(
+        ctx: Context<InitializeUserCollateralAssets>,
+    ) -> Result<()> {
+        let user_collateral_assets = &mut ctx.accounts.user_collateral_assets.load_init()?;
+        user_collateral_assets.user = ctx.accounts.user.key();
+        ctx.accounts.user.has_collateral_assets = 1;
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn deposit_collateral_asset(
+        ctx: Context<DepositCollateralAsset>,
+        asset_index: u64,
+        amount: u64,
+    ) -> Result<()> {
+        if amount == 0 {
+            return Err(ErrorCode::InsufficientDeposit.into());
         }
 
-        // Collect data about market after trade is executed so that it can be stored in trade history
-        let mark_price_after = market.amm.mark_price()?;
-        let price_oracle = &ctx.accounts.oracle;
+        let now = Clock::get()?.unix_timestamp;
 
-        let oracle_mark_spread_pct_after = amm::calculate_oracle_mark_spread_pct(
-            &market.amm,
-            oracle_price_data,
-            Some(mark_price_after),
-        )?;
-        let oracle_price_after = oracle_price_data.price;
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        {
+            let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+            if !collateral_asset.initialized
+                || !collateral_asset
+                    .vault
+                    .eq(&ctx.accounts.collateral_asset_vault.key())
+            {
+                return Err(ErrorCode::InvalidCollateralAsset.into());
+            }
 
-        let is_oracle_valid = amm::is_oracle_valid(
-            &market.amm,
-            oracle_price_data,
-            &ctx.accounts.state.oracle_guard_rails.validity,
-        )?;
-        if is_oracle_valid {
-            let normalised_oracle_price =
-                normalise_oracle_price(&market.amm, oracle_price_data, Some(mark_price_before))?;
-            amm::update_oracle_price_twap(&mut market.amm, now, normalised_oracle_price)?;
+            controller::lending::update_lending_pool(&mut collateral_asset.lending_pool, now)?;
         }
 
-        // Trade fails if the trade is risk increasing and it pushes to mark price too far
-        // away from the oracle price
-        let is_oracle_mark_too_divergent_before = amm::is_oracle_mark_too_divergent(
-            oracle_mark_spread_pct_before,
-            &ctx.accounts.state.oracle_guard_rails.price_divergence,
-        )?;
-        let is_oracle_mark_too_divergent_after = amm::is_oracle_mark_too_divergent(
-            oracle_mark_spread_pct_after,
-            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        let user_collateral_assets = &mut ctx.accounts.user_collateral_assets.load_mut()?;
+        controller::lending::settle_collateral_assets_interest(
+            user_collateral_assets,
+            collateral_assets,
+            &ctx.accounts.state.quote_lending_pool,
         )?;
 
-        // if closing position pushes outside of oracle-mark divergence limit, block trade
-        if (is_oracle_mark_too_divergent_after && !is_oracle_mark_too_divergent_before)
-            && is_oracle_valid
-        {
-            return Err(ErrorCode::OracleMarkSpreadLimit.into());
-        }
+        let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+        collateral_asset.lending_pool.total_deposits = collateral_asset
+            .lending_pool
+            .total_deposits
+            .checked_add(cast(amount)?)
+            .ok_or_else(math_error!())?;
 
-        // Add to the trade history account
-        let trade_history_account = &mut ctx.accounts.trade_history.load_mut()?;
-        let record_id = trade_history_account.next_record_id();
-        trade_history_account.append(TradeRecord {
-            ts: now,
-            record_id,
-            user_authority: *ctx.accounts.authority.to_account_info().key,
-            user: *user.to_account_info().key,
-            direction: direction_to_close,
-            base_asset_amount,
-            quote_asset_amount,
-            mark_price_before,
-            mark_price_after,
-            liquidation: false,
-            fee: cast(user_fee)?,
-            token_discount,
-            quote_asset_amount_surplus,
-            referee_discount,
-            market_index,
-            oracle_price: oracle_price_after,
-        });
+        let balance = user_collateral_assets.get_balance_mut(asset_index);
+        *balance = balance
+            .checked_add(cast(amount)?)
+            .ok_or_else(math_error!())?;
 
-        // Try to update the funding rate at the end of every trade
-        let funding_rate_history = &mut ctx.accounts.funding_rate_history.load_mut()?;
-        controller::funding::update_funding_rate(
-            market_index,
-            market,
-            price_oracle,
-            now,
-            clock_slot,
-            funding_rate_history,
-            &ctx.accounts.state.oracle_guard_rails,
-            ctx.accounts.state.funding_paused,
-            Some(mark_price_before),
+        controller::token::receive(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_collateral_asset_account,
+            &ctx.accounts.collateral_asset_vault,
+            &ctx.accounts.authority,
+            amount,
         )?;
 
         Ok(())
     }
 
-    #[allow(unused_must_use)]
     #[access_control(
-        market_initialized(&ctx.accounts.markets, params.market_index)
+        exchange_not_paused(&ctx.accounts.state)
     )]
-    pub fn place_order<'info>(ctx: Context<PlaceOrder>, params: OrderParams) -> Result<()> {
-        let account_info_iter = &mut ctx.remaining_accounts.iter();
-        let discount_token = get_discount_token(
-            params.optional_accounts.discount_token,
-            account_info_iter,
-            &ctx.accounts.state.discount_mint,
-            ctx.accounts.authority.key,
+    pub fn withdraw_collateral_asset(
+        ctx: Context<WithdrawCollateralAsset>,
+        asset_index: u64,
+        amount: u64,
+    ) -> Result<()> {
+        let user = &mut ctx.accounts.user;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        let markets = &ctx.accounts.markets.load()?;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
         )?;
-        let referrer = get_referrer(
-            params.optional_accounts.referrer,
-            account_info_iter,
-            &ctx.accounts.user.key(),
-            None,
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            markets,
+            funding_payment_history,
+            now,
         )?;
 
-        let oracle = get_oracle_for_place_order(account_info_iter, &ctx.accounts.markets, &params)?;
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        {
+            let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+            if !collateral_asset.initialized
+                || !collateral_asset
+                    .vault
+                    .eq(&ctx.accounts.collateral_asset_vault.key())
+                || !collateral_asset
+                    .vault_authority
+                    .eq(&ctx.accounts.collateral_asset_vault_authority.key())
+            {
+                return Err(ErrorCode::InvalidCollateralAsset.into());
+            }
 
-        if params.order_type == OrderType::Market {
-            msg!("market order must be in place and fill");
-            return Err(ErrorCode::MarketOrderMustBeInPlaceAndFill.into());
+            controller::lending::update_lending_pool(&mut collateral_asset.lending_pool, now)?;
         }
 
-        if params.immediate_or_cancel {
-            msg!("immediate_or_cancel order must be in place and fill");
-            return Err(print_error!(ErrorCode::InvalidOrder)().into());
-        }
+        let user_collateral_assets = &mut ctx.accounts.user_collateral_assets.load_mut()?;
+        controller::lending::settle_collateral_assets_interest(
+            user_collateral_assets,
+            collateral_assets,
+            &ctx.accounts.state.quote_lending_pool,
+        )?;
 
-        controller::orders::place_order(
-            &ctx.accounts.state,
-            &ctx.accounts.order_state,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.order_history,
-            discount_token,
-            &referrer,
-            &Clock::get()?,
-            params,
-            oracle,
+        let collateral_asset_vault_nonce = {
+            let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+            let balance = user_collateral_assets.get_balance_mut(asset_index);
+            if cast_to_u128(amount)? > *balance {
+                return Err(ErrorCode::InsufficientCollateral.into());
+            }
+
+            *balance = balance
+                .checked_sub(cast(amount)?)
+                .ok_or_else(math_error!())?;
+            collateral_asset.lending_pool.total_deposits = collateral_asset
+                .lending_pool
+                .total_deposits
+                .checked_sub(cast(amount)?)
+                .ok_or_else(math_error!())?;
+
+            collateral_asset.vault_nonce
+        };
+
+        // The remaining accounts are the oracles for the collateral assets the user still holds
+        let oracle_account_infos: OracleAccountInfos = ctx
+            .remaining_accounts
+            .iter()
+            .map(|account_info| (account_info.key(), account_info))
+            .collect();
+        let spot_balances_value = calculate_spot_balances_value(
+            user_collateral_assets,
+            collateral_assets,
+            &ctx.accounts.state.quote_lending_pool,
+            &oracle_account_infos,
+            clock.slot,
+            &ctx.accounts.state.oracle_guard_rails.validity,
         )?;
 
-        Ok(())
-    }
+        if !meets_initial_margin_requirement(user, user_positions, spot_balances_value, markets)? {
+            return Err(ErrorCode::InsufficientCollateral.into());
+        }
 
-    pub fn cancel_order(ctx: Context<CancelOrder>, order_id: u128) -> Result<()> {
-        let account_info_iter = &mut ctx.remaining_accounts.iter();
-        let oracle = get_oracle_for_cancel_order_by_order_id(
-            account_info_iter,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.markets,
-            order_id,
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.collateral_asset_vault,
+            &ctx.accounts.user_collateral_asset_account,
+            &ctx.accounts.collateral_asset_vault_authority,
+            collateral_asset_vault_nonce,
+            amount,
         )?;
 
-        controller::orders::cancel_order_by_order_id(
-            &ctx.accounts.state,
-            order_id,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.order_history,
-            &Clock::get()?,
-            oracle,
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
         )?;
 
         Ok(())
     }
 
-    pub fn cancel_order_by_user_id(ctx: Context<CancelOrder>, user_order_id: u8) -> Result<()> {
-        let account_info_iter = &mut ctx.remaining_accounts.iter();
-        let oracle = get_oracle_for_cancel_order_by_user_order_id(
-            account_info_iter,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.markets,
-            user_order_id,
+    /// Covers the part of a user's losses that their quote collateral can't by selling their
+    /// collateral assets to the liquidator at a discount to the oracle price
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn liquidate_collateral_asset(
+        ctx: Context<LiquidateCollateralAsset>,
+        asset_index: u64,
+        max_quote_asset_amount: u64,
+    ) -> Result<()> {
+        let user = &mut ctx.accounts.user;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        let state = &mut ctx.accounts.state;
+        controller::lending::update_lending_pool(&mut state.quote_lending_pool, now)?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut state.quote_lending_pool,
+            deposit_history,
+            now,
         )?;
 
-        controller::orders::cancel_order_by_user_order_id(
-            &ctx.accounts.state,
-            user_order_id,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.order_history,
-            &Clock::get()?,
-            oracle,
+        let markets = &ctx.accounts.markets.load()?;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            markets,
+            funding_payment_history,
+            now,
         )?;
 
-        Ok(())
-    }
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        {
+            let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+            if !collateral_asset.initialized
+                || !collateral_asset
+                    .vault
+                    .eq(&ctx.accounts.collateral_asset_vault.key())
+                || !collateral_asset
+                    .vault_authority
+                    .eq(&ctx.accounts.collateral_asset_vault_authority.key())
+                || !collateral_asset.oracle.eq(&ctx.accounts.oracle.key())
+            {
+                return Err(ErrorCode::InvalidCollateralAsset.into());
+            }
 
-    pub fn cancel_all_orders(ctx: Context<CancelOrder>, best_effort: bool) -> Result<()> {
-        controller::orders::cancel_all_orders(
-            &ctx.accounts.state,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.order_history,
-            &Clock::get()?,
-            ctx.remaining_accounts,
-            best_effort,
-            None,
-            None,
+            controller::lending::update_lending_pool(&mut collateral_asset.lending_pool, now)?;
+        }
+
+        let user_collateral_assets = &mut ctx.accounts.user_collateral_assets.load_mut()?;
+        controller::lending::settle_collateral_assets_interest(
+            user_collateral_assets,
+            collateral_assets,
+            &state.quote_lending_pool,
         )?;
 
-        Ok(())
-    }
+        let quote_collateral_deficit = calculate_quote_collateral_deficit(
+            user,
+            user_positions,
+            user_collateral_assets.quote_borrows,
+            markets,
+        )?;
+        if quote_collateral_deficit == 0 {
+            return Err(ErrorCode::NoQuoteCollateralDeficit.into());
+        }
 
-    pub fn cancel_orders_by_market_and_side(
-        ctx: Context<CancelOrder>,
-        best_effort: bool,
-        market_index_only: u64,
-        direction_only: PositionDirection,
-    ) -> Result<()> {
-        controller::orders::cancel_all_orders(
-            &ctx.accounts.state,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.order_history,
-            &Clock::get()?,
-            ctx.remaining_accounts,
-            best_effort,
-            Some(market_index_only),
-            Some(direction_only),
-        )?;
-
-        Ok(())
-    }
-
-    pub fn expire_orders(ctx: Context<ExpireOrder>) -> Result<()> {
-        controller::orders::expire_orders(
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.user_orders,
-            &mut ctx.accounts.filler,
-            &ctx.accounts.order_history,
-            &Clock::get()?,
-        )?;
-        Ok(())
-    }
+        let collateral_asset = collateral_assets.get_asset_mut(asset_index);
 
-    #[access_control(
-        exchange_not_paused(&ctx.accounts.state)
-    )]
-    pub fn fill_order<'info>(ctx: Context<FillOrder>, order_id: u128) -> Result<()> {
-        let account_info_iter = &mut ctx.remaining_accounts.iter();
-        let referrer = get_referrer_for_fill_order(
-            account_info_iter,
-            &ctx.accounts.user.key(),
-            order_id,
-            &ctx.accounts.user_orders,
+        let oracle_price = get_valid_collateral_asset_price(
+            collateral_asset,
+            &ctx.accounts.oracle,
+            clock.slot,
+            &state.oracle_guard_rails.validity,
         )?;
+        let liquidation_price = cast_to_u128(oracle_price)?
+            .checked_mul(
+                MARGIN_PRECISION
+                    .checked_sub(collateral_asset.liquidation_discount)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?
+            .checked_div(MARGIN_PRECISION)
+            .ok_or_else(math_error!())?;
 
-        let base_asset_amount = controller::orders::fill_order(
-            order_id,
-            &ctx.accounts.state,
-            &ctx.accounts.order_state,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.oracle,
-            &ctx.accounts.user_orders,
-            &mut ctx.accounts.filler,
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.trade_history,
-            &ctx.accounts.order_history,
-            &ctx.accounts.funding_rate_history,
-            referrer,
-            &Clock::get()?,
+        let balance = user_collateral_assets.get_balance_mut(asset_index);
+        let balance_value = calculate_collateral_asset_value(
+            *balance,
+            collateral_asset,
+            cast_to_i128(liquidation_price)?,
+            MARGIN_PRECISION,
         )?;
 
-        if base_asset_amount == 0 {
-            return Err(print_error!(ErrorCode::CouldNotFillOrder)().into());
+        let quote_asset_amount = min(
+            min(
+                quote_collateral_deficit,
+                cast_to_u128(max_quote_asset_amount)?,
+            ),
+            balance_value,
+        );
+        if quote_asset_amount == 0 {
+            return Err(ErrorCode::InsufficientCollateral.into());
         }
 
-        Ok(())
-    }
+        // Swapping the whole balance avoids leaving dust behind from rounding
+        let asset_amount = if quote_asset_amount == balance_value {
+            *balance
+        } else {
+            min(
+                calculate_collateral_asset_amount(
+                    quote_asset_amount,
+                    collateral_asset,
+                    liquidation_price,
+                )?,
+                *balance,
+            )
+        };
 
-    #[allow(unused_must_use)]
-    #[access_control(
-        exchange_not_paused(&ctx.accounts.state) &&
-        market_initialized(&ctx.accounts.markets, params.market_index) &&
-        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, params.market_index)
-    )]
-    pub fn place_and_fill_order<'info>(
-        ctx: Context<PlaceAndFillOrder>,
-        params: OrderParams,
-    ) -> Result<()> {
-        let account_info_iter = &mut ctx.remaining_accounts.iter();
-        let discount_token = get_discount_token(
-            params.optional_accounts.discount_token,
-            account_info_iter,
-            &ctx.accounts.state.discount_mint,
-            ctx.accounts.authority.key,
-        )?;
-        let referrer = get_referrer(
-            params.optional_accounts.referrer,
-            account_info_iter,
-            &ctx.accounts.user.key(),
-            None,
+        *balance = balance
+            .checked_sub(asset_amount)
+            .ok_or_else(math_error!())?;
+        collateral_asset.lending_pool.total_deposits = collateral_asset
+            .lending_pool
+            .total_deposits
+            .checked_sub(asset_amount)
+            .ok_or_else(math_error!())?;
+
+        // The quote paid by the liquidator repays the user's quote borrows before the rest is
+        // added to their collateral
+        let quote_borrows_repaid = min(quote_asset_amount, user_collateral_assets.quote_borrows);
+        user_collateral_assets.quote_borrows = user_collateral_assets
+            .quote_borrows
+            .checked_sub(quote_borrows_repaid)
+            .ok_or_else(math_error!())?;
+        // The pool's borrows are accrued separately from the users' so they can be off by rounding
+        state.quote_lending_pool.total_borrows = state
+            .quote_lending_pool
+            .total_borrows
+            .saturating_sub(quote_borrows_repaid);
+        controller::lending::update_has_borrows(user, user_collateral_assets);
+
+        let collateral_before = user.collateral;
+        let cumulative_deposits_before = user.cumulative_deposits;
+
+        let collateral_added = quote_asset_amount
+            .checked_sub(quote_borrows_repaid)
+            .ok_or_else(math_error!())?;
+        user.collateral = user
+            .collateral
+            .checked_add(collateral_added)
+            .ok_or_else(math_error!())?;
+
+        controller::token::receive(
+            &ctx.accounts.token_program,
+            &ctx.accounts.liquidator_collateral_account,
+            &ctx.accounts.collateral_vault,
+            &ctx.accounts.authority,
+            cast(quote_asset_amount)?,
         )?;
-        let is_immediate_or_cancel = params.immediate_or_cancel;
-        let base_asset_amount_to_fill = params.base_asset_amount;
 
-        controller::orders::place_order(
-            &ctx.accounts.state,
-            &ctx.accounts.order_state,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.user_orders,
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.order_history,
-            discount_token,
-            &referrer,
-            &Clock::get()?,
-            params,
-            Some(&ctx.accounts.oracle),
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.collateral_asset_vault,
+            &ctx.accounts.liquidator_collateral_asset_account,
+            &ctx.accounts.collateral_asset_vault_authority,
+            collateral_asset.vault_nonce,
+            cast(asset_amount)?,
         )?;
 
-        let order_id;
-        {
-            let order_history = &ctx.accounts.order_history.load()?;
-            order_id = order_history.last_order_id;
-        }
+        let record_id = deposit_history.next_record_id();
+        deposit_history.append(DepositRecord {
+            ts: now,
+            record_id,
+            user_authority: user.authority,
+            user: user.key(),
+            direction: DepositDirection::CollateralAssetSwap,
+            collateral_before,
+            cumulative_deposits_before,
+            amount: cast(collateral_added)?,
+        });
 
-        let user = &mut ctx.accounts.user;
-        let base_asset_amount_filled = controller::orders::fill_order(
-            order_id,
-            &ctx.accounts.state,
-            &ctx.accounts.order_state,
-            user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.markets,
-            &ctx.accounts.oracle,
-            &ctx.accounts.user_orders,
-            &mut user.clone(),
-            &ctx.accounts.funding_payment_history,
-            &ctx.accounts.trade_history,
-            &ctx.accounts.order_history,
-            &ctx.accounts.funding_rate_history,
-            referrer,
-            &Clock::get()?,
+        controller::lending::update_quote_deposits(
+            &mut state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
         )?;
 
-        if is_immediate_or_cancel && base_asset_amount_to_fill != base_asset_amount_filled {
-            controller::orders::cancel_order_by_order_id(
-                &ctx.accounts.state,
-                order_id,
-                &mut ctx.accounts.user,
-                &ctx.accounts.user_positions,
-                &ctx.accounts.markets,
-                &ctx.accounts.user_orders,
-                &ctx.accounts.funding_payment_history,
-                &ctx.accounts.order_history,
-                &Clock::get()?,
-                Some(&ctx.accounts.oracle),
-            )?;
-        }
-
         Ok(())
     }
 
+    /// Borrows the quote asset if no asset index is passed. The remaining accounts are the oracles
+    /// for the collateral assets the user holds or owes.
+    #[allow(clippy::result_large_err)]
     #[access_control(
         exchange_not_paused(&ctx.accounts.state)
     )]
-    pub fn liquidate(ctx: Context<Liquidate>) -> Result<()> {
-        let state = &ctx.accounts.state;
+    pub fn borrow(ctx: Context<ManageBorrow>, asset_index: Option<u64>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidBorrow.into());
+        }
+
         let user = &mut ctx.accounts.user;
-        let trade_history = &mut ctx.accounts.trade_history.load_mut()?;
         let clock = Clock::get()?;
         let now = clock.unix_timestamp;
-        let clock_slot = clock.slot;
 
-        // Settle user's funding payments so that collateral is up to date
-        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
+        let markets = &ctx.accounts.markets.load()?;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
         let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
         controller::funding::settle_funding_payment(
             user,
             user_positions,
-            &ctx.accounts.markets.load()?,
+            markets,
             funding_payment_history,
             now,
         )?;
 
-        let LiquidationStatus {
-            liquidation_type,
-            total_collateral,
-            adjusted_total_collateral,
-            unrealized_pnl,
-            base_asset_value,
-            market_statuses,
-            mut margin_requirement,
-            margin_ratio,
-        } = calculate_liquidation_status(
-            user,
-            user_positions,
-            &ctx.accounts.markets.load()?,
-            ctx.remaining_accounts,
-            &ctx.accounts.state.oracle_guard_rails,
-            clock_slot,
+        let state = &mut ctx.accounts.state;
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        let vault_nonce = controller::lending::update_borrowed_lending_pool(
+            state,
+            collateral_assets,
+            asset_index,
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_authority,
+            now,
         )?;
 
-        // Verify that the user is in liquidation territory
-        let collateral = user.collateral;
-        if liquidation_type == LiquidationType::NONE {
-            msg!("total_collateral {}", total_collateral);
-            msg!("adjusted_total_collateral {}", adjusted_total_collateral);
-            msg!("margin_requirement {}", margin_requirement);
-            return Err(ErrorCode::SufficientCollateral.into());
+        let user_collateral_assets = &mut ctx.accounts.user_collateral_assets.load_mut()?;
+        controller::lending::settle_collateral_assets_interest(
+            user_collateral_assets,
+            collateral_assets,
+            &state.quote_lending_pool,
+        )?;
+
+        let borrow_amount = cast_to_u128(amount)?;
+        match asset_index {
+            None => {
+                user_collateral_assets.quote_borrows = user_collateral_assets
+                    .quote_borrows
+                    .checked_add(borrow_amount)
+                    .ok_or_else(math_error!())?;
+                state.quote_lending_pool.total_borrows = state
+                    .quote_lending_pool
+                    .total_borrows
+                    .checked_add(borrow_amount)
+                    .ok_or_else(math_error!())?;
+            }
+            Some(asset_index) => {
+                let borrows = user_collateral_assets.get_borrows_mut(asset_index);
+                *borrows = borrows
+                    .checked_add(borrow_amount)
+                    .ok_or_else(math_error!())?;
+                let lending_pool = &mut collateral_assets.get_asset_mut(asset_index).lending_pool;
+                lending_pool.total_borrows = lending_pool
+                    .total_borrows
+                    .checked_add(borrow_amount)
+                    .ok_or_else(math_error!())?;
+            }
         }
+        user.has_borrows = 1;
+
+        let oracle_account_infos: OracleAccountInfos = ctx
+            .remaining_accounts
+            .iter()
+            .map(|account_info| (account_info.key(), account_info))
+            .collect();
+        let spot_balances_value = calculate_spot_balances_value(
+            user_collateral_assets,
+            collateral_assets,
+            &state.quote_lending_pool,
+            &oracle_account_infos,
+            clock.slot,
+            &state.oracle_guard_rails.validity,
+        )?;
 
-        let is_dust_position = adjusted_total_collateral <= QUOTE_PRECISION;
+        if !meets_initial_margin_requirement(user, user_positions, spot_balances_value, markets)? {
+            return Err(ErrorCode::InsufficientCollateral.into());
+        }
 
-        // Keep track to the value of positions closed. For full liquidation this is the user's entire position,
-        // for partial it is less (it's based on the clearing house state)
-        let mut base_asset_value_closed: u128 = 0;
-        let mut liquidation_fee = 0_u128;
-        // have to fully liquidate dust positions to make it worth it for liquidators
-        let is_full_liquidation = liquidation_type == LiquidationType::FULL || is_dust_position;
-        if is_full_liquidation {
-            let markets = &mut ctx.accounts.markets.load_mut()?;
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.vault_authority,
+            vault_nonce,
+            amount,
+        )?;
 
-            let maximum_liquidation_fee = total_collateral
-                .checked_mul(state.full_liquidation_penalty_percentage_numerator)
-                .ok_or_else(math_error!())?
-                .checked_div(state.full_liquidation_penalty_percentage_denominator)
-                .ok_or_else(math_error!())?;
-            for market_status in market_statuses.iter() {
-                if market_status.base_asset_value == 0 {
-                    continue;
-                }
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
+        )?;
 
-                let market = markets.get_market_mut(market_status.market_index);
-                let mark_price_before = market_status.mark_price_before;
-                let oracle_status = &market_status.oracle_status;
-
-                // if the oracle is invalid and the mark moves too far from twap, dont liquidate
-                let oracle_is_valid = oracle_status.is_valid;
-                if !oracle_is_valid {
-                    let mark_twap_divergence =
-                        calculate_mark_twap_spread_pct(&market.amm, mark_price_before)?;
-                    let mark_twap_too_divergent =
-                        mark_twap_divergence.unsigned_abs() >= MAX_MARK_TWAP_DIVERGENCE;
-
-                    if mark_twap_too_divergent {
-                        let market_index = market_status.market_index;
-                        msg!(
-                            "mark_twap_divergence {} for market {}",
-                            mark_twap_divergence,
-                            market_index
-                        );
-                        continue;
-                    }
-                }
+        Ok(())
+    }
 
-                let market_position = &mut user_positions
-                    .positions
-                    .iter_mut()
-                    .find(|position| position.market_index == market_status.market_index)
-                    .unwrap();
-
-                let mark_price_before_i128 = cast_to_i128(mark_price_before)?;
-                let close_position_slippage = match market_status.close_position_slippage {
-                    Some(close_position_slippage) => close_position_slippage,
-                    None => calculate_slippage(
-                        market_status.base_asset_value,
-                        market_position.base_asset_amount.unsigned_abs(),
-                        mark_price_before_i128,
-                    )?,
-                };
-                let close_position_slippage_pct =
-                    calculate_slippage_pct(close_position_slippage, mark_price_before_i128)?;
+    /// Repays the quote asset if no asset index is passed. Repays at most what the user owes.
+    #[allow(clippy::result_large_err)]
+    pub fn repay(ctx: Context<ManageBorrow>, asset_index: Option<u64>, amount: u64) -> Result<()> {
+        let user = &mut ctx.accounts.user;
+        let now = Clock::get()?.unix_timestamp;
 
-                let close_slippage_pct_too_large = close_position_slippage_pct
-                    > MAX_LIQUIDATION_SLIPPAGE
-                    || close_position_slippage_pct < -MAX_LIQUIDATION_SLIPPAGE;
+        let state = &mut ctx.accounts.state;
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        controller::lending::update_borrowed_lending_pool(
+            state,
+            collateral_assets,
+            asset_index,
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_authority,
+            now,
+        )?;
 
-                let oracle_mark_divergence_after_close = if !close_slippage_pct_too_large {
-                    oracle_status
-                        .oracle_mark_spread_pct
-                        .checked_add(close_position_slippage_pct)
-                        .ok_or_else(math_error!())?
-                } else if close_position_slippage_pct > 0 {
-                    oracle_status
-                        .oracle_mark_spread_pct
-                        // approximates price impact based on slippage
-                        .checked_add(MAX_LIQUIDATION_SLIPPAGE * 2)
-                        .ok_or_else(math_error!())?
-                } else {
-                    oracle_status
-                        .oracle_mark_spread_pct
-                        // approximates price impact based on slippage
-                        .checked_sub(MAX_LIQUIDATION_SLIPPAGE * 2)
-                        .ok_or_else(math_error!())?
-                };
+        let user_collateral_assets = &mut ctx.accounts.user_collateral_assets.load_mut()?;
+        controller::lending::settle_collateral_assets_interest(
+            user_collateral_assets,
+            collateral_assets,
+            &state.quote_lending_pool,
+        )?;
 
-                let oracle_mark_too_divergent_after_close = is_oracle_mark_too_divergent(
-                    oracle_mark_divergence_after_close,
-                    &state.oracle_guard_rails.price_divergence,
-                )?;
+        // The pool's borrows are accrued separately from the users' so they can be off by rounding
+        let repay_amount = match asset_index {
+            None => {
+                let repay_amount = min(cast_to_u128(amount)?, user_collateral_assets.quote_borrows);
+                user_collateral_assets.quote_borrows = user_collateral_assets
+                    .quote_borrows
+                    .checked_sub(repay_amount)
+                    .ok_or_else(math_error!())?;
+                state.quote_lending_pool.total_borrows = state
+                    .quote_lending_pool
+                    .total_borrows
+                    .saturating_sub(repay_amount);
+                repay_amount
+            }
+            Some(asset_index) => {
+                let borrows = user_collateral_assets.get_borrows_mut(asset_index);
+                let repay_amount = min(cast_to_u128(amount)?, *borrows);
+                *borrows = borrows
+                    .checked_sub(repay_amount)
+                    .ok_or_else(math_error!())?;
+                let lending_pool = &mut collateral_assets.get_asset_mut(asset_index).lending_pool;
+                lending_pool.total_borrows =
+                    lending_pool.total_borrows.saturating_sub(repay_amount);
+                repay_amount
+            }
+        };
 
-                // if closing pushes outside the oracle mark threshold, don't liquidate
-                if oracle_is_valid && oracle_mark_too_divergent_after_close {
-                    // but only skip the liquidation if it makes the divergence worse
-                    if oracle_status.oracle_mark_spread_pct.unsigned_abs()
-                        < oracle_mark_divergence_after_close.unsigned_abs()
-                    {
-                        let market_index = market_position.market_index;
-                        msg!(
-                            "oracle_mark_divergence_after_close {} for market {}",
-                            oracle_mark_divergence_after_close,
-                            market_index,
-                        );
-                        continue;
-                    }
-                }
+        if repay_amount == 0 {
+            return Err(ErrorCode::InvalidBorrow.into());
+        }
 
-                let direction_to_close =
-                    math::position::direction_to_close_position(market_position.base_asset_amount);
+        controller::lending::update_has_borrows(user, user_collateral_assets);
 
-                // just reduce position if position is too big
-                let (quote_asset_amount, base_asset_amount) = if close_slippage_pct_too_large {
-                    let quote_asset_amount = market_status
-                        .base_asset_value
-                        .checked_mul(MAX_LIQUIDATION_SLIPPAGE_U128)
-                        .ok_or_else(math_error!())?
-                        .checked_div(close_position_slippage_pct.unsigned_abs())
-                        .ok_or_else(math_error!())?;
-
-                    let (base_asset_amount, _) = controller::position::reduce(
-                        direction_to_close,
-                        quote_asset_amount,
-                        user,
-                        market,
-                        market_position,
-                        now,
-                        Some(mark_price_before),
-                        false,
-                    )?;
-
-                    (quote_asset_amount, base_asset_amount)
-                } else {
-                    let (quote_asset_amount, base_asset_amount, _) = controller::position::close(
-                        user,
-                        market,
-                        market_position,
-                        now,
-                        None,
-                        Some(mark_price_before),
-                        false,
-                    )?;
-
-                    (quote_asset_amount, base_asset_amount)
-                };
-
-                let base_asset_amount = base_asset_amount.unsigned_abs();
-                base_asset_value_closed = base_asset_value_closed
-                    .checked_add(quote_asset_amount)
-                    .ok_or_else(math_error!())?;
-                let mark_price_after = market.amm.mark_price()?;
-
-                let record_id = trade_history.next_record_id();
-                trade_history.append(TradeRecord {
-                    ts: now,
-                    record_id,
-                    user_authority: user.authority,
-                    user: *user.to_account_info().key,
-                    direction: direction_to_close,
-                    base_asset_amount,
-                    quote_asset_amount,
-                    mark_price_before,
-                    mark_price_after,
-                    fee: 0,
-                    token_discount: 0,
-                    quote_asset_amount_surplus: 0,
-                    referee_discount: 0,
-                    liquidation: true,
-                    market_index: market_position.market_index,
-                    oracle_price: market_status.oracle_status.price_data.price,
-                });
-
-                margin_requirement = margin_requirement
-                    .checked_sub(
-                        market_status
-                            .maintenance_margin_requirement
-                            .checked_mul(quote_asset_amount)
-                            .ok_or_else(math_error!())?
-                            .checked_div(market_status.base_asset_value)
-                            .ok_or_else(math_error!())?,
-                    )
-                    .ok_or_else(math_error!())?;
-
-                let market_liquidation_fee = maximum_liquidation_fee
-                    .checked_mul(quote_asset_amount)
-                    .ok_or_else(math_error!())?
-                    .checked_div(base_asset_value)
-                    .ok_or_else(math_error!())?;
-
-                liquidation_fee = liquidation_fee
-                    .checked_add(market_liquidation_fee)
-                    .ok_or_else(math_error!())?;
-
-                let adjusted_total_collateral_after_fee = adjusted_total_collateral
-                    .checked_sub(liquidation_fee)
-                    .ok_or_else(math_error!())?;
-
-                if !is_dust_position && margin_requirement < adjusted_total_collateral_after_fee {
-                    break;
-                }
-            }
-        } else {
-            let markets = &mut ctx.accounts.markets.load_mut()?;
-
-            let maximum_liquidation_fee = total_collateral
-                .checked_mul(state.partial_liquidation_penalty_percentage_numerator)
-                .ok_or_else(math_error!())?
-                .checked_div(state.partial_liquidation_penalty_percentage_denominator)
-                .ok_or_else(math_error!())?;
-            let maximum_base_asset_value_closed = base_asset_value
-                .checked_mul(state.partial_liquidation_close_percentage_numerator)
-                .ok_or_else(math_error!())?
-                .checked_div(state.partial_liquidation_close_percentage_denominator)
-                .ok_or_else(math_error!())?;
-            for market_status in market_statuses.iter() {
-                if market_status.base_asset_value == 0 {
-                    continue;
-                }
-
-                let oracle_status = &market_status.oracle_status;
-                let market = markets.get_market_mut(market_status.market_index);
-                let mark_price_before = market_status.mark_price_before;
-
-                let oracle_is_valid = oracle_status.is_valid;
-                if !oracle_is_valid {
-                    let mark_twap_divergence =
-                        calculate_mark_twap_spread_pct(&market.amm, mark_price_before)?;
-                    let mark_twap_too_divergent =
-                        mark_twap_divergence.unsigned_abs() >= MAX_MARK_TWAP_DIVERGENCE;
-
-                    if mark_twap_too_divergent {
-                        let market_index = market_status.market_index;
-                        msg!(
-                            "mark_twap_divergence {} for market {}",
-                            mark_twap_divergence,
-                            market_index
-                        );
-                        continue;
-                    }
-                }
-
-                let market_position = &mut user_positions
-                    .positions
-                    .iter_mut()
-                    .find(|position| position.market_index == market_status.market_index)
-                    .unwrap();
-
-                let mut quote_asset_amount = market_status
-                    .base_asset_value
-                    .checked_mul(state.partial_liquidation_close_percentage_numerator)
-                    .ok_or_else(math_error!())?
-                    .checked_div(state.partial_liquidation_close_percentage_denominator)
-                    .ok_or_else(math_error!())?;
-
-                let mark_price_before_i128 = cast_to_i128(mark_price_before)?;
-                let reduce_position_slippage = match market_status.close_position_slippage {
-                    Some(close_position_slippage) => close_position_slippage.div(4),
-                    None => calculate_slippage(
-                        market_status.base_asset_value,
-                        market_position.base_asset_amount.unsigned_abs(),
-                        mark_price_before_i128,
-                    )?
-                    .div(4),
-                };
-
-                let reduce_position_slippage_pct =
-                    calculate_slippage_pct(reduce_position_slippage, mark_price_before_i128)?;
-
-                msg!(
-                    "reduce_position_slippage_pct {}",
-                    reduce_position_slippage_pct
-                );
-
-                let reduce_slippage_pct_too_large = reduce_position_slippage_pct
-                    > MAX_LIQUIDATION_SLIPPAGE
-                    || reduce_position_slippage_pct < -MAX_LIQUIDATION_SLIPPAGE;
-
-                let oracle_mark_divergence_after_reduce = if !reduce_slippage_pct_too_large {
-                    oracle_status
-                        .oracle_mark_spread_pct
-                        .checked_add(reduce_position_slippage_pct)
-                        .ok_or_else(math_error!())?
-                } else if reduce_position_slippage_pct > 0 {
-                    oracle_status
-                        .oracle_mark_spread_pct
-                        // approximates price impact based on slippage
-                        .checked_add(MAX_LIQUIDATION_SLIPPAGE * 2)
-                        .ok_or_else(math_error!())?
-                } else {
-                    oracle_status
-                        .oracle_mark_spread_pct
-                        // approximates price impact based on slippage
-                        .checked_sub(MAX_LIQUIDATION_SLIPPAGE * 2)
-                        .ok_or_else(math_error!())?
-                };
-
-                let oracle_mark_too_divergent_after_reduce = is_oracle_mark_too_divergent(
-                    oracle_mark_divergence_after_reduce,
-                    &state.oracle_guard_rails.price_divergence,
-                )?;
-
-                // if reducing pushes outside the oracle mark threshold, don't liquidate
-                if oracle_is_valid && oracle_mark_too_divergent_after_reduce {
-                    // but only skip the liquidation if it makes the divergence worse
-                    if oracle_status.oracle_mark_spread_pct.unsigned_abs()
-                        < oracle_mark_divergence_after_reduce.unsigned_abs()
-                    {
-                        msg!(
-                            "oracle_mark_spread_pct_after_reduce {}",
-                            oracle_mark_divergence_after_reduce
-                        );
-                        return Err(ErrorCode::OracleMarkSpreadLimit.into());
-                    }
-                }
-
-                if reduce_slippage_pct_too_large {
-                    quote_asset_amount = quote_asset_amount
-                        .checked_mul(MAX_LIQUIDATION_SLIPPAGE_U128)
-                        .ok_or_else(math_error!())?
-                        .checked_div(reduce_position_slippage_pct.unsigned_abs())
-                        .ok_or_else(math_error!())?;
-                }
-
-                base_asset_value_closed = base_asset_value_closed
-                    .checked_add(quote_asset_amount)
-                    .ok_or_else(math_error!())?;
+        controller::token::receive(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.authority,
+            cast(repay_amount)?,
+        )?;
 
-                let direction_to_reduce =
-                    math::position::direction_to_close_position(market_position.base_asset_amount);
+        Ok(())
+    }
 
-                let (base_asset_amount, _) = controller::position::reduce(
-                    direction_to_reduce,
-                    quote_asset_amount,
-                    user,
-                    market,
-                    market_position,
-                    now,
-                    Some(mark_price_before),
-                    false,
-                )?;
-                let base_asset_amount = base_asset_amount.unsigned_abs();
-
-                let mark_price_after = market.amm.mark_price()?;
-
-                let record_id = trade_history.next_record_id();
-                trade_history.append(TradeRecord {
-                    ts: now,
-                    record_id,
-                    user_authority: user.authority,
-                    user: *user.to_account_info().key,
-                    direction: direction_to_reduce,
-                    base_asset_amount,
-                    quote_asset_amount,
-                    mark_price_before,
-                    mark_price_after,
-                    fee: 0,
-                    token_discount: 0,
-                    quote_asset_amount_surplus: 0,
-                    referee_discount: 0,
-                    liquidation: true,
-                    market_index: market_position.market_index,
-                    oracle_price: market_status.oracle_status.price_data.price,
-                });
-
-                margin_requirement = margin_requirement
-                    .checked_sub(
-                        market_status
-                            .partial_margin_requirement
-                            .checked_mul(quote_asset_amount)
-                            .ok_or_else(math_error!())?
-                            .checked_div(market_status.base_asset_value)
-                            .ok_or_else(math_error!())?,
-                    )
-                    .ok_or_else(math_error!())?;
+    /// Pays the interest the user's quote collateral has earned into it
+    #[allow(clippy::result_large_err)]
+    pub fn settle_lending_interest(ctx: Context<SettleLendingInterest>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
 
-                let market_liquidation_fee = maximum_liquidation_fee
-                    .checked_mul(quote_asset_amount)
-                    .ok_or_else(math_error!())?
-                    .checked_div(maximum_base_asset_value_closed)
-                    .ok_or_else(math_error!())?;
+        let state = &mut ctx.accounts.state;
+        controller::lending::update_lending_pool(&mut state.quote_lending_pool, now)?;
 
-                liquidation_fee = liquidation_fee
-                    .checked_add(market_liquidation_fee)
-                    .ok_or_else(math_error!())?;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
 
-                let adjusted_total_collateral_after_fee = adjusted_total_collateral
-                    .checked_sub(liquidation_fee)
-                    .ok_or_else(math_error!())?;
+        Ok(())
+    }
 
-                if margin_requirement < adjusted_total_collateral_after_fee {
-                    break;
-                }
-            }
-        }
+    #[allow(clippy::result_large_err)]
+    pub fn initialize_insurance_fund_stake(
+        ctx: Context<InitializeInsuranceFundStake>,
+    ) -> Result<()> {
+        let insurance_fund_stake = &mut ctx.accounts.insurance_fund_stake;
+        insurance_fund_stake.authority = ctx.accounts.authority.key();
+        Ok(())
+    }
 
-        if base_asset_value_closed == 0 {
-            return Err(print_error!(ErrorCode::NoPositionsLiquidatable)().into());
-        }
+    #[allow(clippy::result_large_err)]
+    pub fn add_insurance_fund_stake(
+        ctx: Context<AddInsuranceFundStake>,
+        amount: u64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let insurance_fund = &mut ctx.accounts.state.insurance_fund;
+        let insurance_fund_stake = &mut ctx.accounts.insurance_fund_stake;
+        let insurance_vault_amount = ctx.accounts.insurance_vault.amount;
+
+        let total_shares_before = insurance_fund.total_shares;
+        let shares = controller::insurance_fund::add_stake(
+            insurance_fund,
+            insurance_fund_stake,
+            amount,
+            insurance_vault_amount,
+        )?;
 
-        let (withdrawal_amount, _) = calculate_withdrawal_amounts(
-            cast(liquidation_fee)?,
-            &ctx.accounts.collateral_vault,
+        controller::token::receive(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_collateral_account,
             &ctx.accounts.insurance_vault,
+            &ctx.accounts.authority,
+            amount,
         )?;
 
-        user.collateral = user
-            .collateral
-            .checked_sub(liquidation_fee)
-            .ok_or_else(math_error!())?;
-
-        let fee_to_liquidator = if is_full_liquidation {
-            withdrawal_amount
-                .checked_div(state.full_liquidation_liquidator_share_denominator)
-                .ok_or_else(math_error!())?
-        } else {
-            withdrawal_amount
-                .checked_div(state.partial_liquidation_liquidator_share_denominator)
-                .ok_or_else(math_error!())?
-        };
-
-        let fee_to_insurance_fund = withdrawal_amount
-            .checked_sub(fee_to_liquidator)
-            .ok_or_else(math_error!())?;
-
-        if fee_to_liquidator > 0 {
-            let liquidator = &mut ctx.accounts.liquidator;
-            liquidator.collateral = liquidator
-                .collateral
-                .checked_add(cast(fee_to_liquidator)?)
-                .ok_or_else(math_error!())?;
-        }
-
-        if fee_to_insurance_fund > 0 {
-            controller::token::send(
-                &ctx.accounts.token_program,
-                &ctx.accounts.collateral_vault,
-                &ctx.accounts.insurance_vault,
-                &ctx.accounts.collateral_vault_authority,
-                ctx.accounts.state.collateral_vault_nonce,
-                fee_to_insurance_fund,
-            )?;
-        }
-
-        let liquidation_history = &mut ctx.accounts.liquidation_history.load_mut()?;
-        let record_id = liquidation_history.next_record_id();
-
-        liquidation_history.append(LiquidationRecord {
+        let insurance_fund_stake_history =
+            &mut ctx.accounts.insurance_fund_stake_history.load_mut()?;
+        let record_id = insurance_fund_stake_history.next_record_id();
+        insurance_fund_stake_history.append(InsuranceFundStakeRecord {
             ts: now,
             record_id,
-            user: user.to_account_info().key(),
-            user_authority: user.authority,
-            partial: !is_full_liquidation,
-            base_asset_value,
-            base_asset_value_closed,
-            liquidation_fee,
-            fee_to_liquidator,
-            fee_to_insurance_fund,
-            liquidator: ctx.accounts.liquidator.to_account_info().key(),
-            total_collateral,
-            collateral,
-            unrealized_pnl,
-            margin_ratio,
+            user_authority: insurance_fund_stake.authority,
+            action: StakeAction::Stake,
+            amount,
+            shares,
+            total_shares_before,
+            insurance_vault_amount_before: insurance_vault_amount,
         });
 
         Ok(())
     }
 
-    #[allow(unused_must_use)]
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index) &&
-        exchange_not_paused(&ctx.accounts.state) &&
-        admin_controls_prices(&ctx.accounts.state)
-    )]
-    pub fn move_amm_price(
-        ctx: Context<MoveAMMPrice>,
-        base_asset_reserve: u128,
-        quote_asset_reserve: u128,
-        market_index: u64,
+    /// Starts the unstaking period for the shares
+    #[allow(clippy::result_large_err)]
+    pub fn request_remove_insurance_fund_stake(
+        ctx: Context<RequestRemoveInsuranceFundStake>,
+        shares: u128,
     ) -> Result<()> {
-        let markets = &mut ctx.accounts.markets.load_mut()?;
-        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
-        controller::amm::move_price(&mut market.amm, base_asset_reserve, quote_asset_reserve)?;
-        Ok(())
-    }
-
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index)
-    )]
-    pub fn withdraw_fees(ctx: Context<WithdrawFees>, market_index: u64, amount: u64) -> Result<()> {
-        let state = &mut ctx.accounts.state;
-        let markets = &mut ctx.accounts.markets.load_mut()?;
-        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
-
-        // A portion of fees must always remain in protocol to be used to keep markets optimal
-        let max_withdraw = market
-            .amm
-            .total_fee
-            .checked_mul(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR)
-            .ok_or_else(math_error!())?
-            .checked_div(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR)
-            .ok_or_else(math_error!())?
-            .checked_sub(market.amm.total_fee_withdrawn)
-            .ok_or_else(math_error!())?;
-
-        if cast_to_u128(amount)? > max_withdraw {
-            return Err(ErrorCode::AdminWithdrawTooLarge.into());
-        }
-
-        controller::token::send(
-            &ctx.accounts.token_program,
-            &ctx.accounts.collateral_vault,
-            &ctx.accounts.recipient,
-            &ctx.accounts.collateral_vault_authority,
-            state.collateral_vault_nonce,
-            amount,
+        let now = Clock::get()?.unix_timestamp;
+        let insurance_fund = &ctx.accounts.state.insurance_fund;
+        let insurance_fund_stake = &mut ctx.accounts.insurance_fund_stake;
+        let insurance_vault_amount = ctx.accounts.insurance_vault.amount;
+
+        let amount = controller::insurance_fund::request_remove_stake(
+            insurance_fund,
+            insurance_fund_stake,
+            shares,
+            insurance_vault_amount,
+            now,
         )?;
 
-        market.amm.total_fee_withdrawn = market
-            .amm
-            .total_fee_withdrawn
-            .checked_add(cast(amount)?)
-            .ok_or_else(math_error!())?;
+        let insurance_fund_stake_history =
+            &mut ctx.accounts.insurance_fund_stake_history.load_mut()?;
+        let record_id = insurance_fund_stake_history.next_record_id();
+        insurance_fund_stake_history.append(InsuranceFundStakeRecord {
+            ts: now,
+            record_id,
+            user_authority: insurance_fund_stake.authority,
+            action: StakeAction::UnstakeRequest,
+            amount,
+            shares,
+            total_shares_before: insurance_fund.total_shares,
+            insurance_vault_amount_before: insurance_vault_amount,
+        });
 
         Ok(())
     }
 
-    pub fn withdraw_from_insurance_vault(
-        ctx: Context<WithdrawFromInsuranceVault>,
-        amount: u64,
+    #[allow(clippy::result_large_err)]
+    pub fn cancel_request_remove_insurance_fund_stake(
+        ctx: Context<RequestRemoveInsuranceFundStake>,
     ) -> Result<()> {
-        controller::token::send(
-            &ctx.accounts.token_program,
-            &ctx.accounts.insurance_vault,
-            &ctx.accounts.recipient,
-            &ctx.accounts.insurance_vault_authority,
-            ctx.accounts.state.insurance_vault_nonce,
+        let now = Clock::get()?.unix_timestamp;
+        let insurance_fund_stake = &mut ctx.accounts.insurance_fund_stake;
+        let amount = insurance_fund_stake.last_withdraw_request_value;
+
+        let shares = controller::insurance_fund::cancel_request_remove_stake(insurance_fund_stake)?;
+
+        let insurance_fund_stake_history =
+            &mut ctx.accounts.insurance_fund_stake_history.load_mut()?;
+        let record_id = insurance_fund_stake_history.next_record_id();
+        insurance_fund_stake_history.append(InsuranceFundStakeRecord {
+            ts: now,
+            record_id,
+            user_authority: insurance_fund_stake.authority,
+            action: StakeAction::UnstakeCancelRequest,
             amount,
-        )?;
+            shares,
+            total_shares_before: ctx.accounts.state.insurance_fund.total_shares,
+            insurance_vault_amount_before: ctx.accounts.insurance_vault.amount,
+        });
+
         Ok(())
     }
 
     #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index)
+        exchange_not_paused(&ctx.accounts.state)
     )]
-    pub fn withdraw_from_insurance_vault_to_market(
-        ctx: Context<WithdrawFromInsuranceVaultToMarket>,
-        market_index: u64,
-        amount: u64,
-    ) -> Result<()> {
-        let markets = &mut ctx.accounts.markets.load_mut()?;
-        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
-
-        // The admin can move fees from the insurance fund back to the protocol so that money in
-        // the insurance fund can be used to make market more optimal
-        // 100% goes to user fee pool (symmetric funding, repeg, and k adjustments)
-        market.amm.total_fee_minus_distributions = market
-            .amm
-            .total_fee_minus_distributions
-            .checked_add(cast(amount)?)
-            .ok_or_else(math_error!())?;
+    pub fn remove_insurance_fund_stake(ctx: Context<RemoveInsuranceFundStake>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let insurance_vault_nonce = ctx.accounts.state.insurance_vault_nonce;
+        let insurance_fund = &mut ctx.accounts.state.insurance_fund;
+        let insurance_fund_stake = &mut ctx.accounts.insurance_fund_stake;
+        let insurance_vault_amount = ctx.accounts.insurance_vault.amount;
+
+        let total_shares_before = insurance_fund.total_shares;
+        let (amount, shares) = controller::insurance_fund::remove_stake(
+            insurance_fund,
+            insurance_fund_stake,
+            insurance_vault_amount,
+            now,
+        )?;
 
         controller::token::send(
             &ctx.accounts.token_program,
             &ctx.accounts.insurance_vault,
-            &ctx.accounts.collateral_vault,
+            &ctx.accounts.user_collateral_account,
             &ctx.accounts.insurance_vault_authority,
-            ctx.accounts.state.insurance_vault_nonce,
+            insurance_vault_nonce,
             amount,
         )?;
+
+        let insurance_fund_stake_history =
+            &mut ctx.accounts.insurance_fund_stake_history.load_mut()?;
+        let record_id = insurance_fund_stake_history.next_record_id();
+        insurance_fund_stake_history.append(InsuranceFundStakeRecord {
+            ts: now,
+            record_id,
+            user_authority: insurance_fund_stake.authority,
+            action: StakeAction::Unstake,
+            amount,
+            shares,
+            total_shares_before,
+            insurance_vault_amount_before: insurance_vault_amount,
+        });
+
         Ok(())
     }
 
@@ -1878,42 +1788,2599 @@ pub mod clearing_house {
         exchange_not_paused(&ctx.accounts.state) &&
         valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
     )]
-    pub fn repeg_amm_curve(
-        ctx: Context<RepegCurve>,
-        new_peg_candidate: u128,
+    pub fn open_position<'info>(
+        ctx: Context<OpenPosition>,
+        _sub_account_id: u8,
+        direction: PositionDirection,
+        quote_asset_amount: u128,
         market_index: u64,
+        limit_price: u128,
+        isolated_collateral: u64,
+        optional_accounts: ManagePositionOptionalAccounts,
     ) -> Result<()> {
+        let user = &mut ctx.accounts.user;
         let clock = Clock::get()?;
         let now = clock.unix_timestamp;
         let clock_slot = clock.slot;
 
-        let market =
+        if quote_asset_amount == 0 {
+            return Err(ErrorCode::TradeSizeTooSmall.into());
+        }
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        // Settle user's funding payments so that collateral is up to date
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            &ctx.accounts.markets.load()?,
+            funding_payment_history,
+            now,
+        )?;
+
+        // Get existing position or add a new position for market
+        let position_index = get_position_index(user_positions, market_index)
+            .or_else(|_| add_new_position(user_positions, market_index))?;
+        let market_position = &mut user_positions[position_index];
+
+        // Move collateral into the position's isolated collateral if the user opts into isolated margin
+        if isolated_collateral > 0 {
+            controller::position::isolate_position(
+                user,
+                market_position,
+                cast(isolated_collateral)?,
+            )?;
+        }
+
+        // Collect data about position/market before trade is executed so that it can be stored in trade history
+        let mark_price_before: u128;
+        let oracle_mark_spread_pct_before: i128;
+        let is_oracle_valid: bool;
+        let spread: u128;
+        {
+            let market = &mut ctx.accounts.markets.load_mut()?.markets
+                [Markets::index_from_u64(market_index)];
+            let oracle_price_data = &market
+                .amm
+                .get_oracle_price(&ctx.accounts.oracle, clock_slot)?;
+            is_oracle_valid = amm::is_oracle_valid(
+                &market.amm,
+                oracle_price_data,
+                &ctx.accounts.state.oracle_guard_rails.validity,
+            )?;
+            if is_oracle_valid {
+                let mark_price = market.amm.mark_price()?;
+                let trade_record = ctx.accounts.trade_history.load()?.next_record_id();
+                let extended_curve_history = &mut ctx.accounts.extended_curve_history.load_mut()?;
+                controller::repeg::formulaic_repeg(
+                    market,
+                    market_index,
+                    oracle_price_data,
+                    mark_price,
+                    &ctx.accounts.state,
+                    extended_curve_history,
+                    trade_record,
+                    now,
+                )?;
+            }
+            mark_price_before = market.amm.mark_price()?;
+            oracle_mark_spread_pct_before = amm::calculate_oracle_mark_spread_pct(
+                &market.amm,
+                oracle_price_data,
+                Some(mark_price_before),
+            )?;
+            if is_oracle_valid {
+                let normalised_oracle_price = normalise_oracle_price(
+                    &market.amm,
+                    oracle_price_data,
+                    Some(mark_price_before),
+                )?;
+                amm::update_oracle_price_twap(&mut market.amm, now, normalised_oracle_price)?;
+            }
+            controller::amm::update_spreads(
+                &mut market.amm,
+                market.base_asset_amount,
+                if is_oracle_valid {
+                    Some(oracle_price_data)
+                } else {
+                    None
+                },
+                mark_price_before,
+            )?;
+            spread = amm::get_spread(&market.amm, direction);
+        }
+
+        // A trade is risk increasing if it increases the users leverage
+        // If a trade is risk increasing and brings the user's margin ratio below initial requirement
+        // the trade fails
+        // If a trade is risk increasing and it pushes the mark price too far away from the oracle price
+        // the trade fails
+        let potentially_risk_increasing;
+        let base_asset_amount;
+        let mut quote_asset_amount = quote_asset_amount;
+        let quote_asset_amount_surplus;
+        {
+            let markets = &mut ctx.accounts.markets.load_mut()?;
+            let market = markets.get_market_mut(market_index);
+            let (
+                _potentially_risk_increasing,
+                _,
+                _base_asset_amount,
+                _quote_asset_amount,
+                _quote_asset_amount_surplus,
+            ) = controller::position::update_position_with_quote_asset_amount(
+                quote_asset_amount,
+                direction,
+                market,
+                user,
+                market_position,
+                mark_price_before,
+                now,
+            )?;
+
+            potentially_risk_increasing = _potentially_risk_increasing;
+            base_asset_amount = _base_asset_amount;
+            quote_asset_amount = _quote_asset_amount;
+            quote_asset_amount_surplus = _quote_asset_amount_surplus;
+        }
+
+        // Collect data about position/market after trade is executed so that it can be stored in trade history
+        let mark_price_after: u128;
+        let oracle_price_after: i128;
+        let oracle_mark_spread_pct_after: i128;
+        {
+            let market = &mut ctx.accounts.markets.load_mut()?.markets
+                [Markets::index_from_u64(market_index)];
+            mark_price_after = market.amm.mark_price()?;
+            let oracle_price_data = &market
+                .amm
+                .get_oracle_price(&ctx.accounts.oracle, clock_slot)?;
+            oracle_mark_spread_pct_after = amm::calculate_oracle_mark_spread_pct(
+                &market.amm,
+                oracle_price_data,
+                Some(mark_price_after),
+            )?;
+            oracle_price_after = oracle_price_data.price;
+        }
+
+        // Trade fails if it's risk increasing and it brings the user below the initial margin ratio level
+        {
+            let markets = &ctx.accounts.markets.load()?;
+            let spot_balances_value = optional_accounts::get_spot_balances_value(
+                ctx.remaining_accounts,
+                &ctx.accounts.state,
+                user,
+                &user.key(),
+                clock_slot,
+            )?;
+            let meets_margin_requirement = meets_margin_requirement_for_market(
+                user,
+                user_positions,
+                spot_balances_value,
+                markets,
+                market_index,
+                MarginType::Init,
+            )?;
+            if !meets_margin_requirement && potentially_risk_increasing {
+                return Err(ErrorCode::InsufficientCollateral.into());
+            }
+
+            // Moving collateral into an isolated position can't leave the cross margin positions undercollateralized
+            if isolated_collateral > 0
+                && !meets_initial_margin_requirement(
+                    user,
+                    user_positions,
+                    spot_balances_value,
+                    markets,
+                )?
+            {
+                return Err(ErrorCode::InsufficientCollateral.into());
+            }
+        }
+        let market_position = &mut user_positions[position_index];
+
+        // Calculate the fee to charge the user
+        let (discount_token, referrer) = optional_accounts::get_discount_token_and_referrer(
+            optional_accounts,
+            ctx.remaining_accounts,
+            &ctx.accounts.state.discount_mint,
+            &user.key(),
+            &ctx.accounts.authority.key(),
+        )?;
+        let (user_fee, fee_to_market, token_discount, referrer_reward, referee_discount) =
+            fees::calculate_fee_for_trade(
+                quote_asset_amount,
+                &ctx.accounts.state.fee_structure,
+                discount_token,
+                &referrer,
+                quote_asset_amount_surplus,
+            )?;
+
+        // Increment the clearing house's total fee variables
+        {
+            let market = &mut ctx.accounts.markets.load_mut()?.markets
+                [Markets::index_from_u64(market_index)];
+            market.amm.total_fee = market
+                .amm
+                .total_fee
+                .checked_add(fee_to_market)
+                .ok_or_else(math_error!())?;
+            market.amm.total_fee_minus_distributions = market
+                .amm
+                .total_fee_minus_distributions
+                .checked_add(fee_to_market)
+                .ok_or_else(math_error!())?;
+        }
+
+        // Subtract the fee from the collateral backing the position
+        controller::position::update_collateral_for_fee(
+            user,
+            market_position,
+            cast_to_i128(user_fee)?,
+        )?;
+        controller::position::release_isolated_collateral(user, market_position)?;
+
+        // Increment the user's total fee variables
+        user.total_fee_paid = user
+            .total_fee_paid
+            .checked_add(cast(user_fee)?)
+            .ok_or_else(math_error!())?;
+        user.total_token_discount = user
+            .total_token_discount
+            .checked_add(token_discount)
+            .ok_or_else(math_error!())?;
+        user.total_referee_discount = user
+            .total_referee_discount
+            .checked_add(referee_discount)
+            .ok_or_else(math_error!())?;
+
+        // Update the referrer's collateral with their reward
+        if referrer.is_some() {
+            let mut referrer = referrer.unwrap();
+            referrer.total_referral_reward = referrer
+                .total_referral_reward
+                .checked_add(referrer_reward)
+                .ok_or_else(math_error!())?;
+            referrer.exit(ctx.program_id)?;
+        }
+
+        // Trade fails if the trade is risk increasing and it pushes to mark price too far
+        // away from the oracle price
+        let is_oracle_mark_too_divergent_before = amm::is_oracle_mark_too_divergent(
+            oracle_mark_spread_pct_before,
+            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        )?;
+        let is_oracle_mark_too_divergent_after = amm::is_oracle_mark_too_divergent(
+            oracle_mark_spread_pct_after,
+            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        )?;
+
+        // if oracle-mark divergence pushed outside limit, block trade
+        if is_oracle_mark_too_divergent_after
+            && !is_oracle_mark_too_divergent_before
+            && is_oracle_valid
+        {
+            return Err(ErrorCode::OracleMarkSpreadLimit.into());
+        }
+
+        // if oracle-mark divergence outside limit and risk-increasing, block trade
+        if is_oracle_mark_too_divergent_after
+            && oracle_mark_spread_pct_after.unsigned_abs()
+                >= oracle_mark_spread_pct_before.unsigned_abs()
+            && is_oracle_valid
+            && potentially_risk_increasing
+        {
+            return Err(ErrorCode::OracleMarkSpreadLimit.into());
+        }
+
+        // Add to the trade history account
+        let trade_history_account = &mut ctx.accounts.trade_history.load_mut()?;
+        let record_id = trade_history_account.next_record_id();
+        trade_history_account.append(ExtendedTradeRecord {
+            ts: now,
+            record_id,
+            user_authority: *ctx.accounts.authority.to_account_info().key,
+            user: *user.to_account_info().key,
+            direction,
+            base_asset_amount,
+            quote_asset_amount,
+            mark_price_before,
+            mark_price_after,
+            fee: cast(user_fee)?,
+            token_discount,
+            quote_asset_amount_surplus,
+            referee_discount,
+            liquidation: false,
+            market_index,
+            oracle_price: oracle_price_after,
+            maker: Pubkey::default(),
+            maker_fee: 0,
+            spread,
+            padding: [0; 4],
+        });
+
+        // If the user adds a limit price to their trade, check that their entry price is better than the limit price
+        if limit_price != 0
+            && !limit_price_satisfied(
+                limit_price,
+                quote_asset_amount,
+                base_asset_amount,
+                direction,
+            )?
+        {
+            return Err(ErrorCode::SlippageOutsideLimit.into());
+        }
+
+        // Try to update the funding rate at the end of every trade
+        {
+            let market = &mut ctx.accounts.markets.load_mut()?.markets
+                [Markets::index_from_u64(market_index)];
+            let price_oracle = &ctx.accounts.oracle;
+            let funding_rate_history = &mut ctx.accounts.funding_rate_history.load_mut()?;
+            controller::funding::update_funding_rate(
+                market_index,
+                market,
+                price_oracle,
+                now,
+                clock_slot,
+                funding_rate_history,
+                &ctx.accounts.state.oracle_guard_rails,
+                ctx.accounts.state.funding_paused,
+                Some(mark_price_before),
+            )?;
+        }
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+    )]
+    pub fn close_position(
+        ctx: Context<ClosePosition>,
+        _sub_account_id: u8,
+        market_index: u64,
+        optional_accounts: ManagePositionOptionalAccounts,
+    ) -> Result<()> {
+        let user = &mut ctx.accounts.user;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        // Settle user's funding payments so that collateral is up to date
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            &ctx.accounts.markets.load()?,
+            funding_payment_history,
+            now,
+        )?;
+
+        let position_index = get_position_index(user_positions, market_index)?;
+        let market_position = &mut user_positions[position_index];
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+
+        // Collect data about market before trade is executed so that it can be stored in trade history
+        let mark_price_before = market.amm.mark_price()?;
+        let oracle_price_data = &market
+            .amm
+            .get_oracle_price(&ctx.accounts.oracle, clock_slot)?;
+        let oracle_mark_spread_pct_before = amm::calculate_oracle_mark_spread_pct(
+            &market.amm,
+            oracle_price_data,
+            Some(mark_price_before),
+        )?;
+        let is_oracle_valid = amm::is_oracle_valid(
+            &market.amm,
+            oracle_price_data,
+            &ctx.accounts.state.oracle_guard_rails.validity,
+        )?;
+        let direction_to_close =
+            math::position::direction_to_close_position(market_position.base_asset_amount);
+        controller::amm::update_spreads(
+            &mut market.amm,
+            market.base_asset_amount,
+            if is_oracle_valid {
+                Some(oracle_price_data)
+            } else {
+                None
+            },
+            mark_price_before,
+        )?;
+        let spread = amm::get_spread(&market.amm, direction_to_close);
+        let (quote_asset_amount, base_asset_amount, quote_asset_amount_surplus) =
+            controller::position::close(
+                user,
+                market,
+                market_position,
+                now,
+                None,
+                Some(mark_price_before),
+                true,
+            )?;
+        let base_asset_amount = base_asset_amount.unsigned_abs();
+
+        // Calculate the fee to charge the user
+        let (discount_token, referrer) = optional_accounts::get_discount_token_and_referrer(
+            optional_accounts,
+            ctx.remaining_accounts,
+            &ctx.accounts.state.discount_mint,
+            &user.key(),
+            &ctx.accounts.authority.key(),
+        )?;
+        let (user_fee, fee_to_market, token_discount, referrer_reward, referee_discount) =
+            fees::calculate_fee_for_trade(
+                quote_asset_amount,
+                &ctx.accounts.state.fee_structure,
+                discount_token,
+                &referrer,
+                quote_asset_amount_surplus,
+            )?;
+
+        // Increment the clearing house's total fee variables
+        market.amm.total_fee = market
+            .amm
+            .total_fee
+            .checked_add(fee_to_market)
+            .ok_or_else(math_error!())?;
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .checked_add(fee_to_market)
+            .ok_or_else(math_error!())?;
+
+        // Subtract the fee from the collateral backing the position
+        controller::position::update_collateral_for_fee(
+            user,
+            market_position,
+            cast_to_i128(user_fee)?,
+        )?;
+        controller::position::release_isolated_collateral(user, market_position)?;
+
+        // Increment the user's total fee variables
+        user.total_fee_paid = user
+            .total_fee_paid
+            .checked_add(cast(user_fee)?)
+            .ok_or_else(math_error!())?;
+        user.total_token_discount = user
+            .total_token_discount
+            .checked_add(token_discount)
+            .ok_or_else(math_error!())?;
+        user.total_referee_discount = user
+            .total_referee_discount
+            .checked_add(referee_discount)
+            .ok_or_else(math_error!())?;
+
+        // Update the referrer's collateral with their reward
+        if referrer.is_some() {
+            let mut referrer = referrer.unwrap();
+            referrer.total_referral_reward = referrer
+                .total_referral_reward
+                .checked_add(referrer_reward)
+                .ok_or_else(math_error!())?;
+            referrer.exit(ctx.program_id)?;
+        }
+
+        // Collect data about market after trade is executed so that it can be stored in trade history
+        let mark_price_after = market.amm.mark_price()?;
+        let price_oracle = &ctx.accounts.oracle;
+
+        let oracle_mark_spread_pct_after = amm::calculate_oracle_mark_spread_pct(
+            &market.amm,
+            oracle_price_data,
+            Some(mark_price_after),
+        )?;
+        let oracle_price_after = oracle_price_data.price;
+
+        if is_oracle_valid {
+            let normalised_oracle_price =
+                normalise_oracle_price(&market.amm, oracle_price_data, Some(mark_price_before))?;
+            amm::update_oracle_price_twap(&mut market.amm, now, normalised_oracle_price)?;
+        }
+
+        // Trade fails if the trade is risk increasing and it pushes to mark price too far
+        // away from the oracle price
+        let is_oracle_mark_too_divergent_before = amm::is_oracle_mark_too_divergent(
+            oracle_mark_spread_pct_before,
+            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        )?;
+        let is_oracle_mark_too_divergent_after = amm::is_oracle_mark_too_divergent(
+            oracle_mark_spread_pct_after,
+            &ctx.accounts.state.oracle_guard_rails.price_divergence,
+        )?;
+
+        // if closing position pushes outside of oracle-mark divergence limit, block trade
+        if (is_oracle_mark_too_divergent_after && !is_oracle_mark_too_divergent_before)
+            && is_oracle_valid
+        {
+            return Err(ErrorCode::OracleMarkSpreadLimit.into());
+        }
+
+        // Add to the trade history account
+        let trade_history_account = &mut ctx.accounts.trade_history.load_mut()?;
+        let record_id = trade_history_account.next_record_id();
+        trade_history_account.append(ExtendedTradeRecord {
+            ts: now,
+            record_id,
+            user_authority: *ctx.accounts.authority.to_account_info().key,
+            user: *user.to_account_info().key,
+            direction: direction_to_close,
+            base_asset_amount,
+            quote_asset_amount,
+            mark_price_before,
+            mark_price_after,
+            liquidation: false,
+            fee: cast(user_fee)?,
+            token_discount,
+            quote_asset_amount_surplus,
+            referee_discount,
+            market_index,
+            oracle_price: oracle_price_after,
+            maker: Pubkey::default(),
+            maker_fee: 0,
+            spread,
+            padding: [0; 4],
+        });
+
+        // Linked orders (e.g. a take profit and stop loss) are canceled along with the position
+        let user_orders_accounts = optional_accounts::find_user_orders_and_order_history(
+            ctx.remaining_accounts,
+            &ctx.accounts.state,
+            &user.key(),
+        )?;
+        controller::orders::cancel_linked_orders_for_closed_position(
+            user,
+            user_positions,
+            user_orders_accounts
+                .as_ref()
+                .map(|(user_orders, order_history)| (user_orders, order_history)),
+            market_index,
+            now,
+        )?;
+
+        // Try to update the funding rate at the end of every trade
+        let funding_rate_history = &mut ctx.accounts.funding_rate_history.load_mut()?;
+        controller::funding::update_funding_rate(
+            market_index,
+            market,
+            price_oracle,
+            now,
+            clock_slot,
+            funding_rate_history,
+            &ctx.accounts.state.oracle_guard_rails,
+            ctx.accounts.state.funding_paused,
+            Some(mark_price_before),
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, params.market_index)
+    )]
+    pub fn place_order<'info>(
+        ctx: Context<PlaceOrder>,
+        _sub_account_id: u8,
+        params: OrderParams,
+    ) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let discount_token = get_discount_token(
+            params.optional_accounts.discount_token,
+            account_info_iter,
+            &ctx.accounts.state.discount_mint,
+            ctx.accounts.authority.key,
+        )?;
+        let referrer = get_referrer(
+            params.optional_accounts.referrer,
+            account_info_iter,
+            &ctx.accounts.user.key(),
+            None,
+        )?;
+        let order_book = get_order_book(
+            params.optional_accounts.order_book,
+            account_info_iter,
+            params.market_index,
+        )?;
+
+        let oracle = get_oracle_for_place_order(account_info_iter, &ctx.accounts.markets, &params)?;
+
+        if params.order_type == OrderType::Market {
+            msg!("market order must be in place and fill");
+            return Err(ErrorCode::MarketOrderMustBeInPlaceAndFill.into());
+        }
+
+        if params.immediate_or_cancel {
+            msg!("immediate_or_cancel order must be in place and fill");
+            return Err(print_error!(ErrorCode::InvalidOrder)().into());
+        }
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::place_order(
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            discount_token,
+            &referrer,
+            &Clock::get()?,
+            params,
+            oracle,
+            order_book.as_ref(),
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn place_orders(
+        ctx: Context<PlaceOrder>,
+        sub_account_id: u8,
+        params: Vec<OrderParams>,
+    ) -> Result<()> {
+        cancel_and_place_orders(ctx, sub_account_id, vec![], params)
+    }
+
+    /// The discount token and referrer are taken from the first order's optional accounts. Oracles
+    /// and order books for the orders follow in any order.
+    #[allow(clippy::result_large_err)]
+    pub fn cancel_and_place_orders(
+        ctx: Context<PlaceOrder>,
+        _sub_account_id: u8,
+        order_ids: Vec<u128>,
+        params: Vec<OrderParams>,
+    ) -> Result<()> {
+        for params in params.iter() {
+            market_initialized(&ctx.accounts.markets, params.market_index)?;
+
+            if params.order_type == OrderType::Market {
+                msg!("market order must be in place and fill");
+                return Err(ErrorCode::MarketOrderMustBeInPlaceAndFill.into());
+            }
+
+            if params.immediate_or_cancel {
+                msg!("immediate_or_cancel order must be in place and fill");
+                return Err(print_error!(ErrorCode::InvalidOrder)().into());
+            }
+        }
+
+        let optional_accounts = match params.first() {
+            Some(params) => params.optional_accounts.clone(),
+            None => OrderParamsOptionalAccounts::default(),
+        };
+
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let discount_token = get_discount_token(
+            optional_accounts.discount_token,
+            account_info_iter,
+            &ctx.accounts.state.discount_mint,
+            ctx.accounts.authority.key,
+        )?;
+        let referrer = get_referrer(
+            optional_accounts.referrer,
+            account_info_iter,
+            &ctx.accounts.user.key(),
+            None,
+        )?;
+        let (oracles, order_books) = get_oracles_and_order_books(account_info_iter)?;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::cancel_and_place_orders(
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            discount_token,
+            &referrer,
+            &Clock::get()?,
+            order_ids,
+            params,
+            &oracles,
+            &order_books,
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, entry.market_index)
+    )]
+    pub fn place_bracket_orders<'info>(
+        ctx: Context<PlaceOrder>,
+        _sub_account_id: u8,
+        entry: OrderParams,
+        take_profit: OrderParams,
+        stop_loss: OrderParams,
+    ) -> Result<()> {
+        for exit in [&take_profit, &stop_loss] {
+            if exit.market_index != entry.market_index
+                || exit.direction == entry.direction
+                || !exit.reduce_only
+            {
+                msg!("Take profit and stop loss must be reduce only and close the entry");
+                return Err(print_error!(ErrorCode::InvalidOrder)().into());
+            }
+        }
+
+        for params in [&entry, &take_profit, &stop_loss] {
+            if params.order_type == OrderType::Market {
+                msg!("market order must be in place and fill");
+                return Err(ErrorCode::MarketOrderMustBeInPlaceAndFill.into());
+            }
+
+            if params.immediate_or_cancel {
+                msg!("immediate_or_cancel order must be in place and fill");
+                return Err(print_error!(ErrorCode::InvalidOrder)().into());
+            }
+        }
+
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let discount_token = get_discount_token(
+            entry.optional_accounts.discount_token,
+            account_info_iter,
+            &ctx.accounts.state.discount_mint,
+            ctx.accounts.authority.key,
+        )?;
+        let referrer = get_referrer(
+            entry.optional_accounts.referrer,
+            account_info_iter,
+            &ctx.accounts.user.key(),
+            None,
+        )?;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        let mut linked_order_id = 0;
+        for (params, is_take_profit) in
+            vec![(entry, false), (take_profit, true), (stop_loss, false)]
+        {
+            let oracle =
+                get_oracle_for_place_order(account_info_iter, &ctx.accounts.markets, &params)?;
+
+            // The stop loss is linked to the take profit, so filling either cancels the other
+            let params = OrderParams {
+                linked_order_id,
+                ..params
+            };
+
+            controller::orders::place_order(
+                &ctx.accounts.state,
+                &ctx.accounts.order_state,
+                &mut ctx.accounts.user,
+                &ctx.accounts.user_positions,
+                &ctx.accounts.markets,
+                &ctx.accounts.user_orders,
+                &ctx.accounts.funding_payment_history,
+                &ctx.accounts.order_history,
+                discount_token,
+                &referrer,
+                &Clock::get()?,
+                params,
+                oracle,
+                None,
+            )?;
+
+            if is_take_profit {
+                linked_order_id = ctx.accounts.order_history.load()?.last_order_id;
+            }
+        }
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn cancel_order(
+        ctx: Context<CancelOrder>,
+        _sub_account_id: u8,
+        order_id: u128,
+    ) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let oracle = get_oracle_for_cancel_order_by_order_id(
+            account_info_iter,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.markets,
+            order_id,
+        )?;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::cancel_order_by_order_id(
+            &ctx.accounts.state,
+            order_id,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+            oracle,
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn cancel_order_by_user_id(
+        ctx: Context<CancelOrder>,
+        _sub_account_id: u8,
+        user_order_id: u8,
+    ) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let oracle = get_oracle_for_cancel_order_by_user_order_id(
+            account_info_iter,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.markets,
+            user_order_id,
+        )?;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::cancel_order_by_user_order_id(
+            &ctx.accounts.state,
+            user_order_id,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+            oracle,
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn modify_order(
+        ctx: Context<CancelOrder>,
+        _sub_account_id: u8,
+        order_id: u128,
+        params: ModifyOrderParams,
+    ) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let (order_book, oracle) = get_order_book_and_oracle_for_modify_order(
+            account_info_iter,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.markets,
+            order_id,
+            &params,
+        )?;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::modify_order(
+            order_id,
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+            oracle,
+            order_book.as_ref(),
+            params,
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn modify_order_by_user_id(
+        ctx: Context<CancelOrder>,
+        _sub_account_id: u8,
+        user_order_id: u8,
+        params: ModifyOrderParams,
+    ) -> Result<()> {
+        let order_id = {
+            let user_orders = &ctx.accounts.user_orders.load()?;
+            let order_index = user_orders
+                .orders
+                .iter()
+                .position(|order| order.user_order_id == user_order_id)
+                .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+            user_orders.orders[order_index].order_id
+        };
+
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let (order_book, oracle) = get_order_book_and_oracle_for_modify_order(
+            account_info_iter,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.markets,
+            order_id,
+            &params,
+        )?;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::modify_order(
+            order_id,
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+            oracle,
+            order_book.as_ref(),
+            params,
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn cancel_orders(
+        ctx: Context<CancelOrder>,
+        _sub_account_id: u8,
+        order_ids: Vec<u128>,
+    ) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let (oracles, order_books) = get_oracles_and_order_books(account_info_iter)?;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::cancel_and_place_orders(
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            None,
+            &None,
+            &Clock::get()?,
+            order_ids,
+            vec![],
+            &oracles,
+            &order_books,
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn cancel_all_orders(
+        ctx: Context<CancelOrder>,
+        _sub_account_id: u8,
+        best_effort: bool,
+    ) -> Result<()> {
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::cancel_all_orders(
+            &ctx.accounts.state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+            ctx.remaining_accounts,
+            best_effort,
+            None,
+            None,
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn cancel_orders_by_market_and_side(
+        ctx: Context<CancelOrder>,
+        _sub_account_id: u8,
+        best_effort: bool,
+        market_index_only: u64,
+        direction_only: PositionDirection,
+    ) -> Result<()> {
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        controller::orders::cancel_all_orders(
+            &ctx.accounts.state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+            ctx.remaining_accounts,
+            best_effort,
+            Some(market_index_only),
+            Some(direction_only),
+        )?;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn expire_orders(ctx: Context<ExpireOrder>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        let user_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        let filler_collateral_settled =
+            controller::lending::settle_quote_deposit_interest_unless_user(
+                &mut ctx.accounts.filler,
+                &ctx.accounts.user.key(),
+                quote_lending_pool,
+                deposit_history,
+                now,
+            )?;
+
+        controller::orders::expire_orders(
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.user_orders,
+            &mut ctx.accounts.filler,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+        )?;
+
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            user_collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+        if let Some(filler_collateral_settled) = filler_collateral_settled {
+            controller::lending::update_quote_deposits(
+                quote_lending_pool,
+                filler_collateral_settled,
+                ctx.accounts.filler.collateral,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn cancel_expired_orders(ctx: Context<ExpireOrder>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        let user_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        let filler_collateral_settled =
+            controller::lending::settle_quote_deposit_interest_unless_user(
+                &mut ctx.accounts.filler,
+                &ctx.accounts.user.key(),
+                quote_lending_pool,
+                deposit_history,
+                now,
+            )?;
+
+        controller::orders::cancel_expired_orders(
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.user_orders,
+            &mut ctx.accounts.filler,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+        )?;
+
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            user_collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+        if let Some(filler_collateral_settled) = filler_collateral_settled {
+            controller::lending::update_quote_deposits(
+                quote_lending_pool,
+                filler_collateral_settled,
+                ctx.accounts.filler.collateral,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn fill_order<'info>(ctx: Context<FillOrder>, order_id: u128) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let referrer = get_referrer_for_fill_order(
+            account_info_iter,
+            &ctx.accounts.user.key(),
+            order_id,
+            &ctx.accounts.user_orders,
+        )?;
+        let (order_book, mut makers) = get_order_book_and_makers_for_fill_order(
+            account_info_iter,
+            &ctx.accounts.state,
+            order_id,
+            &ctx.accounts.user_orders,
+        )?;
+        let spot_balances_value = optional_accounts::get_spot_balances_value(
+            ctx.remaining_accounts,
+            &ctx.accounts.state,
+            &ctx.accounts.user,
+            &ctx.accounts.user.key(),
+            Clock::get()?.slot,
+        )?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        let user_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        let filler_collateral_settled =
+            controller::lending::settle_quote_deposit_interest_unless_user(
+                &mut ctx.accounts.filler,
+                &ctx.accounts.user.key(),
+                quote_lending_pool,
+                deposit_history,
+                now,
+            )?;
+        let makers_collateral_settled = controller::lending::settle_makers_quote_deposit_interest(
+            &mut makers,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+
+        let base_asset_amount = controller::orders::fill_order(
+            order_id,
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            spot_balances_value,
+            &ctx.accounts.markets,
+            &ctx.accounts.oracle,
+            &ctx.accounts.user_orders,
+            &mut ctx.accounts.filler,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.trade_history,
+            &ctx.accounts.order_history,
+            &ctx.accounts.funding_rate_history,
+            &ctx.accounts.extended_curve_history,
+            referrer,
+            order_book.as_ref(),
+            &mut makers,
+            None,
+            &Clock::get()?,
+        )?;
+
+        if base_asset_amount == 0 {
+            return Err(print_error!(ErrorCode::CouldNotFillOrder)().into());
+        }
+
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            user_collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+        if let Some(filler_collateral_settled) = filler_collateral_settled {
+            controller::lending::update_quote_deposits(
+                quote_lending_pool,
+                filler_collateral_settled,
+                ctx.accounts.filler.collateral,
+            )?;
+        }
+        controller::lending::update_makers_quote_deposits(
+            &makers,
+            &makers_collateral_settled,
+            quote_lending_pool,
+        )?;
+
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn update_trailing_stop(ctx: Context<UpdateTrailingStop>, order_id: u128) -> Result<()> {
+        controller::orders::update_trailing_stop(
+            order_id,
+            &ctx.accounts.state,
+            &ctx.accounts.user,
+            &ctx.accounts.markets,
+            &ctx.accounts.oracle,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.order_history,
+            &Clock::get()?,
+        )?;
+
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn fill_order_with_jit_maker<'info>(
+        ctx: Context<FillOrderWithJitMaker>,
+        order_id: u128,
+        maker_params: JitMakerParams,
+    ) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let referrer = get_referrer_for_fill_order(
+            account_info_iter,
+            &ctx.accounts.user.key(),
+            order_id,
+            &ctx.accounts.user_orders,
+        )?;
+        let spot_balances_value = optional_accounts::get_spot_balances_value(
+            ctx.remaining_accounts,
+            &ctx.accounts.state,
+            &ctx.accounts.user,
+            &ctx.accounts.user.key(),
+            Clock::get()?.slot,
+        )?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        let user_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        let filler_collateral_settled =
+            controller::lending::settle_quote_deposit_interest_unless_user(
+                &mut ctx.accounts.filler,
+                &ctx.accounts.user.key(),
+                quote_lending_pool,
+                deposit_history,
+                now,
+            )?;
+        let maker_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.maker,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+
+        let base_asset_amount = controller::orders::fill_order(
+            order_id,
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            spot_balances_value,
+            &ctx.accounts.markets,
+            &ctx.accounts.oracle,
+            &ctx.accounts.user_orders,
+            &mut ctx.accounts.filler,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.trade_history,
+            &ctx.accounts.order_history,
+            &ctx.accounts.funding_rate_history,
+            &ctx.accounts.extended_curve_history,
+            referrer,
+            None,
+            &mut [],
+            Some(JitMaker {
+                user: &mut ctx.accounts.maker,
+                user_positions: &ctx.accounts.maker_positions,
+                params: maker_params,
+            }),
+            &Clock::get()?,
+        )?;
+
+        if base_asset_amount == 0 {
+            return Err(print_error!(ErrorCode::CouldNotFillOrder)().into());
+        }
+
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            user_collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+        if let Some(filler_collateral_settled) = filler_collateral_settled {
+            controller::lending::update_quote_deposits(
+                quote_lending_pool,
+                filler_collateral_settled,
+                ctx.accounts.filler.collateral,
+            )?;
+        }
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            maker_collateral_settled,
+            ctx.accounts.maker.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state) &&
+        market_initialized(&ctx.accounts.markets, params.market_index) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, params.market_index)
+    )]
+    pub fn place_and_fill_order<'info>(
+        ctx: Context<PlaceAndFillOrder>,
+        _sub_account_id: u8,
+        params: OrderParams,
+    ) -> Result<()> {
+        let account_info_iter = &mut ctx.remaining_accounts.iter();
+        let discount_token = get_discount_token(
+            params.optional_accounts.discount_token,
+            account_info_iter,
+            &ctx.accounts.state.discount_mint,
+            ctx.accounts.authority.key,
+        )?;
+        let referrer = get_referrer(
+            params.optional_accounts.referrer,
+            account_info_iter,
+            &ctx.accounts.user.key(),
+            None,
+        )?;
+        let is_immediate_or_cancel = params.immediate_or_cancel;
+        let base_asset_amount_to_fill = params.base_asset_amount;
+
+        let now = Clock::get()?.unix_timestamp;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let user_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+
+        controller::orders::place_order(
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.markets,
+            &ctx.accounts.user_orders,
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.order_history,
+            discount_token,
+            &referrer,
+            &Clock::get()?,
+            params,
+            Some(&ctx.accounts.oracle),
+            None,
+        )?;
+
+        let order_id;
+        {
+            let order_history = &ctx.accounts.order_history.load()?;
+            order_id = order_history.last_order_id;
+        }
+        let (order_book, mut makers) = get_order_book_and_makers_for_fill_order(
+            account_info_iter,
+            &ctx.accounts.state,
+            order_id,
+            &ctx.accounts.user_orders,
+        )?;
+        let makers_collateral_settled = controller::lending::settle_makers_quote_deposit_interest(
+            &mut makers,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        let spot_balances_value = optional_accounts::get_spot_balances_value(
+            ctx.remaining_accounts,
+            &ctx.accounts.state,
+            &ctx.accounts.user,
+            &ctx.accounts.user.key(),
+            Clock::get()?.slot,
+        )?;
+
+        let user = &mut ctx.accounts.user;
+        let base_asset_amount_filled = controller::orders::fill_order(
+            order_id,
+            &ctx.accounts.state,
+            &ctx.accounts.order_state,
+            user,
+            &ctx.accounts.user_positions,
+            spot_balances_value,
+            &ctx.accounts.markets,
+            &ctx.accounts.oracle,
+            &ctx.accounts.user_orders,
+            &mut user.clone(),
+            &ctx.accounts.funding_payment_history,
+            &ctx.accounts.trade_history,
+            &ctx.accounts.order_history,
+            &ctx.accounts.funding_rate_history,
+            &ctx.accounts.extended_curve_history,
+            referrer,
+            order_book.as_ref(),
+            &mut makers,
+            None,
+            &Clock::get()?,
+        )?;
+
+        if is_immediate_or_cancel && base_asset_amount_to_fill != base_asset_amount_filled {
+            controller::orders::cancel_order_by_order_id(
+                &ctx.accounts.state,
+                order_id,
+                &mut ctx.accounts.user,
+                &ctx.accounts.user_positions,
+                &ctx.accounts.markets,
+                &ctx.accounts.user_orders,
+                &ctx.accounts.funding_payment_history,
+                &ctx.accounts.order_history,
+                &Clock::get()?,
+                Some(&ctx.accounts.oracle),
+            )?;
+        }
+
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            user_collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+        controller::lending::update_makers_quote_deposits(
+            &makers,
+            &makers_collateral_settled,
+            quote_lending_pool,
+        )?;
+
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn liquidate(ctx: Context<Liquidate>) -> Result<()> {
+        liquidate_user(ctx, None, None)
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn liquidate_market(ctx: Context<Liquidate>, market_index: u64) -> Result<()> {
+        liquidate_user(ctx, None, Some(market_index))
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn liquidate_isolated_position(ctx: Context<Liquidate>, market_index: u64) -> Result<()> {
+        liquidate_user(ctx, Some(market_index), None)
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn liquidate_by_position_transfer(
+        ctx: Context<LiquidateByPositionTransfer>,
+        market_index: u64,
+    ) -> Result<()> {
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        if ctx.accounts.user.key() == ctx.accounts.liquidator.key() {
+            return Err(ErrorCode::UserCantLiquidateThemself.into());
+        }
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        let user_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        let liquidator_collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.liquidator,
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+
+        let state = &ctx.accounts.state;
+
+        // Settle both users' funding payments so that collateral is up to date
+        let user = &mut ctx.accounts.user;
+        let liquidator = &mut ctx.accounts.liquidator;
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let liquidator_positions =
+            &mut UserPositions::load_positions_mut(&ctx.accounts.liquidator_positions)?;
+        {
+            let markets = &ctx.accounts.markets.load()?;
+            let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+            controller::funding::settle_funding_payment(
+                user,
+                user_positions,
+                markets,
+                funding_payment_history,
+                now,
+            )?;
+            controller::funding::settle_funding_payment(
+                liquidator,
+                liquidator_positions,
+                markets,
+                funding_payment_history,
+                now,
+            )?;
+        }
+
+        let position_index = get_position_index(user_positions, market_index)?;
+        let is_isolated = user_positions[position_index].is_isolated();
+
+        let LiquidationStatus {
+            liquidation_type,
+            total_collateral,
+            adjusted_total_collateral,
+            unrealized_pnl,
+            market_statuses,
+            margin_requirement,
+            margin_ratio,
+            ..
+        } = if is_isolated {
+            calculate_isolated_liquidation_status(
+                &user_positions[position_index],
+                &ctx.accounts.markets.load()?,
+                ctx.remaining_accounts,
+                &state.oracle_guard_rails,
+                clock_slot,
+            )?
+        } else {
+            calculate_liquidation_status(
+                user,
+                user_positions,
+                optional_accounts::get_spot_balances_value(
+                    ctx.remaining_accounts,
+                    state,
+                    user,
+                    &user.key(),
+                    clock_slot,
+                )?,
+                &ctx.accounts.markets.load()?,
+                ctx.remaining_accounts,
+                &state.oracle_guard_rails,
+                clock_slot,
+            )?
+        };
+
+        // Verify that the user is in liquidation territory
+        if liquidation_type == LiquidationType::NONE {
+            msg!("total_collateral {}", total_collateral);
+            msg!("adjusted_total_collateral {}", adjusted_total_collateral);
+            msg!("margin_requirement {}", margin_requirement);
+            return Err(ErrorCode::SufficientCollateral.into());
+        }
+
+        // The position is taken over at the oracle price, so the oracle must be valid
+        let market_status = market_statuses
+            .iter()
+            .find(|market_status| market_status.market_index == market_index)
+            .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+        if !market_status.oracle_status.is_valid {
+            return Err(ErrorCode::InvalidOracle.into());
+        }
+        let oracle_price = market_status.oracle_status.price_data.price;
+
+        let collateral = get_position_collateral(user, &user_positions[position_index]);
+        let base_asset_amount = user_positions[position_index].base_asset_amount;
+        let transfer_price = math::position::calculate_position_transfer_price(
+            oracle_price,
+            base_asset_amount,
+            state.position_transfer_discount_numerator,
+            state.position_transfer_discount_denominator,
+        )?;
+        let (quote_asset_amount, pnl) =
+            math::position::calculate_base_asset_value_and_pnl_with_oracle_price(
+                &user_positions[position_index],
+                cast_to_i128(transfer_price)?,
+            )?;
+
+        // The user's collateral has to cover the loss realized at the transfer price
+        if cast_to_i128(collateral)?
+            .checked_add(pnl)
+            .ok_or_else(math_error!())?
+            < 0
+        {
+            msg!("collateral {}", collateral);
+            msg!("pnl {}", pnl);
+            return Err(ErrorCode::PositionTransferBankruptsUser.into());
+        }
+
+        let liquidator_position_index = match get_position_index(liquidator_positions, market_index)
+        {
+            Ok(position_index) => position_index,
+            Err(_) => add_new_position(liquidator_positions, market_index)?,
+        };
+        if liquidator_positions[liquidator_position_index].is_isolated() {
+            return Err(ErrorCode::PositionMarginModeMismatch.into());
+        }
+
+        let direction_to_close = math::position::direction_to_close_position(base_asset_amount);
+        let liquidator_direction = match direction_to_close {
+            PositionDirection::Long => PositionDirection::Short,
+            PositionDirection::Short => PositionDirection::Long,
+        };
+        let base_asset_amount_transferred = base_asset_amount.unsigned_abs();
+        {
+            let markets = &mut ctx.accounts.markets.load_mut()?;
+            let market = markets.get_market_mut(market_index);
+
+            controller::position::update_position_with_matched_trade(
+                direction_to_close,
+                base_asset_amount_transferred,
+                quote_asset_amount,
+                market,
+                user,
+                &mut user_positions[position_index],
+            )?;
+
+            controller::position::update_position_with_matched_trade(
+                liquidator_direction,
+                base_asset_amount_transferred,
+                quote_asset_amount,
+                market,
+                liquidator,
+                &mut liquidator_positions[liquidator_position_index],
+            )?;
+        }
+
+        if is_isolated {
+            controller::position::release_isolated_collateral(
+                user,
+                &mut user_positions[position_index],
+            )?;
+        }
+
+        // The liquidator must be able to margin the position it took over
+        let liquidator_spot_balances_value = optional_accounts::get_spot_balances_value(
+            ctx.remaining_accounts,
+            state,
+            liquidator,
+            &liquidator.key(),
+            clock_slot,
+        )?;
+        if !meets_initial_margin_requirement(
+            liquidator,
+            liquidator_positions,
+            liquidator_spot_balances_value,
+            &ctx.accounts.markets.load()?,
+        )? {
+            return Err(ErrorCode::InsufficientCollateral.into());
+        }
+
+        let liquidation_history = &mut ctx.accounts.liquidation_history.load_mut()?;
+        let record_id = liquidation_history.next_record_id();
+        liquidation_history.append(ExtendedLiquidationRecord {
+            ts: now,
+            record_id,
+            user: user.key(),
+            user_authority: user.authority,
+            partial: false,
+            base_asset_value: market_status.base_asset_value,
+            base_asset_value_closed: quote_asset_amount,
+            liquidation_fee: 0,
+            fee_to_liquidator: 0,
+            fee_to_insurance_fund: 0,
+            liquidator: liquidator.key(),
+            total_collateral,
+            collateral,
+            unrealized_pnl,
+            margin_ratio,
+            market_index,
+            base_asset_amount_transferred,
+            transfer_price,
+            padding: [0; 5],
+        });
+
+        // Linked orders are canceled for the position that was transferred
+        let user_orders_accounts = optional_accounts::find_user_orders_and_order_history(
+            ctx.remaining_accounts,
+            state,
+            &user.key(),
+        )?;
+        controller::orders::cancel_linked_orders_for_closed_position(
+            user,
+            user_positions,
+            user_orders_accounts
+                .as_ref()
+                .map(|(user_orders, order_history)| (user_orders, order_history)),
+            market_index,
+            now,
+        )?;
+
+        let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            user_collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            liquidator_collateral_settled,
+            ctx.accounts.liquidator.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn flag_user_liquidatable(ctx: Context<FlagUserLiquidatable>) -> Result<()> {
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+
+        let state = &ctx.accounts.state;
+        let user = &mut ctx.accounts.user;
+
+        // Settle user's funding payments so that collateral is up to date
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let markets = &ctx.accounts.markets.load()?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            markets,
+            funding_payment_history,
+            now,
+        )?;
+
+        let is_liquidatable = calculate_liquidation_status(
+            user,
+            user_positions,
+            optional_accounts::get_spot_balances_value(
+                ctx.remaining_accounts,
+                state,
+                user,
+                &user.key(),
+                clock_slot,
+            )?,
+            markets,
+            ctx.remaining_accounts,
+            &state.oracle_guard_rails,
+            clock_slot,
+        )?
+        .liquidation_type
+            != LiquidationType::NONE;
+
+        // The liquidation auction runs from the first slot the user was seen liquidatable. Cross
+        // margined positions and each isolated position are liquidated separately, so each has its
+        // own auction.
+        user.liquidation_start_slot = calculate_liquidation_start_slot(
+            is_liquidatable,
+            user.liquidation_start_slot,
+            clock_slot,
+        );
+
+        for market_position in user_positions.iter_mut() {
+            if !market_position.is_isolated() || market_position.base_asset_amount == 0 {
+                continue;
+            }
+
+            let is_liquidatable = calculate_isolated_liquidation_status(
+                market_position,
+                markets,
+                ctx.remaining_accounts,
+                &state.oracle_guard_rails,
+                clock_slot,
+            )?
+            .liquidation_type
+                != LiquidationType::NONE;
+
+            market_position.liquidation_start_slot = calculate_liquidation_start_slot(
+                is_liquidatable,
+                market_position.liquidation_start_slot,
+                clock_slot,
+            );
+        }
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state) &&
+        admin_controls_prices(&ctx.accounts.state) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+    )]
+    pub fn move_amm_price(
+        ctx: Context<MoveAMMPrice>,
+        base_asset_reserve: u128,
+        quote_asset_reserve: u128,
+        market_index: u64,
+    ) -> Result<()> {
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
+        controller::amm::move_price(&mut market.amm, base_asset_reserve, quote_asset_reserve)?;
+        controller::amm::update_market_spreads_with_oracle(
+            market,
+            &ctx.accounts.oracle,
+            Clock::get()?.slot,
+            &ctx.accounts.state.oracle_guard_rails.validity,
+        )?;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, market_index: u64, amount: u64) -> Result<()> {
+        let state = &mut ctx.accounts.state;
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
+
+        // A portion of fees must always remain in protocol to be used to keep markets optimal
+        let max_withdraw = market
+            .amm
+            .total_fee
+            .checked_mul(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR)
+            .ok_or_else(math_error!())?
+            .checked_div(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR)
+            .ok_or_else(math_error!())?
+            .checked_sub(market.amm.total_fee_withdrawn)
+            .ok_or_else(math_error!())?;
+
+        if cast_to_u128(amount)? > max_withdraw {
+            return Err(ErrorCode::AdminWithdrawTooLarge.into());
+        }
+
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.collateral_vault,
+            &ctx.accounts.recipient,
+            &ctx.accounts.collateral_vault_authority,
+            state.collateral_vault_nonce,
+            amount,
+        )?;
+
+        market.amm.total_fee_withdrawn = market
+            .amm
+            .total_fee_withdrawn
+            .checked_add(cast(amount)?)
+            .ok_or_else(math_error!())?;
+
+        Ok(())
+    }
+
+    pub fn withdraw_from_insurance_vault(
+        ctx: Context<WithdrawFromInsuranceVault>,
+        amount: u64,
+    ) -> Result<()> {
+        controller::insurance_fund::remove_protocol_stake(
+            &mut ctx.accounts.state.insurance_fund,
+            amount,
+            ctx.accounts.insurance_vault.amount,
+        )?;
+
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.insurance_vault,
+            &ctx.accounts.recipient,
+            &ctx.accounts.insurance_vault_authority,
+            ctx.accounts.state.insurance_vault_nonce,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn withdraw_from_insurance_vault_to_market(
+        ctx: Context<WithdrawFromInsuranceVaultToMarket>,
+        market_index: u64,
+        amount: u64,
+    ) -> Result<()> {
+        controller::insurance_fund::remove_protocol_stake(
+            &mut ctx.accounts.state.insurance_fund,
+            amount,
+            ctx.accounts.insurance_vault.amount,
+        )?;
+
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
+
+        // The admin can move fees from the insurance fund back to the protocol so that money in
+        // the insurance fund can be used to make market more optimal
+        // 100% goes to user fee pool (symmetric funding, repeg, and k adjustments)
+        market.amm.total_fee_minus_distributions = market
+            .amm
+            .total_fee_minus_distributions
+            .checked_add(cast(amount)?)
+            .ok_or_else(math_error!())?;
+
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.insurance_vault,
+            &ctx.accounts.collateral_vault,
+            &ctx.accounts.insurance_vault_authority,
+            ctx.accounts.state.insurance_vault_nonce,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    /// Moves the stakers' share of the market's fees to the insurance vault
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn settle_fees_to_insurance_fund(
+        ctx: Context<SettleFeesToInsuranceFund>,
+        market_index: u64,
+    ) -> Result<()> {
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let market = markets.get_market_mut(market_index);
+
+        let amount = controller::insurance_fund::settle_fees_to_insurance_fund(
+            market,
+            &ctx.accounts.state.insurance_fund,
+        )?;
+
+        if amount > 0 {
+            controller::token::send(
+                &ctx.accounts.token_program,
+                &ctx.accounts.collateral_vault,
+                &ctx.accounts.insurance_vault,
+                &ctx.accounts.collateral_vault_authority,
+                ctx.accounts.state.collateral_vault_nonce,
+                amount,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+    )]
+    pub fn repeg_amm_curve(
+        ctx: Context<RepegCurve>,
+        new_peg_candidate: u128,
+        market_index: u64,
+    ) -> Result<()> {
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        let price_oracle = &ctx.accounts.oracle;
+        let OraclePriceData {
+            price: oracle_price,
+            ..
+        } = market.amm.get_oracle_price(price_oracle, 0)?;
+
+        let peg_multiplier_before = market.amm.peg_multiplier;
+        let base_asset_reserve_before = market.amm.base_asset_reserve;
+        let quote_asset_reserve_before = market.amm.quote_asset_reserve;
+        let sqrt_k_before = market.amm.sqrt_k;
+
+        let oracle_validity_rails = &ctx.accounts.state.oracle_guard_rails;
+
+        let adjustment_cost = controller::repeg::repeg(
+            market,
+            price_oracle,
+            new_peg_candidate,
+            clock_slot,
+            oracle_validity_rails,
+        )?;
+        controller::amm::update_market_spreads_with_oracle(
+            market,
+            price_oracle,
+            clock_slot,
+            &oracle_validity_rails.validity,
+        )?;
+
+        let peg_multiplier_after = market.amm.peg_multiplier;
+        let base_asset_reserve_after = market.amm.base_asset_reserve;
+        let quote_asset_reserve_after = market.amm.quote_asset_reserve;
+        let sqrt_k_after = market.amm.sqrt_k;
+
+        let curve_history = &mut ctx.accounts.curve_history.load_mut()?;
+        let record_id = curve_history.next_record_id();
+        curve_history.append(ExtendedCurveRecord {
+            ts: now,
+            record_id,
+            market_index,
+            peg_multiplier_before,
+            base_asset_reserve_before,
+            quote_asset_reserve_before,
+            sqrt_k_before,
+            peg_multiplier_after,
+            base_asset_reserve_after,
+            quote_asset_reserve_after,
+            sqrt_k_after,
+            base_asset_amount_long: market.base_asset_amount_long.unsigned_abs(),
+            base_asset_amount_short: market.base_asset_amount_short.unsigned_abs(),
+            base_asset_amount: market.base_asset_amount,
+            open_interest: market.open_interest,
+            total_fee: market.amm.total_fee,
+            total_fee_minus_distributions: market.amm.total_fee_minus_distributions,
+            adjustment_cost,
+            oracle_price,
+            trade_record: 0,
+            padding: [0; 5],
+        });
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+     )]
+    pub fn update_amm_oracle_twap(ctx: Context<RepegCurve>, market_index: u64) -> Result<()> {
+        // allow update to amm's oracle twap iff price gap is reduced and thus more tame funding
+        // otherwise if oracle error or funding flip: set oracle twap to mark twap (0 gap)
+
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        let price_oracle = &ctx.accounts.oracle;
+        let oracle_twap = market.amm.get_oracle_twap(price_oracle)?;
+
+        if let Some(oracle_twap) = oracle_twap {
+            let oracle_mark_gap_before = cast_to_i128(market.amm.last_mark_price_twap)?
+                .checked_sub(market.amm.last_oracle_price_twap)
+                .ok_or_else(math_error!())?;
+
+            let oracle_mark_gap_after = cast_to_i128(market.amm.last_mark_price_twap)?
+                .checked_sub(oracle_twap)
+                .ok_or_else(math_error!())?;
+
+            if (oracle_mark_gap_after > 0 && oracle_mark_gap_before < 0)
+                || (oracle_mark_gap_after < 0 && oracle_mark_gap_before > 0)
+            {
+                market.amm.last_oracle_price_twap = cast_to_i128(market.amm.last_mark_price_twap)?;
+                market.amm.last_oracle_price_twap_ts = now;
+            } else if oracle_mark_gap_after.unsigned_abs() <= oracle_mark_gap_before.unsigned_abs()
+            {
+                market.amm.last_oracle_price_twap = oracle_twap;
+                market.amm.last_oracle_price_twap_ts = now;
+            } else {
+                return Err(ErrorCode::OracleMarkSpreadLimit.into());
+            }
+        } else {
+            return Err(ErrorCode::InvalidOracle.into());
+        }
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+     )]
+    pub fn reset_amm_oracle_twap(ctx: Context<RepegCurve>, market_index: u64) -> Result<()> {
+        // if oracle is invalid, failsafe to reset amm oracle_twap to the mark_twap
+
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        let market =
             &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
         let price_oracle = &ctx.accounts.oracle;
-        let OraclePriceData {
-            price: oracle_price,
-            ..
-        } = market.amm.get_oracle_price(price_oracle, 0)?;
+        let oracle_price_data = &market.amm.get_oracle_price(price_oracle, clock_slot)?;
+
+        let is_oracle_valid = amm::is_oracle_valid(
+            &market.amm,
+            oracle_price_data,
+            &ctx.accounts.state.oracle_guard_rails.validity,
+        )?;
+
+        if !is_oracle_valid {
+            market.amm.last_oracle_price_twap = cast_to_i128(market.amm.last_mark_price_twap)?;
+            market.amm.last_oracle_price_twap_ts = now;
+        }
+
+        Ok(())
+    }
+
+    pub fn initialize_user(
+        ctx: Context<InitializeUser>,
+        _user_nonce: u8,
+        optional_accounts: InitializeUserOptionalAccounts,
+    ) -> Result<()> {
+        user_initialization::initialize(
+            &ctx.accounts.state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.authority,
+            ctx.remaining_accounts,
+            optional_accounts,
+            0,
+        )
+    }
+
+    pub fn initialize_user_with_explicit_payer(
+        ctx: Context<InitializeUserWithExplicitPayer>,
+        _user_nonce: u8,
+        optional_accounts: InitializeUserOptionalAccounts,
+    ) -> Result<()> {
+        user_initialization::initialize(
+            &ctx.accounts.state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.authority,
+            ctx.remaining_accounts,
+            optional_accounts,
+            0,
+        )
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn initialize_user_sub_account(
+        ctx: Context<InitializeUserSubAccount>,
+        _user_nonce: u8,
+        sub_account_id: u8,
+        optional_accounts: InitializeUserOptionalAccounts,
+    ) -> Result<()> {
+        // sub account 0 is the user account created by initialize_user
+        if sub_account_id == 0 {
+            return Err(ErrorCode::InvalidSubAccountId.into());
+        }
+
+        user_initialization::initialize(
+            &ctx.accounts.state,
+            &mut ctx.accounts.user,
+            &ctx.accounts.user_positions,
+            &ctx.accounts.authority,
+            ctx.remaining_accounts,
+            optional_accounts,
+            sub_account_id,
+        )
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn resize_user_positions(
+        ctx: Context<ResizeUserPositions>,
+        number_of_positions: u8,
+    ) -> Result<()> {
+        let user_positions_account_info = ctx.accounts.user_positions.to_account_info();
+        let number_of_positions = number_of_positions as usize;
+        let current_number_of_positions =
+            UserPositions::number_of_positions(user_positions_account_info.data_len());
+
+        if number_of_positions <= current_number_of_positions
+            || number_of_positions > MAX_USER_POSITIONS
+        {
+            return Err(ErrorCode::InvalidNumberOfPositions.into());
+        }
+
+        // an instruction can only grow an account by MAX_PERMITTED_DATA_INCREASE, larger resizes
+        // take multiple calls
+        let new_space = UserPositions::space(number_of_positions);
+        if new_space.saturating_sub(user_positions_account_info.data_len())
+            > MAX_PERMITTED_DATA_INCREASE
+        {
+            return Err(ErrorCode::InvalidNumberOfPositions.into());
+        }
+
+        let rent_exempt_lamports = Rent::get()?.minimum_balance(new_space);
+        let lamports_required =
+            rent_exempt_lamports.saturating_sub(user_positions_account_info.lamports());
+
+        if lamports_required > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.authority.to_account_info(),
+                        to: user_positions_account_info.clone(),
+                    },
+                ),
+                lamports_required,
+            )?;
+        }
+
+        // new position slots are zeroed and therefore available
+        user_positions_account_info.realloc(new_space, true)?;
+
+        Ok(())
+    }
+
+    pub fn initialize_user_orders(
+        ctx: Context<InitializeUserOrders>,
+        _user_orders_nonce: u8,
+    ) -> Result<()> {
+        let orders = &mut ctx.accounts.user_orders.load_init()?;
+        orders.user = ctx.accounts.user.key();
+        Ok(())
+    }
+
+    pub fn initialize_user_orders_with_explicit_payer(
+        ctx: Context<InitializeUserOrdersWithExplicitPayer>,
+        _user_orders_nonce: u8,
+    ) -> Result<()> {
+        let orders = &mut ctx.accounts.user_orders.load_init()?;
+        orders.user = ctx.accounts.user.key();
+        Ok(())
+    }
+
+    #[access_control(
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn settle_funding_payment(ctx: Context<SettleFunding>) -> Result<()> {
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            &mut ctx.accounts.user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            &mut ctx.accounts.deposit_history.load_mut()?,
+            now,
+        )?;
+        controller::funding::settle_funding_payment(
+            &mut ctx.accounts.user,
+            &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?,
+            &ctx.accounts.markets.load()?,
+            &mut ctx.accounts.funding_payment_history.load_mut()?,
+            now,
+        )?;
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            ctx.accounts.user.collateral,
+        )?;
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+    )]
+    pub fn update_funding_rate(ctx: Context<UpdateFundingRate>, market_index: u64) -> Result<()> {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        let price_oracle = &ctx.accounts.oracle;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let clock_slot = clock.slot;
+
+        let last_funding_rate_ts = market.amm.last_funding_rate_ts;
+        let funding_rate_history = &mut ctx.accounts.funding_rate_history.load_mut()?;
+        controller::funding::update_funding_rate(
+            market_index,
+            market,
+            price_oracle,
+            now,
+            clock_slot,
+            funding_rate_history,
+            &ctx.accounts.state.oracle_guard_rails,
+            ctx.accounts.state.funding_paused,
+            None,
+        )?;
+
+        // Resize the curve once per funding period based on the health of the market's fee pool
+        if market.amm.last_funding_rate_ts != last_funding_rate_ts {
+            let oracle_price = market.amm.get_oracle_price(price_oracle, clock_slot)?.price;
+            let extended_curve_history = &mut ctx.accounts.extended_curve_history.load_mut()?;
+            controller::repeg::formulaic_update_k(
+                market,
+                market_index,
+                oracle_price,
+                &ctx.accounts.state,
+                extended_curve_history,
+                now,
+            )?;
+            controller::amm::update_market_spreads_with_oracle(
+                market,
+                price_oracle,
+                clock_slot,
+                &ctx.accounts.state.oracle_guard_rails.validity,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index) &&
+        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index) &&
+        exchange_not_paused(&ctx.accounts.state)
+    )]
+    pub fn update_k(ctx: Context<AdminUpdateK>, sqrt_k: u128, market_index: u64) -> Result<()> {
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
+
+        let base_asset_amount_long = market.base_asset_amount_long.unsigned_abs();
+        let base_asset_amount_short = market.base_asset_amount_short.unsigned_abs();
+        let base_asset_amount = market.base_asset_amount;
+        let open_interest = market.open_interest;
+
+        let price_before = market.amm.mark_price()?;
 
         let peg_multiplier_before = market.amm.peg_multiplier;
         let base_asset_reserve_before = market.amm.base_asset_reserve;
         let quote_asset_reserve_before = market.amm.quote_asset_reserve;
         let sqrt_k_before = market.amm.sqrt_k;
 
-        let oracle_validity_rails = &ctx.accounts.state.oracle_guard_rails;
+        let adjustment_cost = math::amm::adjust_k_cost(market, bn::U256::from(sqrt_k))?;
 
-        let adjustment_cost = controller::repeg::repeg(
+        if adjustment_cost > 0 {
+            let max_cost = market
+                .amm
+                .total_fee_minus_distributions
+                .checked_sub(market.amm.total_fee_withdrawn)
+                .ok_or_else(math_error!())?;
+            if adjustment_cost.unsigned_abs() > max_cost {
+                return Err(ErrorCode::InvalidUpdateK.into());
+            } else {
+                market.amm.total_fee_minus_distributions = market
+                    .amm
+                    .total_fee_minus_distributions
+                    .checked_sub(adjustment_cost.unsigned_abs())
+                    .ok_or_else(math_error!())?;
+            }
+        } else {
+            market.amm.total_fee_minus_distributions = market
+                .amm
+                .total_fee_minus_distributions
+                .checked_add(adjustment_cost.unsigned_abs())
+                .ok_or_else(math_error!())?;
+        }
+
+        controller::amm::update_market_spreads_with_oracle(
             market,
-            price_oracle,
-            new_peg_candidate,
-            clock_slot,
-            oracle_validity_rails,
+            &ctx.accounts.oracle,
+            clock.slot,
+            &ctx.accounts.state.oracle_guard_rails.validity,
         )?;
 
-        let peg_multiplier_after = market.amm.peg_multiplier;
-        let base_asset_reserve_after = market.amm.base_asset_reserve;
-        let quote_asset_reserve_after = market.amm.quote_asset_reserve;
-        let sqrt_k_after = market.amm.sqrt_k;
+        let amm = &market.amm;
+
+        let price_after = amm.mark_price()?;
+
+        let price_change_too_large = cast_to_i128(price_before)?
+            .checked_sub(cast_to_i128(price_after)?)
+            .ok_or_else(math_error!())?
+            .unsigned_abs()
+            .gt(&UPDATE_K_ALLOWED_PRICE_CHANGE);
+
+        if price_change_too_large {
+            return Err(ErrorCode::InvalidUpdateK.into());
+        }
+
+        let k_sqrt_check = amm
+            .curve()
+            .calculate_sqrt_k(amm.base_asset_reserve, amm.quote_asset_reserve)?;
+
+        let k_err = cast_to_i128(k_sqrt_check)?
+            .checked_sub(cast_to_i128(amm.sqrt_k)?)
+            .ok_or_else(math_error!())?;
+
+        if k_err.unsigned_abs() > 100 {
+            let sqrt_k = amm.sqrt_k;
+            msg!("k_err={:?}, {:?} != {:?}", k_err, k_sqrt_check, sqrt_k);
+            return Err(ErrorCode::InvalidUpdateK.into());
+        }
+
+        let peg_multiplier_after = amm.peg_multiplier;
+        let base_asset_reserve_after = amm.base_asset_reserve;
+        let quote_asset_reserve_after = amm.quote_asset_reserve;
+        let sqrt_k_after = amm.sqrt_k;
+
+        let total_fee = amm.total_fee;
+        let total_fee_minus_distributions = amm.total_fee_minus_distributions;
+
+        let OraclePriceData {
+            price: oracle_price,
+            ..
+        } = amm.get_oracle_price(&ctx.accounts.oracle, 0)?;
 
         let curve_history = &mut ctx.accounts.curve_history.load_mut()?;
         let record_id = curve_history.next_record_id();
@@ -1929,13 +4396,13 @@ pub mod clearing_house {
             base_asset_reserve_after,
             quote_asset_reserve_after,
             sqrt_k_after,
-            base_asset_amount_long: market.base_asset_amount_long.unsigned_abs(),
-            base_asset_amount_short: market.base_asset_amount_short.unsigned_abs(),
-            base_asset_amount: market.base_asset_amount,
-            open_interest: market.open_interest,
-            total_fee: market.amm.total_fee,
-            total_fee_minus_distributions: market.amm.total_fee_minus_distributions,
+            base_asset_amount_long,
+            base_asset_amount_short,
+            base_asset_amount,
+            open_interest,
             adjustment_cost,
+            total_fee,
+            total_fee_minus_distributions,
             oracle_price,
             trade_record: 0,
             padding: [0; 5],
@@ -1944,721 +4411,1734 @@ pub mod clearing_house {
         Ok(())
     }
 
-    #[allow(unused_must_use)]
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index) &&
-        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
-     )]
-    pub fn update_amm_oracle_twap(ctx: Context<RepegCurve>, market_index: u64) -> Result<()> {
-        // allow update to amm's oracle twap iff price gap is reduced and thus more tame funding
-        // otherwise if oracle error or funding flip: set oracle twap to mark twap (0 gap)
+    pub fn update_curve_history(ctx: Context<UpdateCurveHistory>) -> Result<()> {
+        let curve_history = &ctx.accounts.curve_history.load()?;
+        let extended_curve_history = &mut ctx.accounts.extended_curve_history.load_init()?;
 
-        let clock = Clock::get()?;
-        let now = clock.unix_timestamp;
+        for old_record in curve_history.curve_records.iter() {
+            if old_record.record_id != 0 {
+                let new_record = ExtendedCurveRecord {
+                    ts: old_record.ts,
+                    record_id: old_record.record_id,
+                    market_index: old_record.market_index,
+                    peg_multiplier_before: old_record.peg_multiplier_before,
+                    base_asset_reserve_before: old_record.base_asset_reserve_before,
+                    quote_asset_reserve_before: old_record.quote_asset_reserve_before,
+                    sqrt_k_before: old_record.sqrt_k_before,
+                    peg_multiplier_after: old_record.peg_multiplier_after,
+                    base_asset_reserve_after: old_record.base_asset_reserve_after,
+                    quote_asset_reserve_after: old_record.quote_asset_reserve_after,
+                    sqrt_k_after: old_record.sqrt_k_after,
+                    base_asset_amount_long: old_record.base_asset_amount_long,
+                    base_asset_amount_short: old_record.base_asset_amount_short,
+                    base_asset_amount: old_record.base_asset_amount,
+                    open_interest: old_record.open_interest,
+                    total_fee: old_record.total_fee,
+                    total_fee_minus_distributions: old_record.total_fee_minus_distributions,
+                    adjustment_cost: old_record.adjustment_cost,
+                    oracle_price: 0,
+                    trade_record: 0,
+                    padding: [0; 5],
+                };
+                extended_curve_history.append(new_record);
+            }
+        }
 
-        let market =
-            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        let price_oracle = &ctx.accounts.oracle;
-        let oracle_twap = market.amm.get_oracle_twap(price_oracle)?;
+        let state = &mut ctx.accounts.state;
+        state.extended_curve_history = ctx.accounts.extended_curve_history.key();
+        Ok(())
+    }
 
-        if let Some(oracle_twap) = oracle_twap {
-            let oracle_mark_gap_before = cast_to_i128(market.amm.last_mark_price_twap)?
-                .checked_sub(market.amm.last_oracle_price_twap)
-                .ok_or_else(math_error!())?;
+    #[allow(clippy::result_large_err)]
+    pub fn update_trade_history(ctx: Context<UpdateTradeHistory>) -> Result<()> {
+        let trade_history = &ctx.accounts.trade_history.load()?;
+        let extended_trade_history = &mut ctx.accounts.extended_trade_history.load_init()?;
+
+        // Copying every record would exceed the compute budget, so only the latest record is
+        // carried over to keep the record ids continuous
+        let old_record = trade_history.last_record();
+        if old_record.record_id != 0 {
+            extended_trade_history.append(ExtendedTradeRecord {
+                ts: old_record.ts,
+                record_id: old_record.record_id,
+                user_authority: old_record.user_authority,
+                user: old_record.user,
+                direction: old_record.direction,
+                base_asset_amount: old_record.base_asset_amount,
+                quote_asset_amount: old_record.quote_asset_amount,
+                mark_price_before: old_record.mark_price_before,
+                mark_price_after: old_record.mark_price_after,
+                fee: old_record.fee,
+                quote_asset_amount_surplus: old_record.quote_asset_amount_surplus,
+                referee_discount: old_record.referee_discount,
+                token_discount: old_record.token_discount,
+                liquidation: old_record.liquidation,
+                market_index: old_record.market_index,
+                oracle_price: old_record.oracle_price,
+                maker: Pubkey::default(),
+                maker_fee: 0,
+                spread: 0,
+                padding: [0; 4],
+            });
+        }
 
-            let oracle_mark_gap_after = cast_to_i128(market.amm.last_mark_price_twap)?
-                .checked_sub(oracle_twap)
-                .ok_or_else(math_error!())?;
+        let state = &mut ctx.accounts.state;
+        state.extended_trade_history = ctx.accounts.extended_trade_history.key();
+        Ok(())
+    }
 
-            if (oracle_mark_gap_after > 0 && oracle_mark_gap_before < 0)
-                || (oracle_mark_gap_after < 0 && oracle_mark_gap_before > 0)
-            {
-                market.amm.last_oracle_price_twap = cast_to_i128(market.amm.last_mark_price_twap)?;
-                market.amm.last_oracle_price_twap_ts = now;
-            } else if oracle_mark_gap_after.unsigned_abs() <= oracle_mark_gap_before.unsigned_abs()
-            {
-                market.amm.last_oracle_price_twap = oracle_twap;
-                market.amm.last_oracle_price_twap_ts = now;
-            } else {
-                return Err(ErrorCode::OracleMarkSpreadLimit.into());
-            }
-        } else {
-            return Err(ErrorCode::InvalidOracle.into());
+    #[allow(clippy::result_large_err)]
+    pub fn update_liquidation_history(ctx: Context<UpdateLiquidationHistory>) -> Result<()> {
+        let liquidation_history = &ctx.accounts.liquidation_history.load()?;
+        let extended_liquidation_history =
+            &mut ctx.accounts.extended_liquidation_history.load_init()?;
+
+        // Copying every record would exceed the compute budget, so only the latest record is
+        // carried over to keep the record ids continuous
+        let old_record = liquidation_history.last_record();
+        if old_record.record_id != 0 {
+            extended_liquidation_history.append(ExtendedLiquidationRecord {
+                ts: old_record.ts,
+                record_id: old_record.record_id,
+                user_authority: old_record.user_authority,
+                user: old_record.user,
+                partial: old_record.partial,
+                base_asset_value: old_record.base_asset_value,
+                base_asset_value_closed: old_record.base_asset_value_closed,
+                liquidation_fee: old_record.liquidation_fee,
+                fee_to_liquidator: old_record.fee_to_liquidator,
+                fee_to_insurance_fund: old_record.fee_to_insurance_fund,
+                liquidator: old_record.liquidator,
+                total_collateral: old_record.total_collateral,
+                collateral: old_record.collateral,
+                unrealized_pnl: old_record.unrealized_pnl,
+                margin_ratio: old_record.margin_ratio,
+                market_index: 0,
+                base_asset_amount_transferred: 0,
+                transfer_price: 0,
+                padding: [0; 5],
+            });
         }
 
+        let state = &mut ctx.accounts.state;
+        state.extended_liquidation_history = ctx.accounts.extended_liquidation_history.key();
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn update_funding_rate_history(ctx: Context<UpdateFundingRateHistory>) -> Result<()> {
+        let funding_rate_history = &ctx.accounts.funding_rate_history.load()?;
+        let extended_funding_rate_history =
+            &mut ctx.accounts.extended_funding_rate_history.load_init()?;
+
+        // Copying every record would exceed the compute budget, so only the latest record is
+        // carried over to keep the record ids continuous
+        let old_record = funding_rate_history.last_record();
+        if old_record.record_id != 0 {
+            extended_funding_rate_history.append(ExtendedFundingRateRecord {
+                ts: old_record.ts,
+                record_id: old_record.record_id,
+                market_index: old_record.market_index,
+                funding_rate: old_record.funding_rate,
+                cumulative_funding_rate_long: old_record.cumulative_funding_rate_long,
+                cumulative_funding_rate_short: old_record.cumulative_funding_rate_short,
+                oracle_price_twap: old_record.oracle_price_twap,
+                mark_price_twap: old_record.mark_price_twap,
+                raw_funding_rate: 0,
+                premium_index: 0,
+                padding: [0; 5],
+            });
+        }
+
+        let state = &mut ctx.accounts.state;
+        state.extended_funding_rate_history = ctx.accounts.extended_funding_rate_history.key();
         Ok(())
     }
 
-    #[allow(unused_must_use)]
     #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index) &&
-        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
-     )]
-    pub fn reset_amm_oracle_twap(ctx: Context<RepegCurve>, market_index: u64) -> Result<()> {
-        // if oracle is invalid, failsafe to reset amm oracle_twap to the mark_twap
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_margin_ratio(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        margin_ratio_initial: u32,
+        margin_ratio_partial: u32,
+        margin_ratio_maintenance: u32,
+        imf_factor: u32,
+    ) -> Result<()> {
+        validate_margin(
+            margin_ratio_initial,
+            margin_ratio_partial,
+            margin_ratio_maintenance,
+        )?;
+        validate_imf_factor(imf_factor)?;
 
-        let clock = Clock::get()?;
-        let now = clock.unix_timestamp;
-        let clock_slot = clock.slot;
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.margin_ratio_initial = margin_ratio_initial;
+        market.margin_ratio_partial = margin_ratio_partial;
+        market.margin_ratio_maintenance = margin_ratio_maintenance;
+        market.imf_factor = imf_factor;
+        Ok(())
+    }
+
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_liquidation_penalty(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        partial_liquidation_penalty: u32,
+        full_liquidation_penalty: u32,
+    ) -> Result<()> {
+        validate_liquidation_fees(partial_liquidation_penalty, full_liquidation_penalty)?;
 
         let market =
             &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        let price_oracle = &ctx.accounts.oracle;
-        let oracle_price_data = &market.amm.get_oracle_price(price_oracle, clock_slot)?;
+        market.partial_liquidation_penalty = partial_liquidation_penalty;
+        market.full_liquidation_penalty = full_liquidation_penalty;
+        Ok(())
+    }
 
-        let is_oracle_valid = amm::is_oracle_valid(
-            &market.amm,
-            oracle_price_data,
-            &ctx.accounts.state.oracle_guard_rails.validity,
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_liquidator_share(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        partial_liquidation_liquidator_share: u32,
+        full_liquidation_liquidator_share: u32,
+    ) -> Result<()> {
+        validate_liquidation_fees(
+            partial_liquidation_liquidator_share,
+            full_liquidation_liquidator_share,
         )?;
 
-        if !is_oracle_valid {
-            market.amm.last_oracle_price_twap = cast_to_i128(market.amm.last_mark_price_twap)?;
-            market.amm.last_oracle_price_twap_ts = now;
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.partial_liquidation_liquidator_share = partial_liquidation_liquidator_share;
+        market.full_liquidation_liquidator_share = full_liquidation_liquidator_share;
+        Ok(())
+    }
+
+    pub fn update_partial_liquidation_close_percentage(
+        ctx: Context<AdminUpdateState>,
+        numerator: u128,
+        denominator: u128,
+    ) -> Result<()> {
+        ctx.accounts
+            .state
+            .partial_liquidation_close_percentage_numerator = numerator;
+        ctx.accounts
+            .state
+            .partial_liquidation_close_percentage_denominator = denominator;
+        Ok(())
+    }
+
+    /// Markets have their own liquidation fees, so the exchange wide ones only set the fees that
+    /// markets initialized or migrated afterwards start out with
+    pub fn update_partial_liquidation_penalty_percentage(
+        ctx: Context<AdminUpdateState>,
+        numerator: u128,
+        denominator: u128,
+    ) -> Result<()> {
+        ctx.accounts
+            .state
+            .partial_liquidation_penalty_percentage_numerator = numerator;
+        ctx.accounts
+            .state
+            .partial_liquidation_penalty_percentage_denominator = denominator;
+        Ok(())
+    }
+
+    /// Only sets the fee that markets initialized or migrated afterwards start out with
+    pub fn update_full_liquidation_penalty_percentage(
+        ctx: Context<AdminUpdateState>,
+        numerator: u128,
+        denominator: u128,
+    ) -> Result<()> {
+        ctx.accounts
+            .state
+            .full_liquidation_penalty_percentage_numerator = numerator;
+        ctx.accounts
+            .state
+            .full_liquidation_penalty_percentage_denominator = denominator;
+        Ok(())
+    }
+
+    /// Only sets the share that markets initialized or migrated afterwards start out with
+    pub fn update_partial_liquidation_liquidator_share_denominator(
+        ctx: Context<AdminUpdateState>,
+        denominator: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .state
+            .partial_liquidation_liquidator_share_denominator = denominator;
+        Ok(())
+    }
+
+    /// Only sets the share that markets initialized or migrated afterwards start out with
+    pub fn update_full_liquidation_liquidator_share_denominator(
+        ctx: Context<AdminUpdateState>,
+        denominator: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .state
+            .full_liquidation_liquidator_share_denominator = denominator;
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn update_position_transfer_discount(
+        ctx: Context<AdminUpdateState>,
+        numerator: u128,
+        denominator: u128,
+    ) -> Result<()> {
+        ctx.accounts.state.position_transfer_discount_numerator = numerator;
+        ctx.accounts.state.position_transfer_discount_denominator = denominator;
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn update_liquidation_auction(
+        ctx: Context<AdminUpdateState>,
+        liquidation_auction: LiquidationAuction,
+    ) -> Result<()> {
+        if liquidation_auction.start_liquidator_share_denominator == 0
+            || liquidation_auction.start_liquidator_share_numerator
+                > liquidation_auction.start_liquidator_share_denominator
+        {
+            return Err(ErrorCode::InvalidLiquidationAuction.into());
         }
 
+        ctx.accounts.state.liquidation_auction = liquidation_auction;
         Ok(())
     }
 
-    pub fn initialize_user(
-        ctx: Context<InitializeUser>,
-        _user_nonce: u8,
-        optional_accounts: InitializeUserOptionalAccounts,
+    #[allow(clippy::result_large_err)]
+    pub fn update_formulaic_repeg_threshold(
+        ctx: Context<AdminUpdateState>,
+        numerator: u128,
+        denominator: u128,
     ) -> Result<()> {
-        user_initialization::initialize(
-            &ctx.accounts.state,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.authority,
-            ctx.remaining_accounts,
-            optional_accounts,
-        )
+        ctx.accounts.state.formulaic_repeg_threshold_numerator = numerator;
+        ctx.accounts.state.formulaic_repeg_threshold_denominator = denominator;
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn update_formulaic_k_max_change(
+        ctx: Context<AdminUpdateState>,
+        numerator: u128,
+        denominator: u128,
+    ) -> Result<()> {
+        if denominator == 0
+            || numerator
+                .checked_mul(MAX_FORMULAIC_K_DECREASE_DENOMINATOR)
+                .ok_or_else(math_error!())?
+                > denominator
+                    .checked_mul(MAX_FORMULAIC_K_DECREASE_NUMERATOR)
+                    .ok_or_else(math_error!())?
+        {
+            return Err(ErrorCode::InvalidFormulaicKMaxChange.into());
+        }
+
+        ctx.accounts.state.formulaic_k_max_change_numerator = numerator;
+        ctx.accounts.state.formulaic_k_max_change_denominator = denominator;
+        Ok(())
     }
 
-    pub fn initialize_user_with_explicit_payer(
-        ctx: Context<InitializeUserWithExplicitPayer>,
-        _user_nonce: u8,
-        optional_accounts: InitializeUserOptionalAccounts,
+    pub fn update_fee(ctx: Context<AdminUpdateState>, fees: FeeStructure) -> Result<()> {
+        ctx.accounts.state.fee_structure = fees;
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn update_maker_rebate(
+        ctx: Context<AdminUpdateState>,
+        maker_rebate_numerator: u128,
+        maker_rebate_denominator: u128,
     ) -> Result<()> {
-        user_initialization::initialize(
-            &ctx.accounts.state,
-            &mut ctx.accounts.user,
-            &ctx.accounts.user_positions,
-            &ctx.accounts.authority,
-            ctx.remaining_accounts,
-            optional_accounts,
-        )
+        if maker_rebate_denominator == 0 || maker_rebate_numerator > maker_rebate_denominator {
+            return Err(ErrorCode::InvalidMakerRebate.into());
+        }
+
+        let state = &mut ctx.accounts.state;
+        state.maker_rebate_numerator = maker_rebate_numerator;
+        state.maker_rebate_denominator = maker_rebate_denominator;
+        Ok(())
     }
 
-    pub fn initialize_user_orders(
-        ctx: Context<InitializeUserOrders>,
-        _user_orders_nonce: u8,
+    pub fn update_order_filler_reward_structure(
+        ctx: Context<AdminUpdateOrderState>,
+        order_filler_reward_structure: OrderFillerRewardStructure,
     ) -> Result<()> {
-        let orders = &mut ctx.accounts.user_orders.load_init()?;
-        orders.user = ctx.accounts.user.key();
+        ctx.accounts.order_state.order_filler_reward_structure = order_filler_reward_structure;
         Ok(())
     }
 
-    pub fn initialize_user_orders_with_explicit_payer(
-        ctx: Context<InitializeUserOrdersWithExplicitPayer>,
-        _user_orders_nonce: u8,
+    pub fn update_oracle_guard_rails(
+        ctx: Context<AdminUpdateState>,
+        oracle_guard_rails: OracleGuardRails,
     ) -> Result<()> {
-        let orders = &mut ctx.accounts.user_orders.load_init()?;
-        orders.user = ctx.accounts.user.key();
+        ctx.accounts.state.oracle_guard_rails = oracle_guard_rails;
         Ok(())
     }
 
     #[access_control(
-        exchange_not_paused(&ctx.accounts.state)
+        market_initialized(&ctx.accounts.markets, market_index)
     )]
-    pub fn settle_funding_payment(ctx: Context<SettleFunding>) -> Result<()> {
-        let clock = Clock::get()?;
-        let now = clock.unix_timestamp;
-        controller::funding::settle_funding_payment(
-            &mut ctx.accounts.user,
-            &mut ctx.accounts.user_positions.load_mut()?,
-            &ctx.accounts.markets.load()?,
-            &mut ctx.accounts.funding_payment_history.load_mut()?,
-            now,
-        )?;
+    pub fn update_market_oracle(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        oracle: Pubkey,
+        oracle_source: OracleSource,
+    ) -> Result<()> {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.amm.oracle = oracle;
+        market.amm.oracle_source = oracle_source;
         Ok(())
     }
 
-    #[allow(unused_must_use)]
     #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index) &&
-        exchange_not_paused(&ctx.accounts.state) &&
-        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index)
+        market_initialized(&ctx.accounts.markets, market_index)
     )]
-    pub fn update_funding_rate(ctx: Context<UpdateFundingRate>, market_index: u64) -> Result<()> {
+    pub fn update_market_minimum_quote_asset_trade_size(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        minimum_trade_size: u128,
+    ) -> Result<()> {
         let market =
             &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        let price_oracle = &ctx.accounts.oracle;
-        let clock = Clock::get()?;
-        let now = clock.unix_timestamp;
-        let clock_slot = clock.slot;
-
-        let funding_rate_history = &mut ctx.accounts.funding_rate_history.load_mut()?;
-        controller::funding::update_funding_rate(
-            market_index,
-            market,
-            price_oracle,
-            now,
-            clock_slot,
-            funding_rate_history,
-            &ctx.accounts.state.oracle_guard_rails,
-            ctx.accounts.state.funding_paused,
-            None,
-        )?;
-
+        market.amm.minimum_quote_asset_trade_size = minimum_trade_size;
         Ok(())
     }
 
-    #[allow(unused_must_use)]
     #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index) &&
-        valid_oracle_for_market(&ctx.accounts.oracle, &ctx.accounts.markets, market_index) &&
-        exchange_not_paused(&ctx.accounts.state)
+        market_initialized(&ctx.accounts.markets, market_index)
     )]
-    pub fn update_k(ctx: Context<AdminUpdateK>, sqrt_k: u128, market_index: u64) -> Result<()> {
-        let clock = Clock::get()?;
-        let now = clock.unix_timestamp;
-
-        let markets = &mut ctx.accounts.markets.load_mut()?;
-        let market = &mut markets.markets[Markets::index_from_u64(market_index)];
-
-        let base_asset_amount_long = market.base_asset_amount_long.unsigned_abs();
-        let base_asset_amount_short = market.base_asset_amount_short.unsigned_abs();
-        let base_asset_amount = market.base_asset_amount;
-        let open_interest = market.open_interest;
-
-        let price_before = math::amm::calculate_price(
-            market.amm.quote_asset_reserve,
-            market.amm.base_asset_reserve,
-            market.amm.peg_multiplier,
-        )?;
-
-        let peg_multiplier_before = market.amm.peg_multiplier;
-        let base_asset_reserve_before = market.amm.base_asset_reserve;
-        let quote_asset_reserve_before = market.amm.quote_asset_reserve;
-        let sqrt_k_before = market.amm.sqrt_k;
-
-        let adjustment_cost = math::amm::adjust_k_cost(market, bn::U256::from(sqrt_k))?;
-
-        if adjustment_cost > 0 {
-            let max_cost = market
-                .amm
-                .total_fee_minus_distributions
-                .checked_sub(market.amm.total_fee_withdrawn)
-                .ok_or_else(math_error!())?;
-            if adjustment_cost.unsigned_abs() > max_cost {
-                return Err(ErrorCode::InvalidUpdateK.into());
-            } else {
-                market.amm.total_fee_minus_distributions = market
-                    .amm
-                    .total_fee_minus_distributions
-                    .checked_sub(adjustment_cost.unsigned_abs())
-                    .ok_or_else(math_error!())?;
-            }
-        } else {
-            market.amm.total_fee_minus_distributions = market
-                .amm
-                .total_fee_minus_distributions
-                .checked_add(adjustment_cost.unsigned_abs())
-                .ok_or_else(math_error!())?;
+    pub fn update_market_base_spread(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        base_spread: u16,
+    ) -> Result<()> {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        if market.amm.max_spread != 0 && base_spread > market.amm.max_spread {
+            return Err(ErrorCode::InvalidSpread.into());
         }
 
-        let amm = &market.amm;
+        market.amm.base_spread = base_spread;
+        Ok(())
+    }
 
-        let price_after = math::amm::calculate_price(
-            amm.quote_asset_reserve,
-            amm.base_asset_reserve,
-            amm.peg_multiplier,
-        )?;
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_max_spread(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        max_spread: u16,
+    ) -> Result<()> {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        if max_spread != 0 && max_spread < market.amm.base_spread {
+            return Err(ErrorCode::InvalidSpread.into());
+        }
 
-        let price_change_too_large = cast_to_i128(price_before)?
-            .checked_sub(cast_to_i128(price_after)?)
-            .ok_or_else(math_error!())?
-            .unsigned_abs()
-            .gt(&UPDATE_K_ALLOWED_PRICE_CHANGE);
+        market.amm.max_spread = max_spread;
+        Ok(())
+    }
 
-        if price_change_too_large {
-            return Err(ErrorCode::InvalidUpdateK.into());
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_funding_rate_parameters(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        max_funding_rate: u32,
+        funding_interest_rate: u32,
+    ) -> Result<()> {
+        if max_funding_rate > MAX_FUNDING_RATE || funding_interest_rate > MAX_FUNDING_INTEREST_RATE
+        {
+            return Err(ErrorCode::InvalidFundingRateParameters.into());
         }
 
-        let k_sqrt_check = bn::U192::from(amm.base_asset_reserve)
-            .checked_mul(bn::U192::from(amm.quote_asset_reserve))
-            .ok_or_else(math_error!())?
-            .integer_sqrt()
-            .try_to_u128()?;
-
-        let k_err = cast_to_i128(k_sqrt_check)?
-            .checked_sub(cast_to_i128(amm.sqrt_k)?)
-            .ok_or_else(math_error!())?;
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.max_funding_rate = max_funding_rate;
+        market.funding_interest_rate = funding_interest_rate;
+        Ok(())
+    }
 
-        if k_err.unsigned_abs() > 100 {
-            let sqrt_k = amm.sqrt_k;
-            msg!("k_err={:?}, {:?} != {:?}", k_err, k_sqrt_check, sqrt_k);
-            return Err(ErrorCode::InvalidUpdateK.into());
-        }
+    #[access_control(
+        market_initialized(&ctx.accounts.markets, market_index)
+    )]
+    pub fn update_market_minimum_base_asset_trade_size(
+        ctx: Context<AdminUpdateMarket>,
+        market_index: u64,
+        minimum_trade_size: u128,
+    ) -> Result<()> {
+        let market =
+            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
+        market.amm.minimum_base_asset_trade_size = minimum_trade_size;
+        Ok(())
+    }
 
-        let peg_multiplier_after = amm.peg_multiplier;
-        let base_asset_reserve_after = amm.base_asset_reserve;
-        let quote_asset_reserve_after = amm.quote_asset_reserve;
-        let sqrt_k_after = amm.sqrt_k;
+    pub fn update_admin(ctx: Context<AdminUpdateState>, admin: Pubkey) -> Result<()> {
+        ctx.accounts.state.admin = admin;
+        Ok(())
+    }
 
-        let total_fee = amm.total_fee;
-        let total_fee_minus_distributions = amm.total_fee_minus_distributions;
+    pub fn update_whitelist_mint(
+        ctx: Context<AdminUpdateState>,
+        whitelist_mint: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.state.whitelist_mint = whitelist_mint;
+        Ok(())
+    }
 
-        let OraclePriceData {
-            price: oracle_price,
-            ..
-        } = amm.get_oracle_price(&ctx.accounts.oracle, 0)?;
+    pub fn update_discount_mint(
+        ctx: Context<AdminUpdateState>,
+        discount_mint: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.state.discount_mint = discount_mint;
+        Ok(())
+    }
 
-        let curve_history = &mut ctx.accounts.curve_history.load_mut()?;
-        let record_id = curve_history.next_record_id();
-        curve_history.append(ExtendedCurveRecord {
-            ts: now,
-            record_id,
-            market_index,
-            peg_multiplier_before,
-            base_asset_reserve_before,
-            quote_asset_reserve_before,
-            sqrt_k_before,
-            peg_multiplier_after,
-            base_asset_reserve_after,
-            quote_asset_reserve_after,
-            sqrt_k_after,
-            base_asset_amount_long,
-            base_asset_amount_short,
-            base_asset_amount,
-            open_interest,
-            adjustment_cost,
-            total_fee,
-            total_fee_minus_distributions,
-            oracle_price,
-            trade_record: 0,
-            padding: [0; 5],
-        });
+    #[allow(clippy::result_large_err)]
+    pub fn update_collateral_asset_weights(
+        ctx: Context<AdminUpdateCollateralAssets>,
+        asset_index: u64,
+        asset_weight: u128,
+        liquidation_discount: u128,
+    ) -> Result<()> {
+        validate_collateral_asset_weights(asset_weight, liquidation_discount)?;
 
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+        if !collateral_asset.initialized {
+            return Err(ErrorCode::InvalidCollateralAsset.into());
+        }
+
+        collateral_asset.asset_weight = asset_weight;
+        collateral_asset.liquidation_discount = liquidation_discount;
         Ok(())
     }
 
-    pub fn update_curve_history(ctx: Context<UpdateCurveHistory>) -> Result<()> {
-        let curve_history = &ctx.accounts.curve_history.load()?;
-        let extended_curve_history = &mut ctx.accounts.extended_curve_history.load_init()?;
+    #[allow(clippy::result_large_err)]
+    pub fn update_collateral_asset_interest_rate_curve(
+        ctx: Context<AdminUpdateCollateralAssets>,
+        asset_index: u64,
+        optimal_utilization: u128,
+        optimal_borrow_rate: u128,
+        max_borrow_rate: u128,
+    ) -> Result<()> {
+        validate_interest_rate_curve(optimal_utilization, optimal_borrow_rate, max_borrow_rate)?;
 
-        for old_record in curve_history.curve_records.iter() {
-            if old_record.record_id != 0 {
-                let new_record = ExtendedCurveRecord {
-                    ts: old_record.ts,
-                    record_id: old_record.record_id,
-                    market_index: old_record.market_index,
-                    peg_multiplier_before: old_record.peg_multiplier_before,
-                    base_asset_reserve_before: old_record.base_asset_reserve_before,
-                    quote_asset_reserve_before: old_record.quote_asset_reserve_before,
-                    sqrt_k_before: old_record.sqrt_k_before,
-                    peg_multiplier_after: old_record.peg_multiplier_after,
-                    base_asset_reserve_after: old_record.base_asset_reserve_after,
-                    quote_asset_reserve_after: old_record.quote_asset_reserve_after,
-                    sqrt_k_after: old_record.sqrt_k_after,
-                    base_asset_amount_long: old_record.base_asset_amount_long,
-                    base_asset_amount_short: old_record.base_asset_amount_short,
-                    base_asset_amount: old_record.base_asset_amount,
-                    open_interest: old_record.open_interest,
-                    total_fee: old_record.total_fee,
-                    total_fee_minus_distributions: old_record.total_fee_minus_distributions,
-                    adjustment_cost: old_record.adjustment_cost,
-                    oracle_price: 0,
-                    trade_record: 0,
-                    padding: [0; 5],
-                };
-                extended_curve_history.append(new_record);
-            }
+        let collateral_assets = &mut ctx.accounts.collateral_assets.load_mut()?;
+        let collateral_asset = collateral_assets.get_asset_mut(asset_index);
+        if !collateral_asset.initialized {
+            return Err(ErrorCode::InvalidCollateralAsset.into());
         }
 
-        let state = &mut ctx.accounts.state;
-        state.extended_curve_history = ctx.accounts.extended_curve_history.key();
+        let lending_pool = &mut collateral_asset.lending_pool;
+        lending_pool.optimal_utilization = optimal_utilization;
+        lending_pool.optimal_borrow_rate = optimal_borrow_rate;
+        lending_pool.max_borrow_rate = max_borrow_rate;
         Ok(())
     }
 
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index)
-    )]
-    pub fn update_margin_ratio(
-        ctx: Context<AdminUpdateMarket>,
-        market_index: u64,
-        margin_ratio_initial: u32,
-        margin_ratio_partial: u32,
-        margin_ratio_maintenance: u32,
+    #[allow(clippy::result_large_err)]
+    pub fn update_quote_interest_rate_curve(
+        ctx: Context<AdminUpdateState>,
+        optimal_utilization: u128,
+        optimal_borrow_rate: u128,
+        max_borrow_rate: u128,
     ) -> Result<()> {
-        validate_margin(
-            margin_ratio_initial,
-            margin_ratio_partial,
-            margin_ratio_maintenance,
-        )?;
+        validate_interest_rate_curve(optimal_utilization, optimal_borrow_rate, max_borrow_rate)?;
 
-        let market =
-            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        market.margin_ratio_initial = margin_ratio_initial;
-        market.margin_ratio_partial = margin_ratio_partial;
-        market.margin_ratio_maintenance = margin_ratio_maintenance;
+        let lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+        lending_pool.optimal_utilization = optimal_utilization;
+        lending_pool.optimal_borrow_rate = optimal_borrow_rate;
+        lending_pool.max_borrow_rate = max_borrow_rate;
         Ok(())
     }
 
-    pub fn update_partial_liquidation_close_percentage(
+    #[allow(clippy::result_large_err)]
+    pub fn update_insurance_fund_unstaking_period(
         ctx: Context<AdminUpdateState>,
-        numerator: u128,
-        denominator: u128,
+        unstaking_period: i64,
     ) -> Result<()> {
-        ctx.accounts
-            .state
-            .partial_liquidation_close_percentage_numerator = numerator;
-        ctx.accounts
-            .state
-            .partial_liquidation_close_percentage_denominator = denominator;
+        ctx.accounts.state.insurance_fund.unstaking_period = unstaking_period;
         Ok(())
     }
 
-    pub fn update_partial_liquidation_penalty_percentage(
+    #[allow(clippy::result_large_err)]
+    pub fn update_insurance_fund_fee_share(
         ctx: Context<AdminUpdateState>,
-        numerator: u128,
-        denominator: u128,
+        fee_share_numerator: u128,
+        fee_share_denominator: u128,
     ) -> Result<()> {
-        ctx.accounts
-            .state
-            .partial_liquidation_penalty_percentage_numerator = numerator;
-        ctx.accounts
-            .state
-            .partial_liquidation_penalty_percentage_denominator = denominator;
+        if fee_share_denominator == 0 || fee_share_numerator > fee_share_denominator {
+            return Err(ErrorCode::InvalidInsuranceFundFeeShare.into());
+        }
+
+        let insurance_fund = &mut ctx.accounts.state.insurance_fund;
+        insurance_fund.fee_share_numerator = fee_share_numerator;
+        insurance_fund.fee_share_denominator = fee_share_denominator;
         Ok(())
     }
 
-    pub fn update_full_liquidation_penalty_percentage(
+    pub fn update_max_deposit(ctx: Context<AdminUpdateState>, max_deposit: u128) -> Result<()> {
+        ctx.accounts.state.max_deposit = max_deposit;
+        Ok(())
+    }
+
+    pub fn update_exchange_paused(
         ctx: Context<AdminUpdateState>,
-        numerator: u128,
-        denominator: u128,
+        exchange_paused: bool,
     ) -> Result<()> {
-        ctx.accounts
-            .state
-            .full_liquidation_penalty_percentage_numerator = numerator;
-        ctx.accounts
-            .state
-            .full_liquidation_penalty_percentage_denominator = denominator;
+        ctx.accounts.state.exchange_paused = exchange_paused;
+        Ok(())
+    }
+
+    pub fn disable_admin_controls_prices(ctx: Context<AdminUpdateState>) -> Result<()> {
+        ctx.accounts.state.admin_controls_prices = false;
+        Ok(())
+    }
+
+    pub fn update_funding_paused(
+        ctx: Context<AdminUpdateState>,
+        funding_paused: bool,
+    ) -> Result<()> {
+        ctx.accounts.state.funding_paused = funding_paused;
+        Ok(())
+    }
+
+    pub fn admin_update_user_forgo_settlement(
+        ctx: Context<AdminUpdateUserForgoSettlement>,
+    ) -> Result<()> {
+        ctx.accounts.user.forgo_position_settlement = 1;
+        Ok(())
+    }
+
+    pub fn update_user_forgo_settlement(ctx: Context<UpdateUserForgoSettlement>) -> Result<()> {
+        ctx.accounts.user.forgo_position_settlement = 1;
+        Ok(())
+    }
+
+    pub fn initialize_settlement_state(
+        ctx: Context<InitializeSettlementState>,
+        collateral_to_be_settled: u64,
+    ) -> Result<()> {
+        let collateral_available_to_claim = ctx.accounts.collateral_vault.amount;
+
+        **ctx.accounts.settlement_state = SettlementState {
+            total_settlement_value: collateral_to_be_settled,
+            collateral_available_to_claim,
+            collateral_claimed: 0,
+            enabled: false,
+        };
+
+        Ok(())
+    }
+
+    pub fn update_settlement_state(ctx: Context<UpdateSettlementState>) -> Result<()> {
+        let settlement_state = &mut ctx.accounts.settlement_state;
+
+        let collateral_vault = &ctx.accounts.collateral_vault;
+        let additional_collateral_available_to_claim = collateral_vault
+            .amount
+            .checked_sub(
+                settlement_state
+                    .collateral_available_to_claim
+                    .checked_sub(settlement_state.collateral_claimed)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?;
+
+        settlement_state.collateral_available_to_claim = min(
+            settlement_state
+                .collateral_available_to_claim
+                .checked_add(additional_collateral_available_to_claim)
+                .ok_or_else(math_error!())?,
+            settlement_state.total_settlement_value,
+        );
+
+        Ok(())
+    }
+
+    pub fn update_settlement_state_enabled(
+        ctx: Context<UpdateSettlementStateEnabled>,
+        enabled: bool,
+    ) -> Result<()> {
+        let settlement_state = &mut ctx.accounts.settlement_state;
+        settlement_state.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn settle_position(ctx: Context<SettlePosition>) -> Result<()> {
+        let user = &mut ctx.accounts.user;
+        if user.forgo_position_settlement == 1 {
+            return Err(ErrorCode::UserMustForgoSettlement.into());
+        }
+
+        if !ctx.accounts.settlement_state.enabled {
+            return Err(ErrorCode::SettlementNotEnabled.into());
+        }
+
+        if user.settled_position_value != 0 {
+            return Ok(());
+        }
+
+        let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+        let markets = &ctx.accounts.markets.load()?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+        let collateral_settled = controller::lending::settle_quote_deposit_interest(
+            user,
+            &mut ctx.accounts.state.quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
+        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+        controller::funding::settle_funding_payment(
+            user,
+            user_positions,
+            markets,
+            funding_payment_history,
+            now,
+        )?;
+
+        let settled_position_value =
+            calculated_settled_position_value(user, user_positions, markets)?;
+
+        user.settled_position_value = settled_position_value;
+        user.has_settled_position = 1;
+
+        controller::lending::update_quote_deposits(
+            &mut ctx.accounts.state.quote_lending_pool,
+            collateral_settled,
+            user.collateral,
+        )?;
+
         Ok(())
     }
 
-    pub fn update_partial_liquidation_liquidator_share_denominator(
-        ctx: Context<AdminUpdateState>,
-        denominator: u64,
-    ) -> Result<()> {
-        ctx.accounts
-            .state
-            .partial_liquidation_liquidator_share_denominator = denominator;
+    pub fn claim_collateral(ctx: Context<ClaimCollateral>) -> Result<()> {
+        let user = &mut ctx.accounts.user;
+        let settlement_state = &mut ctx.accounts.settlement_state;
+        if user.forgo_position_settlement == 1 {
+            return Err(ErrorCode::UserMustForgoSettlement.into());
+        }
+
+        if settlement_state.collateral_available_to_claim == user.last_collateral_available_to_claim
+        {
+            return Err(ErrorCode::NoAvailableCollateralToBeClaimed.into());
+        }
+
+        if user.has_settled_position != 1 {
+            return Err(ErrorCode::MustCallSettlePositionFirst.into());
+        }
+
+        if !settlement_state.enabled {
+            return Err(ErrorCode::SettlementNotEnabled.into());
+        }
+
+        let claim_amount = user
+            .settled_position_value
+            .checked_mul(
+                settlement_state
+                    .collateral_available_to_claim
+                    .checked_sub(user.last_collateral_available_to_claim)
+                    .ok_or_else(math_error!())? as u128,
+            )
+            .ok_or_else(math_error!())?
+            .checked_div(settlement_state.total_settlement_value as u128)
+            .ok_or_else(math_error!())? as u64;
+
+        user.last_collateral_available_to_claim = settlement_state.collateral_available_to_claim;
+        user.collateral_claimed = user
+            .collateral_claimed
+            .checked_add(claim_amount)
+            .ok_or_else(math_error!())?;
+        settlement_state.collateral_claimed = settlement_state
+            .collateral_claimed
+            .checked_add(claim_amount)
+            .ok_or_else(math_error!())?;
+
+        controller::token::send(
+            &ctx.accounts.token_program,
+            &ctx.accounts.collateral_vault,
+            &ctx.accounts.user_collateral_account,
+            &ctx.accounts.collateral_vault_authority,
+            ctx.accounts.state.collateral_vault_nonce,
+            claim_amount,
+        )?;
+
         Ok(())
     }
 
-    pub fn update_full_liquidation_liquidator_share_denominator(
-        ctx: Context<AdminUpdateState>,
-        denominator: u64,
+    /// Moves what the protocol's insurance fund shares are worth to the collateral vault. Stakers'
+    /// funds stay in the insurance vault.
+    pub fn transfer_from_insurance_vault_to_collateral_vault(
+        ctx: Context<TransferFromInsuranceVaultToCollateralVault>,
     ) -> Result<()> {
-        ctx.accounts
-            .state
-            .full_liquidation_liquidator_share_denominator = denominator;
-        Ok(())
-    }
+        let amount = controller::insurance_fund::remove_all_protocol_stake(
+            &mut ctx.accounts.state.insurance_fund,
+            ctx.accounts.insurance_vault.amount,
+        )?;
 
-    pub fn update_fee(ctx: Context<AdminUpdateState>, fees: FeeStructure) -> Result<()> {
-        ctx.accounts.state.fee_structure = fees;
-        Ok(())
-    }
+        if amount > 0 {
+            controller::token::send(
+                &ctx.accounts.token_program,
+                &ctx.accounts.insurance_vault,
+                &ctx.accounts.collateral_vault,
+                &ctx.accounts.insurance_vault_authority,
+                ctx.accounts.state.insurance_vault_nonce,
+                amount,
+            )?;
+        }
 
-    pub fn update_order_filler_reward_structure(
-        ctx: Context<AdminUpdateOrderState>,
-        order_filler_reward_structure: OrderFillerRewardStructure,
-    ) -> Result<()> {
-        ctx.accounts.order_state.order_filler_reward_structure = order_filler_reward_structure;
         Ok(())
     }
+}
 
-    pub fn update_oracle_guard_rails(
-        ctx: Context<AdminUpdateState>,
-        oracle_guard_rails: OracleGuardRails,
-    ) -> Result<()> {
-        ctx.accounts.state.oracle_guard_rails = oracle_guard_rails;
-        Ok(())
-    }
+/// Liquidates the user's cross margin positions or, if a market index is passed, only the user's
+/// isolated position in that market
+#[allow(clippy::result_large_err)]
+fn liquidate_user(
+    ctx: Context<Liquidate>,
+    isolated_market_index: Option<u64>,
+    preferred_market_index: Option<u64>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let clock_slot = clock.slot;
+
+    let deposit_history = &mut ctx.accounts.deposit_history.load_mut()?;
+    let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+    let user_collateral_settled = controller::lending::settle_quote_deposit_interest(
+        &mut ctx.accounts.user,
+        quote_lending_pool,
+        deposit_history,
+        now,
+    )?;
+    let liquidator_collateral_settled =
+        controller::lending::settle_quote_deposit_interest_unless_user(
+            &mut ctx.accounts.liquidator,
+            &ctx.accounts.user.key(),
+            quote_lending_pool,
+            deposit_history,
+            now,
+        )?;
 
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index)
-    )]
-    pub fn update_market_oracle(
-        ctx: Context<AdminUpdateMarket>,
-        market_index: u64,
-        oracle: Pubkey,
-        oracle_source: OracleSource,
-    ) -> Result<()> {
-        let market =
-            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        market.amm.oracle = oracle;
-        market.amm.oracle_source = oracle_source;
-        Ok(())
-    }
+    let state = &ctx.accounts.state;
+    let user = &mut ctx.accounts.user;
+    let trade_history = &mut ctx.accounts.trade_history.load_mut()?;
+
+    // Settle user's funding payments so that collateral is up to date
+    let user_positions = &mut UserPositions::load_positions_mut(&ctx.accounts.user_positions)?;
+    let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
+    controller::funding::settle_funding_payment(
+        user,
+        user_positions,
+        &ctx.accounts.markets.load()?,
+        funding_payment_history,
+        now,
+    )?;
+
+    let isolated_position_index = match isolated_market_index {
+        Some(market_index) => Some(
+            user_positions
+                .iter()
+                .position(|market_position| {
+                    market_position.market_index == market_index && market_position.is_isolated()
+                })
+                .ok_or(ErrorCode::PositionNotIsolated)?,
+        ),
+        None => None,
+    };
 
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index)
-    )]
-    pub fn update_market_minimum_quote_asset_trade_size(
-        ctx: Context<AdminUpdateMarket>,
-        market_index: u64,
-        minimum_trade_size: u128,
-    ) -> Result<()> {
-        let market =
-            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        market.amm.minimum_quote_asset_trade_size = minimum_trade_size;
-        Ok(())
-    }
+    let liquidated_market_indexes: Vec<u64> = match isolated_position_index {
+        Some(position_index) => vec![user_positions[position_index].market_index],
+        None => user_positions
+            .iter()
+            .filter(|market_position| {
+                market_position.base_asset_amount != 0 && !market_position.is_isolated()
+            })
+            .map(|market_position| market_position.market_index)
+            .collect(),
+    };
 
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index)
-    )]
-    pub fn update_market_base_spread(
-        ctx: Context<AdminUpdateMarket>,
-        market_index: u64,
-        base_spread: u16,
-    ) -> Result<()> {
-        let market =
-            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        market.amm.base_spread = base_spread;
-        Ok(())
-    }
+    let LiquidationStatus {
+        liquidation_type,
+        total_collateral,
+        adjusted_total_collateral,
+        unrealized_pnl,
+        base_asset_value,
+        mut market_statuses,
+        mut margin_requirement,
+        margin_ratio,
+    } = match isolated_position_index {
+        Some(position_index) => calculate_isolated_liquidation_status(
+            &user_positions[position_index],
+            &ctx.accounts.markets.load()?,
+            ctx.remaining_accounts,
+            &ctx.accounts.state.oracle_guard_rails,
+            clock_slot,
+        )?,
+        None => calculate_liquidation_status(
+            user,
+            user_positions,
+            optional_accounts::get_spot_balances_value(
+                ctx.remaining_accounts,
+                state,
+                user,
+                &user.key(),
+                clock_slot,
+            )?,
+            &ctx.accounts.markets.load()?,
+            ctx.remaining_accounts,
+            &ctx.accounts.state.oracle_guard_rails,
+            clock_slot,
+        )?,
+    };
 
-    #[access_control(
-        market_initialized(&ctx.accounts.markets, market_index)
-    )]
-    pub fn update_market_minimum_base_asset_trade_size(
-        ctx: Context<AdminUpdateMarket>,
-        market_index: u64,
-        minimum_trade_size: u128,
-    ) -> Result<()> {
-        let market =
-            &mut ctx.accounts.markets.load_mut()?.markets[Markets::index_from_u64(market_index)];
-        market.amm.minimum_base_asset_trade_size = minimum_trade_size;
-        Ok(())
+    // Verify that the user is in liquidation territory
+    let collateral = match isolated_position_index {
+        Some(position_index) => user_positions[position_index].isolated_collateral,
+        None => user.collateral,
+    };
+    if liquidation_type == LiquidationType::NONE {
+        msg!("total_collateral {}", total_collateral);
+        msg!("adjusted_total_collateral {}", adjusted_total_collateral);
+        msg!("margin_requirement {}", margin_requirement);
+        return Err(ErrorCode::SufficientCollateral.into());
     }
 
-    pub fn update_admin(ctx: Context<AdminUpdateState>, admin: Pubkey) -> Result<()> {
-        ctx.accounts.state.admin = admin;
-        Ok(())
+    // If the user wasn't flagged, the liquidation starts the auction, so the liquidator gets the
+    // auction's start share
+    let liquidation_start_slot = match isolated_position_index {
+        Some(position_index) => {
+            let market_position = &mut user_positions[position_index];
+            market_position.liquidation_start_slot = calculate_liquidation_start_slot(
+                true,
+                market_position.liquidation_start_slot,
+                clock_slot,
+            );
+            market_position.liquidation_start_slot
+        }
+        None => {
+            user.liquidation_start_slot =
+                calculate_liquidation_start_slot(true, user.liquidation_start_slot, clock_slot);
+            user.liquidation_start_slot
+        }
+    };
+    let slots_since_liquidation_start = clock_slot
+        .checked_sub(liquidation_start_slot)
+        .ok_or_else(math_error!())?;
+
+    // The liquidator can pick the market to liquidate first, otherwise markets are liquidated in
+    // order of their margin requirement
+    if let Some(market_index) = preferred_market_index {
+        let preferred_market_status_index = market_statuses
+            .iter()
+            .position(|market_status| market_status.market_index == market_index)
+            .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+        let preferred_market_status = market_statuses.remove(preferred_market_status_index);
+        market_statuses.insert(0, preferred_market_status);
     }
 
-    pub fn update_whitelist_mint(
-        ctx: Context<AdminUpdateState>,
-        whitelist_mint: Pubkey,
-    ) -> Result<()> {
-        ctx.accounts.state.whitelist_mint = whitelist_mint;
-        Ok(())
-    }
+    let is_dust_position = adjusted_total_collateral <= QUOTE_PRECISION;
+
+    // Keep track to the value of positions closed. For full liquidation this is the user's entire position,
+    // for partial it is less (it's based on the clearing house state)
+    let mut base_asset_value_closed: u128 = 0;
+    let mut liquidation_fee = 0_u128;
+    // The pnl realized by the positions closed and the market index, loss and base asset amount of
+    // each position closed at a loss
+    let mut realized_pnl = 0_i128;
+    let mut position_losses: Vec<(u64, u128, i128)> = vec![];
+    // The market index, base asset value closed and liquidation fee of each market liquidated
+    let mut liquidation_steps: Vec<(u64, u128, u128)> = vec![];
+    let mut margin_restored = false;
+    // The sum of each market's liquidation fee times its liquidator share
+    let mut liquidator_share_weighted_fee = 0_u128;
+    // have to fully liquidate dust positions to make it worth it for liquidators
+    let is_full_liquidation = liquidation_type == LiquidationType::FULL || is_dust_position;
+    if is_full_liquidation {
+        let markets = &mut ctx.accounts.markets.load_mut()?;
 
-    pub fn update_discount_mint(
-        ctx: Context<AdminUpdateState>,
-        discount_mint: Pubkey,
-    ) -> Result<()> {
-        ctx.accounts.state.discount_mint = discount_mint;
-        Ok(())
-    }
+        for market_status in market_statuses.iter() {
+            if market_status.base_asset_value == 0 {
+                continue;
+            }
 
-    pub fn update_max_deposit(ctx: Context<AdminUpdateState>, max_deposit: u128) -> Result<()> {
-        ctx.accounts.state.max_deposit = max_deposit;
-        Ok(())
-    }
+            let market = markets.get_market_mut(market_status.market_index);
+            let mark_price_before = market_status.mark_price_before;
+            let oracle_status = &market_status.oracle_status;
+
+            // if the oracle is invalid and the mark moves too far from twap, dont liquidate
+            let oracle_is_valid = oracle_status.is_valid;
+            if !oracle_is_valid {
+                let mark_twap_divergence =
+                    calculate_mark_twap_spread_pct(&market.amm, mark_price_before)?;
+                let mark_twap_too_divergent =
+                    mark_twap_divergence.unsigned_abs() >= MAX_MARK_TWAP_DIVERGENCE;
+
+                if mark_twap_too_divergent {
+                    let market_index = market_status.market_index;
+                    msg!(
+                        "mark_twap_divergence {} for market {}",
+                        mark_twap_divergence,
+                        market_index
+                    );
+                    continue;
+                }
+            }
 
-    pub fn update_exchange_paused(
-        ctx: Context<AdminUpdateState>,
-        exchange_paused: bool,
-    ) -> Result<()> {
-        ctx.accounts.state.exchange_paused = exchange_paused;
-        Ok(())
-    }
+            let market_position = &mut user_positions
+                .iter_mut()
+                .find(|position| position.market_index == market_status.market_index)
+                .unwrap();
+
+            let mark_price_before_i128 = cast_to_i128(mark_price_before)?;
+            let close_position_slippage = match market_status.close_position_slippage {
+                Some(close_position_slippage) => close_position_slippage,
+                None => calculate_slippage(
+                    market_status.base_asset_value,
+                    market_position.base_asset_amount.unsigned_abs(),
+                    mark_price_before_i128,
+                )?,
+            };
+            let close_position_slippage_pct =
+                calculate_slippage_pct(close_position_slippage, mark_price_before_i128)?;
+
+            let close_slippage_pct_too_large = close_position_slippage_pct
+                > MAX_LIQUIDATION_SLIPPAGE
+                || close_position_slippage_pct < -MAX_LIQUIDATION_SLIPPAGE;
+
+            let oracle_mark_divergence_after_close = if !close_slippage_pct_too_large {
+                oracle_status
+                    .oracle_mark_spread_pct
+                    .checked_add(close_position_slippage_pct)
+                    .ok_or_else(math_error!())?
+            } else if close_position_slippage_pct > 0 {
+                oracle_status
+                    .oracle_mark_spread_pct
+                    // approximates price impact based on slippage
+                    .checked_add(MAX_LIQUIDATION_SLIPPAGE * 2)
+                    .ok_or_else(math_error!())?
+            } else {
+                oracle_status
+                    .oracle_mark_spread_pct
+                    // approximates price impact based on slippage
+                    .checked_sub(MAX_LIQUIDATION_SLIPPAGE * 2)
+                    .ok_or_else(math_error!())?
+            };
 
-    pub fn disable_admin_controls_prices(ctx: Context<AdminUpdateState>) -> Result<()> {
-        ctx.accounts.state.admin_controls_prices = false;
-        Ok(())
-    }
+            let oracle_mark_too_divergent_after_close = is_oracle_mark_too_divergent(
+                oracle_mark_divergence_after_close,
+                &state.oracle_guard_rails.price_divergence,
+            )?;
 
-    pub fn update_funding_paused(
-        ctx: Context<AdminUpdateState>,
-        funding_paused: bool,
-    ) -> Result<()> {
-        ctx.accounts.state.funding_paused = funding_paused;
-        Ok(())
-    }
+            // if closing pushes outside the oracle mark threshold, don't liquidate
+            if oracle_is_valid && oracle_mark_too_divergent_after_close {
+                // but only skip the liquidation if it makes the divergence worse
+                if oracle_status.oracle_mark_spread_pct.unsigned_abs()
+                    < oracle_mark_divergence_after_close.unsigned_abs()
+                {
+                    let market_index = market_position.market_index;
+                    msg!(
+                        "oracle_mark_divergence_after_close {} for market {}",
+                        oracle_mark_divergence_after_close,
+                        market_index,
+                    );
+                    continue;
+                }
+            }
 
-    pub fn admin_update_user_forgo_settlement(
-        ctx: Context<AdminUpdateUserForgoSettlement>,
-    ) -> Result<()> {
-        ctx.accounts.user.forgo_position_settlement = 1;
-        Ok(())
-    }
+            let direction_to_close =
+                math::position::direction_to_close_position(market_position.base_asset_amount);
+
+            let base_asset_amount_before = market_position.base_asset_amount;
+            let entry_value_before = market_position.quote_asset_amount;
+
+            // just reduce position if position is too big
+            let (quote_asset_amount, base_asset_amount) = if close_slippage_pct_too_large {
+                let quote_asset_amount = market_status
+                    .base_asset_value
+                    .checked_mul(MAX_LIQUIDATION_SLIPPAGE_U128)
+                    .ok_or_else(math_error!())?
+                    .checked_div(close_position_slippage_pct.unsigned_abs())
+                    .ok_or_else(math_error!())?;
+
+                let (base_asset_amount, _) = controller::position::reduce(
+                    direction_to_close,
+                    quote_asset_amount,
+                    user,
+                    market,
+                    market_position,
+                    now,
+                    Some(mark_price_before),
+                    false,
+                )?;
+
+                (quote_asset_amount, base_asset_amount)
+            } else {
+                let (quote_asset_amount, base_asset_amount, _) = controller::position::close(
+                    user,
+                    market,
+                    market_position,
+                    now,
+                    None,
+                    Some(mark_price_before),
+                    false,
+                )?;
+
+                (quote_asset_amount, base_asset_amount)
+            };
+            controller::amm::update_market_spreads(
+                market,
+                if oracle_status.is_valid {
+                    Some(&oracle_status.price_data)
+                } else {
+                    None
+                },
+            )?;
+
+            let pnl = calculate_liquidated_position_pnl(
+                base_asset_amount_before,
+                entry_value_before
+                    .checked_sub(market_position.quote_asset_amount)
+                    .ok_or_else(math_error!())?,
+                quote_asset_amount,
+            )?;
+            realized_pnl = realized_pnl.checked_add(pnl).ok_or_else(math_error!())?;
+            if pnl < 0 {
+                position_losses.push((
+                    market_status.market_index,
+                    pnl.unsigned_abs(),
+                    base_asset_amount_before,
+                ));
+            }
+
+            let base_asset_amount = base_asset_amount.unsigned_abs();
+            base_asset_value_closed = base_asset_value_closed
+                .checked_add(quote_asset_amount)
+                .ok_or_else(math_error!())?;
+            let mark_price_after = market.amm.mark_price()?;
+
+            let record_id = trade_history.next_record_id();
+            trade_history.append(ExtendedTradeRecord {
+                ts: now,
+                record_id,
+                user_authority: user.authority,
+                user: *user.to_account_info().key,
+                direction: direction_to_close,
+                base_asset_amount,
+                quote_asset_amount,
+                mark_price_before,
+                mark_price_after,
+                fee: 0,
+                token_discount: 0,
+                quote_asset_amount_surplus: 0,
+                referee_discount: 0,
+                liquidation: true,
+                market_index: market_position.market_index,
+                oracle_price: market_status.oracle_status.price_data.price,
+                maker: Pubkey::default(),
+                maker_fee: 0,
+                spread: 0,
+                padding: [0; 4],
+            });
+
+            margin_requirement = margin_requirement
+                .checked_sub(
+                    market_status
+                        .maintenance_margin_requirement
+                        .checked_mul(quote_asset_amount)
+                        .ok_or_else(math_error!())?
+                        .checked_div(market_status.base_asset_value)
+                        .ok_or_else(math_error!())?,
+                )
+                .ok_or_else(math_error!())?;
+
+            // The market's penalty is charged on its share of the total position value
+            let market_liquidation_fee = total_collateral
+                .checked_mul(market.full_liquidation_penalty.into())
+                .ok_or_else(math_error!())?
+                .checked_div(MARGIN_PRECISION)
+                .ok_or_else(math_error!())?
+                .checked_mul(quote_asset_amount)
+                .ok_or_else(math_error!())?
+                .checked_div(base_asset_value)
+                .ok_or_else(math_error!())?;
+
+            liquidation_fee = liquidation_fee
+                .checked_add(market_liquidation_fee)
+                .ok_or_else(math_error!())?;
+            liquidator_share_weighted_fee = liquidator_share_weighted_fee
+                .checked_add(
+                    market_liquidation_fee
+                        .checked_mul(market.full_liquidation_liquidator_share.into())
+                        .ok_or_else(math_error!())?,
+                )
+                .ok_or_else(math_error!())?;
+            liquidation_steps.push((
+                market_status.market_index,
+                quote_asset_amount,
+                market_liquidation_fee,
+            ));
+
+            if liquidation_steps.len() == MAX_POSITIONS_LIQUIDATED_PER_INSTRUCTION {
+                break;
+            }
+        }
+    } else {
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+
+        let maximum_base_asset_value_closed = base_asset_value
+            .checked_mul(state.partial_liquidation_close_percentage_numerator)
+            .ok_or_else(math_error!())?
+            .checked_div(state.partial_liquidation_close_percentage_denominator)
+            .ok_or_else(math_error!())?;
+        for market_status in market_statuses.iter() {
+            if market_status.base_asset_value == 0 {
+                continue;
+            }
+
+            let oracle_status = &market_status.oracle_status;
+            let market = markets.get_market_mut(market_status.market_index);
+            let mark_price_before = market_status.mark_price_before;
+
+            let oracle_is_valid = oracle_status.is_valid;
+            if !oracle_is_valid {
+                let mark_twap_divergence =
+                    calculate_mark_twap_spread_pct(&market.amm, mark_price_before)?;
+                let mark_twap_too_divergent =
+                    mark_twap_divergence.unsigned_abs() >= MAX_MARK_TWAP_DIVERGENCE;
+
+                if mark_twap_too_divergent {
+                    let market_index = market_status.market_index;
+                    msg!(
+                        "mark_twap_divergence {} for market {}",
+                        mark_twap_divergence,
+                        market_index
+                    );
+                    continue;
+                }
+            }
+
+            let market_position = &mut user_positions
+                .iter_mut()
+                .find(|position| position.market_index == market_status.market_index)
+                .unwrap();
+
+            let maximum_liquidation_fee = total_collateral
+                .checked_mul(market.partial_liquidation_penalty.into())
+                .ok_or_else(math_error!())?
+                .checked_div(MARGIN_PRECISION)
+                .ok_or_else(math_error!())?;
+
+            // Close just enough of the position to bring the account back above the partial margin
+            // requirement, but no more than the partial liquidation close percentage of it
+            let mut quote_asset_amount = min(
+                math::liquidation::calculate_partial_liquidation_close_amount(
+                    margin_requirement,
+                    adjusted_total_collateral
+                        .checked_sub(liquidation_fee)
+                        .ok_or_else(math_error!())?,
+                    market_status.partial_margin_requirement,
+                    market_status.base_asset_value,
+                    maximum_liquidation_fee,
+                    maximum_base_asset_value_closed,
+                )?,
+                market_status
+                    .base_asset_value
+                    .checked_mul(state.partial_liquidation_close_percentage_numerator)
+                    .ok_or_else(math_error!())?
+                    .checked_div(state.partial_liquidation_close_percentage_denominator)
+                    .ok_or_else(math_error!())?,
+            );
+
+            let mark_price_before_i128 = cast_to_i128(mark_price_before)?;
+            let close_position_slippage = match market_status.close_position_slippage {
+                Some(close_position_slippage) => close_position_slippage,
+                None => calculate_slippage(
+                    market_status.base_asset_value,
+                    market_position.base_asset_amount.unsigned_abs(),
+                    mark_price_before_i128,
+                )?,
+            };
+            // approximates the slippage of the reduce as its share of the slippage to close
+            let reduce_position_slippage = close_position_slippage
+                .checked_mul(cast_to_i128(quote_asset_amount)?)
+                .ok_or_else(math_error!())?
+                .checked_div(cast_to_i128(market_status.base_asset_value)?)
+                .ok_or_else(math_error!())?;
 
-    pub fn update_user_forgo_settlement(ctx: Context<UpdateUserForgoSettlement>) -> Result<()> {
-        ctx.accounts.user.forgo_position_settlement = 1;
-        Ok(())
-    }
+            let reduce_position_slippage_pct =
+                calculate_slippage_pct(reduce_position_slippage, mark_price_before_i128)?;
 
-    pub fn initialize_settlement_state(
-        ctx: Context<InitializeSettlementState>,
-        collateral_to_be_settled: u64,
-    ) -> Result<()> {
-        let collateral_available_to_claim = ctx.accounts.collateral_vault.amount;
+            msg!(
+                "reduce_position_slippage_pct {}",
+                reduce_position_slippage_pct
+            );
 
-        **ctx.accounts.settlement_state = SettlementState {
-            total_settlement_value: collateral_to_be_settled,
-            collateral_available_to_claim,
-            collateral_claimed: 0,
-            enabled: false,
-        };
+            let reduce_slippage_pct_too_large = reduce_position_slippage_pct
+                > MAX_LIQUIDATION_SLIPPAGE
+                || reduce_position_slippage_pct < -MAX_LIQUIDATION_SLIPPAGE;
 
-        Ok(())
-    }
+            let oracle_mark_divergence_after_reduce = if !reduce_slippage_pct_too_large {
+                oracle_status
+                    .oracle_mark_spread_pct
+                    .checked_add(reduce_position_slippage_pct)
+                    .ok_or_else(math_error!())?
+            } else if reduce_position_slippage_pct > 0 {
+                oracle_status
+                    .oracle_mark_spread_pct
+                    // approximates price impact based on slippage
+                    .checked_add(MAX_LIQUIDATION_SLIPPAGE * 2)
+                    .ok_or_else(math_error!())?
+            } else {
+                oracle_status
+                    .oracle_mark_spread_pct
+                    // approximates price impact based on slippage
+                    .checked_sub(MAX_LIQUIDATION_SLIPPAGE * 2)
+                    .ok_or_else(math_error!())?
+            };
 
-    pub fn update_settlement_state(ctx: Context<UpdateSettlementState>) -> Result<()> {
-        let settlement_state = &mut ctx.accounts.settlement_state;
+            let oracle_mark_too_divergent_after_reduce = is_oracle_mark_too_divergent(
+                oracle_mark_divergence_after_reduce,
+                &state.oracle_guard_rails.price_divergence,
+            )?;
 
-        let collateral_vault = &ctx.accounts.collateral_vault;
-        let additional_collateral_available_to_claim = collateral_vault
-            .amount
-            .checked_sub(
-                settlement_state
-                    .collateral_available_to_claim
-                    .checked_sub(settlement_state.collateral_claimed)
-                    .ok_or_else(math_error!())?,
-            )
-            .ok_or_else(math_error!())?;
+            // if reducing pushes outside the oracle mark threshold, don't liquidate
+            if oracle_is_valid && oracle_mark_too_divergent_after_reduce {
+                // but only skip the liquidation if it makes the divergence worse
+                if oracle_status.oracle_mark_spread_pct.unsigned_abs()
+                    < oracle_mark_divergence_after_reduce.unsigned_abs()
+                {
+                    msg!(
+                        "oracle_mark_spread_pct_after_reduce {}",
+                        oracle_mark_divergence_after_reduce
+                    );
+                    return Err(ErrorCode::OracleMarkSpreadLimit.into());
+                }
+            }
 
-        settlement_state.collateral_available_to_claim = min(
-            settlement_state
-                .collateral_available_to_claim
-                .checked_add(additional_collateral_available_to_claim)
-                .ok_or_else(math_error!())?,
-            settlement_state.total_settlement_value,
-        );
+            if reduce_slippage_pct_too_large {
+                quote_asset_amount = quote_asset_amount
+                    .checked_mul(MAX_LIQUIDATION_SLIPPAGE_U128)
+                    .ok_or_else(math_error!())?
+                    .checked_div(reduce_position_slippage_pct.unsigned_abs())
+                    .ok_or_else(math_error!())?;
+            }
 
-        Ok(())
-    }
+            base_asset_value_closed = base_asset_value_closed
+                .checked_add(quote_asset_amount)
+                .ok_or_else(math_error!())?;
 
-    pub fn update_settlement_state_enabled(
-        ctx: Context<UpdateSettlementStateEnabled>,
-        enabled: bool,
-    ) -> Result<()> {
-        let settlement_state = &mut ctx.accounts.settlement_state;
-        settlement_state.enabled = enabled;
-        Ok(())
-    }
+            let direction_to_reduce =
+                math::position::direction_to_close_position(market_position.base_asset_amount);
 
-    pub fn settle_position(ctx: Context<SettlePosition>) -> Result<()> {
-        let user = &mut ctx.accounts.user;
-        if user.forgo_position_settlement == 1 {
-            return Err(ErrorCode::UserMustForgoSettlement.into());
-        }
+            let base_asset_amount_before = market_position.base_asset_amount;
+            let entry_value_before = market_position.quote_asset_amount;
 
-        if !ctx.accounts.settlement_state.enabled {
-            return Err(ErrorCode::SettlementNotEnabled.into());
-        }
+            let (base_asset_amount, _) = controller::position::reduce(
+                direction_to_reduce,
+                quote_asset_amount,
+                user,
+                market,
+                market_position,
+                now,
+                Some(mark_price_before),
+                false,
+            )?;
+            let base_asset_amount = base_asset_amount.unsigned_abs();
+            controller::amm::update_market_spreads(
+                market,
+                if oracle_status.is_valid {
+                    Some(&oracle_status.price_data)
+                } else {
+                    None
+                },
+            )?;
 
-        if user.settled_position_value != 0 {
-            return Ok(());
-        }
+            let pnl = calculate_liquidated_position_pnl(
+                base_asset_amount_before,
+                entry_value_before
+                    .checked_sub(market_position.quote_asset_amount)
+                    .ok_or_else(math_error!())?,
+                quote_asset_amount,
+            )?;
+            realized_pnl = realized_pnl.checked_add(pnl).ok_or_else(math_error!())?;
+            if pnl < 0 {
+                position_losses.push((
+                    market_status.market_index,
+                    pnl.unsigned_abs(),
+                    base_asset_amount_before,
+                ));
+            }
 
-        let user_positions = &mut ctx.accounts.user_positions.load_mut()?;
-        let markets = &ctx.accounts.markets.load()?;
+            let mark_price_after = market.amm.mark_price()?;
 
-        let funding_payment_history = &mut ctx.accounts.funding_payment_history.load_mut()?;
-        controller::funding::settle_funding_payment(
-            user,
-            user_positions,
-            markets,
-            funding_payment_history,
-            Clock::get()?.unix_timestamp,
-        )?;
+            let record_id = trade_history.next_record_id();
+            trade_history.append(ExtendedTradeRecord {
+                ts: now,
+                record_id,
+                user_authority: user.authority,
+                user: *user.to_account_info().key,
+                direction: direction_to_reduce,
+                base_asset_amount,
+                quote_asset_amount,
+                mark_price_before,
+                mark_price_after,
+                fee: 0,
+                token_discount: 0,
+                quote_asset_amount_surplus: 0,
+                referee_discount: 0,
+                liquidation: true,
+                market_index: market_position.market_index,
+                oracle_price: market_status.oracle_status.price_data.price,
+                maker: Pubkey::default(),
+                maker_fee: 0,
+                spread: 0,
+                padding: [0; 4],
+            });
+
+            margin_requirement = margin_requirement
+                .checked_sub(
+                    market_status
+                        .partial_margin_requirement
+                        .checked_mul(quote_asset_amount)
+                        .ok_or_else(math_error!())?
+                        .checked_div(market_status.base_asset_value)
+                        .ok_or_else(math_error!())?,
+                )
+                .ok_or_else(math_error!())?;
 
-        let settled_position_value =
-            calculated_settled_position_value(user, user_positions, markets)?;
+            let market_liquidation_fee = maximum_liquidation_fee
+                .checked_mul(quote_asset_amount)
+                .ok_or_else(math_error!())?
+                .checked_div(maximum_base_asset_value_closed)
+                .ok_or_else(math_error!())?;
 
-        user.settled_position_value = settled_position_value;
-        user.has_settled_position = 1;
+            liquidation_fee = liquidation_fee
+                .checked_add(market_liquidation_fee)
+                .ok_or_else(math_error!())?;
+            liquidator_share_weighted_fee = liquidator_share_weighted_fee
+                .checked_add(
+                    market_liquidation_fee
+                        .checked_mul(market.partial_liquidation_liquidator_share.into())
+                        .ok_or_else(math_error!())?,
+                )
+                .ok_or_else(math_error!())?;
+            liquidation_steps.push((
+                market_status.market_index,
+                quote_asset_amount,
+                market_liquidation_fee,
+            ));
 
-        Ok(())
-    }
+            let adjusted_total_collateral_after_fee = adjusted_total_collateral
+                .checked_sub(liquidation_fee)
+                .ok_or_else(math_error!())?;
 
-    pub fn claim_collateral(ctx: Context<ClaimCollateral>) -> Result<()> {
-        let user = &mut ctx.accounts.user;
-        let settlement_state = &mut ctx.accounts.settlement_state;
-        if user.forgo_position_settlement == 1 {
-            return Err(ErrorCode::UserMustForgoSettlement.into());
-        }
+            if margin_requirement < adjusted_total_collateral_after_fee {
+                margin_restored = true;
+                break;
+            }
 
-        if settlement_state.collateral_available_to_claim == user.last_collateral_available_to_claim
-        {
-            return Err(ErrorCode::NoAvailableCollateralToBeClaimed.into());
+            if liquidation_steps.len() == MAX_POSITIONS_LIQUIDATED_PER_INSTRUCTION {
+                break;
+            }
         }
+    }
 
-        if user.has_settled_position != 1 {
-            return Err(ErrorCode::MustCallSettlePositionFirst.into());
+    if base_asset_value_closed == 0 {
+        return Err(print_error!(ErrorCode::NoPositionsLiquidatable)().into());
+    }
+
+    // Each close floors the collateral at zero, so a loss can't be offset by a profit realized
+    // after it. The collateral is recomputed from the net pnl and any deficit is allocated to the
+    // markets that lost.
+    let bankrupt_loss = calculate_bankrupt_loss(collateral, realized_pnl)?;
+    let collateral_after =
+        math::collateral::calculate_updated_collateral(collateral, realized_pnl)?;
+    match isolated_position_index {
+        Some(position_index) => {
+            user_positions[position_index].isolated_collateral = collateral_after
         }
+        None => user.collateral = collateral_after,
+    }
+    let bankruptcies = allocate_bankrupt_loss(bankrupt_loss, &position_losses)?;
 
-        if !settlement_state.enabled {
-            return Err(ErrorCode::SettlementNotEnabled.into());
+    // Collateral assets count towards the total collateral the fee is based on, but the fee can only
+    // be paid from quote collateral. A bankrupt position has no collateral left to pay it.
+    let uncapped_liquidation_fee = liquidation_fee;
+    liquidation_fee = match isolated_position_index {
+        Some(position_index) => min(
+            liquidation_fee,
+            user_positions[position_index].isolated_collateral,
+        ),
+        None => min(liquidation_fee, user.collateral),
+    };
+
+    let (withdrawal_amount, _) = calculate_withdrawal_amounts(
+        cast(liquidation_fee)?,
+        &ctx.accounts.collateral_vault,
+        &ctx.accounts.insurance_vault,
+    )?;
+
+    // Isolated positions only pay the liquidation fee from their own collateral
+    match isolated_position_index {
+        Some(position_index) => {
+            let market_position = &mut user_positions[position_index];
+            market_position.isolated_collateral = market_position
+                .isolated_collateral
+                .checked_sub(liquidation_fee)
+                .ok_or_else(math_error!())?;
+            controller::position::release_isolated_collateral(user, market_position)?;
+        }
+        None => {
+            user.collateral = user
+                .collateral
+                .checked_sub(liquidation_fee)
+                .ok_or_else(math_error!())?;
         }
+    }
 
-        let claim_amount = user
-            .settled_position_value
-            .checked_mul(
-                settlement_state
-                    .collateral_available_to_claim
-                    .checked_sub(user.last_collateral_available_to_claim)
-                    .ok_or_else(math_error!())? as u128,
-            )
-            .ok_or_else(math_error!())?
-            .checked_div(settlement_state.total_settlement_value as u128)
-            .ok_or_else(math_error!())? as u64;
+    // Each market pays the liquidator its own share of the fee it charged
+    let max_fee_to_liquidator: u64 = if uncapped_liquidation_fee == 0 {
+        0
+    } else {
+        cast(
+            cast_to_u128(withdrawal_amount)?
+                .checked_mul(liquidator_share_weighted_fee)
+                .ok_or_else(math_error!())?
+                .checked_div(uncapped_liquidation_fee)
+                .ok_or_else(math_error!())?
+                .checked_div(MARGIN_PRECISION)
+                .ok_or_else(math_error!())?,
+        )?
+    };
 
-        user.last_collateral_available_to_claim = settlement_state.collateral_available_to_claim;
-        user.collateral_claimed = user
-            .collateral_claimed
-            .checked_add(claim_amount)
-            .ok_or_else(math_error!())?;
-        settlement_state.collateral_claimed = settlement_state
-            .collateral_claimed
-            .checked_add(claim_amount)
+    // The liquidator's share grows the longer the user has been liquidatable, the rest goes to the
+    // insurance fund
+    let fee_to_liquidator = math::liquidation::calculate_liquidator_fee_for_auction(
+        max_fee_to_liquidator,
+        &state.liquidation_auction,
+        slots_since_liquidation_start,
+    )?;
+
+    let fee_to_insurance_fund = withdrawal_amount
+        .checked_sub(fee_to_liquidator)
+        .ok_or_else(math_error!())?;
+
+    if fee_to_liquidator > 0 {
+        let liquidator = &mut ctx.accounts.liquidator;
+        liquidator.collateral = liquidator
+            .collateral
+            .checked_add(cast(fee_to_liquidator)?)
             .ok_or_else(math_error!())?;
+    }
 
+    if fee_to_insurance_fund > 0 {
         controller::token::send(
             &ctx.accounts.token_program,
             &ctx.accounts.collateral_vault,
-            &ctx.accounts.user_collateral_account,
+            &ctx.accounts.insurance_vault,
             &ctx.accounts.collateral_vault_authority,
             ctx.accounts.state.collateral_vault_nonce,
-            claim_amount,
+            fee_to_insurance_fund,
         )?;
+    }
 
-        Ok(())
+    if !bankruptcies.is_empty() {
+        let markets = &mut ctx.accounts.markets.load_mut()?;
+        let bankruptcy_history = &mut ctx.accounts.bankruptcy_history.load_mut()?;
+        ctx.accounts.insurance_vault.reload()?;
+        let mut insurance_vault_amount = ctx.accounts.insurance_vault.amount;
+
+        for (market_index, bankrupt_loss, base_asset_amount) in bankruptcies {
+            let resolution = controller::bankruptcy::resolve_bankruptcy(
+                markets.get_market_mut(market_index),
+                bankrupt_loss,
+                base_asset_amount,
+                insurance_vault_amount,
+            )?;
+
+            if resolution.loss_covered_by_insurance_fund > 0 {
+                controller::token::send(
+                    &ctx.accounts.token_program,
+                    &ctx.accounts.insurance_vault,
+                    &ctx.accounts.collateral_vault,
+                    &ctx.accounts.insurance_vault_authority,
+                    state.insurance_vault_nonce,
+                    resolution.loss_covered_by_insurance_fund,
+                )?;
+                insurance_vault_amount = insurance_vault_amount
+                    .checked_sub(resolution.loss_covered_by_insurance_fund)
+                    .ok_or_else(math_error!())?;
+            }
+
+            let record_id = bankruptcy_history.next_record_id();
+            bankruptcy_history.append(BankruptcyRecord {
+                ts: now,
+                record_id,
+                user_authority: user.authority,
+                user: user.key(),
+                liquidator: ctx.accounts.liquidator.key(),
+                market_index,
+                bankrupt_loss,
+                loss_covered_by_fee_pool: resolution.loss_covered_by_fee_pool,
+                loss_covered_by_insurance_fund: resolution.loss_covered_by_insurance_fund,
+                loss_socialized: resolution.loss_socialized,
+                cumulative_funding_rate_long_delta: resolution.cumulative_funding_rate_long_delta,
+                cumulative_funding_rate_short_delta: resolution.cumulative_funding_rate_short_delta,
+                uncovered_loss: resolution.uncovered_loss,
+            });
+        }
     }
 
-    pub fn transfer_from_insurance_vault_to_collateral_vault(
-        ctx: Context<TransferFromInsuranceVaultToCollateralVault>,
-    ) -> Result<()> {
-        controller::token::send(
-            &ctx.accounts.token_program,
-            &ctx.accounts.insurance_vault,
-            &ctx.accounts.collateral_vault,
-            &ctx.accounts.insurance_vault_authority,
-            ctx.accounts.state.insurance_vault_nonce,
-            ctx.accounts.insurance_vault.amount,
+    // The next time the user is liquidatable starts a new auction
+    match isolated_position_index {
+        Some(position_index) => {
+            let market_position = &mut user_positions[position_index];
+            if margin_restored || market_position.base_asset_amount == 0 {
+                market_position.liquidation_start_slot = 0;
+            }
+        }
+        None => {
+            if margin_restored
+                || user_positions.iter().all(|market_position| {
+                    market_position.base_asset_amount == 0 || market_position.is_isolated()
+                })
+            {
+                user.liquidation_start_slot = 0;
+            }
+        }
+    }
+
+    // Each market liquidated gets its own record, with the fees split by the fee each market charged
+    let liquidation_history = &mut ctx.accounts.liquidation_history.load_mut()?;
+    let mut liquidation_fee_recorded = 0_u128;
+    let mut fee_to_liquidator_recorded = 0_u64;
+    let mut fee_to_insurance_fund_recorded = 0_u64;
+    let last_step_index = liquidation_steps.len() - 1;
+    for (step_index, (market_index, market_base_asset_value_closed, market_liquidation_fee)) in
+        liquidation_steps.into_iter().enumerate()
+    {
+        let (market_fee, market_fee_to_liquidator, market_fee_to_insurance_fund) =
+            if step_index == last_step_index {
+                (
+                    liquidation_fee
+                        .checked_sub(liquidation_fee_recorded)
+                        .ok_or_else(math_error!())?,
+                    fee_to_liquidator
+                        .checked_sub(fee_to_liquidator_recorded)
+                        .ok_or_else(math_error!())?,
+                    fee_to_insurance_fund
+                        .checked_sub(fee_to_insurance_fund_recorded)
+                        .ok_or_else(math_error!())?,
+                )
+            } else if uncapped_liquidation_fee == 0 {
+                (0, 0, 0)
+            } else {
+                (
+                    liquidation_fee
+                        .checked_mul(market_liquidation_fee)
+                        .ok_or_else(math_error!())?
+                        .checked_div(uncapped_liquidation_fee)
+                        .ok_or_else(math_error!())?,
+                    cast(
+                        cast_to_u128(fee_to_liquidator)?
+                            .checked_mul(market_liquidation_fee)
+                            .ok_or_else(math_error!())?
+                            .checked_div(uncapped_liquidation_fee)
+                            .ok_or_else(math_error!())?,
+                    )?,
+                    cast(
+                        cast_to_u128(fee_to_insurance_fund)?
+                            .checked_mul(market_liquidation_fee)
+                            .ok_or_else(math_error!())?
+                            .checked_div(uncapped_liquidation_fee)
+                            .ok_or_else(math_error!())?,
+                    )?,
+                )
+            };
+        liquidation_fee_recorded = liquidation_fee_recorded
+            .checked_add(market_fee)
+            .ok_or_else(math_error!())?;
+        fee_to_liquidator_recorded = fee_to_liquidator_recorded
+            .checked_add(market_fee_to_liquidator)
+            .ok_or_else(math_error!())?;
+        fee_to_insurance_fund_recorded = fee_to_insurance_fund_recorded
+            .checked_add(market_fee_to_insurance_fund)
+            .ok_or_else(math_error!())?;
+
+        let record_id = liquidation_history.next_record_id();
+        liquidation_history.append(ExtendedLiquidationRecord {
+            ts: now,
+            record_id,
+            user: user.to_account_info().key(),
+            user_authority: user.authority,
+            partial: !is_full_liquidation,
+            base_asset_value,
+            base_asset_value_closed: market_base_asset_value_closed,
+            liquidation_fee: market_fee,
+            fee_to_liquidator: market_fee_to_liquidator,
+            fee_to_insurance_fund: market_fee_to_insurance_fund,
+            liquidator: ctx.accounts.liquidator.to_account_info().key(),
+            total_collateral,
+            collateral,
+            unrealized_pnl,
+            margin_ratio,
+            market_index,
+            base_asset_amount_transferred: 0,
+            transfer_price: 0,
+            padding: [0; 5],
+        });
+    }
+
+    // Linked orders are canceled for every position the liquidation closed
+    let user_orders_accounts = optional_accounts::find_user_orders_and_order_history(
+        ctx.remaining_accounts,
+        state,
+        &user.key(),
+    )?;
+    for market_index in liquidated_market_indexes {
+        controller::orders::cancel_linked_orders_for_closed_position(
+            user,
+            user_positions,
+            user_orders_accounts
+                .as_ref()
+                .map(|(user_orders, order_history)| (user_orders, order_history)),
+            market_index,
+            now,
         )?;
+    }
 
-        Ok(())
+    let quote_lending_pool = &mut ctx.accounts.state.quote_lending_pool;
+    controller::lending::update_quote_deposits(
+        quote_lending_pool,
+        user_collateral_settled,
+        ctx.accounts.user.collateral,
+    )?;
+    if let Some(liquidator_collateral_settled) = liquidator_collateral_settled {
+        controller::lending::update_quote_deposits(
+            quote_lending_pool,
+            liquidator_collateral_settled,
+            ctx.accounts.liquidator.collateral,
+        )?;
     }
+
+    Ok(())
 }
 
 fn market_initialized(markets: &AccountLoader<Markets>, market_index: u64) -> Result<()> {
@@ -2696,3 +6176,64 @@ fn admin_controls_prices(state: &Account<State>) -> Result<()> {
     }
     Ok(())
 }
+
+fn calculate_liquidation_start_slot(
+    is_liquidatable: bool,
+    liquidation_start_slot: u64,
+    clock_slot: u64,
+) -> u64 {
+    if !is_liquidatable {
+        0
+    } else if liquidation_start_slot == 0 {
+        clock_slot
+    } else {
+        liquidation_start_slot
+    }
+}
+
+/// Ends the user's liquidation auctions once they're back above the partial margin requirement, so
+/// the next time they're liquidatable starts a new auction instead of resuming the old one. The
+/// cross margin auction is left alone if the user's spot balances value isn't known.
+fn reset_liquidation_start_slots(
+    user: &mut User,
+    user_positions: &mut [MarketPosition],
+    spot_balances_value: Option<i128>,
+    markets: &Ref<Markets>,
+) -> ClearingHouseResult {
+    if let Some(spot_balances_value) = spot_balances_value {
+        if user.liquidation_start_slot != 0
+            && meets_partial_margin_requirement(user, user_positions, spot_balances_value, markets)?
+        {
+            user.liquidation_start_slot = 0;
+        }
+    }
+
+    for market_position in user_positions.iter_mut() {
+        if !market_position.is_isolated() || market_position.liquidation_start_slot == 0 {
+            continue;
+        }
+
+        let (partial_margin_requirement, total_collateral) =
+            calculate_isolated_margin_requirement_and_total_collateral(
+                market_position,
+                markets,
+                MarginType::Partial,
+            )?;
+        let partial_margin_requirement = partial_margin_requirement
+            .checked_div(MARGIN_PRECISION)
+            .ok_or_else(math_error!())?;
+        if total_collateral >= partial_margin_requirement {
+            market_position.liquidation_start_slot = 0;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_position_collateral(user: &User, market_position: &MarketPosition) -> u128 {
+    if market_position.is_isolated() {
+        market_position.isolated_collateral
+    } else {
+        user.collateral
+    }
+}
@@ -1,5 +1,8 @@
 use crate::error::{ClearingHouseResult, ErrorCode};
-use crate::math::constants::{MAXIMUM_MARGIN_RATIO, MINIMUM_MARGIN_RATIO};
+use crate::math::constants::{
+    MARGIN_PRECISION, MAXIMUM_MARGIN_RATIO, MAX_IMF_FACTOR, MINIMUM_MARGIN_RATIO,
+    UTILIZATION_PRECISION,
+};
 
 pub fn validate_margin(
     margin_ratio_initial: u32,
@@ -28,3 +31,55 @@ pub fn validate_margin(
 
     Ok(())
 }
+
+pub fn validate_imf_factor(imf_factor: u32) -> ClearingHouseResult {
+    if imf_factor > MAX_IMF_FACTOR {
+        return Err(ErrorCode::InvalidImfFactor);
+    }
+
+    Ok(())
+}
+
+pub fn validate_liquidation_fees(
+    partial_liquidation_fee: u32,
+    full_liquidation_fee: u32,
+) -> ClearingHouseResult {
+    if u128::from(partial_liquidation_fee) > MARGIN_PRECISION
+        || u128::from(full_liquidation_fee) > MARGIN_PRECISION
+    {
+        return Err(ErrorCode::InvalidLiquidationFee);
+    }
+
+    Ok(())
+}
+
+pub fn validate_collateral_asset_weights(
+    asset_weight: u128,
+    liquidation_discount: u128,
+) -> ClearingHouseResult {
+    if asset_weight == 0 || asset_weight > MARGIN_PRECISION {
+        return Err(ErrorCode::InvalidCollateralAsset);
+    }
+
+    if liquidation_discount >= MARGIN_PRECISION {
+        return Err(ErrorCode::InvalidCollateralAsset);
+    }
+
+    Ok(())
+}
+
+pub fn validate_interest_rate_curve(
+    optimal_utilization: u128,
+    optimal_borrow_rate: u128,
+    max_borrow_rate: u128,
+) -> ClearingHouseResult {
+    if optimal_utilization == 0 || optimal_utilization >= UTILIZATION_PRECISION {
+        return Err(ErrorCode::InvalidInterestRateCurve);
+    }
+
+    if optimal_borrow_rate > max_borrow_rate {
+        return Err(ErrorCode::InvalidInterestRateCurve);
+    }
+
+    Ok(())
+}
@@ -6,35 +6,18 @@ use crate::controller::amm::SwapDirection;
 use crate::controller::position::PositionDirection;
 use crate::error::{ClearingHouseResult, ErrorCode};
 use crate::math::bn;
-use crate::math::bn::U192;
 use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
 use crate::math::constants::{
-    BID_ASK_SPREAD_PRECISION, MARK_PRICE_PRECISION, PEG_PRECISION, PRICE_SPREAD_PRECISION,
-    PRICE_SPREAD_PRECISION_U128, PRICE_TO_PEG_PRECISION_RATIO,
+    BID_ASK_SPREAD_PRECISION, MARK_PRICE_PRECISION, MAX_INVENTORY_SPREAD_SCALE,
+    PRICE_SPREAD_PRECISION, PRICE_SPREAD_PRECISION_U128,
 };
+use crate::math::curve::Curve;
 use crate::math::position::_calculate_base_asset_value_and_pnl;
 use crate::math::quote_asset::{asset_to_reserve_amount, reserve_to_asset_amount};
 use crate::math_error;
 use crate::state::market::{Market, OraclePriceData, AMM};
 use crate::state::state::{PriceDivergenceGuardRails, ValidityGuardRails};
 
-pub fn calculate_price(
-    quote_asset_reserve: u128,
-    base_asset_reserve: u128,
-    peg_multiplier: u128,
-) -> ClearingHouseResult<u128> {
-    let peg_quote_asset_amount = quote_asset_reserve
-        .checked_mul(peg_multiplier)
-        .ok_or_else(math_error!())?;
-
-    U192::from(peg_quote_asset_amount)
-        .checked_mul(U192::from(PRICE_TO_PEG_PRECISION_RATIO))
-        .ok_or_else(math_error!())?
-        .checked_div(U192::from(base_asset_reserve))
-        .ok_or_else(math_error!())?
-        .try_to_u128()
-}
-
 pub fn calculate_terminal_price(market: &mut Market) -> ClearingHouseResult<u128> {
     let swap_direction = if market.base_asset_amount > 0 {
         SwapDirection::Add
@@ -45,13 +28,14 @@ pub fn calculate_terminal_price(market: &mut Market) -> ClearingHouseResult<u128
         market.base_asset_amount.unsigned_abs(),
         market.amm.base_asset_reserve,
         swap_direction,
-        market.amm.sqrt_k,
+        &market.amm,
     )?;
 
-    let terminal_price = calculate_price(
+    let terminal_price = market.amm.curve().calculate_price(
         new_quote_asset_amount,
         new_base_asset_amount,
         market.amm.peg_multiplier,
+        market.amm.sqrt_k,
     )?;
 
     Ok(terminal_price)
@@ -241,13 +225,8 @@ pub fn calculate_swap_output(
     swap_amount: u128,
     input_asset_amount: u128,
     direction: SwapDirection,
-    invariant_sqrt: u128,
+    amm: &AMM,
 ) -> ClearingHouseResult<(u128, u128)> {
-    let invariant_sqrt_u192 = U192::from(invariant_sqrt);
-    let invariant = invariant_sqrt_u192
-        .checked_mul(invariant_sqrt_u192)
-        .ok_or_else(math_error!())?;
-
     if direction == SwapDirection::Remove && swap_amount > input_asset_amount {
         return Err(ErrorCode::TradeSizeTooLarge);
     }
@@ -262,11 +241,9 @@ pub fn calculate_swap_output(
             .ok_or_else(math_error!())?
     };
 
-    let new_input_amount_u192 = U192::from(new_input_amount);
-    let new_output_amount = invariant
-        .checked_div(new_input_amount_u192)
-        .ok_or_else(math_error!())?
-        .try_to_u128()?;
+    let new_output_amount = amm
+        .curve()
+        .calculate_reserve(new_input_amount, amm.sqrt_k)?;
 
     Ok((new_output_amount, new_input_amount))
 }
@@ -301,19 +278,141 @@ pub fn calculate_quote_asset_amount_swapped(
     Ok(quote_asset_amount)
 }
 
-pub fn calculate_spread_reserves(
+/// The spread charged on trades in direction. Falls back to half the base spread if the spreads
+/// haven't been updated since the market was initialized. A market with no base spread can still
+/// charge a spread up to its max spread.
+pub fn get_spread(amm: &AMM, direction: PositionDirection) -> u128 {
+    if amm.base_spread == 0 && amm.max_spread == 0 {
+        return 0;
+    }
+
+    let spread = match direction {
+        PositionDirection::Long => amm.long_spread,
+        PositionDirection::Short => amm.short_spread,
+    };
+
+    if spread == 0 {
+        u128::from(amm.base_spread) / 2
+    } else {
+        u128::from(spread)
+    }
+}
+
+/// The spread on each side of the mark price. Both sides widen with the oracle's confidence and
+/// with volatility, measured by the gap between the mark and oracle twaps. The side that moves the
+/// mark further from the oracle widens by their divergence, and the side that grows the amm's net
+/// exposure widens in proportion to that exposure. Without a valid oracle both sides are at the max.
+pub fn calculate_spreads(
     amm: &AMM,
-    direction: PositionDirection,
-) -> ClearingHouseResult<(u128, u128)> {
-    let base_spread = amm.base_spread as u128;
-    let quote_asset_reserve_delta = if base_spread > 0 {
-        amm.quote_asset_reserve
-            .checked_div(BID_ASK_SPREAD_PRECISION / (base_spread / 4))
+    net_base_asset_amount: i128,
+    oracle_price_data: Option<&OraclePriceData>,
+    mark_price: u128,
+) -> ClearingHouseResult<(u16, u16)> {
+    let min_spread = u128::from(amm.base_spread) / 2;
+    let max_spread = max(u128::from(amm.max_spread) / 2, min_spread);
+
+    let oracle_price_data = match oracle_price_data {
+        Some(oracle_price_data) if oracle_price_data.price > 0 => oracle_price_data,
+        _ => return Ok((cast(max_spread)?, cast(max_spread)?)),
+    };
+
+    if max_spread == min_spread {
+        return Ok((cast(min_spread)?, cast(min_spread)?));
+    }
+
+    let oracle_price = oracle_price_data.price.unsigned_abs();
+
+    let confidence_spread = oracle_price_data
+        .confidence
+        .checked_mul(BID_ASK_SPREAD_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_div(oracle_price)
+        .ok_or_else(math_error!())?;
+
+    let volatility_spread = if amm.last_oracle_price_twap > 0 {
+        cast_to_i128(amm.last_mark_price_twap)?
+            .checked_sub(amm.last_oracle_price_twap)
+            .ok_or_else(math_error!())?
+            .unsigned_abs()
+            .checked_mul(BID_ASK_SPREAD_PRECISION)
+            .ok_or_else(math_error!())?
+            .checked_div(amm.last_oracle_price_twap.unsigned_abs())
             .ok_or_else(math_error!())?
     } else {
         0
     };
 
+    let mut long_spread = min_spread
+        .checked_add(confidence_spread)
+        .ok_or_else(math_error!())?
+        .checked_add(volatility_spread)
+        .ok_or_else(math_error!())?;
+    let mut short_spread = long_spread;
+
+    let mark_oracle_divergence = cast_to_i128(mark_price)?
+        .checked_sub(oracle_price_data.price)
+        .ok_or_else(math_error!())?;
+    let divergence_spread = mark_oracle_divergence
+        .unsigned_abs()
+        .checked_mul(BID_ASK_SPREAD_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_div(oracle_price)
+        .ok_or_else(math_error!())?;
+    if mark_oracle_divergence > 0 {
+        long_spread = long_spread
+            .checked_add(divergence_spread)
+            .ok_or_else(math_error!())?;
+    } else {
+        short_spread = short_spread
+            .checked_add(divergence_spread)
+            .ok_or_else(math_error!())?;
+    }
+
+    // users being net long means the amm is net short, so longs grow its exposure
+    let inventory_scale = min(
+        net_base_asset_amount
+            .unsigned_abs()
+            .checked_mul(BID_ASK_SPREAD_PRECISION)
+            .ok_or_else(math_error!())?
+            .checked_div(amm.base_asset_reserve)
+            .ok_or_else(math_error!())?,
+        MAX_INVENTORY_SPREAD_SCALE,
+    );
+    let scale_spread = |spread: u128| -> ClearingHouseResult<u128> {
+        spread
+            .checked_mul(
+                BID_ASK_SPREAD_PRECISION
+                    .checked_add(inventory_scale)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?
+            .checked_div(BID_ASK_SPREAD_PRECISION)
+            .ok_or_else(math_error!())
+    };
+    if net_base_asset_amount > 0 {
+        long_spread = scale_spread(long_spread)?;
+    } else {
+        short_spread = scale_spread(short_spread)?;
+    }
+
+    Ok((
+        cast(min(long_spread, max_spread))?,
+        cast(min(short_spread, max_spread))?,
+    ))
+}
+
+pub fn calculate_spread_reserves(
+    amm: &AMM,
+    direction: PositionDirection,
+) -> ClearingHouseResult<(u128, u128)> {
+    // moving the quote asset reserve by half the spread moves the price by the spread
+    let quote_asset_reserve_delta = amm
+        .quote_asset_reserve
+        .checked_mul(get_spread(amm, direction))
+        .ok_or_else(math_error!())?
+        .checked_div(BID_ASK_SPREAD_PRECISION * 2)
+        .ok_or_else(math_error!())?;
+
     let quote_asset_reserve = match direction {
         PositionDirection::Long => amm
             .quote_asset_reserve
@@ -325,15 +424,9 @@ pub fn calculate_spread_reserves(
             .ok_or_else(math_error!())?,
     };
 
-    let invariant_sqrt_u192 = U192::from(amm.sqrt_k);
-    let invariant = invariant_sqrt_u192
-        .checked_mul(invariant_sqrt_u192)
-        .ok_or_else(math_error!())?;
-
-    let base_asset_reserve = invariant
-        .checked_div(U192::from(quote_asset_reserve))
-        .ok_or_else(math_error!())?
-        .try_to_u128()?;
+    let base_asset_reserve = amm
+        .curve()
+        .calculate_reserve(quote_asset_reserve, amm.sqrt_k)?;
 
     Ok((base_asset_reserve, quote_asset_reserve))
 }
@@ -546,16 +639,10 @@ pub fn adjust_k_cost(market: &mut Market, new_sqrt_k: bn::U256) -> ClearingHouse
         .try_to_u128()
         .unwrap();
 
-    let invariant_sqrt_u192 = U192::from(market.amm.sqrt_k);
-    let invariant = invariant_sqrt_u192
-        .checked_mul(invariant_sqrt_u192)
-        .ok_or_else(math_error!())?;
-
-    market.amm.quote_asset_reserve = invariant
-        .checked_div(U192::from(market.amm.base_asset_reserve))
-        .ok_or_else(math_error!())?
-        .try_to_u128()
-        .unwrap();
+    market.amm.quote_asset_reserve = market
+        .amm
+        .curve()
+        .calculate_reserve(market.amm.base_asset_reserve, market.amm.sqrt_k)?;
 
     let (_new_net_market_value, cost) = _calculate_base_asset_value_and_pnl(
         market.base_asset_amount,
@@ -572,24 +659,11 @@ pub fn calculate_max_base_asset_amount_to_trade(
     direction: PositionDirection,
     use_spread: bool,
 ) -> ClearingHouseResult<(u128, PositionDirection)> {
-    let invariant_sqrt_u192 = U192::from(amm.sqrt_k);
-    let invariant = invariant_sqrt_u192
-        .checked_mul(invariant_sqrt_u192)
-        .ok_or_else(math_error!())?;
-
-    let new_base_asset_reserve_squared = invariant
-        .checked_mul(U192::from(MARK_PRICE_PRECISION))
-        .ok_or_else(math_error!())?
-        .checked_div(U192::from(limit_price))
-        .ok_or_else(math_error!())?
-        .checked_mul(U192::from(amm.peg_multiplier))
-        .ok_or_else(math_error!())?
-        .checked_div(U192::from(PEG_PRECISION))
-        .ok_or_else(math_error!())?;
-
-    let new_base_asset_reserve = new_base_asset_reserve_squared
-        .integer_sqrt()
-        .try_to_u128()?;
+    let new_base_asset_reserve = amm.curve().calculate_base_asset_reserve_for_price(
+        limit_price,
+        amm.peg_multiplier,
+        amm.sqrt_k,
+    )?;
 
     let base_asset_reserve_before = if use_spread && amm.base_spread > 0 {
         let (spread_base_asset_reserve, _) = calculate_spread_reserves(amm, direction)?;
@@ -630,3 +704,121 @@ pub fn should_round_trade(
 
     Ok(quote_asset_reserve_amount < amm.minimum_quote_asset_trade_size)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amm() -> AMM {
+        AMM {
+            base_asset_reserve: 1_000_000 * MARK_PRICE_PRECISION,
+            base_spread: 500,
+            max_spread: 2000,
+            ..AMM::default()
+        }
+    }
+
+    fn oracle_price_data(price: i128) -> OraclePriceData {
+        OraclePriceData {
+            price,
+            confidence: 0,
+            delay: 0,
+            has_sufficient_number_of_data_points: true,
+        }
+    }
+
+    #[test]
+    fn max_spreads_without_valid_oracle() {
+        let spreads = calculate_spreads(&amm(), 0, None, MARK_PRICE_PRECISION).unwrap();
+        assert_eq!(spreads, (1000, 1000));
+    }
+
+    #[test]
+    fn base_spreads_without_max_spread() {
+        let amm = AMM {
+            max_spread: 0,
+            ..amm()
+        };
+        let oracle_price_data = oracle_price_data(MARK_PRICE_PRECISION as i128);
+        let spreads = calculate_spreads(
+            &amm,
+            1_000 * MARK_PRICE_PRECISION as i128,
+            Some(&oracle_price_data),
+            MARK_PRICE_PRECISION * 2,
+        )
+        .unwrap();
+        assert_eq!(spreads, (250, 250));
+    }
+
+    #[test]
+    fn inventory_widens_side_growing_exposure() {
+        let oracle_price_data = oracle_price_data(MARK_PRICE_PRECISION as i128);
+
+        // users net long a tenth of the base asset reserve widens longs by 10%
+        let spreads = calculate_spreads(
+            &amm(),
+            100_000 * MARK_PRICE_PRECISION as i128,
+            Some(&oracle_price_data),
+            MARK_PRICE_PRECISION,
+        )
+        .unwrap();
+        assert_eq!(spreads, (275, 250));
+
+        let spreads = calculate_spreads(
+            &amm(),
+            -100_000 * MARK_PRICE_PRECISION as i128,
+            Some(&oracle_price_data),
+            MARK_PRICE_PRECISION,
+        )
+        .unwrap();
+        assert_eq!(spreads, (250, 275));
+    }
+
+    #[test]
+    fn confidence_and_divergence_widen_spreads() {
+        // 0.02% confidence widens both sides, the mark 0.01% above the oracle widens longs
+        let oracle_price_data = OraclePriceData {
+            confidence: MARK_PRICE_PRECISION / 5_000,
+            ..oracle_price_data(MARK_PRICE_PRECISION as i128)
+        };
+        let spreads = calculate_spreads(
+            &amm(),
+            0,
+            Some(&oracle_price_data),
+            MARK_PRICE_PRECISION + MARK_PRICE_PRECISION / 10_000,
+        )
+        .unwrap();
+        assert_eq!(spreads, (550, 450));
+    }
+
+    #[test]
+    fn spreads_capped_at_half_max_spread() {
+        let oracle_price_data = oracle_price_data(MARK_PRICE_PRECISION as i128);
+        let spreads = calculate_spreads(
+            &amm(),
+            0,
+            Some(&oracle_price_data),
+            MARK_PRICE_PRECISION * 2,
+        )
+        .unwrap();
+        assert_eq!(spreads, (1000, 250));
+    }
+
+    #[test]
+    fn spread_without_base_spread_uses_updated_spreads() {
+        let amm = AMM {
+            base_spread: 0,
+            long_spread: 1000,
+            short_spread: 0,
+            ..amm()
+        };
+        assert_eq!(get_spread(&amm, PositionDirection::Long), 1000);
+        assert_eq!(get_spread(&amm, PositionDirection::Short), 0);
+
+        let amm = AMM {
+            max_spread: 0,
+            ..amm
+        };
+        assert_eq!(get_spread(&amm, PositionDirection::Long), 0);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::controller::amm::SwapDirection;
+use crate::error::ClearingHouseResult;
+use crate::math::casting::cast_to_i128;
+use crate::math::constants::QUOTE_TO_BASE_AMT_FUNDING_PRECISION;
+use crate::math::pnl::calculate_pnl;
+use crate::math_error;
+use solana_program::msg;
+
+/// The pnl realized by closing part of a position during liquidation
+pub fn calculate_liquidated_position_pnl(
+    base_asset_amount_before: i128,
+    entry_value_closed: u128,
+    exit_value: u128,
+) -> ClearingHouseResult<i128> {
+    let swap_direction_to_close = if base_asset_amount_before > 0 {
+        SwapDirection::Add
+    } else {
+        SwapDirection::Remove
+    };
+
+    calculate_pnl(exit_value, entry_value_closed, swap_direction_to_close)
+}
+
+/// The part of the pnl realized by a liquidation that the collateral the positions shared couldn't
+/// pay. Profits on some positions offset losses on others, so it's measured once after all of them
+/// are closed.
+pub fn calculate_bankrupt_loss(
+    collateral_before: u128,
+    realized_pnl: i128,
+) -> ClearingHouseResult<u128> {
+    let collateral_after = cast_to_i128(collateral_before)?
+        .checked_add(realized_pnl)
+        .ok_or_else(math_error!())?;
+
+    Ok(if collateral_after < 0 {
+        collateral_after.unsigned_abs()
+    } else {
+        0
+    })
+}
+
+/// Splits the bankrupt loss between the markets whose positions were closed at a loss, in
+/// proportion to each market's loss. Takes and returns (market index, loss, base asset amount)
+/// tuples, the last market gets what rounding leaves over.
+pub fn allocate_bankrupt_loss(
+    bankrupt_loss: u128,
+    position_losses: &[(u64, u128, i128)],
+) -> ClearingHouseResult<Vec<(u64, u128, i128)>> {
+    let mut bankruptcies = vec![];
+    if bankrupt_loss == 0 || position_losses.is_empty() {
+        return Ok(bankruptcies);
+    }
+
+    let total_loss = position_losses
+        .iter()
+        .try_fold(0_u128, |total_loss, (_, loss, _)| {
+            total_loss.checked_add(*loss)
+        })
+        .ok_or_else(math_error!())?;
+
+    let mut loss_allocated = 0_u128;
+    let last_index = position_losses.len() - 1;
+    for (index, (market_index, loss, base_asset_amount)) in position_losses.iter().enumerate() {
+        let market_bankrupt_loss = if index == last_index {
+            bankrupt_loss
+                .checked_sub(loss_allocated)
+                .ok_or_else(math_error!())?
+        } else {
+            bankrupt_loss
+                .checked_mul(*loss)
+                .ok_or_else(math_error!())?
+                .checked_div(total_loss)
+                .ok_or_else(math_error!())?
+        };
+        loss_allocated = loss_allocated
+            .checked_add(market_bankrupt_loss)
+            .ok_or_else(math_error!())?;
+
+        if market_bankrupt_loss > 0 {
+            bankruptcies.push((*market_index, market_bankrupt_loss, *base_asset_amount));
+        }
+    }
+
+    Ok(bankruptcies)
+}
+
+/// The change to the cumulative funding rate that charges the loss to the base asset amount.
+/// Rounded up so the whole loss is charged.
+pub fn calculate_socialized_loss_funding_rate(
+    loss: u128,
+    base_asset_amount: i128,
+) -> ClearingHouseResult<i128> {
+    let base_asset_amount = cast_to_i128(base_asset_amount.unsigned_abs())?;
+
+    cast_to_i128(loss)?
+        .checked_mul(QUOTE_TO_BASE_AMT_FUNDING_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_add(base_asset_amount)
+        .ok_or_else(math_error!())?
+        .checked_sub(1)
+        .ok_or_else(math_error!())?
+        .checked_div(base_asset_amount)
+        .ok_or_else(math_error!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{AMM_RESERVE_PRECISION, QUOTE_PRECISION};
+
+    #[test]
+    fn bankrupt_loss_is_pnl_collateral_cant_pay() {
+        assert_eq!(calculate_bankrupt_loss(100, -150).unwrap(), 50);
+        assert_eq!(calculate_bankrupt_loss(100, -100).unwrap(), 0);
+        assert_eq!(calculate_bankrupt_loss(100, -50).unwrap(), 0);
+        assert_eq!(calculate_bankrupt_loss(0, 50).unwrap(), 0);
+    }
+
+    #[test]
+    fn liquidated_position_pnl() {
+        // a long entered at 100 and closed at 80
+        assert_eq!(
+            calculate_liquidated_position_pnl(AMM_RESERVE_PRECISION as i128, 100, 80).unwrap(),
+            -20
+        );
+        // a short entered at 100 and closed at 80
+        assert_eq!(
+            calculate_liquidated_position_pnl(-(AMM_RESERVE_PRECISION as i128), 100, 80).unwrap(),
+            20
+        );
+    }
+
+    #[test]
+    fn bankrupt_loss_allocated_by_position_loss() {
+        let bankruptcies = allocate_bankrupt_loss(100, &[(0, 30, 1), (1, 60, -2)]).unwrap();
+        assert_eq!(bankruptcies, vec![(0, 33, 1), (1, 67, -2)]);
+
+        // markets that didn't lose aren't charged
+        let bankruptcies = allocate_bankrupt_loss(100, &[(0, 0, 1), (1, 60, -2)]).unwrap();
+        assert_eq!(bankruptcies, vec![(1, 100, -2)]);
+
+        assert!(allocate_bankrupt_loss(0, &[(0, 30, 1)]).unwrap().is_empty());
+        assert!(allocate_bankrupt_loss(100, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn socialized_loss_funding_rate_charges_whole_loss() {
+        let base_asset_amount = AMM_RESERVE_PRECISION as i128;
+        assert_eq!(
+            calculate_socialized_loss_funding_rate(QUOTE_PRECISION, base_asset_amount).unwrap(),
+            QUOTE_PRECISION as i128 * QUOTE_TO_BASE_AMT_FUNDING_PRECISION / base_asset_amount
+        );
+
+        // rounded up when the loss doesn't divide evenly between the base
+        let base_asset_amount = -3 * AMM_RESERVE_PRECISION as i128;
+        let funding_rate = calculate_socialized_loss_funding_rate(1, base_asset_amount).unwrap();
+        assert_eq!(funding_rate, 33_333_334);
+        assert!(funding_rate * base_asset_amount.abs() >= QUOTE_TO_BASE_AMT_FUNDING_PRECISION);
+    }
+}
@@ -1,6 +1,17 @@
-use crate::error::ClearingHouseResult;
+use crate::error::{ClearingHouseResult, ErrorCode};
+use crate::math::casting::{cast_to_i128, cast_to_u128};
+use crate::math::constants::{MARGIN_PRECISION, PRICE_TO_QUOTE_PRECISION_RATIO};
+use crate::math::lending::calculate_balance_with_interest;
 use crate::math_error;
+use crate::state::collateral::{CollateralAsset, CollateralAssets, UserCollateralAssets};
+use crate::state::lending::LendingPool;
+use crate::state::market::OraclePriceData;
+use crate::state::state::ValidityGuardRails;
+use anchor_lang::prelude::{AccountInfo, Pubkey};
+use solana_program::clock::Slot;
 use solana_program::msg;
+use std::cmp::max;
+use std::collections::BTreeMap;
 
 pub fn calculate_updated_collateral(collateral: u128, pnl: i128) -> ClearingHouseResult<u128> {
     Ok(if pnl.is_negative() && pnl.unsigned_abs() > collateral {
@@ -15,3 +26,147 @@ pub fn calculate_updated_collateral(collateral: u128, pnl: i128) -> ClearingHous
             .ok_or_else(math_error!())?
     })
 }
+
+/// Value of a collateral asset balance in quote precision, after applying the asset weight haircut
+pub fn calculate_collateral_asset_value(
+    amount: u128,
+    collateral_asset: &CollateralAsset,
+    oracle_price: i128,
+    asset_weight: u128,
+) -> ClearingHouseResult<u128> {
+    let token_precision = 10_u128.pow(collateral_asset.mint_decimals as u32);
+
+    amount
+        .checked_mul(cast_to_u128(oracle_price)?)
+        .ok_or_else(math_error!())?
+        .checked_div(token_precision)
+        .ok_or_else(math_error!())?
+        .checked_div(PRICE_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?
+        .checked_mul(asset_weight)
+        .ok_or_else(math_error!())?
+        .checked_div(MARGIN_PRECISION)
+        .ok_or_else(math_error!())
+}
+
+/// Amount of a collateral asset, in its native token precision, worth quote_asset_amount at the price
+pub fn calculate_collateral_asset_amount(
+    quote_asset_amount: u128,
+    collateral_asset: &CollateralAsset,
+    price: u128,
+) -> ClearingHouseResult<u128> {
+    let token_precision = 10_u128.pow(collateral_asset.mint_decimals as u32);
+
+    quote_asset_amount
+        .checked_mul(PRICE_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?
+        .checked_mul(token_precision)
+        .ok_or_else(math_error!())?
+        .checked_div(price)
+        .ok_or_else(math_error!())
+}
+
+/// Value of a user's spot balances in quote precision: their collateral assets at the haircut price
+/// net of their borrows, which are weighted up, and their quote borrows. Balances include the
+/// interest accrued since they were last settled. Every asset the user holds or owes must have a
+/// valid oracle among the oracle accounts.
+pub fn calculate_spot_balances_value(
+    user_collateral_assets: &UserCollateralAssets,
+    collateral_assets: &CollateralAssets,
+    quote_lending_pool: &LendingPool,
+    oracle_account_infos: &BTreeMap<Pubkey, &AccountInfo>,
+    clock_slot: Slot,
+    validity_guard_rails: &ValidityGuardRails,
+) -> ClearingHouseResult<i128> {
+    let mut spot_balances_value: i128 = 0;
+
+    for (index, collateral_asset) in collateral_assets.assets.iter().enumerate() {
+        if !collateral_asset.initialized {
+            continue;
+        }
+
+        let lending_pool = &collateral_asset.lending_pool;
+        let balance = calculate_balance_with_interest(
+            user_collateral_assets.balances[index],
+            user_collateral_assets.last_cumulative_deposit_interest[index],
+            lending_pool.cumulative_deposit_interest,
+        )?;
+        let borrows = calculate_balance_with_interest(
+            user_collateral_assets.borrows[index],
+            user_collateral_assets.last_cumulative_borrow_interest[index],
+            lending_pool.cumulative_borrow_interest,
+        )?;
+        if balance == 0 && borrows == 0 {
+            continue;
+        }
+
+        let oracle_account_info = oracle_account_infos
+            .get(&collateral_asset.oracle)
+            .ok_or(ErrorCode::OracleNotFound)?;
+        let oracle_price = get_valid_collateral_asset_price(
+            collateral_asset,
+            oracle_account_info,
+            clock_slot,
+            validity_guard_rails,
+        )?;
+
+        spot_balances_value = spot_balances_value
+            .checked_add(cast_to_i128(calculate_collateral_asset_value(
+                balance,
+                collateral_asset,
+                oracle_price,
+                collateral_asset.asset_weight,
+            )?)?)
+            .ok_or_else(math_error!())?
+            .checked_sub(cast_to_i128(calculate_collateral_asset_value(
+                borrows,
+                collateral_asset,
+                oracle_price,
+                collateral_asset.liability_weight()?,
+            )?)?)
+            .ok_or_else(math_error!())?;
+    }
+
+    let quote_borrows = calculate_balance_with_interest(
+        user_collateral_assets.quote_borrows,
+        user_collateral_assets.last_cumulative_quote_borrow_interest,
+        quote_lending_pool.cumulative_borrow_interest,
+    )?;
+
+    spot_balances_value
+        .checked_sub(cast_to_i128(quote_borrows)?)
+        .ok_or_else(math_error!())
+}
+
+pub fn get_valid_collateral_asset_price(
+    collateral_asset: &CollateralAsset,
+    oracle_account_info: &AccountInfo,
+    clock_slot: Slot,
+    validity_guard_rails: &ValidityGuardRails,
+) -> ClearingHouseResult<i128> {
+    let OraclePriceData {
+        price,
+        confidence,
+        delay,
+        has_sufficient_number_of_data_points,
+    } = collateral_asset.get_oracle_price(oracle_account_info, clock_slot)?;
+
+    let is_conf_too_large = cast_to_u128(max(price, 1))?
+        .checked_div(max(1, confidence))
+        .ok_or_else(math_error!())?
+        < validity_guard_rails.confidence_interval_max_size;
+
+    if price <= 0
+        || delay > validity_guard_rails.slots_before_stale
+        || !has_sufficient_number_of_data_points
+        || is_conf_too_large
+    {
+        msg!(
+            "Invalid oracle for collateral asset {}",
+            collateral_asset.mint
+        );
+        return Err(ErrorCode::InvalidOracle);
+    }
+
+    Ok(price)
+}
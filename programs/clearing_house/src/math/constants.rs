@@ -3,11 +3,16 @@ pub const AMM_RESERVE_PRECISION: u128 = 10_000_000_000_000; //expo = -13;
 pub const MARK_PRICE_PRECISION: u128 = 10_000_000_000; //expo = -10
 pub const QUOTE_PRECISION: u128 = 1_000_000; // expo = -6
 pub const FUNDING_PAYMENT_PRECISION: u128 = 10_000; // expo = -4
+pub const FUNDING_RATE_PCT_PRECISION: u128 = 1_000_000; // expo = -6
 pub const MARGIN_PRECISION: u128 = 10_000; // expo = -4
 pub const PEG_PRECISION: u128 = 1_000; //expo = -3
 pub const PRICE_SPREAD_PRECISION: i128 = 10_000; // expo = -4
 pub const PRICE_SPREAD_PRECISION_U128: u128 = 10_000; // expo = -4
 pub const BID_ASK_SPREAD_PRECISION: u128 = 1_000_000; // expo = -6
+pub const BASIS_POINTS_PRECISION: u128 = 10_000; // expo = -4
+pub const INTEREST_PRECISION: u128 = 10_000_000_000; // expo = -10
+pub const INTEREST_RATE_PRECISION: u128 = 1_000_000; // expo = -6
+pub const UTILIZATION_PRECISION: u128 = 1_000_000; // expo = -6
 
 // PRECISION CONVERSIONS
 pub const PRICE_TO_PEG_PRECISION_RATIO: u128 = MARK_PRICE_PRECISION / PEG_PRECISION; // expo: 7
@@ -31,10 +36,13 @@ pub const UPDATE_K_ALLOWED_PRICE_CHANGE: u128 = MARK_PRICE_PRECISION / 100_000;
 
 // TIME PERIODS
 pub const ONE_HOUR: i128 = 3600;
+pub const ONE_YEAR: u128 = 31_536_000;
 
 // FEES
 pub const DEFAULT_FEE_NUMERATOR: u128 = 10;
 pub const DEFAULT_FEE_DENOMINATOR: u128 = 10000;
+pub const DEFAULT_MAKER_REBATE_NUMERATOR: u128 = 2;
+pub const DEFAULT_MAKER_REBATE_DENOMINATOR: u128 = 10000;
 pub const DEFAULT_DISCOUNT_TOKEN_FIRST_TIER_MINIMUM_BALANCE: u64 = 1_000_000_000_000; // 1000
 pub const DEFAULT_DISCOUNT_TOKEN_FIRST_TIER_DISCOUNT_NUMERATOR: u128 = 20;
 pub const DEFAULT_DISCOUNT_TOKEN_FIRST_TIER_DISCOUNT_DENOMINATOR: u128 = 100;
@@ -52,9 +60,56 @@ pub const DEFAULT_REFERRER_REWARD_DENOMINATOR: u128 = 100;
 pub const DEFAULT_REFEREE_DISCOUNT_NUMERATOR: u128 = 5;
 pub const DEFAULT_REFEREE_DISCOUNT_DENOMINATOR: u128 = 100;
 
+// INTEREST
+pub const DEFAULT_OPTIMAL_UTILIZATION: u128 = UTILIZATION_PRECISION * 8 / 10; // 80%
+pub const DEFAULT_OPTIMAL_BORROW_RATE: u128 = INTEREST_RATE_PRECISION / 10; // 10%
+pub const DEFAULT_MAX_BORROW_RATE: u128 = INTEREST_RATE_PRECISION; // 100%
+
+// INSURANCE FUND
+pub const DEFAULT_INSURANCE_FUND_UNSTAKING_PERIOD: i64 = 60 * 60 * 24 * 7; // 7 days
+pub const DEFAULT_INSURANCE_FUND_FEE_SHARE_NUMERATOR: u128 = 0;
+pub const DEFAULT_INSURANCE_FUND_FEE_SHARE_DENOMINATOR: u128 = 100;
+pub const INSURANCE_FUND_DEAD_SHARES: u128 = QUOTE_PRECISION;
+
+// POSITION TRANSFER LIQUIDATION
+pub const DEFAULT_POSITION_TRANSFER_DISCOUNT_NUMERATOR: u128 = 1;
+pub const DEFAULT_POSITION_TRANSFER_DISCOUNT_DENOMINATOR: u128 = 100;
+
+// LIQUIDATION AUCTION
+pub const DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_NUMERATOR: u128 = 1;
+pub const DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_DENOMINATOR: u128 = 10;
+pub const DEFAULT_LIQUIDATION_AUCTION_DURATION: u64 = 0; // slots, liquidators get the full share
+
+// FORMULAIC REPEG
+pub const DEFAULT_FORMULAIC_REPEG_THRESHOLD_NUMERATOR: u128 = 1;
+pub const DEFAULT_FORMULAIC_REPEG_THRESHOLD_DENOMINATOR: u128 = 100; // 1% mark/oracle gap
+
+// FORMULAIC K
+pub const DEFAULT_FORMULAIC_K_MAX_CHANGE_NUMERATOR: u128 = 1;
+pub const DEFAULT_FORMULAIC_K_MAX_CHANGE_DENOMINATOR: u128 = 100; // 1% change in sqrt_k per update
+pub const MAX_FORMULAIC_K_DECREASE_NUMERATOR: u128 = 25;
+pub const MAX_FORMULAIC_K_DECREASE_DENOMINATOR: u128 = 1000; // adjust_k_cost rejects larger decreases
+pub const MAX_BUDGETED_K_RESCALES: u8 = 4;
+
+// CURVE
+pub const MAX_AMPLIFICATION_COEFFICIENT: u64 = 10_000;
+
+// FUNDING
+// funding is already clamped to a 3% mark/oracle spread, so larger settings have no effect
+pub const MAX_FUNDING_RATE: u32 = FUNDING_RATE_PCT_PRECISION as u32 * 3 / 100;
+pub const MAX_FUNDING_INTEREST_RATE: u32 = FUNDING_RATE_PCT_PRECISION as u32 * 3 / 100;
+
+// SPREAD
+pub const MAX_INVENTORY_SPREAD_SCALE: u128 = BID_ASK_SPREAD_PRECISION; // expo = -6, at most doubles
+
 // CONSTRAINTS
 pub const MAX_LIQUIDATION_SLIPPAGE: i128 = 100; // expo = -2
 pub const MAX_LIQUIDATION_SLIPPAGE_U128: u128 = 100; // expo = -2
 pub const MAX_MARK_TWAP_DIVERGENCE: u128 = 5_000; // expo = -3
 pub const MAXIMUM_MARGIN_RATIO: u32 = MARGIN_PRECISION as u32;
 pub const MINIMUM_MARGIN_RATIO: u32 = MARGIN_PRECISION as u32 / 50;
+pub const IMF_FACTOR_PRECISION: u128 = 1_000; // expo = -3
+pub const MAX_IMF_FACTOR: u32 = 100 * IMF_FACTOR_PRECISION as u32; // 100% premium at 10k of value
+pub const MAX_USER_POSITIONS: usize = 64;
+// bounds the compute of a liquidation, positions past it are liquidated by later instructions
+pub const MAX_POSITIONS_LIQUIDATED_PER_INSTRUCTION: usize = 5;
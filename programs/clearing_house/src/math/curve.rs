@@ -0,0 +1,545 @@
+use crate::error::{ClearingHouseResult, ErrorCode};
+use crate::math::bn::{U192, U256};
+use crate::math::constants::{MARK_PRICE_PRECISION, PEG_PRECISION, PRICE_TO_PEG_PRECISION_RATIO};
+use crate::math_error;
+use crate::state::market::CurveType;
+use solana_program::msg;
+use std::cmp::max;
+
+const MAX_CURVE_ITERATIONS: u8 = 255;
+const MAX_BISECTION_ITERATIONS: u8 = 64;
+// bisection stops once the base asset reserve is known to within 1 / BISECTION_PRECISION of itself
+const BISECTION_PRECISION: u128 = 1_000_000_000;
+
+pub fn get_curve(curve_type: CurveType, amplification_coefficient: u64) -> AmmCurve {
+    match curve_type {
+        CurveType::ConstantProduct => AmmCurve::ConstantProduct,
+        CurveType::StableSwap => AmmCurve::StableSwap {
+            amplification_coefficient: amplification_coefficient as u128,
+        },
+    }
+}
+
+/// The bonding curve between the amm's base and quote asset reserves. A curve's invariant is
+/// expressed as sqrt_k, the size of both reserves when they are balanced and mark price is the peg
+pub trait Curve {
+    /// The reserve on one side of the curve given the reserve on the other side
+    fn calculate_reserve(&self, other_reserve: u128, sqrt_k: u128) -> ClearingHouseResult<u128>;
+
+    /// The sqrt_k of the curve that passes through the reserves
+    fn calculate_sqrt_k(
+        &self,
+        base_asset_reserve: u128,
+        quote_asset_reserve: u128,
+    ) -> ClearingHouseResult<u128>;
+
+    /// The marginal price of the base asset in MARK_PRICE_PRECISION. The reserves are on the curve
+    /// through sqrt_k
+    fn calculate_price(
+        &self,
+        quote_asset_reserve: u128,
+        base_asset_reserve: u128,
+        peg_multiplier: u128,
+        sqrt_k: u128,
+    ) -> ClearingHouseResult<u128>;
+
+    /// The base asset reserve at which the marginal price is price. Price falls as the base asset
+    /// reserve grows, so curves without a closed form can find it by bisection. Bisection stops
+    /// once the reserve is known to within 1 / BISECTION_PRECISION of itself and the lower bound,
+    /// where the price is still above price, is returned
+    fn calculate_base_asset_reserve_for_price(
+        &self,
+        price: u128,
+        peg_multiplier: u128,
+        sqrt_k: u128,
+    ) -> ClearingHouseResult<u128> {
+        let price_at = |base_asset_reserve: u128| -> ClearingHouseResult<u128> {
+            let quote_asset_reserve = self.calculate_reserve(base_asset_reserve, sqrt_k)?;
+            self.calculate_price(
+                quote_asset_reserve,
+                base_asset_reserve,
+                peg_multiplier,
+                sqrt_k,
+            )
+        };
+
+        let mut low = sqrt_k;
+        let mut high = sqrt_k;
+        let mut bracketed = false;
+        if price_at(sqrt_k)? < price {
+            for _ in 0..MAX_BISECTION_ITERATIONS {
+                high = low;
+                low /= 2;
+                if low <= 1 || price_at(low)? >= price {
+                    bracketed = true;
+                    break;
+                }
+            }
+        } else {
+            for _ in 0..MAX_BISECTION_ITERATIONS {
+                low = high;
+                high = high.checked_mul(2).ok_or_else(math_error!())?;
+                if price_at(high)? <= price {
+                    bracketed = true;
+                    break;
+                }
+            }
+        }
+
+        if !bracketed {
+            return Err(ErrorCode::CurveDidNotConverge);
+        }
+
+        for _ in 0..MAX_BISECTION_ITERATIONS {
+            let tolerance = max(1, low / BISECTION_PRECISION);
+            if high.checked_sub(low).ok_or_else(math_error!())? <= tolerance {
+                return Ok(low);
+            }
+
+            let middle = low
+                .checked_add(high.checked_sub(low).ok_or_else(math_error!())? / 2)
+                .ok_or_else(math_error!())?;
+            if price_at(middle)? > price {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+
+        Err(ErrorCode::CurveDidNotConverge)
+    }
+}
+
+/// The curve an amm trades on, dispatched by match so no curve has to be boxed. Variants only hold
+/// the curve parameters so the enum stays describable in the idl
+pub enum AmmCurve {
+    ConstantProduct,
+    StableSwap { amplification_coefficient: u128 },
+}
+
+impl Curve for AmmCurve {
+    fn calculate_reserve(&self, other_reserve: u128, sqrt_k: u128) -> ClearingHouseResult<u128> {
+        match *self {
+            AmmCurve::ConstantProduct => {
+                ConstantProductCurve.calculate_reserve(other_reserve, sqrt_k)
+            }
+            AmmCurve::StableSwap {
+                amplification_coefficient,
+            } => StableSwapCurve {
+                amplification_coefficient,
+            }
+            .calculate_reserve(other_reserve, sqrt_k),
+        }
+    }
+
+    fn calculate_sqrt_k(
+        &self,
+        base_asset_reserve: u128,
+        quote_asset_reserve: u128,
+    ) -> ClearingHouseResult<u128> {
+        match *self {
+            AmmCurve::ConstantProduct => {
+                ConstantProductCurve.calculate_sqrt_k(base_asset_reserve, quote_asset_reserve)
+            }
+            AmmCurve::StableSwap {
+                amplification_coefficient,
+            } => StableSwapCurve {
+                amplification_coefficient,
+            }
+            .calculate_sqrt_k(base_asset_reserve, quote_asset_reserve),
+        }
+    }
+
+    fn calculate_price(
+        &self,
+        quote_asset_reserve: u128,
+        base_asset_reserve: u128,
+        peg_multiplier: u128,
+        sqrt_k: u128,
+    ) -> ClearingHouseResult<u128> {
+        match *self {
+            AmmCurve::ConstantProduct => ConstantProductCurve.calculate_price(
+                quote_asset_reserve,
+                base_asset_reserve,
+                peg_multiplier,
+                sqrt_k,
+            ),
+            AmmCurve::StableSwap {
+                amplification_coefficient,
+            } => StableSwapCurve {
+                amplification_coefficient,
+            }
+            .calculate_price(
+                quote_asset_reserve,
+                base_asset_reserve,
+                peg_multiplier,
+                sqrt_k,
+            ),
+        }
+    }
+
+    fn calculate_base_asset_reserve_for_price(
+        &self,
+        price: u128,
+        peg_multiplier: u128,
+        sqrt_k: u128,
+    ) -> ClearingHouseResult<u128> {
+        match *self {
+            AmmCurve::ConstantProduct => ConstantProductCurve
+                .calculate_base_asset_reserve_for_price(price, peg_multiplier, sqrt_k),
+            AmmCurve::StableSwap {
+                amplification_coefficient,
+            } => StableSwapCurve {
+                amplification_coefficient,
+            }
+            .calculate_base_asset_reserve_for_price(price, peg_multiplier, sqrt_k),
+        }
+    }
+}
+
+/// x * y = k
+pub struct ConstantProductCurve;
+
+impl Curve for ConstantProductCurve {
+    fn calculate_reserve(&self, other_reserve: u128, sqrt_k: u128) -> ClearingHouseResult<u128> {
+        let invariant_sqrt_u192 = U192::from(sqrt_k);
+        let invariant = invariant_sqrt_u192
+            .checked_mul(invariant_sqrt_u192)
+            .ok_or_else(math_error!())?;
+
+        invariant
+            .checked_div(U192::from(other_reserve))
+            .ok_or_else(math_error!())?
+            .try_to_u128()
+    }
+
+    fn calculate_sqrt_k(
+        &self,
+        base_asset_reserve: u128,
+        quote_asset_reserve: u128,
+    ) -> ClearingHouseResult<u128> {
+        U256::from(base_asset_reserve)
+            .checked_mul(U256::from(quote_asset_reserve))
+            .ok_or_else(math_error!())?
+            .integer_sqrt()
+            .try_to_u128()
+    }
+
+    fn calculate_price(
+        &self,
+        quote_asset_reserve: u128,
+        base_asset_reserve: u128,
+        peg_multiplier: u128,
+        _sqrt_k: u128,
+    ) -> ClearingHouseResult<u128> {
+        let peg_quote_asset_amount = quote_asset_reserve
+            .checked_mul(peg_multiplier)
+            .ok_or_else(math_error!())?;
+
+        U192::from(peg_quote_asset_amount)
+            .checked_mul(U192::from(PRICE_TO_PEG_PRECISION_RATIO))
+            .ok_or_else(math_error!())?
+            .checked_div(U192::from(base_asset_reserve))
+            .ok_or_else(math_error!())?
+            .try_to_u128()
+    }
+
+    fn calculate_base_asset_reserve_for_price(
+        &self,
+        price: u128,
+        peg_multiplier: u128,
+        sqrt_k: u128,
+    ) -> ClearingHouseResult<u128> {
+        let invariant_sqrt_u192 = U192::from(sqrt_k);
+        let invariant = invariant_sqrt_u192
+            .checked_mul(invariant_sqrt_u192)
+            .ok_or_else(math_error!())?;
+
+        invariant
+            .checked_mul(U192::from(MARK_PRICE_PRECISION))
+            .ok_or_else(math_error!())?
+            .checked_div(U192::from(price))
+            .ok_or_else(math_error!())?
+            .checked_mul(U192::from(peg_multiplier))
+            .ok_or_else(math_error!())?
+            .checked_div(U192::from(PEG_PRECISION))
+            .ok_or_else(math_error!())?
+            .integer_sqrt()
+            .try_to_u128()
+    }
+}
+
+/// Curve's stableswap invariant for two assets, A * 4 * (x + y) + D = A * 4 * D + D^3 / (4 * x * y).
+/// Liquidity is concentrated around balanced reserves (mark price at the peg) and the amplification
+/// coefficient A controls how flat the curve is there. D is 2 * sqrt_k
+pub struct StableSwapCurve {
+    pub amplification_coefficient: u128,
+}
+
+impl StableSwapCurve {
+    fn amplification_times_n_pow_n(&self) -> ClearingHouseResult<U256> {
+        if self.amplification_coefficient == 0 {
+            return Err(ErrorCode::InvalidAmplificationCoefficient);
+        }
+
+        U256::from(self.amplification_coefficient)
+            .checked_mul(U256::from(4))
+            .ok_or_else(math_error!())
+    }
+}
+
+impl Curve for StableSwapCurve {
+    fn calculate_reserve(&self, other_reserve: u128, sqrt_k: u128) -> ClearingHouseResult<u128> {
+        let ann = self.amplification_times_n_pow_n()?;
+        let d = U256::from(sqrt_k)
+            .checked_mul(U256::from(2))
+            .ok_or_else(math_error!())?;
+        let x = U256::from(other_reserve);
+
+        // y^2 + (b - D) * y = c, solved with newton's method
+        let c = d
+            .checked_mul(d)
+            .ok_or_else(math_error!())?
+            .checked_div(x.checked_mul(U256::from(2)).ok_or_else(math_error!())?)
+            .ok_or_else(math_error!())?
+            .checked_mul(d)
+            .ok_or_else(math_error!())?
+            .checked_div(ann.checked_mul(U256::from(2)).ok_or_else(math_error!())?)
+            .ok_or_else(math_error!())?;
+        let b = x
+            .checked_add(d.checked_div(ann).ok_or_else(math_error!())?)
+            .ok_or_else(math_error!())?;
+
+        let mut y = d;
+        for _ in 0..MAX_CURVE_ITERATIONS {
+            let y_before = y;
+            y = y
+                .checked_mul(y)
+                .ok_or_else(math_error!())?
+                .checked_add(c)
+                .ok_or_else(math_error!())?
+                .checked_div(
+                    y.checked_mul(U256::from(2))
+                        .ok_or_else(math_error!())?
+                        .checked_add(b)
+                        .ok_or_else(math_error!())?
+                        .checked_sub(d)
+                        .ok_or_else(math_error!())?,
+                )
+                .ok_or_else(math_error!())?;
+
+            let difference = if y > y_before {
+                y - y_before
+            } else {
+                y_before - y
+            };
+            if difference <= U256::from(1) {
+                return y.try_to_u128();
+            }
+        }
+
+        Err(ErrorCode::CurveDidNotConverge)
+    }
+
+    fn calculate_sqrt_k(
+        &self,
+        base_asset_reserve: u128,
+        quote_asset_reserve: u128,
+    ) -> ClearingHouseResult<u128> {
+        let ann = self.amplification_times_n_pow_n()?;
+        let x = U256::from(base_asset_reserve);
+        let y = U256::from(quote_asset_reserve);
+        let s = x.checked_add(y).ok_or_else(math_error!())?;
+
+        let mut d = s;
+        for _ in 0..MAX_CURVE_ITERATIONS {
+            // d_p = D^3 / (4 * x * y)
+            let d_p = d
+                .checked_mul(d)
+                .ok_or_else(math_error!())?
+                .checked_div(x.checked_mul(U256::from(2)).ok_or_else(math_error!())?)
+                .ok_or_else(math_error!())?
+                .checked_mul(d)
+                .ok_or_else(math_error!())?
+                .checked_div(y.checked_mul(U256::from(2)).ok_or_else(math_error!())?)
+                .ok_or_else(math_error!())?;
+
+            let d_before = d;
+            d = ann
+                .checked_mul(s)
+                .ok_or_else(math_error!())?
+                .checked_add(d_p.checked_mul(U256::from(2)).ok_or_else(math_error!())?)
+                .ok_or_else(math_error!())?
+                .checked_mul(d)
+                .ok_or_else(math_error!())?
+                .checked_div(
+                    ann.checked_sub(U256::from(1))
+                        .ok_or_else(math_error!())?
+                        .checked_mul(d)
+                        .ok_or_else(math_error!())?
+                        .checked_add(d_p.checked_mul(U256::from(3)).ok_or_else(math_error!())?)
+                        .ok_or_else(math_error!())?,
+                )
+                .ok_or_else(math_error!())?;
+
+            let difference = if d > d_before {
+                d - d_before
+            } else {
+                d_before - d
+            };
+            if difference <= U256::from(1) {
+                return d
+                    .checked_div(U256::from(2))
+                    .ok_or_else(math_error!())?
+                    .try_to_u128();
+            }
+        }
+
+        Err(ErrorCode::CurveDidNotConverge)
+    }
+
+    fn calculate_price(
+        &self,
+        quote_asset_reserve: u128,
+        base_asset_reserve: u128,
+        peg_multiplier: u128,
+        sqrt_k: u128,
+    ) -> ClearingHouseResult<u128> {
+        // price = -dy/dx = (4 * A * 4 * x * y + D^3 / x) / (4 * A * 4 * x * y + D^3 / y)
+        let ann = self.amplification_times_n_pow_n()?;
+        let x = U256::from(base_asset_reserve);
+        let y = U256::from(quote_asset_reserve);
+        let d = U256::from(sqrt_k)
+            .checked_mul(U256::from(2))
+            .ok_or_else(math_error!())?;
+        let d_cubed = d
+            .checked_mul(d)
+            .ok_or_else(math_error!())?
+            .checked_mul(d)
+            .ok_or_else(math_error!())?;
+
+        let amplified_xy = ann
+            .checked_mul(U256::from(4))
+            .ok_or_else(math_error!())?
+            .checked_mul(x)
+            .ok_or_else(math_error!())?
+            .checked_mul(y)
+            .ok_or_else(math_error!())?;
+
+        let numerator = amplified_xy
+            .checked_add(d_cubed.checked_div(x).ok_or_else(math_error!())?)
+            .ok_or_else(math_error!())?;
+        let denominator = amplified_xy
+            .checked_add(d_cubed.checked_div(y).ok_or_else(math_error!())?)
+            .ok_or_else(math_error!())?;
+
+        numerator
+            .checked_mul(U256::from(peg_multiplier))
+            .ok_or_else(math_error!())?
+            .checked_mul(U256::from(PRICE_TO_PEG_PRECISION_RATIO))
+            .ok_or_else(math_error!())?
+            .checked_div(denominator)
+            .ok_or_else(math_error!())?
+            .try_to_u128()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::AMM_RESERVE_PRECISION;
+
+    const SQRT_K: u128 = 1_000_000 * AMM_RESERVE_PRECISION;
+
+    fn stable_swap() -> AmmCurve {
+        get_curve(CurveType::StableSwap, 100)
+    }
+
+    fn price_after_buying(curve: &AmmCurve, base_asset_amount: u128) -> u128 {
+        let base_asset_reserve = SQRT_K - base_asset_amount;
+        let quote_asset_reserve = curve.calculate_reserve(base_asset_reserve, SQRT_K).unwrap();
+        curve
+            .calculate_price(
+                quote_asset_reserve,
+                base_asset_reserve,
+                PEG_PRECISION,
+                SQRT_K,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn constant_product_reserves() {
+        let curve = get_curve(CurveType::ConstantProduct, 0);
+        assert_eq!(
+            curve.calculate_reserve(SQRT_K * 2, SQRT_K).unwrap(),
+            SQRT_K / 2
+        );
+        assert_eq!(
+            curve.calculate_sqrt_k(SQRT_K * 2, SQRT_K / 2).unwrap(),
+            SQRT_K
+        );
+    }
+
+    #[test]
+    fn balanced_reserves_price_at_peg() {
+        for curve in [get_curve(CurveType::ConstantProduct, 0), stable_swap()] {
+            let price = curve
+                .calculate_price(SQRT_K, SQRT_K, PEG_PRECISION * 2, SQRT_K)
+                .unwrap();
+            assert_eq!(price, MARK_PRICE_PRECISION * 2);
+        }
+    }
+
+    #[test]
+    fn stable_swap_invariant_round_trips() {
+        let curve = stable_swap();
+        assert_eq!(curve.calculate_sqrt_k(SQRT_K, SQRT_K).unwrap(), SQRT_K);
+
+        let base_asset_reserve = SQRT_K * 9 / 10;
+        let quote_asset_reserve = curve.calculate_reserve(base_asset_reserve, SQRT_K).unwrap();
+        assert!(quote_asset_reserve > SQRT_K * 11 / 10);
+
+        let sqrt_k = curve
+            .calculate_sqrt_k(base_asset_reserve, quote_asset_reserve)
+            .unwrap();
+        assert!(sqrt_k.abs_diff(SQRT_K) <= 1);
+    }
+
+    #[test]
+    fn stable_swap_concentrates_liquidity_around_peg() {
+        let constant_product = get_curve(CurveType::ConstantProduct, 0);
+        let base_asset_amount = SQRT_K / 100;
+
+        let constant_product_price = price_after_buying(&constant_product, base_asset_amount);
+        let stable_swap_price = price_after_buying(&stable_swap(), base_asset_amount);
+        assert!(stable_swap_price > MARK_PRICE_PRECISION);
+        assert!(stable_swap_price < constant_product_price);
+
+        let flatter_price =
+            price_after_buying(&get_curve(CurveType::StableSwap, 1_000), base_asset_amount);
+        assert!(flatter_price > MARK_PRICE_PRECISION);
+        assert!(flatter_price < stable_swap_price);
+    }
+
+    #[test]
+    fn base_asset_reserve_for_price_inverts_price() {
+        for curve in [get_curve(CurveType::ConstantProduct, 0), stable_swap()] {
+            let target_price = MARK_PRICE_PRECISION * 11 / 10;
+            let base_asset_reserve = curve
+                .calculate_base_asset_reserve_for_price(target_price, PEG_PRECISION, SQRT_K)
+                .unwrap();
+            assert!(base_asset_reserve < SQRT_K);
+
+            let price = price_after_buying(&curve, SQRT_K - base_asset_reserve);
+            assert!(price.abs_diff(target_price) <= target_price / 1_000_000);
+        }
+    }
+
+    #[test]
+    fn stable_swap_needs_amplification() {
+        let curve = get_curve(CurveType::StableSwap, 0);
+        assert!(curve.calculate_reserve(SQRT_K, SQRT_K).is_err());
+        assert!(curve.calculate_sqrt_k(SQRT_K, SQRT_K).is_err());
+    }
+}
@@ -253,6 +253,64 @@ pub fn calculate_fee_for_order(
     }
 }
 
+/// Fees for a taker order matched against a resting maker order. The taker pays the regular fee and
+/// the maker receives a rebate out of the taker fee.
+pub fn calculate_fee_for_matched_order(
+    quote_asset_amount: u128,
+    fee_structure: &FeeStructure,
+    maker_rebate_numerator: u128,
+    maker_rebate_denominator: u128,
+    filler_reward_structure: &OrderFillerRewardStructure,
+    order_fee_tier: &OrderDiscountTier,
+    order_ts: i64,
+    now: i64,
+    referrer: &Option<Account<User>>,
+    filler_is_user: bool,
+) -> ClearingHouseResult<(i128, u128, u128, u128, u128, u128, u128)> {
+    let (
+        taker_fee,
+        fee_to_market,
+        token_discount,
+        filler_reward,
+        referrer_reward,
+        referee_discount,
+    ) = calculate_fee_for_order(
+        quote_asset_amount,
+        fee_structure,
+        filler_reward_structure,
+        order_fee_tier,
+        order_ts,
+        now,
+        referrer,
+        filler_is_user,
+        0,
+        false,
+    )?;
+
+    let maker_rebate = min(
+        fee_to_market,
+        quote_asset_amount
+            .checked_mul(maker_rebate_numerator)
+            .ok_or_else(math_error!())?
+            .checked_div(maker_rebate_denominator)
+            .ok_or_else(math_error!())?,
+    );
+
+    let fee_to_market = fee_to_market
+        .checked_sub(maker_rebate)
+        .ok_or_else(math_error!())?;
+
+    Ok((
+        taker_fee,
+        maker_rebate,
+        fee_to_market,
+        token_discount,
+        filler_reward,
+        referrer_reward,
+        referee_discount,
+    ))
+}
+
 fn calculate_token_discount_for_limit_order(
     fee: u128,
     fee_structure: &FeeStructure,
@@ -1,16 +1,72 @@
 use crate::error::{ClearingHouseResult, ErrorCode};
 use crate::math::bn;
-use crate::math::casting::cast_to_i128;
+use crate::math::casting::{cast, cast_to_i128};
 use crate::math::constants::{
-    AMM_TO_QUOTE_PRECISION_RATIO, FUNDING_PAYMENT_PRECISION, MARK_PRICE_PRECISION,
-    QUOTE_TO_BASE_AMT_FUNDING_PRECISION, SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR,
+    AMM_TO_QUOTE_PRECISION_RATIO, FUNDING_PAYMENT_PRECISION, FUNDING_RATE_PCT_PRECISION,
+    MARK_PRICE_PRECISION, QUOTE_TO_BASE_AMT_FUNDING_PRECISION,
+    SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR,
     SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR,
 };
 use crate::math_error;
 use crate::state::market::Market;
 use crate::state::user::MarketPosition;
 use solana_program::msg;
-use std::cmp::max;
+use std::cmp::{max, min};
+
+/// The funding rate for a period from the premium index (the time weighted mark/oracle spread
+/// sampled over the period) plus the market's interest rate, both daily rates scaled down by period_adjustment.
+/// Returns the raw rate and the rate clamped to a 3% spread and the market's max funding per period
+pub fn calculate_funding_rate(
+    premium_index: i128,
+    oracle_price_twap: i128,
+    period_adjustment: i64,
+    max_funding_rate: u32,
+    funding_interest_rate: u32,
+) -> ClearingHouseResult<(i128, i128)> {
+    let interest_spread = oracle_price_twap
+        .checked_mul(cast(funding_interest_rate)?)
+        .ok_or_else(math_error!())?
+        .checked_div(cast(FUNDING_RATE_PCT_PRECISION)?)
+        .ok_or_else(math_error!())?;
+
+    let price_spread = premium_index
+        .checked_add(interest_spread)
+        .ok_or_else(math_error!())?;
+
+    let raw_funding_rate = price_spread
+        .checked_mul(cast(FUNDING_PAYMENT_PRECISION)?)
+        .ok_or_else(math_error!())?
+        .checked_div(cast(period_adjustment)?)
+        .ok_or_else(math_error!())?;
+
+    // clamp price divergence to 3% for funding rate calculation
+    let max_price_spread = oracle_price_twap
+        .checked_div(33)
+        .ok_or_else(math_error!())?; // 3%
+    let clamped_price_spread = max(-max_price_spread, min(price_spread, max_price_spread));
+
+    let mut clamped_funding_rate = clamped_price_spread
+        .checked_mul(cast(FUNDING_PAYMENT_PRECISION)?)
+        .ok_or_else(math_error!())?
+        .checked_div(cast(period_adjustment)?)
+        .ok_or_else(math_error!())?;
+
+    if max_funding_rate > 0 {
+        let max_funding_rate = oracle_price_twap
+            .checked_mul(cast(max_funding_rate)?)
+            .ok_or_else(math_error!())?
+            .checked_mul(cast(FUNDING_PAYMENT_PRECISION)?)
+            .ok_or_else(math_error!())?
+            .checked_div(cast(FUNDING_RATE_PCT_PRECISION)?)
+            .ok_or_else(math_error!())?;
+        clamped_funding_rate = max(
+            -max_funding_rate,
+            min(clamped_funding_rate, max_funding_rate),
+        );
+    }
+
+    Ok((raw_funding_rate, clamped_funding_rate))
+}
 
 /// With a virtual AMM, there can be an imbalance between longs and shorts and thus funding can be asymmetric.
 /// To account for this, amm keeps track of the cumulative funding rate for both longs and shorts.
@@ -228,3 +284,99 @@ fn calculate_funding_payment_in_quote_precision(
 
     Ok(funding_payment_collateral)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORACLE_PRICE_TWAP: i128 = MARK_PRICE_PRECISION as i128;
+    const PERIOD_ADJUSTMENT: i64 = 24;
+
+    fn funding_rate(price_spread: i128) -> i128 {
+        price_spread * FUNDING_PAYMENT_PRECISION as i128 / PERIOD_ADJUSTMENT as i128
+    }
+
+    #[test]
+    fn premium_within_clamps() {
+        let premium_index = ORACLE_PRICE_TWAP / 100;
+        let (raw_funding_rate, clamped_funding_rate) =
+            calculate_funding_rate(premium_index, ORACLE_PRICE_TWAP, PERIOD_ADJUSTMENT, 0, 0)
+                .unwrap();
+        assert_eq!(raw_funding_rate, funding_rate(premium_index));
+        assert_eq!(clamped_funding_rate, raw_funding_rate);
+    }
+
+    #[test]
+    fn premium_clamped_to_three_percent() {
+        let premium_index = ORACLE_PRICE_TWAP / 10;
+        let (raw_funding_rate, clamped_funding_rate) =
+            calculate_funding_rate(premium_index, ORACLE_PRICE_TWAP, PERIOD_ADJUSTMENT, 0, 0)
+                .unwrap();
+        assert_eq!(raw_funding_rate, funding_rate(premium_index));
+        assert_eq!(clamped_funding_rate, funding_rate(ORACLE_PRICE_TWAP / 33));
+
+        let (raw_funding_rate, clamped_funding_rate) =
+            calculate_funding_rate(-premium_index, ORACLE_PRICE_TWAP, PERIOD_ADJUSTMENT, 0, 0)
+                .unwrap();
+        assert_eq!(raw_funding_rate, funding_rate(-premium_index));
+        assert_eq!(clamped_funding_rate, funding_rate(-ORACLE_PRICE_TWAP / 33));
+    }
+
+    #[test]
+    fn funding_rate_clamped_to_max_funding_rate() {
+        // 0.01% of the oracle twap per period
+        let max_funding_rate = 100;
+        let max_rate = ORACLE_PRICE_TWAP * FUNDING_PAYMENT_PRECISION as i128 / 10_000;
+
+        let premium_index = ORACLE_PRICE_TWAP / 100;
+        let (raw_funding_rate, clamped_funding_rate) = calculate_funding_rate(
+            premium_index,
+            ORACLE_PRICE_TWAP,
+            PERIOD_ADJUSTMENT,
+            max_funding_rate,
+            0,
+        )
+        .unwrap();
+        assert_eq!(raw_funding_rate, funding_rate(premium_index));
+        assert_eq!(clamped_funding_rate, max_rate);
+
+        let (raw_funding_rate, clamped_funding_rate) = calculate_funding_rate(
+            -premium_index,
+            ORACLE_PRICE_TWAP,
+            PERIOD_ADJUSTMENT,
+            max_funding_rate,
+            0,
+        )
+        .unwrap();
+        assert_eq!(raw_funding_rate, funding_rate(-premium_index));
+        assert_eq!(clamped_funding_rate, -max_rate);
+    }
+
+    #[test]
+    fn interest_rate_added_to_premium() {
+        // 0.1% of the oracle twap
+        let funding_interest_rate = 1000;
+        let interest_spread = ORACLE_PRICE_TWAP / 1000;
+
+        let (raw_funding_rate, clamped_funding_rate) = calculate_funding_rate(
+            0,
+            ORACLE_PRICE_TWAP,
+            PERIOD_ADJUSTMENT,
+            0,
+            funding_interest_rate,
+        )
+        .unwrap();
+        assert_eq!(raw_funding_rate, funding_rate(interest_spread));
+        assert_eq!(clamped_funding_rate, raw_funding_rate);
+
+        let (raw_funding_rate, _) = calculate_funding_rate(
+            -interest_spread,
+            ORACLE_PRICE_TWAP,
+            PERIOD_ADJUSTMENT,
+            0,
+            funding_interest_rate,
+        )
+        .unwrap();
+        assert_eq!(raw_funding_rate, 0);
+    }
+}
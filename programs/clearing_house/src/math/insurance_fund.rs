@@ -0,0 +1,95 @@
+use crate::error::ClearingHouseResult;
+use crate::math::casting::{cast, cast_to_u128};
+use crate::math_error;
+use solana_program::msg;
+
+/// Shares minted for a deposit into the insurance vault at the current share value
+pub fn calculate_shares_for_amount(
+    amount: u64,
+    total_shares: u128,
+    insurance_vault_amount: u64,
+) -> ClearingHouseResult<u128> {
+    if total_shares == 0 {
+        return cast_to_u128(amount);
+    }
+
+    cast_to_u128(amount)?
+        .checked_mul(total_shares)
+        .ok_or_else(math_error!())?
+        .checked_div(cast_to_u128(insurance_vault_amount)?)
+        .ok_or_else(math_error!())
+}
+
+/// Shares burned for a withdrawal from the insurance vault, rounded up so that the withdrawal
+/// can't take more than the shares are worth
+pub fn calculate_shares_for_amount_rounded_up(
+    amount: u64,
+    total_shares: u128,
+    insurance_vault_amount: u64,
+) -> ClearingHouseResult<u128> {
+    let insurance_vault_amount = cast_to_u128(insurance_vault_amount)?;
+
+    cast_to_u128(amount)?
+        .checked_mul(total_shares)
+        .ok_or_else(math_error!())?
+        .checked_add(insurance_vault_amount)
+        .ok_or_else(math_error!())?
+        .checked_sub(1)
+        .ok_or_else(math_error!())?
+        .checked_div(insurance_vault_amount)
+        .ok_or_else(math_error!())
+}
+
+pub fn calculate_amount_for_shares(
+    shares: u128,
+    total_shares: u128,
+    insurance_vault_amount: u64,
+) -> ClearingHouseResult<u64> {
+    if total_shares == 0 {
+        return Ok(0);
+    }
+
+    cast(
+        shares
+            .checked_mul(cast_to_u128(insurance_vault_amount)?)
+            .ok_or_else(math_error!())?
+            .checked_div(total_shares)
+            .ok_or_else(math_error!())?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_stake_gets_one_share_per_token() {
+        assert_eq!(calculate_shares_for_amount(100, 0, 0).unwrap(), 100);
+    }
+
+    #[test]
+    fn shares_priced_against_vault() {
+        // the vault doubled in value since the shares were minted
+        assert_eq!(calculate_shares_for_amount(100, 1000, 2000).unwrap(), 50);
+        assert_eq!(calculate_amount_for_shares(50, 1000, 2000).unwrap(), 100);
+
+        // losses covered by the vault dilute the share value
+        assert_eq!(calculate_shares_for_amount(100, 1000, 500).unwrap(), 200);
+        assert_eq!(calculate_amount_for_shares(200, 1000, 500).unwrap(), 100);
+
+        assert_eq!(calculate_amount_for_shares(100, 0, 500).unwrap(), 0);
+    }
+
+    #[test]
+    fn shares_for_withdrawal_round_up() {
+        assert_eq!(calculate_shares_for_amount(1, 1000, 3000).unwrap(), 0);
+        assert_eq!(
+            calculate_shares_for_amount_rounded_up(1, 1000, 3000).unwrap(),
+            1
+        );
+        assert_eq!(
+            calculate_shares_for_amount_rounded_up(300, 1000, 3000).unwrap(),
+            100
+        );
+    }
+}
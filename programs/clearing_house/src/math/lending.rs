@@ -0,0 +1,229 @@
+use crate::error::ClearingHouseResult;
+use crate::math::casting::cast_to_u128;
+use crate::math::constants::{INTEREST_RATE_PRECISION, ONE_YEAR, UTILIZATION_PRECISION};
+use crate::math_error;
+use crate::state::lending::LendingPool;
+use solana_program::msg;
+use std::cmp::min;
+
+/// The share of the pool's deposits lent out. The deposits are the balances that earn interest, so
+/// depositors are paid what borrowers pay. The vault can also hold tokens that don't earn interest,
+/// so the borrows can exceed the deposits, in which case the pool counts as fully utilized.
+pub fn calculate_utilization(
+    total_borrows: u128,
+    total_deposits: u128,
+) -> ClearingHouseResult<u128> {
+    if total_deposits == 0 {
+        return Ok(if total_borrows > 0 {
+            UTILIZATION_PRECISION
+        } else {
+            0
+        });
+    }
+
+    Ok(min(
+        total_borrows
+            .checked_mul(UTILIZATION_PRECISION)
+            .ok_or_else(math_error!())?
+            .checked_div(total_deposits)
+            .ok_or_else(math_error!())?,
+        UTILIZATION_PRECISION,
+    ))
+}
+
+/// The yearly borrow rate rises linearly up to the optimal borrow rate at the optimal utilization
+/// and then more steeply up to the max borrow rate at full utilization
+pub fn calculate_borrow_rate(
+    lending_pool: &LendingPool,
+    utilization: u128,
+) -> ClearingHouseResult<u128> {
+    let optimal_utilization = lending_pool.optimal_utilization;
+    let optimal_borrow_rate = lending_pool.optimal_borrow_rate;
+
+    if utilization <= optimal_utilization {
+        return optimal_borrow_rate
+            .checked_mul(utilization)
+            .ok_or_else(math_error!())?
+            .checked_div(optimal_utilization)
+            .ok_or_else(math_error!());
+    }
+
+    let excess_utilization = utilization
+        .checked_sub(optimal_utilization)
+        .ok_or_else(math_error!())?;
+    let excess_utilization_range = UTILIZATION_PRECISION
+        .checked_sub(optimal_utilization)
+        .ok_or_else(math_error!())?;
+
+    lending_pool
+        .max_borrow_rate
+        .checked_sub(optimal_borrow_rate)
+        .ok_or_else(math_error!())?
+        .checked_mul(excess_utilization)
+        .ok_or_else(math_error!())?
+        .checked_div(excess_utilization_range)
+        .ok_or_else(math_error!())?
+        .checked_add(optimal_borrow_rate)
+        .ok_or_else(math_error!())
+}
+
+/// Borrowers pay the borrow rate and depositors split it, so the deposit rate is the borrow rate
+/// scaled down by the utilization
+pub fn calculate_cumulative_interest(
+    lending_pool: &LendingPool,
+    now: i64,
+) -> ClearingHouseResult<(u128, u128)> {
+    let time_since_last_update = cast_to_u128(
+        now.checked_sub(lending_pool.last_interest_ts)
+            .ok_or_else(math_error!())?
+            .max(0),
+    )?;
+
+    let utilization =
+        calculate_utilization(lending_pool.total_borrows, lending_pool.total_deposits)?;
+    let borrow_rate = calculate_borrow_rate(lending_pool, utilization)?;
+
+    let borrow_interest = borrow_rate
+        .checked_mul(time_since_last_update)
+        .ok_or_else(math_error!())?
+        .checked_div(ONE_YEAR)
+        .ok_or_else(math_error!())?;
+    let deposit_interest = borrow_interest
+        .checked_mul(utilization)
+        .ok_or_else(math_error!())?
+        .checked_div(UTILIZATION_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    Ok((
+        apply_interest(lending_pool.cumulative_deposit_interest, deposit_interest)?,
+        apply_interest(lending_pool.cumulative_borrow_interest, borrow_interest)?,
+    ))
+}
+
+fn apply_interest(cumulative_interest: u128, interest: u128) -> ClearingHouseResult<u128> {
+    cumulative_interest
+        .checked_mul(interest)
+        .ok_or_else(math_error!())?
+        .checked_div(INTEREST_RATE_PRECISION)
+        .ok_or_else(math_error!())?
+        .checked_add(cumulative_interest)
+        .ok_or_else(math_error!())
+}
+
+/// A balance last settled at last_cumulative_interest, with the interest accrued since added
+pub fn calculate_balance_with_interest(
+    balance: u128,
+    last_cumulative_interest: u128,
+    cumulative_interest: u128,
+) -> ClearingHouseResult<u128> {
+    if balance == 0 || last_cumulative_interest == 0 {
+        return Ok(balance);
+    }
+
+    balance
+        .checked_mul(cumulative_interest)
+        .ok_or_else(math_error!())?
+        .checked_div(last_cumulative_interest)
+        .ok_or_else(math_error!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{INTEREST_PRECISION, QUOTE_PRECISION};
+
+    #[test]
+    fn utilization() {
+        assert_eq!(calculate_utilization(0, 0).unwrap(), 0);
+        assert_eq!(
+            calculate_utilization(25 * QUOTE_PRECISION, 100 * QUOTE_PRECISION).unwrap(),
+            UTILIZATION_PRECISION / 4
+        );
+        assert_eq!(
+            calculate_utilization(25 * QUOTE_PRECISION, 0).unwrap(),
+            UTILIZATION_PRECISION
+        );
+        // borrows of tokens that don't earn interest don't push the utilization past 100%
+        assert_eq!(
+            calculate_utilization(50 * QUOTE_PRECISION, 25 * QUOTE_PRECISION).unwrap(),
+            UTILIZATION_PRECISION
+        );
+    }
+
+    #[test]
+    fn borrow_rate_follows_curve() {
+        let lending_pool = LendingPool::new(0);
+
+        // below the optimal utilization of 80% the rate rises linearly to 10%
+        assert_eq!(calculate_borrow_rate(&lending_pool, 0).unwrap(), 0);
+        assert_eq!(
+            calculate_borrow_rate(&lending_pool, UTILIZATION_PRECISION * 4 / 10).unwrap(),
+            INTEREST_RATE_PRECISION / 20
+        );
+        assert_eq!(
+            calculate_borrow_rate(&lending_pool, UTILIZATION_PRECISION * 8 / 10).unwrap(),
+            INTEREST_RATE_PRECISION / 10
+        );
+
+        // above it the rate rises to 100% at full utilization
+        assert_eq!(
+            calculate_borrow_rate(&lending_pool, UTILIZATION_PRECISION * 9 / 10).unwrap(),
+            INTEREST_RATE_PRECISION * 55 / 100
+        );
+        assert_eq!(
+            calculate_borrow_rate(&lending_pool, UTILIZATION_PRECISION).unwrap(),
+            INTEREST_RATE_PRECISION
+        );
+    }
+
+    #[test]
+    fn cumulative_interest_after_one_year() {
+        let mut lending_pool = LendingPool::new(0);
+        lending_pool.total_deposits = 100 * QUOTE_PRECISION;
+        lending_pool.total_borrows = 25 * QUOTE_PRECISION;
+
+        // 25% utilization is a 3.125% borrow rate, a quarter of which goes to depositors
+        let (cumulative_deposit_interest, cumulative_borrow_interest) =
+            calculate_cumulative_interest(&lending_pool, ONE_YEAR as i64).unwrap();
+        assert_eq!(cumulative_borrow_interest, 10_312_500_000);
+        assert_eq!(cumulative_deposit_interest, 10_078_120_000);
+    }
+
+    #[test]
+    fn no_cumulative_interest_without_time_or_borrows() {
+        let mut lending_pool = LendingPool::new(100);
+        lending_pool.total_deposits = QUOTE_PRECISION;
+        let (cumulative_deposit_interest, cumulative_borrow_interest) =
+            calculate_cumulative_interest(&lending_pool, 1000).unwrap();
+        assert_eq!(cumulative_deposit_interest, INTEREST_PRECISION);
+        assert_eq!(cumulative_borrow_interest, INTEREST_PRECISION);
+
+        lending_pool.total_borrows = QUOTE_PRECISION;
+        let (cumulative_deposit_interest, cumulative_borrow_interest) =
+            calculate_cumulative_interest(&lending_pool, 100).unwrap();
+        assert_eq!(cumulative_deposit_interest, INTEREST_PRECISION);
+        assert_eq!(cumulative_borrow_interest, INTEREST_PRECISION);
+
+        // a clock behind the last update doesn't accrue negative interest
+        let (_, cumulative_borrow_interest) =
+            calculate_cumulative_interest(&lending_pool, 0).unwrap();
+        assert_eq!(cumulative_borrow_interest, INTEREST_PRECISION);
+    }
+
+    #[test]
+    fn balance_with_interest() {
+        assert_eq!(
+            calculate_balance_with_interest(100, INTEREST_PRECISION, 10_312_500_000).unwrap(),
+            103
+        );
+        assert_eq!(
+            calculate_balance_with_interest(0, INTEREST_PRECISION, 10_312_500_000).unwrap(),
+            0
+        );
+        // balances opened before interest was tracked are left as they are
+        assert_eq!(
+            calculate_balance_with_interest(100, 0, 10_312_500_000).unwrap(),
+            100
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::error::ClearingHouseResult;
+use crate::math::casting::{cast, cast_to_u128};
+use crate::math::constants::MARGIN_PRECISION;
+use crate::math_error;
+use crate::state::state::{LiquidationAuction, State};
+use solana_program::msg;
+
+/// The partial and full liquidation penalties and liquidator shares a market starts out with,
+/// converted from the exchange wide ones on the state to MARGIN_PRECISION
+pub fn calculate_market_liquidation_fees(
+    state: &State,
+) -> ClearingHouseResult<(u32, u32, u32, u32)> {
+    let partial_liquidation_penalty = cast(
+        state
+            .partial_liquidation_penalty_percentage_numerator
+            .checked_mul(MARGIN_PRECISION)
+            .ok_or_else(math_error!())?
+            .checked_div(state.partial_liquidation_penalty_percentage_denominator)
+            .ok_or_else(math_error!())?,
+    )?;
+    let full_liquidation_penalty = cast(
+        state
+            .full_liquidation_penalty_percentage_numerator
+            .checked_mul(MARGIN_PRECISION)
+            .ok_or_else(math_error!())?
+            .checked_div(state.full_liquidation_penalty_percentage_denominator)
+            .ok_or_else(math_error!())?,
+    )?;
+    let partial_liquidation_liquidator_share = cast(
+        MARGIN_PRECISION
+            .checked_div(cast(
+                state.partial_liquidation_liquidator_share_denominator,
+            )?)
+            .ok_or_else(math_error!())?,
+    )?;
+    let full_liquidation_liquidator_share = cast(
+        MARGIN_PRECISION
+            .checked_div(cast(state.full_liquidation_liquidator_share_denominator)?)
+            .ok_or_else(math_error!())?,
+    )?;
+
+    Ok((
+        partial_liquidation_penalty,
+        full_liquidation_penalty,
+        partial_liquidation_liquidator_share,
+        full_liquidation_liquidator_share,
+    ))
+}
+
+/// The value of a market's position to close so that the account's margin requirement drops
+/// below its collateral after paying the liquidation fee on the value closed.
+/// Rounded up so the account ends up above the requirement.
+pub fn calculate_partial_liquidation_close_amount(
+    margin_requirement: u128,
+    adjusted_total_collateral: u128,
+    market_margin_requirement: u128,
+    market_base_asset_value: u128,
+    maximum_liquidation_fee: u128,
+    maximum_base_asset_value_closed: u128,
+) -> ClearingHouseResult<u128> {
+    if margin_requirement < adjusted_total_collateral {
+        return Ok(0);
+    }
+
+    let margin_shortage = margin_requirement
+        .checked_sub(adjusted_total_collateral)
+        .ok_or_else(math_error!())?
+        .checked_add(1)
+        .ok_or_else(math_error!())?;
+
+    // Closing a unit of value frees market_margin_requirement / market_base_asset_value of margin
+    // and costs maximum_liquidation_fee / maximum_base_asset_value_closed in fees
+    let margin_freed = market_margin_requirement
+        .checked_mul(maximum_base_asset_value_closed)
+        .ok_or_else(math_error!())?;
+    let fee_paid = maximum_liquidation_fee
+        .checked_mul(market_base_asset_value)
+        .ok_or_else(math_error!())?;
+
+    // Closing the position can't cover the shortage, so close all of it
+    if margin_freed <= fee_paid {
+        return Ok(market_base_asset_value);
+    }
+
+    let denominator = margin_freed
+        .checked_sub(fee_paid)
+        .ok_or_else(math_error!())?;
+
+    margin_shortage
+        .checked_mul(market_base_asset_value)
+        .ok_or_else(math_error!())?
+        .checked_mul(maximum_base_asset_value_closed)
+        .ok_or_else(math_error!())?
+        .checked_add(denominator)
+        .ok_or_else(math_error!())?
+        .checked_sub(1)
+        .ok_or_else(math_error!())?
+        .checked_div(denominator)
+        .ok_or_else(math_error!())
+}
+
+/// The liquidator's share of the liquidation fee, which rises from the auction's start share to the
+/// full share over the auction's duration
+pub fn calculate_liquidator_fee_for_auction(
+    max_fee_to_liquidator: u64,
+    liquidation_auction: &LiquidationAuction,
+    slots_since_liquidation_start: u64,
+) -> ClearingHouseResult<u64> {
+    if slots_since_liquidation_start >= liquidation_auction.duration {
+        return Ok(max_fee_to_liquidator);
+    }
+
+    let start_share_numerator = liquidation_auction.start_liquidator_share_numerator;
+    let share_denominator = liquidation_auction.start_liquidator_share_denominator;
+    let duration = cast_to_u128(liquidation_auction.duration)?;
+
+    // start_share + (1 - start_share) * slots_since_liquidation_start / duration
+    let share_numerator = start_share_numerator
+        .checked_mul(duration)
+        .ok_or_else(math_error!())?
+        .checked_add(
+            share_denominator
+                .checked_sub(start_share_numerator)
+                .ok_or_else(math_error!())?
+                .checked_mul(cast_to_u128(slots_since_liquidation_start)?)
+                .ok_or_else(math_error!())?,
+        )
+        .ok_or_else(math_error!())?;
+
+    cast(
+        cast_to_u128(max_fee_to_liquidator)?
+            .checked_mul(share_numerator)
+            .ok_or_else(math_error!())?
+            .checked_div(
+                share_denominator
+                    .checked_mul(duration)
+                    .ok_or_else(math_error!())?,
+            )
+            .ok_or_else(math_error!())?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn liquidation_auction() -> LiquidationAuction {
+        LiquidationAuction {
+            start_liquidator_share_numerator: 1,
+            start_liquidator_share_denominator: 4,
+            duration: 100,
+        }
+    }
+
+    #[test]
+    fn no_close_amount_above_margin_requirement() {
+        let close_amount =
+            calculate_partial_liquidation_close_amount(100, 101, 100, 1000, 25, 1000).unwrap();
+        assert_eq!(close_amount, 0);
+    }
+
+    #[test]
+    fn close_amount_restores_margin_after_fee() {
+        // closing 147 frees 14.7 of margin and pays 3.675 in fees, covering the shortage of 11
+        let close_amount =
+            calculate_partial_liquidation_close_amount(100, 90, 100, 1000, 25, 1000).unwrap();
+        assert_eq!(close_amount, 147);
+    }
+
+    #[test]
+    fn close_whole_position_when_fee_exceeds_margin_freed() {
+        let close_amount =
+            calculate_partial_liquidation_close_amount(100, 90, 20, 1000, 25, 1000).unwrap();
+        assert_eq!(close_amount, 1000);
+    }
+
+    #[test]
+    fn auction_starts_at_start_share() {
+        let fee = calculate_liquidator_fee_for_auction(1000, &liquidation_auction(), 0).unwrap();
+        assert_eq!(fee, 250);
+    }
+
+    #[test]
+    fn auction_share_rises_linearly() {
+        // half way through the auction the share is half way between 1/4 and 1
+        let fee = calculate_liquidator_fee_for_auction(1000, &liquidation_auction(), 50).unwrap();
+        assert_eq!(fee, 625);
+    }
+
+    #[test]
+    fn auction_ends_at_full_share() {
+        let fee = calculate_liquidator_fee_for_auction(1000, &liquidation_auction(), 100).unwrap();
+        assert_eq!(fee, 1000);
+
+        let fee = calculate_liquidator_fee_for_auction(1000, &liquidation_auction(), 500).unwrap();
+        assert_eq!(fee, 1000);
+    }
+
+    #[test]
+    fn no_auction_pays_full_share() {
+        let liquidation_auction = LiquidationAuction {
+            duration: 0,
+            ..liquidation_auction()
+        };
+        let fee = calculate_liquidator_fee_for_auction(1000, &liquidation_auction, 0).unwrap();
+        assert_eq!(fee, 1000);
+    }
+}
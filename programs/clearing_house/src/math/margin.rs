@@ -1,23 +1,26 @@
 use crate::error::{ClearingHouseResult, ErrorCode};
-use crate::math::collateral::calculate_updated_collateral;
-use crate::math::constants::MARGIN_PRECISION;
+use crate::math::constants::{
+    IMF_FACTOR_PRECISION, MARGIN_PRECISION, MAXIMUM_MARGIN_RATIO, QUOTE_PRECISION,
+};
 use crate::math::position::{
     calculate_base_asset_value_and_pnl, calculate_base_asset_value_and_pnl_with_oracle_price,
 };
 use crate::math_error;
 use crate::state::market::Markets;
-use crate::state::user::{User, UserPositions};
-use std::cell::{Ref, RefMut};
+use crate::state::user::{MarketPosition, User};
+use std::cell::Ref;
 
 use crate::math::amm::use_oracle_price_for_margin_calculation;
-use crate::math::casting::cast_to_i128;
+use crate::math::casting::{cast, cast_to_i128};
 use crate::math::oracle::{get_oracle_status, OracleStatus};
 use crate::math::slippage::calculate_slippage;
 use crate::state::state::OracleGuardRails;
 use anchor_lang::prelude::{AccountInfo, Pubkey};
 use anchor_lang::Key;
+use num_integer::Roots;
 use solana_program::clock::Slot;
 use solana_program::msg;
+use std::cmp::min;
 use std::collections::BTreeMap;
 use std::ops::Div;
 
@@ -28,16 +31,122 @@ pub enum MarginType {
     Maint,
 }
 
+/// The margin ratio for a position of base_asset_value. The initial margin ratio gets a premium
+/// that grows with the square root of the position's value, capped at the maximum margin ratio,
+/// and the partial and maintenance ratios are scaled by as much so that they stay below it.
+pub fn calculate_size_adjusted_margin_ratio(
+    margin_ratio: u32,
+    margin_ratio_initial: u32,
+    imf_factor: u32,
+    base_asset_value: u128,
+) -> ClearingHouseResult<u32> {
+    if imf_factor == 0 || margin_ratio_initial == 0 {
+        return Ok(margin_ratio);
+    }
+
+    let size_sqrt = base_asset_value
+        .checked_div(QUOTE_PRECISION)
+        .ok_or_else(math_error!())?
+        .sqrt();
+
+    let size_premium = u128::from(imf_factor)
+        .checked_mul(size_sqrt)
+        .ok_or_else(math_error!())?
+        .checked_div(IMF_FACTOR_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    let size_adjusted_margin_ratio_initial = min(
+        u128::from(margin_ratio_initial)
+            .checked_add(size_premium)
+            .ok_or_else(math_error!())?,
+        u128::from(MAXIMUM_MARGIN_RATIO),
+    );
+
+    cast(
+        u128::from(margin_ratio)
+            .checked_mul(size_adjusted_margin_ratio_initial)
+            .ok_or_else(math_error!())?
+            .checked_div(u128::from(margin_ratio_initial))
+            .ok_or_else(math_error!())?,
+    )
+}
+
+/// The user's spot balances back the cross margin positions alongside their quote collateral.
+/// Their value, haircut collateral assets net of borrows, is passed in as spot_balances_value.
 pub fn calculate_margin_requirement_and_total_collateral(
     user: &User,
-    user_positions: &UserPositions,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
+    markets: &Markets,
+    margin_type: MarginType,
+) -> ClearingHouseResult<(u128, u128)> {
+    calculate_margin_requirement_and_total_collateral_for_positions(
+        cross_margin_collateral(user, spot_balances_value)?,
+        cross_margin_positions(user_positions),
+        markets,
+        margin_type,
+    )
+}
+
+pub fn calculate_isolated_margin_requirement_and_total_collateral(
+    market_position: &MarketPosition,
+    markets: &Markets,
+    margin_type: MarginType,
+) -> ClearingHouseResult<(u128, u128)> {
+    calculate_margin_requirement_and_total_collateral_for_positions(
+        cast_to_i128(market_position.isolated_collateral)?,
+        std::iter::once(market_position),
+        markets,
+        margin_type,
+    )
+}
+
+fn cross_margin_collateral(user: &User, spot_balances_value: i128) -> ClearingHouseResult<i128> {
+    cast_to_i128(user.collateral)?
+        .checked_add(spot_balances_value)
+        .ok_or_else(math_error!())
+}
+
+/// Paths that don't load the user's spot balances can leave out their collateral assets, which only
+/// understates their collateral. Their borrows can't be left out.
+pub fn spot_balances_value_lower_bound(user: &User) -> ClearingHouseResult<i128> {
+    if user.has_borrows == 1 {
+        return Err(ErrorCode::UserCollateralAssetsNotFound);
+    }
+
+    Ok(0)
+}
+
+fn calculate_total_collateral(collateral: i128, unrealized_pnl: i128) -> ClearingHouseResult<u128> {
+    let total_collateral = collateral
+        .checked_add(unrealized_pnl)
+        .ok_or_else(math_error!())?;
+
+    Ok(if total_collateral > 0 {
+        total_collateral.unsigned_abs()
+    } else {
+        0
+    })
+}
+
+fn cross_margin_positions(
+    user_positions: &[MarketPosition],
+) -> impl Iterator<Item = &MarketPosition> {
+    user_positions
+        .iter()
+        .filter(|market_position| !market_position.is_isolated())
+}
+
+fn calculate_margin_requirement_and_total_collateral_for_positions<'a>(
+    collateral: i128,
+    market_positions: impl Iterator<Item = &'a MarketPosition>,
     markets: &Markets,
     margin_type: MarginType,
 ) -> ClearingHouseResult<(u128, u128)> {
     let mut margin_requirement: u128 = 0;
     let mut unrealized_pnl: i128 = 0;
 
-    for market_position in user_positions.positions.iter() {
+    for market_position in market_positions {
         if market_position.base_asset_amount == 0 {
             continue;
         }
@@ -47,7 +156,7 @@ pub fn calculate_margin_requirement_and_total_collateral(
         let (position_base_asset_value, position_unrealized_pnl) =
             calculate_base_asset_value_and_pnl(market_position, amm)?;
 
-        let margin_ratio = market.get_margin_ratio(margin_type);
+        let margin_ratio = market.get_margin_ratio(position_base_asset_value, margin_type)?;
 
         margin_requirement = margin_requirement
             .checked_add(
@@ -62,20 +171,22 @@ pub fn calculate_margin_requirement_and_total_collateral(
             .ok_or_else(math_error!())?;
     }
 
-    let total_collateral = calculate_updated_collateral(user.collateral, unrealized_pnl)?;
+    let total_collateral = calculate_total_collateral(collateral, unrealized_pnl)?;
 
     Ok((margin_requirement, total_collateral))
 }
 
 pub fn meets_initial_margin_requirement(
     user: &User,
-    user_positions: &RefMut<UserPositions>,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
     markets: &Ref<Markets>,
 ) -> ClearingHouseResult<bool> {
     let (mut initial_margin_requirement, total_collateral) =
         calculate_margin_requirement_and_total_collateral(
             user,
             user_positions,
+            spot_balances_value,
             markets,
             MarginType::Init,
         )?;
@@ -89,13 +200,15 @@ pub fn meets_initial_margin_requirement(
 
 pub fn meets_partial_margin_requirement(
     user: &User,
-    user_positions: &RefMut<UserPositions>,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
     markets: &Ref<Markets>,
 ) -> ClearingHouseResult<bool> {
     let (mut partial_margin_requirement, total_collateral) =
         calculate_margin_requirement_and_total_collateral(
             user,
             user_positions,
+            spot_balances_value,
             markets,
             MarginType::Partial,
         )?;
@@ -107,6 +220,42 @@ pub fn meets_partial_margin_requirement(
     Ok(total_collateral >= partial_margin_requirement)
 }
 
+/// Checks the margin requirement that a trade in the market affects: the isolated position's own
+/// collateral if the position is isolated, otherwise the user's cross margin collateral
+pub fn meets_margin_requirement_for_market(
+    user: &User,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
+    markets: &Ref<Markets>,
+    market_index: u64,
+    margin_type: MarginType,
+) -> ClearingHouseResult<bool> {
+    let isolated_market_position = user_positions.iter().find(|market_position| {
+        market_position.market_index == market_index && market_position.is_isolated()
+    });
+
+    let (mut margin_requirement, total_collateral) = match isolated_market_position {
+        Some(market_position) => calculate_isolated_margin_requirement_and_total_collateral(
+            market_position,
+            markets,
+            margin_type,
+        )?,
+        None => calculate_margin_requirement_and_total_collateral(
+            user,
+            user_positions,
+            spot_balances_value,
+            markets,
+            margin_type,
+        )?,
+    };
+
+    margin_requirement = margin_requirement
+        .checked_div(MARGIN_PRECISION)
+        .ok_or_else(math_error!())?;
+
+    Ok(total_collateral >= margin_requirement)
+}
+
 #[derive(PartialEq)]
 pub enum LiquidationType {
     NONE,
@@ -122,7 +271,7 @@ pub struct LiquidationStatus {
     pub adjusted_total_collateral: u128,
     pub base_asset_value: u128,
     pub margin_ratio: u128,
-    pub market_statuses: [MarketStatus; 5],
+    pub market_statuses: Vec<MarketStatus>,
 }
 
 #[derive(Default, Clone, Copy, Debug)]
@@ -138,7 +287,43 @@ pub struct MarketStatus {
 
 pub fn calculate_liquidation_status(
     user: &User,
-    user_positions: &RefMut<UserPositions>,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
+    markets: &Ref<Markets>,
+    remaining_accounts: &[AccountInfo],
+    oracle_guard_rails: &OracleGuardRails,
+    clock_slot: Slot,
+) -> ClearingHouseResult<LiquidationStatus> {
+    calculate_liquidation_status_for_positions(
+        cross_margin_collateral(user, spot_balances_value)?,
+        cross_margin_positions(user_positions),
+        markets,
+        remaining_accounts,
+        oracle_guard_rails,
+        clock_slot,
+    )
+}
+
+pub fn calculate_isolated_liquidation_status(
+    market_position: &MarketPosition,
+    markets: &Ref<Markets>,
+    remaining_accounts: &[AccountInfo],
+    oracle_guard_rails: &OracleGuardRails,
+    clock_slot: Slot,
+) -> ClearingHouseResult<LiquidationStatus> {
+    calculate_liquidation_status_for_positions(
+        cast_to_i128(market_position.isolated_collateral)?,
+        std::iter::once(market_position),
+        markets,
+        remaining_accounts,
+        oracle_guard_rails,
+        clock_slot,
+    )
+}
+
+fn calculate_liquidation_status_for_positions<'a>(
+    collateral: i128,
+    market_positions: impl Iterator<Item = &'a MarketPosition>,
     markets: &Ref<Markets>,
     remaining_accounts: &[AccountInfo],
     oracle_guard_rails: &OracleGuardRails,
@@ -149,14 +334,14 @@ pub fn calculate_liquidation_status(
     let mut base_asset_value: u128 = 0;
     let mut unrealized_pnl: i128 = 0;
     let mut adjusted_unrealized_pnl: i128 = 0;
-    let mut market_statuses = [MarketStatus::default(); 5];
+    let mut market_statuses: Vec<MarketStatus> = Vec::new();
 
     let mut oracle_account_infos: BTreeMap<Pubkey, &AccountInfo> = BTreeMap::new();
     for account_info in remaining_accounts.iter() {
         oracle_account_infos.insert(account_info.key(), account_info);
     }
 
-    for (i, market_position) in user_positions.positions.iter().enumerate() {
+    for market_position in market_positions {
         if market_position.base_asset_amount == 0 {
             continue;
         }
@@ -226,7 +411,14 @@ pub fn calculate_liquidation_status(
                     .ok_or_else(math_error!())?;
 
                 market_partial_margin_requirement = (oracle_position_base_asset_value)
-                    .checked_mul(market.margin_ratio_partial.into())
+                    .checked_mul(
+                        market
+                            .get_margin_ratio(
+                                oracle_position_base_asset_value,
+                                MarginType::Partial,
+                            )?
+                            .into(),
+                    )
                     .ok_or_else(math_error!())?;
 
                 partial_margin_requirement = partial_margin_requirement
@@ -234,7 +426,11 @@ pub fn calculate_liquidation_status(
                     .ok_or_else(math_error!())?;
 
                 market_maintenance_margin_requirement = oracle_position_base_asset_value
-                    .checked_mul(market.margin_ratio_maintenance.into())
+                    .checked_mul(
+                        market
+                            .get_margin_ratio(oracle_position_base_asset_value, MarginType::Maint)?
+                            .into(),
+                    )
                     .ok_or_else(math_error!())?;
 
                 maintenance_margin_requirement = maintenance_margin_requirement
@@ -246,7 +442,11 @@ pub fn calculate_liquidation_status(
                     .ok_or_else(math_error!())?;
 
                 market_partial_margin_requirement = (amm_position_base_asset_value)
-                    .checked_mul(market.margin_ratio_partial.into())
+                    .checked_mul(
+                        market
+                            .get_margin_ratio(amm_position_base_asset_value, MarginType::Partial)?
+                            .into(),
+                    )
                     .ok_or_else(math_error!())?;
 
                 partial_margin_requirement = partial_margin_requirement
@@ -254,7 +454,11 @@ pub fn calculate_liquidation_status(
                     .ok_or_else(math_error!())?;
 
                 market_maintenance_margin_requirement = amm_position_base_asset_value
-                    .checked_mul(market.margin_ratio_maintenance.into())
+                    .checked_mul(
+                        market
+                            .get_margin_ratio(amm_position_base_asset_value, MarginType::Maint)?
+                            .into(),
+                    )
                     .ok_or_else(math_error!())?;
 
                 maintenance_margin_requirement = maintenance_margin_requirement
@@ -267,7 +471,11 @@ pub fn calculate_liquidation_status(
                 .ok_or_else(math_error!())?;
 
             market_partial_margin_requirement = (amm_position_base_asset_value)
-                .checked_mul(market.margin_ratio_partial.into())
+                .checked_mul(
+                    market
+                        .get_margin_ratio(amm_position_base_asset_value, MarginType::Partial)?
+                        .into(),
+                )
                 .ok_or_else(math_error!())?;
 
             partial_margin_requirement = partial_margin_requirement
@@ -275,7 +483,11 @@ pub fn calculate_liquidation_status(
                 .ok_or_else(math_error!())?;
 
             market_maintenance_margin_requirement = amm_position_base_asset_value
-                .checked_mul(market.margin_ratio_maintenance.into())
+                .checked_mul(
+                    market
+                        .get_margin_ratio(amm_position_base_asset_value, MarginType::Maint)?
+                        .into(),
+                )
                 .ok_or_else(math_error!())?;
 
             maintenance_margin_requirement = maintenance_margin_requirement
@@ -283,7 +495,7 @@ pub fn calculate_liquidation_status(
                 .ok_or_else(math_error!())?;
         }
 
-        market_statuses[i] = MarketStatus {
+        market_statuses.push(MarketStatus {
             market_index: market_position.market_index,
             partial_margin_requirement: market_partial_margin_requirement.div(MARGIN_PRECISION),
             maintenance_margin_requirement: market_maintenance_margin_requirement
@@ -292,7 +504,7 @@ pub fn calculate_liquidation_status(
             mark_price_before,
             oracle_status,
             close_position_slippage,
-        };
+        });
     }
 
     partial_margin_requirement = partial_margin_requirement
@@ -303,9 +515,9 @@ pub fn calculate_liquidation_status(
         .checked_div(MARGIN_PRECISION)
         .ok_or_else(math_error!())?;
 
-    let total_collateral = calculate_updated_collateral(user.collateral, unrealized_pnl)?;
+    let total_collateral = calculate_total_collateral(collateral, unrealized_pnl)?;
     let adjusted_total_collateral =
-        calculate_updated_collateral(user.collateral, adjusted_unrealized_pnl)?;
+        calculate_total_collateral(collateral, adjusted_unrealized_pnl)?;
 
     let requires_partial_liquidation = adjusted_total_collateral < partial_margin_requirement;
     let requires_full_liquidation = adjusted_total_collateral < maintenance_margin_requirement;
@@ -361,7 +573,35 @@ pub fn calculate_liquidation_status(
 
 pub fn calculate_free_collateral(
     user: &User,
-    user_positions: &mut UserPositions,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
+    markets: &Markets,
+    market_to_close: Option<u64>,
+) -> ClearingHouseResult<(u128, u128)> {
+    calculate_free_collateral_for_positions(
+        cross_margin_collateral(user, spot_balances_value)?,
+        cross_margin_positions(user_positions),
+        markets,
+        market_to_close,
+    )
+}
+
+pub fn calculate_isolated_free_collateral(
+    market_position: &MarketPosition,
+    markets: &Markets,
+    market_to_close: Option<u64>,
+) -> ClearingHouseResult<(u128, u128)> {
+    calculate_free_collateral_for_positions(
+        cast_to_i128(market_position.isolated_collateral)?,
+        std::iter::once(market_position),
+        markets,
+        market_to_close,
+    )
+}
+
+fn calculate_free_collateral_for_positions<'a>(
+    collateral: i128,
+    market_positions: impl Iterator<Item = &'a MarketPosition>,
     markets: &Markets,
     market_to_close: Option<u64>,
 ) -> ClearingHouseResult<(u128, u128)> {
@@ -369,7 +609,7 @@ pub fn calculate_free_collateral(
     let mut initial_margin_requirement: u128 = 0;
     let mut unrealized_pnl: i128 = 0;
 
-    for market_position in user_positions.positions.iter() {
+    for market_position in market_positions {
         if market_position.base_asset_amount == 0 {
             continue;
         }
@@ -385,7 +625,11 @@ pub fn calculate_free_collateral(
             initial_margin_requirement = initial_margin_requirement
                 .checked_add(
                     position_base_asset_value
-                        .checked_mul(market.margin_ratio_initial.into())
+                        .checked_mul(
+                            market
+                                .get_margin_ratio(position_base_asset_value, MarginType::Init)?
+                                .into(),
+                        )
                         .ok_or_else(math_error!())?,
                 )
                 .ok_or_else(math_error!())?;
@@ -400,7 +644,7 @@ pub fn calculate_free_collateral(
         .checked_div(MARGIN_PRECISION)
         .ok_or_else(math_error!())?;
 
-    let total_collateral = calculate_updated_collateral(user.collateral, unrealized_pnl)?;
+    let total_collateral = calculate_total_collateral(collateral, unrealized_pnl)?;
 
     let free_collateral = if initial_margin_requirement < total_collateral {
         total_collateral
@@ -412,3 +656,102 @@ pub fn calculate_free_collateral(
 
     Ok((free_collateral, closed_position_base_asset_value))
 }
+
+/// The amount the user's quote collateral falls short of covering their quote borrows and the
+/// unrealized losses of their cross margin positions. The shortfall is covered by swapping the
+/// user's collateral assets.
+pub fn calculate_quote_collateral_deficit(
+    user: &User,
+    user_positions: &[MarketPosition],
+    quote_borrows: u128,
+    markets: &Markets,
+) -> ClearingHouseResult<u128> {
+    let mut unrealized_pnl: i128 = 0;
+    for market_position in cross_margin_positions(user_positions) {
+        if market_position.base_asset_amount == 0 {
+            continue;
+        }
+
+        let market = markets.get_market(market_position.market_index);
+        let (_, position_unrealized_pnl) =
+            calculate_base_asset_value_and_pnl(market_position, &market.amm)?;
+
+        unrealized_pnl = unrealized_pnl
+            .checked_add(position_unrealized_pnl)
+            .ok_or_else(math_error!())?;
+    }
+
+    let quote_collateral = cast_to_i128(user.collateral)?
+        .checked_add(unrealized_pnl)
+        .ok_or_else(math_error!())?
+        .checked_sub(cast_to_i128(quote_borrows)?)
+        .ok_or_else(math_error!())?;
+
+    Ok(if quote_collateral < 0 {
+        quote_collateral.unsigned_abs()
+    } else {
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_size_premium_without_imf_factor() {
+        let margin_ratio =
+            calculate_size_adjusted_margin_ratio(2000, 2000, 0, 1_000_000 * QUOTE_PRECISION)
+                .unwrap();
+        assert_eq!(margin_ratio, 2000);
+    }
+
+    #[test]
+    fn size_premium_grows_with_square_root_of_value() {
+        // 100000 * sqrt(49) / 1000
+        let margin_ratio =
+            calculate_size_adjusted_margin_ratio(2000, 2000, 100_000, 49 * QUOTE_PRECISION)
+                .unwrap();
+        assert_eq!(margin_ratio, 2700);
+
+        let margin_ratio =
+            calculate_size_adjusted_margin_ratio(2000, 2000, 100_000, 196 * QUOTE_PRECISION)
+                .unwrap();
+        assert_eq!(margin_ratio, 3400);
+    }
+
+    #[test]
+    fn size_adjusted_margin_ratio_capped_at_maximum() {
+        let margin_ratio =
+            calculate_size_adjusted_margin_ratio(2000, 2000, 100_000, 1_000_000 * QUOTE_PRECISION)
+                .unwrap();
+        assert_eq!(margin_ratio, MAXIMUM_MARGIN_RATIO);
+    }
+
+    #[test]
+    fn size_adjusted_margin_ratios_stay_ordered() {
+        for base_asset_value in [49, 196, 10_000, 1_000_000] {
+            let base_asset_value = base_asset_value * QUOTE_PRECISION;
+            let [initial, partial, maintenance] = [2000, 625, 500].map(|margin_ratio| {
+                calculate_size_adjusted_margin_ratio(margin_ratio, 2000, 100_000, base_asset_value)
+                    .unwrap()
+            });
+            assert!(initial > partial);
+            assert!(partial > maintenance);
+        }
+
+        // past the cap the partial and maintenance ratios keep their share of the initial one
+        let [initial, partial, maintenance] = [2000, 625, 500].map(|margin_ratio| {
+            calculate_size_adjusted_margin_ratio(
+                margin_ratio,
+                2000,
+                100_000,
+                1_000_000 * QUOTE_PRECISION,
+            )
+            .unwrap()
+        });
+        assert_eq!(initial, MAXIMUM_MARGIN_RATIO);
+        assert_eq!(partial, 3125);
+        assert_eq!(maintenance, 2500);
+    }
+}
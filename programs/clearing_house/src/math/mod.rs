@@ -1,10 +1,15 @@
 pub mod amm;
+pub mod bankruptcy;
 pub mod bn;
 pub mod casting;
 pub mod collateral;
 pub mod constants;
+pub mod curve;
 pub mod fees;
 pub mod funding;
+pub mod insurance_fund;
+pub mod lending;
+pub mod liquidation;
 pub mod margin;
 pub mod oracle;
 pub mod orders;
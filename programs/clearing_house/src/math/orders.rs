@@ -2,10 +2,10 @@ use crate::error::{ClearingHouseResult, ErrorCode};
 use crate::math;
 use crate::math_error;
 use crate::state::market::Market;
-use crate::state::user_orders::{Order, OrderTriggerCondition, OrderType};
+use crate::state::user_orders::{Order, OrderTriggerCondition, OrderType, TrailingDistanceType};
 use solana_program::msg;
 use std::cell::RefMut;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::ops::Div;
 
 use crate::controller::amm::SwapDirection;
@@ -14,13 +14,13 @@ use crate::controller::position::PositionDirection;
 use crate::math::amm::{calculate_spread_reserves, calculate_swap_output};
 use crate::math::casting::{cast, cast_to_i128, cast_to_u128};
 use crate::math::constants::{
-    AMM_TO_QUOTE_PRECISION_RATIO, MARGIN_PRECISION, MARK_PRICE_PRECISION,
+    AMM_TO_QUOTE_PRECISION_RATIO, BASIS_POINTS_PRECISION, MARGIN_PRECISION, MARK_PRICE_PRECISION,
     MARK_PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO,
 };
-use crate::math::margin::calculate_free_collateral;
+use crate::math::margin::{calculate_free_collateral, calculate_isolated_free_collateral};
 use crate::math::quote_asset::asset_to_reserve_amount;
 use crate::state::market::Markets;
-use crate::state::user::{User, UserPositions};
+use crate::state::user::{MarketPosition, User};
 
 pub fn calculate_base_asset_amount_market_can_execute(
     order: &Order,
@@ -44,6 +44,15 @@ pub fn calculate_base_asset_amount_market_can_execute(
             precomputed_mark_price,
             valid_oracle_price,
         ),
+        OrderType::TrailingStop => calculate_base_asset_amount_to_trade_for_trigger_market(
+            order,
+            market,
+            precomputed_mark_price,
+            valid_oracle_price,
+        ),
+        OrderType::Twap => {
+            calculate_base_asset_amount_to_trade_for_twap(order, market, valid_oracle_price)
+        }
         OrderType::Market => Err(ErrorCode::InvalidOrder),
     }
 }
@@ -76,6 +85,20 @@ pub fn calculate_base_asset_amount_to_trade_for_limit(
     Ok(base_asset_amount_to_trade)
 }
 
+fn calculate_base_asset_amount_to_trade_for_twap(
+    order: &Order,
+    market: &Market,
+    valid_oracle_price: Option<i128>,
+) -> ClearingHouseResult<u128> {
+    let base_asset_amount_to_trade =
+        calculate_base_asset_amount_to_trade_for_limit(order, market, valid_oracle_price)?;
+
+    Ok(min(
+        base_asset_amount_to_trade,
+        order.slice_base_asset_amount,
+    ))
+}
+
 fn calculate_base_asset_amount_to_trade_for_trigger_market(
     order: &Order,
     market: &Market,
@@ -156,9 +179,59 @@ fn calculate_base_asset_amount_to_trade_for_trigger_limit(
     calculate_base_asset_amount_to_trade_for_limit(order, market, None)
 }
 
+/// Returns the trailing stop's new trigger price if the price moved in the position's favor far
+/// enough to ratchet it. Longs trail above the price and shorts trail below it, so the trigger
+/// price only moves toward the price. When the oracle is valid, the less favorable of the mark and
+/// oracle price is used so a single manipulated price can't move the stop.
+pub fn calculate_trailing_stop_trigger_price(
+    order: &Order,
+    mark_price: u128,
+    valid_oracle_price: Option<i128>,
+) -> ClearingHouseResult<Option<u128>> {
+    let price = match valid_oracle_price {
+        Some(oracle_price) => {
+            let oracle_price = cast_to_u128(oracle_price)?;
+            match order.direction {
+                PositionDirection::Long => max(mark_price, oracle_price),
+                PositionDirection::Short => min(mark_price, oracle_price),
+            }
+        }
+        None => mark_price,
+    };
+
+    let trailing_distance = match order.trailing_distance_type {
+        TrailingDistanceType::Absolute => order.trailing_distance,
+        TrailingDistanceType::BasisPoints => price
+            .checked_mul(order.trailing_distance)
+            .ok_or_else(math_error!())?
+            .checked_div(BASIS_POINTS_PRECISION)
+            .ok_or_else(math_error!())?,
+    };
+
+    let trigger_price = match order.direction {
+        PositionDirection::Long => price
+            .checked_add(trailing_distance)
+            .ok_or_else(math_error!())?,
+        PositionDirection::Short => price.saturating_sub(trailing_distance),
+    };
+
+    let improves_trigger_price = trigger_price > 0
+        && match order.direction {
+            PositionDirection::Long => trigger_price < order.trigger_price,
+            PositionDirection::Short => trigger_price > order.trigger_price,
+        };
+
+    Ok(if improves_trigger_price {
+        Some(trigger_price)
+    } else {
+        None
+    })
+}
+
 pub fn calculate_base_asset_amount_user_can_execute(
     user: &mut User,
-    user_positions: &mut RefMut<UserPositions>,
+    user_positions: &mut [MarketPosition],
+    spot_balances_value: i128,
     order: &mut Order,
     markets: &mut RefMut<Markets>,
     market_index: u64,
@@ -170,6 +243,7 @@ pub fn calculate_base_asset_amount_user_can_execute(
         order,
         position_index,
         user_positions,
+        spot_balances_value,
         markets,
     )?;
 
@@ -204,7 +278,7 @@ pub fn calculate_base_asset_amount_user_can_execute(
         quote_asset_reserve_amount,
         quote_asset_reserves_before,
         swap_direction,
-        market.amm.sqrt_k,
+        &market.amm,
     )?;
 
     let mut base_asset_amount = cast_to_i128(base_asset_reserves_before)?
@@ -213,7 +287,7 @@ pub fn calculate_base_asset_amount_user_can_execute(
         .unsigned_abs();
 
     if order.reduce_only && base_asset_amount != 0 {
-        let existing_position = user_positions.positions[position_index].base_asset_amount;
+        let existing_position = user_positions[position_index].base_asset_amount;
         base_asset_amount = calculate_base_asset_amount_for_reduce_only_order(
             base_asset_amount,
             order.direction,
@@ -224,14 +298,132 @@ pub fn calculate_base_asset_amount_user_can_execute(
     Ok(base_asset_amount)
 }
 
+pub fn calculate_base_asset_amount_user_can_execute_at_price(
+    user: &User,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
+    order: &Order,
+    markets: &Markets,
+    market_index: u64,
+    price: u128,
+) -> ClearingHouseResult<u128> {
+    let position_index = user_positions
+        .iter()
+        .position(|market_position| market_position.is_for(market_index))
+        .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+
+    let quote_asset_amount = calculate_available_quote_asset_user_can_execute(
+        user,
+        order,
+        position_index,
+        user_positions,
+        spot_balances_value,
+        markets,
+    )?;
+
+    let mut base_asset_amount = quote_asset_amount
+        .checked_mul(MARK_PRICE_TIMES_AMM_TO_QUOTE_PRECISION_RATIO)
+        .ok_or_else(math_error!())?
+        .checked_div(price)
+        .ok_or_else(math_error!())?;
+
+    if order.reduce_only && base_asset_amount != 0 {
+        let existing_position = user_positions[position_index].base_asset_amount;
+        base_asset_amount = calculate_base_asset_amount_for_reduce_only_order(
+            base_asset_amount,
+            order.direction,
+            existing_position,
+        )
+    }
+
+    Ok(base_asset_amount)
+}
+
+/// The base asset amount a taker can fill against a maker at the maker's price. Market orders
+/// aren't limited by free collateral here since they're checked against the margin requirement
+/// after the fill.
+pub fn calculate_base_asset_amount_taker_can_execute_at_price(
+    user: &User,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
+    order: &Order,
+    markets: &Markets,
+    market_index: u64,
+    price: u128,
+) -> ClearingHouseResult<u128> {
+    let base_asset_amount_unfilled = order
+        .base_asset_amount
+        .checked_sub(order.base_asset_amount_filled)
+        .ok_or_else(math_error!())?;
+
+    let base_asset_amount = match order.order_type {
+        OrderType::Market if order.reduce_only => {
+            let market_position = user_positions
+                .iter()
+                .find(|market_position| market_position.is_for(market_index))
+                .ok_or(ErrorCode::UserHasNoPositionInMarket)?;
+            calculate_base_asset_amount_for_reduce_only_order(
+                base_asset_amount_unfilled,
+                order.direction,
+                market_position.base_asset_amount,
+            )
+        }
+        OrderType::Market => base_asset_amount_unfilled,
+        _ => min(
+            base_asset_amount_unfilled,
+            calculate_base_asset_amount_user_can_execute_at_price(
+                user,
+                user_positions,
+                spot_balances_value,
+                order,
+                markets,
+                market_index,
+                price,
+            )?,
+        ),
+    };
+
+    Ok(base_asset_amount)
+}
+
+/// Splits the base asset amount left to fill between a just in time maker and the amm. The maker
+/// fills first, up to the amount it offered, and the amm fills the rest. The maker's share is
+/// reduced so that neither leg is below the minimum trade size.
+pub fn calculate_jit_maker_base_asset_amount_split(
+    base_asset_amount: u128,
+    maker_base_asset_amount: u128,
+    minimum_base_asset_trade_size: u128,
+) -> ClearingHouseResult<(u128, u128)> {
+    let mut jit_base_asset_amount = min(base_asset_amount, maker_base_asset_amount);
+
+    let amm_base_asset_amount = base_asset_amount
+        .checked_sub(jit_base_asset_amount)
+        .ok_or_else(math_error!())?;
+    if amm_base_asset_amount > 0 && amm_base_asset_amount < minimum_base_asset_trade_size {
+        // leave the amm enough to fill the minimum trade size
+        jit_base_asset_amount = base_asset_amount.saturating_sub(minimum_base_asset_trade_size);
+    }
+
+    if jit_base_asset_amount < minimum_base_asset_trade_size {
+        jit_base_asset_amount = 0;
+    }
+
+    let amm_base_asset_amount = base_asset_amount
+        .checked_sub(jit_base_asset_amount)
+        .ok_or_else(math_error!())?;
+
+    Ok((jit_base_asset_amount, amm_base_asset_amount))
+}
+
 pub fn calculate_available_quote_asset_user_can_execute(
     user: &User,
     order: &Order,
     position_index: usize,
-    user_positions: &mut UserPositions,
+    user_positions: &[MarketPosition],
+    spot_balances_value: i128,
     markets: &Markets,
 ) -> ClearingHouseResult<u128> {
-    let market_position = &user_positions.positions[position_index];
+    let market_position = &user_positions[position_index];
     let market = markets.get_market(market_position.market_index);
     let max_leverage = MARGIN_PRECISION
         .checked_div(
@@ -247,15 +439,28 @@ pub fn calculate_available_quote_asset_user_can_execute(
         || market_position.base_asset_amount < 0 && order.direction == PositionDirection::Short;
 
     let available_quote_asset_for_order = if risk_increasing_in_same_direction {
-        let (free_collateral, _) = calculate_free_collateral(user, user_positions, markets, None)?;
+        let (free_collateral, _) = if market_position.is_isolated() {
+            calculate_isolated_free_collateral(market_position, markets, None)?
+        } else {
+            calculate_free_collateral(user, user_positions, spot_balances_value, markets, None)?
+        };
 
         free_collateral
             .checked_mul(max_leverage)
             .ok_or_else(math_error!())?
     } else {
         let market_index = market_position.market_index;
-        let (free_collateral, closed_position_base_asset_value) =
-            calculate_free_collateral(user, user_positions, markets, Some(market_index))?;
+        let (free_collateral, closed_position_base_asset_value) = if market_position.is_isolated() {
+            calculate_isolated_free_collateral(market_position, markets, Some(market_index))?
+        } else {
+            calculate_free_collateral(
+                user,
+                user_positions,
+                spot_balances_value,
+                markets,
+                Some(market_index),
+            )?
+        };
 
         free_collateral
             .checked_mul(max_leverage)
@@ -294,6 +499,28 @@ pub fn limit_price_satisfied(
     Ok(true)
 }
 
+/// Whether a maker's price can fill a taker's order. It has to cross the taker's limit price, if
+/// the taker set one, and be at least as good for the taker as the amm's mark price, so a market
+/// order without a limit price is never filled by a maker at a worse price than the amm's.
+pub fn maker_price_fills_taker(
+    taker_direction: PositionDirection,
+    maker_price: u128,
+    taker_limit_price: u128,
+    mark_price: u128,
+) -> bool {
+    let crosses_taker_price = taker_limit_price == 0
+        || match taker_direction {
+            PositionDirection::Long => maker_price <= taker_limit_price,
+            PositionDirection::Short => maker_price >= taker_limit_price,
+        };
+    let improves_on_amm_price = match taker_direction {
+        PositionDirection::Long => maker_price <= mark_price,
+        PositionDirection::Short => maker_price >= mark_price,
+    };
+
+    maker_price != 0 && crosses_taker_price && improves_on_amm_price
+}
+
 pub fn calculate_quote_asset_amount_for_maker_order(
     base_asset_amount: u128,
     limit_price: u128,
@@ -329,3 +556,61 @@ pub fn calculate_base_asset_amount_for_reduce_only_order(
         min(proposed_base_asset_amount, existing_position.unsigned_abs())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maker_price_must_cross_taker_limit_price() {
+        let mark_price = 100 * MARK_PRICE_PRECISION;
+        assert!(maker_price_fills_taker(
+            PositionDirection::Long,
+            99 * MARK_PRICE_PRECISION,
+            99 * MARK_PRICE_PRECISION,
+            mark_price
+        ));
+        assert!(!maker_price_fills_taker(
+            PositionDirection::Long,
+            99 * MARK_PRICE_PRECISION,
+            98 * MARK_PRICE_PRECISION,
+            mark_price
+        ));
+        assert!(!maker_price_fills_taker(
+            PositionDirection::Short,
+            101 * MARK_PRICE_PRECISION,
+            102 * MARK_PRICE_PRECISION,
+            mark_price
+        ));
+    }
+
+    #[test]
+    fn maker_price_must_improve_on_amm_price() {
+        let mark_price = 100 * MARK_PRICE_PRECISION;
+        // a market order without a limit price is capped at the mark price
+        assert!(maker_price_fills_taker(
+            PositionDirection::Long,
+            mark_price,
+            0,
+            mark_price
+        ));
+        assert!(!maker_price_fills_taker(
+            PositionDirection::Long,
+            1000 * MARK_PRICE_PRECISION,
+            0,
+            mark_price
+        ));
+        assert!(!maker_price_fills_taker(
+            PositionDirection::Short,
+            MARK_PRICE_PRECISION,
+            0,
+            mark_price
+        ));
+        assert!(!maker_price_fills_taker(
+            PositionDirection::Long,
+            101 * MARK_PRICE_PRECISION,
+            102 * MARK_PRICE_PRECISION,
+            mark_price
+        ));
+    }
+}
@@ -11,7 +11,7 @@ use crate::math::pnl::calculate_pnl;
 use crate::math_error;
 use crate::settlement_ratios::{SETTLEMENT_RATIOS, SETTLEMENT_RATIO_PRECISION};
 use crate::state::market::{Markets, AMM};
-use crate::state::user::{MarketPosition, User, UserPositions};
+use crate::state::user::{MarketPosition, User};
 
 pub fn calculate_base_asset_value_and_pnl(
     market_position: &MarketPosition,
@@ -39,7 +39,7 @@ pub fn _calculate_base_asset_value_and_pnl(
         base_asset_amount.unsigned_abs(),
         amm.base_asset_reserve,
         swap_direction,
-        amm.sqrt_k,
+        amm,
     )?;
 
     let base_asset_value = calculate_quote_asset_amount_swapped(
@@ -87,6 +87,33 @@ pub fn calculate_base_asset_value_and_pnl_with_oracle_price(
     Ok((base_asset_value, pnl))
 }
 
+/// The oracle price discounted in the favor of the liquidator taking over the position
+pub fn calculate_position_transfer_price(
+    oracle_price: i128,
+    base_asset_amount: i128,
+    discount_numerator: u128,
+    discount_denominator: u128,
+) -> ClearingHouseResult<u128> {
+    let oracle_price = if oracle_price > 0 {
+        oracle_price.unsigned_abs()
+    } else {
+        0
+    };
+
+    let discount = oracle_price
+        .checked_mul(discount_numerator)
+        .ok_or_else(math_error!())?
+        .checked_div(discount_denominator)
+        .ok_or_else(math_error!())?;
+
+    // the liquidator buys longs below the oracle price and sells shorts above it
+    if base_asset_amount > 0 {
+        oracle_price.checked_sub(discount).ok_or_else(math_error!())
+    } else {
+        oracle_price.checked_add(discount).ok_or_else(math_error!())
+    }
+}
+
 pub fn direction_to_close_position(base_asset_amount: i128) -> PositionDirection {
     if base_asset_amount > 0 {
         PositionDirection::Short
@@ -105,13 +132,17 @@ pub fn swap_direction_to_close_position(base_asset_amount: i128) -> SwapDirectio
 
 pub fn calculated_settled_position_value(
     user: &User,
-    user_positions: &UserPositions,
+    user_positions: &[MarketPosition],
     markets: &Markets,
 ) -> ClearingHouseResult<u128> {
     let mut pnl: i128 = 0;
+    let mut isolated_position_value: u128 = 0;
 
-    for market_position in user_positions.positions.iter() {
+    for market_position in user_positions.iter() {
         if market_position.base_asset_amount == 0 {
+            isolated_position_value = isolated_position_value
+                .checked_add(market_position.isolated_collateral)
+                .ok_or_else(math_error!())?;
             continue;
         }
 
@@ -129,10 +160,49 @@ pub fn calculated_settled_position_value(
             position_pnl
         };
 
+        // an isolated position's losses are limited to its own collateral
+        if market_position.is_isolated() {
+            isolated_position_value = isolated_position_value
+                .checked_add(calculate_updated_collateral(
+                    market_position.isolated_collateral,
+                    position_pnl,
+                )?)
+                .ok_or_else(math_error!())?;
+            continue;
+        }
+
         pnl = pnl.checked_add(position_pnl).ok_or_else(math_error!())?;
     }
 
-    let settled_position_value = calculate_updated_collateral(user.collateral, pnl)?;
+    let settled_position_value = calculate_updated_collateral(user.collateral, pnl)?
+        .checked_add(isolated_position_value)
+        .ok_or_else(math_error!())?;
 
     Ok(settled_position_value)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::MARK_PRICE_PRECISION;
+
+    #[test]
+    fn long_transferred_below_oracle_price() {
+        let transfer_price =
+            calculate_position_transfer_price(MARK_PRICE_PRECISION as i128, 1, 1, 100).unwrap();
+        assert_eq!(transfer_price, MARK_PRICE_PRECISION * 99 / 100);
+    }
+
+    #[test]
+    fn short_transferred_above_oracle_price() {
+        let transfer_price =
+            calculate_position_transfer_price(MARK_PRICE_PRECISION as i128, -1, 1, 100).unwrap();
+        assert_eq!(transfer_price, MARK_PRICE_PRECISION * 101 / 100);
+    }
+
+    #[test]
+    fn negative_oracle_price_transferred_at_zero() {
+        let transfer_price = calculate_position_transfer_price(-1, 1, 1, 100).unwrap();
+        assert_eq!(transfer_price, 0);
+    }
+}
@@ -1,6 +1,15 @@
 use crate::error::ClearingHouseResult;
+use crate::math::amm::adjust_k_cost;
+use crate::math::bn::{U192, U256};
+use crate::math::casting::{cast_to_i128, cast_to_u128};
+use crate::math::constants::{
+    MAX_BUDGETED_K_RESCALES, SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR,
+    SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR,
+};
 use crate::math::position::_calculate_base_asset_value_and_pnl;
-use crate::state::market::Market;
+use crate::math_error;
+use crate::state::market::{Market, AMM};
+use solana_program::msg;
 
 pub fn adjust_peg_cost(market: &mut Market, new_peg: u128) -> ClearingHouseResult<i128> {
     // Find the net market value before adjusting peg
@@ -17,3 +26,249 @@ pub fn adjust_peg_cost(market: &mut Market, new_peg: u128) -> ClearingHouseResul
 
     Ok(cost)
 }
+
+/// The peg that puts the mark price at the oracle price for the current reserves. Mark price is
+/// linear in the peg for every curve, so the peg scales by oracle price / mark price
+pub fn calculate_optimal_peg(amm: &AMM, oracle_price: i128) -> ClearingHouseResult<u128> {
+    let oracle_price = cast_to_u128(oracle_price)?;
+
+    U192::from(oracle_price)
+        .checked_mul(U192::from(amm.peg_multiplier))
+        .ok_or_else(math_error!())?
+        .checked_div(U192::from(amm.mark_price()?))
+        .ok_or_else(math_error!())?
+        .try_to_u128()
+}
+
+/// The fees that can be spent on repegging without dipping into the clearing house's share
+pub fn calculate_fee_pool_budget(amm: &AMM) -> ClearingHouseResult<u128> {
+    let minimum_fee_pool = amm
+        .total_fee
+        .checked_mul(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_NUMERATOR)
+        .ok_or_else(math_error!())?
+        .checked_div(SHARE_OF_FEES_ALLOCATED_TO_CLEARING_HOUSE_DENOMINATOR)
+        .ok_or_else(math_error!())?;
+
+    Ok(amm
+        .total_fee_minus_distributions
+        .saturating_sub(minimum_fee_pool))
+}
+
+/// Moves the peg toward optimal_peg as far as budget allows. The cost of a repeg is linear in the
+/// peg change, so when the full move is too expensive the peg moves budget / full cost of the way.
+/// Returns the new peg and its cost
+pub fn calculate_budgeted_peg(
+    market: &Market,
+    optimal_peg: u128,
+    budget: u128,
+) -> ClearingHouseResult<(u128, i128)> {
+    let current_peg = market.amm.peg_multiplier;
+    let full_cost = adjust_peg_cost(&mut { *market }, optimal_peg)?;
+
+    if full_cost <= 0 || full_cost.unsigned_abs() <= budget {
+        return Ok((optimal_peg, full_cost));
+    }
+
+    if budget == 0 {
+        return Ok((current_peg, 0));
+    }
+
+    let peg_change = cast_to_i128(optimal_peg)?
+        .checked_sub(cast_to_i128(current_peg)?)
+        .ok_or_else(math_error!())?;
+
+    let budgeted_peg_change = peg_change
+        .checked_mul(cast_to_i128(budget)?)
+        .ok_or_else(math_error!())?
+        .checked_div(full_cost)
+        .ok_or_else(math_error!())?;
+
+    let budgeted_peg = cast_to_u128(
+        cast_to_i128(current_peg)?
+            .checked_add(budgeted_peg_change)
+            .ok_or_else(math_error!())?,
+    )?;
+
+    let budgeted_cost = adjust_peg_cost(&mut { *market }, budgeted_peg)?;
+
+    Ok((budgeted_peg, budgeted_cost))
+}
+
+/// Scales sqrt_k up (or down) by at most max_change_numerator / max_change_denominator, shrinking
+/// the change until its cost fits within budget. Returns None if no change fits
+pub fn calculate_budgeted_k(
+    market: &Market,
+    increase: bool,
+    budget: u128,
+    max_change_numerator: u128,
+    max_change_denominator: u128,
+) -> ClearingHouseResult<Option<(u128, i128)>> {
+    let sqrt_k = market.amm.sqrt_k;
+    let mut sqrt_k_change = sqrt_k
+        .checked_mul(max_change_numerator)
+        .ok_or_else(math_error!())?
+        .checked_div(max_change_denominator)
+        .ok_or_else(math_error!())?;
+
+    let new_sqrt_k = |sqrt_k_change: u128| {
+        if increase {
+            sqrt_k.checked_add(sqrt_k_change).ok_or_else(math_error!())
+        } else {
+            sqrt_k.checked_sub(sqrt_k_change).ok_or_else(math_error!())
+        }
+    };
+
+    if sqrt_k_change == 0 {
+        return Ok(None);
+    }
+
+    let mut cost = adjust_k_cost(&mut { *market }, U256::from(new_sqrt_k(sqrt_k_change)?))?;
+
+    // cost is roughly linear in the change in k, so rescaling by budget / cost gets close to the
+    // budget. It grows a little slower than linear, so a rescale can still overshoot slightly
+    let mut rescales = 0;
+    while cost > 0 && cost.unsigned_abs() > budget {
+        if rescales == MAX_BUDGETED_K_RESCALES {
+            return Ok(None);
+        }
+
+        sqrt_k_change = U192::from(sqrt_k_change)
+            .checked_mul(U192::from(budget))
+            .ok_or_else(math_error!())?
+            .checked_div(U192::from(cost.unsigned_abs()))
+            .ok_or_else(math_error!())?
+            .try_to_u128()?;
+
+        if sqrt_k_change == 0 {
+            return Ok(None);
+        }
+
+        cost = adjust_k_cost(&mut { *market }, U256::from(new_sqrt_k(sqrt_k_change)?))?;
+        rescales += 1;
+    }
+
+    Ok(Some((new_sqrt_k(sqrt_k_change)?, cost)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::constants::{AMM_RESERVE_PRECISION, MARK_PRICE_PRECISION, PEG_PRECISION};
+
+    fn market(base_asset_amount: i128) -> Market {
+        Market {
+            base_asset_amount,
+            amm: AMM {
+                base_asset_reserve: 1_000_000 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 1_000_000 * AMM_RESERVE_PRECISION,
+                sqrt_k: 1_000_000 * AMM_RESERVE_PRECISION,
+                peg_multiplier: PEG_PRECISION,
+                ..AMM::default()
+            },
+            ..Market::default()
+        }
+    }
+
+    #[test]
+    fn optimal_peg_puts_mark_at_oracle() {
+        let amm = market(0).amm;
+        let optimal_peg =
+            calculate_optimal_peg(&amm, (MARK_PRICE_PRECISION * 105 / 100) as i128).unwrap();
+        assert_eq!(optimal_peg, PEG_PRECISION * 105 / 100);
+    }
+
+    #[test]
+    fn fee_pool_budget_keeps_clearing_house_share() {
+        let amm = AMM {
+            total_fee: 100,
+            total_fee_minus_distributions: 80,
+            ..AMM::default()
+        };
+        assert_eq!(calculate_fee_pool_budget(&amm).unwrap(), 30);
+
+        let amm = AMM {
+            total_fee: 100,
+            total_fee_minus_distributions: 40,
+            ..AMM::default()
+        };
+        assert_eq!(calculate_fee_pool_budget(&amm).unwrap(), 0);
+    }
+
+    #[test]
+    fn free_repeg_goes_to_optimal_peg() {
+        let market = market(0);
+        let (new_peg, cost) = calculate_budgeted_peg(&market, PEG_PRECISION * 2, 0).unwrap();
+        assert_eq!(new_peg, PEG_PRECISION * 2);
+        assert_eq!(cost, 0);
+    }
+
+    #[test]
+    fn budget_limits_costly_repeg() {
+        // users are net long, so raising the peg costs the amm
+        let market = market(1_000 * AMM_RESERVE_PRECISION as i128);
+        let optimal_peg = PEG_PRECISION * 2;
+        let (_, full_cost) = calculate_budgeted_peg(&market, optimal_peg, u128::MAX).unwrap();
+        assert!(full_cost > 0);
+
+        let (new_peg, cost) = calculate_budgeted_peg(&market, optimal_peg, 0).unwrap();
+        assert_eq!(new_peg, PEG_PRECISION);
+        assert_eq!(cost, 0);
+
+        // half the budget moves the peg half of the way, rounded down to stay within budget
+        let budget = full_cost.unsigned_abs() / 2;
+        let (new_peg, cost) = calculate_budgeted_peg(&market, optimal_peg, budget).unwrap();
+        assert_eq!(new_peg, PEG_PRECISION * 3 / 2 - 1);
+        assert!(cost.unsigned_abs() <= budget);
+    }
+
+    #[test]
+    fn free_k_change_moves_by_max_change() {
+        let market = market(0);
+        let sqrt_k = market.amm.sqrt_k;
+
+        let (new_sqrt_k, cost) = calculate_budgeted_k(&market, true, 0, 1, 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(new_sqrt_k, sqrt_k * 101 / 100);
+        assert_eq!(cost, 0);
+
+        let (new_sqrt_k, cost) = calculate_budgeted_k(&market, false, 0, 1, 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(new_sqrt_k, sqrt_k * 99 / 100);
+        assert_eq!(cost, 0);
+    }
+
+    #[test]
+    fn budget_limits_costly_k_increase() {
+        // users are net long, so deepening the curve costs the amm
+        let market = market(1_000 * AMM_RESERVE_PRECISION as i128);
+        let sqrt_k = market.amm.sqrt_k;
+
+        assert!(calculate_budgeted_k(&market, true, 0, 1, 100)
+            .unwrap()
+            .is_none());
+
+        let (_, full_cost) = calculate_budgeted_k(&market, true, u128::MAX, 1, 100)
+            .unwrap()
+            .unwrap();
+        assert!(full_cost > 0);
+
+        let budget = full_cost.unsigned_abs() / 2;
+        let (new_sqrt_k, cost) = calculate_budgeted_k(&market, true, budget, 1, 100)
+            .unwrap()
+            .unwrap();
+        assert!(new_sqrt_k > sqrt_k);
+        assert!(new_sqrt_k < sqrt_k * 101 / 100);
+        assert!(cost.unsigned_abs() <= budget);
+    }
+
+    #[test]
+    fn k_decrease_pays_the_amm() {
+        let market = market(1_000 * AMM_RESERVE_PRECISION as i128);
+        let (_, cost) = calculate_budgeted_k(&market, false, 0, 1, 100)
+            .unwrap()
+            .unwrap();
+        assert!(cost < 0);
+    }
+}
@@ -1,15 +1,25 @@
-use crate::context::{InitializeUserOptionalAccounts, ManagePositionOptionalAccounts, OrderParams};
+use crate::context::{
+    InitializeUserOptionalAccounts, ManagePositionOptionalAccounts, ModifyOrderParams, OrderParams,
+};
+use crate::controller::orders::MakerAccounts;
 use crate::error::{ClearingHouseResult, ErrorCode};
+use crate::math::collateral::calculate_spot_balances_value;
 use crate::print_error;
+use crate::state::collateral::{CollateralAssets, UserCollateralAssets};
+use crate::state::history::order_history::OrderHistory;
 use crate::state::market::Markets;
-use crate::state::user::User;
+use crate::state::order_book::OrderBook;
+use crate::state::order_state::OrderState;
+use crate::state::state::State;
+use crate::state::user::{User, UserPositions};
 use crate::state::user_orders::UserOrders;
 use anchor_lang::prelude::{Account, AccountLoader};
-use anchor_lang::prelude::{AccountInfo, Pubkey};
+use anchor_lang::prelude::{AccountInfo, Key, Pubkey};
 use solana_program::account_info::next_account_info;
 use solana_program::msg;
 use spl_token::solana_program::program_pack::{IsInitialized, Pack};
 use spl_token::state::Account as TokenAccount;
+use std::collections::BTreeMap;
 use std::slice::Iter;
 
 pub fn get_whitelist_token(
@@ -256,3 +266,273 @@ pub fn get_oracle_for_cancel_order_by_user_order_id<'a, 'b, 'c, 'd>(
 
     Ok(oracle)
 }
+
+/// The order book comes first if the order rests on it. Modifying can add an oracle offset to an
+/// order, so the market's oracle is used whenever it's passed.
+pub fn get_order_book_and_oracle_for_modify_order<'a, 'b>(
+    account_info_iter: &'a mut Iter<AccountInfo<'b>>,
+    user_orders: &AccountLoader<UserOrders>,
+    markets: &AccountLoader<Markets>,
+    order_id: u128,
+    params: &ModifyOrderParams,
+) -> ClearingHouseResult<(
+    Option<AccountLoader<'b, OrderBook>>,
+    Option<&'a AccountInfo<'b>>,
+)> {
+    let market_index = {
+        let user_orders = user_orders
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let order_index = user_orders
+            .orders
+            .iter()
+            .position(|order| order.order_id == order_id)
+            .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
+        user_orders.orders[order_index].market_index
+    };
+
+    let order_book = get_order_book(params.order_book, account_info_iter, market_index)?;
+
+    let markets = markets
+        .load()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let market = markets.get_market(market_index);
+    let oracle = account_info_iter.find(|account_info| account_info.key.eq(&market.amm.oracle));
+
+    Ok((order_book, oracle))
+}
+
+pub fn get_order_book<'b>(
+    expect_order_book: bool,
+    account_info_iter: &mut Iter<AccountInfo<'b>>,
+    market_index: u64,
+) -> ClearingHouseResult<Option<AccountLoader<'b, OrderBook>>> {
+    let mut optional_order_book = None;
+    if expect_order_book {
+        let order_book_account_info =
+            next_account_info(account_info_iter).or(Err(ErrorCode::InvalidOrderBook))?;
+
+        optional_order_book = Some(load_order_book(order_book_account_info, market_index)?);
+    }
+
+    Ok(optional_order_book)
+}
+
+pub type OracleAccountInfos<'a, 'b> = BTreeMap<Pubkey, &'a AccountInfo<'b>>;
+pub type OrderBooks<'b> = BTreeMap<u64, AccountLoader<'b, OrderBook>>;
+
+/// Oracles and order books for a batch of orders can be passed in any order. Order books are keyed by
+/// their market index and every other account is looked up as an oracle.
+pub fn get_oracles_and_order_books<'a, 'b>(
+    account_info_iter: &mut Iter<'a, AccountInfo<'b>>,
+) -> ClearingHouseResult<(OracleAccountInfos<'a, 'b>, OrderBooks<'b>)> {
+    let mut oracles = BTreeMap::new();
+    let mut order_books = BTreeMap::new();
+    for account_info in account_info_iter {
+        match AccountLoader::<OrderBook>::try_from(account_info) {
+            Ok(order_book) => {
+                let market_index = order_book
+                    .load()
+                    .or(Err(ErrorCode::UnableToLoadAccountLoader))?
+                    .market_index;
+                order_books.insert(market_index, order_book);
+            }
+            Err(_) => {
+                oracles.insert(account_info.key(), account_info);
+            }
+        }
+    }
+
+    Ok((oracles, order_books))
+}
+
+/// After the referrer, the remaining accounts for a fill are the order book for the order's market
+/// followed by a (user, user positions, user orders) triple for every maker the filler wants to
+/// match against. The taker's collateral asset accounts go last, starting with the collateral assets account.
+pub fn get_order_book_and_makers_for_fill_order<'b>(
+    account_info_iter: &mut Iter<AccountInfo<'b>>,
+    state: &State,
+    order_id: u128,
+    user_orders: &AccountLoader<UserOrders>,
+) -> ClearingHouseResult<(Option<AccountLoader<'b, OrderBook>>, Vec<MakerAccounts<'b>>)> {
+    let order_book_account_info = match account_info_iter.next() {
+        Some(account_info) if !account_info.key.eq(&state.collateral_assets) => account_info,
+        _ => return Ok((None, vec![])),
+    };
+
+    let market_index = {
+        let user_orders = user_orders
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let order_index = user_orders
+            .orders
+            .iter()
+            .position(|order| order.order_id == order_id)
+            .ok_or(ErrorCode::OrderDoesNotExist)?;
+        user_orders.orders[order_index].market_index
+    };
+    let order_book = load_order_book(order_book_account_info, market_index)?;
+
+    let mut makers = vec![];
+    while let Some(user_account_info) = account_info_iter.next() {
+        if user_account_info.key.eq(&state.collateral_assets) {
+            break;
+        }
+
+        let user: Box<Account<User>> = Box::new(
+            Account::try_from(user_account_info).or(Err(ErrorCode::InvalidMakerAccounts))?,
+        );
+
+        let user_positions_account_info =
+            next_account_info(account_info_iter).or(Err(ErrorCode::InvalidMakerAccounts))?;
+        if !user_positions_account_info.key.eq(&user.positions) {
+            return Err(ErrorCode::InvalidMakerAccounts);
+        }
+        let user_positions: AccountLoader<UserPositions> =
+            AccountLoader::try_from(user_positions_account_info)
+                .or(Err(ErrorCode::InvalidMakerAccounts))?;
+
+        let user_orders_account_info =
+            next_account_info(account_info_iter).or(Err(ErrorCode::InvalidMakerAccounts))?;
+        let user_orders: AccountLoader<UserOrders> =
+            AccountLoader::try_from(user_orders_account_info)
+                .or(Err(ErrorCode::InvalidMakerAccounts))?;
+        {
+            let user_orders = user_orders
+                .load()
+                .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+            if !user_orders.user.eq(user_account_info.key) {
+                return Err(ErrorCode::InvalidMakerAccounts);
+            }
+        }
+
+        makers.push(MakerAccounts {
+            user,
+            user_positions,
+            user_orders,
+        });
+    }
+
+    Ok((Some(order_book), makers))
+}
+
+/// The accounts needed to cancel linked orders when a position is closed. They're looked up by key
+/// so they can be passed in any order alongside the other remaining accounts: the order state, the
+/// user's orders and the order history.
+pub fn find_user_orders_and_order_history<'b>(
+    accounts: &[AccountInfo<'b>],
+    state: &State,
+    user_public_key: &Pubkey,
+) -> ClearingHouseResult<
+    Option<(
+        AccountLoader<'b, UserOrders>,
+        AccountLoader<'b, OrderHistory>,
+    )>,
+> {
+    let order_state_account_info = match accounts
+        .iter()
+        .find(|account_info| account_info.key.eq(&state.order_state))
+    {
+        Some(account_info) => account_info,
+        None => return Ok(None),
+    };
+    let order_state: Account<OrderState> =
+        Account::try_from(order_state_account_info).or(Err(ErrorCode::UserOrdersNotFound))?;
+
+    let order_history_account_info = accounts
+        .iter()
+        .find(|account_info| account_info.key.eq(&order_state.order_history))
+        .ok_or(ErrorCode::UserOrdersNotFound)?;
+    let order_history: AccountLoader<OrderHistory> =
+        AccountLoader::try_from(order_history_account_info)
+            .or(Err(ErrorCode::UserOrdersNotFound))?;
+
+    let user_orders = accounts
+        .iter()
+        .filter_map(|account_info| AccountLoader::<UserOrders>::try_from(account_info).ok())
+        .find(|user_orders| {
+            user_orders
+                .load()
+                .map_or(false, |user_orders| user_orders.user.eq(user_public_key))
+        })
+        .ok_or(ErrorCode::UserOrdersNotFound)?;
+
+    Ok(Some((user_orders, order_history)))
+}
+
+/// Users holding collateral assets must pass the collateral assets account, their user collateral
+/// assets account and the oracle for every asset they hold or owe. They're looked up by key so they can be
+/// passed in any order after the other remaining accounts.
+pub fn get_spot_balances_value(
+    accounts: &[AccountInfo],
+    state: &State,
+    user: &User,
+    user_public_key: &Pubkey,
+    clock_slot: u64,
+) -> ClearingHouseResult<i128> {
+    if user.has_collateral_assets == 0 {
+        return Ok(0);
+    }
+
+    let collateral_assets_account_info = accounts
+        .iter()
+        .find(|account_info| account_info.key.eq(&state.collateral_assets))
+        .ok_or(ErrorCode::UserCollateralAssetsNotFound)?;
+    let collateral_assets: AccountLoader<CollateralAssets> =
+        AccountLoader::try_from(collateral_assets_account_info)
+            .or(Err(ErrorCode::InvalidCollateralAsset))?;
+
+    let user_collateral_assets = accounts
+        .iter()
+        .filter_map(|account_info| {
+            AccountLoader::<UserCollateralAssets>::try_from(account_info).ok()
+        })
+        .find(|user_collateral_assets| {
+            user_collateral_assets
+                .load()
+                .map_or(false, |user_collateral_assets| {
+                    user_collateral_assets.user.eq(user_public_key)
+                })
+        })
+        .ok_or(ErrorCode::UserCollateralAssetsNotFound)?;
+
+    let oracle_account_infos: BTreeMap<Pubkey, &AccountInfo> = accounts
+        .iter()
+        .map(|account_info| (account_info.key(), account_info))
+        .collect();
+
+    let user_collateral_assets = user_collateral_assets
+        .load()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+    let collateral_assets = collateral_assets
+        .load()
+        .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+    calculate_spot_balances_value(
+        &user_collateral_assets,
+        &collateral_assets,
+        &state.quote_lending_pool,
+        &oracle_account_infos,
+        clock_slot,
+        &state.oracle_guard_rails.validity,
+    )
+}
+
+fn load_order_book<'a>(
+    account_info: &AccountInfo<'a>,
+    market_index: u64,
+) -> ClearingHouseResult<AccountLoader<'a, OrderBook>> {
+    let order_book: AccountLoader<OrderBook> =
+        AccountLoader::try_from(account_info).or(Err(ErrorCode::InvalidOrderBook))?;
+
+    {
+        let order_book = order_book
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        if order_book.market_index != market_index {
+            return Err(ErrorCode::InvalidOrderBook);
+        }
+    }
+
+    Ok(order_book)
+}
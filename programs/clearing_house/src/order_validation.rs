@@ -4,15 +4,15 @@ use crate::math::constants::*;
 use crate::math::quote_asset::asset_to_reserve_amount;
 use crate::state::market::{Market, Markets};
 use crate::state::order_state::OrderState;
-use crate::state::user_orders::{Order, OrderTriggerCondition, OrderType};
+use crate::state::user_orders::{Order, OrderTriggerCondition, OrderType, TrailingDistanceType};
 
 use crate::context::OrderParams;
 use crate::math::orders::calculate_base_asset_amount_to_trade_for_limit;
-use crate::state::user::{MarketPosition, User, UserPositions};
+use crate::state::user::{MarketPosition, User};
 use solana_program::msg;
 
-use crate::math::margin::meets_initial_margin_requirement;
-use std::cell::{Ref, RefMut};
+use crate::math::margin::{meets_margin_requirement_for_market, MarginType};
+use std::cell::Ref;
 use std::ops::Div;
 
 pub fn validate_order(
@@ -26,6 +26,8 @@ pub fn validate_order(
         OrderType::Limit => validate_limit_order(order, market, order_state, valid_oracle_price)?,
         OrderType::TriggerMarket => validate_trigger_market_order(order, market, order_state)?,
         OrderType::TriggerLimit => validate_trigger_limit_order(order, market, order_state)?,
+        OrderType::TrailingStop => validate_trailing_stop_order(order, market, order_state)?,
+        OrderType::Twap => validate_twap_order(order, market, order_state)?,
     }
 
     Ok(())
@@ -247,6 +249,145 @@ fn validate_trigger_market_order(
     Ok(())
 }
 
+fn validate_trailing_stop_order(
+    order: &Order,
+    market: &Market,
+    order_state: &OrderState,
+) -> ClearingHouseResult {
+    validate_base_asset_amount(order, market)?;
+
+    if order.price > 0 {
+        msg!("Trailing stop order should not have price");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.trigger_price == 0 {
+        msg!("Trailing stop order trigger_price == 0");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.trailing_distance == 0 {
+        msg!("Trailing stop order trailing_distance == 0");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.trailing_distance_type == TrailingDistanceType::BasisPoints
+        && order.trailing_distance >= BASIS_POINTS_PRECISION
+    {
+        msg!("Trailing stop order trailing_distance must be less than 100%");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    // A long stop trails the price down and triggers when it bounces up, a short stop trails the
+    // price up and triggers when it drops
+    let expected_trigger_condition = match order.direction {
+        PositionDirection::Long => OrderTriggerCondition::Above,
+        PositionDirection::Short => OrderTriggerCondition::Below,
+    };
+    if order.trigger_condition != expected_trigger_condition {
+        msg!("Trailing stop order trigger condition must be above for longs and below for shorts");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.quote_asset_amount != 0 {
+        msg!("Trailing stop order should not have a quote asset amount");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.post_only {
+        msg!("Trailing stop order can not be post only");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.has_oracle_price_offset() {
+        msg!("Trailing stop order can not have oracle offset");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    let approximate_market_value = order
+        .trigger_price
+        .checked_mul(order.base_asset_amount)
+        .unwrap_or(u128::MAX)
+        .div(AMM_RESERVE_PRECISION)
+        .div(MARK_PRICE_PRECISION / QUOTE_PRECISION);
+
+    if approximate_market_value < order_state.min_order_quote_asset_amount {
+        msg!("Order value < $0.50 ({:?})", approximate_market_value);
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    Ok(())
+}
+
+fn validate_twap_order(
+    order: &Order,
+    market: &Market,
+    order_state: &OrderState,
+) -> ClearingHouseResult {
+    validate_base_asset_amount(order, market)?;
+
+    if order.price == 0 {
+        msg!("Twap order price == 0");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.slice_base_asset_amount < market.amm.minimum_base_asset_trade_size {
+        msg!(
+            "Twap order slice_base_asset_amount smaller than market minimum_base_asset_trade_size"
+        );
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.slice_base_asset_amount > order.base_asset_amount {
+        msg!("Twap order slice_base_asset_amount larger than base_asset_amount");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.slice_interval <= 0 {
+        msg!("Twap order slice_interval must be positive");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.trigger_price > 0 {
+        msg!("Twap order should not have trigger price");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.quote_asset_amount != 0 {
+        msg!("Twap order should not have a quote asset amount");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.post_only {
+        msg!("Twap order can not be post only");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.immediate_or_cancel {
+        msg!("Twap order can not be immediate or cancel");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    if order.has_oracle_price_offset() {
+        msg!("Twap order can not have oracle offset");
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    let approximate_market_value = order
+        .price
+        .checked_mul(order.base_asset_amount)
+        .unwrap_or(u128::MAX)
+        .div(AMM_RESERVE_PRECISION)
+        .div(MARK_PRICE_PRECISION / QUOTE_PRECISION);
+
+    if approximate_market_value < order_state.min_order_quote_asset_amount {
+        msg!("Order value < $0.50 ({:?})", approximate_market_value);
+        return Err(ErrorCode::InvalidOrder);
+    }
+
+    Ok(())
+}
+
 fn validate_base_asset_amount(order: &Order, market: &Market) -> ClearingHouseResult {
     if order.base_asset_amount == 0 {
         msg!("Order base_asset_amount cant be 0");
@@ -281,7 +422,7 @@ fn validate_quote_asset_amount(order: &Order, market: &Market) -> ClearingHouseR
 pub fn check_if_order_can_be_canceled(
     order: &Order,
     user: &User,
-    user_positions: &RefMut<UserPositions>,
+    user_positions: &[MarketPosition],
     markets: &Ref<Markets>,
     valid_oracle_price: Option<i128>,
 ) -> ClearingHouseResult<bool> {
@@ -296,8 +437,15 @@ pub fn check_if_order_can_be_canceled(
     )?;
 
     if base_asset_amount_market_can_fill > 0 {
-        let meets_initial_margin_requirement =
-            meets_initial_margin_requirement(user, user_positions, markets)?;
+        // Collateral assets aren't counted, which can only make the order easier to cancel
+        let meets_initial_margin_requirement = meets_margin_requirement_for_market(
+            user,
+            user_positions,
+            0,
+            markets,
+            order.market_index,
+            MarginType::Init,
+        )?;
 
         if meets_initial_margin_requirement {
             msg!(
@@ -316,7 +464,7 @@ pub fn check_if_order_can_be_canceled(
 pub fn validate_order_can_be_canceled(
     order: &Order,
     user: &User,
-    user_positions: &RefMut<UserPositions>,
+    user_positions: &[MarketPosition],
     markets: &Ref<Markets>,
     valid_oracle_price: Option<i128>,
 ) -> ClearingHouseResult {
@@ -0,0 +1,104 @@
+use anchor_lang::prelude::*;
+
+use crate::error::ClearingHouseResult;
+use crate::math::constants::MARGIN_PRECISION;
+use crate::math_error;
+use crate::state::lending::LendingPool;
+use crate::state::market::{OraclePriceData, OracleSource, AMM};
+use solana_program::msg;
+
+/// Tokens other than the quote asset that users can deposit as margin
+#[account(zero_copy)]
+#[derive(Default)]
+#[repr(packed)]
+pub struct CollateralAssets {
+    pub assets: [CollateralAsset; 8],
+}
+
+impl CollateralAssets {
+    pub fn index_from_u64(index: u64) -> usize {
+        std::convert::TryInto::try_into(index).unwrap()
+    }
+
+    pub fn get_asset(&self, index: u64) -> &CollateralAsset {
+        &self.assets[CollateralAssets::index_from_u64(index)]
+    }
+
+    pub fn get_asset_mut(&mut self, index: u64) -> &mut CollateralAsset {
+        &mut self.assets[CollateralAssets::index_from_u64(index)]
+    }
+}
+
+#[zero_copy]
+#[derive(Default)]
+#[repr(packed)]
+pub struct CollateralAsset {
+    pub initialized: bool,
+    pub mint: Pubkey,
+    pub mint_decimals: u8,
+    pub vault: Pubkey,
+    pub vault_authority: Pubkey,
+    pub vault_nonce: u8,
+    pub oracle: Pubkey,
+    pub oracle_source: OracleSource,
+    pub asset_weight: u128, // share of the oracle value counted as collateral, MARGIN_PRECISION
+    pub liquidation_discount: u128, // discount to the oracle price paid to liquidators, MARGIN_PRECISION
+    pub lending_pool: LendingPool,
+
+    // upgrade-ability
+    pub padding0: u128,
+    pub padding1: u128,
+}
+
+impl CollateralAsset {
+    /// Borrows are weighted up by the same haircut that deposits are weighted down by
+    pub fn liability_weight(&self) -> ClearingHouseResult<u128> {
+        MARGIN_PRECISION
+            .checked_mul(2)
+            .ok_or_else(math_error!())?
+            .checked_sub(self.asset_weight)
+            .ok_or_else(math_error!())
+    }
+
+    pub fn get_oracle_price(
+        &self,
+        price_oracle: &AccountInfo,
+        clock_slot: u64,
+    ) -> ClearingHouseResult<OraclePriceData> {
+        // the oracle readers don't depend on the amm's state
+        let amm = AMM::default();
+        match self.oracle_source {
+            OracleSource::Pyth => amm.get_pyth_price(price_oracle, clock_slot),
+            OracleSource::Switchboard => amm.get_switchboard_price(price_oracle, clock_slot),
+        }
+    }
+}
+
+/// A user's deposits and borrows of each collateral asset, in the asset's native token precision,
+/// and their borrows of the quote asset
+#[account(zero_copy)]
+#[derive(Default)]
+#[repr(packed)]
+pub struct UserCollateralAssets {
+    pub user: Pubkey,
+    pub balances: [u128; 8],
+    pub borrows: [u128; 8],
+    pub last_cumulative_deposit_interest: [u128; 8],
+    pub last_cumulative_borrow_interest: [u128; 8],
+    pub quote_borrows: u128,
+    pub last_cumulative_quote_borrow_interest: u128,
+}
+
+impl UserCollateralAssets {
+    pub fn get_balance(&self, index: u64) -> u128 {
+        self.balances[CollateralAssets::index_from_u64(index)]
+    }
+
+    pub fn get_balance_mut(&mut self, index: u64) -> &mut u128 {
+        &mut self.balances[CollateralAssets::index_from_u64(index)]
+    }
+
+    pub fn get_borrows_mut(&mut self, index: u64) -> &mut u128 {
+        &mut self.borrows[CollateralAssets::index_from_u64(index)]
+    }
+}
@@ -0,0 +1,45 @@
+use anchor_lang::prelude::*;
+
+#[account(zero_copy)]
+#[repr(packed)]
+pub struct BankruptcyHistory {
+    head: u64,
+    bankruptcy_records: [BankruptcyRecord; 1024],
+}
+
+impl BankruptcyHistory {
+    pub fn append(&mut self, pos: BankruptcyRecord) {
+        self.bankruptcy_records[BankruptcyHistory::index_of(self.head)] = pos;
+        self.head = (self.head + 1) % 1024;
+    }
+
+    pub fn index_of(counter: u64) -> usize {
+        std::convert::TryInto::try_into(counter).unwrap()
+    }
+
+    pub fn next_record_id(&self) -> u128 {
+        let prev_record_id = if self.head == 0 { 1023 } else { self.head - 1 };
+        let prev_record = &self.bankruptcy_records[BankruptcyHistory::index_of(prev_record_id)];
+        prev_record.record_id + 1
+    }
+}
+
+/// A loss that a liquidated position's collateral couldn't pay and how it was covered
+#[zero_copy]
+#[derive(Default)]
+#[repr(packed)]
+pub struct BankruptcyRecord {
+    pub ts: i64,
+    pub record_id: u128,
+    pub user_authority: Pubkey,
+    pub user: Pubkey,
+    pub liquidator: Pubkey,
+    pub market_index: u64,
+    pub bankrupt_loss: u128,
+    pub loss_covered_by_fee_pool: u128,
+    pub loss_covered_by_insurance_fund: u64,
+    pub loss_socialized: u128,
+    pub cumulative_funding_rate_long_delta: i128,
+    pub cumulative_funding_rate_short_delta: i128,
+    pub uncovered_loss: u128,
+}
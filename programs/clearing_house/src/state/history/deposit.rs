@@ -29,6 +29,10 @@ impl DepositHistory {
 pub enum DepositDirection {
     DEPOSIT,
     WITHDRAW,
+    TransferIn,
+    TransferOut,
+    CollateralAssetSwap,
+    InterestAccrual,
 }
 
 impl Default for DepositDirection {
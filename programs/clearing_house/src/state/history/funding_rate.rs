@@ -22,6 +22,11 @@ impl FundingRateHistory {
         let prev_record = &self.funding_rate_records[FundingRateHistory::index_of(prev_record_id)];
         prev_record.record_id + 1
     }
+
+    pub fn last_record(&self) -> FundingRateRecord {
+        let prev_record_id = if self.head == 0 { 1023 } else { self.head - 1 };
+        self.funding_rate_records[FundingRateHistory::index_of(prev_record_id)]
+    }
 }
 
 #[zero_copy]
@@ -37,3 +42,45 @@ pub struct FundingRateRecord {
     pub oracle_price_twap: i128,
     pub mark_price_twap: u128,
 }
+
+#[account(zero_copy)]
+#[repr(packed)]
+pub struct ExtendedFundingRateHistory {
+    head: u64,
+    funding_rate_records: [ExtendedFundingRateRecord; 1024],
+}
+
+impl ExtendedFundingRateHistory {
+    pub fn append(&mut self, pos: ExtendedFundingRateRecord) {
+        self.funding_rate_records[ExtendedFundingRateHistory::index_of(self.head)] = pos;
+        self.head = (self.head + 1) % 1024;
+    }
+
+    pub fn index_of(counter: u64) -> usize {
+        std::convert::TryInto::try_into(counter).unwrap()
+    }
+
+    pub fn next_record_id(&self) -> u128 {
+        let prev_record_id = if self.head == 0 { 1023 } else { self.head - 1 };
+        let prev_record =
+            &self.funding_rate_records[ExtendedFundingRateHistory::index_of(prev_record_id)];
+        prev_record.record_id + 1
+    }
+}
+
+#[zero_copy]
+#[derive(Default)]
+#[repr(packed)]
+pub struct ExtendedFundingRateRecord {
+    pub ts: i64,
+    pub record_id: u128,
+    pub market_index: u64,
+    pub funding_rate: i128, // clamped rate applied to the market
+    pub cumulative_funding_rate_long: i128,
+    pub cumulative_funding_rate_short: i128,
+    pub oracle_price_twap: i128,
+    pub mark_price_twap: u128,
+    pub raw_funding_rate: i128,
+    pub premium_index: i128,
+    pub padding: [u128; 5],
+}
@@ -0,0 +1,50 @@
+use anchor_lang::prelude::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+#[account(zero_copy)]
+#[repr(packed)]
+pub struct InsuranceFundStakeHistory {
+    head: u64,
+    insurance_fund_stake_records: [InsuranceFundStakeRecord; 1024],
+}
+
+impl InsuranceFundStakeHistory {
+    pub fn append(&mut self, pos: InsuranceFundStakeRecord) {
+        self.insurance_fund_stake_records[InsuranceFundStakeHistory::index_of(self.head)] = pos;
+        self.head = (self.head + 1) % 1024;
+    }
+
+    pub fn index_of(counter: u64) -> usize {
+        std::convert::TryInto::try_into(counter).unwrap()
+    }
+
+    pub fn next_record_id(&self) -> u128 {
+        let prev_record_id = if self.head == 0 { 1023 } else { self.head - 1 };
+        let prev_record =
+            &self.insurance_fund_stake_records[InsuranceFundStakeHistory::index_of(prev_record_id)];
+        prev_record.record_id + 1
+    }
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Default)]
+pub enum StakeAction {
+    #[default]
+    Stake,
+    UnstakeRequest,
+    UnstakeCancelRequest,
+    Unstake,
+}
+
+#[zero_copy]
+#[derive(Default)]
+#[repr(packed)]
+pub struct InsuranceFundStakeRecord {
+    pub ts: i64,
+    pub record_id: u128,
+    pub user_authority: Pubkey,
+    pub action: StakeAction,
+    pub amount: u64,
+    pub shares: u128,
+    pub total_shares_before: u128,
+    pub insurance_vault_amount_before: u64,
+}
@@ -1,7 +1,9 @@
+pub mod bankruptcy;
 pub mod curve;
 pub mod deposit;
 pub mod funding_payment;
 pub mod funding_rate;
+pub mod insurance_fund_stake;
 pub mod liquidation;
 pub mod order_history;
 pub mod trade;
@@ -50,7 +50,8 @@ pub struct OrderRecord {
     pub fee: i128,
     pub filler_reward: u128,
     pub quote_asset_amount_surplus: u128,
-    pub padding: [u64; 8],
+    pub counterparty: Pubkey,
+    pub padding: [u64; 4],
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq)]
@@ -59,6 +60,9 @@ pub enum OrderAction {
     Cancel,
     Fill,
     Expire,
+    TriggerUpdate,
+    ExpireMaxTs,
+    Modify,
 }
 
 impl Default for OrderAction {
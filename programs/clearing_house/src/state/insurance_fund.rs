@@ -0,0 +1,26 @@
+use anchor_lang::prelude::*;
+
+/// The insurance vault's balance backs all of the shares, so liquidation penalties and fees paid
+/// into the vault raise the share value and the losses it covers lower it
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct InsuranceFund {
+    pub stake_history: Pubkey,
+    pub total_shares: u128,
+    pub protocol_shares: u128, // the vault balance from before anyone staked
+    pub unstaking_period: i64,
+    pub fee_share_numerator: u128, // share of market fees paid to stakers
+    pub fee_share_denominator: u128,
+}
+
+#[account]
+#[derive(Default)]
+#[repr(packed)]
+pub struct InsuranceFundStake {
+    pub authority: Pubkey,
+    pub shares: u128,
+
+    // unstaking
+    pub last_withdraw_request_shares: u128,
+    pub last_withdraw_request_value: u64,
+    pub last_withdraw_request_ts: i64,
+}
@@ -0,0 +1,40 @@
+use anchor_lang::prelude::*;
+
+use crate::math::constants::{
+    DEFAULT_MAX_BORROW_RATE, DEFAULT_OPTIMAL_BORROW_RATE, DEFAULT_OPTIMAL_UTILIZATION,
+    INTEREST_PRECISION,
+};
+
+/// Lending of an asset held in one of the clearing house's vaults. Deposits and borrows accrue
+/// interest through the cumulative interest indexes and users' balances are settled against them
+/// lazily, the same way funding payments are settled against the cumulative funding rates.
+#[zero_copy]
+#[derive(Default, AnchorSerialize, AnchorDeserialize)]
+#[repr(packed)]
+pub struct LendingPool {
+    pub total_deposits: u128,
+    pub total_borrows: u128,
+    pub cumulative_deposit_interest: u128, // INTEREST_PRECISION
+    pub cumulative_borrow_interest: u128,  // INTEREST_PRECISION
+    pub last_interest_ts: i64,
+
+    // utilization curve
+    pub optimal_utilization: u128, // UTILIZATION_PRECISION
+    pub optimal_borrow_rate: u128, // yearly, INTEREST_RATE_PRECISION
+    pub max_borrow_rate: u128,     // yearly, INTEREST_RATE_PRECISION
+}
+
+impl LendingPool {
+    pub fn new(now: i64) -> Self {
+        LendingPool {
+            total_deposits: 0,
+            total_borrows: 0,
+            cumulative_deposit_interest: INTEREST_PRECISION,
+            cumulative_borrow_interest: INTEREST_PRECISION,
+            last_interest_ts: now,
+            optimal_utilization: DEFAULT_OPTIMAL_UTILIZATION,
+            optimal_borrow_rate: DEFAULT_OPTIMAL_BORROW_RATE,
+            max_borrow_rate: DEFAULT_MAX_BORROW_RATE,
+        }
+    }
+}
@@ -2,9 +2,9 @@ use anchor_lang::prelude::*;
 use switchboard_v2::AggregatorAccountData;
 
 use crate::error::{ClearingHouseResult, ErrorCode};
-use crate::math::amm;
 use crate::math::casting::{cast, cast_to_i128, cast_to_i64, cast_to_u128};
-use crate::math::margin::MarginType;
+use crate::math::curve::{get_curve, AmmCurve, Curve};
+use crate::math::margin::{calculate_size_adjusted_margin_ratio, MarginType};
 use crate::math_error;
 use crate::MARK_PRICE_PRECISION;
 use solana_program::msg;
@@ -52,22 +52,45 @@ pub struct Market {
     pub margin_ratio_initial: u32,
     pub margin_ratio_partial: u32,
     pub margin_ratio_maintenance: u32,
-
-    // upgrade-ability
-    pub padding0: u32,
-    pub padding1: u128,
-    pub padding2: u128,
-    pub padding3: u128,
-    pub padding4: u128,
+    // share of total collateral charged, unit is MARGIN_PRECISION
+    pub partial_liquidation_penalty: u32,
+    pub full_liquidation_penalty: u32,
+    // share of the liquidation fee paid to the liquidator, unit is MARGIN_PRECISION
+    pub partial_liquidation_liquidator_share: u32,
+    pub full_liquidation_liquidator_share: u32,
+    // initial margin ratio premium per square root of position value, the partial and maintenance
+    // ratios scale with it, unit is IMF_FACTOR_PRECISION
+    pub imf_factor: u32,
+    // mark/oracle spread weighted by the seconds it held since the last funding rate update, and
+    // the seconds covered
+    pub premium_sample_sum: i128,
+    pub premium_sample_duration: u64,
+    // mark/oracle spread at the last sample, it holds until the next sample
+    pub last_premium: i64,
+    pub last_premium_sample_ts: i64,
+    // share of the oracle twap, unit is FUNDING_RATE_PCT_PRECISION
+    pub max_funding_rate: u32,
+    pub funding_interest_rate: u32,
 }
 
 impl Market {
-    pub fn get_margin_ratio(&self, margin_type: MarginType) -> u32 {
-        match margin_type {
+    pub fn get_margin_ratio(
+        &self,
+        base_asset_value: u128,
+        margin_type: MarginType,
+    ) -> ClearingHouseResult<u32> {
+        let margin_ratio = match margin_type {
             MarginType::Init => self.margin_ratio_initial,
             MarginType::Partial => self.margin_ratio_partial,
             MarginType::Maint => self.margin_ratio_maintenance,
-        }
+        };
+
+        calculate_size_adjusted_margin_ratio(
+            margin_ratio,
+            self.margin_ratio_initial,
+            self.imf_factor,
+            base_asset_value,
+        )
     }
 }
 
@@ -77,6 +100,13 @@ pub enum OracleSource {
     Switchboard,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum CurveType {
+    #[default]
+    ConstantProduct,
+    StableSwap,
+}
+
 impl Default for OracleSource {
     // UpOnly
     fn default() -> Self {
@@ -112,19 +142,27 @@ pub struct AMM {
     pub last_oracle_price: i128,
     pub minimum_base_asset_trade_size: u128,
     pub base_spread: u16,
-
-    pub padding0: u16,
-    pub padding1: u32,
-    pub padding2: u128,
-    pub padding3: u128,
+    // spread on each side of the mark price, bounded by half the base spread and half the max spread
+    pub long_spread: u16,
+    pub short_spread: u16,
+    pub max_spread: u16,
+    pub total_fee_settled_to_insurance_fund: u128,
+    pub curve_type: CurveType,
+    pub amplification_coefficient: u64,
+    pub padding3: [u8; 7],
 }
 
 impl AMM {
+    pub fn curve(&self) -> AmmCurve {
+        get_curve(self.curve_type, self.amplification_coefficient)
+    }
+
     pub fn mark_price(&self) -> ClearingHouseResult<u128> {
-        amm::calculate_price(
+        self.curve().calculate_price(
             self.quote_asset_reserve,
             self.base_asset_reserve,
             self.peg_multiplier,
+            self.sqrt_k,
         )
     }
 
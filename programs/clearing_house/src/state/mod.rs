@@ -1,5 +1,9 @@
+pub mod collateral;
 pub mod history;
+pub mod insurance_fund;
+pub mod lending;
 pub mod market;
+pub mod order_book;
 pub mod order_state;
 pub mod settlement;
 #[allow(clippy::module_inception)]
@@ -0,0 +1,90 @@
+use crate::controller::position::PositionDirection;
+use anchor_lang::prelude::*;
+
+pub const ORDER_BOOK_SIDE_SIZE: usize = 64;
+
+// SPACE: 9232
+#[account(zero_copy)]
+#[repr(packed)]
+pub struct OrderBook {
+    pub market_index: u64,
+    pub bids: [OrderBookEntry; 64],
+    pub asks: [OrderBookEntry; 64],
+}
+
+/// Points to a resting order in the maker's UserOrders account. The user's order is the source of
+/// truth for the amount left to fill, so entries for orders that were canceled or filled elsewhere
+/// are removed lazily when they are reached during matching.
+#[zero_copy]
+#[derive(Default)]
+#[repr(packed)]
+pub struct OrderBookEntry {
+    pub user: Pubkey,
+    pub order_id: u128,
+    pub price: u128,
+    pub ts: i64,
+}
+
+impl OrderBookEntry {
+    pub fn is_empty(&self) -> bool {
+        self.order_id == 0
+    }
+}
+
+impl OrderBook {
+    /// Bids hold long orders and asks hold short orders. Each side is sorted best price first and,
+    /// within the same price, oldest order first.
+    pub fn side(&self, direction: PositionDirection) -> &[OrderBookEntry; ORDER_BOOK_SIDE_SIZE] {
+        match direction {
+            PositionDirection::Long => &self.bids,
+            PositionDirection::Short => &self.asks,
+        }
+    }
+
+    fn side_mut(
+        &mut self,
+        direction: PositionDirection,
+    ) -> &mut [OrderBookEntry; ORDER_BOOK_SIDE_SIZE] {
+        match direction {
+            PositionDirection::Long => &mut self.bids,
+            PositionDirection::Short => &mut self.asks,
+        }
+    }
+
+    /// If the side is full, the worst priced entry is dropped to make room. An order priced worse
+    /// than every entry on a full side isn't added. Orders left off the book stay open and can still
+    /// be filled against the amm. Returns whether the order rests on the book.
+    pub fn insert(&mut self, direction: PositionDirection, entry: OrderBookEntry) -> bool {
+        let side = self.side_mut(direction);
+
+        let insert_index = side.iter().position(|existing_entry| {
+            existing_entry.is_empty()
+                || match direction {
+                    PositionDirection::Long => entry.price > existing_entry.price,
+                    PositionDirection::Short => entry.price < existing_entry.price,
+                }
+        });
+
+        match insert_index {
+            Some(insert_index) => {
+                side.copy_within(insert_index..ORDER_BOOK_SIDE_SIZE - 1, insert_index + 1);
+                side[insert_index] = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn find(&self, direction: PositionDirection, order_id: u128) -> Option<usize> {
+        self.side(direction)
+            .iter()
+            .position(|entry| !entry.is_empty() && entry.order_id == order_id)
+    }
+
+    pub fn remove(&mut self, direction: PositionDirection, index: usize) {
+        let side = self.side_mut(direction);
+
+        side.copy_within(index + 1.., index);
+        side[ORDER_BOOK_SIDE_SIZE - 1] = OrderBookEntry::default();
+    }
+}
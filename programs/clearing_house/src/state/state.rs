@@ -1,5 +1,8 @@
 use anchor_lang::prelude::*;
 
+use crate::state::insurance_fund::InsuranceFund;
+use crate::state::lending::LendingPool;
+
 #[account]
 #[derive(Default)]
 #[repr(packed)]
@@ -40,12 +43,29 @@ pub struct State {
     pub max_deposit: u128,
     pub extended_curve_history: Pubkey,
     pub order_state: Pubkey,
+    // rebate paid to makers out of the taker fee
+    pub maker_rebate_numerator: u128,
+    pub maker_rebate_denominator: u128,
 
     // upgrade-ability
     pub padding0: u128,
     pub padding1: u128,
-    pub padding2: u128,
-    pub padding3: u128,
+
+    // fields appended to the original layout, states created before them are resized by migrate_state
+    pub extended_trade_history: Pubkey,
+    pub collateral_assets: Pubkey,
+    pub quote_lending_pool: LendingPool,
+    pub insurance_fund: InsuranceFund,
+    pub bankruptcy_history: Pubkey,
+    pub position_transfer_discount_numerator: u128,
+    pub position_transfer_discount_denominator: u128,
+    pub extended_liquidation_history: Pubkey,
+    pub liquidation_auction: LiquidationAuction,
+    pub formulaic_repeg_threshold_numerator: u128,
+    pub formulaic_repeg_threshold_denominator: u128,
+    pub formulaic_k_max_change_numerator: u128,
+    pub formulaic_k_max_change_denominator: u128,
+    pub extended_funding_rate_history: Pubkey,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
@@ -68,6 +88,16 @@ pub struct ValidityGuardRails {
     pub too_volatile_ratio: i128,
 }
 
+/// The liquidator's share of the liquidation fee starts at start_liquidator_share and rises
+/// linearly to the full share over duration slots from when the user was first flagged as
+/// liquidatable or liquidated
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct LiquidationAuction {
+    pub start_liquidator_share_numerator: u128,
+    pub start_liquidator_share_denominator: u128,
+    pub duration: u64,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
 pub struct FeeStructure {
     pub fee_numerator: u128,
@@ -1,4 +1,8 @@
 use anchor_lang::prelude::*;
+use std::cell::{Ref, RefMut};
+use std::mem::size_of;
+
+use crate::error::{ClearingHouseResult, ErrorCode};
 
 // space: 224
 #[account]
@@ -22,9 +26,22 @@ pub struct User {
     pub forgo_position_settlement: u8,
     pub has_settled_position: u8,
 
+    // sub accounts
+    pub sub_account_id: u8,
+
+    // collateral assets
+    pub has_collateral_assets: u8,
+
+    // lending
+    pub last_cumulative_deposit_interest: u128,
+    pub has_borrows: u8,
+
+    // liquidation auction for the cross margined positions, 0 if the user hasn't been flagged as
+    // liquidatable or liquidated
+    pub liquidation_start_slot: u64,
+
     // upgrade-ability
-    pub padding1: u128,
-    pub padding2: [u8; 14],
+    pub padding2: [u8; 3],
 }
 
 // space: 1072
@@ -36,6 +53,57 @@ pub struct UserPositions {
     pub positions: [MarketPosition; 5],
 }
 
+impl UserPositions {
+    // discriminator + user
+    pub const POSITIONS_OFFSET: usize = 8 + 32;
+
+    pub fn space(number_of_positions: usize) -> usize {
+        UserPositions::POSITIONS_OFFSET + number_of_positions * size_of::<MarketPosition>()
+    }
+
+    pub fn number_of_positions(data_len: usize) -> usize {
+        data_len.saturating_sub(UserPositions::POSITIONS_OFFSET) / size_of::<MarketPosition>()
+    }
+
+    /// The account can be resized to hold more positions than the 5 in the struct layout. Extra
+    /// positions are stored contiguously after `positions`, so the full set is read from the raw account data
+    pub fn load_positions<'a>(
+        user_positions: &'a AccountLoader<UserPositions>,
+    ) -> ClearingHouseResult<Ref<'a, [MarketPosition]>> {
+        user_positions
+            .load()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+        let account_info: &AccountInfo = user_positions.as_ref();
+        let data = account_info
+            .try_borrow_data()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let end = UserPositions::space(UserPositions::number_of_positions(data.len()));
+
+        Ok(Ref::map(data, |data| {
+            bytemuck::cast_slice(&data[UserPositions::POSITIONS_OFFSET..end])
+        }))
+    }
+
+    pub fn load_positions_mut<'a>(
+        user_positions: &'a AccountLoader<UserPositions>,
+    ) -> ClearingHouseResult<RefMut<'a, [MarketPosition]>> {
+        user_positions
+            .load_mut()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+
+        let account_info: &AccountInfo = user_positions.as_ref();
+        let data = account_info
+            .try_borrow_mut_data()
+            .or(Err(ErrorCode::UnableToLoadAccountLoader))?;
+        let end = UserPositions::space(UserPositions::number_of_positions(data.len()));
+
+        Ok(RefMut::map(data, |data| {
+            bytemuck::cast_slice_mut(&mut data[UserPositions::POSITIONS_OFFSET..end])
+        }))
+    }
+}
+
 // SPACE: 1040
 #[zero_copy]
 #[derive(Default)]
@@ -49,9 +117,14 @@ pub struct MarketPosition {
     pub last_funding_rate_ts: i64,
     pub open_orders: u128,
 
+    // isolated margin
+    pub isolated_collateral: u128,
+    pub is_isolated: u8,
+    pub padding1: [u8; 7],
+    // liquidation auction for the isolated position, 0 if it hasn't been flagged or liquidated
+    pub liquidation_start_slot: u64,
+
     // upgrade-ability
-    pub padding0: u128,
-    pub padding1: u128,
     pub padding2: u128,
     pub padding3: u128,
     pub padding4: u128,
@@ -59,13 +132,24 @@ pub struct MarketPosition {
     pub padding6: u128,
 }
 
+// needed to read positions stored past the end of the UserPositions struct layout
+//
+// SAFETY: MarketPosition is repr(packed) so it has no padding bytes, and every field is an integer
+// or an integer array, for which any bit pattern (including all zeros) is valid. Fields added to it
+// must keep both properties and keep the size below
+unsafe impl bytemuck::Pod for MarketPosition {}
+unsafe impl bytemuck::Zeroable for MarketPosition {}
+
+const _: () = assert!(size_of::<MarketPosition>() == 208);
+
 impl MarketPosition {
     pub fn is_for(&self, market_index: u64) -> bool {
-        self.market_index == market_index && (self.is_open_position() || self.has_open_order())
+        self.market_index == market_index
+            && (self.is_open_position() || self.has_open_order() || self.is_isolated())
     }
 
     pub fn is_available(&self) -> bool {
-        !self.is_open_position() && !self.has_open_order()
+        !self.is_open_position() && !self.has_open_order() && !self.is_isolated()
     }
 
     pub fn is_open_position(&self) -> bool {
@@ -75,4 +159,8 @@ impl MarketPosition {
     pub fn has_open_order(&self) -> bool {
         self.open_orders != 0
     }
+
+    pub fn is_isolated(&self) -> bool {
+        self.is_isolated == 1
+    }
 }
@@ -47,6 +47,13 @@ pub struct Order {
     pub trigger_condition: OrderTriggerCondition,
     pub referrer: Pubkey,
     pub oracle_price_offset: i128,
+    pub trailing_distance: u128,
+    pub trailing_distance_type: TrailingDistanceType,
+    pub slice_base_asset_amount: u128,
+    pub slice_interval: i64,
+    pub last_slice_ts: i64,
+    pub linked_order_id: u128,
+    pub max_ts: i64,
     pub padding: [u16; 3],
 }
 
@@ -87,6 +94,43 @@ impl Order {
 
         Ok(price)
     }
+
+    /// One-cancels-other orders are linked to each other, e.g. the take profit and stop loss of a
+    /// bracket order
+    pub fn is_linked(self) -> bool {
+        self.linked_order_id != 0
+    }
+
+    /// Good-til-time orders can't be filled once max_ts has passed. A max_ts of 0 never expires.
+    pub fn is_expired(self, now: i64) -> bool {
+        self.max_ts != 0 && now > self.max_ts
+    }
+
+    /// Twap orders can only fill one slice per interval
+    pub fn is_slice_available(self, now: i64) -> ClearingHouseResult<bool> {
+        if self.order_type != OrderType::Twap || self.last_slice_ts == 0 {
+            return Ok(true);
+        }
+
+        let next_slice_ts = self
+            .last_slice_ts
+            .checked_add(self.slice_interval)
+            .ok_or_else(math_error!())?;
+
+        Ok(now >= next_slice_ts)
+    }
+
+    /// Filler rewards grow with the time an order has been waiting to be filled. For twap orders,
+    /// each slice starts waiting once its interval has passed.
+    pub fn get_filler_reward_ts(self) -> ClearingHouseResult<i64> {
+        if self.order_type != OrderType::Twap || self.last_slice_ts == 0 {
+            return Ok(self.ts);
+        }
+
+        self.last_slice_ts
+            .checked_add(self.slice_interval)
+            .ok_or_else(math_error!())
+    }
 }
 
 impl Default for Order {
@@ -114,6 +158,13 @@ impl Default for Order {
             trigger_condition: OrderTriggerCondition::Above,
             referrer: Pubkey::default(),
             oracle_price_offset: 0,
+            trailing_distance: 0,
+            trailing_distance_type: TrailingDistanceType::Absolute,
+            slice_base_asset_amount: 0,
+            slice_interval: 0,
+            last_slice_ts: 0,
+            linked_order_id: 0,
+            max_ts: 0,
             padding: [0; 3],
         }
     }
@@ -131,6 +182,8 @@ pub enum OrderType {
     Limit,
     TriggerMarket,
     TriggerLimit,
+    TrailingStop,
+    Twap,
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq)]
@@ -154,3 +207,12 @@ impl Default for OrderTriggerCondition {
         OrderTriggerCondition::Above
     }
 }
+
+/// How far a trailing stop's trigger price trails the best price seen since the order was placed.
+/// Absolute distances use MARK_PRICE_PRECISION and relative distances use basis points.
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Default)]
+pub enum TrailingDistanceType {
+    #[default]
+    Absolute,
+    BasisPoints,
+}
@@ -0,0 +1,203 @@
+use crate::error::{ClearingHouseResult, ErrorCode};
+use crate::margin_validation::validate_liquidation_fees;
+use crate::math::constants::{
+    DEFAULT_FORMULAIC_K_MAX_CHANGE_DENOMINATOR, DEFAULT_FORMULAIC_K_MAX_CHANGE_NUMERATOR,
+    DEFAULT_FORMULAIC_REPEG_THRESHOLD_DENOMINATOR, DEFAULT_FORMULAIC_REPEG_THRESHOLD_NUMERATOR,
+    DEFAULT_INSURANCE_FUND_FEE_SHARE_DENOMINATOR, DEFAULT_INSURANCE_FUND_FEE_SHARE_NUMERATOR,
+    DEFAULT_INSURANCE_FUND_UNSTAKING_PERIOD, DEFAULT_LIQUIDATION_AUCTION_DURATION,
+    DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_DENOMINATOR,
+    DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_NUMERATOR, DEFAULT_MAKER_REBATE_DENOMINATOR,
+    DEFAULT_MAKER_REBATE_NUMERATOR, DEFAULT_POSITION_TRANSFER_DISCOUNT_DENOMINATOR,
+    DEFAULT_POSITION_TRANSFER_DISCOUNT_NUMERATOR,
+};
+use crate::math::liquidation::calculate_market_liquidation_fees;
+use crate::state::insurance_fund::InsuranceFund;
+use crate::state::lending::LendingPool;
+use crate::state::market::Market;
+use crate::state::state::{LiquidationAuction, State};
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+
+/// Resizes a State account created with an older, shorter layout to fit the current one and sets
+/// the fields appended to the State since to the values `initialize` gives them.
+#[allow(clippy::result_large_err)]
+pub fn migrate<'info>(
+    state_account_info: &AccountInfo<'info>,
+    admin: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    program_id: &Pubkey,
+) -> Result<()> {
+    if state_account_info.owner != program_id {
+        return Err(error::ErrorCode::AccountOwnedByWrongProgram.into());
+    }
+
+    let new_space = std::mem::size_of::<State>() + 8;
+    if state_account_info.data_len() >= new_space {
+        return Err(ErrorCode::StateAlreadyMigrated.into());
+    }
+
+    // the state can't be deserialized until it's resized, so the admin is read from the raw data
+    {
+        let data = state_account_info.try_borrow_data()?;
+        if data.len() < 40 || data[..8] != State::discriminator() {
+            return Err(error::ErrorCode::AccountDiscriminatorMismatch.into());
+        }
+
+        if Pubkey::new(&data[8..40]) != admin.key() {
+            return Err(error::ErrorCode::ConstraintHasOne.into());
+        }
+    }
+
+    let rent_exempt_lamports = Rent::get()?.minimum_balance(new_space);
+    let lamports_required = rent_exempt_lamports.saturating_sub(state_account_info.lamports());
+
+    if lamports_required > 0 {
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: admin.to_account_info(),
+                    to: state_account_info.clone(),
+                },
+            ),
+            lamports_required,
+        )?;
+    }
+
+    state_account_info.realloc(new_space, true)?;
+
+    let mut data = state_account_info.try_borrow_mut_data()?;
+    let mut state = State::try_deserialize(&mut &data[..])?;
+    set_migrated_field_defaults(&mut state, Clock::get()?.unix_timestamp);
+    state.try_serialize(&mut &mut data[..])?;
+
+    Ok(())
+}
+
+/// Sets the fields an older state holds zeroed, because they were appended to the layout or took
+/// over its padding, to the values `initialize` gives them
+fn set_migrated_field_defaults(state: &mut State, now: i64) {
+    state.maker_rebate_numerator = DEFAULT_MAKER_REBATE_NUMERATOR;
+    state.maker_rebate_denominator = DEFAULT_MAKER_REBATE_DENOMINATOR;
+    state.quote_lending_pool = LendingPool::new(now);
+    state.insurance_fund = InsuranceFund {
+        stake_history: Pubkey::default(),
+        total_shares: 0,
+        protocol_shares: 0,
+        unstaking_period: DEFAULT_INSURANCE_FUND_UNSTAKING_PERIOD,
+        fee_share_numerator: DEFAULT_INSURANCE_FUND_FEE_SHARE_NUMERATOR,
+        fee_share_denominator: DEFAULT_INSURANCE_FUND_FEE_SHARE_DENOMINATOR,
+    };
+    state.position_transfer_discount_numerator = DEFAULT_POSITION_TRANSFER_DISCOUNT_NUMERATOR;
+    state.position_transfer_discount_denominator = DEFAULT_POSITION_TRANSFER_DISCOUNT_DENOMINATOR;
+    state.liquidation_auction = LiquidationAuction {
+        start_liquidator_share_numerator:
+            DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_NUMERATOR,
+        start_liquidator_share_denominator:
+            DEFAULT_LIQUIDATION_AUCTION_START_LIQUIDATOR_SHARE_DENOMINATOR,
+        duration: DEFAULT_LIQUIDATION_AUCTION_DURATION,
+    };
+    state.formulaic_repeg_threshold_numerator = DEFAULT_FORMULAIC_REPEG_THRESHOLD_NUMERATOR;
+    state.formulaic_repeg_threshold_denominator = DEFAULT_FORMULAIC_REPEG_THRESHOLD_DENOMINATOR;
+    state.formulaic_k_max_change_numerator = DEFAULT_FORMULAIC_K_MAX_CHANGE_NUMERATOR;
+    state.formulaic_k_max_change_denominator = DEFAULT_FORMULAIC_K_MAX_CHANGE_DENOMINATOR;
+}
+
+/// Seeds the liquidation fees of a market initialized before markets had their own with the
+/// exchange wide ones, which is what `initialize_market` gives new markets
+pub fn migrate_market(market: &mut Market, state: &State) -> ClearingHouseResult {
+    if market.partial_liquidation_penalty != 0
+        || market.full_liquidation_penalty != 0
+        || market.partial_liquidation_liquidator_share != 0
+        || market.full_liquidation_liquidator_share != 0
+    {
+        return Err(ErrorCode::MarketAlreadyMigrated);
+    }
+
+    let (
+        partial_liquidation_penalty,
+        full_liquidation_penalty,
+        partial_liquidation_liquidator_share,
+        full_liquidation_liquidator_share,
+    ) = calculate_market_liquidation_fees(state)?;
+    validate_liquidation_fees(partial_liquidation_penalty, full_liquidation_penalty)?;
+    validate_liquidation_fees(
+        partial_liquidation_liquidator_share,
+        full_liquidation_liquidator_share,
+    )?;
+
+    market.partial_liquidation_penalty = partial_liquidation_penalty;
+    market.full_liquidation_penalty = full_liquidation_penalty;
+    market.partial_liquidation_liquidator_share = partial_liquidation_liquidator_share;
+    market.full_liquidation_liquidator_share = full_liquidation_liquidator_share;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrated_state_pays_maker_rebates() {
+        let mut state = State::default();
+        set_migrated_field_defaults(&mut state, 0);
+
+        let (maker_rebate_numerator, maker_rebate_denominator) =
+            (state.maker_rebate_numerator, state.maker_rebate_denominator);
+        assert_eq!(maker_rebate_numerator, DEFAULT_MAKER_REBATE_NUMERATOR);
+        assert_eq!(maker_rebate_denominator, DEFAULT_MAKER_REBATE_DENOMINATOR);
+    }
+
+    #[test]
+    fn migrated_state_gets_appended_field_defaults() {
+        let mut state = State::default();
+        set_migrated_field_defaults(&mut state, 100);
+
+        let last_interest_ts = state.quote_lending_pool.last_interest_ts;
+        assert_eq!(last_interest_ts, 100);
+        let unstaking_period = state.insurance_fund.unstaking_period;
+        assert_eq!(unstaking_period, DEFAULT_INSURANCE_FUND_UNSTAKING_PERIOD);
+        let duration = state.liquidation_auction.duration;
+        assert_eq!(duration, DEFAULT_LIQUIDATION_AUCTION_DURATION);
+        let formulaic_k_max_change_denominator = state.formulaic_k_max_change_denominator;
+        assert_eq!(
+            formulaic_k_max_change_denominator,
+            DEFAULT_FORMULAIC_K_MAX_CHANGE_DENOMINATOR
+        );
+    }
+
+    #[test]
+    fn migrated_market_gets_state_liquidation_fees() {
+        let state = State {
+            partial_liquidation_penalty_percentage_numerator: 25,
+            partial_liquidation_penalty_percentage_denominator: 1000,
+            full_liquidation_penalty_percentage_numerator: 1,
+            full_liquidation_penalty_percentage_denominator: 1,
+            partial_liquidation_liquidator_share_denominator: 2,
+            full_liquidation_liquidator_share_denominator: 20,
+            ..State::default()
+        };
+        let mut market = Market::default();
+        migrate_market(&mut market, &state).unwrap();
+
+        let (
+            partial_liquidation_penalty,
+            full_liquidation_penalty,
+            partial_liquidation_liquidator_share,
+            full_liquidation_liquidator_share,
+        ) = (
+            market.partial_liquidation_penalty,
+            market.full_liquidation_penalty,
+            market.partial_liquidation_liquidator_share,
+            market.full_liquidation_liquidator_share,
+        );
+        assert_eq!(partial_liquidation_penalty, 250);
+        assert_eq!(full_liquidation_penalty, 10000);
+        assert_eq!(partial_liquidation_liquidator_share, 5000);
+        assert_eq!(full_liquidation_liquidator_share, 500);
+
+        // the fees are only seeded once, later changes go through the market's own setters
+        assert!(migrate_market(&mut market, &state).is_err());
+    }
+}
@@ -12,6 +12,7 @@ pub fn initialize(
     authority: &Signer,
     remaining_accounts: &[AccountInfo],
     optional_accounts: InitializeUserOptionalAccounts,
+    sub_account_id: u8,
 ) -> Result<()> {
     if !state.whitelist_mint.eq(&Pubkey::default()) {
         let whitelist_token =
@@ -41,8 +42,13 @@ pub fn initialize(
     user.forgo_position_settlement = 0;
     user.has_settled_position = 0;
 
-    user.padding1 = 0;
-    user.padding2 = [0; 14];
+    user.sub_account_id = sub_account_id;
+
+    user.last_cumulative_deposit_interest = state.quote_lending_pool.cumulative_deposit_interest;
+
+    user.liquidation_start_slot = 0;
+
+    user.padding2 = [0; 3];
 
     let user_positions = &mut user_positions.load_init()?;
     user_positions.user = *user.to_account_info().key;
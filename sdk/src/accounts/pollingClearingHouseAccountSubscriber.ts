@@ -11,14 +11,14 @@ import { EventEmitter } from 'events';
 import {
 	DepositHistoryAccount,
 	ExtendedCurveHistoryAccount,
+	ExtendedFundingRateHistoryAccount,
+	ExtendedLiquidationHistoryAccount,
+	ExtendedTradeHistoryAccount,
 	FundingPaymentHistoryAccount,
-	FundingRateHistoryAccount,
-	LiquidationHistoryAccount,
 	MarketsAccount,
 	OrderHistoryAccount,
 	OrderStateAccount,
 	StateAccount,
-	TradeHistoryAccount,
 } from '../types';
 import { getClearingHouseStateAccountPublicKey } from '../addresses';
 import { BulkAccountLoader } from './bulkAccountLoader';
@@ -41,11 +41,11 @@ export class PollingClearingHouseAccountSubscriber
 	state?: StateAccount;
 	markets?: MarketsAccount;
 	orderState?: OrderStateAccount;
-	tradeHistory?: TradeHistoryAccount;
+	extendedTradeHistory?: ExtendedTradeHistoryAccount;
 	depositHistory?: DepositHistoryAccount;
 	fundingPaymentHistory?: FundingPaymentHistoryAccount;
-	fundingRateHistory?: FundingRateHistoryAccount;
-	liquidationHistory?: LiquidationHistoryAccount;
+	extendedFundingRateHistory?: ExtendedFundingRateHistoryAccount;
+	extendedLiquidationHistory?: ExtendedLiquidationHistoryAccount;
 	extendedCurveHistory: ExtendedCurveHistoryAccount;
 	orderHistory?: OrderHistoryAccount;
 
@@ -131,9 +131,9 @@ export class PollingClearingHouseAccountSubscriber
 		});
 
 		if (this.optionalExtraSubscriptions?.includes('tradeHistoryAccount')) {
-			this.accountsToPoll.set(accounts.tradeHistory.toString(), {
-				key: 'tradeHistory',
-				publicKey: accounts.tradeHistory,
+			this.accountsToPoll.set(accounts.extendedTradeHistory.toString(), {
+				key: 'extendedTradeHistory',
+				publicKey: accounts.extendedTradeHistory,
 				eventType: 'tradeHistoryAccountUpdate',
 			});
 		}
@@ -159,9 +159,9 @@ export class PollingClearingHouseAccountSubscriber
 		if (
 			this.optionalExtraSubscriptions?.includes('fundingRateHistoryAccount')
 		) {
-			this.accountsToPoll.set(accounts.fundingRateHistory.toString(), {
-				key: 'fundingRateHistory',
-				publicKey: accounts.fundingRateHistory,
+			this.accountsToPoll.set(accounts.extendedFundingRateHistory.toString(), {
+				key: 'extendedFundingRateHistory',
+				publicKey: accounts.extendedFundingRateHistory,
 				eventType: 'fundingRateHistoryAccountUpdate',
 			});
 		}
@@ -177,9 +177,9 @@ export class PollingClearingHouseAccountSubscriber
 		if (
 			this.optionalExtraSubscriptions?.includes('liquidationHistoryAccount')
 		) {
-			this.accountsToPoll.set(accounts.liquidationHistory.toString(), {
-				key: 'liquidationHistory',
-				publicKey: accounts.liquidationHistory,
+			this.accountsToPoll.set(accounts.extendedLiquidationHistory.toString(), {
+				key: 'extendedLiquidationHistory',
+				publicKey: accounts.extendedLiquidationHistory,
 				eventType: 'liquidationHistoryAccountUpdate',
 			});
 		}
@@ -211,12 +211,12 @@ export class PollingClearingHouseAccountSubscriber
 			state: statePublicKey,
 			markets: state.markets,
 			orderState: state.orderState,
-			tradeHistory: state.tradeHistory,
+			extendedTradeHistory: state.extendedTradeHistory,
 			depositHistory: state.depositHistory,
 			fundingPaymentHistory: state.fundingPaymentHistory,
-			fundingRateHistory: state.fundingRateHistory,
+			extendedFundingRateHistory: state.extendedFundingRateHistory,
 			extendedCurveHistory: state.extendedCurveHistory,
-			liquidationHistory: state.liquidationHistory,
+			extendedLiquidationHistory: state.extendedLiquidationHistory,
 			orderHistory: undefined,
 		};
 
@@ -337,10 +337,10 @@ export class PollingClearingHouseAccountSubscriber
 		return this.orderState;
 	}
 
-	public getTradeHistoryAccount(): TradeHistoryAccount {
+	public getTradeHistoryAccount(): ExtendedTradeHistoryAccount {
 		this.assertIsSubscribed();
 		this.assertOptionalIsSubscribed('tradeHistoryAccount');
-		return this.tradeHistory;
+		return this.extendedTradeHistory;
 	}
 
 	public getDepositHistoryAccount(): DepositHistoryAccount {
@@ -355,10 +355,10 @@ export class PollingClearingHouseAccountSubscriber
 		return this.fundingPaymentHistory;
 	}
 
-	public getFundingRateHistoryAccount(): FundingRateHistoryAccount {
+	public getFundingRateHistoryAccount(): ExtendedFundingRateHistoryAccount {
 		this.assertIsSubscribed();
 		this.assertOptionalIsSubscribed('fundingRateHistoryAccount');
-		return this.fundingRateHistory;
+		return this.extendedFundingRateHistory;
 	}
 
 	public getCurveHistoryAccount(): ExtendedCurveHistoryAccount {
@@ -367,10 +367,10 @@ export class PollingClearingHouseAccountSubscriber
 		return this.extendedCurveHistory;
 	}
 
-	public getLiquidationHistoryAccount(): LiquidationHistoryAccount {
+	public getLiquidationHistoryAccount(): ExtendedLiquidationHistoryAccount {
 		this.assertIsSubscribed();
 		this.assertOptionalIsSubscribed('liquidationHistoryAccount');
-		return this.liquidationHistory;
+		return this.extendedLiquidationHistory;
 	}
 
 	public getOrderHistoryAccount(): OrderHistoryAccount {
@@ -384,11 +384,11 @@ type ClearingHouseAccounts = {
 	state: PublicKey;
 	markets: PublicKey;
 	orderState: PublicKey;
-	tradeHistory?: PublicKey;
+	extendedTradeHistory?: PublicKey;
 	depositHistory?: PublicKey;
 	fundingPaymentHistory?: PublicKey;
-	fundingRateHistory?: PublicKey;
+	extendedFundingRateHistory?: PublicKey;
 	extendedCurveHistory?: PublicKey;
-	liquidationHistory?: PublicKey;
+	extendedLiquidationHistory?: PublicKey;
 	orderHistory?: PublicKey;
 };
@@ -1,14 +1,14 @@
 import {
 	DepositHistoryAccount,
 	ExtendedCurveHistoryAccount,
+	ExtendedFundingRateHistoryAccount,
+	ExtendedLiquidationHistoryAccount,
+	ExtendedTradeHistoryAccount,
 	FundingPaymentHistoryAccount,
-	FundingRateHistoryAccount,
-	LiquidationHistoryAccount,
 	MarketsAccount,
 	OrderHistoryAccount,
 	OrderStateAccount,
 	StateAccount,
-	TradeHistoryAccount,
 	UserAccount,
 	UserOrdersAccount,
 	UserPositionsAccount,
@@ -40,9 +40,13 @@ export interface ClearingHouseAccountEvents {
 	fundingPaymentHistoryAccountUpdate: (
 		payload: FundingPaymentHistoryAccount
 	) => void;
-	fundingRateHistoryAccountUpdate: (payload: FundingRateHistoryAccount) => void;
-	tradeHistoryAccountUpdate: (payload: TradeHistoryAccount) => void;
-	liquidationHistoryAccountUpdate: (payload: LiquidationHistoryAccount) => void;
+	fundingRateHistoryAccountUpdate: (
+		payload: ExtendedFundingRateHistoryAccount
+	) => void;
+	tradeHistoryAccountUpdate: (payload: ExtendedTradeHistoryAccount) => void;
+	liquidationHistoryAccountUpdate: (
+		payload: ExtendedLiquidationHistoryAccount
+	) => void;
 	depositHistoryAccountUpdate: (payload: DepositHistoryAccount) => void;
 	curveHistoryAccountUpdate: (payload: ExtendedCurveHistoryAccount) => void;
 	orderHistoryAccountUpdate: (payload: OrderHistoryAccount) => void;
@@ -74,12 +78,12 @@ export interface ClearingHouseAccountSubscriber {
 
 	getStateAccount(): StateAccount;
 	getMarketsAccount(): MarketsAccount;
-	getTradeHistoryAccount(): TradeHistoryAccount;
+	getTradeHistoryAccount(): ExtendedTradeHistoryAccount;
 	getDepositHistoryAccount(): DepositHistoryAccount;
 	getFundingPaymentHistoryAccount(): FundingPaymentHistoryAccount;
-	getFundingRateHistoryAccount(): FundingRateHistoryAccount;
+	getFundingRateHistoryAccount(): ExtendedFundingRateHistoryAccount;
 	getCurveHistoryAccount(): ExtendedCurveHistoryAccount;
-	getLiquidationHistoryAccount(): LiquidationHistoryAccount;
+	getLiquidationHistoryAccount(): ExtendedLiquidationHistoryAccount;
 	getOrderStateAccount(): OrderStateAccount;
 	getOrderHistoryAccount(): OrderHistoryAccount;
 
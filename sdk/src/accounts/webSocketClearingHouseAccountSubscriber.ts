@@ -7,14 +7,14 @@ import { AccountSubscriber, NotSubscribedError } from './types';
 import {
 	DepositHistoryAccount,
 	ExtendedCurveHistoryAccount,
+	ExtendedFundingRateHistoryAccount,
+	ExtendedLiquidationHistoryAccount,
+	ExtendedTradeHistoryAccount,
 	FundingPaymentHistoryAccount,
-	FundingRateHistoryAccount,
-	LiquidationHistoryAccount,
 	MarketsAccount,
 	OrderHistoryAccount,
 	OrderStateAccount,
 	StateAccount,
-	TradeHistoryAccount,
 } from '../types';
 import { Program } from '@project-serum/anchor';
 import StrictEventEmitter from 'strict-event-emitter-types';
@@ -31,12 +31,12 @@ export class WebSocketClearingHouseAccountSubscriber
 	eventEmitter: StrictEventEmitter<EventEmitter, ClearingHouseAccountEvents>;
 	stateAccountSubscriber?: AccountSubscriber<StateAccount>;
 	marketsAccountSubscriber?: AccountSubscriber<MarketsAccount>;
-	tradeHistoryAccountSubscriber?: AccountSubscriber<TradeHistoryAccount>;
+	tradeHistoryAccountSubscriber?: AccountSubscriber<ExtendedTradeHistoryAccount>;
 	depositHistoryAccountSubscriber?: AccountSubscriber<DepositHistoryAccount>;
 	fundingPaymentHistoryAccountSubscriber?: AccountSubscriber<FundingPaymentHistoryAccount>;
-	fundingRateHistoryAccountSubscriber?: AccountSubscriber<FundingRateHistoryAccount>;
+	fundingRateHistoryAccountSubscriber?: AccountSubscriber<ExtendedFundingRateHistoryAccount>;
 	curveHistoryAccountSubscriber?: AccountSubscriber<ExtendedCurveHistoryAccount>;
-	liquidationHistoryAccountSubscriber?: AccountSubscriber<LiquidationHistoryAccount>;
+	liquidationHistoryAccountSubscriber?: AccountSubscriber<ExtendedLiquidationHistoryAccount>;
 	orderStateAccountSubscriber?: AccountSubscriber<OrderStateAccount>;
 	orderHistoryAccountSubscriber?: AccountSubscriber<OrderHistoryAccount>;
 
@@ -117,9 +117,9 @@ export class WebSocketClearingHouseAccountSubscriber
 		// create subscribers for other state accounts
 
 		this.tradeHistoryAccountSubscriber = new WebSocketAccountSubscriber(
-			'tradeHistory',
+			'extendedTradeHistory',
 			this.program,
-			state.tradeHistory
+			state.extendedTradeHistory
 		);
 
 		this.depositHistoryAccountSubscriber = new WebSocketAccountSubscriber(
@@ -136,15 +136,15 @@ export class WebSocketClearingHouseAccountSubscriber
 			);
 
 		this.fundingRateHistoryAccountSubscriber = new WebSocketAccountSubscriber(
-			'fundingRateHistory',
+			'extendedFundingRateHistory',
 			this.program,
-			state.fundingRateHistory
+			state.extendedFundingRateHistory
 		);
 
 		this.liquidationHistoryAccountSubscriber = new WebSocketAccountSubscriber(
-			'liquidationHistory',
+			'extendedLiquidationHistory',
 			this.program,
-			state.liquidationHistory
+			state.extendedLiquidationHistory
 		);
 
 		this.curveHistoryAccountSubscriber = new WebSocketAccountSubscriber(
@@ -322,7 +322,7 @@ export class WebSocketClearingHouseAccountSubscriber
 		return this.marketsAccountSubscriber.data;
 	}
 
-	public getTradeHistoryAccount(): TradeHistoryAccount {
+	public getTradeHistoryAccount(): ExtendedTradeHistoryAccount {
 		this.assertIsSubscribed();
 		this.assertOptionalIsSubscribed('tradeHistoryAccount');
 		return this.tradeHistoryAccountSubscriber.data;
@@ -340,7 +340,7 @@ export class WebSocketClearingHouseAccountSubscriber
 		return this.fundingPaymentHistoryAccountSubscriber.data;
 	}
 
-	public getFundingRateHistoryAccount(): FundingRateHistoryAccount {
+	public getFundingRateHistoryAccount(): ExtendedFundingRateHistoryAccount {
 		this.assertIsSubscribed();
 		this.assertOptionalIsSubscribed('fundingRateHistoryAccount');
 		return this.fundingRateHistoryAccountSubscriber.data;
@@ -352,7 +352,7 @@ export class WebSocketClearingHouseAccountSubscriber
 		return this.curveHistoryAccountSubscriber.data;
 	}
 
-	public getLiquidationHistoryAccount(): LiquidationHistoryAccount {
+	public getLiquidationHistoryAccount(): ExtendedLiquidationHistoryAccount {
 		this.assertIsSubscribed();
 		this.assertOptionalIsSubscribed('liquidationHistoryAccount');
 		return this.liquidationHistoryAccountSubscriber.data;
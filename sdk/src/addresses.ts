@@ -1,5 +1,6 @@
 import { PublicKey } from '@solana/web3.js';
 import * as anchor from '@project-serum/anchor';
+import { BN } from '@project-serum/anchor';
 
 export async function getClearingHouseStateAccountPublicKeyAndNonce(
 	programId: PublicKey
@@ -48,6 +49,36 @@ export async function getUserAccountPublicKey(
 	return (await getUserAccountPublicKeyAndNonce(programId, authority))[0];
 }
 
+export async function getUserSubAccountPublicKeyAndNonce(
+	programId: PublicKey,
+	authority: PublicKey,
+	subAccountId: number
+): Promise<[PublicKey, number]> {
+	// sub account 0 is the user account created by initializeUser
+	if (subAccountId === 0) {
+		return getUserAccountPublicKeyAndNonce(programId, authority);
+	}
+
+	return anchor.web3.PublicKey.findProgramAddress(
+		[
+			Buffer.from(anchor.utils.bytes.utf8.encode('user')),
+			authority.toBuffer(),
+			Buffer.from([subAccountId]),
+		],
+		programId
+	);
+}
+
+export async function getUserSubAccountPublicKey(
+	programId: PublicKey,
+	authority: PublicKey,
+	subAccountId: number
+): Promise<PublicKey> {
+	return (
+		await getUserSubAccountPublicKeyAndNonce(programId, authority, subAccountId)
+	)[0];
+}
+
 export async function getUserOrdersAccountPublicKeyAndNonce(
 	programId: PublicKey,
 	userAccount: PublicKey
@@ -80,3 +111,91 @@ export async function getSettlementStatePublicKey(
 		)
 	)[0];
 }
+
+export async function getOrderBookPublicKeyAndNonce(
+	programId: PublicKey,
+	marketIndex: BN
+): Promise<[PublicKey, number]> {
+	return anchor.web3.PublicKey.findProgramAddress(
+		[
+			Buffer.from(anchor.utils.bytes.utf8.encode('order_book')),
+			marketIndex.toArrayLike(Buffer, 'le', 8),
+		],
+		programId
+	);
+}
+
+export async function getOrderBookPublicKey(
+	programId: PublicKey,
+	marketIndex: BN
+): Promise<PublicKey> {
+	return (await getOrderBookPublicKeyAndNonce(programId, marketIndex))[0];
+}
+
+export async function getCollateralAssetsPublicKey(
+	programId: PublicKey
+): Promise<PublicKey> {
+	return (
+		await anchor.web3.PublicKey.findProgramAddress(
+			[Buffer.from(anchor.utils.bytes.utf8.encode('collateral_assets'))],
+			programId
+		)
+	)[0];
+}
+
+export async function getCollateralAssetVaultPublicKey(
+	programId: PublicKey,
+	collateralAssetMint: PublicKey
+): Promise<PublicKey> {
+	return (
+		await anchor.web3.PublicKey.findProgramAddress(
+			[
+				Buffer.from(anchor.utils.bytes.utf8.encode('collateral_asset_vault')),
+				collateralAssetMint.toBuffer(),
+			],
+			programId
+		)
+	)[0];
+}
+
+export async function getCollateralAssetVaultAuthorityPublicKey(
+	programId: PublicKey,
+	collateralAssetVault: PublicKey
+): Promise<PublicKey> {
+	return (
+		await anchor.web3.PublicKey.findProgramAddress(
+			[collateralAssetVault.toBuffer()],
+			programId
+		)
+	)[0];
+}
+
+export async function getUserCollateralAssetsPublicKey(
+	programId: PublicKey,
+	userAccount: PublicKey
+): Promise<PublicKey> {
+	return (
+		await anchor.web3.PublicKey.findProgramAddress(
+			[
+				Buffer.from(anchor.utils.bytes.utf8.encode('user_collateral_assets')),
+				userAccount.toBuffer(),
+			],
+			programId
+		)
+	)[0];
+}
+
+export async function getInsuranceFundStakePublicKey(
+	programId: PublicKey,
+	authority: PublicKey
+): Promise<PublicKey> {
+	return (
+		await anchor.web3.PublicKey.findProgramAddress(
+			[
+				Buffer.from(anchor.utils.bytes.utf8.encode('insurance_fund_stake')),
+				authority.toBuffer(),
+			],
+			programId
+		)
+	)[0];
+}
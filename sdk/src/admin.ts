@@ -6,8 +6,10 @@ import {
 	TransactionSignature,
 } from '@solana/web3.js';
 import {
+	CurveType,
 	FeeStructure,
 	IWallet,
+	LiquidationAuction,
 	OracleGuardRails,
 	OracleSource,
 	OrderFillerRewardStructure,
@@ -17,13 +19,17 @@ import * as anchor from '@project-serum/anchor';
 import {
 	getClearingHouseStateAccountPublicKey,
 	getClearingHouseStateAccountPublicKeyAndNonce,
+	getCollateralAssetsPublicKey,
+	getCollateralAssetVaultAuthorityPublicKey,
+	getCollateralAssetVaultPublicKey,
+	getOrderBookPublicKey,
 	getOrderStateAccountPublicKeyAndNonce,
 	getSettlementStatePublicKey,
 	getUserAccountPublicKey,
 } from './addresses';
 import { TOKEN_PROGRAM_ID } from '@solana/spl-token';
 import { ClearingHouse } from './clearingHouse';
-import { PEG_PRECISION } from './constants/numericConstants';
+import { PEG_PRECISION, ZERO } from './constants/numericConstants';
 import { calculateTargetPriceTrade } from './math/trade';
 import { calculateAmmReservesAfterSwap, getSwapDirection } from './math/amm';
 import {
@@ -51,7 +57,12 @@ export class Admin extends ClearingHouse {
 		usdcMint: PublicKey,
 		adminControlsPrices: boolean
 	): Promise<
-		[TransactionSignature, TransactionSignature, TransactionSignature]
+		[
+			TransactionSignature,
+			TransactionSignature,
+			TransactionSignature,
+			TransactionSignature
+		]
 	> {
 		const stateAccountRPCResponse = await this.connection.getParsedAccountInfo(
 			await this.getStatePublicKey()
@@ -142,16 +153,16 @@ export class Admin extends ClearingHouse {
 					systemProgram: anchor.web3.SystemProgram.programId,
 				},
 				instructions: [
-					await this.program.account.fundingRateHistory.createInstruction(
+					await this.program.account.extendedFundingRateHistory.createInstruction(
 						fundingRateHistory
 					),
 					await this.program.account.fundingPaymentHistory.createInstruction(
 						fundingPaymentHistory
 					),
-					await this.program.account.tradeHistory.createInstruction(
+					await this.program.account.extendedTradeHistory.createInstruction(
 						tradeHistory
 					),
-					await this.program.account.liquidationHistory.createInstruction(
+					await this.program.account.extendedLiquidationHistory.createInstruction(
 						liquidationHistory
 					),
 					await this.program.account.depositHistory.createInstruction(
@@ -178,7 +189,42 @@ export class Admin extends ClearingHouse {
 
 		const initializeOrderStateTxSig = await this.initializeOrderState();
 
-		return [initializeTxSig, initializeHistoryTxSig, initializeOrderStateTxSig];
+		const initializeBankruptcyHistoryTxSig =
+			await this.initializeBankruptcyHistory();
+
+		return [
+			initializeTxSig,
+			initializeHistoryTxSig,
+			initializeOrderStateTxSig,
+			initializeBankruptcyHistoryTxSig,
+		];
+	}
+
+	/**
+	 * Creates the history that records how each bankrupt liquidation's loss was covered
+	 * @returns
+	 */
+	public async initializeBankruptcyHistory(): Promise<TransactionSignature> {
+		const bankruptcyHistory = anchor.web3.Keypair.generate();
+		const initializeBankruptcyHistoryTx =
+			await this.program.transaction.initializeBankruptcyHistory({
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					bankruptcyHistory: bankruptcyHistory.publicKey,
+				},
+				instructions: [
+					await this.program.account.bankruptcyHistory.createInstruction(
+						bankruptcyHistory
+					),
+				],
+			});
+
+		return await this.txSender.send(
+			initializeBankruptcyHistoryTx,
+			[bankruptcyHistory],
+			this.opts
+		);
 	}
 
 	public async initializeOrderState(): Promise<TransactionSignature> {
@@ -212,6 +258,180 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	public async initializeOrderBook(
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.initializeOrderBook(marketIndex, {
+			accounts: {
+				admin: this.wallet.publicKey,
+				state: await this.getStatePublicKey(),
+				markets: this.getStateAccount().markets,
+				orderBook: await getOrderBookPublicKey(
+					this.program.programId,
+					marketIndex
+				),
+				rent: SYSVAR_RENT_PUBKEY,
+				systemProgram: anchor.web3.SystemProgram.programId,
+			},
+		});
+	}
+
+	/**
+	 * Resizes a state created with an older layout and sets the fields appended to it since
+	 */
+	public async migrateState(): Promise<TransactionSignature> {
+		return await this.program.rpc.migrateState({
+			accounts: {
+				admin: this.wallet.publicKey,
+				state: await this.getStatePublicKey(),
+				systemProgram: anchor.web3.SystemProgram.programId,
+			},
+		});
+	}
+
+	/**
+	 * Gives a market initialized before markets had their own liquidation fees the state's ones
+	 * @param marketIndex
+	 * @returns
+	 */
+	public async migrateMarket(marketIndex: BN): Promise<TransactionSignature> {
+		return await this.program.rpc.migrateMarket(marketIndex, {
+			accounts: {
+				admin: this.wallet.publicKey,
+				state: await this.getStatePublicKey(),
+				markets: this.getStateAccount().markets,
+			},
+		});
+	}
+
+	public async initializeCollateralAssets(): Promise<TransactionSignature> {
+		return await this.program.rpc.initializeCollateralAssets({
+			accounts: {
+				admin: this.wallet.publicKey,
+				state: await this.getStatePublicKey(),
+				collateralAssets: await getCollateralAssetsPublicKey(
+					this.program.programId
+				),
+				rent: SYSVAR_RENT_PUBKEY,
+				systemProgram: anchor.web3.SystemProgram.programId,
+			},
+		});
+	}
+
+	/**
+	 * Registers an spl token that users can deposit as margin
+	 * @param assetIndex
+	 * @param collateralAssetMint
+	 * @param oracle
+	 * @param oracleSource
+	 * @param assetWeight haircut applied to the oracle price when valuing deposits, in MARGIN_PRECISION
+	 * @param liquidationDiscount discount to the oracle price the asset is sold to liquidators at, in MARGIN_PRECISION
+	 * @returns
+	 */
+	public async addCollateralAsset(
+		assetIndex: BN,
+		collateralAssetMint: PublicKey,
+		oracle: PublicKey,
+		oracleSource: OracleSource,
+		assetWeight: BN,
+		liquidationDiscount: BN
+	): Promise<TransactionSignature> {
+		const collateralAssetVault = await getCollateralAssetVaultPublicKey(
+			this.program.programId,
+			collateralAssetMint
+		);
+
+		return await this.program.rpc.addCollateralAsset(
+			assetIndex,
+			oracleSource,
+			assetWeight,
+			liquidationDiscount,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					collateralAssets: this.getStateAccount().collateralAssets,
+					collateralAssetMint,
+					collateralAssetVault,
+					collateralAssetVaultAuthority:
+						await getCollateralAssetVaultAuthorityPublicKey(
+							this.program.programId,
+							collateralAssetVault
+						),
+					oracle,
+					rent: SYSVAR_RENT_PUBKEY,
+					systemProgram: anchor.web3.SystemProgram.programId,
+					tokenProgram: TOKEN_PROGRAM_ID,
+				},
+			}
+		);
+	}
+
+	public async updateCollateralAssetWeights(
+		assetIndex: BN,
+		assetWeight: BN,
+		liquidationDiscount: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateCollateralAssetWeights(
+			assetIndex,
+			assetWeight,
+			liquidationDiscount,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					collateralAssets: this.getStateAccount().collateralAssets,
+				},
+			}
+		);
+	}
+
+	/**
+	 * Sets the utilization curve the quote lending pool's borrow rate follows
+	 * @param optimalUtilization in UTILIZATION_PRECISION
+	 * @param optimalBorrowRate the borrow rate at optimal utilization, in INTEREST_RATE_PRECISION
+	 * @param maxBorrowRate the borrow rate at full utilization, in INTEREST_RATE_PRECISION
+	 * @returns
+	 */
+	public async updateQuoteInterestRateCurve(
+		optimalUtilization: BN,
+		optimalBorrowRate: BN,
+		maxBorrowRate: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateQuoteInterestRateCurve(
+			optimalUtilization,
+			optimalBorrowRate,
+			maxBorrowRate,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
+	public async updateCollateralAssetInterestRateCurve(
+		assetIndex: BN,
+		optimalUtilization: BN,
+		optimalBorrowRate: BN,
+		maxBorrowRate: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateCollateralAssetInterestRateCurve(
+			assetIndex,
+			optimalUtilization,
+			optimalBorrowRate,
+			maxBorrowRate,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					collateralAssets: this.getStateAccount().collateralAssets,
+				},
+			}
+		);
+	}
+
 	public async initializeMarket(
 		marketIndex: BN,
 		priceOracle: PublicKey,
@@ -222,7 +442,9 @@ export class Admin extends ClearingHouse {
 		oracleSource: OracleSource = OracleSource.PYTH,
 		marginRatioInitial = 2000,
 		marginRatioPartial = 625,
-		marginRatioMaintenance = 500
+		marginRatioMaintenance = 500,
+		curveType: CurveType = CurveType.CONSTANT_PRODUCT,
+		amplificationCoefficient: BN = ZERO
 	): Promise<TransactionSignature> {
 		if (this.getMarketsAccount().markets[marketIndex.toNumber()].initialized) {
 			throw Error(`MarketIndex ${marketIndex.toNumber()} already initialized`);
@@ -238,6 +460,8 @@ export class Admin extends ClearingHouse {
 			marginRatioInitial,
 			marginRatioPartial,
 			marginRatioMaintenance,
+			curveType,
+			amplificationCoefficient,
 			{
 				accounts: {
 					state: await this.getStatePublicKey(),
@@ -256,6 +480,7 @@ export class Admin extends ClearingHouse {
 		marketIndex: BN
 	): Promise<TransactionSignature> {
 		const state = this.getStateAccount();
+		const market = this.getMarket(marketIndex);
 		return await this.program.rpc.moveAmmPrice(
 			baseAssetReserve,
 			quoteAssetReserve,
@@ -265,6 +490,7 @@ export class Admin extends ClearingHouse {
 					state: await this.getStatePublicKey(),
 					admin: this.wallet.publicKey,
 					markets: state.markets,
+					oracle: market.amm.oracle,
 				},
 			}
 		);
@@ -310,6 +536,66 @@ export class Admin extends ClearingHouse {
 		});
 	}
 
+	public async updateTradeHistory(): Promise<TransactionSignature> {
+		const extendedTradeHistory = anchor.web3.Keypair.generate();
+
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateTradeHistory({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				admin: this.wallet.publicKey,
+				tradeHistory: state.tradeHistory,
+				extendedTradeHistory: extendedTradeHistory.publicKey,
+			},
+			instructions: [
+				await this.program.account.extendedTradeHistory.createInstruction(
+					extendedTradeHistory
+				),
+			],
+			signers: [extendedTradeHistory],
+		});
+	}
+
+	public async updateLiquidationHistory(): Promise<TransactionSignature> {
+		const extendedLiquidationHistory = anchor.web3.Keypair.generate();
+
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateLiquidationHistory({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				admin: this.wallet.publicKey,
+				liquidationHistory: state.liquidationHistory,
+				extendedLiquidationHistory: extendedLiquidationHistory.publicKey,
+			},
+			instructions: [
+				await this.program.account.extendedLiquidationHistory.createInstruction(
+					extendedLiquidationHistory
+				),
+			],
+			signers: [extendedLiquidationHistory],
+		});
+	}
+
+	public async updateFundingRateHistory(): Promise<TransactionSignature> {
+		const extendedFundingRateHistory = anchor.web3.Keypair.generate();
+
+		const state = this.getStateAccount();
+		return await this.program.rpc.updateFundingRateHistory({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				admin: this.wallet.publicKey,
+				fundingRateHistory: state.fundingRateHistory,
+				extendedFundingRateHistory: extendedFundingRateHistory.publicKey,
+			},
+			instructions: [
+				await this.program.account.extendedFundingRateHistory.createInstruction(
+					extendedFundingRateHistory
+				),
+			],
+			signers: [extendedFundingRateHistory],
+		});
+	}
+
 	public async moveAmmToPrice(
 		marketIndex: BN,
 		targetPrice: BN
@@ -339,6 +625,7 @@ export class Admin extends ClearingHouse {
 					state: await this.getStatePublicKey(),
 					admin: this.wallet.publicKey,
 					markets: state.markets,
+					oracle: market.amm.oracle,
 				},
 			}
 		);
@@ -402,6 +689,33 @@ export class Admin extends ClearingHouse {
 		});
 	}
 
+	/**
+	 * Creates the stake history that opens the insurance fund to stakers
+	 * @returns
+	 */
+	public async initializeInsuranceFund(): Promise<TransactionSignature> {
+		const insuranceFundStakeHistory = anchor.web3.Keypair.generate();
+		const initializeInsuranceFundTx =
+			await this.program.transaction.initializeInsuranceFund({
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					insuranceFundStakeHistory: insuranceFundStakeHistory.publicKey,
+				},
+				instructions: [
+					await this.program.account.insuranceFundStakeHistory.createInstruction(
+						insuranceFundStakeHistory
+					),
+				],
+			});
+
+		return await this.txSender.send(
+			initializeInsuranceFundTx,
+			[insuranceFundStakeHistory],
+			this.opts
+		);
+	}
+
 	public async withdrawFromInsuranceVault(
 		amount: BN,
 		recipient: PublicKey
@@ -473,13 +787,65 @@ export class Admin extends ClearingHouse {
 		marketIndex: BN,
 		marginRatioInitial: number,
 		marginRatioPartial: number,
-		marginRatioMaintenance: number
+		marginRatioMaintenance: number,
+		imfFactor: number
 	): Promise<TransactionSignature> {
 		return await this.program.rpc.updateMarginRatio(
 			marketIndex,
 			marginRatioInitial,
 			marginRatioPartial,
 			marginRatioMaintenance,
+			imfFactor,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: this.getStateAccount().markets,
+				},
+			}
+		);
+	}
+
+	/**
+	 * @param marketIndex
+	 * @param partialLiquidationPenalty share of the total collateral charged per partial liquidation, in MARGIN_PRECISION
+	 * @param fullLiquidationPenalty share of the total collateral charged per full liquidation, in MARGIN_PRECISION
+	 * @returns
+	 */
+	public async updateLiquidationPenalty(
+		marketIndex: BN,
+		partialLiquidationPenalty: number,
+		fullLiquidationPenalty: number
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateLiquidationPenalty(
+			marketIndex,
+			partialLiquidationPenalty,
+			fullLiquidationPenalty,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: this.getStateAccount().markets,
+				},
+			}
+		);
+	}
+
+	/**
+	 * @param marketIndex
+	 * @param partialLiquidationLiquidatorShare share of the partial liquidation fee paid to the liquidator, in MARGIN_PRECISION
+	 * @param fullLiquidationLiquidatorShare share of the full liquidation fee paid to the liquidator, in MARGIN_PRECISION
+	 * @returns
+	 */
+	public async updateLiquidatorShare(
+		marketIndex: BN,
+		partialLiquidationLiquidatorShare: number,
+		fullLiquidationLiquidatorShare: number
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateLiquidatorShare(
+			marketIndex,
+			partialLiquidationLiquidatorShare,
+			fullLiquidationLiquidatorShare,
 			{
 				accounts: {
 					admin: this.wallet.publicKey,
@@ -507,6 +873,42 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	public async updateMarketMaxSpread(
+		marketIndex: BN,
+		maxSpread: number
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateMarketMaxSpread(
+			marketIndex,
+			maxSpread,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: this.getStateAccount().markets,
+				},
+			}
+		);
+	}
+
+	public async updateMarketFundingRateParameters(
+		marketIndex: BN,
+		maxFundingRate: number,
+		fundingInterestRate: number
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateMarketFundingRateParameters(
+			marketIndex,
+			maxFundingRate,
+			fundingInterestRate,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					markets: this.getStateAccount().markets,
+				},
+			}
+		);
+	}
+
 	public async updatePartialLiquidationClosePercentage(
 		numerator: BN,
 		denominator: BN
@@ -523,6 +925,9 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	/**
+	 * Only sets the fee that markets initialized or migrated afterwards start out with
+	 */
 	public async updatePartialLiquidationPenaltyPercentage(
 		numerator: BN,
 		denominator: BN
@@ -539,6 +944,9 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	/**
+	 * Only sets the fee that markets initialized or migrated afterwards start out with
+	 */
 	public async updateFullLiquidationPenaltyPercentage(
 		numerator: BN,
 		denominator: BN
@@ -555,6 +963,9 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	/**
+	 * Only sets the share that markets initialized or migrated afterwards start out with
+	 */
 	public async updatePartialLiquidationShareDenominator(
 		denominator: BN
 	): Promise<TransactionSignature> {
@@ -569,6 +980,9 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	/**
+	 * Only sets the share that markets initialized or migrated afterwards start out with
+	 */
 	public async updateFullLiquidationShareDenominator(
 		denominator: BN
 	): Promise<TransactionSignature> {
@@ -598,6 +1012,150 @@ export class Admin extends ClearingHouse {
 		);
 	}
 
+	public async updateMakerRebate(
+		makerRebateNumerator: BN,
+		makerRebateDenominator: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateMakerRebate(
+			makerRebateNumerator,
+			makerRebateDenominator,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
+	/**
+	 * @param unstakingPeriod seconds a staker waits between requesting to unstake and unstaking
+	 * @returns
+	 */
+	public async updateInsuranceFundUnstakingPeriod(
+		unstakingPeriod: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateInsuranceFundUnstakingPeriod(
+			unstakingPeriod,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
+	/**
+	 * Sets the share of the markets' fees that is paid to insurance fund stakers
+	 * @param feeShareNumerator
+	 * @param feeShareDenominator
+	 * @returns
+	 */
+	public async updateInsuranceFundFeeShare(
+		feeShareNumerator: BN,
+		feeShareDenominator: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateInsuranceFundFeeShare(
+			feeShareNumerator,
+			feeShareDenominator,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
+	/**
+	 * Sets the discount to the oracle price at which liquidators take over positions
+	 * @param numerator
+	 * @param denominator
+	 * @returns
+	 */
+	public async updatePositionTransferDiscount(
+		numerator: BN,
+		denominator: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updatePositionTransferDiscount(
+			numerator,
+			denominator,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
+	/**
+	 * Sets the liquidator's share of the liquidation fee when a user is first flagged as liquidatable
+	 * or liquidated and the number of slots it takes to rise to the full share
+	 * @param liquidationAuction
+	 * @returns
+	 */
+	public async updateLiquidationAuction(
+		liquidationAuction: LiquidationAuction
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateLiquidationAuction(
+			liquidationAuction,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
+	/**
+	 * Sets the mark/oracle gap past which fills repeg the amm toward the oracle price.
+	 * A zero denominator turns off repegging during fills
+	 * @param numerator
+	 * @param denominator
+	 * @returns
+	 */
+	public async updateFormulaicRepegThreshold(
+		numerator: BN,
+		denominator: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateFormulaicRepegThreshold(
+			numerator,
+			denominator,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
+	/**
+	 * Sets the most sqrt_k can change each funding period as the market's fee pool grows or shrinks.
+	 * A zero numerator turns off the adjustment
+	 * @param numerator
+	 * @param denominator
+	 * @returns
+	 */
+	public async updateFormulaicKMaxChange(
+		numerator: BN,
+		denominator: BN
+	): Promise<TransactionSignature> {
+		return await this.program.rpc.updateFormulaicKMaxChange(
+			numerator,
+			denominator,
+			{
+				accounts: {
+					admin: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+				},
+			}
+		);
+	}
+
 	public async updateFee(fees: FeeStructure): Promise<TransactionSignature> {
 		return await this.program.rpc.updateFee(fees, {
 			accounts: {
@@ -9,11 +9,11 @@ import {
 	StateAccount,
 	DepositHistoryAccount,
 	FundingPaymentHistoryAccount,
-	FundingRateHistoryAccount,
+	ExtendedFundingRateHistoryAccount,
 	IWallet,
-	LiquidationHistoryAccount,
+	ExtendedLiquidationHistoryAccount,
 	PositionDirection,
-	TradeHistoryAccount,
+	ExtendedTradeHistoryAccount,
 	UserAccount,
 	Market,
 	OrderHistoryAccount,
@@ -23,6 +23,13 @@ import {
 	ExtendedCurveHistoryAccount,
 	UserPositionsAccount,
 	SettlementStateAccount,
+	OrderBookAccount,
+	MakerInfo,
+	JitMakerParams,
+	ModifyOrderParams,
+	CollateralAssetsAccount,
+	UserCollateralAssetsAccount,
+	InsuranceFundStake,
 } from './types';
 import * as anchor from '@project-serum/anchor';
 import clearingHouseIDL from './idl/clearing_house.json';
@@ -35,6 +42,7 @@ import {
 	ConfirmOptions,
 	Transaction,
 	TransactionInstruction,
+	AccountMeta,
 } from '@solana/web3.js';
 
 import { MockUSDCFaucet } from './mockUSDCFaucet';
@@ -42,12 +50,16 @@ import { EventEmitter } from 'events';
 import StrictEventEmitter from 'strict-event-emitter-types';
 import {
 	getClearingHouseStateAccountPublicKey,
+	getInsuranceFundStakePublicKey,
+	getOrderBookPublicKey,
+	getUserCollateralAssetsPublicKey,
 	getOrderStateAccountPublicKey,
 	getSettlementStatePublicKey,
-	getUserAccountPublicKey,
 	getUserAccountPublicKeyAndNonce,
 	getUserOrdersAccountPublicKey,
 	getUserOrdersAccountPublicKeyAndNonce,
+	getUserSubAccountPublicKey,
+	getUserSubAccountPublicKeyAndNonce,
 } from './addresses';
 import {
 	ClearingHouseAccountSubscriber,
@@ -69,6 +81,7 @@ import {
 	getPollingClearingHouseUserConfig,
 } from './factory/clearingHouseUser';
 import { bulkPollingUserSubscribe } from './accounts/bulkUserSubscription';
+import { decodeUserPositionsAccount } from './userPositions';
 
 /**
  * # ClearingHouse
@@ -87,6 +100,7 @@ export class ClearingHouse {
 	eventEmitter: StrictEventEmitter<EventEmitter, ClearingHouseAccountEvents>;
 	_isSubscribed = false;
 	txSender: TxSender;
+	activeSubAccountId = 0;
 
 	public get isSubscribed() {
 		return this._isSubscribed && this.accountSubscriber.isSubscribed;
@@ -218,15 +232,15 @@ export class ClearingHouse {
 		return this.accountSubscriber.getFundingPaymentHistoryAccount();
 	}
 
-	public getFundingRateHistoryAccount(): FundingRateHistoryAccount {
+	public getFundingRateHistoryAccount(): ExtendedFundingRateHistoryAccount {
 		return this.accountSubscriber.getFundingRateHistoryAccount();
 	}
 
-	public getTradeHistoryAccount(): TradeHistoryAccount {
+	public getTradeHistoryAccount(): ExtendedTradeHistoryAccount {
 		return this.accountSubscriber.getTradeHistoryAccount();
 	}
 
-	public getLiquidationHistoryAccount(): LiquidationHistoryAccount {
+	public getLiquidationHistoryAccount(): ExtendedLiquidationHistoryAccount {
 		return this.accountSubscriber.getLiquidationHistoryAccount();
 	}
 
@@ -285,6 +299,18 @@ export class ClearingHouse {
 		this.userOrdersExist = undefined;
 	}
 
+	/**
+	 * Switch the sub account used for clearing house transactions. Sub account 0 is the user account created by {@link initializeUserAccount}
+	 * @param subAccountId
+	 */
+	public switchActiveSubAccount(subAccountId: number): void {
+		this.activeSubAccountId = subAccountId;
+		this.userAccountPublicKey = undefined;
+		this.userAccount = undefined;
+		this.userOrdersAccountPublicKey = undefined;
+		this.userOrdersExist = undefined;
+	}
+
 	public async initializeUserAccount(): Promise<
 		[TransactionSignature, PublicKey]
 	> {
@@ -315,6 +341,41 @@ export class ClearingHouse {
 				this.wallet.publicKey
 			);
 
+		const [optionalAccounts, remainingAccounts] =
+			await this.getInitializeUserOptionalAccounts();
+
+		const userPositions = new Keypair();
+		const initializeUserAccountIx =
+			await this.program.instruction.initializeUser(
+				userAccountNonce,
+				optionalAccounts,
+				{
+					accounts: {
+						user: userAccountPublicKey,
+						authority: this.wallet.publicKey,
+						rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+						systemProgram: anchor.web3.SystemProgram.programId,
+						userPositions: userPositions.publicKey,
+						state: await this.getStatePublicKey(),
+					},
+					remainingAccounts: remainingAccounts,
+				}
+			);
+
+		const initializeUserOrdersAccountIx =
+			await this.getInitializeUserOrdersInstruction(userAccountPublicKey);
+
+		return [
+			userPositions,
+			userAccountPublicKey,
+			initializeUserAccountIx,
+			initializeUserOrdersAccountIx,
+		];
+	}
+
+	async getInitializeUserOptionalAccounts(): Promise<
+		[{ whitelistToken: boolean }, AccountMeta[]]
+	> {
 		const remainingAccounts = [];
 		const optionalAccounts = {
 			whitelistToken: false,
@@ -336,10 +397,32 @@ export class ClearingHouse {
 			});
 		}
 
+		return [optionalAccounts, remainingAccounts];
+	}
+
+	/**
+	 * Creates a sub account under the wallet's authority along with its user orders account. Use {@link switchActiveSubAccount} to trade with it
+	 * @param subAccountId
+	 * @returns
+	 */
+	public async initializeUserSubAccount(
+		subAccountId: number
+	): Promise<[TransactionSignature, PublicKey]> {
+		const [userAccountPublicKey, userAccountNonce] =
+			await getUserSubAccountPublicKeyAndNonce(
+				this.program.programId,
+				this.wallet.publicKey,
+				subAccountId
+			);
+
+		const [optionalAccounts, remainingAccounts] =
+			await this.getInitializeUserOptionalAccounts();
+
 		const userPositions = new Keypair();
-		const initializeUserAccountIx =
-			await this.program.instruction.initializeUser(
+		const initializeUserSubAccountIx =
+			await this.program.instruction.initializeUserSubAccount(
 				userAccountNonce,
+				subAccountId,
 				optionalAccounts,
 				{
 					accounts: {
@@ -357,12 +440,11 @@ export class ClearingHouse {
 		const initializeUserOrdersAccountIx =
 			await this.getInitializeUserOrdersInstruction(userAccountPublicKey);
 
-		return [
-			userPositions,
-			userAccountPublicKey,
-			initializeUserAccountIx,
-			initializeUserOrdersAccountIx,
-		];
+		const tx = new Transaction()
+			.add(initializeUserSubAccountIx)
+			.add(initializeUserOrdersAccountIx);
+		const txSig = await this.txSender.send(tx, [userPositions], this.opts);
+		return [txSig, userAccountPublicKey];
 	}
 
 	async getInitializeUserOrdersInstruction(
@@ -403,9 +485,10 @@ export class ClearingHouse {
 			return this.userAccountPublicKey;
 		}
 
-		this.userAccountPublicKey = await getUserAccountPublicKey(
+		this.userAccountPublicKey = await getUserSubAccountPublicKey(
 			this.program.programId,
-			this.wallet.publicKey
+			this.wallet.publicKey,
+			this.activeSubAccountId
 		);
 		return this.userAccountPublicKey;
 	}
@@ -422,6 +505,101 @@ export class ClearingHouse {
 		return this.userAccount;
 	}
 
+	/**
+	 * Fetches every position in a user positions account, including the ones added by {@link resizeUserPositions}
+	 * @param userPositionsAccountPublicKey - defaults to the active sub account's positions
+	 * @returns
+	 */
+	public async fetchUserPositionsAccount(
+		userPositionsAccountPublicKey?: PublicKey
+	): Promise<UserPositionsAccount> {
+		if (!userPositionsAccountPublicKey) {
+			userPositionsAccountPublicKey = (await this.getUserAccount()).positions;
+		}
+
+		const accountInfo = await this.connection.getAccountInfo(
+			userPositionsAccountPublicKey
+		);
+		return decodeUserPositionsAccount(this.program, accountInfo.data);
+	}
+
+	public async getOrderBookPublicKey(marketIndex: BN): Promise<PublicKey> {
+		return await getOrderBookPublicKey(this.program.programId, marketIndex);
+	}
+
+	public async fetchOrderBookAccount(
+		marketIndex: BN
+	): Promise<OrderBookAccount> {
+		return (await this.program.account.orderBook.fetch(
+			await this.getOrderBookPublicKey(marketIndex)
+		)) as OrderBookAccount;
+	}
+
+	/**
+	 * The order book for the order's market followed by each maker's user, positions and orders accounts
+	 * @param marketIndex
+	 * @param makers
+	 * @returns
+	 */
+	async getOrderBookAndMakerAccounts(
+		marketIndex: BN,
+		makers: MakerInfo[]
+	): Promise<AccountMeta[]> {
+		const remainingAccounts = [
+			{
+				pubkey: await this.getOrderBookPublicKey(marketIndex),
+				isWritable: true,
+				isSigner: false,
+			},
+		];
+		for (const maker of makers) {
+			for (const pubkey of [
+				maker.maker,
+				maker.makerPositions,
+				maker.makerOrders,
+			]) {
+				remainingAccounts.push({
+					pubkey,
+					isWritable: true,
+					isSigner: false,
+				});
+			}
+		}
+		return remainingAccounts;
+	}
+
+	/**
+	 * Grows the active sub account's positions account so it can hold positions in more markets at once
+	 * @param numberOfPositions - the new number of positions, an instruction can add up to 49 at a time
+	 * @returns
+	 */
+	public async resizeUserPositions(
+		numberOfPositions: number
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getResizeUserPositionsIx(numberOfPositions)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getResizeUserPositionsIx(
+		numberOfPositions: number
+	): Promise<TransactionInstruction> {
+		const userAccount = await this.getUserAccount();
+		return await this.program.instruction.resizeUserPositions(
+			numberOfPositions,
+			{
+				accounts: {
+					user: await this.getUserAccountPublicKey(),
+					userPositions: userAccount.positions,
+					authority: this.wallet.publicKey,
+					systemProgram: anchor.web3.SystemProgram.programId,
+				},
+			}
+		);
+	}
+
 	userOrdersAccountPublicKey?: PublicKey;
 	/**
 	 * Get the address for the Clearing House User Order's account. NOT the user's wallet address.
@@ -480,20 +658,24 @@ export class ClearingHouse {
 		}
 
 		const state = this.getStateAccount();
-		return await this.program.instruction.depositCollateral(amount, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user: userAccountPublicKey,
-				collateralVault: state.collateralVault,
-				userCollateralAccount: collateralAccountPublicKey,
-				authority: this.wallet.publicKey,
-				tokenProgram: TOKEN_PROGRAM_ID,
-				markets: state.markets,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-				depositHistory: state.depositHistory,
-				userPositions: userPositionsAccountPublicKey,
-			},
-		});
+		return await this.program.instruction.depositCollateral(
+			this.activeSubAccountId,
+			amount,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					collateralVault: state.collateralVault,
+					userCollateralAccount: collateralAccountPublicKey,
+					authority: this.wallet.publicKey,
+					tokenProgram: TOKEN_PROGRAM_ID,
+					markets: state.markets,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					userPositions: userPositionsAccountPublicKey,
+				},
+			}
+		);
 	}
 
 	/**
@@ -589,42 +771,50 @@ export class ClearingHouse {
 		);
 
 		const state = this.getStateAccount();
-		return await this.program.instruction.withdrawCollateral(amount, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user: userAccountPublicKey,
-				collateralVault: state.collateralVault,
-				collateralVaultAuthority: state.collateralVaultAuthority,
-				insuranceVault: state.insuranceVault,
-				insuranceVaultAuthority: state.insuranceVaultAuthority,
-				userCollateralAccount: collateralAccountPublicKey,
-				authority: this.wallet.publicKey,
-				tokenProgram: TOKEN_PROGRAM_ID,
-				markets: state.markets,
-				userPositions: user.positions,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-				depositHistory: state.depositHistory,
-			},
-		});
+		return await this.program.instruction.withdrawCollateral(
+			this.activeSubAccountId,
+			amount,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					collateralVault: state.collateralVault,
+					collateralVaultAuthority: state.collateralVaultAuthority,
+					insuranceVault: state.insuranceVault,
+					insuranceVaultAuthority: state.insuranceVaultAuthority,
+					userCollateralAccount: collateralAccountPublicKey,
+					authority: this.wallet.publicKey,
+					tokenProgram: TOKEN_PROGRAM_ID,
+					markets: state.markets,
+					userPositions: user.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+				},
+				remainingAccounts: await this.getCollateralAssetsRemainingAccounts(
+					userAccountPublicKey
+				),
+			}
+		);
 	}
 
-	public async openPosition(
-		direction: PositionDirection,
+	/**
+	 * Moves collateral between two sub accounts of the wallet's authority
+	 * @param amount
+	 * @param fromSubAccountId
+	 * @param toSubAccountId
+	 * @returns
+	 */
+	public async transferCollateral(
 		amount: BN,
-		marketIndex: BN,
-		limitPrice?: BN,
-		discountToken?: PublicKey,
-		referrer?: PublicKey
+		fromSubAccountId: number,
+		toSubAccountId: number
 	): Promise<TransactionSignature> {
-		return await this.txSender.send(
+		return this.txSender.send(
 			wrapInTx(
-				await this.getOpenPositionIx(
-					direction,
+				await this.getTransferCollateralIx(
 					amount,
-					marketIndex,
-					limitPrice,
-					discountToken,
-					referrer
+					fromSubAccountId,
+					toSubAccountId
 				)
 			),
 			[],
@@ -632,182 +822,1450 @@ export class ClearingHouse {
 		);
 	}
 
-	public async getOpenPositionIx(
-		direction: PositionDirection,
+	public async getTransferCollateralIx(
 		amount: BN,
-		marketIndex: BN,
-		limitPrice?: BN,
-		discountToken?: PublicKey,
-		referrer?: PublicKey
+		fromSubAccountId: number,
+		toSubAccountId: number
 	): Promise<TransactionInstruction> {
-		const userAccountPublicKey = await this.getUserAccountPublicKey();
-		const userAccount = await this.getUserAccount();
-
-		if (limitPrice == undefined) {
-			limitPrice = new BN(0); // no limit
-		}
-
-		const optionalAccounts = {
-			discountToken: false,
-			referrer: false,
-		};
-		const remainingAccounts = [];
-		if (discountToken) {
-			optionalAccounts.discountToken = true;
-			remainingAccounts.push({
-				pubkey: discountToken,
-				isWritable: false,
-				isSigner: false,
-			});
-		}
-		if (referrer) {
-			optionalAccounts.referrer = true;
-			remainingAccounts.push({
-				pubkey: referrer,
-				isWritable: true,
-				isSigner: false,
-			});
-		}
-
-		const priceOracle =
-			this.getMarketsAccount().markets[marketIndex.toNumber()].amm.oracle;
+		const fromUserPublicKey = await getUserSubAccountPublicKey(
+			this.program.programId,
+			this.wallet.publicKey,
+			fromSubAccountId
+		);
+		const fromUser: any = await this.program.account.user.fetch(
+			fromUserPublicKey
+		);
+		const toUserPublicKey = await getUserSubAccountPublicKey(
+			this.program.programId,
+			this.wallet.publicKey,
+			toSubAccountId
+		);
+		const toUser: any = await this.program.account.user.fetch(toUserPublicKey);
 
 		const state = this.getStateAccount();
-		return await this.program.instruction.openPosition(
-			direction,
+		return await this.program.instruction.transferCollateral(
+			fromSubAccountId,
+			toSubAccountId,
 			amount,
-			marketIndex,
-			limitPrice,
-			optionalAccounts,
 			{
 				accounts: {
 					state: await this.getStatePublicKey(),
-					user: userAccountPublicKey,
+					fromUser: fromUserPublicKey,
+					fromUserPositions: fromUser.positions,
+					toUser: toUserPublicKey,
+					toUserPositions: toUser.positions,
 					authority: this.wallet.publicKey,
 					markets: state.markets,
-					userPositions: userAccount.positions,
-					tradeHistory: state.tradeHistory,
 					fundingPaymentHistory: state.fundingPaymentHistory,
-					fundingRateHistory: state.fundingRateHistory,
-					oracle: priceOracle,
+					depositHistory: state.depositHistory,
 				},
-				remainingAccounts: remainingAccounts,
+				remainingAccounts: await this.getCollateralAssetsRemainingAccounts(
+					fromUserPublicKey
+				),
 			}
 		);
 	}
 
-	public async initializeUserOrdersThenPlaceOrder(
-		orderParams: OrderParams,
-		discountToken?: PublicKey,
-		referrer?: PublicKey
+	public async addIsolatedCollateral(
+		amount: BN,
+		marketIndex: BN
 	): Promise<TransactionSignature> {
-		const instructions: anchor.web3.TransactionInstruction[] = [];
-		const userOrdersAccountExists = await this.userOrdersAccountExists();
-		if (!userOrdersAccountExists) {
-			instructions.push(await this.getInitializeUserOrdersInstruction());
-		}
-		instructions.push(
-			await this.getPlaceOrderIx(orderParams, discountToken, referrer)
+		return await this.txSender.send(
+			wrapInTx(await this.getAddIsolatedCollateralIx(amount, marketIndex)),
+			[],
+			this.opts
 		);
-		const tx = new Transaction();
-		for (const instruction of instructions) {
+	}
+
+	public async getAddIsolatedCollateralIx(
+		amount: BN,
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.addIsolatedCollateral(
+			this.activeSubAccountId,
+			marketIndex,
+			amount,
+			{
+				accounts: await this.getTransferIsolatedCollateralAccounts(),
+				remainingAccounts: await this.getCollateralAssetsRemainingAccounts(
+					await this.getUserAccountPublicKey()
+				),
+			}
+		);
+	}
+
+	public async removeIsolatedCollateral(
+		amount: BN,
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getRemoveIsolatedCollateralIx(amount, marketIndex)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getRemoveIsolatedCollateralIx(
+		amount: BN,
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.removeIsolatedCollateral(
+			this.activeSubAccountId,
+			marketIndex,
+			amount,
+			{
+				accounts: await this.getTransferIsolatedCollateralAccounts(),
+				remainingAccounts: await this.getCollateralAssetsRemainingAccounts(
+					await this.getUserAccountPublicKey()
+				),
+			}
+		);
+	}
+
+	async getTransferIsolatedCollateralAccounts(): Promise<any> {
+		const state = this.getStateAccount();
+		return {
+			state: await this.getStatePublicKey(),
+			user: await this.getUserAccountPublicKey(),
+			userPositions: (await this.getUserAccount()).positions,
+			authority: this.wallet.publicKey,
+			markets: state.markets,
+			fundingPaymentHistory: state.fundingPaymentHistory,
+			depositHistory: state.depositHistory,
+		};
+	}
+
+	public async getUserCollateralAssetsPublicKey(
+		userAccountPublicKey?: PublicKey
+	): Promise<PublicKey> {
+		return await getUserCollateralAssetsPublicKey(
+			this.program.programId,
+			userAccountPublicKey ?? (await this.getUserAccountPublicKey())
+		);
+	}
+
+	public async fetchCollateralAssetsAccount(): Promise<CollateralAssetsAccount> {
+		return (await this.program.account.collateralAssets.fetch(
+			this.getStateAccount().collateralAssets
+		)) as CollateralAssetsAccount;
+	}
+
+	public async fetchUserCollateralAssetsAccount(
+		userAccountPublicKey?: PublicKey
+	): Promise<UserCollateralAssetsAccount> {
+		return (await this.program.account.userCollateralAssets.fetch(
+			await this.getUserCollateralAssetsPublicKey(userAccountPublicKey)
+		)) as UserCollateralAssetsAccount;
+	}
+
+	/**
+	 * The accounts the margin check needs to value a user's collateral assets and borrows: the collateral assets account, the user's collateral assets account and the oracle of every asset the user holds or owes
+	 * @param userAccountPublicKey
+	 * @returns
+	 */
+	async getCollateralAssetsRemainingAccounts(
+		userAccountPublicKey: PublicKey
+	): Promise<AccountMeta[]> {
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		if (userAccount.hasCollateralAssets === 0) {
+			return [];
+		}
+
+		const userCollateralAssetsPublicKey =
+			await this.getUserCollateralAssetsPublicKey(userAccountPublicKey);
+		const remainingAccounts = [
+			{
+				pubkey: this.getStateAccount().collateralAssets,
+				isWritable: false,
+				isSigner: false,
+			},
+			{
+				pubkey: userCollateralAssetsPublicKey,
+				isWritable: false,
+				isSigner: false,
+			},
+		];
+		remainingAccounts.push(
+			...(await this.getCollateralAssetOracleAccounts(userAccountPublicKey))
+		);
+		return remainingAccounts;
+	}
+
+	/**
+	 * The oracle of every collateral asset the user holds or owes
+	 * @param userAccountPublicKey
+	 * @returns
+	 */
+	async getCollateralAssetOracleAccounts(
+		userAccountPublicKey: PublicKey
+	): Promise<AccountMeta[]> {
+		const collateralAssets = await this.fetchCollateralAssetsAccount();
+		const userCollateralAssets = await this.fetchUserCollateralAssetsAccount(
+			userAccountPublicKey
+		);
+		const remainingAccounts = [];
+		userCollateralAssets.balances.forEach((balance, assetIndex) => {
+			if (
+				balance.gt(ZERO) ||
+				userCollateralAssets.borrows[assetIndex].gt(ZERO)
+			) {
+				remainingAccounts.push({
+					pubkey: collateralAssets.assets[assetIndex].oracle,
+					isWritable: false,
+					isSigner: false,
+				});
+			}
+		});
+		return remainingAccounts;
+	}
+
+	public async initializeUserCollateralAssets(): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getInitializeUserCollateralAssetsIx()),
+			[],
+			this.opts
+		);
+	}
+
+	public async getInitializeUserCollateralAssetsIx(): Promise<TransactionInstruction> {
+		return await this.program.instruction.initializeUserCollateralAssets({
+			accounts: {
+				user: await this.getUserAccountPublicKey(),
+				userCollateralAssets: await this.getUserCollateralAssetsPublicKey(),
+				authority: this.wallet.publicKey,
+				rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+				systemProgram: anchor.web3.SystemProgram.programId,
+			},
+		});
+	}
+
+	public async depositCollateralAsset(
+		assetIndex: BN,
+		amount: BN,
+		userCollateralAssetAccount: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getDepositCollateralAssetIx(
+					assetIndex,
+					amount,
+					userCollateralAssetAccount
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getDepositCollateralAssetIx(
+		assetIndex: BN,
+		amount: BN,
+		userCollateralAssetAccount: PublicKey
+	): Promise<TransactionInstruction> {
+		const state = this.getStateAccount();
+		const collateralAsset = (await this.fetchCollateralAssetsAccount()).assets[
+			assetIndex.toNumber()
+		];
+		return await this.program.instruction.depositCollateralAsset(
+			assetIndex,
+			amount,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: await this.getUserAccountPublicKey(),
+					authority: this.wallet.publicKey,
+					collateralAssets: state.collateralAssets,
+					userCollateralAssets: await this.getUserCollateralAssetsPublicKey(),
+					collateralAssetVault: collateralAsset.vault,
+					userCollateralAssetAccount,
+					tokenProgram: TOKEN_PROGRAM_ID,
+				},
+			}
+		);
+	}
+
+	public async withdrawCollateralAsset(
+		assetIndex: BN,
+		amount: BN,
+		userCollateralAssetAccount: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getWithdrawCollateralAssetIx(
+					assetIndex,
+					amount,
+					userCollateralAssetAccount
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getWithdrawCollateralAssetIx(
+		assetIndex: BN,
+		amount: BN,
+		userCollateralAssetAccount: PublicKey
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const state = this.getStateAccount();
+		const collateralAsset = (await this.fetchCollateralAssetsAccount()).assets[
+			assetIndex.toNumber()
+		];
+		return await this.program.instruction.withdrawCollateralAsset(
+			assetIndex,
+			amount,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					collateralAssets: state.collateralAssets,
+					userCollateralAssets: await this.getUserCollateralAssetsPublicKey(),
+					collateralAssetVault: collateralAsset.vault,
+					collateralAssetVaultAuthority: collateralAsset.vaultAuthority,
+					userCollateralAssetAccount,
+					tokenProgram: TOKEN_PROGRAM_ID,
+					markets: state.markets,
+					userPositions: (await this.getUserAccount()).positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+				},
+				remainingAccounts: await this.getCollateralAssetOracleAccounts(
+					userAccountPublicKey
+				),
+			}
+		);
+	}
+
+	/**
+	 * Borrows against the active sub account's margin
+	 * @param assetIndex - the collateral asset to borrow, null to borrow usdc
+	 * @param amount
+	 * @param userTokenAccount - the token account the borrowed tokens are sent to
+	 * @returns
+	 */
+	public async borrow(
+		assetIndex: BN | null,
+		amount: BN,
+		userTokenAccount: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getBorrowIx(assetIndex, amount, userTokenAccount)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getBorrowIx(
+		assetIndex: BN | null,
+		amount: BN,
+		userTokenAccount: PublicKey
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.borrow(assetIndex, amount, {
+			accounts: await this.getManageBorrowAccounts(
+				assetIndex,
+				userTokenAccount
+			),
+			remainingAccounts: await this.getCollateralAssetOracleAccounts(
+				await this.getUserAccountPublicKey()
+			),
+		});
+	}
+
+	/**
+	 * Repays up to amount of what the active sub account owes
+	 * @param assetIndex - the collateral asset to repay, null to repay usdc
+	 * @param amount
+	 * @param userTokenAccount - the token account the repayment is taken from
+	 * @returns
+	 */
+	public async repay(
+		assetIndex: BN | null,
+		amount: BN,
+		userTokenAccount: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getRepayIx(assetIndex, amount, userTokenAccount)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getRepayIx(
+		assetIndex: BN | null,
+		amount: BN,
+		userTokenAccount: PublicKey
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.repay(assetIndex, amount, {
+			accounts: await this.getManageBorrowAccounts(
+				assetIndex,
+				userTokenAccount
+			),
+		});
+	}
+
+	async getManageBorrowAccounts(
+		assetIndex: BN | null,
+		userTokenAccount: PublicKey
+	): Promise<any> {
+		const state = this.getStateAccount();
+		let vault = state.collateralVault;
+		let vaultAuthority = state.collateralVaultAuthority;
+		if (assetIndex !== null) {
+			const collateralAsset = (await this.fetchCollateralAssetsAccount())
+				.assets[assetIndex.toNumber()];
+			vault = collateralAsset.vault;
+			vaultAuthority = collateralAsset.vaultAuthority;
+		}
+
+		return {
+			state: await this.getStatePublicKey(),
+			user: await this.getUserAccountPublicKey(),
+			authority: this.wallet.publicKey,
+			collateralAssets: state.collateralAssets,
+			userCollateralAssets: await this.getUserCollateralAssetsPublicKey(),
+			vault,
+			vaultAuthority,
+			userTokenAccount,
+			tokenProgram: TOKEN_PROGRAM_ID,
+			markets: state.markets,
+			userPositions: (await this.getUserAccount()).positions,
+			fundingPaymentHistory: state.fundingPaymentHistory,
+			depositHistory: state.depositHistory,
+		};
+	}
+
+	/**
+	 * Pays the interest a user's usdc collateral has earned into it
+	 * @param userAccountPublicKey
+	 * @returns
+	 */
+	public async settleLendingInterest(
+		userAccountPublicKey: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getSettleLendingInterestIx(userAccountPublicKey)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getSettleLendingInterestIx(
+		userAccountPublicKey: PublicKey
+	): Promise<TransactionInstruction> {
+		const state = this.getStateAccount();
+		return await this.program.instruction.settleLendingInterest({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				user: userAccountPublicKey,
+				depositHistory: state.depositHistory,
+			},
+		});
+	}
+
+	/**
+	 * Buys a user's collateral asset at a discount to the oracle price to cover their quote collateral deficit
+	 * @param liquidateeUserAccountPublicKey
+	 * @param assetIndex
+	 * @param maxQuoteAssetAmount - the most quote the liquidator pays for the asset
+	 * @param liquidatorCollateralAccount - the liquidator's usdc token account
+	 * @param liquidatorCollateralAssetAccount - the liquidator's token account for the asset
+	 * @returns
+	 */
+	public async liquidateCollateralAsset(
+		liquidateeUserAccountPublicKey: PublicKey,
+		assetIndex: BN,
+		maxQuoteAssetAmount: BN,
+		liquidatorCollateralAccount: PublicKey,
+		liquidatorCollateralAssetAccount: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getLiquidateCollateralAssetIx(
+					liquidateeUserAccountPublicKey,
+					assetIndex,
+					maxQuoteAssetAmount,
+					liquidatorCollateralAccount,
+					liquidatorCollateralAssetAccount
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getLiquidateCollateralAssetIx(
+		liquidateeUserAccountPublicKey: PublicKey,
+		assetIndex: BN,
+		maxQuoteAssetAmount: BN,
+		liquidatorCollateralAccount: PublicKey,
+		liquidatorCollateralAssetAccount: PublicKey
+	): Promise<TransactionInstruction> {
+		const liquidateeUserAccount: any = await this.program.account.user.fetch(
+			liquidateeUserAccountPublicKey
+		);
+		const state = this.getStateAccount();
+		const collateralAsset = (await this.fetchCollateralAssetsAccount()).assets[
+			assetIndex.toNumber()
+		];
+		return await this.program.instruction.liquidateCollateralAsset(
+			assetIndex,
+			maxQuoteAssetAmount,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					authority: this.wallet.publicKey,
+					user: liquidateeUserAccountPublicKey,
+					collateralAssets: state.collateralAssets,
+					userCollateralAssets: await this.getUserCollateralAssetsPublicKey(
+						liquidateeUserAccountPublicKey
+					),
+					collateralVault: state.collateralVault,
+					collateralAssetVault: collateralAsset.vault,
+					collateralAssetVaultAuthority: collateralAsset.vaultAuthority,
+					oracle: collateralAsset.oracle,
+					liquidatorCollateralAccount,
+					liquidatorCollateralAssetAccount,
+					tokenProgram: TOKEN_PROGRAM_ID,
+					markets: state.markets,
+					userPositions: liquidateeUserAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+				},
+			}
+		);
+	}
+
+	public async getInsuranceFundStakePublicKey(): Promise<PublicKey> {
+		return await getInsuranceFundStakePublicKey(
+			this.program.programId,
+			this.wallet.publicKey
+		);
+	}
+
+	public async fetchInsuranceFundStakeAccount(): Promise<InsuranceFundStake> {
+		return (await this.program.account.insuranceFundStake.fetch(
+			await this.getInsuranceFundStakePublicKey()
+		)) as InsuranceFundStake;
+	}
+
+	public async initializeInsuranceFundStake(): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getInitializeInsuranceFundStakeIx()),
+			[],
+			this.opts
+		);
+	}
+
+	public async getInitializeInsuranceFundStakeIx(): Promise<TransactionInstruction> {
+		return await this.program.instruction.initializeInsuranceFundStake({
+			accounts: {
+				insuranceFundStake: await this.getInsuranceFundStakePublicKey(),
+				authority: this.wallet.publicKey,
+				rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+				systemProgram: anchor.web3.SystemProgram.programId,
+			},
+		});
+	}
+
+	/**
+	 * Stakes usdc in the insurance fund for shares priced against the insurance vault's balance
+	 * @param amount
+	 * @param collateralAccountPublicKey
+	 * @returns
+	 */
+	public async addInsuranceFundStake(
+		amount: BN,
+		collateralAccountPublicKey: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getAddInsuranceFundStakeIx(
+					amount,
+					collateralAccountPublicKey
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getAddInsuranceFundStakeIx(
+		amount: BN,
+		collateralAccountPublicKey: PublicKey
+	): Promise<TransactionInstruction> {
+		const state = this.getStateAccount();
+		return await this.program.instruction.addInsuranceFundStake(amount, {
+			accounts: {
+				state: await this.getStatePublicKey(),
+				insuranceFundStake: await this.getInsuranceFundStakePublicKey(),
+				authority: this.wallet.publicKey,
+				insuranceVault: state.insuranceVault,
+				userCollateralAccount: collateralAccountPublicKey,
+				tokenProgram: TOKEN_PROGRAM_ID,
+				insuranceFundStakeHistory: state.insuranceFund.stakeHistory,
+			},
+		});
+	}
+
+	/**
+	 * Starts the unstaking period for some of the stake's shares. Their value is fixed when requested.
+	 * @param shares
+	 * @returns
+	 */
+	public async requestRemoveInsuranceFundStake(
+		shares: BN
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getRequestRemoveInsuranceFundStakeIx(shares)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getRequestRemoveInsuranceFundStakeIx(
+		shares: BN
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.requestRemoveInsuranceFundStake(
+			shares,
+			{
+				accounts: await this.getInsuranceFundStakeRequestAccounts(),
+			}
+		);
+	}
+
+	public async cancelRequestRemoveInsuranceFundStake(): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getCancelRequestRemoveInsuranceFundStakeIx()),
+			[],
+			this.opts
+		);
+	}
+
+	public async getCancelRequestRemoveInsuranceFundStakeIx(): Promise<TransactionInstruction> {
+		return await this.program.instruction.cancelRequestRemoveInsuranceFundStake({
+			accounts: await this.getInsuranceFundStakeRequestAccounts(),
+		});
+	}
+
+	async getInsuranceFundStakeRequestAccounts(): Promise<any> {
+		const state = this.getStateAccount();
+		return {
+			state: await this.getStatePublicKey(),
+			insuranceFundStake: await this.getInsuranceFundStakePublicKey(),
+			authority: this.wallet.publicKey,
+			insuranceVault: state.insuranceVault,
+			insuranceFundStakeHistory: state.insuranceFund.stakeHistory,
+		};
+	}
+
+	/**
+	 * Unstakes the requested shares once the unstaking period is over
+	 * @param collateralAccountPublicKey
+	 * @returns
+	 */
+	public async removeInsuranceFundStake(
+		collateralAccountPublicKey: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getRemoveInsuranceFundStakeIx(collateralAccountPublicKey)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getRemoveInsuranceFundStakeIx(
+		collateralAccountPublicKey: PublicKey
+	): Promise<TransactionInstruction> {
+		const state = this.getStateAccount();
+		return await this.program.instruction.removeInsuranceFundStake({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				insuranceFundStake: await this.getInsuranceFundStakePublicKey(),
+				authority: this.wallet.publicKey,
+				insuranceVault: state.insuranceVault,
+				insuranceVaultAuthority: state.insuranceVaultAuthority,
+				userCollateralAccount: collateralAccountPublicKey,
+				tokenProgram: TOKEN_PROGRAM_ID,
+				insuranceFundStakeHistory: state.insuranceFund.stakeHistory,
+			},
+		});
+	}
+
+	/**
+	 * Moves the stakers' share of the fees a market has collected since it was last settled to the insurance vault
+	 * @param marketIndex
+	 * @returns
+	 */
+	public async settleFeesToInsuranceFund(
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getSettleFeesToInsuranceFundIx(marketIndex)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getSettleFeesToInsuranceFundIx(
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
+		const state = this.getStateAccount();
+		return await this.program.instruction.settleFeesToInsuranceFund(
+			marketIndex,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					markets: state.markets,
+					collateralVault: state.collateralVault,
+					collateralVaultAuthority: state.collateralVaultAuthority,
+					insuranceVault: state.insuranceVault,
+					tokenProgram: TOKEN_PROGRAM_ID,
+				},
+			}
+		);
+	}
+
+	public async openPosition(
+		direction: PositionDirection,
+		amount: BN,
+		marketIndex: BN,
+		limitPrice?: BN,
+		discountToken?: PublicKey,
+		referrer?: PublicKey,
+		isolatedCollateral?: BN
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getOpenPositionIx(
+					direction,
+					amount,
+					marketIndex,
+					limitPrice,
+					discountToken,
+					referrer,
+					isolatedCollateral
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getOpenPositionIx(
+		direction: PositionDirection,
+		amount: BN,
+		marketIndex: BN,
+		limitPrice?: BN,
+		discountToken?: PublicKey,
+		referrer?: PublicKey,
+		isolatedCollateral?: BN
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
+		if (limitPrice == undefined) {
+			limitPrice = new BN(0); // no limit
+		}
+
+		if (isolatedCollateral == undefined) {
+			isolatedCollateral = new BN(0); // cross margin
+		}
+
+		const optionalAccounts = {
+			discountToken: false,
+			referrer: false,
+		};
+		const remainingAccounts = [];
+		if (discountToken) {
+			optionalAccounts.discountToken = true;
+			remainingAccounts.push({
+				pubkey: discountToken,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+		if (referrer) {
+			optionalAccounts.referrer = true;
+			remainingAccounts.push({
+				pubkey: referrer,
+				isWritable: true,
+				isSigner: false,
+			});
+		}
+
+		remainingAccounts.push(
+			...(await this.getCollateralAssetsRemainingAccounts(userAccountPublicKey))
+		);
+
+		const priceOracle =
+			this.getMarketsAccount().markets[marketIndex.toNumber()].amm.oracle;
+
+		const state = this.getStateAccount();
+		return await this.program.instruction.openPosition(
+			this.activeSubAccountId,
+			direction,
+			amount,
+			marketIndex,
+			limitPrice,
+			isolatedCollateral,
+			optionalAccounts,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userPositions: userAccount.positions,
+					tradeHistory: state.extendedTradeHistory,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					extendedCurveHistory: state.extendedCurveHistory,
+					oracle: priceOracle,
+				},
+				remainingAccounts: remainingAccounts,
+			}
+		);
+	}
+
+	public async initializeUserOrdersThenPlaceOrder(
+		orderParams: OrderParams,
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<TransactionSignature> {
+		const instructions: anchor.web3.TransactionInstruction[] = [];
+		const userOrdersAccountExists = await this.userOrdersAccountExists();
+		if (!userOrdersAccountExists) {
+			instructions.push(await this.getInitializeUserOrdersInstruction());
+		}
+		instructions.push(
+			await this.getPlaceOrderIx(orderParams, discountToken, referrer)
+		);
+		const tx = new Transaction();
+		for (const instruction of instructions) {
 			tx.add(instruction);
 		}
 
-		return await this.txSender.send(tx, [], this.opts);
+		return await this.txSender.send(tx, [], this.opts);
+	}
+
+	public async placeOrder(
+		orderParams: OrderParams,
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getPlaceOrderIx(orderParams, discountToken, referrer)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getPlaceOrderIx(
+		orderParams: OrderParams,
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
+		const priceOracle =
+			this.getMarketsAccount().markets[orderParams.marketIndex.toNumber()].amm
+				.oracle;
+
+		const remainingAccounts = [];
+		if (orderParams.optionalAccounts.discountToken) {
+			if (!discountToken) {
+				throw Error(
+					'Optional accounts specified discount token but no discount token present'
+				);
+			}
+
+			remainingAccounts.push({
+				pubkey: discountToken,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+
+		if (orderParams.optionalAccounts.referrer) {
+			if (!referrer) {
+				throw Error(
+					'Optional accounts specified referrer but no referrer present'
+				);
+			}
+
+			remainingAccounts.push({
+				pubkey: referrer,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+
+		if (orderParams.optionalAccounts.orderBook) {
+			remainingAccounts.push({
+				pubkey: await this.getOrderBookPublicKey(orderParams.marketIndex),
+				isWritable: true,
+				isSigner: false,
+			});
+		}
+
+		if (!orderParams.oraclePriceOffset.eq(ZERO)) {
+			remainingAccounts.push({
+				pubkey: priceOracle,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+		return await this.program.instruction.placeOrder(
+			this.activeSubAccountId,
+			orderParams,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts,
+			}
+		);
+	}
+
+	/**
+	 * Places an entry order with a take profit and stop loss. The take profit and stop loss are linked,
+	 * so once one of them fills the other is canceled.
+	 * @param entry
+	 * @param takeProfit must be reduce only and in the opposite direction of the entry
+	 * @param stopLoss must be reduce only and in the opposite direction of the entry
+	 * @param discountToken
+	 * @param referrer
+	 * @returns
+	 */
+	public async placeBracketOrders(
+		entry: OrderParams,
+		takeProfit: OrderParams,
+		stopLoss: OrderParams,
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getPlaceBracketOrdersIx(
+					entry,
+					takeProfit,
+					stopLoss,
+					discountToken,
+					referrer
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getPlaceBracketOrdersIx(
+		entry: OrderParams,
+		takeProfit: OrderParams,
+		stopLoss: OrderParams,
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
+		const remainingAccounts = [];
+		if (entry.optionalAccounts.discountToken) {
+			if (!discountToken) {
+				throw Error(
+					'Optional accounts specified discount token but no discount token present'
+				);
+			}
+
+			remainingAccounts.push({
+				pubkey: discountToken,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+
+		if (entry.optionalAccounts.referrer) {
+			if (!referrer) {
+				throw Error(
+					'Optional accounts specified referrer but no referrer present'
+				);
+			}
+
+			remainingAccounts.push({
+				pubkey: referrer,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+
+		// each order with an oracle offset reads the oracle in turn
+		for (const orderParams of [entry, takeProfit, stopLoss]) {
+			if (!orderParams.oraclePriceOffset.eq(ZERO)) {
+				remainingAccounts.push({
+					pubkey: this.getMarket(orderParams.marketIndex).amm.oracle,
+					isWritable: false,
+					isSigner: false,
+				});
+			}
+		}
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+		return await this.program.instruction.placeBracketOrders(
+			this.activeSubAccountId,
+			entry,
+			takeProfit,
+			stopLoss,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts,
+			}
+		);
+	}
+
+	public async expireOrders(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getExpireOrdersIx(
+					userAccountPublicKey,
+					userOrdersAccountPublicKey
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getExpireOrdersIx(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey
+	): Promise<TransactionInstruction> {
+		const fillerPublicKey = await this.getUserAccountPublicKey();
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+		return await this.program.instruction.expireOrders({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				filler: fillerPublicKey,
+				user: userAccountPublicKey,
+				authority: this.wallet.publicKey,
+				userPositions: userAccount.positions,
+				userOrders: userOrdersAccountPublicKey,
+				orderState: await this.getOrderStatePublicKey(),
+				orderHistory: orderState.orderHistory,
+				depositHistory: state.depositHistory,
+			},
+		});
+	}
+
+	/**
+	 * Cancels the user's orders that are past their maxTs. The filler is paid a reward for each expired order.
+	 * @param userAccountPublicKey
+	 * @param userOrdersAccountPublicKey
+	 * @returns
+	 */
+	public async cancelExpiredOrders(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getCancelExpiredOrdersIx(
+					userAccountPublicKey,
+					userOrdersAccountPublicKey
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getCancelExpiredOrdersIx(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey
+	): Promise<TransactionInstruction> {
+		const fillerPublicKey = await this.getUserAccountPublicKey();
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+		return await this.program.instruction.cancelExpiredOrders({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				filler: fillerPublicKey,
+				user: userAccountPublicKey,
+				authority: this.wallet.publicKey,
+				userPositions: userAccount.positions,
+				userOrders: userOrdersAccountPublicKey,
+				orderState: await this.getOrderStatePublicKey(),
+				orderHistory: orderState.orderHistory,
+				depositHistory: state.depositHistory,
+			},
+		});
+	}
+
+	public async cancelOrder(
+		orderId: BN,
+		oracle?: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getCancelOrderIx(orderId, oracle)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getCancelOrderIx(
+		orderId: BN,
+		oracle?: PublicKey
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+
+		const remainingAccounts = [];
+		if (oracle) {
+			remainingAccounts.push({
+				pubkey: oracle,
+				isWritable: false,
+				isSigner: false,
+			});
+		}
+
+		return await this.program.instruction.cancelOrder(
+			this.activeSubAccountId,
+			orderId,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts,
+			}
+		);
 	}
 
-	public async placeOrder(
-		orderParams: OrderParams,
-		discountToken?: PublicKey,
-		referrer?: PublicKey
+	public async cancelOrderByUserId(
+		userOrderId: number,
+		oracle?: PublicKey
 	): Promise<TransactionSignature> {
 		return await this.txSender.send(
-			wrapInTx(
-				await this.getPlaceOrderIx(orderParams, discountToken, referrer)
-			),
+			wrapInTx(await this.getCancelOrderByUserIdIx(userOrderId, oracle)),
 			[],
 			this.opts
 		);
 	}
 
-	public async getPlaceOrderIx(
-		orderParams: OrderParams,
-		discountToken?: PublicKey,
-		referrer?: PublicKey
+	public async getCancelOrderByUserIdIx(
+		userOrderId: number,
+		oracle?: PublicKey
 	): Promise<TransactionInstruction> {
 		const userAccountPublicKey = await this.getUserAccountPublicKey();
 		const userAccount = await this.getUserAccount();
 
-		const priceOracle =
-			this.getMarketsAccount().markets[orderParams.marketIndex.toNumber()].amm
-				.oracle;
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
 
 		const remainingAccounts = [];
-		if (orderParams.optionalAccounts.discountToken) {
-			if (!discountToken) {
-				throw Error(
-					'Optional accounts specified discount token but no discount token present'
-				);
-			}
-
+		if (oracle) {
 			remainingAccounts.push({
-				pubkey: discountToken,
+				pubkey: oracle,
 				isWritable: false,
 				isSigner: false,
 			});
 		}
 
-		if (orderParams.optionalAccounts.referrer) {
-			if (!referrer) {
-				throw Error(
-					'Optional accounts specified referrer but no referrer present'
-				);
+		return await this.program.instruction.cancelOrderByUserId(
+			this.activeSubAccountId,
+			userOrderId,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts,
 			}
+		);
+	}
 
-			remainingAccounts.push({
-				pubkey: referrer,
-				isWritable: false,
-				isSigner: false,
-			});
-		}
+	/**
+	 * Modifies an open order in place. Fields left as null in the params keep the order's current value.
+	 * @param orderId
+	 * @param marketIndex the order's market
+	 * @param params
+	 * @returns
+	 */
+	public async modifyOrder(
+		orderId: BN,
+		marketIndex: BN,
+		params: ModifyOrderParams
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(await this.getModifyOrderIx(orderId, marketIndex, params)),
+			[],
+			this.opts
+		);
+	}
 
-		if (!orderParams.oraclePriceOffset.eq(ZERO)) {
+	public async getModifyOrderIx(
+		orderId: BN,
+		marketIndex: BN,
+		params: ModifyOrderParams
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+
+		return await this.program.instruction.modifyOrder(
+			this.activeSubAccountId,
+			orderId,
+			params,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts: await this.getModifyOrderRemainingAccounts(
+					marketIndex,
+					params
+				),
+			}
+		);
+	}
+
+	public async modifyOrderByUserId(
+		userOrderId: number,
+		marketIndex: BN,
+		params: ModifyOrderParams
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getModifyOrderByUserIdIx(userOrderId, marketIndex, params)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getModifyOrderByUserIdIx(
+		userOrderId: number,
+		marketIndex: BN,
+		params: ModifyOrderParams
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+
+		return await this.program.instruction.modifyOrderByUserId(
+			this.activeSubAccountId,
+			userOrderId,
+			params,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts: await this.getModifyOrderRemainingAccounts(
+					marketIndex,
+					params
+				),
+			}
+		);
+	}
+
+	/**
+	 * The order book if the order rests on it, followed by the market's oracle
+	 * @param marketIndex
+	 * @param params
+	 * @returns
+	 */
+	async getModifyOrderRemainingAccounts(
+		marketIndex: BN,
+		params: ModifyOrderParams
+	): Promise<AccountMeta[]> {
+		const remainingAccounts = [];
+		if (params.orderBook) {
 			remainingAccounts.push({
-				pubkey: priceOracle,
-				isWritable: false,
+				pubkey: await this.getOrderBookPublicKey(marketIndex),
+				isWritable: true,
 				isSigner: false,
 			});
 		}
 
+		remainingAccounts.push({
+			pubkey: this.getMarket(marketIndex).amm.oracle,
+			isWritable: false,
+			isSigner: false,
+		});
+		return remainingAccounts;
+	}
+
+	/**
+	 * Places a batch of orders atomically. The discount token and referrer are taken from the first order's optional accounts.
+	 * @param orderParams
+	 * @param discountToken
+	 * @param referrer
+	 * @returns
+	 */
+	public async placeOrders(
+		orderParams: OrderParams[],
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getPlaceOrdersIx(orderParams, discountToken, referrer)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getPlaceOrdersIx(
+		orderParams: OrderParams[],
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<TransactionInstruction> {
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
+
 		const state = this.getStateAccount();
 		const orderState = this.getOrderStateAccount();
-		return await this.program.instruction.placeOrder(orderParams, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user: userAccountPublicKey,
-				authority: this.wallet.publicKey,
-				markets: state.markets,
-				userOrders: await this.getUserOrdersAccountPublicKey(),
-				userPositions: userAccount.positions,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-				fundingRateHistory: state.fundingRateHistory,
-				orderState: await this.getOrderStatePublicKey(),
-				orderHistory: orderState.orderHistory,
-			},
-			remainingAccounts,
-		});
+
+		return await this.program.instruction.placeOrders(
+			this.activeSubAccountId,
+			orderParams,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts: await this.getBatchOrdersRemainingAccounts(
+					[],
+					orderParams,
+					discountToken,
+					referrer
+				),
+			}
+		);
 	}
 
-	public async expireOrders(
-		userAccountPublicKey: PublicKey,
-		userOrdersAccountPublicKey: PublicKey
+	/**
+	 * Cancels orders and places new ones atomically. The cancels go first, so the new orders can reuse their slots.
+	 * @param orderIds
+	 * @param orderParams
+	 * @param discountToken
+	 * @param referrer
+	 * @returns
+	 */
+	public async cancelAndPlaceOrders(
+		orderIds: BN[],
+		orderParams: OrderParams[],
+		discountToken?: PublicKey,
+		referrer?: PublicKey
 	): Promise<TransactionSignature> {
 		return await this.txSender.send(
 			wrapInTx(
-				await this.getExpireOrdersIx(
-					userAccountPublicKey,
-					userOrdersAccountPublicKey
+				await this.getCancelAndPlaceOrdersIx(
+					orderIds,
+					orderParams,
+					discountToken,
+					referrer
 				)
 			),
 			[],
@@ -815,44 +2273,56 @@ export class ClearingHouse {
 		);
 	}
 
-	public async getExpireOrdersIx(
-		userAccountPublicKey: PublicKey,
-		userOrdersAccountPublicKey: PublicKey
+	public async getCancelAndPlaceOrdersIx(
+		orderIds: BN[],
+		orderParams: OrderParams[],
+		discountToken?: PublicKey,
+		referrer?: PublicKey
 	): Promise<TransactionInstruction> {
-		const fillerPublicKey = await this.getUserAccountPublicKey();
-		const userAccount: any = await this.program.account.user.fetch(
-			userAccountPublicKey
-		);
+		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const userAccount = await this.getUserAccount();
 
+		const state = this.getStateAccount();
 		const orderState = this.getOrderStateAccount();
-		return await this.program.instruction.expireOrders({
-			accounts: {
-				state: await this.getStatePublicKey(),
-				filler: fillerPublicKey,
-				user: userAccountPublicKey,
-				authority: this.wallet.publicKey,
-				userPositions: userAccount.positions,
-				userOrders: userOrdersAccountPublicKey,
-				orderState: await this.getOrderStatePublicKey(),
-				orderHistory: orderState.orderHistory,
-			},
-		});
+
+		return await this.program.instruction.cancelAndPlaceOrders(
+			this.activeSubAccountId,
+			orderIds,
+			orderParams,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts: await this.getBatchOrdersRemainingAccounts(
+					orderIds,
+					orderParams,
+					discountToken,
+					referrer
+				),
+			}
+		);
 	}
 
-	public async cancelOrder(
-		orderId: BN,
-		oracle?: PublicKey
-	): Promise<TransactionSignature> {
+	public async cancelOrders(orderIds: BN[]): Promise<TransactionSignature> {
 		return await this.txSender.send(
-			wrapInTx(await this.getCancelOrderIx(orderId, oracle)),
+			wrapInTx(await this.getCancelOrdersIx(orderIds)),
 			[],
 			this.opts
 		);
 	}
 
-	public async getCancelOrderIx(
-		orderId: BN,
-		oracle?: PublicKey
+	public async getCancelOrdersIx(
+		orderIds: BN[]
 	): Promise<TransactionInstruction> {
 		const userAccountPublicKey = await this.getUserAccountPublicKey();
 		const userAccount = await this.getUserAccount();
@@ -860,77 +2330,122 @@ export class ClearingHouse {
 		const state = this.getStateAccount();
 		const orderState = this.getOrderStateAccount();
 
+		return await this.program.instruction.cancelOrders(
+			this.activeSubAccountId,
+			orderIds,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts: await this.getBatchOrdersRemainingAccounts(
+					orderIds,
+					[]
+				),
+			}
+		);
+	}
+
+	/**
+	 * The discount token and referrer for the first order, followed by the oracles for every market in the batch
+	 * and the order books for the orders that rest on them
+	 * @param orderIds
+	 * @param orderParams
+	 * @param discountToken
+	 * @param referrer
+	 * @returns
+	 */
+	async getBatchOrdersRemainingAccounts(
+		orderIds: BN[],
+		orderParams: OrderParams[],
+		discountToken?: PublicKey,
+		referrer?: PublicKey
+	): Promise<AccountMeta[]> {
 		const remainingAccounts = [];
-		if (oracle) {
+		const optionalAccounts = orderParams[0]?.optionalAccounts;
+		if (optionalAccounts?.discountToken) {
+			if (!discountToken) {
+				throw Error(
+					'Optional accounts specified discount token but no discount token present'
+				);
+			}
+
 			remainingAccounts.push({
-				pubkey: oracle,
+				pubkey: discountToken,
 				isWritable: false,
 				isSigner: false,
 			});
 		}
 
-		return await this.program.instruction.cancelOrder(orderId, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user: userAccountPublicKey,
-				authority: this.wallet.publicKey,
-				markets: state.markets,
-				userOrders: await this.getUserOrdersAccountPublicKey(),
-				userPositions: userAccount.positions,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-				fundingRateHistory: state.fundingRateHistory,
-				orderState: await this.getOrderStatePublicKey(),
-				orderHistory: orderState.orderHistory,
-			},
-			remainingAccounts,
-		});
-	}
-
-	public async cancelOrderByUserId(
-		userOrderId: number,
-		oracle?: PublicKey
-	): Promise<TransactionSignature> {
-		return await this.txSender.send(
-			wrapInTx(await this.getCancelOrderByUserIdIx(userOrderId, oracle)),
-			[],
-			this.opts
-		);
-	}
-
-	public async getCancelOrderByUserIdIx(
-		userOrderId: number,
-		oracle?: PublicKey
-	): Promise<TransactionInstruction> {
-		const userAccountPublicKey = await this.getUserAccountPublicKey();
-		const userAccount = await this.getUserAccount();
-
-		const state = this.getStateAccount();
-		const orderState = this.getOrderStateAccount();
+		if (optionalAccounts?.referrer) {
+			if (!referrer) {
+				throw Error(
+					'Optional accounts specified referrer but no referrer present'
+				);
+			}
 
-		const remainingAccounts = [];
-		if (oracle) {
 			remainingAccounts.push({
-				pubkey: oracle,
+				pubkey: referrer,
 				isWritable: false,
 				isSigner: false,
 			});
 		}
 
-		return await this.program.instruction.cancelOrderByUserId(userOrderId, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user: userAccountPublicKey,
-				authority: this.wallet.publicKey,
-				markets: state.markets,
-				userOrders: await this.getUserOrdersAccountPublicKey(),
-				userPositions: userAccount.positions,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-				fundingRateHistory: state.fundingRateHistory,
-				orderState: await this.getOrderStatePublicKey(),
-				orderHistory: orderState.orderHistory,
-			},
-			remainingAccounts,
-		});
+		const marketIndexes = orderParams.map(
+			(orderParams) => orderParams.marketIndex
+		);
+		if (orderIds.length > 0) {
+			const userOrders: any = await this.program.account.userOrders.fetch(
+				await this.getUserOrdersAccountPublicKey()
+			);
+			for (const orderId of orderIds) {
+				const order = userOrders.orders.find((order) =>
+					order.orderId.eq(orderId)
+				);
+				if (order) {
+					marketIndexes.push(order.marketIndex);
+				}
+			}
+		}
+
+		const oracles = new Set<string>();
+		for (const marketIndex of marketIndexes) {
+			const oracle = this.getMarket(marketIndex).amm.oracle;
+			if (!oracles.has(oracle.toBase58())) {
+				oracles.add(oracle.toBase58());
+				remainingAccounts.push({
+					pubkey: oracle,
+					isWritable: false,
+					isSigner: false,
+				});
+			}
+		}
+
+		const orderBooks = new Set<string>();
+		for (const params of orderParams) {
+			if (
+				params.optionalAccounts.orderBook &&
+				!orderBooks.has(params.marketIndex.toString())
+			) {
+				orderBooks.add(params.marketIndex.toString());
+				remainingAccounts.push({
+					pubkey: await this.getOrderBookPublicKey(params.marketIndex),
+					isWritable: true,
+					isSigner: false,
+				});
+			}
+		}
+
+		return remainingAccounts;
 	}
 
 	public async cancelAllOrders(
@@ -963,21 +2478,26 @@ export class ClearingHouse {
 			});
 		}
 
-		return await this.program.instruction.cancelAllOrders(bestEffort, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user: userAccountPublicKey,
-				authority: this.wallet.publicKey,
-				markets: state.markets,
-				userOrders: await this.getUserOrdersAccountPublicKey(),
-				userPositions: userAccount.positions,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-				fundingRateHistory: state.fundingRateHistory,
-				orderState: await this.getOrderStatePublicKey(),
-				orderHistory: orderState.orderHistory,
-			},
-			remainingAccounts,
-		});
+		return await this.program.instruction.cancelAllOrders(
+			this.activeSubAccountId,
+			bestEffort,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+				},
+				remainingAccounts,
+			}
+		);
 	}
 
 	public async cancelOrdersByMarketAndSide(
@@ -1022,6 +2542,7 @@ export class ClearingHouse {
 		}
 
 		return await this.program.instruction.cancelOrdersByMarketAndSide(
+			this.activeSubAccountId,
 			bestEffort,
 			marketIndexOnly,
 			directionOnly,
@@ -1034,7 +2555,8 @@ export class ClearingHouse {
 					userOrders: await this.getUserOrdersAccountPublicKey(),
 					userPositions: userAccount.positions,
 					fundingPaymentHistory: state.fundingPaymentHistory,
-					fundingRateHistory: state.fundingRateHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
 					orderState: await this.getOrderStatePublicKey(),
 					orderHistory: orderState.orderHistory,
 				},
@@ -1043,14 +2565,14 @@ export class ClearingHouse {
 		);
 	}
 
-	public async fillOrder(
+	public async updateTrailingStop(
 		userAccountPublicKey: PublicKey,
 		userOrdersAccountPublicKey: PublicKey,
 		order: Order
 	): Promise<TransactionSignature> {
 		return await this.txSender.send(
 			wrapInTx(
-				await this.getFillOrderIx(
+				await this.getUpdateTrailingStopIx(
 					userAccountPublicKey,
 					userOrdersAccountPublicKey,
 					order
@@ -1061,10 +2583,54 @@ export class ClearingHouse {
 		);
 	}
 
-	public async getFillOrderIx(
+	public async getUpdateTrailingStopIx(
 		userAccountPublicKey: PublicKey,
 		userOrdersAccountPublicKey: PublicKey,
 		order: Order
+	): Promise<TransactionInstruction> {
+		const oracle = this.getMarket(order.marketIndex).amm.oracle;
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+
+		return await this.program.instruction.updateTrailingStop(order.orderId, {
+			accounts: {
+				state: await this.getStatePublicKey(),
+				orderState: await this.getOrderStatePublicKey(),
+				user: userAccountPublicKey,
+				markets: state.markets,
+				userOrders: userOrdersAccountPublicKey,
+				orderHistory: orderState.orderHistory,
+				oracle: oracle,
+			},
+		});
+	}
+
+	public async fillOrder(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey,
+		order: Order,
+		makers?: MakerInfo[]
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getFillOrderIx(
+					userAccountPublicKey,
+					userOrdersAccountPublicKey,
+					order,
+					makers
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getFillOrderIx(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey,
+		order: Order,
+		makers?: MakerInfo[]
 	): Promise<TransactionInstruction> {
 		const fillerPublicKey = await this.getUserAccountPublicKey();
 		const userAccount: any = await this.program.account.user.fetch(
@@ -1086,6 +2652,16 @@ export class ClearingHouse {
 			});
 		}
 
+		if (makers) {
+			remainingAccounts.push(
+				...(await this.getOrderBookAndMakerAccounts(marketIndex, makers))
+			);
+		}
+
+		remainingAccounts.push(
+			...(await this.getCollateralAssetsRemainingAccounts(userAccountPublicKey))
+		);
+
 		const orderId = order.orderId;
 		return await this.program.instruction.fillOrder(orderId, {
 			accounts: {
@@ -1096,9 +2672,10 @@ export class ClearingHouse {
 				markets: state.markets,
 				userPositions: userAccount.positions,
 				userOrders: userOrdersAccountPublicKey,
-				tradeHistory: state.tradeHistory,
+				tradeHistory: state.extendedTradeHistory,
 				fundingPaymentHistory: state.fundingPaymentHistory,
-				fundingRateHistory: state.fundingRateHistory,
+				depositHistory: state.depositHistory,
+				fundingRateHistory: state.extendedFundingRateHistory,
 				orderState: await this.getOrderStatePublicKey(),
 				orderHistory: orderState.orderHistory,
 				extendedCurveHistory: state.extendedCurveHistory,
@@ -1108,10 +2685,110 @@ export class ClearingHouse {
 		});
 	}
 
+	/**
+	 * Fills the order with the active sub account as the filler and another of the wallet's sub accounts
+	 * taking the other side of the trade. The rest of the order is filled against the amm.
+	 * @param userAccountPublicKey
+	 * @param userOrdersAccountPublicKey
+	 * @param order
+	 * @param makerParams
+	 * @param makerSubAccountId
+	 * @returns
+	 */
+	public async fillOrderWithJitMaker(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey,
+		order: Order,
+		makerParams: JitMakerParams,
+		makerSubAccountId: number
+	): Promise<TransactionSignature> {
+		return await this.txSender.send(
+			wrapInTx(
+				await this.getFillOrderWithJitMakerIx(
+					userAccountPublicKey,
+					userOrdersAccountPublicKey,
+					order,
+					makerParams,
+					makerSubAccountId
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getFillOrderWithJitMakerIx(
+		userAccountPublicKey: PublicKey,
+		userOrdersAccountPublicKey: PublicKey,
+		order: Order,
+		makerParams: JitMakerParams,
+		makerSubAccountId: number
+	): Promise<TransactionInstruction> {
+		const fillerPublicKey = await this.getUserAccountPublicKey();
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const makerPublicKey = await getUserSubAccountPublicKey(
+			this.program.programId,
+			this.wallet.publicKey,
+			makerSubAccountId
+		);
+		const makerAccount: any = await this.program.account.user.fetch(
+			makerPublicKey
+		);
+
+		const oracle = this.getMarket(order.marketIndex).amm.oracle;
+
+		const state = this.getStateAccount();
+		const orderState = this.getOrderStateAccount();
+
+		const remainingAccounts = [];
+		if (!order.referrer.equals(PublicKey.default)) {
+			remainingAccounts.push({
+				pubkey: order.referrer,
+				isWritable: true,
+				isSigner: false,
+			});
+		}
+
+		remainingAccounts.push(
+			...(await this.getCollateralAssetsRemainingAccounts(userAccountPublicKey))
+		);
+
+		return await this.program.instruction.fillOrderWithJitMaker(
+			order.orderId,
+			makerParams,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					filler: fillerPublicKey,
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					maker: makerPublicKey,
+					makerAuthority: this.wallet.publicKey,
+					markets: state.markets,
+					userPositions: userAccount.positions,
+					makerPositions: makerAccount.positions,
+					userOrders: userOrdersAccountPublicKey,
+					tradeHistory: state.extendedTradeHistory,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+					extendedCurveHistory: state.extendedCurveHistory,
+					oracle: oracle,
+				},
+				remainingAccounts,
+			}
+		);
+	}
+
 	public async initializeUserOrdersThenPlaceAndFillOrder(
 		orderParams: OrderParams,
 		discountToken?: PublicKey,
-		referrer?: PublicKey
+		referrer?: PublicKey,
+		makers?: MakerInfo[]
 	): Promise<TransactionSignature> {
 		const instructions: anchor.web3.TransactionInstruction[] = [];
 		const userOrdersAccountExists = await this.userOrdersAccountExists();
@@ -1119,7 +2796,12 @@ export class ClearingHouse {
 			instructions.push(await this.getInitializeUserOrdersInstruction());
 		}
 		instructions.push(
-			await this.getPlaceAndFillOrderIx(orderParams, discountToken, referrer)
+			await this.getPlaceAndFillOrderIx(
+				orderParams,
+				discountToken,
+				referrer,
+				makers
+			)
 		);
 		const tx = new Transaction();
 		for (const instruction of instructions) {
@@ -1132,11 +2814,17 @@ export class ClearingHouse {
 	public async placeAndFillOrder(
 		orderParams: OrderParams,
 		discountToken?: PublicKey,
-		referrer?: PublicKey
+		referrer?: PublicKey,
+		makers?: MakerInfo[]
 	): Promise<TransactionSignature> {
 		return await this.txSender.send(
 			wrapInTx(
-				await this.getPlaceAndFillOrderIx(orderParams, discountToken, referrer)
+				await this.getPlaceAndFillOrderIx(
+					orderParams,
+					discountToken,
+					referrer,
+					makers
+				)
 			),
 			[],
 			this.opts
@@ -1146,7 +2834,8 @@ export class ClearingHouse {
 	public async getPlaceAndFillOrderIx(
 		orderParams: OrderParams,
 		discountToken?: PublicKey,
-		referrer?: PublicKey
+		referrer?: PublicKey,
+		makers?: MakerInfo[]
 	): Promise<TransactionInstruction> {
 		const userAccountPublicKey = await this.getUserAccountPublicKey();
 		const userAccount = await this.getUserAccount();
@@ -1184,26 +2873,44 @@ export class ClearingHouse {
 			});
 		}
 
+		if (makers) {
+			remainingAccounts.push(
+				...(await this.getOrderBookAndMakerAccounts(
+					orderParams.marketIndex,
+					makers
+				))
+			);
+		}
+
+		remainingAccounts.push(
+			...(await this.getCollateralAssetsRemainingAccounts(userAccountPublicKey))
+		);
+
 		const state = this.getStateAccount();
 		const orderState = this.getOrderStateAccount();
-		return await this.program.instruction.placeAndFillOrder(orderParams, {
-			accounts: {
-				state: await this.getStatePublicKey(),
-				user: userAccountPublicKey,
-				authority: this.wallet.publicKey,
-				markets: state.markets,
-				userOrders: await this.getUserOrdersAccountPublicKey(),
-				userPositions: userAccount.positions,
-				tradeHistory: state.tradeHistory,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-				fundingRateHistory: state.fundingRateHistory,
-				orderState: await this.getOrderStatePublicKey(),
-				orderHistory: orderState.orderHistory,
-				extendedCurveHistory: state.extendedCurveHistory,
-				oracle: priceOracle,
-			},
-			remainingAccounts,
-		});
+		return await this.program.instruction.placeAndFillOrder(
+			this.activeSubAccountId,
+			orderParams,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					user: userAccountPublicKey,
+					authority: this.wallet.publicKey,
+					markets: state.markets,
+					userOrders: await this.getUserOrdersAccountPublicKey(),
+					userPositions: userAccount.positions,
+					tradeHistory: state.extendedTradeHistory,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
+					orderState: await this.getOrderStatePublicKey(),
+					orderHistory: orderState.orderHistory,
+					extendedCurveHistory: state.extendedCurveHistory,
+					oracle: priceOracle,
+				},
+				remainingAccounts,
+			}
+		);
 	}
 
 	/**
@@ -1260,8 +2967,17 @@ export class ClearingHouse {
 			});
 		}
 
+		// linked orders are canceled along with the position
+		const position = (
+			await this.fetchUserPositionsAccount(userAccount.positions)
+		).positions.find((position) => position.marketIndex.eq(marketIndex));
+		if (position && position.openOrders.gt(ZERO)) {
+			remainingAccounts.push(...(await this.getUserOrdersRemainingAccounts()));
+		}
+
 		const state = this.getStateAccount();
 		return await this.program.instruction.closePosition(
+			this.activeSubAccountId,
 			marketIndex,
 			optionalAccounts,
 			{
@@ -1271,9 +2987,10 @@ export class ClearingHouse {
 					authority: this.wallet.publicKey,
 					markets: state.markets,
 					userPositions: userAccount.positions,
-					tradeHistory: state.tradeHistory,
+					tradeHistory: state.extendedTradeHistory,
 					fundingPaymentHistory: state.fundingPaymentHistory,
-					fundingRateHistory: state.fundingRateHistory,
+					depositHistory: state.depositHistory,
+					fundingRateHistory: state.extendedFundingRateHistory,
 					oracle: priceOracle,
 				},
 				remainingAccounts: remainingAccounts,
@@ -1281,6 +2998,36 @@ export class ClearingHouse {
 		);
 	}
 
+	/**
+	 * The user's orders, passed as remaining accounts so that linked orders can be canceled.
+	 * Defaults to the clearing house's own user
+	 */
+	async getUserOrdersRemainingAccounts(
+		userAccountPublicKey?: PublicKey
+	): Promise<AccountMeta[]> {
+		const userOrdersAccountPublicKey = await getUserOrdersAccountPublicKey(
+			this.program.programId,
+			userAccountPublicKey ?? (await this.getUserAccountPublicKey())
+		);
+		return [
+			{
+				pubkey: await this.getOrderStatePublicKey(),
+				isWritable: false,
+				isSigner: false,
+			},
+			{
+				pubkey: this.getOrderStateAccount().orderHistory,
+				isWritable: true,
+				isSigner: false,
+			},
+			{
+				pubkey: userOrdersAccountPublicKey,
+				isWritable: true,
+				isSigner: false,
+			},
+		];
+	}
+
 	public async closeAllPositions(
 		userPositionsAccount: UserPositionsAccount,
 		discountToken?: PublicKey,
@@ -1306,6 +3053,62 @@ export class ClearingHouse {
 		return this.txSender.send(tx, [], this.opts);
 	}
 
+	/**
+	 * Starts the liquidation auction for a user whose account or isolated positions are liquidatable,
+	 * or ends it for a user who is no longer liquidatable
+	 * @param userAccountPublicKey
+	 * @returns
+	 */
+	public async flagUserLiquidatable(
+		userAccountPublicKey: PublicKey
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(await this.getFlagUserLiquidatableIx(userAccountPublicKey)),
+			[],
+			this.opts
+		);
+	}
+
+	public async getFlagUserLiquidatableIx(
+		userAccountPublicKey: PublicKey
+	): Promise<TransactionInstruction> {
+		const userAccount: any = await this.program.account.user.fetch(
+			userAccountPublicKey
+		);
+		const userPositions = await this.fetchUserPositionsAccount(
+			userAccount.positions
+		);
+		const markets = this.getMarketsAccount();
+
+		const remainingAccounts = [];
+		for (const position of userPositions.positions) {
+			if (!position.baseAssetAmount.eq(ZERO)) {
+				const market = markets.markets[position.marketIndex.toNumber()];
+				remainingAccounts.push({
+					pubkey: market.amm.oracle,
+					isWritable: false,
+					isSigner: false,
+				});
+			}
+		}
+		remainingAccounts.push(
+			...(await this.getCollateralAssetsRemainingAccounts(userAccountPublicKey))
+		);
+
+		const state = this.getStateAccount();
+		return await this.program.instruction.flagUserLiquidatable({
+			accounts: {
+				state: await this.getStatePublicKey(),
+				user: userAccountPublicKey,
+				userPositions: userAccount.positions,
+				markets: state.markets,
+				fundingPaymentHistory: state.fundingPaymentHistory,
+				depositHistory: state.depositHistory,
+			},
+			remainingAccounts: remainingAccounts,
+		});
+	}
+
 	public async liquidate(
 		liquidateeUserAccountPublicKey: PublicKey
 	): Promise<TransactionSignature> {
@@ -1316,18 +3119,139 @@ export class ClearingHouse {
 		);
 	}
 
+	public async liquidateIsolatedPosition(
+		liquidateeUserAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(
+				await this.getLiquidateIsolatedPositionIx(
+					liquidateeUserAccountPublicKey,
+					marketIndex
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getLiquidateIsolatedPositionIx(
+		liquidateeUserAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
+		const liquidateeUserAccount: any = await this.program.account.user.fetch(
+			liquidateeUserAccountPublicKey
+		);
+
+		const market = this.getMarketsAccount().markets[marketIndex.toNumber()];
+		const remainingAccounts = [
+			{
+				pubkey: market.amm.oracle,
+				isWritable: false,
+				isSigner: false,
+			},
+		];
+
+		const liquidateePositions = await this.fetchUserPositionsAccount(
+			liquidateeUserAccount.positions
+		);
+		const position = liquidateePositions.positions.find((position) =>
+			position.marketIndex.eq(marketIndex)
+		);
+		if (position && position.openOrders.gt(ZERO)) {
+			remainingAccounts.push(
+				...(await this.getUserOrdersRemainingAccounts(
+					liquidateeUserAccountPublicKey
+				))
+			);
+		}
+
+		return await this.program.instruction.liquidateIsolatedPosition(
+			marketIndex,
+			{
+				accounts: await this.getLiquidateAccounts(
+					liquidateeUserAccountPublicKey,
+					liquidateeUserAccount.positions
+				),
+				remainingAccounts: remainingAccounts,
+			}
+		);
+	}
+
 	public async getLiquidateIx(
 		liquidateeUserAccountPublicKey: PublicKey
 	): Promise<TransactionInstruction> {
-		const userAccountPublicKey = await this.getUserAccountPublicKey();
+		const liquidateeUserAccount: any = await this.program.account.user.fetch(
+			liquidateeUserAccountPublicKey
+		);
+
+		return await this.program.instruction.liquidate({
+			accounts: await this.getLiquidateAccounts(
+				liquidateeUserAccountPublicKey,
+				liquidateeUserAccount.positions
+			),
+			remainingAccounts: await this.getLiquidateRemainingAccounts(
+				liquidateeUserAccountPublicKey,
+				liquidateeUserAccount.positions
+			),
+		});
+	}
+
+	public async liquidateMarket(
+		liquidateeUserAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(
+				await this.getLiquidateMarketIx(
+					liquidateeUserAccountPublicKey,
+					marketIndex
+				)
+			),
+			[],
+			this.opts
+		);
+	}
 
+	/**
+	 * Liquidates the liquidatee's cross margin positions starting with the position in the given market
+	 * @param liquidateeUserAccountPublicKey
+	 * @param marketIndex
+	 * @returns
+	 */
+	public async getLiquidateMarketIx(
+		liquidateeUserAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
 		const liquidateeUserAccount: any = await this.program.account.user.fetch(
 			liquidateeUserAccountPublicKey
 		);
-		const liquidateePositions: any =
-			await this.program.account.userPositions.fetch(
+
+		return await this.program.instruction.liquidateMarket(marketIndex, {
+			accounts: await this.getLiquidateAccounts(
+				liquidateeUserAccountPublicKey,
 				liquidateeUserAccount.positions
-			);
+			),
+			remainingAccounts: await this.getLiquidateRemainingAccounts(
+				liquidateeUserAccountPublicKey,
+				liquidateeUserAccount.positions
+			),
+		});
+	}
+
+	/**
+	 * The oracles of the liquidatee's positions, the liquidatee's collateral assets and, if any position has open orders, the liquidatee's orders
+	 * @param liquidateeUserAccountPublicKey
+	 * @param liquidateeUserPositionsAccountPublicKey
+	 * @returns
+	 */
+	async getLiquidateRemainingAccounts(
+		liquidateeUserAccountPublicKey: PublicKey,
+		liquidateeUserPositionsAccountPublicKey: PublicKey
+	): Promise<AccountMeta[]> {
+		const liquidateePositions = await this.fetchUserPositionsAccount(
+			liquidateeUserPositionsAccountPublicKey
+		);
 		const markets = this.getMarketsAccount();
 
 		const remainingAccounts = [];
@@ -1342,26 +3266,138 @@ export class ClearingHouse {
 			}
 		}
 
+		remainingAccounts.push(
+			...(await this.getCollateralAssetsRemainingAccounts(
+				liquidateeUserAccountPublicKey
+			))
+		);
+		if (
+			liquidateePositions.positions.some((position) =>
+				position.openOrders.gt(ZERO)
+			)
+		) {
+			remainingAccounts.push(
+				...(await this.getUserOrdersRemainingAccounts(
+					liquidateeUserAccountPublicKey
+				))
+			);
+		}
+
+		return remainingAccounts;
+	}
+
+	/**
+	 * Takes over the liquidatee's position in a market at the oracle price less the position transfer discount
+	 * @param liquidateeUserAccountPublicKey
+	 * @param marketIndex
+	 * @returns
+	 */
+	public async liquidateByPositionTransfer(
+		liquidateeUserAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionSignature> {
+		return this.txSender.send(
+			wrapInTx(
+				await this.getLiquidateByPositionTransferIx(
+					liquidateeUserAccountPublicKey,
+					marketIndex
+				)
+			),
+			[],
+			this.opts
+		);
+	}
+
+	public async getLiquidateByPositionTransferIx(
+		liquidateeUserAccountPublicKey: PublicKey,
+		marketIndex: BN
+	): Promise<TransactionInstruction> {
+		const liquidateeUserAccount: any = await this.program.account.user.fetch(
+			liquidateeUserAccountPublicKey
+		);
+		const liquidateePositions = await this.fetchUserPositionsAccount(
+			liquidateeUserAccount.positions
+		);
+		const markets = this.getMarketsAccount();
+
+		const remainingAccounts = [];
+		for (const position of liquidateePositions.positions) {
+			if (!position.baseAssetAmount.eq(ZERO)) {
+				const market = markets.markets[position.marketIndex.toNumber()];
+				remainingAccounts.push({
+					pubkey: market.amm.oracle,
+					isWritable: false,
+					isSigner: false,
+				});
+			}
+		}
+
+		// the liquidator's margin is checked after taking over the position
+		const liquidatorUserAccountPublicKey = await this.getUserAccountPublicKey();
+		remainingAccounts.push(
+			...(await this.getCollateralAssetsRemainingAccounts(
+				liquidateeUserAccountPublicKey
+			)),
+			...(await this.getCollateralAssetsRemainingAccounts(
+				liquidatorUserAccountPublicKey
+			))
+		);
+
+		const position = liquidateePositions.positions.find((position) =>
+			position.marketIndex.eq(marketIndex)
+		);
+		if (position && position.openOrders.gt(ZERO)) {
+			remainingAccounts.push(
+				...(await this.getUserOrdersRemainingAccounts(
+					liquidateeUserAccountPublicKey
+				))
+			);
+		}
+
 		const state = this.getStateAccount();
-		return await this.program.instruction.liquidate({
-			accounts: {
-				state: await this.getStatePublicKey(),
-				authority: this.wallet.publicKey,
-				user: liquidateeUserAccountPublicKey,
-				liquidator: userAccountPublicKey,
-				collateralVault: state.collateralVault,
-				collateralVaultAuthority: state.collateralVaultAuthority,
-				insuranceVault: state.insuranceVault,
-				insuranceVaultAuthority: state.insuranceVaultAuthority,
-				tokenProgram: TOKEN_PROGRAM_ID,
-				markets: state.markets,
-				userPositions: liquidateeUserAccount.positions,
-				tradeHistory: state.tradeHistory,
-				liquidationHistory: state.liquidationHistory,
-				fundingPaymentHistory: state.fundingPaymentHistory,
-			},
-			remainingAccounts: remainingAccounts,
-		});
+		return await this.program.instruction.liquidateByPositionTransfer(
+			marketIndex,
+			{
+				accounts: {
+					state: await this.getStatePublicKey(),
+					authority: this.wallet.publicKey,
+					liquidator: liquidatorUserAccountPublicKey,
+					liquidatorPositions: (await this.getUserAccount()).positions,
+					user: liquidateeUserAccountPublicKey,
+					userPositions: liquidateeUserAccount.positions,
+					markets: state.markets,
+					fundingPaymentHistory: state.fundingPaymentHistory,
+					depositHistory: state.depositHistory,
+					liquidationHistory: state.extendedLiquidationHistory,
+				},
+				remainingAccounts: remainingAccounts,
+			}
+		);
+	}
+
+	async getLiquidateAccounts(
+		liquidateeUserAccountPublicKey: PublicKey,
+		liquidateeUserPositionsAccountPublicKey: PublicKey
+	): Promise<any> {
+		const state = this.getStateAccount();
+		return {
+			state: await this.getStatePublicKey(),
+			authority: this.wallet.publicKey,
+			user: liquidateeUserAccountPublicKey,
+			liquidator: await this.getUserAccountPublicKey(),
+			collateralVault: state.collateralVault,
+			collateralVaultAuthority: state.collateralVaultAuthority,
+			insuranceVault: state.insuranceVault,
+			insuranceVaultAuthority: state.insuranceVaultAuthority,
+			tokenProgram: TOKEN_PROGRAM_ID,
+			markets: state.markets,
+			userPositions: liquidateeUserPositionsAccountPublicKey,
+			tradeHistory: state.extendedTradeHistory,
+			liquidationHistory: state.extendedLiquidationHistory,
+			fundingPaymentHistory: state.fundingPaymentHistory,
+			depositHistory: state.depositHistory,
+			bankruptcyHistory: state.bankruptcyHistory,
+		};
 	}
 
 	public async updateFundingRate(
@@ -1385,7 +3421,8 @@ export class ClearingHouse {
 				state: await this.getStatePublicKey(),
 				markets: state.markets,
 				oracle: oracle,
-				fundingRateHistory: state.fundingRateHistory,
+				fundingRateHistory: state.extendedFundingRateHistory,
+				extendedCurveHistory: state.extendedCurveHistory,
 			},
 		});
 	}
@@ -1415,6 +3452,7 @@ export class ClearingHouse {
 				user: userAccount,
 				userPositions: userPositionsAccount,
 				fundingPaymentHistory: state.fundingPaymentHistory,
+				depositHistory: state.depositHistory,
 			},
 		});
 	}
@@ -1455,6 +3493,7 @@ export class ClearingHouse {
 				userPositions: user.positions,
 				settlementState,
 				fundingPaymentHistory: state.fundingPaymentHistory,
+				depositHistory: state.depositHistory,
 			},
 		});
 	}
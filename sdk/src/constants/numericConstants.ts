@@ -25,3 +25,6 @@ export const AMM_TIMES_PEG_TO_QUOTE_PRECISION_RATIO =
 	AMM_RESERVE_PRECISION.mul(PEG_PRECISION).div(QUOTE_PRECISION); // 10^10
 export const MARGIN_PRECISION = TEN_THOUSAND;
 export const BID_ASK_SPREAD_PRECISION = new BN(1000000);
+export const INTEREST_PRECISION = new BN(10 ** 10);
+export const INTEREST_RATE_PRECISION = new BN(10 ** 6);
+export const UTILIZATION_PRECISION = new BN(10 ** 6);
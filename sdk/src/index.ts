@@ -32,6 +32,7 @@ export * from './math/trade';
 export * from './math/orders';
 export * from './orders';
 export * from './orderParams';
+export * from './userPositions';
 export * from './wallet';
 export * from './types';
 export * from './math/utils';
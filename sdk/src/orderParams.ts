@@ -1,8 +1,10 @@
 import {
+	isVariant,
 	OrderParams,
 	OrderTriggerCondition,
 	OrderType,
 	PositionDirection,
+	TrailingDistanceType,
 } from './types';
 import { BN } from '@project-serum/anchor';
 import { ZERO } from './constants/numericConstants';
@@ -18,7 +20,10 @@ export function getLimitOrderParams(
 	userOrderId = 0,
 	postOnly = false,
 	oraclePriceOffset = ZERO,
-	immediateOrCancel = false
+	immediateOrCancel = false,
+	isolatedCollateral = ZERO,
+	orderBook = false,
+	maxTs = ZERO
 ): OrderParams {
 	return {
 		orderType: OrderType.LIMIT,
@@ -37,10 +42,18 @@ export function getLimitOrderParams(
 		optionalAccounts: {
 			discountToken,
 			referrer,
+			orderBook,
 		},
 		triggerCondition: OrderTriggerCondition.ABOVE,
 		triggerPrice: ZERO,
 		oraclePriceOffset,
+		isolatedCollateral,
+		trailingDistance: ZERO,
+		trailingDistanceType: TrailingDistanceType.ABSOLUTE,
+		sliceBaseAssetAmount: ZERO,
+		sliceInterval: ZERO,
+		linkedOrderId: ZERO,
+		maxTs,
 	};
 }
 
@@ -72,10 +85,18 @@ export function getTriggerMarketOrderParams(
 		optionalAccounts: {
 			discountToken,
 			referrer,
+			orderBook: false,
 		},
 		triggerCondition,
 		triggerPrice,
 		oraclePriceOffset: ZERO,
+		isolatedCollateral: ZERO,
+		trailingDistance: ZERO,
+		trailingDistanceType: TrailingDistanceType.ABSOLUTE,
+		sliceBaseAssetAmount: ZERO,
+		sliceInterval: ZERO,
+		linkedOrderId: ZERO,
+		maxTs: ZERO,
 	};
 }
 
@@ -108,10 +129,109 @@ export function getTriggerLimitOrderParams(
 		optionalAccounts: {
 			discountToken,
 			referrer,
+			orderBook: false,
 		},
 		triggerCondition,
 		triggerPrice,
 		oraclePriceOffset: ZERO,
+		isolatedCollateral: ZERO,
+		trailingDistance: ZERO,
+		trailingDistanceType: TrailingDistanceType.ABSOLUTE,
+		sliceBaseAssetAmount: ZERO,
+		sliceInterval: ZERO,
+		linkedOrderId: ZERO,
+		maxTs: ZERO,
+	};
+}
+
+export function getTrailingStopOrderParams(
+	marketIndex: BN,
+	direction: PositionDirection,
+	baseAssetAmount: BN,
+	triggerPrice: BN,
+	trailingDistance: BN,
+	trailingDistanceType: TrailingDistanceType,
+	reduceOnly: boolean,
+	discountToken = false,
+	referrer = false,
+	userOrderId = 0
+): OrderParams {
+	return {
+		orderType: OrderType.TRAILING_STOP,
+		userOrderId,
+		marketIndex,
+		direction,
+		quoteAssetAmount: ZERO,
+		baseAssetAmount,
+		price: ZERO,
+		reduceOnly,
+		postOnly: false,
+		immediateOrCancel: false,
+		positionLimit: ZERO,
+		padding0: true,
+		padding1: ZERO,
+		optionalAccounts: {
+			discountToken,
+			referrer,
+			orderBook: false,
+		},
+		// a long stop triggers when the price bounces up, a short stop when it drops
+		triggerCondition: isVariant(direction, 'long')
+			? OrderTriggerCondition.ABOVE
+			: OrderTriggerCondition.BELOW,
+		triggerPrice,
+		oraclePriceOffset: ZERO,
+		isolatedCollateral: ZERO,
+		trailingDistance,
+		trailingDistanceType,
+		sliceBaseAssetAmount: ZERO,
+		sliceInterval: ZERO,
+		linkedOrderId: ZERO,
+		maxTs: ZERO,
+	};
+}
+
+export function getTwapOrderParams(
+	marketIndex: BN,
+	direction: PositionDirection,
+	baseAssetAmount: BN,
+	price: BN,
+	sliceBaseAssetAmount: BN,
+	sliceInterval: BN,
+	reduceOnly: boolean,
+	discountToken = false,
+	referrer = false,
+	userOrderId = 0
+): OrderParams {
+	return {
+		orderType: OrderType.TWAP,
+		userOrderId,
+		marketIndex,
+		direction,
+		quoteAssetAmount: ZERO,
+		baseAssetAmount,
+		price,
+		reduceOnly,
+		postOnly: false,
+		immediateOrCancel: false,
+		positionLimit: ZERO,
+		padding0: true,
+		padding1: ZERO,
+		optionalAccounts: {
+			discountToken,
+			referrer,
+			orderBook: false,
+		},
+		triggerCondition: OrderTriggerCondition.ABOVE,
+		triggerPrice: ZERO,
+		oraclePriceOffset: ZERO,
+		isolatedCollateral: ZERO,
+		trailingDistance: ZERO,
+		trailingDistanceType: TrailingDistanceType.ABSOLUTE,
+		sliceBaseAssetAmount,
+		sliceInterval,
+		linkedOrderId: ZERO,
+		maxTs: ZERO,
 	};
 }
 
@@ -123,7 +243,8 @@ export function getMarketOrderParams(
 	reduceOnly: boolean,
 	price = ZERO,
 	discountToken = false,
-	referrer = false
+	referrer = false,
+	isolatedCollateral = ZERO
 ): OrderParams {
 	if (baseAssetAmount.eq(ZERO) && quoteAssetAmount.eq(ZERO)) {
 		throw Error('baseAssetAmount or quoteAssetAmount must be zero');
@@ -146,9 +267,17 @@ export function getMarketOrderParams(
 		optionalAccounts: {
 			discountToken,
 			referrer,
+			orderBook: false,
 		},
 		triggerCondition: OrderTriggerCondition.ABOVE,
 		triggerPrice: ZERO,
 		oraclePriceOffset: ZERO,
+		isolatedCollateral,
+		trailingDistance: ZERO,
+		trailingDistanceType: TrailingDistanceType.ABSOLUTE,
+		sliceBaseAssetAmount: ZERO,
+		sliceInterval: ZERO,
+		linkedOrderId: ZERO,
+		maxTs: ZERO,
 	};
 }
@@ -17,11 +17,23 @@ export class OracleSource {
 	static readonly SWITCHBOARD = { switchboard: {} };
 }
 
+export class CurveType {
+	static readonly CONSTANT_PRODUCT = { constantProduct: {} };
+	static readonly STABLE_SWAP = { stableSwap: {} };
+}
+
 export class OrderType {
 	static readonly LIMIT = { limit: {} };
 	static readonly TRIGGER_MARKET = { triggerMarket: {} };
 	static readonly TRIGGER_LIMIT = { triggerLimit: {} };
 	static readonly MARKET = { market: {} };
+	static readonly TRAILING_STOP = { trailingStop: {} };
+	static readonly TWAP = { twap: {} };
+}
+
+export class TrailingDistanceType {
+	static readonly ABSOLUTE = { absolute: {} };
+	static readonly BASIS_POINTS = { basisPoints: {} };
 }
 
 export class OrderStatus {
@@ -42,6 +54,16 @@ export class OrderAction {
 	static readonly CANCEL = { cancel: {} };
 	static readonly EXPIRE = { expire: {} };
 	static readonly FILL = { fill: {} };
+	static readonly TRIGGER_UPDATE = { triggerUpdate: {} };
+	static readonly EXPIRE_MAX_TS = { expireMaxTs: {} };
+	static readonly MODIFY = { modify: {} };
+}
+
+export class StakeAction {
+	static readonly STAKE = { stake: {} };
+	static readonly UNSTAKE_REQUEST = { unstakeRequest: {} };
+	static readonly UNSTAKE_CANCEL_REQUEST = { unstakeCancelRequest: {} };
+	static readonly UNSTAKE = { unstake: {} };
 }
 
 export class OrderTriggerCondition {
@@ -70,9 +92,9 @@ export type CandleResolution =
 	| 'M';
 
 // # ClearingHouse Account Types
-export type TradeHistoryAccount = {
+export type ExtendedTradeHistoryAccount = {
 	head: BN;
-	tradeRecords: TradeRecord[];
+	tradeRecords: ExtendedTradeRecord[];
 };
 
 export type DepositHistoryAccount = {
@@ -90,6 +112,11 @@ export type FundingRateHistoryAccount = {
 	fundingRateRecords: FundingRateRecord[];
 };
 
+export type ExtendedFundingRateHistoryAccount = {
+	head: BN;
+	fundingRateRecords: ExtendedFundingRateRecord[];
+};
+
 export type FundingPaymentHistoryAccount = {
 	head: BN;
 	fundingPaymentRecords: FundingPaymentRecord[];
@@ -100,12 +127,54 @@ export type LiquidationHistoryAccount = {
 	liquidationRecords: LiquidationRecord[];
 };
 
+export type ExtendedLiquidationHistoryAccount = {
+	head: BN;
+	liquidationRecords: ExtendedLiquidationRecord[];
+};
+
+export type BankruptcyHistoryAccount = {
+	head: BN;
+	bankruptcyRecords: BankruptcyRecord[];
+};
+
+export type InsuranceFundStakeHistoryAccount = {
+	head: BN;
+	insuranceFundStakeRecords: InsuranceFundStakeRecord[];
+};
+
 export type OrderHistoryAccount = {
 	head: BN;
 	lastOrderId: BN;
 	orderRecords: OrderRecord[];
 };
 
+export type BankruptcyRecord = {
+	ts: BN;
+	recordId: BN;
+	userAuthority: PublicKey;
+	user: PublicKey;
+	liquidator: PublicKey;
+	marketIndex: BN;
+	bankruptLoss: BN;
+	lossCoveredByFeePool: BN;
+	lossCoveredByInsuranceFund: BN;
+	lossSocialized: BN;
+	cumulativeFundingRateLongDelta: BN;
+	cumulativeFundingRateShortDelta: BN;
+	uncoveredLoss: BN;
+};
+
+export type InsuranceFundStakeRecord = {
+	ts: BN;
+	recordId: BN;
+	userAuthority: PublicKey;
+	action: StakeAction;
+	amount: BN;
+	shares: BN;
+	totalSharesBefore: BN;
+	insuranceVaultAmountBefore: BN;
+};
+
 export type DepositRecord = {
 	ts: BN;
 	recordId: BN;
@@ -114,6 +183,10 @@ export type DepositRecord = {
 	direction: {
 		deposit?: any;
 		withdraw?: any;
+		transferIn?: any;
+		transferOut?: any;
+		collateralAssetSwap?: any;
+		interestAccrual?: any;
 	};
 	collateralBefore: BN;
 	cumulativeDepositsBefore: BN;
@@ -136,11 +209,14 @@ export type ExtendedCurveRecord = {
 	baseAssetAmountShort: BN;
 	baseAssetAmount: BN;
 	openInterest: BN;
+	totalFee: BN;
+	totalFeeMinusDistributions: BN;
+	adjustmentCost: BN;
 	oraclePrice: BN;
-	tradeId: BN;
+	tradeRecord: BN;
 };
 
-export type TradeRecord = {
+export type ExtendedTradeRecord = {
 	ts: BN;
 	recordId: BN;
 	userAuthority: PublicKey;
@@ -160,6 +236,9 @@ export type TradeRecord = {
 	marketIndex: BN;
 	liquidation: boolean;
 	oraclePrice: BN;
+	maker: PublicKey;
+	makerFee: BN;
+	spread: BN;
 };
 
 export type FundingRateRecord = {
@@ -173,6 +252,19 @@ export type FundingRateRecord = {
 	markPriceTwap: BN;
 };
 
+export type ExtendedFundingRateRecord = {
+	ts: BN;
+	recordId: BN;
+	marketIndex: BN;
+	fundingRate: BN;
+	cumulativeFundingRateLong: BN;
+	cumulativeFundingRateShort: BN;
+	oraclePriceTwap: BN;
+	markPriceTwap: BN;
+	rawFundingRate: BN;
+	premiumIndex: BN;
+};
+
 export type FundingPaymentRecord = {
 	ts: BN;
 	recordId: BN;
@@ -205,6 +297,27 @@ export type LiquidationRecord = {
 	marginRatio: BN;
 };
 
+export type ExtendedLiquidationRecord = {
+	ts: BN;
+	recordId: BN;
+	userAuthority: PublicKey;
+	user: PublicKey;
+	partial: boolean;
+	baseAssetValue: BN;
+	baseAssetValueClosed: BN;
+	liquidationFee: BN;
+	feeToLiquidator: BN;
+	feeToInsuranceFund: BN;
+	liquidator: PublicKey;
+	totalCollateral: BN;
+	collateral: BN;
+	unrealizedPnl: BN;
+	marginRatio: BN;
+	marketIndex: BN;
+	baseAssetAmountTransferred: BN;
+	transferPrice: BN;
+};
+
 export type OrderRecord = {
 	ts: BN;
 	recordId: BN;
@@ -260,6 +373,84 @@ export type StateAccount = {
 	maxDeposit: BN;
 	orderState: PublicKey;
 	extendedCurveHistory: PublicKey;
+	makerRebateNumerator: BN;
+	makerRebateDenominator: BN;
+	extendedTradeHistory: PublicKey;
+	collateralAssets: PublicKey;
+	quoteLendingPool: LendingPool;
+	insuranceFund: InsuranceFund;
+	bankruptcyHistory: PublicKey;
+	positionTransferDiscountNumerator: BN;
+	positionTransferDiscountDenominator: BN;
+	extendedLiquidationHistory: PublicKey;
+	liquidationAuction: LiquidationAuction;
+	formulaicRepegThresholdNumerator: BN;
+	formulaicRepegThresholdDenominator: BN;
+	formulaicKMaxChangeNumerator: BN;
+	formulaicKMaxChangeDenominator: BN;
+	extendedFundingRateHistory: PublicKey;
+};
+
+export type LiquidationAuction = {
+	startLiquidatorShareNumerator: BN;
+	startLiquidatorShareDenominator: BN;
+	duration: BN;
+};
+
+export type InsuranceFund = {
+	stakeHistory: PublicKey;
+	totalShares: BN;
+	protocolShares: BN;
+	unstakingPeriod: BN;
+	feeShareNumerator: BN;
+	feeShareDenominator: BN;
+};
+
+export type InsuranceFundStake = {
+	authority: PublicKey;
+	shares: BN;
+	lastWithdrawRequestShares: BN;
+	lastWithdrawRequestValue: BN;
+	lastWithdrawRequestTs: BN;
+};
+
+export type LendingPool = {
+	totalDeposits: BN;
+	totalBorrows: BN;
+	cumulativeDepositInterest: BN;
+	cumulativeBorrowInterest: BN;
+	lastInterestTs: BN;
+	optimalUtilization: BN;
+	optimalBorrowRate: BN;
+	maxBorrowRate: BN;
+};
+
+export type CollateralAssetsAccount = {
+	assets: CollateralAsset[];
+};
+
+export type CollateralAsset = {
+	initialized: boolean;
+	mint: PublicKey;
+	mintDecimals: number;
+	vault: PublicKey;
+	vaultAuthority: PublicKey;
+	vaultNonce: number;
+	oracle: PublicKey;
+	oracleSource: OracleSource;
+	assetWeight: BN;
+	liquidationDiscount: BN;
+	lendingPool: LendingPool;
+};
+
+export type UserCollateralAssetsAccount = {
+	user: PublicKey;
+	balances: BN[];
+	borrows: BN[];
+	lastCumulativeDepositInterest: BN[];
+	lastCumulativeBorrowInterest: BN[];
+	quoteBorrows: BN;
+	lastCumulativeQuoteBorrowInterest: BN;
 };
 
 export type OrderStateAccount = {
@@ -289,6 +480,17 @@ export type Market = {
 	marginRatioInitial: number;
 	marginRatioMaintenance: number;
 	marginRatioPartial: number;
+	partialLiquidationPenalty: number;
+	fullLiquidationPenalty: number;
+	partialLiquidationLiquidatorShare: number;
+	fullLiquidationLiquidatorShare: number;
+	imfFactor: number;
+	premiumSampleSum: BN;
+	premiumSampleDuration: BN;
+	lastPremium: BN;
+	lastPremiumSampleTs: BN;
+	maxFundingRate: number;
+	fundingInterestRate: number;
 };
 
 export type AMM = {
@@ -317,6 +519,12 @@ export type AMM = {
 	minimumBaseAssetTradeSize: BN;
 	lastOraclePrice: BN;
 	baseSpread: number;
+	longSpread: number;
+	shortSpread: number;
+	maxSpread: number;
+	totalFeeSettledToInsuranceFund: BN;
+	curveType: CurveType;
+	amplificationCoefficient: BN;
 };
 
 // # User Account Types
@@ -326,6 +534,9 @@ export type UserPosition = {
 	marketIndex: BN;
 	quoteAssetAmount: BN;
 	openOrders: BN;
+	isolatedCollateral: BN;
+	isIsolated: number;
+	liquidationStartSlot: BN;
 };
 
 export type UserPositionsAccount = {
@@ -348,6 +559,11 @@ export type UserAccount = {
 	lastCollateralAvailableToClaim: BN;
 	forgoPositionSettlement: number;
 	hasSettledPosition: number;
+	subAccountId: number;
+	hasCollateralAssets: number;
+	lastCumulativeDepositInterest: BN;
+	hasBorrows: number;
+	liquidationStartSlot: BN;
 };
 
 export type UserOrdersAccount = {
@@ -378,6 +594,13 @@ export type Order = {
 	postOnly: boolean;
 	immediateOrCancel: boolean;
 	oraclePriceOffset: BN;
+	trailingDistance: BN;
+	trailingDistanceType: TrailingDistanceType;
+	sliceBaseAssetAmount: BN;
+	sliceInterval: BN;
+	lastSliceTs: BN;
+	linkedOrderId: BN;
+	maxTs: BN;
 };
 
 export type OrderParams = {
@@ -395,14 +618,57 @@ export type OrderParams = {
 	triggerCondition: OrderTriggerCondition;
 	positionLimit: BN;
 	oraclePriceOffset: BN;
+	isolatedCollateral: BN;
+	trailingDistance: BN;
+	trailingDistanceType: TrailingDistanceType;
+	sliceBaseAssetAmount: BN;
+	sliceInterval: BN;
+	linkedOrderId: BN;
+	maxTs: BN;
 	padding0: boolean;
 	padding1: BN;
 	optionalAccounts: {
 		discountToken: boolean;
 		referrer: boolean;
+		orderBook: boolean;
 	};
 };
 
+/**
+ * Fields left as null keep the order's current value
+ */
+export type ModifyOrderParams = {
+	baseAssetAmount: BN | null;
+	price: BN | null;
+	oraclePriceOffset: BN | null;
+	triggerPrice: BN | null;
+	orderBook: boolean;
+};
+
+export type OrderBookAccount = {
+	marketIndex: BN;
+	bids: OrderBookEntry[];
+	asks: OrderBookEntry[];
+};
+
+export type OrderBookEntry = {
+	user: PublicKey;
+	orderId: BN;
+	price: BN;
+	ts: BN;
+};
+
+export type JitMakerParams = {
+	baseAssetAmount: BN;
+	price: BN;
+};
+
+export type MakerInfo = {
+	maker: PublicKey;
+	makerPositions: PublicKey;
+	makerOrders: PublicKey;
+};
+
 // # Misc Types
 export interface IWallet {
 	signTransaction(tx: Transaction): Promise<Transaction>;
@@ -0,0 +1,67 @@
+import { Program } from '@project-serum/anchor';
+import { UserPositionsAccount } from './types';
+
+// discriminator + user
+export const USER_POSITIONS_OFFSET = 8 + 32;
+export const MARKET_POSITION_SIZE = 208;
+export const USER_POSITIONS_LAYOUT_LENGTH = 5;
+export const MAX_USER_POSITIONS = 64;
+
+export function getNumberOfPositions(dataLength: number): number {
+	return Math.floor(
+		Math.max(dataLength - USER_POSITIONS_OFFSET, 0) / MARKET_POSITION_SIZE
+	);
+}
+
+export function getUserPositionsAccountSize(numberOfPositions: number): number {
+	return USER_POSITIONS_OFFSET + numberOfPositions * MARKET_POSITION_SIZE;
+}
+
+/**
+ * The idl layout only holds the first five positions. Positions added by resizing the account are stored
+ * contiguously after them, so they're decoded by swapping each chunk of five into the layout's positions.
+ * @param program
+ * @param buffer
+ * @returns
+ */
+export function decodeUserPositionsAccount(
+	program: Program,
+	buffer: Buffer
+): UserPositionsAccount {
+	const layoutLength = getUserPositionsAccountSize(
+		USER_POSITIONS_LAYOUT_LENGTH
+	);
+	const numberOfPositions = getNumberOfPositions(buffer.length);
+
+	const userPositionsAccount = program.coder.accounts.decode(
+		'UserPositions',
+		buffer.slice(0, layoutLength)
+	) as UserPositionsAccount;
+
+	for (
+		let start = USER_POSITIONS_LAYOUT_LENGTH;
+		start < numberOfPositions;
+		start += USER_POSITIONS_LAYOUT_LENGTH
+	) {
+		const chunk = Buffer.alloc(layoutLength);
+		buffer.copy(chunk, 0, 0, USER_POSITIONS_OFFSET);
+		buffer.copy(
+			chunk,
+			USER_POSITIONS_OFFSET,
+			getUserPositionsAccountSize(start),
+			getUserPositionsAccountSize(
+				Math.min(start + USER_POSITIONS_LAYOUT_LENGTH, numberOfPositions)
+			)
+		);
+
+		const decodedChunk = program.coder.accounts.decode(
+			'UserPositions',
+			chunk
+		) as UserPositionsAccount;
+		userPositionsAccount.positions.push(
+			...decodedChunk.positions.slice(0, numberOfPositions - start)
+		);
+	}
+
+	return userPositionsAccount;
+}
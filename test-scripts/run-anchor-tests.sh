@@ -4,7 +4,7 @@ if [ "$1" != "--skip-build" ]
     cp target/idl/clearing_house.json sdk/src/idl/
 fi
 
-test_files=(settleAndClaimCollateral.ts ordersWithSpread.ts order.ts orderReferrer.ts marketOrder.ts triggerOrders.ts stopLimits.ts userOrderId.ts makerOrder.ts roundInFavorBaseAsset.ts marketOrderBaseAssetAmount.ts expireOrders.ts oracleOffsetOrders.ts clearingHouse.ts pyth.ts userAccount.ts admin.ts updateK.ts adminWithdraw.ts curve.ts whitelist.ts fees.ts idempotentCurve.ts maxDeposit.ts maxPositions.ts maxReserves.ts twapDivergenceLiquidation.ts oraclePnlLiquidation.ts whaleLiquidation.ts roundInFavor.ts minimumTradeSize.ts cappedSymFunding.ts cancelAllOrders.ts)
+test_files=(settleAndClaimCollateral.ts ordersWithSpread.ts order.ts orderReferrer.ts marketOrder.ts triggerOrders.ts stopLimits.ts userOrderId.ts makerOrder.ts roundInFavorBaseAsset.ts marketOrderBaseAssetAmount.ts expireOrders.ts oracleOffsetOrders.ts clearingHouse.ts pyth.ts userAccount.ts admin.ts updateK.ts adminWithdraw.ts curve.ts whitelist.ts fees.ts idempotentCurve.ts maxDeposit.ts maxPositions.ts maxReserves.ts twapDivergenceLiquidation.ts oraclePnlLiquidation.ts whaleLiquidation.ts roundInFavor.ts minimumTradeSize.ts cappedSymFunding.ts cancelAllOrders.ts subAccounts.ts resizeUserPositions.ts isolatedMargin.ts orderBook.ts jitMaker.ts trailingStop.ts twapOrders.ts bracketOrders.ts maxTsOrders.ts modifyOrder.ts batchOrders.ts collateralAssets.ts lending.ts insuranceFundStake.ts bankruptcyHistory.ts liquidateByPositionTransfer.ts liquidateMarket.ts liquidationAuction.ts marketLiquidationFees.ts imfFactor.ts dynamicSpread.ts formulaicRepeg.ts formulaicK.ts stableSwapCurve.ts fundingRateClamp.ts)
 
 for test_file in ${test_files[@]}; do
   export ANCHOR_TEST_FILE=${test_file} && anchor test --skip-build || exit 1;
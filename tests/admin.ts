@@ -58,12 +58,14 @@ describe('admin', () => {
 		const marginRatioInitial = 3000;
 		const marginRatioPartial = 2000;
 		const marginRatioMaintenance = 1000;
+		const imfFactor = 100;
 
 		await clearingHouse.updateMarginRatio(
 			Markets[0].marketIndex,
 			marginRatioInitial,
 			marginRatioPartial,
-			marginRatioMaintenance
+			marginRatioMaintenance,
+			imfFactor
 		);
 
 		await clearingHouse.fetchAccounts();
@@ -72,6 +74,7 @@ describe('admin', () => {
 		assert(market.marginRatioInitial === marginRatioInitial);
 		assert(market.marginRatioPartial === marginRatioPartial);
 		assert(market.marginRatioMaintenance === marginRatioMaintenance);
+		assert(market.imfFactor === imfFactor);
 	});
 
 	it('Update Partial Liquidation Close Percentages', async () => {
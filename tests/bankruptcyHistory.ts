@@ -0,0 +1,60 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Admin } from '../sdk/src';
+
+import { mockUSDCMint } from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('bankruptcy history', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Initialize bankruptcy history with the state', async () => {
+		const state = clearingHouse.getStateAccount();
+		assert(!state.bankruptcyHistory.equals(anchor.web3.PublicKey.default));
+
+		const bankruptcyHistory: any =
+			await chProgram.account.bankruptcyHistory.fetch(state.bankruptcyHistory);
+		assert(bankruptcyHistory.head.eq(ZERO));
+	});
+
+	it('Fail to initialize bankruptcy history twice', async () => {
+		try {
+			await clearingHouse.initializeBankruptcyHistory();
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+});
@@ -0,0 +1,202 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+	getLimitOrderParams,
+	getMarketOrderParams,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { BASE_PRECISION, ZERO } from '../sdk';
+
+describe('batch orders', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+
+	function getOrderParams(direction: PositionDirection, price: BN) {
+		return getLimitOrderParams(
+			marketIndex,
+			direction,
+			BASE_PRECISION,
+			price,
+			false
+		);
+	}
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function getOrders() {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+		return userOrders.orders.filter((order) => !order.orderId.eq(ZERO));
+	}
+
+	it('Place orders', async () => {
+		await clearingHouse.placeOrders([
+			getOrderParams(
+				PositionDirection.LONG,
+				MARK_PRICE_PRECISION.div(new BN(2))
+			),
+			getOrderParams(
+				PositionDirection.LONG,
+				MARK_PRICE_PRECISION.mul(new BN(6)).div(new BN(10))
+			),
+			getOrderParams(
+				PositionDirection.SHORT,
+				MARK_PRICE_PRECISION.mul(new BN(3)).div(new BN(2))
+			),
+		]);
+
+		const orders = await getOrders();
+		assert(orders.length === 3);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.openOrders.eq(new BN(3)));
+	});
+
+	it('Fail to place batch with a market order', async () => {
+		try {
+			await clearingHouse.placeOrders([
+				getOrderParams(
+					PositionDirection.LONG,
+					MARK_PRICE_PRECISION.div(new BN(2))
+				),
+				getMarketOrderParams(
+					marketIndex,
+					PositionDirection.LONG,
+					ZERO,
+					BASE_PRECISION,
+					false
+				),
+			]);
+		} catch (e) {
+			// the whole batch is rejected
+			assert((await getOrders()).length === 3);
+			return;
+		}
+		assert(false);
+	});
+
+	it('Cancel and place orders', async () => {
+		const [firstOrder, secondOrder, thirdOrder] = await getOrders();
+		const price = MARK_PRICE_PRECISION.mul(new BN(7)).div(new BN(10));
+
+		await clearingHouse.cancelAndPlaceOrders(
+			[firstOrder.orderId, secondOrder.orderId],
+			[getOrderParams(PositionDirection.LONG, price)]
+		);
+
+		const orders = await getOrders();
+		assert(orders.length === 2);
+		assert(orders.some((order) => order.orderId.eq(thirdOrder.orderId)));
+		assert(orders.some((order) => order.price.eq(price)));
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.openOrders.eq(new BN(2)));
+	});
+
+	it('Cancel orders', async () => {
+		const orderIds = (await getOrders()).map((order) => order.orderId);
+		await clearingHouse.cancelOrders(orderIds);
+
+		assert((await getOrders()).length === 0);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.openOrders.eq(ZERO));
+	});
+});
@@ -0,0 +1,228 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	OrderParams,
+	OrderTriggerCondition,
+	Wallet,
+	getLimitOrderParams,
+	getTriggerMarketOrderParams,
+} from '../sdk/src';
+
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+import { BASE_PRECISION, ZERO } from '../sdk';
+
+describe('bracket orders', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	let solUsd;
+
+	const entry = getLimitOrderParams(
+		marketIndex,
+		PositionDirection.LONG,
+		BASE_PRECISION,
+		MARK_PRICE_PRECISION.mul(new BN(101)).div(new BN(100)),
+		false
+	);
+	const takeProfit = getLimitOrderParams(
+		marketIndex,
+		PositionDirection.SHORT,
+		BASE_PRECISION,
+		MARK_PRICE_PRECISION.mul(new BN(3)).div(new BN(2)),
+		true
+	);
+	const stopLoss = getTriggerMarketOrderParams(
+		marketIndex,
+		PositionDirection.SHORT,
+		BASE_PRECISION,
+		MARK_PRICE_PRECISION.div(new BN(2)),
+		OrderTriggerCondition.BELOW,
+		true
+	);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function getOrders() {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+		return userOrders.orders.filter((order) => !order.orderId.eq(ZERO));
+	}
+
+	async function resetPrice() {
+		await fillerClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			marketIndex
+		);
+		await setFeedPrice(anchor.workspace.Pyth, 1, solUsd);
+	}
+
+	async function fillOrder(order) {
+		await fillerClearingHouse.fillOrder(
+			await clearingHouse.getUserAccountPublicKey(),
+			await clearingHouse.getUserOrdersAccountPublicKey(),
+			order
+		);
+	}
+
+	it('Fail to place bracket without a reduce only take profit', async () => {
+		const notReduceOnly: OrderParams = { ...takeProfit, reduceOnly: false };
+		try {
+			await clearingHouse.placeBracketOrders(entry, notReduceOnly, stopLoss);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Place bracket orders', async () => {
+		await clearingHouse.placeBracketOrders(entry, takeProfit, stopLoss);
+
+		// the take profit and stop loss are linked to each other
+		const [entryOrder, takeProfitOrder, stopLossOrder] = await getOrders();
+		assert(entryOrder.linkedOrderId.eq(ZERO));
+		assert(takeProfitOrder.linkedOrderId.eq(stopLossOrder.orderId));
+		assert(stopLossOrder.linkedOrderId.eq(takeProfitOrder.orderId));
+	});
+
+	it('Fill entry order', async () => {
+		const [entryOrder] = await getOrders();
+		await fillOrder(entryOrder);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(BASE_PRECISION));
+		assert((await getOrders()).length === 2);
+	});
+
+	it('Fill take profit cancels stop loss', async () => {
+		await fillerClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve.div(new BN(2)),
+			ammInitialQuoteAssetReserve,
+			marketIndex
+		);
+		await setFeedPrice(anchor.workspace.Pyth, 2, solUsd);
+
+		const [takeProfitOrder] = await getOrders();
+		await fillOrder(takeProfitOrder);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(ZERO));
+		assert(position.openOrders.eq(ZERO));
+		assert((await getOrders()).length === 0);
+	});
+
+	it('Close position cancels linked orders', async () => {
+		await resetPrice();
+
+		await clearingHouse.placeBracketOrders(entry, takeProfit, stopLoss);
+		const [entryOrder] = await getOrders();
+		await fillOrder(entryOrder);
+		assert((await getOrders()).length === 2);
+
+		await clearingHouse.closePosition(marketIndex);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(ZERO));
+		assert(position.openOrders.eq(ZERO));
+		assert((await getOrders()).length === 0);
+	});
+});
@@ -0,0 +1,285 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	OracleSource,
+	PositionDirection,
+	Wallet,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { QUOTE_PRECISION, ZERO } from '../sdk';
+
+describe('collateral assets', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let btcMint;
+	let fillerBTCAccount;
+	let traderBTCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const btcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const assetIndex = new BN(0);
+	// more than the usdc deposit alone can back at 5x leverage
+	const positionQuoteAssetAmount = QUOTE_PRECISION.mul(new BN(70));
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		btcMint = await mockUSDCMint(provider);
+		fillerBTCAccount = await mockUserUSDCAccount(btcMint, ZERO, provider);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		traderBTCAccount = await mockUserUSDCAccount(
+			btcMint,
+			btcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Fail to migrate an up to date state', async () => {
+		try {
+			await fillerClearingHouse.migrateState();
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Initialize collateral assets', async () => {
+		await fillerClearingHouse.initializeCollateralAssets();
+		await fillerClearingHouse.fetchAccounts();
+		await clearingHouse.fetchAccounts();
+
+		const state = fillerClearingHouse.getStateAccount();
+		assert(!state.collateralAssets.equals(anchor.web3.PublicKey.default));
+	});
+
+	it('Fail to add collateral asset with asset weight above 100%', async () => {
+		try {
+			await fillerClearingHouse.addCollateralAsset(
+				assetIndex,
+				btcMint.publicKey,
+				await mockOracle(1),
+				OracleSource.PYTH,
+				new BN(10001),
+				new BN(500)
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Add collateral asset', async () => {
+		const btcUsd = await mockOracle(1);
+		await fillerClearingHouse.addCollateralAsset(
+			assetIndex,
+			btcMint.publicKey,
+			btcUsd,
+			OracleSource.PYTH,
+			new BN(8000),
+			new BN(500)
+		);
+
+		const collateralAsset = (
+			await fillerClearingHouse.fetchCollateralAssetsAccount()
+		).assets[0];
+		assert(collateralAsset.initialized);
+		assert(collateralAsset.mint.equals(btcMint.publicKey));
+		assert(collateralAsset.oracle.equals(btcUsd));
+		assert(collateralAsset.mintDecimals === 6);
+		assert(collateralAsset.assetWeight.eq(new BN(8000)));
+		assert(collateralAsset.liquidationDiscount.eq(new BN(500)));
+	});
+
+	it('Update collateral asset weights', async () => {
+		await fillerClearingHouse.updateCollateralAssetWeights(
+			assetIndex,
+			new BN(7500),
+			new BN(600)
+		);
+
+		const collateralAsset = (
+			await fillerClearingHouse.fetchCollateralAssetsAccount()
+		).assets[0];
+		assert(collateralAsset.assetWeight.eq(new BN(7500)));
+		assert(collateralAsset.liquidationDiscount.eq(new BN(600)));
+	});
+
+	it('Fail to open position above usdc margin', async () => {
+		try {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				positionQuoteAssetAmount,
+				marketIndex
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Deposit collateral asset', async () => {
+		await clearingHouse.initializeUserCollateralAssets();
+		await clearingHouse.depositCollateralAsset(
+			assetIndex,
+			btcAmount,
+			traderBTCAccount.publicKey
+		);
+
+		const userCollateralAssets =
+			await clearingHouse.fetchUserCollateralAssetsAccount();
+		assert(userCollateralAssets.balances[0].eq(btcAmount));
+
+		const collateralAsset = (await clearingHouse.fetchCollateralAssetsAccount())
+			.assets[0];
+		assert(collateralAsset.lendingPool.totalDeposits.eq(btcAmount));
+		const vaultBalance = await connection.getTokenAccountBalance(
+			collateralAsset.vault
+		);
+		assert(new BN(vaultBalance.value.amount).eq(btcAmount));
+	});
+
+	it('Open position backed by collateral asset', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			positionQuoteAssetAmount,
+			marketIndex
+		);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.gt(ZERO));
+	});
+
+	it('Fail to withdraw collateral asset backing open position', async () => {
+		try {
+			await clearingHouse.withdrawCollateralAsset(
+				assetIndex,
+				btcAmount,
+				traderBTCAccount.publicKey
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to liquidate collateral asset without deficit', async () => {
+		try {
+			await fillerClearingHouse.liquidateCollateralAsset(
+				await clearingHouse.getUserAccountPublicKey(),
+				assetIndex,
+				usdcAmount,
+				userUSDCAccount.publicKey,
+				fillerBTCAccount.publicKey
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Withdraw collateral asset after closing position', async () => {
+		await clearingHouse.closePosition(marketIndex);
+		await clearingHouse.withdrawCollateralAsset(
+			assetIndex,
+			btcAmount,
+			traderBTCAccount.publicKey
+		);
+
+		const userCollateralAssets =
+			await clearingHouse.fetchUserCollateralAssetsAccount();
+		assert(userCollateralAssets.balances[0].eq(ZERO));
+
+		const traderBalance = await connection.getTokenAccountBalance(
+			traderBTCAccount.publicKey
+		);
+		assert(new BN(traderBalance.value.amount).eq(btcAmount));
+	});
+});
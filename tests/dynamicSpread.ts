@@ -0,0 +1,136 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Admin, BN, MARK_PRICE_PRECISION, PositionDirection } from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('dynamic spread', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const baseSpread = 500;
+	const maxSpread = 2000;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe(['tradeHistoryAccount']);
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+		await clearingHouse.updateMarketBaseSpread(marketIndex, baseSpread);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Fail to set a max spread below the base spread', async () => {
+		try {
+			await clearingHouse.updateMarketMaxSpread(marketIndex, baseSpread - 1);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update the max spread', async () => {
+		await clearingHouse.updateMarketMaxSpread(marketIndex, maxSpread);
+
+		await clearingHouse.fetchAccounts();
+		const market = clearingHouse.getMarket(marketIndex);
+		assert(market.amm.maxSpread === maxSpread);
+	});
+
+	it('Fail to set a base spread above the max spread', async () => {
+		try {
+			await clearingHouse.updateMarketBaseSpread(marketIndex, maxSpread + 1);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Record the spread a trade paid', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			marketIndex
+		);
+
+		// each side's spread is between half the base and half the max spread
+		await clearingHouse.fetchAccounts();
+		const tradeRecord = clearingHouse.getTradeHistoryAccount().tradeRecords[0];
+		assert(tradeRecord.spread.gte(new BN(baseSpread / 2)));
+		assert(tradeRecord.spread.lte(new BN(maxSpread / 2)));
+	});
+
+	it('Widen the spread on the side that grows the amm exposure', async () => {
+		// users are net long so the amm is net short and the mark is above the oracle
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			marketIndex
+		);
+
+		await clearingHouse.fetchAccounts();
+		const market = clearingHouse.getMarket(marketIndex);
+		assert(market.amm.longSpread > market.amm.shortSpread);
+		assert(market.amm.longSpread <= maxSpread / 2);
+
+		const tradeHistory = clearingHouse.getTradeHistoryAccount();
+		assert(
+			tradeHistory.tradeRecords[1].spread.gt(
+				tradeHistory.tradeRecords[0].spread
+			)
+		);
+	});
+});
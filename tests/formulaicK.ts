@@ -0,0 +1,145 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { PublicKey } from '@solana/web3.js';
+
+import { Admin, BN, MARK_PRICE_PRECISION, PositionDirection } from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('formulaic k', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	let solUsd: PublicKey;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe(['curveHistoryAccount']);
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(0);
+
+		await clearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	async function updateFundingRate(): Promise<void> {
+		await new Promise((r) => setTimeout(r, 1000)); // wait 1 second
+		await clearingHouse.updateFundingRate(solUsd, marketIndex);
+		await clearingHouse.fetchAccounts();
+	}
+
+	it('Fail to set a max change above the largest decrease', async () => {
+		try {
+			await clearingHouse.updateFormulaicKMaxChange(new BN(1), new BN(10));
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update the formulaic k max change', async () => {
+		await clearingHouse.updateFormulaicKMaxChange(new BN(2), new BN(100));
+
+		await clearingHouse.fetchAccounts();
+		const state = clearingHouse.getStateAccount();
+		assert(state.formulaicKMaxChangeNumerator.eq(new BN(2)));
+		assert(state.formulaicKMaxChangeDenominator.eq(new BN(100)));
+	});
+
+	it('Shrink k when the fee pool is empty', async () => {
+		const sqrtKBefore = clearingHouse.getMarket(marketIndex).amm.sqrtK;
+
+		await updateFundingRate();
+
+		// with no open positions the change is free, so k moves the full max change
+		const sqrtKAfter = clearingHouse.getMarket(marketIndex).amm.sqrtK;
+		assert(sqrtKAfter.eq(sqrtKBefore.mul(new BN(98)).div(new BN(100))));
+
+		const curveHistory = clearingHouse.getCurveHistoryAccount();
+		assert(curveHistory.head.eq(new BN(1)));
+		const curveRecord = curveHistory.curveRecords[0];
+		assert(curveRecord.sqrtKBefore.eq(sqrtKBefore));
+		assert(curveRecord.sqrtKAfter.eq(sqrtKAfter));
+		assert(curveRecord.adjustmentCost.eq(ZERO));
+	});
+
+	it('Grow k once fees are collected', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			marketIndex
+		);
+		await clearingHouse.closePosition(marketIndex);
+		await clearingHouse.fetchAccounts();
+		const sqrtKBefore = clearingHouse.getMarket(marketIndex).amm.sqrtK;
+
+		await updateFundingRate();
+
+		const sqrtKAfter = clearingHouse.getMarket(marketIndex).amm.sqrtK;
+		assert(sqrtKAfter.eq(sqrtKBefore.mul(new BN(102)).div(new BN(100))));
+		assert(clearingHouse.getCurveHistoryAccount().head.eq(new BN(2)));
+	});
+
+	it('Do not adjust k once the max change is turned off', async () => {
+		await clearingHouse.updateFormulaicKMaxChange(ZERO, new BN(100));
+		const sqrtKBefore = clearingHouse.getMarket(marketIndex).amm.sqrtK;
+
+		await updateFundingRate();
+
+		const sqrtKAfter = clearingHouse.getMarket(marketIndex).amm.sqrtK;
+		assert(sqrtKAfter.eq(sqrtKBefore));
+		assert(clearingHouse.getCurveHistoryAccount().head.eq(new BN(2)));
+	});
+});
@@ -0,0 +1,150 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	PEG_PRECISION,
+	PositionDirection,
+} from '../sdk/src';
+
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('formulaic repeg', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	let solUsd: PublicKey;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe(['curveHistoryAccount']);
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Update the formulaic repeg threshold', async () => {
+		await clearingHouse.updateFormulaicRepegThreshold(new BN(2), new BN(100));
+
+		await clearingHouse.fetchAccounts();
+		const state = clearingHouse.getStateAccount();
+		assert(state.formulaicRepegThresholdNumerator.eq(new BN(2)));
+		assert(state.formulaicRepegThresholdDenominator.eq(new BN(100)));
+	});
+
+	it('Do not repeg when the mark is within the threshold of the oracle', async () => {
+		await setFeedPrice(anchor.workspace.Pyth, 1.01, solUsd);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			marketIndex
+		);
+		await clearingHouse.closePosition(marketIndex);
+
+		await clearingHouse.fetchAccounts();
+		const market = clearingHouse.getMarket(marketIndex);
+		assert(market.amm.pegMultiplier.eq(PEG_PRECISION));
+		assert(clearingHouse.getCurveHistoryAccount().head.eq(ZERO));
+	});
+
+	it('Repeg toward the oracle when the mark is past the threshold', async () => {
+		await setFeedPrice(anchor.workspace.Pyth, 1.05, solUsd);
+
+		// with no open positions the repeg is free, so the peg moves all the way
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			marketIndex
+		);
+
+		await clearingHouse.fetchAccounts();
+		const market = clearingHouse.getMarket(marketIndex);
+		const expectedPeg = PEG_PRECISION.mul(new BN(105)).div(new BN(100));
+		assert(market.amm.pegMultiplier.sub(expectedPeg).abs().lte(new BN(1)));
+
+		const curveHistory = clearingHouse.getCurveHistoryAccount();
+		assert(curveHistory.head.eq(new BN(1)));
+		const curveRecord = curveHistory.curveRecords[0];
+		assert(curveRecord.marketIndex.eq(marketIndex));
+		assert(curveRecord.pegMultiplierBefore.eq(PEG_PRECISION));
+		assert(curveRecord.pegMultiplierAfter.eq(market.amm.pegMultiplier));
+		assert(curveRecord.adjustmentCost.eq(ZERO));
+	});
+
+	it('Do not repeg once the threshold is turned off', async () => {
+		await clearingHouse.updateFormulaicRepegThreshold(ZERO, ZERO);
+		await setFeedPrice(anchor.workspace.Pyth, 1.1, solUsd);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			marketIndex
+		);
+
+		await clearingHouse.fetchAccounts();
+		assert(clearingHouse.getCurveHistoryAccount().head.eq(new BN(1)));
+	});
+});
@@ -0,0 +1,157 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	FUNDING_PAYMENT_PRECISION,
+	MARK_PRICE_PRECISION,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, setFeedPrice } from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('funding rate clamp', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const marketIndex = new BN(0);
+	let solUsd: PublicKey;
+
+	// 0.01% of the oracle twap per funding period
+	const maxFundingRate = 100;
+	const fundingRatePctPrecision = new BN(1000000);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe(['fundingRateHistoryAccount']);
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(0);
+
+		await clearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	async function updateFundingRate(): Promise<void> {
+		await new Promise((r) => setTimeout(r, 1000)); // wait 1 second
+		await clearingHouse.updateFundingRate(solUsd, marketIndex);
+		await clearingHouse.fetchAccounts();
+	}
+
+	it('Fail to set a max funding rate above 3%', async () => {
+		try {
+			await clearingHouse.updateMarketFundingRateParameters(
+				marketIndex,
+				30001,
+				0
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to set a funding interest rate above 3%', async () => {
+		try {
+			await clearingHouse.updateMarketFundingRateParameters(
+				marketIndex,
+				0,
+				30001
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update the funding rate parameters', async () => {
+		await clearingHouse.updateMarketFundingRateParameters(
+			marketIndex,
+			maxFundingRate,
+			0
+		);
+
+		await clearingHouse.fetchAccounts();
+		const market = clearingHouse.getMarket(marketIndex);
+		assert(market.maxFundingRate === maxFundingRate);
+		assert(market.fundingInterestRate === 0);
+	});
+
+	it('Sample the premium between funding rate updates', async () => {
+		await setFeedPrice(anchor.workspace.Pyth, 1.01, solUsd);
+
+		// the first sample only sets the premium the next one is weighted by
+		await updateFundingRate();
+		const market = clearingHouse.getMarket(marketIndex);
+		assert(market.lastPremium.lt(ZERO));
+		assert(!market.lastPremiumSampleTs.eq(ZERO));
+		assert(market.premiumSampleSum.eq(ZERO));
+		assert(market.premiumSampleDuration.eq(ZERO));
+	});
+
+	it('Clamp the funding rate to the max funding rate', async () => {
+		const lastPremium = clearingHouse.getMarket(marketIndex).lastPremium;
+
+		await updateFundingRate();
+
+		const fundingRateHistory = clearingHouse.getFundingRateHistoryAccount();
+		assert(fundingRateHistory.head.eq(new BN(2)));
+		const fundingRateRecord = fundingRateHistory.fundingRateRecords[1];
+		assert(fundingRateRecord.premiumIndex.eq(lastPremium));
+
+		// the mark is below the oracle so shorts pay longs, capped at the max rate
+		const maxRate = fundingRateRecord.oraclePriceTwap
+			.mul(new BN(maxFundingRate))
+			.mul(FUNDING_PAYMENT_PRECISION)
+			.div(fundingRatePctPrecision);
+		assert(fundingRateRecord.fundingRate.eq(maxRate.neg()));
+		assert(fundingRateRecord.rawFundingRate.lt(fundingRateRecord.fundingRate));
+
+		const market = clearingHouse.getMarket(marketIndex);
+		assert(market.amm.lastFundingRate.eq(fundingRateRecord.fundingRate));
+		assert(market.premiumSampleSum.eq(ZERO));
+		assert(market.premiumSampleDuration.eq(ZERO));
+	});
+});
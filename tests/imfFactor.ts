@@ -0,0 +1,174 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('imf factor', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let adminClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const marginRatioInitial = 2000;
+	const marginRatioPartial = 625;
+	const marginRatioMaintenance = 500;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		adminClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await adminClearingHouse.initialize(usdcMint.publicKey, true);
+		await adminClearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await adminClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await adminClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Markets start without an imf factor', async () => {
+		const market = adminClearingHouse.getMarket(marketIndex);
+		assert(market.imfFactor === 0);
+	});
+
+	it('Update the imf factor', async () => {
+		// a 49 usdc position needs an extra 100000 * sqrt(49) / 1000 = 7% of margin
+		await adminClearingHouse.updateMarginRatio(
+			marketIndex,
+			marginRatioInitial,
+			marginRatioPartial,
+			marginRatioMaintenance,
+			100000
+		);
+
+		await adminClearingHouse.fetchAccounts();
+		const market = adminClearingHouse.getMarket(marketIndex);
+		assert(market.marginRatioInitial === marginRatioInitial);
+		assert(market.imfFactor === 100000);
+	});
+
+	it('Fail to set an imf factor above the maximum', async () => {
+		try {
+			await adminClearingHouse.updateMarginRatio(
+				marketIndex,
+				marginRatioInitial,
+				marginRatioPartial,
+				marginRatioMaintenance,
+				100001
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to open a large position with the size adjusted margin ratio', async () => {
+		// 10 usdc of collateral is ~20% of the position, short of the 27% needed
+		try {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				usdcAmount.mul(new BN(49)).div(new BN(10)),
+				marketIndex
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Open the same position without the imf factor', async () => {
+		await adminClearingHouse.updateMarginRatio(
+			marketIndex,
+			marginRatioInitial,
+			marginRatioPartial,
+			marginRatioMaintenance,
+			0
+		);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount.mul(new BN(49)).div(new BN(10)),
+			marketIndex
+		);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.gt(ZERO));
+	});
+});
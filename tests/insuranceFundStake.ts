@@ -0,0 +1,304 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { QUOTE_PRECISION, ZERO } from '../sdk';
+
+describe('insurance fund stake', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let stakerUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const unstakingPeriod = new BN(5);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		stakerUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function getInsuranceVaultBalance(): Promise<BN> {
+		const balance = await connection.getTokenAccountBalance(
+			fillerClearingHouse.getStateAccount().insuranceVault
+		);
+		return new BN(balance.value.amount);
+	}
+
+	it('Initialize insurance fund', async () => {
+		await fillerClearingHouse.initializeInsuranceFund();
+		await fillerClearingHouse.fetchAccounts();
+		await clearingHouse.fetchAccounts();
+
+		const insuranceFund = fillerClearingHouse.getStateAccount().insuranceFund;
+		assert(!insuranceFund.stakeHistory.equals(anchor.web3.PublicKey.default));
+		assert(insuranceFund.totalShares.eq(ZERO));
+	});
+
+	it('Fail to initialize insurance fund twice', async () => {
+		try {
+			await fillerClearingHouse.initializeInsuranceFund();
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update unstaking period and fee share', async () => {
+		await fillerClearingHouse.updateInsuranceFundUnstakingPeriod(
+			unstakingPeriod
+		);
+		await fillerClearingHouse.updateInsuranceFundFeeShare(
+			new BN(1),
+			new BN(2)
+		);
+
+		await fillerClearingHouse.fetchAccounts();
+		const insuranceFund = fillerClearingHouse.getStateAccount().insuranceFund;
+		assert(insuranceFund.unstakingPeriod.eq(unstakingPeriod));
+		assert(insuranceFund.feeShareNumerator.eq(new BN(1)));
+		assert(insuranceFund.feeShareDenominator.eq(new BN(2)));
+	});
+
+	it('Fail to set fee share above 100%', async () => {
+		try {
+			await fillerClearingHouse.updateInsuranceFundFeeShare(
+				new BN(3),
+				new BN(2)
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Stake', async () => {
+		await clearingHouse.initializeInsuranceFundStake();
+		await clearingHouse.addInsuranceFundStake(
+			usdcAmount,
+			stakerUSDCAccount.publicKey
+		);
+
+		// the protocol keeps the first dollar of shares
+		const insuranceFundStake =
+			await clearingHouse.fetchInsuranceFundStakeAccount();
+		assert(insuranceFundStake.shares.eq(usdcAmount.sub(QUOTE_PRECISION)));
+
+		await fillerClearingHouse.fetchAccounts();
+		const insuranceFund = fillerClearingHouse.getStateAccount().insuranceFund;
+		assert(insuranceFund.totalShares.eq(usdcAmount));
+		assert(insuranceFund.protocolShares.eq(QUOTE_PRECISION));
+		assert((await getInsuranceVaultBalance()).eq(usdcAmount));
+
+		const stakeHistory: any =
+			await chProgram.account.insuranceFundStakeHistory.fetch(
+				insuranceFund.stakeHistory
+			);
+		assert(stakeHistory.head.eq(new BN(1)));
+		assert(stakeHistory.insuranceFundStakeRecords[0].amount.eq(usdcAmount));
+	});
+
+	it('Settle fees to insurance fund', async () => {
+		await fillerClearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			marketIndex
+		);
+		await fillerClearingHouse.closePosition(marketIndex);
+
+		await fillerClearingHouse.fetchAccounts();
+		const ammBefore = fillerClearingHouse.getMarket(marketIndex).amm;
+		const vaultBalanceBefore = await getInsuranceVaultBalance();
+
+		await fillerClearingHouse.settleFeesToInsuranceFund(marketIndex);
+
+		await fillerClearingHouse.fetchAccounts();
+		const ammAfter = fillerClearingHouse.getMarket(marketIndex).amm;
+		const vaultBalanceAfter = await getInsuranceVaultBalance();
+
+		const settled = vaultBalanceAfter.sub(vaultBalanceBefore);
+		assert(settled.gt(ZERO));
+		assert(settled.eq(ammBefore.totalFee.div(new BN(2))));
+		assert(
+			ammAfter.totalFeeMinusDistributions.eq(
+				ammBefore.totalFeeMinusDistributions.sub(settled)
+			)
+		);
+		assert(ammAfter.totalFeeSettledToInsuranceFund.eq(ammAfter.totalFee));
+	});
+
+	it('Admin transfers leave stakers share value unchanged', async () => {
+		const shares = (await clearingHouse.fetchInsuranceFundStakeAccount())
+			.shares;
+		const getStakeValue = async () => {
+			await fillerClearingHouse.fetchAccounts();
+			const insuranceFund = fillerClearingHouse.getStateAccount().insuranceFund;
+			return shares
+				.mul(await getInsuranceVaultBalance())
+				.div(insuranceFund.totalShares);
+		};
+		const stakeValueBefore = await getStakeValue();
+
+		// the protocol only holds the dead shares, so there's nothing to move
+		await fillerClearingHouse.transferFromInsuranceVaultToCollateralVault();
+		assert((await getStakeValue()).eq(stakeValueBefore));
+
+		try {
+			await fillerClearingHouse.withdrawFromInsuranceVaultToMarket(
+				marketIndex,
+				QUOTE_PRECISION
+			);
+		} catch (e) {
+			assert((await getStakeValue()).eq(stakeValueBefore));
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to unstake without a request', async () => {
+		try {
+			await clearingHouse.removeInsuranceFundStake(stakerUSDCAccount.publicKey);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Request and cancel unstake', async () => {
+		const shares = (await clearingHouse.fetchInsuranceFundStakeAccount())
+			.shares;
+		await clearingHouse.requestRemoveInsuranceFundStake(shares);
+
+		let insuranceFundStake =
+			await clearingHouse.fetchInsuranceFundStakeAccount();
+		assert(insuranceFundStake.lastWithdrawRequestShares.eq(shares));
+		assert(insuranceFundStake.lastWithdrawRequestValue.gt(ZERO));
+
+		await clearingHouse.cancelRequestRemoveInsuranceFundStake();
+
+		insuranceFundStake = await clearingHouse.fetchInsuranceFundStakeAccount();
+		assert(insuranceFundStake.lastWithdrawRequestShares.eq(ZERO));
+		assert(insuranceFundStake.shares.eq(shares));
+	});
+
+	it('Fail to unstake before the unstaking period is over', async () => {
+		const shares = (await clearingHouse.fetchInsuranceFundStakeAccount())
+			.shares;
+		await clearingHouse.requestRemoveInsuranceFundStake(shares);
+
+		try {
+			await clearingHouse.removeInsuranceFundStake(stakerUSDCAccount.publicKey);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Unstake after the unstaking period', async () => {
+		const requestValue = (await clearingHouse.fetchInsuranceFundStakeAccount())
+			.lastWithdrawRequestValue;
+
+		await new Promise((r) => setTimeout(r, 7000));
+		await clearingHouse.removeInsuranceFundStake(stakerUSDCAccount.publicKey);
+
+		const insuranceFundStake =
+			await clearingHouse.fetchInsuranceFundStakeAccount();
+		assert(insuranceFundStake.shares.eq(ZERO));
+		assert(insuranceFundStake.lastWithdrawRequestShares.eq(ZERO));
+
+		// the stake earned its share of the settled fees
+		const stakerBalance = await connection.getTokenAccountBalance(
+			stakerUSDCAccount.publicKey
+		);
+		assert(new BN(stakerBalance.value.amount).eq(requestValue));
+		assert(requestValue.gt(usdcAmount.sub(QUOTE_PRECISION)));
+
+		await fillerClearingHouse.fetchAccounts();
+		const insuranceFund = fillerClearingHouse.getStateAccount().insuranceFund;
+		assert(insuranceFund.totalShares.eq(insuranceFund.protocolShares));
+	});
+});
@@ -0,0 +1,254 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+import { BN } from '../sdk';
+
+import { Program } from '@project-serum/anchor';
+
+import {
+	Admin,
+	AMM_RESERVE_PRECISION,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	ZERO,
+	getLimitOrderParams,
+} from '../sdk/src';
+
+import { Markets } from '../sdk/src/constants/markets';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('isolated margin', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		preflightCommitment: 'confirmed',
+		commitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const isolatedCollateral = new BN(2 * 10 ** 6);
+	const marketIndex = Markets[0].marketIndex;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Open isolated position', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			isolatedCollateral.mul(new BN(2)),
+			marketIndex,
+			new BN(0),
+			undefined,
+			undefined,
+			isolatedCollateral
+		);
+
+		const user: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(user.collateral.eq(usdcAmount.sub(isolatedCollateral)));
+
+		// the fee is paid out of the isolated collateral
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.isIsolated === 1);
+		assert(position.baseAssetAmount.gt(ZERO));
+		assert(
+			position.isolatedCollateral.eq(isolatedCollateral.sub(user.totalFeePaid))
+		);
+	});
+
+	it('Fail to isolate more collateral than the user has', async () => {
+		try {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				isolatedCollateral,
+				marketIndex,
+				new BN(0),
+				undefined,
+				undefined,
+				usdcAmount
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Add isolated collateral', async () => {
+		const positionBefore = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+
+		await clearingHouse.addIsolatedCollateral(isolatedCollateral, marketIndex);
+
+		const user: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(
+			user.collateral.eq(usdcAmount.sub(isolatedCollateral.mul(new BN(2))))
+		);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(
+			position.isolatedCollateral.eq(
+				positionBefore.isolatedCollateral.add(isolatedCollateral)
+			)
+		);
+	});
+
+	it('Remove isolated collateral', async () => {
+		const positionBefore = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+
+		await clearingHouse.removeIsolatedCollateral(
+			isolatedCollateral,
+			marketIndex
+		);
+
+		const user: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(user.collateral.eq(usdcAmount.sub(isolatedCollateral)));
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(
+			position.isolatedCollateral.eq(
+				positionBefore.isolatedCollateral.sub(isolatedCollateral)
+			)
+		);
+	});
+
+	it('Fail to remove more than the isolated collateral', async () => {
+		try {
+			await clearingHouse.removeIsolatedCollateral(
+				isolatedCollateral,
+				marketIndex
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Close isolated position', async () => {
+		const positionBefore = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+
+		await clearingHouse.closePosition(marketIndex);
+
+		// the remaining isolated collateral is released back to cross margin
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(ZERO));
+		assert(position.isIsolated === 0);
+		assert(position.isolatedCollateral.eq(ZERO));
+
+		const user: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(
+			user.collateral.gt(
+				usdcAmount
+					.sub(isolatedCollateral)
+					.add(positionBefore.isolatedCollateral)
+					.sub(new BN(10 ** 4))
+			)
+		);
+	});
+
+	it('Place isolated limit order', async () => {
+		const orderParams = getLimitOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			AMM_RESERVE_PRECISION,
+			MARK_PRICE_PRECISION.div(new BN(2)),
+			false,
+			false,
+			false,
+			0,
+			false,
+			ZERO,
+			false,
+			isolatedCollateral
+		);
+		await clearingHouse.initializeUserOrdersThenPlaceOrder(orderParams);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.isIsolated === 1);
+		assert(position.isolatedCollateral.eq(isolatedCollateral));
+		assert(position.openOrders.eq(new BN(1)));
+	});
+
+	it('Cancel isolated limit order', async () => {
+		const userBefore: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+
+		await clearingHouse.cancelOrder(userOrders.orders[0].orderId);
+
+		// with no position or open orders left, the collateral is released
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.isIsolated === 0);
+		assert(position.isolatedCollateral.eq(ZERO));
+
+		const user: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(user.collateral.eq(userBefore.collateral.add(isolatedCollateral)));
+	});
+});
@@ -0,0 +1,194 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+	getUserSubAccountPublicKey,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { BASE_PRECISION, getLimitOrderParams, ZERO } from '../sdk';
+
+describe('jit maker', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let takerClearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const makerSubAccountId = 1;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe(['tradeHistoryAccount']);
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+		await fillerClearingHouse.initializeUserSubAccount(makerSubAccountId);
+		await fillerClearingHouse.transferCollateral(
+			usdcAmount.div(new BN(2)),
+			0,
+			makerSubAccountId
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const takerUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		takerClearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await takerClearingHouse.subscribe();
+		await takerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			takerUSDCAccount.publicKey
+		);
+
+		const orderParams = getLimitOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			BASE_PRECISION,
+			MARK_PRICE_PRECISION,
+			false
+		);
+		await takerClearingHouse.initializeUserOrdersThenPlaceOrder(orderParams);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await takerClearingHouse.unsubscribe();
+	});
+
+	async function getTakerOrder() {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await takerClearingHouse.getUserOrdersAccountPublicKey()
+		);
+		return userOrders.orders[0];
+	}
+
+	it('Fail to fill with a maker price worse than the amm', async () => {
+		try {
+			await fillerClearingHouse.fillOrderWithJitMaker(
+				await takerClearingHouse.getUserAccountPublicKey(),
+				await takerClearingHouse.getUserOrdersAccountPublicKey(),
+				await getTakerOrder(),
+				{
+					baseAssetAmount: BASE_PRECISION,
+					price: MARK_PRICE_PRECISION.mul(new BN(101)).div(new BN(100)),
+				},
+				makerSubAccountId
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fill order with jit maker', async () => {
+		const takerUserAccountPublicKey =
+			await takerClearingHouse.getUserAccountPublicKey();
+		const makerUserAccountPublicKey = await getUserSubAccountPublicKey(
+			chProgram.programId,
+			provider.wallet.publicKey,
+			makerSubAccountId
+		);
+		const makerPrice = MARK_PRICE_PRECISION.mul(new BN(99)).div(new BN(100));
+
+		await fillerClearingHouse.fillOrderWithJitMaker(
+			takerUserAccountPublicKey,
+			await takerClearingHouse.getUserOrdersAccountPublicKey(),
+			await getTakerOrder(),
+			{
+				baseAssetAmount: BASE_PRECISION,
+				price: makerPrice,
+			},
+			makerSubAccountId
+		);
+
+		const takerPosition = (
+			await takerClearingHouse.fetchUserPositionsAccount()
+		).positions[0];
+		assert(takerPosition.baseAssetAmount.eq(BASE_PRECISION));
+
+		const maker: any = await chProgram.account.user.fetch(
+			makerUserAccountPublicKey
+		);
+		const makerPosition = (
+			await fillerClearingHouse.fetchUserPositionsAccount(maker.positions)
+		).positions[0];
+		assert(makerPosition.baseAssetAmount.eq(BASE_PRECISION.neg()));
+
+		await fillerClearingHouse.fetchAccounts();
+		const tradeHistory = fillerClearingHouse.getTradeHistoryAccount();
+		const tradeRecord =
+			tradeHistory.tradeRecords[tradeHistory.head.toNumber() - 1];
+		assert(tradeRecord.user.equals(takerUserAccountPublicKey));
+		assert(tradeRecord.maker.equals(makerUserAccountPublicKey));
+		assert(tradeRecord.baseAssetAmount.eq(BASE_PRECISION));
+
+		const takerOrder = await getTakerOrder();
+		assert(takerOrder.orderId.eq(ZERO));
+	});
+});
@@ -0,0 +1,308 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	OracleSource,
+	Wallet,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import {
+	INTEREST_RATE_PRECISION,
+	UTILIZATION_PRECISION,
+	ZERO,
+} from '../sdk';
+
+describe('lending', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let traderUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const borrowAmount = new BN(5 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const assetIndex = new BN(0);
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		await fillerClearingHouse.initializeCollateralAssets();
+		await fillerClearingHouse.fetchAccounts();
+		const btcMint = await mockUSDCMint(provider);
+		await fillerClearingHouse.addCollateralAsset(
+			assetIndex,
+			btcMint.publicKey,
+			await mockOracle(1),
+			OracleSource.PYTH,
+			new BN(8000),
+			new BN(500)
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount.mul(new BN(2)),
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+		await clearingHouse.initializeUserCollateralAssets();
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Fail to update quote interest rate curve with invalid curve', async () => {
+		try {
+			await fillerClearingHouse.updateQuoteInterestRateCurve(
+				UTILIZATION_PRECISION,
+				INTEREST_RATE_PRECISION.div(new BN(10)),
+				INTEREST_RATE_PRECISION
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update quote interest rate curve', async () => {
+		const optimalUtilization = UTILIZATION_PRECISION.div(new BN(2));
+		const optimalBorrowRate = INTEREST_RATE_PRECISION;
+		const maxBorrowRate = INTEREST_RATE_PRECISION.mul(new BN(10));
+		await fillerClearingHouse.updateQuoteInterestRateCurve(
+			optimalUtilization,
+			optimalBorrowRate,
+			maxBorrowRate
+		);
+
+		await fillerClearingHouse.fetchAccounts();
+		const lendingPool = fillerClearingHouse.getStateAccount().quoteLendingPool;
+		assert(lendingPool.optimalUtilization.eq(optimalUtilization));
+		assert(lendingPool.optimalBorrowRate.eq(optimalBorrowRate));
+		assert(lendingPool.maxBorrowRate.eq(maxBorrowRate));
+	});
+
+	it('Update collateral asset interest rate curve', async () => {
+		const optimalUtilization = UTILIZATION_PRECISION.mul(new BN(9)).div(
+			new BN(10)
+		);
+		const optimalBorrowRate = INTEREST_RATE_PRECISION.div(new BN(20));
+		const maxBorrowRate = INTEREST_RATE_PRECISION.div(new BN(2));
+		await fillerClearingHouse.updateCollateralAssetInterestRateCurve(
+			assetIndex,
+			optimalUtilization,
+			optimalBorrowRate,
+			maxBorrowRate
+		);
+
+		const lendingPool = (
+			await fillerClearingHouse.fetchCollateralAssetsAccount()
+		).assets[0].lendingPool;
+		assert(lendingPool.optimalUtilization.eq(optimalUtilization));
+		assert(lendingPool.optimalBorrowRate.eq(optimalBorrowRate));
+		assert(lendingPool.maxBorrowRate.eq(maxBorrowRate));
+	});
+
+	it('Fail to borrow more than margin allows', async () => {
+		try {
+			await clearingHouse.borrow(
+				null,
+				usdcAmount.mul(new BN(2)),
+				traderUSDCAccount.publicKey
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Borrow usdc', async () => {
+		await clearingHouse.borrow(null, borrowAmount, traderUSDCAccount.publicKey);
+
+		const userCollateralAssets =
+			await clearingHouse.fetchUserCollateralAssetsAccount();
+		assert(userCollateralAssets.quoteBorrows.eq(borrowAmount));
+
+		const user: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(user.hasBorrows === 1);
+
+		await clearingHouse.fetchAccounts();
+		const lendingPool = clearingHouse.getStateAccount().quoteLendingPool;
+		assert(lendingPool.totalBorrows.eq(borrowAmount));
+		// the borrowed usdc leaves the vault but the depositors still earn on it
+		assert(lendingPool.totalDeposits.eq(usdcAmount.mul(new BN(2))));
+	});
+
+	it('Fail to withdraw collateral backing borrow', async () => {
+		try {
+			await clearingHouse.withdrawCollateral(
+				usdcAmount,
+				traderUSDCAccount.publicKey
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Settle lending interest', async () => {
+		await fillerClearingHouse.fetchAccounts();
+		const lendingPoolBefore =
+			fillerClearingHouse.getStateAccount().quoteLendingPool;
+
+		await new Promise((r) => setTimeout(r, 3000));
+		await fillerClearingHouse.settleLendingInterest(
+			await fillerClearingHouse.getUserAccountPublicKey()
+		);
+
+		await fillerClearingHouse.fetchAccounts();
+		const lendingPoolAfter =
+			fillerClearingHouse.getStateAccount().quoteLendingPool;
+		assert(
+			lendingPoolAfter.lastInterestTs.gt(lendingPoolBefore.lastInterestTs)
+		);
+		assert(
+			lendingPoolAfter.cumulativeBorrowInterest.gt(
+				lendingPoolBefore.cumulativeBorrowInterest
+			)
+		);
+		assert(
+			lendingPoolAfter.cumulativeDepositInterest.gt(
+				lendingPoolBefore.cumulativeDepositInterest
+			)
+		);
+
+		const user: any = await chProgram.account.user.fetch(
+			await fillerClearingHouse.getUserAccountPublicKey()
+		);
+		assert(
+			user.lastCumulativeDepositInterest.eq(
+				lendingPoolAfter.cumulativeDepositInterest
+			)
+		);
+		// the interest paid is added to the deposits, the trader's is still unsettled
+		assert(lendingPoolAfter.totalDeposits.eq(user.collateral.add(usdcAmount)));
+	});
+
+	it('Settle lending interest with funding payments', async () => {
+		const userAccountPublicKey = await clearingHouse.getUserAccountPublicKey();
+		let user: any = await chProgram.account.user.fetch(userAccountPublicKey);
+		await clearingHouse.settleFundingPayment(
+			userAccountPublicKey,
+			user.positions
+		);
+
+		await clearingHouse.fetchAccounts();
+		const lendingPool = clearingHouse.getStateAccount().quoteLendingPool;
+		user = await chProgram.account.user.fetch(userAccountPublicKey);
+		assert(
+			user.lastCumulativeDepositInterest.eq(
+				lendingPool.cumulativeDepositInterest
+			)
+		);
+
+		const fillerUser: any = await chProgram.account.user.fetch(
+			await fillerClearingHouse.getUserAccountPublicKey()
+		);
+		assert(
+			lendingPool.totalDeposits.eq(user.collateral.add(fillerUser.collateral))
+		);
+	});
+
+	it('Repay usdc', async () => {
+		// more than is owed, the repayment is capped at the borrows plus interest
+		await clearingHouse.repay(null, usdcAmount, traderUSDCAccount.publicKey);
+
+		const userCollateralAssets =
+			await clearingHouse.fetchUserCollateralAssetsAccount();
+		assert(userCollateralAssets.quoteBorrows.eq(ZERO));
+
+		const user: any = await chProgram.account.user.fetch(
+			await clearingHouse.getUserAccountPublicKey()
+		);
+		assert(user.hasBorrows === 0);
+	});
+
+	it('Fail to repay without borrows', async () => {
+		try {
+			await clearingHouse.repay(null, usdcAmount, traderUSDCAccount.publicKey);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+});
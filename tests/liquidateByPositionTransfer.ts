@@ -0,0 +1,206 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	ExtendedLiquidationHistoryAccount,
+	PositionDirection,
+	Wallet,
+} from '../sdk/src';
+
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('liquidate by position transfer', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let liquidatorClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const liquidatorUsdcAmount = new BN(100 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	let solUsd;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			liquidatorUsdcAmount,
+			provider
+		);
+
+		liquidatorClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await liquidatorClearingHouse.initialize(usdcMint.publicKey, true);
+		await liquidatorClearingHouse.subscribe();
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await liquidatorClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await liquidatorClearingHouse.initializeUserAccountAndDepositCollateral(
+			liquidatorUsdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount.mul(new BN(49)).div(new BN(10)),
+			marketIndex
+		);
+	});
+
+	after(async () => {
+		await liquidatorClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Fail to take over the position of a user with sufficient collateral', async () => {
+		try {
+			await liquidatorClearingHouse.liquidateByPositionTransfer(
+				await clearingHouse.getUserAccountPublicKey(),
+				marketIndex
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Take over the position at the discounted oracle price', async () => {
+		await liquidatorClearingHouse.updatePositionTransferDiscount(
+			new BN(1),
+			new BN(100)
+		);
+
+		await liquidatorClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve.mul(new BN(84)).div(new BN(100)),
+			marketIndex
+		);
+		await setFeedPrice(anchor.workspace.Pyth, 0.84, solUsd);
+
+		const baseAssetAmount = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0].baseAssetAmount;
+
+		await liquidatorClearingHouse.liquidateByPositionTransfer(
+			await clearingHouse.getUserAccountPublicKey(),
+			marketIndex
+		);
+
+		const userPosition = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(userPosition.baseAssetAmount.eq(ZERO));
+
+		const liquidatorPosition = (
+			await liquidatorClearingHouse.fetchUserPositionsAccount()
+		).positions[0];
+		assert(liquidatorPosition.baseAssetAmount.eq(baseAssetAmount));
+
+		await liquidatorClearingHouse.fetchAccounts();
+		const liquidationHistory =
+			(await chProgram.account.extendedLiquidationHistory.fetch(
+				liquidatorClearingHouse.getStateAccount().extendedLiquidationHistory
+			)) as ExtendedLiquidationHistoryAccount;
+		const liquidationRecord = liquidationHistory.liquidationRecords[0];
+		assert(
+			liquidationRecord.user.equals(
+				await clearingHouse.getUserAccountPublicKey()
+			)
+		);
+		assert(
+			liquidationRecord.liquidator.equals(
+				await liquidatorClearingHouse.getUserAccountPublicKey()
+			)
+		);
+		assert(liquidationRecord.marketIndex.eq(marketIndex));
+		assert(liquidationRecord.baseAssetAmountTransferred.eq(baseAssetAmount));
+
+		// the liquidator buys the long one percent below the oracle price
+		const oraclePrice = MARK_PRICE_PRECISION.mul(new BN(84)).div(new BN(100));
+		assert(
+			liquidationRecord.transferPrice.eq(
+				oraclePrice.sub(oraclePrice.div(new BN(100)))
+			)
+		);
+	});
+
+	it('Fail to take over a position the user no longer has', async () => {
+		try {
+			await liquidatorClearingHouse.liquidateByPositionTransfer(
+				await clearingHouse.getUserAccountPublicKey(),
+				marketIndex
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+});
@@ -0,0 +1,135 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair, PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('liquidate market', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let liquidatorClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndexes = [new BN(0), new BN(1)];
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		liquidatorClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await liquidatorClearingHouse.initialize(usdcMint.publicKey, true);
+		await liquidatorClearingHouse.subscribe(['liquidationHistoryAccount']);
+
+		const periodicity = new BN(60 * 60); // 1 HOUR
+		for (const marketIndex of marketIndexes) {
+			await liquidatorClearingHouse.initializeMarket(
+				marketIndex,
+				await mockOracle(1),
+				ammInitialBaseAssetReserve,
+				ammInitialQuoteAssetReserve,
+				periodicity
+			);
+		}
+
+		await liquidatorClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+
+		for (const marketIndex of marketIndexes) {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				usdcAmount,
+				marketIndex
+			);
+		}
+	});
+
+	after(async () => {
+		await liquidatorClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Liquidations are recorded in the extended liquidation history', async () => {
+		const state = liquidatorClearingHouse.getStateAccount();
+		assert(!state.extendedLiquidationHistory.equals(PublicKey.default));
+
+		const liquidationHistory =
+			liquidatorClearingHouse.getLiquidationHistoryAccount();
+		assert(liquidationHistory.head.eq(ZERO));
+	});
+
+	it('Fail to liquidate a market of a user with sufficient collateral', async () => {
+		try {
+			await liquidatorClearingHouse.liquidateMarket(
+				await clearingHouse.getUserAccountPublicKey(),
+				marketIndexes[1]
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+});
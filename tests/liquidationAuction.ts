@@ -0,0 +1,283 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair, PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	BankruptcyHistoryAccount,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	UserPosition,
+	Wallet,
+} from '../sdk/src';
+
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('liquidation auction', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let liquidatorClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+	let userAccountPublicKey: PublicKey;
+
+	let usdcMint;
+	let userUSDCAccount;
+	let traderUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(20 * 10 ** 6);
+	const isolatedCollateral = new BN(5 * 10 ** 6);
+	const extraCollateral = new BN(5 * 10 ** 6);
+
+	const crossMarketIndex = new BN(0);
+	const isolatedMarketIndex = new BN(1);
+	let crossOracle: PublicKey;
+	let isolatedOracle: PublicKey;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		liquidatorClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await liquidatorClearingHouse.initialize(usdcMint.publicKey, true);
+		await liquidatorClearingHouse.subscribe(['liquidationHistoryAccount']);
+
+		crossOracle = await mockOracle(1);
+		isolatedOracle = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await liquidatorClearingHouse.initializeMarket(
+			crossMarketIndex,
+			crossOracle,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+		await liquidatorClearingHouse.initializeMarket(
+			isolatedMarketIndex,
+			isolatedOracle,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await liquidatorClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount.add(extraCollateral),
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				traderUSDCAccount.publicKey
+			);
+
+		// 15 usdc of cross margin collateral backs the cross position
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			new BN(70 * 10 ** 6),
+			crossMarketIndex
+		);
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			new BN(24 * 10 ** 6),
+			isolatedMarketIndex,
+			new BN(0),
+			undefined,
+			undefined,
+			isolatedCollateral
+		);
+	});
+
+	after(async () => {
+		await liquidatorClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function movePrice(
+		marketIndex: BN,
+		oracle: PublicKey,
+		price: number
+	): Promise<void> {
+		await liquidatorClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve.mul(new BN(price * 1000)).div(new BN(1000)),
+			marketIndex
+		);
+		await setFeedPrice(anchor.workspace.Pyth, price, oracle);
+	}
+
+	async function getIsolatedPosition(): Promise<UserPosition> {
+		return (await clearingHouse.fetchUserPositionsAccount()).positions.find(
+			(position) => position.marketIndex.eq(isolatedMarketIndex)
+		);
+	}
+
+	it('Fail to start the auction above the full liquidator share', async () => {
+		try {
+			await liquidatorClearingHouse.updateLiquidationAuction({
+				startLiquidatorShareNumerator: new BN(2),
+				startLiquidatorShareDenominator: new BN(1),
+				duration: new BN(100),
+			});
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update the liquidation auction', async () => {
+		await liquidatorClearingHouse.updateLiquidationAuction({
+			startLiquidatorShareNumerator: new BN(1),
+			startLiquidatorShareDenominator: new BN(10),
+			duration: new BN(1000000),
+		});
+
+		await liquidatorClearingHouse.fetchAccounts();
+		const liquidationAuction =
+			liquidatorClearingHouse.getStateAccount().liquidationAuction;
+		assert(liquidationAuction.startLiquidatorShareNumerator.eq(new BN(1)));
+		assert(liquidationAuction.startLiquidatorShareDenominator.eq(new BN(10)));
+		assert(liquidationAuction.duration.eq(new BN(1000000)));
+	});
+
+	it('Flagging a user with sufficient collateral does not start the auction', async () => {
+		await liquidatorClearingHouse.flagUserLiquidatable(userAccountPublicKey);
+
+		const user: any = await chProgram.account.user.fetch(userAccountPublicKey);
+		assert(user.liquidationStartSlot.eq(ZERO));
+	});
+
+	it('Depositing back above the partial margin ends the auction', async () => {
+		await movePrice(crossMarketIndex, crossOracle, 0.836);
+
+		await liquidatorClearingHouse.flagUserLiquidatable(userAccountPublicKey);
+		let user: any = await chProgram.account.user.fetch(userAccountPublicKey);
+		assert(user.liquidationStartSlot.gt(ZERO));
+
+		await clearingHouse.depositCollateral(
+			extraCollateral,
+			traderUSDCAccount.publicKey
+		);
+		user = await chProgram.account.user.fetch(userAccountPublicKey);
+		assert(user.liquidationStartSlot.eq(ZERO));
+	});
+
+	it('Liquidate an unflagged user at the start of the auction', async () => {
+		await movePrice(crossMarketIndex, crossOracle, 0.76);
+
+		await liquidatorClearingHouse.liquidate(userAccountPublicKey);
+
+		await liquidatorClearingHouse.fetchAccounts();
+		const liquidationHistory =
+			liquidatorClearingHouse.getLiquidationHistoryAccount();
+		const liquidationRecord = liquidationHistory.liquidationRecords[0];
+		assert(liquidationRecord.user.equals(userAccountPublicKey));
+		assert(liquidationRecord.marketIndex.eq(crossMarketIndex));
+
+		// the liquidation started the auction so the liquidator gets its start share
+		assert(liquidationRecord.feeToLiquidator.gt(ZERO));
+		assert(
+			liquidationRecord.feeToLiquidator.lte(
+				liquidationRecord.liquidationFee.div(new BN(10))
+			)
+		);
+	});
+
+	it('Liquidate an isolated position', async () => {
+		await movePrice(isolatedMarketIndex, isolatedOracle, 0.84);
+
+		const positionBefore = await getIsolatedPosition();
+
+		// the isolated position gets its own auction
+		await liquidatorClearingHouse.flagUserLiquidatable(userAccountPublicKey);
+		const flaggedPosition = await getIsolatedPosition();
+		assert(flaggedPosition.liquidationStartSlot.gt(ZERO));
+
+		await liquidatorClearingHouse.liquidateIsolatedPosition(
+			userAccountPublicKey,
+			isolatedMarketIndex
+		);
+
+		const position = await getIsolatedPosition();
+		assert(position.baseAssetAmount.lt(positionBefore.baseAssetAmount));
+
+		await liquidatorClearingHouse.fetchAccounts();
+		const liquidationHistory =
+			liquidatorClearingHouse.getLiquidationHistoryAccount();
+		const liquidationRecord =
+			liquidationHistory.liquidationRecords[
+				liquidationHistory.head.toNumber() - 1
+			];
+		assert(liquidationRecord.marketIndex.eq(isolatedMarketIndex));
+	});
+
+	it('Record the bankruptcy of a liquidated user', async () => {
+		await movePrice(crossMarketIndex, crossOracle, 0.5);
+
+		await liquidatorClearingHouse.liquidate(userAccountPublicKey);
+
+		await liquidatorClearingHouse.fetchAccounts();
+		const bankruptcyHistory = (await chProgram.account.bankruptcyHistory.fetch(
+			liquidatorClearingHouse.getStateAccount().bankruptcyHistory
+		)) as BankruptcyHistoryAccount;
+		assert(bankruptcyHistory.head.gt(ZERO));
+
+		const bankruptcyRecord = bankruptcyHistory.bankruptcyRecords[0];
+		assert(bankruptcyRecord.user.equals(userAccountPublicKey));
+		assert(bankruptcyRecord.marketIndex.eq(crossMarketIndex));
+		assert(bankruptcyRecord.bankruptLoss.gt(ZERO));
+
+		const user: any = await chProgram.account.user.fetch(userAccountPublicKey);
+		assert(user.collateral.eq(ZERO));
+	});
+});
@@ -0,0 +1,215 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair, PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	MARGIN_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+} from '../sdk/src';
+
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('market liquidation fees', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let liquidatorClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+	let userAccountPublicKey: PublicKey;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	let solUsd: PublicKey;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		liquidatorClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await liquidatorClearingHouse.initialize(usdcMint.publicKey, true);
+		await liquidatorClearingHouse.subscribe(['liquidationHistoryAccount']);
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await liquidatorClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await liquidatorClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		[, userAccountPublicKey] =
+			await clearingHouse.initializeUserAccountAndDepositCollateral(
+				usdcAmount,
+				traderUSDCAccount.publicKey
+			);
+
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount.mul(new BN(49)).div(new BN(10)),
+			marketIndex
+		);
+	});
+
+	after(async () => {
+		await liquidatorClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Markets start with the state liquidation fees', async () => {
+		// 2.5% and 100% penalties with the liquidator getting 1/2 and 1/20 of them
+		const market = liquidatorClearingHouse.getMarket(marketIndex);
+		assert(market.partialLiquidationPenalty === 250);
+		assert(market.fullLiquidationPenalty === 10000);
+		assert(market.partialLiquidationLiquidatorShare === 5000);
+		assert(market.fullLiquidationLiquidatorShare === 500);
+	});
+
+	it('Fail to migrate a market that already has liquidation fees', async () => {
+		try {
+			await liquidatorClearingHouse.migrateMarket(marketIndex);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to set a liquidation penalty above the total collateral', async () => {
+		try {
+			await liquidatorClearingHouse.updateLiquidationPenalty(
+				marketIndex,
+				MARGIN_PRECISION.toNumber() + 1,
+				500
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to set a liquidator share above the whole fee', async () => {
+		try {
+			await liquidatorClearingHouse.updateLiquidatorShare(
+				marketIndex,
+				5000,
+				MARGIN_PRECISION.toNumber() + 1
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update the liquidation penalty and liquidator share', async () => {
+		await liquidatorClearingHouse.updateLiquidationPenalty(
+			marketIndex,
+			500,
+			1000
+		);
+		await liquidatorClearingHouse.updateLiquidatorShare(
+			marketIndex,
+			MARGIN_PRECISION.toNumber(),
+			MARGIN_PRECISION.toNumber()
+		);
+
+		await liquidatorClearingHouse.fetchAccounts();
+		const market = liquidatorClearingHouse.getMarket(marketIndex);
+		assert(market.partialLiquidationPenalty === 500);
+		assert(market.fullLiquidationPenalty === 1000);
+		assert(
+			market.partialLiquidationLiquidatorShare === MARGIN_PRECISION.toNumber()
+		);
+		assert(
+			market.fullLiquidationLiquidatorShare === MARGIN_PRECISION.toNumber()
+		);
+	});
+
+	it('Pay the liquidator the market share of the fee', async () => {
+		// without an auction the liquidator gets the market's full share straight away
+		await liquidatorClearingHouse.updateLiquidationAuction({
+			startLiquidatorShareNumerator: new BN(1),
+			startLiquidatorShareDenominator: new BN(1),
+			duration: ZERO,
+		});
+
+		await liquidatorClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve.mul(new BN(84)).div(new BN(100)),
+			marketIndex
+		);
+		await setFeedPrice(anchor.workspace.Pyth, 0.84, solUsd);
+
+		await liquidatorClearingHouse.liquidate(userAccountPublicKey);
+
+		await liquidatorClearingHouse.fetchAccounts();
+		const liquidationHistory =
+			liquidatorClearingHouse.getLiquidationHistoryAccount();
+		const liquidationRecord = liquidationHistory.liquidationRecords[0];
+		assert(liquidationRecord.liquidationFee.gt(ZERO));
+		assert(liquidationRecord.feeToLiquidator.gt(ZERO));
+		assert(liquidationRecord.feeToInsuranceFund.eq(ZERO));
+	});
+});
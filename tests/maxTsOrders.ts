@@ -0,0 +1,220 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+	getLimitOrderParams,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { BASE_PRECISION, ZERO } from '../sdk';
+
+describe('max ts orders', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const price = MARK_PRICE_PRECISION.mul(new BN(101)).div(new BN(100));
+
+	function getOrderParams(maxTs: BN) {
+		return getLimitOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			BASE_PRECISION,
+			price,
+			false,
+			false,
+			false,
+			0,
+			false,
+			ZERO,
+			false,
+			ZERO,
+			false,
+			maxTs
+		);
+	}
+
+	async function getNow() {
+		return new BN(await connection.getBlockTime(await connection.getSlot()));
+	}
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function getOrders() {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+		return userOrders.orders.filter((order) => !order.orderId.eq(ZERO));
+	}
+
+	async function fillOrder(order) {
+		await fillerClearingHouse.fillOrder(
+			await clearingHouse.getUserAccountPublicKey(),
+			await clearingHouse.getUserOrdersAccountPublicKey(),
+			order
+		);
+	}
+
+	it('Fail to place order past its max ts', async () => {
+		const maxTs = (await getNow()).sub(new BN(10));
+		try {
+			await clearingHouse.placeOrder(getOrderParams(maxTs));
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Place order with max ts', async () => {
+		const maxTs = (await getNow()).add(new BN(3));
+		await clearingHouse.placeOrder(getOrderParams(maxTs));
+
+		const [order] = await getOrders();
+		assert(order.maxTs.eq(maxTs));
+	});
+
+	it('Fail to cancel orders before max ts', async () => {
+		try {
+			await fillerClearingHouse.cancelExpiredOrders(
+				await clearingHouse.getUserAccountPublicKey(),
+				await clearingHouse.getUserOrdersAccountPublicKey()
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to fill order past max ts', async () => {
+		await new Promise((r) => setTimeout(r, 6000)); // wait 6 seconds
+
+		const [order] = await getOrders();
+		try {
+			await fillOrder(order);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Cancel expired orders', async () => {
+		const userAccountPublicKey = await clearingHouse.getUserAccountPublicKey();
+		const fillerAccountPublicKey =
+			await fillerClearingHouse.getUserAccountPublicKey();
+		const userBefore: any = await chProgram.account.user.fetch(
+			userAccountPublicKey
+		);
+		const fillerBefore: any = await chProgram.account.user.fetch(
+			fillerAccountPublicKey
+		);
+
+		await fillerClearingHouse.cancelExpiredOrders(
+			userAccountPublicKey,
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+
+		assert((await getOrders()).length === 0);
+
+		// the filler is paid a reward out of the user's collateral
+		const user: any = await chProgram.account.user.fetch(userAccountPublicKey);
+		const filler: any = await chProgram.account.user.fetch(
+			fillerAccountPublicKey
+		);
+		const reward = filler.collateral.sub(fillerBefore.collateral);
+		assert(reward.gt(ZERO));
+		assert(user.collateral.eq(userBefore.collateral.sub(reward)));
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.openOrders.eq(ZERO));
+	});
+});
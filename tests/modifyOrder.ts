@@ -0,0 +1,217 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	ModifyOrderParams,
+	Wallet,
+	getLimitOrderParams,
+	isVariant,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { BASE_PRECISION, ZERO } from '../sdk';
+
+describe('modify order', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const price = MARK_PRICE_PRECISION.div(new BN(2));
+	const userOrderId = 1;
+
+	function getModifyOrderParams(
+		params: Partial<ModifyOrderParams>
+	): ModifyOrderParams {
+		return {
+			baseAssetAmount: null,
+			price: null,
+			oraclePriceOffset: null,
+			triggerPrice: null,
+			orderBook: false,
+			...params,
+		};
+	}
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe(['orderHistoryAccount']);
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function getOrders() {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+		return userOrders.orders.filter((order) => !order.orderId.eq(ZERO));
+	}
+
+	it('Place order', async () => {
+		const orderParams = getLimitOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			BASE_PRECISION,
+			price,
+			false,
+			false,
+			false,
+			userOrderId
+		);
+		await clearingHouse.placeOrder(orderParams);
+
+		const [order] = await getOrders();
+		assert(order.price.eq(price));
+	});
+
+	it('Modify order', async () => {
+		const [orderBefore] = await getOrders();
+		const newPrice = price.mul(new BN(11)).div(new BN(10));
+		const newBaseAssetAmount = BASE_PRECISION.mul(new BN(2));
+
+		await clearingHouse.modifyOrder(
+			orderBefore.orderId,
+			marketIndex,
+			getModifyOrderParams({
+				price: newPrice,
+				baseAssetAmount: newBaseAssetAmount,
+			})
+		);
+
+		// the order keeps its id and only the given fields change
+		const [order] = await getOrders();
+		assert(order.orderId.eq(orderBefore.orderId));
+		assert(order.price.eq(newPrice));
+		assert(order.baseAssetAmount.eq(newBaseAssetAmount));
+		assert(order.triggerPrice.eq(orderBefore.triggerPrice));
+
+		await fillerClearingHouse.fetchAccounts();
+		const orderHistory = fillerClearingHouse.getOrderHistoryAccount();
+		const orderRecord =
+			orderHistory.orderRecords[orderHistory.head.toNumber() - 1];
+		assert(isVariant(orderRecord.action, 'modify'));
+		assert(orderRecord.order.price.eq(newPrice));
+	});
+
+	it('Modify order by user id', async () => {
+		const newPrice = price.mul(new BN(12)).div(new BN(10));
+		await clearingHouse.modifyOrderByUserId(
+			userOrderId,
+			marketIndex,
+			getModifyOrderParams({ price: newPrice })
+		);
+
+		const [order] = await getOrders();
+		assert(order.userOrderId === userOrderId);
+		assert(order.price.eq(newPrice));
+	});
+
+	it('Fail to modify base asset amount to zero', async () => {
+		const [order] = await getOrders();
+		try {
+			await clearingHouse.modifyOrder(
+				order.orderId,
+				marketIndex,
+				getModifyOrderParams({ baseAssetAmount: ZERO })
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to modify order that does not exist', async () => {
+		try {
+			await clearingHouse.modifyOrderByUserId(
+				userOrderId + 1,
+				marketIndex,
+				getModifyOrderParams({ price })
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+});
@@ -0,0 +1,327 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import {
+	BASE_PRECISION,
+	getLimitOrderParams,
+	getMarketOrderParams,
+	ZERO,
+} from '../sdk';
+
+describe('order book', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let makerClearingHouse: Admin;
+	let takerClearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	// the maker's ask improves on the amm's mark price of 1
+	const makerPrice = MARK_PRICE_PRECISION.mul(new BN(99)).div(new BN(100));
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		makerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await makerClearingHouse.initialize(usdcMint.publicKey, true);
+		await makerClearingHouse.subscribe(['tradeHistoryAccount']);
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await makerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+		await makerClearingHouse.initializeOrderBook(marketIndex);
+
+		await makerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const takerUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		takerClearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await takerClearingHouse.subscribe();
+		await takerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			takerUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await makerClearingHouse.unsubscribe();
+		await takerClearingHouse.unsubscribe();
+	});
+
+	it('Initialize order book', async () => {
+		const orderBook = await makerClearingHouse.fetchOrderBookAccount(
+			marketIndex
+		);
+		assert(orderBook.marketIndex.eq(marketIndex));
+		assert(orderBook.bids[0].orderId.eq(ZERO));
+		assert(orderBook.asks[0].orderId.eq(ZERO));
+	});
+
+	it('Update maker rebate', async () => {
+		await makerClearingHouse.updateMakerRebate(new BN(1), new BN(5));
+
+		await makerClearingHouse.fetchAccounts();
+		const state = makerClearingHouse.getStateAccount();
+		assert(state.makerRebateNumerator.eq(new BN(1)));
+		assert(state.makerRebateDenominator.eq(new BN(5)));
+	});
+
+	it('Fail to update maker rebate above 100%', async () => {
+		try {
+			await makerClearingHouse.updateMakerRebate(new BN(2), new BN(1));
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Place limit order on the order book', async () => {
+		const orderParams = getLimitOrderParams(
+			marketIndex,
+			PositionDirection.SHORT,
+			BASE_PRECISION,
+			makerPrice,
+			false,
+			false,
+			false,
+			0,
+			false,
+			ZERO,
+			false,
+			ZERO,
+			true
+		);
+		await makerClearingHouse.initializeUserOrdersThenPlaceOrder(orderParams);
+
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await makerClearingHouse.getUserOrdersAccountPublicKey()
+		);
+		const makerOrderId = userOrders.orders[0].orderId;
+
+		const orderBook = await makerClearingHouse.fetchOrderBookAccount(
+			marketIndex
+		);
+		const entry = orderBook.asks[0];
+		assert(entry.orderId.eq(makerOrderId));
+		assert(entry.price.eq(makerPrice));
+		assert(
+			entry.user.equals(await makerClearingHouse.getUserAccountPublicKey())
+		);
+		assert(orderBook.bids[0].orderId.eq(ZERO));
+	});
+
+	it('Fail to rest an oracle offset order on the order book', async () => {
+		const orderParams = getLimitOrderParams(
+			marketIndex,
+			PositionDirection.SHORT,
+			BASE_PRECISION,
+			ZERO,
+			false,
+			false,
+			false,
+			0,
+			false,
+			MARK_PRICE_PRECISION.div(new BN(20)),
+			false,
+			ZERO,
+			true
+		);
+		try {
+			await makerClearingHouse.placeOrder(orderParams);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fill taker order against the order book', async () => {
+		const makerUserAccountPublicKey =
+			await makerClearingHouse.getUserAccountPublicKey();
+		const makerBefore: any = await chProgram.account.user.fetch(
+			makerUserAccountPublicKey
+		);
+
+		const orderParams = getMarketOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			ZERO,
+			BASE_PRECISION,
+			false
+		);
+		await takerClearingHouse.initializeUserOrdersThenPlaceAndFillOrder(
+			orderParams,
+			undefined,
+			undefined,
+			[
+				{
+					maker: makerUserAccountPublicKey,
+					makerPositions: makerBefore.positions,
+					makerOrders:
+						await makerClearingHouse.getUserOrdersAccountPublicKey(),
+				},
+			]
+		);
+
+		const takerPosition = (
+			await takerClearingHouse.fetchUserPositionsAccount()
+		).positions[0];
+		assert(takerPosition.baseAssetAmount.eq(BASE_PRECISION));
+
+		const makerPosition = (
+			await makerClearingHouse.fetchUserPositionsAccount()
+		).positions[0];
+		assert(makerPosition.baseAssetAmount.eq(BASE_PRECISION.neg()));
+		assert(makerPosition.openOrders.eq(ZERO));
+
+		// the filled order is taken off the book
+		const orderBook = await makerClearingHouse.fetchOrderBookAccount(
+			marketIndex
+		);
+		assert(orderBook.asks[0].orderId.eq(ZERO));
+
+		await makerClearingHouse.fetchAccounts();
+		const tradeHistory = makerClearingHouse.getTradeHistoryAccount();
+		const tradeRecord =
+			tradeHistory.tradeRecords[tradeHistory.head.toNumber() - 1];
+		const takerUserAccountPublicKey =
+			await takerClearingHouse.getUserAccountPublicKey();
+		assert(tradeRecord.maker.equals(makerUserAccountPublicKey));
+		assert(tradeRecord.user.equals(takerUserAccountPublicKey));
+		assert(tradeRecord.baseAssetAmount.eq(BASE_PRECISION));
+
+		// the maker is paid a rebate instead of a fee
+		assert(tradeRecord.makerFee.lt(ZERO));
+		const maker: any = await chProgram.account.user.fetch(
+			makerUserAccountPublicKey
+		);
+		assert(maker.collateral.gt(makerBefore.collateral));
+	});
+
+	it('Fill taker order with the amm when the book is worse', async () => {
+		const orderParams = getLimitOrderParams(
+			marketIndex,
+			PositionDirection.SHORT,
+			BASE_PRECISION,
+			MARK_PRICE_PRECISION.mul(new BN(105)).div(new BN(100)),
+			false,
+			false,
+			false,
+			0,
+			false,
+			ZERO,
+			false,
+			ZERO,
+			true
+		);
+		await makerClearingHouse.placeOrder(orderParams);
+		const makerOrderId = (
+			await makerClearingHouse.fetchOrderBookAccount(marketIndex)
+		).asks[0].orderId;
+
+		const makerUserAccountPublicKey =
+			await makerClearingHouse.getUserAccountPublicKey();
+		const maker: any = await chProgram.account.user.fetch(
+			makerUserAccountPublicKey
+		);
+		await takerClearingHouse.placeAndFillOrder(
+			getMarketOrderParams(
+				marketIndex,
+				PositionDirection.LONG,
+				ZERO,
+				BASE_PRECISION,
+				false
+			),
+			undefined,
+			undefined,
+			[
+				{
+					maker: makerUserAccountPublicKey,
+					makerPositions: maker.positions,
+					makerOrders:
+						await makerClearingHouse.getUserOrdersAccountPublicKey(),
+				},
+			]
+		);
+
+		const takerPosition = (
+			await takerClearingHouse.fetchUserPositionsAccount()
+		).positions[0];
+		assert(takerPosition.baseAssetAmount.eq(BASE_PRECISION.mul(new BN(2))));
+
+		// the ask above the mark price is left on the book
+		const orderBook = await makerClearingHouse.fetchOrderBookAccount(
+			marketIndex
+		);
+		assert(orderBook.asks[0].orderId.eq(makerOrderId));
+
+		await makerClearingHouse.fetchAccounts();
+		const tradeHistory = makerClearingHouse.getTradeHistoryAccount();
+		const tradeRecord =
+			tradeHistory.tradeRecords[tradeHistory.head.toNumber() - 1];
+		assert(tradeRecord.maker.equals(anchor.web3.PublicKey.default));
+	});
+});
@@ -0,0 +1,160 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+import { BN } from '../sdk';
+
+import { Program } from '@project-serum/anchor';
+
+import {
+	Admin,
+	MARK_PRICE_PRECISION,
+	PositionDirection,
+	ZERO,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('resize user positions', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		preflightCommitment: 'confirmed',
+		commitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const initialPositions = 5;
+	const numberOfMarkets = initialPositions + 1;
+	const usdcPerPosition = usdcAmount
+		.mul(new BN(5))
+		.div(new BN(numberOfMarkets))
+		.mul(new BN(99))
+		.div(new BN(100));
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe();
+
+		for (let i = 0; i < numberOfMarkets; i++) {
+			const oracle = await mockOracle(1);
+			const periodicity = new BN(0);
+
+			await clearingHouse.initializeMarket(
+				new BN(i),
+				oracle,
+				ammInitialBaseAssetReserve,
+				ammInitialQuoteAssetReserve,
+				periodicity
+			);
+		}
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		for (let i = 0; i < initialPositions; i++) {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				usdcPerPosition,
+				new BN(i),
+				new BN(0)
+			);
+		}
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Fail to open more positions than the account holds', async () => {
+		try {
+			await clearingHouse.openPosition(
+				PositionDirection.LONG,
+				usdcPerPosition,
+				new BN(initialPositions),
+				new BN(0)
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Resize user positions', async () => {
+		const numberOfPositions = 10;
+		await clearingHouse.resizeUserPositions(numberOfPositions);
+
+		const userPositionsAccount =
+			await clearingHouse.fetchUserPositionsAccount();
+		assert(userPositionsAccount.positions.length === numberOfPositions);
+		assert(
+			userPositionsAccount.user.equals(
+				await clearingHouse.getUserAccountPublicKey()
+			)
+		);
+
+		for (let i = 0; i < numberOfPositions; i++) {
+			const position = userPositionsAccount.positions[i];
+			if (i < initialPositions) {
+				assert(position.marketIndex.eq(new BN(i)));
+				assert(position.baseAssetAmount.gt(ZERO));
+			} else {
+				assert(position.baseAssetAmount.eq(ZERO));
+			}
+		}
+	});
+
+	it('Open position in the added slot', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcPerPosition,
+			new BN(initialPositions),
+			new BN(0)
+		);
+
+		const userPositionsAccount =
+			await clearingHouse.fetchUserPositionsAccount();
+		const position = userPositionsAccount.positions.find(
+			(position) =>
+				position.marketIndex.eq(new BN(initialPositions)) &&
+				!position.baseAssetAmount.eq(ZERO)
+		);
+		assert(position !== undefined);
+	});
+
+	it('Fail to shrink user positions', async () => {
+		try {
+			await clearingHouse.resizeUserPositions(initialPositions);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+});
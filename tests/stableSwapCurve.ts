@@ -0,0 +1,187 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { PublicKey } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	CurveType,
+	MARK_PRICE_PRECISION,
+	OracleSource,
+	PEG_PRECISION,
+	PositionDirection,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { ZERO } from '../sdk';
+
+describe('stableswap curve', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const periodicity = new BN(60 * 60); // 1 HOUR
+
+	const constantProductMarketIndex = new BN(0);
+	const stableSwapMarketIndex = new BN(1);
+	const amplificationCoefficient = new BN(100);
+	let oracle: PublicKey;
+
+	async function initializeMarket(
+		marketIndex: BN,
+		curveType: CurveType,
+		amplification: BN
+	): Promise<void> {
+		await clearingHouse.initializeMarket(
+			marketIndex,
+			oracle,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity,
+			PEG_PRECISION,
+			OracleSource.PYTH,
+			2000,
+			625,
+			500,
+			curveType,
+			amplification
+		);
+	}
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe(['tradeHistoryAccount']);
+
+		oracle = await mockOracle(1);
+
+		await clearingHouse.initializeMarket(
+			constantProductMarketIndex,
+			oracle,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Fail to initialize a stableswap market without amplification', async () => {
+		try {
+			await initializeMarket(
+				stableSwapMarketIndex,
+				CurveType.STABLE_SWAP,
+				ZERO
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to initialize a constant product market with amplification', async () => {
+		try {
+			await initializeMarket(
+				stableSwapMarketIndex,
+				CurveType.CONSTANT_PRODUCT,
+				amplificationCoefficient
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Initialize a stableswap market', async () => {
+		await initializeMarket(
+			stableSwapMarketIndex,
+			CurveType.STABLE_SWAP,
+			amplificationCoefficient
+		);
+
+		await clearingHouse.fetchAccounts();
+		const amm = clearingHouse.getMarket(stableSwapMarketIndex).amm;
+		assert(amm.curveType['stableSwap'] !== undefined);
+		assert(amm.amplificationCoefficient.eq(amplificationCoefficient));
+
+		const constantProductAmm = clearingHouse.getMarket(
+			constantProductMarketIndex
+		).amm;
+		assert(constantProductAmm.curveType['constantProduct'] !== undefined);
+		assert(constantProductAmm.amplificationCoefficient.eq(ZERO));
+	});
+
+	it('Trade with less price impact around the peg', async () => {
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			constantProductMarketIndex
+		);
+		await clearingHouse.openPosition(
+			PositionDirection.LONG,
+			usdcAmount,
+			stableSwapMarketIndex
+		);
+
+		await clearingHouse.fetchAccounts();
+		const tradeRecords = clearingHouse.getTradeHistoryAccount().tradeRecords;
+		const constantProductRecord = tradeRecords[0];
+		const stableSwapRecord = tradeRecords[1];
+		assert(stableSwapRecord.marketIndex.eq(stableSwapMarketIndex));
+
+		// balanced reserves put the mark at the peg on either curve
+		assert(constantProductRecord.markPriceBefore.eq(MARK_PRICE_PRECISION));
+		assert(stableSwapRecord.markPriceBefore.eq(MARK_PRICE_PRECISION));
+
+		const constantProductImpact = constantProductRecord.markPriceAfter.sub(
+			constantProductRecord.markPriceBefore
+		);
+		const stableSwapImpact = stableSwapRecord.markPriceAfter.sub(
+			stableSwapRecord.markPriceBefore
+		);
+		assert(stableSwapImpact.gt(ZERO));
+		assert(stableSwapImpact.lt(constantProductImpact));
+		assert(
+			stableSwapRecord.baseAssetAmount.gt(constantProductRecord.baseAssetAmount)
+		);
+	});
+});
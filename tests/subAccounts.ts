@@ -0,0 +1,187 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+import { BN } from '../sdk';
+
+import { Program } from '@project-serum/anchor';
+import { getTokenAccount } from '@project-serum/common';
+
+import {
+	Admin,
+	MARK_PRICE_PRECISION,
+	ZERO,
+	getUserSubAccountPublicKey,
+	isVariant,
+} from '../sdk/src';
+
+import { Markets } from '../sdk/src/constants/markets';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+
+describe('sub accounts', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let clearingHouse: Admin;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+	const transferAmount = usdcAmount.div(new BN(2));
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		clearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.initialize(usdcMint.publicKey, true);
+		await clearingHouse.subscribe(['depositHistoryAccount']);
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await clearingHouse.initializeMarket(
+			Markets[0].marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await clearingHouse.unsubscribe();
+	});
+
+	it('Initialize sub account', async () => {
+		const [, subAccountPublicKey] =
+			await clearingHouse.initializeUserSubAccount(1);
+
+		const expectedSubAccountPublicKey = await getUserSubAccountPublicKey(
+			chProgram.programId,
+			provider.wallet.publicKey,
+			1
+		);
+		assert(subAccountPublicKey.equals(expectedSubAccountPublicKey));
+
+		const subAccount: any = await chProgram.account.user.fetch(
+			subAccountPublicKey
+		);
+		assert(subAccount.authority.equals(provider.wallet.publicKey));
+		assert(subAccount.subAccountId === 1);
+		assert(subAccount.collateral.eq(ZERO));
+	});
+
+	it('Fail to initialize sub account 0', async () => {
+		try {
+			await clearingHouse.initializeUserSubAccount(0);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Transfer collateral between sub accounts', async () => {
+		await clearingHouse.transferCollateral(transferAmount, 0, 1);
+
+		const user: any = await chProgram.account.user.fetch(
+			await getUserSubAccountPublicKey(
+				chProgram.programId,
+				provider.wallet.publicKey,
+				0
+			)
+		);
+		const subAccount: any = await chProgram.account.user.fetch(
+			await getUserSubAccountPublicKey(
+				chProgram.programId,
+				provider.wallet.publicKey,
+				1
+			)
+		);
+
+		assert(user.collateral.eq(usdcAmount.sub(transferAmount)));
+		assert(subAccount.collateral.eq(transferAmount));
+
+		// transfers don't move funds in or out of the vault
+		assert(user.cumulativeDeposits.eq(usdcAmount));
+		assert(subAccount.cumulativeDeposits.eq(ZERO));
+
+		await clearingHouse.fetchAccounts();
+		const depositHistory = clearingHouse.getDepositHistoryAccount();
+		assert(depositHistory.head.toNumber() === 3);
+		assert(
+			isVariant(depositHistory.depositRecords[1].direction, 'transferOut')
+		);
+		assert(depositHistory.depositRecords[1].amount.eq(transferAmount));
+		assert(isVariant(depositHistory.depositRecords[2].direction, 'transferIn'));
+		assert(depositHistory.depositRecords[2].amount.eq(transferAmount));
+	});
+
+	it('Fail to transfer to the same sub account', async () => {
+		try {
+			await clearingHouse.transferCollateral(transferAmount, 1, 1);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Fail to transfer more than the sub account holds', async () => {
+		try {
+			await clearingHouse.transferCollateral(usdcAmount, 1, 0);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Withdraw from sub account', async () => {
+		clearingHouse.switchActiveSubAccount(1);
+		await clearingHouse.withdrawCollateral(
+			transferAmount,
+			userUSDCAccount.publicKey
+		);
+		clearingHouse.switchActiveSubAccount(0);
+
+		const subAccount: any = await chProgram.account.user.fetch(
+			await getUserSubAccountPublicKey(
+				chProgram.programId,
+				provider.wallet.publicKey,
+				1
+			)
+		);
+		assert(subAccount.collateral.eq(ZERO));
+
+		const userUSDCTokenAccount = await getTokenAccount(
+			provider,
+			userUSDCAccount.publicKey
+		);
+		assert(userUSDCTokenAccount.amount.eq(transferAmount));
+	});
+});
@@ -0,0 +1,234 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	TrailingDistanceType,
+	Wallet,
+	getMarketOrderParams,
+	getTrailingStopOrderParams,
+} from '../sdk/src';
+
+import {
+	mockOracle,
+	mockUSDCMint,
+	mockUserUSDCAccount,
+	setFeedPrice,
+} from './testHelpers';
+import { BASE_PRECISION, ZERO } from '../sdk';
+
+describe('trailing stop', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const trailingDistance = MARK_PRICE_PRECISION.div(new BN(10));
+	const initialTriggerPrice = MARK_PRICE_PRECISION.sub(trailingDistance);
+	let solUsd;
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+
+		const marketOrderParams = getMarketOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			ZERO,
+			BASE_PRECISION,
+			false
+		);
+		await clearingHouse.initializeUserOrdersThenPlaceAndFillOrder(
+			marketOrderParams
+		);
+		await fillerClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			marketIndex
+		);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function getOrder() {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+		return userOrders.orders[0];
+	}
+
+	it('Fail to place trailing stop without a trailing distance', async () => {
+		const orderParams = getTrailingStopOrderParams(
+			marketIndex,
+			PositionDirection.SHORT,
+			BASE_PRECISION,
+			initialTriggerPrice,
+			ZERO,
+			TrailingDistanceType.ABSOLUTE,
+			false
+		);
+		try {
+			await clearingHouse.placeOrder(orderParams);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Place trailing stop', async () => {
+		const orderParams = getTrailingStopOrderParams(
+			marketIndex,
+			PositionDirection.SHORT,
+			BASE_PRECISION,
+			initialTriggerPrice,
+			trailingDistance,
+			TrailingDistanceType.ABSOLUTE,
+			false
+		);
+		await clearingHouse.placeOrder(orderParams);
+
+		const order = await getOrder();
+		assert(order.triggerPrice.eq(initialTriggerPrice));
+		assert(order.trailingDistance.eq(trailingDistance));
+	});
+
+	it('Fail to update trailing stop before the price moves', async () => {
+		try {
+			await fillerClearingHouse.updateTrailingStop(
+				await clearingHouse.getUserAccountPublicKey(),
+				await clearingHouse.getUserOrdersAccountPublicKey(),
+				await getOrder()
+			);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Update trailing stop as the price rises', async () => {
+		await fillerClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve.div(new BN(2)),
+			ammInitialQuoteAssetReserve,
+			marketIndex
+		);
+		await setFeedPrice(anchor.workspace.Pyth, 2, solUsd);
+
+		await fillerClearingHouse.updateTrailingStop(
+			await clearingHouse.getUserAccountPublicKey(),
+			await clearingHouse.getUserOrdersAccountPublicKey(),
+			await getOrder()
+		);
+
+		// the trigger trails the new price by the trailing distance
+		const order = await getOrder();
+		assert(order.triggerPrice.gt(MARK_PRICE_PRECISION));
+		assert(
+			order.triggerPrice.lte(
+				MARK_PRICE_PRECISION.mul(new BN(2)).sub(trailingDistance)
+			)
+		);
+	});
+
+	it('Fill trailing stop after the price drops', async () => {
+		await fillerClearingHouse.moveAmmPrice(
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			marketIndex
+		);
+		await setFeedPrice(anchor.workspace.Pyth, 1, solUsd);
+
+		await fillerClearingHouse.fillOrder(
+			await clearingHouse.getUserAccountPublicKey(),
+			await clearingHouse.getUserOrdersAccountPublicKey(),
+			await getOrder()
+		);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(ZERO));
+
+		const order = await getOrder();
+		assert(order.orderId.eq(ZERO));
+	});
+});
@@ -0,0 +1,201 @@
+import * as anchor from '@project-serum/anchor';
+import { assert } from 'chai';
+
+import { Program } from '@project-serum/anchor';
+
+import { Keypair } from '@solana/web3.js';
+
+import {
+	Admin,
+	BN,
+	MARK_PRICE_PRECISION,
+	ClearingHouse,
+	PositionDirection,
+	Wallet,
+	getTwapOrderParams,
+} from '../sdk/src';
+
+import { mockOracle, mockUSDCMint, mockUserUSDCAccount } from './testHelpers';
+import { BASE_PRECISION, ZERO } from '../sdk';
+
+describe('twap orders', () => {
+	const provider = anchor.AnchorProvider.local(undefined, {
+		commitment: 'confirmed',
+		preflightCommitment: 'confirmed',
+	});
+	const connection = provider.connection;
+	anchor.setProvider(provider);
+	const chProgram = anchor.workspace.ClearingHouse as Program;
+
+	let fillerClearingHouse: Admin;
+	let clearingHouse: ClearingHouse;
+
+	let usdcMint;
+	let userUSDCAccount;
+
+	// ammInvariant == k == x * y
+	const mantissaSqrtScale = new BN(Math.sqrt(MARK_PRICE_PRECISION.toNumber()));
+	const ammInitialQuoteAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+	const ammInitialBaseAssetReserve = new anchor.BN(5 * 10 ** 13).mul(
+		mantissaSqrtScale
+	);
+
+	const usdcAmount = new BN(10 * 10 ** 6);
+
+	const marketIndex = new BN(0);
+	const baseAssetAmount = BASE_PRECISION.mul(new BN(2));
+	const sliceBaseAssetAmount = BASE_PRECISION;
+	const sliceInterval = new BN(2);
+	const price = MARK_PRICE_PRECISION.mul(new BN(105)).div(new BN(100));
+
+	before(async () => {
+		usdcMint = await mockUSDCMint(provider);
+		userUSDCAccount = await mockUserUSDCAccount(usdcMint, usdcAmount, provider);
+
+		fillerClearingHouse = Admin.from(
+			connection,
+			provider.wallet,
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await fillerClearingHouse.initialize(usdcMint.publicKey, true);
+		await fillerClearingHouse.subscribe();
+
+		const solUsd = await mockOracle(1);
+		const periodicity = new BN(60 * 60); // 1 HOUR
+
+		await fillerClearingHouse.initializeMarket(
+			marketIndex,
+			solUsd,
+			ammInitialBaseAssetReserve,
+			ammInitialQuoteAssetReserve,
+			periodicity
+		);
+
+		await fillerClearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			userUSDCAccount.publicKey
+		);
+
+		const keypair = new Keypair();
+		await provider.connection.requestAirdrop(keypair.publicKey, 10 ** 9);
+		const traderUSDCAccount = await mockUserUSDCAccount(
+			usdcMint,
+			usdcAmount,
+			provider,
+			keypair.publicKey
+		);
+		clearingHouse = ClearingHouse.from(
+			connection,
+			new Wallet(keypair),
+			chProgram.programId,
+			{
+				commitment: 'confirmed',
+			}
+		);
+		await clearingHouse.subscribe();
+		await clearingHouse.initializeUserAccountAndDepositCollateral(
+			usdcAmount,
+			traderUSDCAccount.publicKey
+		);
+	});
+
+	after(async () => {
+		await fillerClearingHouse.unsubscribe();
+		await clearingHouse.unsubscribe();
+	});
+
+	async function getOrder() {
+		const userOrders: any = await chProgram.account.userOrders.fetch(
+			await clearingHouse.getUserOrdersAccountPublicKey()
+		);
+		return userOrders.orders[0];
+	}
+
+	it('Fail to place twap order with an oversized slice', async () => {
+		const orderParams = getTwapOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			sliceBaseAssetAmount,
+			price,
+			baseAssetAmount,
+			sliceInterval,
+			false
+		);
+		try {
+			await clearingHouse.initializeUserOrdersThenPlaceOrder(orderParams);
+		} catch (e) {
+			return;
+		}
+		assert(false);
+	});
+
+	it('Place twap order', async () => {
+		const orderParams = getTwapOrderParams(
+			marketIndex,
+			PositionDirection.LONG,
+			baseAssetAmount,
+			price,
+			sliceBaseAssetAmount,
+			sliceInterval,
+			false
+		);
+		await clearingHouse.initializeUserOrdersThenPlaceOrder(orderParams);
+
+		const order = await getOrder();
+		assert(order.baseAssetAmount.eq(baseAssetAmount));
+		assert(order.sliceBaseAssetAmount.eq(sliceBaseAssetAmount));
+		assert(order.sliceInterval.eq(sliceInterval));
+		assert(order.lastSliceTs.eq(ZERO));
+	});
+
+	it('Fill first slice', async () => {
+		await fillerClearingHouse.fillOrder(
+			await clearingHouse.getUserAccountPublicKey(),
+			await clearingHouse.getUserOrdersAccountPublicKey(),
+			await getOrder()
+		);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(sliceBaseAssetAmount));
+
+		// the order stays open until every slice is filled
+		const order = await getOrder();
+		assert(order.baseAssetAmountFilled.eq(sliceBaseAssetAmount));
+		assert(order.lastSliceTs.gt(ZERO));
+	});
+
+	it('Skip fill before the slice interval passes', async () => {
+		await fillerClearingHouse.fillOrder(
+			await clearingHouse.getUserAccountPublicKey(),
+			await clearingHouse.getUserOrdersAccountPublicKey(),
+			await getOrder()
+		);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(sliceBaseAssetAmount));
+	});
+
+	it('Fill second slice after the slice interval', async () => {
+		await new Promise((r) => setTimeout(r, 4000)); // wait 4 seconds
+
+		await fillerClearingHouse.fillOrder(
+			await clearingHouse.getUserAccountPublicKey(),
+			await clearingHouse.getUserOrdersAccountPublicKey(),
+			await getOrder()
+		);
+
+		const position = (await clearingHouse.fetchUserPositionsAccount())
+			.positions[0];
+		assert(position.baseAssetAmount.eq(baseAssetAmount));
+
+		const order = await getOrder();
+		assert(order.orderId.eq(ZERO));
+	});
+});